@@ -35,6 +35,7 @@ CREATE TABLE `transactions` (
   `hashBlock`       binary(32)                          NOT NULL,
   `version`         int(11) unsigned               		NOT NULL,
   `lockTime`        int(10) unsigned     				NOT NULL,
+  `isCoinbase`      tinyint(1) unsigned                 NOT NULL,
 
   PRIMARY KEY (`id`)
 ) ENGINE=InnoDB;
@@ -48,7 +49,8 @@ CREATE TABLE `tx_out` (
   `indexOut`        int(10) unsigned            		NOT NULL,
   `value`           bigint(8) unsigned            		NOT NULL,
   `scriptPubKey`    blob                                NOT NULL,
-  `address`     	varchar(36) 					DEFAULT NULL,
+  `address`     	varchar(90) 					DEFAULT NULL,
+  `type`            varchar(11)                         NOT NULL,
   `unspent`        	bit DEFAULT TRUE                    NOT NULL,
 
   PRIMARY KEY (`id`)
@@ -110,7 +112,7 @@ LOAD DATA INFILE '/media/tmp/dump/transactions-0-393489.csv'
 INTO TABLE transactions
 FIELDS TERMINATED BY ';'
 LINES TERMINATED BY '\n'
-(@txid, @hashBlock, version, lockTime)
+(@txid, @hashBlock, version, lockTime, isCoinbase)
 SET txid = unhex(@txid),
 	hashBlock = unhex(@hashBlock);
 COMMIT;
@@ -122,7 +124,7 @@ LOAD DATA INFILE '/media/tmp/dump/tx_out-0-393489.csv'
 INTO TABLE tx_out
 FIELDS TERMINATED BY ';'
 LINES TERMINATED BY '\n'
-(@txid, indexOut, value, @scriptPubKey, address)
+(@txid, indexOut, value, @scriptPubKey, address, type)
 SET txid = unhex(@txid),
 	scriptPubKey = unhex(@scriptPubKey);
 COMMIT;
@@ -20,7 +20,8 @@ SELECT
 	LOWER(HEX(txid)) as txid,
     LOWER(HEX(hashBlock)) as hashBlock,
     version,
-    lockTime
+    lockTime,
+    isCoinbase
 FROM transactions;
 
 SELECT * FROM view_transactions;
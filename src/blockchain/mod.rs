@@ -1,2 +1,3 @@
 pub mod parser;
 pub mod proto;
+pub mod undo;
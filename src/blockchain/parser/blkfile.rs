@@ -1,39 +1,128 @@
 use std::collections::HashMap;
 use std::convert::From;
 use std::fs::{self, DirEntry, File};
-use std::io::{self, Seek, SeekFrom};
+use std::io::{self, Cursor, Read, Seek, SeekFrom};
 use std::path::{Path, PathBuf};
 
 use byteorder::{LittleEndian, ReadBytesExt};
+use memmap2::Mmap;
 use seek_bufread::BufReader;
 
 use crate::blockchain::parser::reader::BlockchainRead;
+use crate::blockchain::parser::remote::{HttpReader, RemoteSource};
 use crate::blockchain::parser::types::CoinType;
 use crate::blockchain::proto::block::Block;
+use crate::common::bench::{self, Stage};
 use crate::errors::{OpError, OpErrorKind, OpResult};
 
+/// Reader of an opened blk file
+#[derive(Debug)]
+enum BlkReader {
+    Buffered(BufReader<File>),
+    Mapped(Cursor<Mmap>),
+    Remote(HttpReader),
+}
+
+impl Read for BlkReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            BlkReader::Buffered(reader) => reader.read(buf),
+            BlkReader::Mapped(cursor) => cursor.read(buf),
+            BlkReader::Remote(reader) => reader.read(buf),
+        }
+    }
+}
+
+impl Seek for BlkReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match self {
+            BlkReader::Buffered(reader) => reader.seek(pos),
+            BlkReader::Mapped(cursor) => cursor.seek(pos),
+            BlkReader::Remote(reader) => reader.seek(pos),
+        }
+    }
+}
+
+/// Serialized block borrowed from a memory-mapped blk file or a read buffer
+#[derive(Debug, Clone, Copy)]
+pub struct RawBlock<'a> {
+    /// Offset of the block within the blk file, behind magic and size
+    pub offset: u64,
+    /// Size stated in the blk file, `data` is shorter if the block is truncated
+    pub size: u32,
+    pub data: &'a [u8],
+}
+
+impl RawBlock<'_> {
+    /// Decodes the block without copying it, unless `keep_raw` keeps the bytes in `Block::raw`.
+    /// Fails with `OpErrorKind::DeserializeError` carrying the offset where decoding stopped.
+    pub fn decode(&self, coin: &CoinType, keep_raw: bool) -> OpResult<Block> {
+        let mut data = self.data;
+        let mut block = data.read_block(self.size, coin).map_err(|err| {
+            OpError::deserialize(self.offset + (self.data.len() - data.len()) as u64, err)
+        })?;
+        if keep_raw {
+            block.raw = Some(self.data.to_vec());
+        }
+        Ok(block)
+    }
+}
+
 /// Holds all necessary data about a raw blk file
 #[derive(Debug)]
 pub struct BlkFile {
     pub path: PathBuf,
+    #[allow(dead_code)]
     pub size: u64,
-    reader: Option<BufReader<File>>,
+    mmap: bool,
+    // Only set for files served by an object store, `path` holds the URL then
+    remote: Option<RemoteSource>,
+    reader: Option<BlkReader>,
+    // Reused for blocks which are read at once, to avoid an allocation per block
+    buffer: Vec<u8>,
 }
 
 impl BlkFile {
-    fn new(path: PathBuf, size: u64) -> BlkFile {
+    fn new(path: PathBuf, size: u64, mmap: bool) -> BlkFile {
         BlkFile {
             path,
             size,
+            mmap,
+            remote: None,
             reader: None,
+            buffer: Vec::new(),
+        }
+    }
+
+    fn new_remote(source: &RemoteSource, url: String, size: u64) -> BlkFile {
+        BlkFile {
+            path: PathBuf::from(url),
+            size,
+            mmap: false,
+            remote: Some(source.clone()),
+            reader: None,
+            buffer: Vec::new(),
         }
     }
 
     /// Opens the file handle (does nothing if the file has been opened already)
-    fn open(&mut self) -> OpResult<&mut BufReader<File>> {
+    fn open(&mut self) -> OpResult<&mut BlkReader> {
         if self.reader.is_none() {
             debug!(target: "blkfile", "Opening {} ...", &self.path.display());
-            self.reader = Some(BufReader::new(File::open(&self.path)?));
+            let reader = if let Some(source) = &self.remote {
+                let url = self.path.to_string_lossy().into_owned();
+                BlkReader::Remote(HttpReader::new(source.clone(), url, self.size))
+            } else if self.mmap {
+                let file = File::open(&self.path)?;
+                // SAFETY: The mapping is only valid as long as nobody else modifies the file.
+                // Bitcoin Core appends new blocks to the last blk file and a pruning node deletes old ones,
+                // which is why `--mmap` is opt-in and should only be used if the node is not running.
+                // Truncating a mapped file results in SIGBUS on access.
+                BlkReader::Mapped(Cursor::new(unsafe { Mmap::map(&file)? }))
+            } else {
+                BlkReader::Buffered(BufReader::new(File::open(&self.path)?))
+            };
+            self.reader = Some(reader);
         }
         Ok(self.reader.as_mut().unwrap())
     }
@@ -46,19 +135,77 @@ impl BlkFile {
         }
     }
 
-    pub fn read_block(&mut self, offset: u64, coin: &CoinType) -> OpResult<Block> {
+    /// Reads the block at the given offset. With `keep_raw` the serialized block is kept in `Block::raw`.
+    /// Memory-mapped blocks are decoded in place, other blocks are streamed from the reader
+    /// or, with `keep_raw` or while benchmarking, read at once into a reused buffer.
+    /// Fails with `OpErrorKind::DeserializeError` carrying the offset where reading stopped.
+    pub fn read_block(&mut self, offset: u64, coin: &CoinType, keep_raw: bool) -> OpResult<Block> {
+        // The block is read at once while benchmarking to separate reading from decoding
+        let mapped = matches!(self.open()?, BlkReader::Mapped(_));
+        if mapped || keep_raw || bench::is_enabled() {
+            return self.raw_block(offset)?.decode(coin, keep_raw);
+        }
+        let reader = self.reader.as_mut().unwrap();
+        reader.seek(SeekFrom::Start(offset - 4))?;
+        let block_size = reader.read_u32::<LittleEndian>()?;
+        reader.read_block(block_size, coin).map_err(|err| {
+            let position = reader.stream_position().unwrap_or(offset);
+            OpError::deserialize(position, err)
+        })
+    }
+
+    /// Returns the serialized block at the given offset without decoding it. Memory-mapped blocks
+    /// are borrowed from the mapping, other blocks are read into a reused buffer.
+    /// A truncated mapped block ends at the end of the file, so decoding reports where it stopped.
+    pub fn raw_block(&mut self, offset: u64) -> OpResult<RawBlock<'_>> {
+        self.open()?;
+        let reader = self.reader.as_mut().unwrap();
+        reader.seek(SeekFrom::Start(offset - 4))?;
+        let size = reader.read_u32::<LittleEndian>()?;
+        let data = match reader {
+            BlkReader::Mapped(cursor) => {
+                let start = cursor.position() as usize;
+                let end = (start + size as usize).min(cursor.get_ref().len());
+                cursor.set_position(end as u64);
+                &cursor.get_ref()[start..end]
+            }
+            reader => {
+                self.buffer.resize(size as usize, 0);
+                bench::time(Stage::Read, || reader.read_exact(&mut self.buffer))
+                    .map_err(|err| OpError::deserialize(offset, OpError::from(err)))?;
+                &self.buffer[..]
+            }
+        };
+        Ok(RawBlock { offset, size, data })
+    }
+
+    /// Reads the serialized block at the given offset without decoding it, along with its size.
+    /// A truncated block is returned up to the end of the file, so decoding reports where it stopped.
+    pub fn read_raw(&mut self, offset: u64) -> OpResult<(u32, Vec<u8>)> {
         let reader = self.open()?;
         reader.seek(SeekFrom::Start(offset - 4))?;
         let block_size = reader.read_u32::<LittleEndian>()?;
-        reader.read_block(block_size, coin)
+        let mut raw = Vec::with_capacity(block_size as usize);
+        bench::time(Stage::Read, || {
+            reader
+                .by_ref()
+                .take(block_size as u64)
+                .read_to_end(&mut raw)
+        })?;
+        Ok((block_size, raw))
     }
 
-    /// Collects all blk*.dat paths in the given directory
-    pub fn from_path(path: &Path) -> OpResult<HashMap<u64, BlkFile>> {
+    /// Collects all blk*.dat paths in the given directory.
+    /// With `mmap` the files are memory-mapped instead of read through a buffer.
+    pub fn from_path(path: &Path, mmap: bool) -> OpResult<HashMap<u64, BlkFile>> {
         info!(target: "blkfile", "Reading files from {} ...", path.display());
         let mut collected = HashMap::with_capacity(4000);
 
-        for entry in fs::read_dir(path)? {
+        let entries = fs::read_dir(path).map_err(|err| {
+            let msg = format!("Unable to read blockchain dir {}:", path.display());
+            OpError::from(err).join_msg(&msg)
+        })?;
+        for entry in entries {
             match entry {
                 Ok(de) => {
                     let path = BlkFile::resolve_path(&de)?;
@@ -73,7 +220,7 @@ impl BlkFile {
                         // Build BlkFile structures
                         let size = fs::metadata(path.as_path())?.len();
                         trace!(target: "blkfile", "Adding {} ... (index: {}, size: {})", path.display(), index, size);
-                        collected.insert(index, BlkFile::new(path, size));
+                        collected.insert(index, BlkFile::new(path, size, mmap));
                     }
                 }
                 Err(msg) => {
@@ -84,12 +231,63 @@ impl BlkFile {
 
         trace!(target: "blkfile", "Found {} blk files", collected.len());
         if collected.is_empty() {
-            Err(OpError::new(OpErrorKind::RuntimeError).join_msg("No blk files found!"))
+            let msg = format!(
+                "No blk files found in {}! Make sure --blockchain-dir points to the blocks directory.",
+                path.display()
+            );
+            Err(OpError::new(OpErrorKind::RuntimeError).join_msg(&msg))
+        } else {
+            Ok(collected)
+        }
+    }
+
+    /// Probes blk00000.dat, blk00001.dat, ... on the object store until a file is missing
+    pub fn from_remote(source: &RemoteSource) -> OpResult<HashMap<u64, BlkFile>> {
+        info!(target: "blkfile", "Reading files from {} ...", source);
+        let mut collected = HashMap::with_capacity(4000);
+        for index in 0.. {
+            let url = source.url(&format!("blk{:05}.dat", index));
+            match source.content_length(&url)? {
+                Some(size) => {
+                    trace!(target: "blkfile", "Adding {} ... (index: {}, size: {})", url, index, size);
+                    collected.insert(index, BlkFile::new_remote(source, url, size));
+                }
+                None => break,
+            }
+        }
+
+        trace!(target: "blkfile", "Found {} blk files", collected.len());
+        if collected.is_empty() {
+            let msg = format!("No blk files found at {}!", source);
+            Err(OpError::new(OpErrorKind::RuntimeError).join_msg(&msg))
         } else {
             Ok(collected)
         }
     }
 
+    /// Returns true if at least one of the given blk files starts with the network magic.
+    /// Empty files (preallocated by Core) are ignored.
+    pub fn has_magic(blk_files: &HashMap<u64, BlkFile>, magic: u32) -> bool {
+        blk_files
+            .values()
+            .any(|blk_file| matches!(blk_file.read_magic(), Ok(file_magic) if file_magic == magic))
+    }
+
+    /// Reads the first 4 bytes without keeping the file open
+    fn read_magic(&self) -> OpResult<u32> {
+        if self.size < 4 {
+            return Err(OpError::new(OpErrorKind::RuntimeError).join_msg("File too small"));
+        }
+        let magic = match &self.remote {
+            Some(source) => {
+                let bytes = source.read_range(&self.path.to_string_lossy(), 0, 4)?;
+                (&bytes[..]).read_u32::<LittleEndian>()?
+            }
+            None => File::open(&self.path)?.read_u32::<LittleEndian>()?,
+        };
+        Ok(magic)
+    }
+
     /// Resolves a PathBuf for the given entry.
     /// Also resolves symlinks if present.
     fn resolve_path(entry: &DirEntry) -> io::Result<PathBuf> {
@@ -117,6 +315,33 @@ impl BlkFile {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::blockchain::proto::ToRaw;
+    use crate::common::testutils;
+    use bitcoin::hashes::{sha256d, Hash};
+    use std::time::Instant;
+
+    /// Chain of `n` blocks, each spending the coinbase of the previous one
+    fn spending_chain(n: usize) -> Vec<Block> {
+        let mut blocks: Vec<Block> = Vec::with_capacity(n);
+        for i in 0..n {
+            let prev_hash = blocks
+                .last()
+                .map(|b| b.header.hash)
+                .unwrap_or(sha256d::Hash::all_zeros());
+            let mut txs = vec![testutils::coinbase(
+                i as u64,
+                &[(5000, testutils::p2pkh_script(0))],
+            )];
+            if let Some(prev) = blocks.last() {
+                txs.push(testutils::tx(
+                    &[(prev.txs[0].hash, 0)],
+                    &[(4000, testutils::p2pkh_script(1))],
+                ));
+            }
+            blocks.push(testutils::block(prev_hash, 600 * i as u32, txs));
+        }
+        blocks
+    }
 
     #[test]
     fn test_parse_blk_index() {
@@ -139,13 +364,216 @@ mod tests {
             13412451,
             BlkFile::parse_blk_index("blk13412451.dat", prefix, ext).unwrap()
         );
-        assert_eq!(
-            true,
-            BlkFile::parse_blk_index("blkindex.dat", prefix, ext).is_none()
+        assert!(BlkFile::parse_blk_index("blkindex.dat", prefix, ext).is_none());
+        assert!(BlkFile::parse_blk_index("invalid.dat", prefix, ext).is_none());
+    }
+
+    #[test]
+    fn test_from_path_empty_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("rev00000.dat"), [0u8; 8]).unwrap();
+        let err = BlkFile::from_path(dir.path(), false).unwrap_err();
+        assert!(err.to_string().contains("No blk files found in"));
+        assert!(err.to_string().contains(&dir.path().display().to_string()));
+    }
+
+    #[test]
+    fn test_has_magic() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("blk00000.dat"), 0xd9b4bef9u32.to_le_bytes()).unwrap();
+        fs::write(dir.path().join("blk00001.dat"), []).unwrap();
+        let blk_files = BlkFile::from_path(dir.path(), false).unwrap();
+        assert!(BlkFile::has_magic(&blk_files, 0xd9b4bef9));
+        assert!(!BlkFile::has_magic(&blk_files, 0xdbb6c0fb));
+    }
+
+    #[test]
+    fn test_read_block_mmap() {
+        let block1 = testutils::block(
+            sha256d::Hash::all_zeros(),
+            0,
+            vec![testutils::coinbase(1, &[(50, testutils::p2pkh_script(0))])],
         );
-        assert_eq!(
-            true,
-            BlkFile::parse_blk_index("invalid.dat", prefix, ext).is_none()
+        let block2 = testutils::block(
+            block1.header.hash,
+            600,
+            vec![
+                testutils::coinbase(2, &[(50, testutils::p2pkh_script(1))]),
+                testutils::tx(
+                    &[(block1.txs[0].hash, 0)],
+                    &[(40, testutils::p2pkh_script(2))],
+                ),
+            ],
+        );
+        let dir = tempfile::tempdir().unwrap();
+        testutils::write_blocks_dir(dir.path(), &[block1, block2]);
+        let coin = CoinType::default();
+
+        let read_all = |mmap: bool| {
+            let mut blk_files = BlkFile::from_path(dir.path(), mmap).unwrap();
+            let blk_file = blk_files.get_mut(&0).unwrap();
+            let first = blk_file.read_block(8, &coin, false).unwrap();
+            let second = blk_file
+                .read_block(16 + first.size as u64, &coin, false)
+                .unwrap();
+            [first, second]
+        };
+        let buffered = read_all(false);
+        let mapped = read_all(true);
+        for (a, b) in buffered.iter().zip(mapped.iter()) {
+            assert_eq!(a.header.hash, b.header.hash);
+            assert_eq!(a.size, b.size);
+            assert_eq!(a.txs.len(), b.txs.len());
+            for (tx_a, tx_b) in a.txs.iter().zip(b.txs.iter()) {
+                assert_eq!(tx_a.hash, tx_b.hash);
+                assert_eq!(tx_a.value.to_bytes(), tx_b.value.to_bytes());
+            }
+        }
+        assert_eq!(mapped[1].txs.len(), 2);
+    }
+
+    #[test]
+    fn test_read_truncated_block() {
+        let block = testutils::block(
+            sha256d::Hash::all_zeros(),
+            0,
+            vec![testutils::coinbase(1, &[(50, testutils::p2pkh_script(0))])],
+        );
+        let dir = tempfile::tempdir().unwrap();
+        testutils::write_blocks_dir(dir.path(), &[block]);
+        // Cut the block within its coinbase transaction
+        let path = dir.path().join("blk00000.dat");
+        let data = fs::read(&path).unwrap();
+        let len = 8 + 80 + 1 + 10;
+        fs::write(&path, &data[..len]).unwrap();
+
+        for mmap in [false, true] {
+            let mut blk_files = BlkFile::from_path(dir.path(), mmap).unwrap();
+            let blk_file = blk_files.get_mut(&0).unwrap();
+            let err = blk_file
+                .read_block(8, &CoinType::default(), false)
+                .unwrap_err();
+            match err.kind {
+                OpErrorKind::DeserializeError(err) => {
+                    assert_eq!(err.offset, len as u64);
+                    assert_eq!(err.height, None);
+                    assert!(matches!(err.cause.kind, OpErrorKind::IoError(_)));
+                }
+                kind => panic!("Unexpected error: {}", kind),
+            }
+        }
+    }
+
+    #[test]
+    fn test_read_block_paths() {
+        let blocks = spending_chain(3);
+        let dir = tempfile::tempdir().unwrap();
+        testutils::write_blocks_dir(dir.path(), &blocks);
+        let coin = CoinType::default();
+
+        // Streamed, read at once into the buffer and decoded in place from the mapping
+        for (mmap, keep_raw) in [(false, false), (false, true), (true, false), (true, true)] {
+            let mut blk_files = BlkFile::from_path(dir.path(), mmap).unwrap();
+            let blk_file = blk_files.get_mut(&0).unwrap();
+            let mut offset = 8;
+            for expected in &blocks {
+                let block = blk_file.read_block(offset, &coin, keep_raw).unwrap();
+                assert_eq!(block.header.hash, expected.header.hash);
+                assert_eq!(block.size, expected.size);
+                assert_eq!(block.txs.len(), expected.txs.len());
+                for (tx, expected) in block.txs.iter().zip(expected.txs.iter()) {
+                    assert_eq!(tx.hash, expected.hash);
+                    assert_eq!(tx.value.to_bytes(), expected.value.to_bytes());
+                }
+                match keep_raw {
+                    true => assert_eq!(block.raw.unwrap(), testutils::block_bytes(expected)),
+                    false => assert!(block.raw.is_none()),
+                }
+                offset += 8 + block.size as u64;
+            }
+        }
+    }
+
+    #[test]
+    fn test_raw_block() {
+        let blocks = spending_chain(2);
+        let dir = tempfile::tempdir().unwrap();
+        testutils::write_blocks_dir(dir.path(), &blocks);
+        let coin = CoinType::default();
+
+        for mmap in [false, true] {
+            let mut blk_files = BlkFile::from_path(dir.path(), mmap).unwrap();
+            let blk_file = blk_files.get_mut(&0).unwrap();
+            let offset = 16 + blocks[0].size as u64;
+            let raw_block = blk_file.raw_block(offset).unwrap();
+            assert_eq!(raw_block.offset, offset);
+            assert_eq!(raw_block.size, blocks[1].size);
+            assert_eq!(raw_block.data, testutils::block_bytes(&blocks[1]));
+            let block = raw_block.decode(&coin, true).unwrap();
+            assert_eq!(block.header.hash, blocks[1].header.hash);
+            assert_eq!(block.raw.unwrap(), raw_block.data);
+
+            // Borrowed from the mapping, without a copy into the buffer
+            let raw_block = blk_file.raw_block(8).unwrap();
+            let data = raw_block.data.as_ptr();
+            match mmap {
+                true => assert!(blk_file.buffer.is_empty()),
+                false => assert_eq!(data, blk_file.buffer.as_ptr()),
+            }
+        }
+    }
+
+    /// Compares decoding memory-mapped blocks in place with copying each block first, run with
+    /// `cargo test --release bench_read_block_mmap -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn bench_read_block_mmap() {
+        let blocks = spending_chain(20000);
+        let dir = tempfile::tempdir().unwrap();
+        testutils::write_blocks_dir(dir.path(), &blocks);
+        let coin = CoinType::default();
+        let mut blk_files = BlkFile::from_path(dir.path(), true).unwrap();
+        let blk_file = blk_files.get_mut(&0).unwrap();
+        let data = fs::read(&blk_file.path).unwrap();
+
+        let mut read_all = || {
+            let started_at = Instant::now();
+            let mut offset = 8;
+            for _ in &blocks {
+                let size = blk_file.read_block(offset, &coin, false).unwrap().size;
+                offset += 8 + size as u64;
+            }
+            Instant::now() - started_at
+        };
+        // The first pass faults in the pages of the mapping
+        read_all();
+        let in_place = read_all();
+
+        let mut copied = 0;
+
+        let started_at = Instant::now();
+        let mut offset = 8;
+        for _ in &blocks {
+            let size = (&data[offset - 4..]).read_u32::<LittleEndian>().unwrap();
+            let raw = data[offset..offset + size as usize].to_vec();
+            let raw_block = RawBlock {
+                offset: offset as u64,
+                size,
+                data: &raw,
+            };
+            raw_block.decode(&coin, false).unwrap();
+            copied += raw.len();
+            offset += 8 + size as usize;
+        }
+        let copying = Instant::now() - started_at;
+        println!(
+            "{} blocks: in place {:.2?} without block allocations, copying {:.2?} with {} allocations of {} bytes ({:.2}x)",
+            blocks.len(),
+            in_place,
+            copying,
+            blocks.len(),
+            copied,
+            copying.as_secs_f64() / in_place.as_secs_f64()
         );
     }
 }
@@ -0,0 +1,170 @@
+use bitcoin::hashes::sha256d;
+
+use crate::blockchain::parser::chain::{self, ChainStorage};
+use crate::blockchain::parser::index::StaleBlock;
+use crate::blockchain::parser::rpc::RpcClient;
+use crate::blockchain::parser::types::CoinType;
+use crate::blockchain::proto::block::Block;
+use crate::errors::{OpError, OpErrorKind, OpResult};
+use crate::{BlockHeightRange, ParserOptions};
+
+/// Provides the blocks of the main chain by height to the parser.
+/// Implemented by `ChainStorage` for the blk files and by `RpcBlockSource` for the RPC of a node.
+pub trait BlockSource {
+    /// Returns the block at the given height, None if there is no block at this height.
+    /// Errors while reading the block and blocks which failed `--verify` are returned as errors.
+    fn get_block(&mut self, height: u64) -> OpResult<Option<Block>>;
+
+    /// Picks up the blocks which arrived since, e.g. while watching
+    fn refresh(&mut self) -> OpResult<()>;
+
+    /// Reads and verifies the block at the given height, returns the first error
+    fn validate_block(&mut self, height: u64) -> OpResult<()>;
+
+    /// Returns the blk file index and the offset of the block at the given height
+    fn block_location(&self, _height: u64) -> Option<(u64, u64)> {
+        None
+    }
+
+    /// Returns the hash of the block at the given height, None if there is no block at this height
+    fn block_hash(&self, height: u64) -> OpResult<Option<sha256d::Hash>>;
+
+    /// Returns the highest block which can be requested
+    fn max_height(&self) -> u64;
+
+    /// Returns the known blocks which are not part of the main chain
+    fn stale_blocks(&self) -> &[StaleBlock] {
+        &[]
+    }
+
+    /// Reads one of the blocks returned by stale_blocks()
+    fn read_stale_block(&mut self, stale: &StaleBlock) -> OpResult<Block> {
+        let msg = format!("Stale block {} is not available", stale.hash);
+        Err(OpError::new(OpErrorKind::ValidationError).join_msg(&msg))
+    }
+
+    /// Returns the number of blk files the blocks are read from
+    fn blk_file_count(&self) -> usize {
+        0
+    }
+}
+
+/// Opens the block source selected by the options, the blk files unless `--rpc-only` is given
+pub fn open(options: &ParserOptions) -> OpResult<Box<dyn BlockSource>> {
+    match options.rpc_only {
+        true => Ok(Box::new(RpcBlockSource::new(options)?)),
+        false => Ok(Box::new(ChainStorage::new(options)?)),
+    }
+}
+
+/// Fetches all blocks via `getblockhash` and `getblock` from the RPC of a node,
+/// so neither blk files nor the block index are needed, e.g. for pruned or remote nodes.
+/// Stale blocks and the undo data are not available this way.
+pub struct RpcBlockSource {
+    rpc: RpcClient,
+    coin: CoinType,
+    verify: bool,
+    keep_raw: bool,
+    range: BlockHeightRange,
+    max_height: u64,
+    // Hash of the last fetched block, to verify the next one without another request
+    last_block: Option<(u64, sha256d::Hash)>,
+}
+
+impl RpcBlockSource {
+    pub fn new(options: &ParserOptions) -> OpResult<Self> {
+        let rpc = options.rpc.clone().ok_or_else(|| {
+            OpError::new(OpErrorKind::InvalidArgsError).join_msg("--rpc-only requires --rpc-url")
+        })?;
+        if options.callback.needs_undo() {
+            let msg = format!(
+                "{} reads the undo data from the rev files, which is not available via RPC",
+                options.callback_name
+            );
+            return Err(OpError::new(OpErrorKind::InvalidArgsError).join_msg(&msg));
+        }
+        // Not every node implementation reports the pruning state
+        if let Ok(Some(prune_height)) = rpc.prune_height() {
+            if options.range.start < prune_height {
+                let msg = format!(
+                    "The node is pruned and only stores blocks from height {}, \
+                     use --start {} or later",
+                    prune_height, prune_height
+                );
+                return Err(OpError::new(OpErrorKind::InvalidArgsError).join_msg(&msg));
+            }
+        }
+        let max_height = RpcBlockSource::max_height_of(&rpc, &options.range)?;
+        info!(target: "chain", "Fetching blocks up to height {} via RPC from {} ...", max_height, rpc);
+        Ok(Self {
+            rpc,
+            coin: options.coin.clone(),
+            verify: options.verify,
+            keep_raw: options.callback.needs_raw_block(),
+            range: options.range,
+            max_height,
+            last_block: None,
+        })
+    }
+
+    /// Returns the tip of the node, limited to the end of the range
+    fn max_height_of(rpc: &RpcClient, range: &BlockHeightRange) -> OpResult<u64> {
+        let tip = rpc.block_count()?;
+        Ok(range.end.unwrap_or(u64::MAX).min(tip))
+    }
+
+    fn fetch(&self, height: u64) -> OpResult<Block> {
+        self.rpc
+            .block(height, &self.coin, self.keep_raw)
+            .map_err(|err| err.at_height(height))
+    }
+
+    fn verify(&self, block: &Block, height: u64) -> OpResult<()> {
+        let prev_hash = match (height, self.last_block) {
+            (0, _) => None,
+            (_, Some((last, hash))) if last + 1 == height => Some(hash),
+            _ => Some(chain::parse_block_hash(&self.rpc.block_hash(height - 1)?)?),
+        };
+        chain::verify_block(&self.coin, block, height, prev_hash.as_ref())
+    }
+}
+
+impl BlockSource for RpcBlockSource {
+    fn get_block(&mut self, height: u64) -> OpResult<Option<Block>> {
+        if height > self.max_height {
+            return Ok(None);
+        }
+        let block = self.fetch(height)?;
+        if self.verify {
+            if let Err(err) = self.verify(&block, height) {
+                error!(target: "chain", "Block {} failed verification: {}", height, err);
+                return Err(err);
+            }
+        }
+        self.last_block = Some((height, block.hash()));
+        Ok(Some(block))
+    }
+
+    fn refresh(&mut self) -> OpResult<()> {
+        self.max_height = RpcBlockSource::max_height_of(&self.rpc, &self.range)?;
+        Ok(())
+    }
+
+    fn validate_block(&mut self, height: u64) -> OpResult<()> {
+        let block = self.fetch(height)?;
+        self.verify(&block, height)?;
+        self.last_block = Some((height, block.hash()));
+        Ok(())
+    }
+
+    fn block_hash(&self, height: u64) -> OpResult<Option<sha256d::Hash>> {
+        if height > self.max_height {
+            return Ok(None);
+        }
+        chain::parse_block_hash(&self.rpc.block_hash(height)?).map(Some)
+    }
+
+    fn max_height(&self) -> u64 {
+        self.max_height
+    }
+}
@@ -1,11 +1,19 @@
+use bitcoin::hashes::sha256d;
 use std::collections::HashMap;
+use std::path::PathBuf;
+use std::str::FromStr;
 
 use crate::blockchain::parser::blkfile::BlkFile;
-use crate::blockchain::parser::index::ChainIndex;
+use crate::blockchain::parser::blocksource::BlockSource;
+use crate::blockchain::parser::index::{ChainIndex, StaleBlock};
+use crate::blockchain::parser::prefetch::Prefetcher;
+use crate::blockchain::parser::remote::RemoteSource;
+use crate::blockchain::parser::rpc::RpcClient;
 use crate::blockchain::parser::types::CoinType;
 use crate::blockchain::proto::block::Block;
+use crate::blockchain::undo::{self, BlockUndo, RevFile};
 use crate::errors::{OpError, OpErrorKind, OpResult};
-use crate::ParserOptions;
+use crate::{BlockHeightRange, ParserOptions};
 
 /// Manages the index and data of longest valid chain
 pub struct ChainStorage {
@@ -13,69 +21,415 @@ pub struct ChainStorage {
     blk_files: HashMap<u64, BlkFile>, // maps blk_index to BlkFile
     coin: CoinType,
     verify: bool,
+    keep_raw: bool,
+    // Rev files by blk_index, only set if the callback needs the undo data
+    rev_files: Option<HashMap<u64, RevFile>>,
+    // Blocks are requested from the tip downward
+    reverse: bool,
+    // Fetches the blocks above the block index, up to max_height
+    rpc: Option<RpcClient>,
+    max_height: u64,
+    // Location of the blk files and the index, to read them again in `refresh()`
+    blockchain_dir: PathBuf,
+    source: Option<RemoteSource>,
+    mmap: bool,
+    range: BlockHeightRange,
+    // Decodes the next blocks in parallel with `--threads`
+    prefetcher: Option<Prefetcher>,
 }
 
 impl ChainStorage {
     pub fn new(options: &ParserOptions) -> OpResult<Self> {
+        let blk_files = match &options.source {
+            Some(source) => BlkFile::from_remote(source)?,
+            None => BlkFile::from_path(options.blockchain_dir.as_path(), options.mmap)?,
+        };
+        let needs_undo = options.callback.needs_undo();
+        if needs_undo && options.source.is_some() {
+            let msg = format!(
+                "{} reads the undo data from the rev files, which is not supported with --source",
+                options.callback_name
+            );
+            return Err(OpError::new(OpErrorKind::InvalidArgsError).join_msg(&msg));
+        }
+        if !BlkFile::has_magic(&blk_files, options.coin.magic) {
+            warn!(target: "chain", "None of the {} blk files starts with the {} network magic {:#010x}. Wrong --coin?",
+                  blk_files.len(), options.coin.name, options.coin.magic);
+        }
+        let chain_index = ChainIndex::new(&options.blockchain_dir, &options.range)?;
+        let max_height =
+            ChainStorage::max_height_with_rpc(&chain_index, &options.rpc, &options.range)?;
         Ok(Self {
-            chain_index: ChainIndex::new(options)?,
-            blk_files: BlkFile::from_path(options.blockchain_dir.as_path())?,
+            chain_index,
+            blk_files,
             coin: options.coin.clone(),
             verify: options.verify,
+            keep_raw: options.callback.needs_raw_block(),
+            rev_files: needs_undo.then(HashMap::new),
+            reverse: options.reverse,
+            rpc: options.rpc.clone(),
+            max_height,
+            blockchain_dir: options.blockchain_dir.clone(),
+            source: options.source.clone(),
+            mmap: options.mmap,
+            range: options.range,
+            prefetcher: (options.threads > 1).then(|| {
+                let keep_raw = options.callback.needs_raw_block();
+                Prefetcher::new(
+                    options.threads,
+                    options.threads * 4,
+                    &options.coin,
+                    keep_raw,
+                )
+            }),
         })
     }
 
-    /// Returns the next block and its height
-    pub fn get_block(&mut self, height: u64) -> Option<Block> {
-        // Read block
-        let block_meta = self.chain_index.get(height)?;
-        let blk_file = self.blk_files.get_mut(&block_meta.blk_index)?;
-        let block = blk_file
-            .read_block(block_meta.data_offset, &self.coin)
-            .ok()?;
+    /// Returns the highest block of the index, or of the node if it serves more blocks via RPC
+    fn max_height_with_rpc(
+        chain_index: &ChainIndex,
+        rpc: &Option<RpcClient>,
+        range: &BlockHeightRange,
+    ) -> OpResult<u64> {
+        let max_height = chain_index.max_height();
+        if let Some(rpc) = rpc {
+            let tip = rpc.block_count()?;
+            let end = range.end.unwrap_or(u64::MAX).min(tip);
+            if end > max_height {
+                info!(target: "chain", "Fetching blocks from height {} to {} via RPC from {} ...",
+                      max_height + 1, end, rpc);
+                return Ok(end);
+            }
+        }
+        Ok(max_height)
+    }
+
+    /// Reads the outputs spent by the block at the given height from the rev file with the same index as its blk file
+    fn read_undo(&mut self, block: &Block, height: u64) -> OpResult<BlockUndo> {
+        // The genesis block can't be disconnected, so Core writes no undo data for it
+        if height == 0 {
+            return Ok(BlockUndo::default());
+        }
+        let record = self.chain_index.get(height).unwrap();
+        let offset = record.undo_offset.ok_or_else(|| {
+            let msg = format!("Block {} has no undo data, is the node pruned?", height);
+            OpError::new(OpErrorKind::ValidationError).join_msg(&msg)
+        })?;
+        let (blk_index, prev_hash) = (record.blk_index, record.prev_hash);
+        let is_last = self.is_last_in_file(blk_index, height);
+        let rev_file = self
+            .rev_files
+            .as_mut()
+            .unwrap()
+            .entry(blk_index)
+            .or_insert_with(|| RevFile::new(undo::rev_path(&self.blockchain_dir, blk_index)));
+        let undo = rev_file.read_undo(offset, &prev_hash)?;
+        if is_last {
+            rev_file.close();
+        }
+        undo.check(block)?;
+        Ok(undo)
+    }
+
+    /// Reads the block at the given height from the blk files
+    fn read_block(&mut self, height: u64) -> OpResult<Block> {
+        let (blk_index, data_offset) = self.block_location_or_err(height)?;
+        let block = ChainStorage::blk_file(&mut self.blk_files, blk_index)?
+            .read_block(data_offset, &self.coin, self.keep_raw)
+            .map_err(|err| err.at_height(height))?;
+        self.close_after(blk_index, height);
+        Ok(block)
+    }
 
-        // Check if blk file can be closed
-        if height == self.chain_index.max_height_by_blk(block_meta.blk_index) {
-            blk_file.close()
+    /// Returns the block at the given height from the prefetcher, after reading the next blocks ahead.
+    /// Requesting another height than the next one drops the blocks read ahead.
+    fn prefetch_block(&mut self, height: u64) -> OpResult<Block> {
+        let mut prefetcher = self.prefetcher.take().unwrap();
+        if !prefetcher.is_next(height) {
+            prefetcher.reset();
+        }
+        while prefetcher.has_capacity() {
+            let next = match prefetcher.last_requested() {
+                None => Some(height),
+                Some(last) if self.reverse => last.checked_sub(1),
+                Some(last) => Some(last + 1),
+            };
+            // Blocks above the block index are fetched via RPC once they are reached
+            let next = match next.filter(|h| self.chain_index.get(*h).is_some()) {
+                Some(next) => next,
+                None => break,
+            };
+            match self.read_raw(next) {
+                Ok((offset, block_size, raw)) => prefetcher.submit(next, offset, block_size, raw),
+                Err(err) => prefetcher.submit_err(next, err),
+            }
+        }
+        let block = prefetcher.take();
+        self.prefetcher = Some(prefetcher);
+        block
+    }
+
+    /// Reads the serialized block at the given height, along with its offset and size
+    fn read_raw(&mut self, height: u64) -> OpResult<(u64, u32, Vec<u8>)> {
+        let (blk_index, data_offset) = self.block_location_or_err(height)?;
+        let (block_size, raw) = ChainStorage::blk_file(&mut self.blk_files, blk_index)?
+            .read_raw(data_offset)
+            .map_err(|err| err.at_height(height))?;
+        self.close_after(blk_index, height);
+        Ok((data_offset, block_size, raw))
+    }
+
+    fn block_location_or_err(&self, height: u64) -> OpResult<(u64, u64)> {
+        self.block_location(height).ok_or_else(|| {
+            let msg = format!("Block {} is not in the block index", height);
+            OpError::new(OpErrorKind::ValidationError).join_msg(&msg)
+        })
+    }
+
+    fn blk_file(blk_files: &mut HashMap<u64, BlkFile>, blk_index: u64) -> OpResult<&mut BlkFile> {
+        blk_files.get_mut(&blk_index).ok_or_else(|| {
+            let msg = format!("blk{:05}.dat not found", blk_index);
+            OpError::new(OpErrorKind::ValidationError).join_msg(&msg)
+        })
+    }
+
+    /// Closes the blk file if the block at the given height is the last one read from it
+    fn close_after(&mut self, blk_index: u64, height: u64) {
+        if self.is_last_in_file(blk_index, height) {
+            if let Some(blk_file) = self.blk_files.get_mut(&blk_index) {
+                blk_file.close()
+            }
+        }
+    }
+
+    /// Returns true if no more blocks are read from the given blk_index after the one at the given height
+    fn is_last_in_file(&self, blk_index: u64, height: u64) -> bool {
+        let last_height = if self.reverse {
+            self.chain_index.min_height_by_blk(blk_index)
+        } else {
+            self.chain_index.max_height_by_blk(blk_index)
+        };
+        height == last_height
+    }
+
+    /// Fetches a block above the block index from the node, None above its tip or without `--rpc-url`
+    fn get_rpc_block(&mut self, height: u64) -> OpResult<Option<Block>> {
+        let rpc = match self.rpc.as_ref() {
+            Some(rpc) if height <= self.max_height => rpc,
+            _ => return Ok(None),
+        };
+        if self.rev_files.is_some() {
+            let msg = format!(
+                "Block {} is not in the block index, the undo data is only read from the rev files",
+                height
+            );
+            return Err(OpError::new(OpErrorKind::ValidationError).join_msg(&msg));
+        }
+        let block = rpc
+            .block(height, &self.coin, self.keep_raw)
+            .map_err(|err| err.at_height(height))?;
+
+        // The node must continue the chain of the blk files
+        if height == self.chain_index.max_height() + 1 {
+            if let Some(tip) = self.chain_index.get(height - 1) {
+                if block.header.value.prev_hash != tip.block_hash {
+                    let msg = format!(
+                        "Block {} from RPC doesn't extend the blk files tip {}",
+                        block.hash(),
+                        tip.block_hash
+                    );
+                    return Err(OpError::new(OpErrorKind::ValidationError).join_msg(&msg));
+                }
+            }
         }
 
         if self.verify {
-            self.verify(&block, height).unwrap();
+            self.verify_or_report(&block, height)?;
         }
+        Ok(Some(block))
+    }
 
-        Some(block)
+    /// Verifies the block and logs the error with its location
+    fn verify_or_report(&self, block: &Block, height: u64) -> OpResult<()> {
+        let err = match self.verify(block, height) {
+            Ok(()) => return Ok(()),
+            Err(err) => err,
+        };
+        match self.block_location(height) {
+            Some((blk_index, offset)) => {
+                error!(target: "chain", "Block {} (blk{:05}.dat, offset {}) failed verification: {}", height, blk_index, offset, err)
+            }
+            None => error!(target: "chain", "Block {} failed verification: {}", height, err),
+        }
+        Err(err)
     }
 
-    /// Verifies the given block in a chain.
-    /// Panics if not valid
+    /// Verifies the block against the previous block of the main chain
     fn verify(&self, block: &Block, height: u64) -> OpResult<()> {
-        block.verify_merkle_root()?;
-        if height == 0 {
-            if block.header.hash != self.coin.genesis_hash {
+        let prev_hash = match height {
+            0 => None,
+            _ => Some(self.block_hash(height - 1)?.ok_or_else(|| {
                 let msg = format!(
-                    "Genesis block hash doesn't match!\n  -> expected: {}\n  -> got: {}\n",
-                    &self.coin.genesis_hash, &block.header.hash,
+                    "Block {} is neither in the block index nor known to the node",
+                    height - 1
                 );
-                return Err(OpError::new(OpErrorKind::ValidationError).join_msg(&msg));
-            }
-        } else {
-            let prev_hash = self
-                .chain_index
-                .get(height - 1)
-                .expect("unable to fetch prev block in chain index")
-                .block_hash;
-            if block.header.value.prev_hash != prev_hash {
+                OpError::new(OpErrorKind::ValidationError).join_msg(&msg)
+            })?),
+        };
+        verify_block(&self.coin, block, height, prev_hash.as_ref())
+    }
+}
+
+impl BlockSource for ChainStorage {
+    /// Returns the block at the given height, from the blk files or above them via RPC
+    fn get_block(&mut self, height: u64) -> OpResult<Option<Block>> {
+        if self.chain_index.get(height).is_none() {
+            return self.get_rpc_block(height);
+        }
+        let mut block = match self.prefetcher.is_some() {
+            true => self.prefetch_block(height)?,
+            false => self.read_block(height)?,
+        };
+        if self.verify {
+            self.verify_or_report(&block, height)?;
+        }
+        if self.rev_files.is_some() {
+            let undo = self.read_undo(&block, height).map_err(|err| {
+                let blk_index = self.chain_index.get(height).unwrap().blk_index;
                 let msg = format!(
-                    "prev_hash for block {} doesn't match!\n  -> expected: {}\n  -> got: {}\n",
-                    &block.header.hash, &block.header.value.prev_hash, &prev_hash
+                    "Unable to read the undo data of block {} from rev{:05}.dat: {}",
+                    height, blk_index, err.message
                 );
-                return Err(OpError::new(OpErrorKind::ValidationError).join_msg(&msg));
-            }
+                OpError {
+                    message: msg,
+                    ..err
+                }
+            })?;
+            block.undo = Some(undo);
+        }
+
+        Ok(Some(block))
+    }
+
+    /// Reads the block index and the blk files again to pick up blocks the node wrote since.
+    /// Blk files may have grown or been rewritten, so all block locations are replaced.
+    fn refresh(&mut self) -> OpResult<()> {
+        let blk_files = match &self.source {
+            Some(source) => BlkFile::from_remote(source)?,
+            None => BlkFile::from_path(self.blockchain_dir.as_path(), self.mmap)?,
+        };
+        let chain_index = ChainIndex::new(&self.blockchain_dir, &self.range)?;
+        self.max_height = ChainStorage::max_height_with_rpc(&chain_index, &self.rpc, &self.range)?;
+        self.chain_index = chain_index;
+        self.blk_files = blk_files;
+        if let Some(rev_files) = self.rev_files.as_mut() {
+            rev_files.clear();
+        }
+        if let Some(prefetcher) = self.prefetcher.as_mut() {
+            prefetcher.reset();
         }
         Ok(())
     }
 
-    pub(crate) fn max_height(&self) -> u64 {
-        self.chain_index.max_height()
+    /// Reads and verifies the block at the given height, returns the first error
+    fn validate_block(&mut self, height: u64) -> OpResult<()> {
+        let block = self.read_block(height)?;
+        self.verify(&block, height)
+    }
+
+    /// Returns the blk file index and the offset of the block at the given height
+    fn block_location(&self, height: u64) -> Option<(u64, u64)> {
+        self.chain_index
+            .get(height)
+            .map(|record| (record.blk_index, record.data_offset))
+    }
+
+    /// Returns the hash of the block at the given height, from the block index or via RPC above it
+    fn block_hash(&self, height: u64) -> OpResult<Option<sha256d::Hash>> {
+        match (self.chain_index.get(height), &self.rpc) {
+            (Some(record), _) => Ok(Some(record.block_hash)),
+            (None, Some(rpc)) if height <= self.max_height => {
+                parse_block_hash(&rpc.block_hash(height)?).map(Some)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn max_height(&self) -> u64 {
+        self.max_height
+    }
+
+    /// Returns the blocks of the index which are not part of the main chain
+    fn stale_blocks(&self) -> &[StaleBlock] {
+        self.chain_index.stale_blocks()
+    }
+
+    /// Reads the given stale block from the blk files
+    fn read_stale_block(&mut self, stale: &StaleBlock) -> OpResult<Block> {
+        let blk_file = ChainStorage::blk_file(&mut self.blk_files, stale.blk_index)?;
+        let block = blk_file
+            .read_block(stale.data_offset, &self.coin, self.keep_raw)
+            .map_err(|err| err.at_height(stale.height))?;
+        if block.hash() != stale.hash {
+            let msg = format!(
+                "Expected stale block {} at blk{:05}.dat offset {}, got {}",
+                stale.hash,
+                stale.blk_index,
+                stale.data_offset,
+                block.hash()
+            );
+            return Err(OpError::new(OpErrorKind::ValidationError).join_msg(&msg));
+        }
+        Ok(block)
+    }
+
+    /// Returns the number of discovered blk files
+    fn blk_file_count(&self) -> usize {
+        self.blk_files.len()
+    }
+}
+
+/// Parses a block hash returned by the node
+pub(crate) fn parse_block_hash(hash: &str) -> OpResult<sha256d::Hash> {
+    sha256d::Hash::from_str(hash).map_err(|err| {
+        let msg = format!("Invalid block hash `{}` from RPC: {}", hash, err);
+        OpError::new(OpErrorKind::RuntimeError).join_msg(&msg)
+    })
+}
+
+/// Verifies the merkle root, the proof of work and the link to the previous block,
+/// whose hash is required for all blocks but the genesis block
+pub(crate) fn verify_block(
+    coin: &CoinType,
+    block: &Block,
+    height: u64,
+    prev_hash: Option<&sha256d::Hash>,
+) -> OpResult<()> {
+    block.verify_merkle_root()?;
+    // The proof of work of AuxPow blocks is in the header of the parent block
+    if coin.sha256d_pow && block.aux_pow_extension.is_none() {
+        block.verify_pow()?;
+    }
+    if height == 0 {
+        if block.hash() != coin.genesis_hash {
+            let msg = format!(
+                "Genesis block hash doesn't match!\n  -> expected: {}\n  -> got: {}\n",
+                &coin.genesis_hash,
+                &block.hash(),
+            );
+            return Err(OpError::new(OpErrorKind::ValidationError).join_msg(&msg));
+        }
+    } else {
+        let prev_hash = prev_hash.expect("prev block hash of a non-genesis block");
+        if block.header.value.prev_hash != *prev_hash {
+            let msg = format!(
+                "prev_hash for block {} doesn't match!\n  -> expected: {}\n  -> got: {}\n",
+                &block.hash(),
+                &block.header.value.prev_hash,
+                &prev_hash
+            );
+            return Err(OpError::new(OpErrorKind::ValidationError).join_msg(&msg));
+        }
     }
+    Ok(())
 }
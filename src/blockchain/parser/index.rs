@@ -1,31 +1,52 @@
 use bitcoin::hashes::{sha256d, Hash};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::convert::TryInto;
 use std::fmt;
 use std::io::Cursor;
 use std::path::Path;
 
-use byteorder::ReadBytesExt;
 use rusty_leveldb::{LdbIterator, Options, DB};
 
-use crate::errors::OpResult;
-use crate::ParserOptions;
+use crate::blockchain::parser::reader::BlockchainRead;
+use crate::errors::{OpError, OpErrorKind, OpResult};
+use crate::BlockHeightRange;
 
 const BLOCK_VALID_CHAIN: u64 = 4;
 const BLOCK_HAVE_DATA: u64 = 8;
+const BLOCK_HAVE_UNDO: u64 = 16;
+// The block or one of its ancestors failed validation
+const BLOCK_FAILED_VALID: u64 = 32;
+const BLOCK_FAILED_CHILD: u64 = 64;
+// The lowest 3 bits of the status are the validity level
+const BLOCK_VALID_MASK: u64 = 7;
+
+/// Block of the index which is not part of the main chain
+#[derive(Clone, Debug)]
+pub struct StaleBlock {
+    pub height: u64,
+    pub hash: sha256d::Hash,
+    pub prev_hash: sha256d::Hash,
+    // Location of the block data
+    pub blk_index: u64,
+    pub data_offset: u64,
+}
 
 /// Holds the index of longest valid chain
 pub struct ChainIndex {
     max_height: u64,
     block_index: HashMap<u64, BlockIndexRecord>,
+    // Stale blocks with data in the blk files, ordered by height
+    stale_blocks: Vec<StaleBlock>,
     max_height_blk_index: HashMap<u64, u64>, // Maps blk_index to max_height found in the file
+    min_height_blk_index: HashMap<u64, u64>, // Maps blk_index to min_height found in the file
 }
 
 impl ChainIndex {
-    pub fn new(options: &ParserOptions) -> OpResult<Self> {
-        let path = options.blockchain_dir.join("index");
-        let mut block_index = get_block_index(&path)?;
+    pub fn new(blockchain_dir: &Path, range: &BlockHeightRange) -> OpResult<Self> {
+        let path = blockchain_dir.join("index");
+        let (mut block_index, mut stale_blocks) = get_block_index(&path)?;
         let mut max_height_blk_index = HashMap::new();
+        let mut min_height_blk_index = HashMap::new();
 
         for (height, index_record) in &block_index {
             match max_height_blk_index.get(&index_record.blk_index) {
@@ -37,27 +58,42 @@ impl ChainIndex {
                 }
                 _ => {}
             }
+            match min_height_blk_index.get(&index_record.blk_index) {
+                Some(cur_height) if height >= cur_height => {}
+                _ => {
+                    min_height_blk_index.insert(index_record.blk_index, *height);
+                }
+            }
         }
 
-        let min_height = options.range.start;
-        let max_known_height = *block_index.keys().max().unwrap();
-        let max_height = match options.range.end {
+        let min_height = range.start;
+        let max_known_height = match block_index.keys().max() {
+            Some(height) => *height,
+            None => {
+                let msg = format!("No blocks found in block index {}!", path.display());
+                return Err(OpError::new(OpErrorKind::RuntimeError).join_msg(&msg));
+            }
+        };
+        let max_height = match range.end {
             Some(height) if height < max_known_height => height,
             Some(_) | None => max_known_height,
         };
 
         // Filter to only keep relevant block index
-        if !options.range.is_default() {
+        if !range.is_default() {
             info!(target: "index", "Trimming block index from height {} to {} ...", min_height, max_height);
             block_index.retain(|height, _| {
                 *height >= min_height.saturating_sub(1) && *height <= max_height
             });
+            stale_blocks.retain(|block| block.height >= min_height && block.height <= max_height);
         }
 
         Ok(Self {
             max_height,
             block_index,
             max_height_blk_index,
+            min_height_blk_index,
+            stale_blocks,
         })
     }
 
@@ -75,6 +111,16 @@ impl ChainIndex {
     pub fn max_height_by_blk(&self, blk_index: u64) -> u64 {
         *self.max_height_blk_index.get(&blk_index).unwrap()
     }
+
+    /// Returns the minimum height that can be found in the given blk_index
+    pub fn min_height_by_blk(&self, blk_index: u64) -> u64 {
+        *self.min_height_blk_index.get(&blk_index).unwrap()
+    }
+
+    /// Returns the stale blocks within the parsed range
+    pub fn stale_blocks(&self) -> &[StaleBlock] {
+        &self.stale_blocks
+    }
 }
 
 /// Holds the metadata where the block data is stored,
@@ -82,7 +128,11 @@ impl ChainIndex {
 pub struct BlockIndexRecord {
     pub block_hash: sha256d::Hash,
     pub blk_index: u64,
-    pub data_offset: u64, // offset within the blk file
+    pub data_offset: u64,         // offset within the blk file
+    pub undo_offset: Option<u64>, // offset within the rev file, not set for pruned blocks and the genesis block
+    pub prev_hash: sha256d::Hash,
+    /// Timestamp of the block header
+    pub timestamp: u32,
     version: u64,
     height: u64,
     status: u64,
@@ -94,21 +144,35 @@ impl BlockIndexRecord {
         let mut reader = Cursor::new(values);
 
         let block_hash: [u8; 32] = key.try_into().expect("leveldb: malformed blockhash");
-        let version = read_varint(&mut reader)?;
-        let height = read_varint(&mut reader)?;
-        let status = read_varint(&mut reader)?;
-        let tx_count = read_varint(&mut reader)?;
-        let blk_index = read_varint(&mut reader)?;
-        let data_offset = read_varint(&mut reader)?;
+        let version = reader.read_core_varint()?;
+        let height = reader.read_core_varint()?;
+        let status = reader.read_core_varint()?;
+        let tx_count = reader.read_core_varint()?;
+        let blk_index = match status & (BLOCK_HAVE_DATA | BLOCK_HAVE_UNDO) {
+            0 => 0,
+            _ => reader.read_core_varint()?,
+        };
+        let data_offset = match status & BLOCK_HAVE_DATA {
+            0 => 0,
+            _ => reader.read_core_varint()?,
+        };
+        let undo_offset = match status & BLOCK_HAVE_UNDO {
+            0 => None,
+            _ => Some(reader.read_core_varint()?),
+        };
+        let header = reader.read_block_header()?;
 
         Ok(BlockIndexRecord {
             block_hash: sha256d::Hash::from_byte_array(block_hash),
+            prev_hash: header.prev_hash,
+            timestamp: header.timestamp,
             version,
             height,
             status,
             tx_count,
             blk_index,
             data_offset,
+            undo_offset,
         })
     }
 }
@@ -123,14 +187,17 @@ impl fmt::Debug for BlockIndexRecord {
             .field("n_tx", &self.tx_count)
             .field("n_file", &self.blk_index)
             .field("n_data_pos", &self.data_offset)
+            .field("n_undo_pos", &self.undo_offset)
             .finish()
     }
 }
 
-pub fn get_block_index(path: &Path) -> OpResult<HashMap<u64, BlockIndexRecord>> {
+/// Reads all block records and follows the previous block hashes from the highest block
+/// down to the genesis block. Returns the main chain by height and the remaining stale blocks.
+pub fn get_block_index(path: &Path) -> OpResult<(HashMap<u64, BlockIndexRecord>, Vec<StaleBlock>)> {
     info!(target: "index", "Reading index from {} ...", path.display());
 
-    let mut block_index = HashMap::with_capacity(900000);
+    let mut records = HashMap::with_capacity(900000);
     let mut db_iter = DB::open(path, Options::default())?.new_iter()?;
     let (mut key, mut value) = (vec![], vec![]);
 
@@ -139,37 +206,109 @@ pub fn get_block_index(path: &Path) -> OpResult<HashMap<u64, BlockIndexRecord>>
         if is_block_index_record(&key) {
             let record = BlockIndexRecord::from(&key[1..], &value)?;
             if record.status & (BLOCK_VALID_CHAIN | BLOCK_HAVE_DATA) > 0 {
-                block_index.insert(record.height, record);
+                records.insert(record.block_hash, record);
             }
         }
     }
-    info!(target: "index", "Got longest chain with {} blocks ...", block_index.len());
-    Ok(block_index)
-}
 
-#[inline]
-fn is_block_index_record(data: &[u8]) -> bool {
-    *data.first().unwrap() == b'b'
+    // Blocks marked as invalid by the node can't be the tip of the main chain.
+    // Competing tips at the same height are resolved by the higher validity level, then by hash
+    let connected = connected_blocks(&records);
+    let mut block_index = HashMap::with_capacity(records.len());
+    let mut next_hash = records
+        .values()
+        .filter(|r| r.status & (BLOCK_FAILED_VALID | BLOCK_FAILED_CHILD) == 0)
+        .filter(|r| connected.contains(&r.block_hash))
+        .max_by_key(|r| (r.height, r.status & BLOCK_VALID_MASK, r.block_hash))
+        .map(|r| r.block_hash);
+    while let Some(record) = next_hash.and_then(|hash| records.remove(&hash)) {
+        next_hash = Some(record.prev_hash);
+        block_index.insert(record.height, record);
+    }
+    if !block_index.is_empty() && !block_index.contains_key(&0) {
+        warn!(target: "index", "Main chain ends at height {}, the previous block is not in the index!",
+              block_index.keys().min().unwrap());
+    }
+
+    let mut stale_blocks: Vec<StaleBlock> = records
+        .into_values()
+        .filter(|r| r.status & BLOCK_HAVE_DATA > 0)
+        .map(|r| StaleBlock {
+            height: r.height,
+            hash: r.block_hash,
+            prev_hash: r.prev_hash,
+            blk_index: r.blk_index,
+            data_offset: r.data_offset,
+        })
+        .collect();
+    stale_blocks.sort_unstable_by_key(|b| (b.height, b.hash));
+    info!(target: "index", "Got longest chain with {} blocks and {} stale blocks ...",
+          block_index.len(), stale_blocks.len());
+    Ok((block_index, stale_blocks))
 }
 
-/// TODO: this is a wonky 1:1 translation from https://github.com/bitcoin/bitcoin
-/// It is NOT the same as CompactSize.
-fn read_varint(reader: &mut Cursor<&[u8]>) -> OpResult<u64> {
-    let mut n = 0;
-    loop {
-        let ch_data = reader.read_u8()?;
-        if n > u64::MAX >> 7 {
-            panic!("size too large");
+/// Number of blocks whose median timestamp is the median time past (BIP113)
+const MEDIAN_TIME_SPAN: usize = 11;
+
+/// Translates a time range (unix timestamps, inclusive) into the heights of the main chain.
+/// Block timestamps may decrease, so a block belongs to the range if its median time past,
+/// the median timestamp of the block and its 10 predecessors, is within it. That never decreases.
+/// The end stays open if no known block is past the end time.
+pub fn height_range_by_time(
+    blockchain_dir: &Path,
+    start_time: Option<i64>,
+    end_time: Option<i64>,
+) -> OpResult<BlockHeightRange> {
+    let (block_index, _) = get_block_index(&blockchain_dir.join("index"))?;
+    let mut window = VecDeque::with_capacity(MEDIAN_TIME_SPAN);
+    let (mut start, mut end) = (None, None);
+    let mut height = block_index.keys().min().copied().unwrap_or(0);
+    while let Some(record) = block_index.get(&height) {
+        if window.len() == MEDIAN_TIME_SPAN {
+            window.pop_front();
         }
-        n = (n << 7) | (ch_data & 0x7F) as u64;
-        if ch_data & 0x80 > 0 {
-            if n == u64::MAX {
-                panic!("size too large");
-            }
-            n += 1;
-        } else {
+        window.push_back(record.timestamp as i64);
+        let mut sorted: Vec<i64> = window.iter().copied().collect();
+        sorted.sort_unstable();
+        let median_time = sorted[sorted.len() / 2];
+
+        if start.is_none() && start_time.is_none_or(|time| median_time >= time) {
+            start = Some(height);
+        }
+        if matches!(end_time, Some(time) if median_time > time) {
+            end = Some(height.saturating_sub(1));
             break;
         }
+        height += 1;
+    }
+    let start = match start {
+        Some(start) if end.is_none_or(|end| start <= end) => start,
+        _ => {
+            return Err(OpError::new(OpErrorKind::InvalidArgsError)
+                .join_msg("No block of the main chain lies within the given dates"))
+        }
+    };
+    info!(target: "index", "Dates are translated to heights {}", BlockHeightRange { start, end });
+    BlockHeightRange::new(start, end)
+}
+
+/// Returns the blocks whose ancestors are all in the index down to the lowest height.
+/// While syncing the node downloads blocks ahead of the ones it is missing,
+/// a tip above such a gap would cut off the main chain below it.
+fn connected_blocks(records: &HashMap<sha256d::Hash, BlockIndexRecord>) -> HashSet<sha256d::Hash> {
+    let mut by_height: Vec<&BlockIndexRecord> = records.values().collect();
+    by_height.sort_unstable_by_key(|r| r.height);
+    let min_height = by_height.first().map_or(0, |r| r.height);
+    let mut connected = HashSet::with_capacity(records.len());
+    for record in by_height {
+        if record.height == min_height || connected.contains(&record.prev_hash) {
+            connected.insert(record.block_hash);
+        }
     }
-    Ok(n)
+    connected
+}
+
+#[inline]
+fn is_block_index_record(data: &[u8]) -> bool {
+    *data.first().unwrap() == b'b'
 }
@@ -0,0 +1,168 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use bitcoin::hashes::sha256d;
+
+use crate::blockchain::parser::rpc::RpcClient;
+use crate::blockchain::parser::types::CoinType;
+use crate::blockchain::proto::block::Block;
+use crate::blockchain::proto::tx::OutpointKey;
+use crate::common::utils;
+use crate::errors::OpResult;
+
+/// Reports transactions which were seen in the mempool of the node but got replaced on-chain
+/// by a different transaction spending the same outputs (e.g. RBF), for `--include-mempool-conflicts`.
+/// The mempool is polled along with the new blocks, so replacements happening between two polls are missed.
+pub struct MempoolConflicts {
+    rpc: RpcClient,
+    writer: BufWriter<File>,
+
+    // key: spent outpoint (txid + index), value: txid of the mempool transaction spending it.
+    // Transactions which left the mempool are kept, as their replacement might confirm later.
+    spends: HashMap<OutpointKey, sha256d::Hash>,
+    seen: HashSet<sha256d::Hash>,
+    n_conflicts: u64,
+}
+
+impl MempoolConflicts {
+    /// Writes the csv header to the given file
+    pub fn new(rpc: RpcClient, path: &Path) -> OpResult<Self> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(
+            format!("{};{};{}\n", "height", "replaced_txid", "confirming_txid").as_bytes(),
+        )?;
+        writer.flush()?;
+        Ok(Self {
+            rpc,
+            writer,
+            spends: HashMap::new(),
+            seen: HashSet::new(),
+            n_conflicts: 0,
+        })
+    }
+
+    /// Records the inputs of all transactions which entered the mempool since the last poll
+    pub fn refresh(&mut self, coin: &CoinType) -> OpResult<()> {
+        let mut n_new = 0;
+        for txid in self.rpc.mempool_txids()? {
+            if self.seen.contains(&txid) {
+                continue;
+            }
+            let tx = match self.rpc.mempool_tx(&txid, coin) {
+                Ok(tx) => tx,
+                Err(err) => {
+                    debug!(target: "mempool", "Skipping mempool transaction {}: {}", txid, err);
+                    continue;
+                }
+            };
+            for input in &tx.inputs {
+                self.spends.insert(input.outpoint.key(), txid);
+            }
+            self.seen.insert(txid);
+            n_new += 1;
+        }
+        debug!(target: "mempool", "Recorded {} new mempool transactions ({} spent outputs tracked)",
+               n_new, self.spends.len());
+        Ok(())
+    }
+
+    /// Reports each mempool transaction whose inputs are spent by a different transaction of the block.
+    /// A replaced transaction is reported once per confirming transaction.
+    pub fn check(&mut self, block: &Block, height: u64) -> OpResult<()> {
+        for tx in block.txs.iter().filter(|tx| !tx.value.is_coinbase()) {
+            let mut replaced = Vec::new();
+            for input in &tx.value.inputs {
+                match self.spends.remove(&input.outpoint.key()) {
+                    Some(txid) if txid != tx.hash && !replaced.contains(&txid) => {
+                        replaced.push(txid)
+                    }
+                    _ => (),
+                }
+            }
+            for txid in replaced {
+                warn!(target: "mempool", "Mempool transaction {} was replaced by {} at height {}",
+                      utils::hash_to_string(&txid), utils::hash_to_string(&tx.hash), height);
+                self.writer.write_all(
+                    format!(
+                        "{};{};{}\n",
+                        height,
+                        utils::hash_to_string(&txid),
+                        utils::hash_to_string(&tx.hash)
+                    )
+                    .as_bytes(),
+                )?;
+                self.n_conflicts += 1;
+            }
+        }
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    pub fn n_conflicts(&self) -> u64 {
+        self.n_conflicts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::parser::rpc;
+    use crate::blockchain::parser::types::Bitcoin;
+    use crate::blockchain::proto::tx::EvaluatedTx;
+    use crate::blockchain::proto::Hashed;
+    use crate::common::testutils;
+    use bitcoin::hashes::Hash;
+    use std::fs;
+
+    #[test]
+    fn test_mempool_conflicts() {
+        let funding = testutils::block(
+            sha256d::Hash::all_zeros(),
+            0,
+            vec![testutils::coinbase(
+                1,
+                &[
+                    (100000, testutils::p2pkh_script(1)),
+                    (100000, testutils::p2pkh_script(2)),
+                ],
+            )],
+        );
+        let cb_txid = funding.txs[0].hash;
+        // The first transaction is replaced with a higher fee, the second one confirms unchanged
+        let original = || testutils::tx(&[(cb_txid, 0)], &[(99000, testutils::p2pkh_script(3))]);
+        let unchanged = || testutils::tx(&[(cb_txid, 1)], &[(99000, testutils::p2pkh_script(4))]);
+        let replacement = testutils::tx(&[(cb_txid, 0)], &[(95000, testutils::p2pkh_script(3))]);
+        let block = testutils::block(
+            funding.header.hash,
+            600,
+            vec![
+                testutils::coinbase(2, &[(5000, testutils::p2pkh_script(0))]),
+                replacement,
+                unchanged(),
+            ],
+        );
+        let original_txid = Hashed::double_sha256(EvaluatedTx::from(original())).hash;
+
+        let url = rpc::tests::serve_with_mempool(&[], vec![original(), unchanged()]);
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("conflicts.csv");
+        let mut conflicts =
+            MempoolConflicts::new(RpcClient::new(&url, None).unwrap(), &path).unwrap();
+        conflicts.refresh(&CoinType::from(Bitcoin)).unwrap();
+        conflicts.check(&funding, 1).unwrap();
+        conflicts.check(&block, 2).unwrap();
+
+        assert_eq!(conflicts.n_conflicts(), 1);
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            format!(
+                "height;replaced_txid;confirming_txid\n2;{};{}\n",
+                utils::hash_to_string(&original_txid),
+                utils::hash_to_string(&block.txs[1].hash)
+            )
+        );
+        assert!(conflicts.spends.is_empty());
+    }
+}
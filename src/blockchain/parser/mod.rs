@@ -1,113 +1,2055 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use std::thread;
 use std::time::{Duration, Instant};
 
-use crate::blockchain::parser::chain::ChainStorage;
+use bitcoin::hashes::sha256d;
+use serde_json::json;
+
+use crate::blockchain::parser::blocksource::BlockSource;
+use crate::blockchain::parser::index::StaleBlock;
+use crate::blockchain::parser::mempool::MempoolConflicts;
+use crate::blockchain::parser::progress::{Progress, Status};
+use crate::blockchain::parser::state::ParserState;
+use crate::blockchain::parser::types::CoinType;
 use crate::blockchain::proto::block::Block;
-use crate::callbacks::Callback;
-use crate::errors::OpResult;
+use crate::callbacks::{common, Callback, Control};
+use crate::common::bench::{self, Stage};
+use crate::common::errorlog;
+use crate::common::utils;
+use crate::errors::{OpError, OpErrorKind, OpResult};
 use crate::ParserOptions;
 
 mod blkfile;
+pub mod blocksource;
 pub mod chain;
-mod index;
+pub mod index;
+pub mod mempool;
+mod prefetch;
+pub mod progress;
 pub mod reader;
+pub mod remote;
+pub mod rpc;
+pub mod snapshot;
+pub mod state;
+pub mod subsidy;
 pub mod types;
 
 /// Small struct to hold statistics together
 struct WorkerStats {
     pub started_at: Instant,
-    pub last_log: Instant,
-    pub last_height: u64,
-    pub measure_frame: Duration,
+    pub start_height: u64,
+    pub n_blocks: u64,
+    pub n_txs: u64,
+    pub n_bytes: u64,
 }
 
 impl WorkerStats {
     fn new(start_range: u64) -> Self {
         Self {
             started_at: Instant::now(),
-            last_log: Instant::now(),
-            last_height: start_range,
-            measure_frame: Duration::from_secs(10),
+            start_height: start_range,
+            n_blocks: 0,
+            n_txs: 0,
+            n_bytes: 0,
+        }
+    }
+}
+
+/// Work of a run as reported by `--dry-run`
+#[derive(Debug)]
+pub struct ParsePlan {
+    pub coin: String,
+    pub version_id: u8,
+    pub blockchain_dir: PathBuf,
+    pub blk_files: usize,
+    pub start_height: u64,
+    pub end_height: u64,
+    pub callback: String,
+    pub dump_folder: Option<PathBuf>,
+}
+
+impl ParsePlan {
+    fn new(
+        options: &ParserOptions,
+        chain_storage: &dyn BlockSource,
+        state_path: Option<&PathBuf>,
+    ) -> Self {
+        // Incremental runs continue after the stored tip
+        let start_height = match state_path.and_then(|path| ParserState::load(path).ok().flatten())
+        {
+            Some(state) => state.height + 1,
+            None => options.range.start,
+        };
+        Self {
+            coin: options.coin.name.clone(),
+            version_id: options.coin.version_id,
+            blockchain_dir: options.blockchain_dir.clone(),
+            blk_files: chain_storage.blk_file_count(),
+            start_height,
+            end_height: chain_storage.max_height(),
+            callback: options.callback_name.clone(),
+            dump_folder: options.callback.dump_folder().map(|p| p.to_path_buf()),
         }
     }
+
+    /// Number of blocks which would be processed
+    pub fn block_count(&self) -> u64 {
+        (self.end_height + 1).saturating_sub(self.start_height)
+    }
+}
+
+impl fmt::Display for ParsePlan {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let dump_folder = match &self.dump_folder {
+            Some(folder) => folder.display().to_string(),
+            None => String::from("-"),
+        };
+        write!(
+            f,
+            "\t-> coin:           {} (version id: {:#04x})\n\
+             \t-> blockchain dir: {}\n\
+             \t-> blk files:      {}\n\
+             \t-> blocks:         {} (height {} to {})\n\
+             \t-> callback:       {}\n\
+             \t-> dump folder:    {}",
+            self.coin,
+            self.version_id,
+            self.blockchain_dir.display(),
+            self.blk_files,
+            self.block_count(),
+            self.start_height,
+            self.end_height,
+            self.callback,
+            dump_folder
+        )
+    }
 }
 
 pub struct BlockchainParser {
-    chain_storage: ChainStorage, // Hash storage with the longest chain
-    stats: WorkerStats,          // struct for thread management & statistics
+    chain_storage: Box<dyn BlockSource>, // Blocks of the longest chain
+    stats: WorkerStats,                  // struct for thread management & statistics
+    progress: Progress,
     callback: Box<dyn Callback>,
+    coin: CoinType,
     cur_height: u64,
+    last_hash: Option<sha256d::Hash>,
+    state_path: Option<PathBuf>, // Only set for incremental runs
+    dry_run_plan: Option<ParsePlan>,
+    validate_only: bool,
+    reverse: bool,
+    range_start: u64,
+    callback_name: String,
+    snapshot_interval: Option<u64>,
+    flush_interval: Option<u64>,
+    checkpoint_interval: Option<u64>,
+    bench_report: Option<PathBuf>,
+    watch_interval: Option<Duration>,
+    watch_timeout: Option<Duration>,
+    mempool_conflicts: Option<MempoolConflicts>,
 }
 
 impl BlockchainParser {
     /// Instantiates a new Parser.
-    pub fn new(options: ParserOptions, chain_storage: ChainStorage) -> Self {
+    pub fn new(options: ParserOptions, chain_storage: Box<dyn BlockSource>) -> Self {
         info!(target: "parser", "Parsing {} blockchain ...", options.coin.name);
+        let state_path = match options.callback.dump_folder() {
+            Some(folder) if options.incremental => Some(ParserState::path(folder, &options.coin)),
+            _ => None,
+        };
+        let dry_run_plan = options
+            .dry_run
+            .then(|| ParsePlan::new(&options, chain_storage.as_ref(), state_path.as_ref()));
+        if options.bench_report.is_some() {
+            bench::enable();
+        }
+        utils::set_hash_endian(options.hash_endian);
+        utils::set_atomic_rename(options.atomic_rename);
+        utils::set_filename_template(options.filename_template);
+        utils::set_output_config(options.output);
         Self {
             chain_storage,
             stats: WorkerStats::new(options.range.start),
+            progress: Progress::new(options.progress_json),
             callback: options.callback,
+            coin: options.coin,
             cur_height: options.range.start,
+            last_hash: None,
+            state_path,
+            dry_run_plan,
+            validate_only: options.validate_only,
+            reverse: options.reverse,
+            range_start: options.range.start,
+            callback_name: options.callback_name,
+            snapshot_interval: options.snapshot_interval,
+            flush_interval: options.flush_interval,
+            checkpoint_interval: options.checkpoint_interval,
+            bench_report: options.bench_report,
+            watch_interval: options.watch_interval,
+            watch_timeout: options.watch_timeout,
+            mempool_conflicts: options.mempool_conflicts,
         }
     }
 
+    /// Loads the blockchain data of the options and runs the callback over the selected blocks.
+    /// Returns the callback, so a program embedding the parser can read its results.
+    pub fn run(
+        mut options: ParserOptions,
+        callback: Box<dyn Callback>,
+    ) -> OpResult<Box<dyn Callback>> {
+        options.callback = callback;
+        let chain_storage = blocksource::open(&options)?;
+        let mut parser = BlockchainParser::new(options, chain_storage);
+        parser.start()?;
+        Ok(parser.callback)
+    }
+
+    /// Parses the blocks, the final `--progress-json` status tells whether the run completed or failed
     pub fn start(&mut self) -> OpResult<()> {
+        let result = self.parse();
+        if self.dry_run_plan.is_none() {
+            // Forward runs stop one above the last processed block
+            let height = match self.reverse || self.validate_only {
+                true => self.cur_height,
+                false => self.cur_height.saturating_sub(1),
+            };
+            let counters = self.counters();
+            let error = result.as_ref().err().map(|err| err.to_string());
+            self.progress
+                .finish(&self.status(height), &counters, error)?;
+        }
+        result
+    }
+
+    fn parse(&mut self) -> OpResult<()> {
+        if let Some(plan) = &self.dry_run_plan {
+            info!(target: "parser", "Dry run, no blocks are processed:\n{}", plan);
+            return Ok(());
+        }
+        if self.validate_only {
+            return self.validate();
+        }
+        if self.reverse {
+            return self.start_reverse();
+        }
         debug!(target: "parser", "Starting worker ...");
 
+        if self.state_path.is_some() && !self.resume()? {
+            return Ok(());
+        }
+        self.on_start(self.cur_height)?;
+        self.refresh_mempool();
+        'parse: loop {
+            while let Some(block) = self.next_block()? {
+                let control = self.on_block(&block, self.cur_height)?;
+                self.last_hash = Some(block.hash());
+                if matches!(self.checkpoint_interval, Some(n) if self.stats.n_blocks.is_multiple_of(n))
+                {
+                    self.checkpoint(self.cur_height)?;
+                }
+                self.cur_height += 1;
+                if control == Control::Stop {
+                    break 'parse;
+                }
+            }
+            if !self.wait_for_blocks()? {
+                break;
+            }
+        }
+        let end_height = self.cur_height.saturating_sub(1);
+        self.on_complete(end_height)?;
+        self.write_summary(end_height)?;
+        self.write_bench_report()?;
+        self.save_state(end_height)
+    }
+
+    /// Processes the main chain from the tip down to the start height.
+    /// on_start() receives the tip and on_complete() the lowest processed height.
+    fn start_reverse(&mut self) -> OpResult<()> {
+        if !self.callback.supports_reverse() {
+            let msg = format!(
+                "{} depends on previous blocks and can't process them in reverse order",
+                self.callback_name
+            );
+            return Err(OpError::new(OpErrorKind::InvalidArgsError).join_msg(&msg));
+        }
+        debug!(target: "parser", "Starting worker in reverse ...");
+
+        self.cur_height = self.chain_storage.max_height();
         self.on_start(self.cur_height)?;
-        while let Some(block) = self.chain_storage.get_block(self.cur_height) {
-            self.on_block(&block, self.cur_height)?;
-            self.cur_height += 1;
+        let mut end_height = self.cur_height;
+        while self.cur_height >= self.range_start {
+            let block = match self.next_block()? {
+                Some(block) => block,
+                None => break,
+            };
+            let control = self.on_block(&block, self.cur_height)?;
+            end_height = self.cur_height;
+            if self.cur_height == 0 || control == Control::Stop {
+                break;
+            }
+            self.cur_height -= 1;
+        }
+        self.on_complete(end_height)?;
+        self.write_summary(end_height)?;
+        self.write_bench_report()
+    }
+
+    /// Reads the block at the current height, which is attached to all warnings and errors until the next one
+    fn next_block(&mut self) -> OpResult<Option<Block>> {
+        errorlog::set_height(Some(self.cur_height));
+        // Stops before the callback completes, so it doesn't publish output from corrupted blocks
+        self.chain_storage.get_block(self.cur_height)
+    }
+
+    /// Writes the run statistics along with the callback totals to `summary.json` in the dump folder.
+    /// Incremental runs overwrite the summary of the previous run.
+    fn write_summary(&self, end_height: u64) -> OpResult<()> {
+        let folder = match self.callback.dump_folder() {
+            Some(folder) => folder,
+            None => return Ok(()),
+        };
+        let summary = json!({
+            "coin": self.coin.name,
+            "network": format!("{:#010x}", self.coin.magic),
+            "start_height": self.stats.start_height,
+            "end_height": end_height,
+            "blocks_processed": self.stats.n_blocks,
+            "transactions_processed": self.stats.n_txs,
+            "elapsed_secs": (Instant::now() - self.stats.started_at).as_secs_f64(),
+            "callback": self.callback.summary(),
+        });
+        let path = folder.join("summary.json");
+        fs::write(&path, serde_json::to_string_pretty(&summary).unwrap())?;
+        debug!(target: "parser", "Wrote summary to {}", path.display());
+        Ok(())
+    }
+
+    /// Logs the throughput and the time spent per stage and writes them as JSON to `--bench-report`.
+    /// Stages which run in parallel report the time summed over all threads.
+    fn write_bench_report(&self) -> OpResult<()> {
+        let path = match &self.bench_report {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let elapsed = (Instant::now() - self.stats.started_at).as_secs_f64();
+        let per_sec = |value: f64| if elapsed > 0.0 { value / elapsed } else { 0.0 };
+        let mut stages = serde_json::Map::new();
+        let mut stage_lines = String::new();
+        for stage in bench::STAGES {
+            let secs = bench::elapsed(stage).as_secs_f64();
+            stages.insert(String::from(stage.name()), json!(secs));
+            stage_lines.push_str(&format!("\n\t-> {:13} {:.3}s", stage.name(), secs));
+        }
+        let report = json!({
+            "blocks": self.stats.n_blocks,
+            "transactions": self.stats.n_txs,
+            "bytes": self.stats.n_bytes,
+            "elapsed_secs": elapsed,
+            "blocks_per_sec": per_sec(self.stats.n_blocks as f64),
+            "mb_per_sec": per_sec(self.stats.n_bytes as f64 / 1_000_000.0),
+            "transactions_per_sec": per_sec(self.stats.n_txs as f64),
+            "stages_secs": stages,
+        });
+        info!(target: "parser", "Benchmark: {:.2} blocks/s, {:.2} MB/s, {:.2} transactions/s{}",
+              per_sec(self.stats.n_blocks as f64), per_sec(self.stats.n_bytes as f64 / 1_000_000.0),
+              per_sec(self.stats.n_txs as f64), stage_lines);
+        fs::write(path, serde_json::to_string_pretty(&report).unwrap())?;
+        debug!(target: "parser", "Wrote benchmark report to {}", path.display());
+        Ok(())
+    }
+
+    /// Reads and verifies all blocks without running the callback.
+    /// Every error is logged with its location, fails if there is at least one.
+    fn validate(&mut self) -> OpResult<()> {
+        let start_height = self.cur_height;
+        let end_height = self.chain_storage.max_height();
+        info!(target: "parser", "Validating blocks from height {} to {} ...", start_height, end_height);
+        self.stats.started_at = Instant::now();
+        self.progress.restart();
+        self.progress
+            .report(&self.status(start_height), &[], false)?;
+
+        let mut n_errors = 0;
+        for height in start_height..=end_height {
+            errorlog::set_height(Some(height));
+            if let Err(err) = self.chain_storage.validate_block(height) {
+                match self.chain_storage.block_location(height) {
+                    Some((blk_index, offset)) => {
+                        error!(target: "parser", "Block {} (blk{:05}.dat, offset {}): {}", height, blk_index, offset, err)
+                    }
+                    None => error!(target: "parser", "Block {}: {}", height, err),
+                }
+                n_errors += 1;
+            }
+            self.cur_height = height;
+            self.stats.n_blocks += 1;
+            self.report_progress(height, true)?;
+        }
+        errorlog::set_height(None);
+
+        let n_blocks = (end_height + 1).saturating_sub(start_height);
+        if n_errors > 0 {
+            let msg = format!("{} of {} blocks failed validation!", n_errors, n_blocks);
+            return Err(OpError::new(OpErrorKind::ValidationError).join_msg(&msg));
+        }
+        info!(target: "parser", "Done. Validated {} blocks in {:.2} minutes, no errors found.",
+              n_blocks, (Instant::now() - self.stats.started_at).as_secs_f32() / 60.0);
+        Ok(())
+    }
+
+    /// Continues right after the tip of the last incremental run, if there is one.
+    /// Returns false if there are no new blocks to process.
+    fn resume(&mut self) -> OpResult<bool> {
+        let state_path = self.state_path.as_ref().unwrap();
+        let state = match ParserState::load(state_path)? {
+            Some(state) => state,
+            None => {
+                info!(target: "parser", "No previous run found in {}, starting from height {} ...",
+                      state_path.display(), self.cur_height);
+                return Ok(true);
+            }
+        };
+        if state.height > self.chain_storage.max_height() {
+            info!(target: "parser", "Last run already processed blocks up to height {}.", state.height);
+            return Ok(false);
         }
-        self.on_complete(self.cur_height.saturating_sub(1))
+        match self.chain_storage.block_hash(state.height)? {
+            Some(hash) if hash == state.block_hash => {}
+            _ => {
+                let msg = format!(
+                    "Stored tip {} at height {} is not part of the main chain anymore. \
+                     Remove {} to start over.",
+                    &state.block_hash,
+                    state.height,
+                    state_path.display()
+                );
+                return Err(OpError::new(OpErrorKind::ValidationError).join_msg(&msg));
+            }
+        }
+        if state.height == self.chain_storage.max_height() {
+            info!(target: "parser", "No new blocks since last run at height {}.", state.height);
+            return Ok(false);
+        }
+        let folder = self.callback.dump_folder().unwrap().to_path_buf();
+        self.callback.load_state(&folder)?;
+        self.cur_height = state.height + 1;
+        info!(target: "parser", "Resuming after last run at height {} ...", state.height);
+        Ok(true)
+    }
+
+    /// Polls the block index and the blk files every `--watch` interval until the node wrote
+    /// blocks above the processed tip. Returns false without `--watch`, once `--watch-timeout`
+    /// passed without new blocks or if the processed tip was replaced by a reorg.
+    fn wait_for_blocks(&mut self) -> OpResult<bool> {
+        let interval = match self.watch_interval {
+            Some(interval) => interval,
+            None => return Ok(false),
+        };
+        info!(target: "parser", "Waiting for blocks from height {} ...", self.cur_height);
+        let waiting_since = Instant::now();
+        loop {
+            if matches!(self.watch_timeout, Some(timeout) if waiting_since.elapsed() >= timeout) {
+                info!(target: "parser", "No new blocks within {:.1}s, stopping to watch.",
+                      waiting_since.elapsed().as_secs_f32());
+                return Ok(false);
+            }
+            thread::sleep(interval);
+            // The node might hold the index lock or be in the middle of writing a blk file
+            if let Err(err) = self.chain_storage.refresh() {
+                warn!(target: "parser", "Unable to read blockchain data, retrying: {}", err);
+                continue;
+            }
+            self.refresh_mempool();
+            if let Some(last_hash) = self.last_hash {
+                let tip_height = self.cur_height - 1;
+                let tip_hash = match self.chain_storage.block_hash(tip_height) {
+                    Ok(hash) => hash,
+                    Err(err) => {
+                        warn!(target: "parser", "Unable to read the hash of block {}, retrying: {}", tip_height, err);
+                        continue;
+                    }
+                };
+                if tip_hash != Some(last_hash) {
+                    error!(target: "parser", "Processed block {} at height {} was replaced by a reorg, \
+                           stopping to watch.", last_hash, tip_height);
+                    if self.callback.needs_orphan_blocks() {
+                        let replaced = self.replaced_blocks(last_hash);
+                        self.on_orphan_blocks(&replaced)?;
+                    }
+                    return Ok(false);
+                }
+            }
+            if self.chain_storage.max_height() >= self.cur_height {
+                return Ok(true);
+            }
+        }
+    }
+
+    /// Polls the mempool for `--include-mempool-conflicts`, failures don't interrupt the parsing
+    fn refresh_mempool(&mut self) {
+        if let Some(conflicts) = &mut self.mempool_conflicts {
+            if let Err(err) = conflicts.refresh(&self.coin) {
+                warn!(target: "parser", "Unable to poll the mempool: {}", err);
+            }
+        }
+    }
+
+    /// Stores the processed tip along with the callback state for the next incremental run.
+    /// The callback state is written first, an interruption in between replays some blocks on it,
+    /// which doesn't change an UTXO set.
+    fn save_state(&self, height: u64) -> OpResult<()> {
+        let (state_path, block_hash) = match (&self.state_path, self.last_hash) {
+            (Some(path), Some(hash)) => (path, hash),
+            _ => return Ok(()),
+        };
+        let folder = self.callback.dump_folder().unwrap();
+        self.callback.save_state(folder)?;
+        ParserState { height, block_hash }.save(state_path)?;
+        debug!(target: "parser", "Saved state at height {} to {}", height, state_path.display());
+        Ok(())
+    }
+
+    /// Saves the state every `--checkpoint-interval` blocks of a `--resume` run,
+    /// so an interrupted run continues right after the last checkpoint.
+    fn checkpoint(&mut self, height: u64) -> OpResult<()> {
+        self.callback.flush()?;
+        self.save_state(height)?;
+        info!(target: "parser", "Saved checkpoint at height {}.", height);
+        Ok(())
     }
 
     /// Returns number of remaining blocks
     pub fn remaining(&self) -> u64 {
+        if self.reverse {
+            return self.cur_height.saturating_sub(self.range_start);
+        }
         self.chain_storage
             .max_height()
             .saturating_sub(self.cur_height)
     }
 
     /// Triggers the on_start() callback and initializes state.
+    /// Passes the stale blocks which haven't been reported by a previous incremental run.
     fn on_start(&mut self, height: u64) -> OpResult<()> {
         let now = Instant::now();
         self.stats.started_at = now;
-        self.stats.last_log = now;
+        self.stats.start_height = height;
+        self.progress.restart();
+        self.progress.report(&self.status(height), &[], false)?;
         info!(target: "parser", "Processing blocks starting from height {} ...", height);
         self.callback.on_start(height)?;
         trace!(target: "parser", "on_start() called");
+
+        let min_height = if self.reverse {
+            self.range_start
+        } else {
+            height
+        };
+        let stale_blocks: Vec<StaleBlock> = self
+            .chain_storage
+            .stale_blocks()
+            .iter()
+            .filter(|block| block.height >= min_height)
+            .cloned()
+            .collect();
+        self.callback.on_stale_blocks(&stale_blocks)?;
+        trace!(target: "parser", "on_stale_blocks() called with {} blocks", stale_blocks.len());
+        if self.callback.needs_orphan_blocks() {
+            self.on_orphan_blocks(&stale_blocks)?;
+        }
         Ok(())
     }
 
+    /// Reads the given stale blocks and passes them to on_orphan_block().
+    /// Blocks which can't be read, e.g. as the node only wrote a part of them, are skipped.
+    fn on_orphan_blocks(&mut self, stale_blocks: &[StaleBlock]) -> OpResult<()> {
+        for stale in stale_blocks {
+            match self.chain_storage.read_stale_block(stale) {
+                Ok(block) => self.callback.on_orphan_block(&block, stale.height)?,
+                Err(err) => {
+                    warn!(target: "parser", "Skipping stale block {} at height {}: {}", stale.hash, stale.height, err)
+                }
+            }
+        }
+        trace!(target: "parser", "on_orphan_block() called for {} blocks", stale_blocks.len());
+        Ok(())
+    }
+
+    /// Returns the processed blocks which got replaced by a reorg, from the tip downward.
+    /// They are stale blocks of the refreshed index, down to the one extending the new main chain.
+    fn replaced_blocks(&self, last_hash: sha256d::Hash) -> Vec<StaleBlock> {
+        let by_hash: HashMap<sha256d::Hash, &StaleBlock> = self
+            .chain_storage
+            .stale_blocks()
+            .iter()
+            .map(|block| (block.hash, block))
+            .collect();
+        let mut replaced = Vec::new();
+        let mut next = by_hash.get(&last_hash);
+        while let Some(block) = next {
+            replaced.push((*block).clone());
+            next = by_hash.get(&block.prev_hash);
+        }
+        replaced
+    }
+
     /// Triggers the on_block() callback and updates statistics.
-    fn on_block(&mut self, block: &Block, height: u64) -> OpResult<()> {
-        self.callback.on_block(block, height)?;
+    /// Every `--snapshot-interval` blocks on_interval() and every `--flush-interval` blocks
+    /// flush() is triggered as well.
+    fn on_block(&mut self, block: &Block, height: u64) -> OpResult<Control> {
+        let control = bench::time(Stage::Callback, || self.callback.on_block(block, height))?;
+        if let Some(conflicts) = &mut self.mempool_conflicts {
+            conflicts.check(block, height)?;
+        }
         trace!(target: "parser", "on_block(height={}) called", height);
-        if self.callback.show_progress() {
-            self.print_progress(height);
+        self.stats.n_blocks += 1;
+        self.stats.n_txs += block.tx_count.value;
+        self.stats.n_bytes += block.size as u64;
+        if matches!(self.snapshot_interval, Some(n) if self.stats.n_blocks.is_multiple_of(n)) {
+            self.callback.on_interval(height)?;
+            trace!(target: "parser", "on_interval(height={}) called", height);
         }
-        Ok(())
+        if matches!(self.flush_interval, Some(n) if self.stats.n_blocks.is_multiple_of(n)) {
+            self.callback.flush()?;
+            trace!(target: "parser", "flush(height={}) called", height);
+        }
+        self.report_progress(height, self.callback.show_progress())?;
+        if control == Control::Stop {
+            info!(target: "parser", "{} requested to stop at height {}.", self.callback_name, height);
+        }
+        Ok(control)
     }
 
     /// Triggers the on_complete() callback and updates statistics.
     fn on_complete(&mut self, height: u64) -> OpResult<()> {
-        info!(target: "parser", "Done. Processed blocks up to height {} in {:.2} minutes.",
-        height, (Instant::now() - self.stats.started_at).as_secs_f32() / 60.0);
+        let direction = if self.reverse { "down" } else { "up" };
+        info!(target: "parser", "Done. Processed blocks {} to height {} in {:.2} minutes.",
+        direction, height, (Instant::now() - self.stats.started_at).as_secs_f32() / 60.0);
+        if let Some(conflicts) = &self.mempool_conflicts {
+            info!(target: "parser", "Found {} replaced mempool transactions.", conflicts.n_conflicts());
+        }
 
+        errorlog::set_height(None);
         self.callback.on_complete(height)?;
+        common::compress_outputs()?;
         trace!(target: "parser", "on_complete() called");
         Ok(())
     }
 
-    fn print_progress(&mut self, height: u64) {
-        let now = Instant::now();
-        let blocks_speed = (height - self.stats.last_height) / self.stats.measure_frame.as_secs();
+    fn status(&self, height: u64) -> Status {
+        Status {
+            height,
+            end_height: match self.reverse {
+                true => self.range_start,
+                false => self.chain_storage.max_height(),
+            },
+            remaining: self.remaining(),
+            n_blocks: self.stats.n_blocks,
+            n_txs: self.stats.n_txs,
+        }
+    }
+
+    fn counters(&self) -> Vec<(&'static str, u64)> {
+        self.callback
+            .metrics()
+            .map_or_else(Vec::new, |metrics| metrics.counters())
+    }
+
+    /// Logs the progress and updates the `--progress-json` status once per frame
+    fn report_progress(&mut self, height: u64, log: bool) -> OpResult<()> {
+        if !self.progress.is_due() {
+            return Ok(());
+        }
+        let counters = self.counters();
+        self.progress.report(&self.status(height), &counters, log)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::parser::blkfile::BlkFile;
+    use crate::blockchain::parser::chain::ChainStorage;
+    use crate::blockchain::parser::types::{Bitcoin, CoinType};
+    use crate::callbacks::balances::Balances;
+    use crate::callbacks::csvdump::CsvDump;
+    use crate::callbacks::fees::Fees;
+    use crate::callbacks::rawblocks::RawBlocks;
+    use crate::callbacks::reorgs::Reorgs;
+    use crate::callbacks::txinfo::TxInfo;
+    use crate::callbacks::unspentcsvdump::UnspentCsvDump;
+    use crate::callbacks::NullCallback;
+    use crate::common::logger::SimpleLogger;
+    use crate::common::testutils;
+    use crate::common::utils::HashEndian;
+    use crate::BlockHeightRange;
+    use bitcoin::hashes::Hash;
+    use log::LevelFilter;
+    use std::cell::RefCell;
+    use std::fs;
+    use std::path::Path;
+    use std::rc::Rc;
+
+    fn chain(len: u64) -> Vec<Block> {
+        let mut blocks: Vec<Block> = Vec::new();
+        for height in 0..len {
+            let prev_hash = blocks
+                .last()
+                .map_or(sha256d::Hash::all_zeros(), |b| b.header.hash);
+            let coinbase = testutils::coinbase(
+                height,
+                &[(5000000000, testutils::p2pkh_script(height as u8))],
+            );
+            blocks.push(testutils::block(
+                prev_hash,
+                height as u32 * 600,
+                vec![coinbase],
+            ));
+        }
+        blocks
+    }
+
+    fn options(blocks_dir: &Path, callback: Box<dyn Callback>) -> ParserOptions {
+        ParserOptions {
+            callback,
+            coin: CoinType::from(Bitcoin),
+            verify: false,
+            blockchain_dir: blocks_dir.to_path_buf(),
+            log_level_filter: log::LevelFilter::Off,
+            range: BlockHeightRange::new(0, None).unwrap(),
+            hash_endian: HashEndian::Display,
+            atomic_rename: true,
+            filename_template: None,
+            output: utils::OutputConfig::default(),
+            incremental: true,
+            callback_name: String::from("csvdump"),
+            progress_json: None,
+            rpc_only: false,
+            dry_run: false,
+            mmap: false,
+            threads: 1,
+            source: None,
+            rpc: None,
+            validate_only: false,
+            reverse: false,
+            snapshot_interval: None,
+            flush_interval: None,
+            checkpoint_interval: None,
+            bench_report: None,
+            watch_interval: None,
+            watch_timeout: None,
+            error_log: None,
+            mempool_conflicts: None,
+        }
+    }
+
+    fn run(blocks_dir: &Path, callback: Box<dyn Callback>) -> OpResult<()> {
+        let options = options(blocks_dir, callback);
+        let chain_storage = ChainStorage::new(&options)?;
+        BlockchainParser::new(options, Box::new(chain_storage)).start()
+    }
+
+    fn csvdump(dump_folder: &Path) -> Box<dyn Callback> {
+        let matches = CsvDump::build_subcommand()
+            .get_matches_from(["csvdump", dump_folder.to_str().unwrap()]);
+        Box::new(CsvDump::new(&matches).unwrap())
+    }
+
+    fn unspentcsvdump(dump_folder: &Path) -> Box<dyn Callback> {
+        let matches = UnspentCsvDump::build_subcommand()
+            .get_matches_from(["unspentcsvdump", dump_folder.to_str().unwrap()]);
+        Box::new(UnspentCsvDump::new(&matches).unwrap())
+    }
+
+    #[test]
+    fn test_incremental_csvdump() {
+        let blocks_dir = tempfile::tempdir().unwrap();
+        let dump_folder = tempfile::tempdir().unwrap();
+        let blocks = chain(5);
+
+        testutils::write_blocks_dir(blocks_dir.path(), &blocks[..3]);
+        run(blocks_dir.path(), csvdump(dump_folder.path())).unwrap();
+        let first = fs::read_to_string(dump_folder.path().join("blocks-0-2.csv")).unwrap();
+        assert_eq!(first.lines().count(), 3);
+
+        testutils::write_blocks_dir(blocks_dir.path(), &blocks);
+        run(blocks_dir.path(), csvdump(dump_folder.path())).unwrap();
+        let second = fs::read_to_string(dump_folder.path().join("blocks-3-4.csv")).unwrap();
+        let heights = second
+            .lines()
+            .map(|l| l.split(';').nth(1).unwrap())
+            .collect::<Vec<&str>>();
+        assert_eq!(heights, vec!["3", "4"]);
+
+        let state = ParserState::load(&ParserState::path(
+            dump_folder.path(),
+            &CoinType::from(Bitcoin),
+        ))
+        .unwrap()
+        .unwrap();
+        assert_eq!(state.height, 4);
+        assert_eq!(state.block_hash, blocks[4].header.hash);
+
+        // Nothing new, no further output
+        run(blocks_dir.path(), csvdump(dump_folder.path())).unwrap();
+        assert!(!dump_folder.path().join("blocks-5-4.csv").exists());
+    }
+
+    #[test]
+    fn test_summary() {
+        let blocks_dir = tempfile::tempdir().unwrap();
+        let dump_folder = tempfile::tempdir().unwrap();
+        testutils::write_blocks_dir(blocks_dir.path(), &chain(3));
+        run(blocks_dir.path(), csvdump(dump_folder.path())).unwrap();
+
+        let summary = fs::read_to_string(dump_folder.path().join("summary.json")).unwrap();
+        let summary: serde_json::Value = serde_json::from_str(&summary).unwrap();
+        assert_eq!(summary["coin"], "Bitcoin");
+        assert_eq!(summary["network"], "0xd9b4bef9");
+        assert_eq!(summary["start_height"], 0);
+        assert_eq!(summary["end_height"], 2);
+        assert_eq!(summary["blocks_processed"], 3);
+        assert_eq!(summary["transactions_processed"], 3);
+        assert!(summary["elapsed_secs"].as_f64().unwrap() >= 0.0);
+        assert_eq!(summary["callback"]["transactions"], 3);
+        assert_eq!(summary["callback"]["outputs"], 3);
+    }
+
+    #[test]
+    fn test_incremental_reloads_unspents() {
+        let blocks_dir = tempfile::tempdir().unwrap();
+        let dump_folder = tempfile::tempdir().unwrap();
+        let blocks = chain(4);
+
+        testutils::write_blocks_dir(blocks_dir.path(), &blocks[..2]);
+        run(blocks_dir.path(), unspentcsvdump(dump_folder.path())).unwrap();
+        testutils::write_blocks_dir(blocks_dir.path(), &blocks);
+        run(blocks_dir.path(), unspentcsvdump(dump_folder.path())).unwrap();
+
+        // The second run only processed blocks 2 and 3, but still knows all unspents
+        let unspents = fs::read_to_string(dump_folder.path().join("unspent-2-3.csv")).unwrap();
+        assert_eq!(unspents.lines().count(), 1 + 4);
+    }
+
+    #[test]
+    fn test_resume_from_checkpoint() {
+        let blocks_dir = tempfile::tempdir().unwrap();
+        let dump_folder = tempfile::tempdir().unwrap();
+        testutils::write_blocks_dir(blocks_dir.path(), &chain(5));
+
+        let callback = FailAt {
+            unspentcsvdump: unspentcsvdump(dump_folder.path()),
+            height: 3,
+        };
+        let mut options = options(blocks_dir.path(), Box::new(callback));
+        options.checkpoint_interval = Some(2);
+        let chain_storage = ChainStorage::new(&options).unwrap();
+        assert!(BlockchainParser::new(options, Box::new(chain_storage))
+            .start()
+            .is_err());
+        let state = ParserState::load(&ParserState::path(
+            dump_folder.path(),
+            &CoinType::from(Bitcoin),
+        ))
+        .unwrap()
+        .unwrap();
+        assert_eq!(state.height, 1);
 
-        if now - self.stats.last_log > self.stats.measure_frame {
-            info!(target: "parser", "Status: {:7} Blocks processed. (remaining: {:7}, speed: {:5.2} blocks/s)",
-              height, self.remaining(), blocks_speed);
-            self.stats.last_log = now;
-            self.stats.last_height = height;
+        // Continues after the checkpoint with the unspents of blocks 0 and 1
+        run(blocks_dir.path(), unspentcsvdump(dump_folder.path())).unwrap();
+        let unspents = fs::read_to_string(dump_folder.path().join("unspent-2-4.csv")).unwrap();
+        assert_eq!(unspents.lines().count(), 1 + 5);
+    }
+
+    #[test]
+    fn test_reverse() {
+        let blocks_dir = tempfile::tempdir().unwrap();
+        let dump_folder = tempfile::tempdir().unwrap();
+        testutils::write_blocks_dir(blocks_dir.path(), &chain(3));
+        let run_reverse = |callback: Box<dyn Callback>| {
+            let mut options = options(blocks_dir.path(), callback);
+            options.incremental = false;
+            options.reverse = true;
+            let chain_storage = ChainStorage::new(&options)?;
+            BlockchainParser::new(options, Box::new(chain_storage)).start()
+        };
+
+        run_reverse(csvdump(dump_folder.path())).unwrap();
+        let blocks = fs::read_to_string(dump_folder.path().join("blocks-2-0.csv")).unwrap();
+        let heights = blocks
+            .lines()
+            .map(|l| l.split(';').nth(1).unwrap())
+            .collect::<Vec<&str>>();
+        assert_eq!(heights, vec!["2", "1", "0"]);
+
+        // The UTXO set can't be built from the tip downward
+        assert!(run_reverse(unspentcsvdump(dump_folder.path())).is_err());
+        assert!(!dump_folder.path().join("unspent-2-0.csv").exists());
+    }
+
+    #[test]
+    fn test_reorgs() {
+        let blocks_dir = tempfile::tempdir().unwrap();
+        let dump_folder = tempfile::tempdir().unwrap();
+        let blocks = chain(5);
+        // Competing branch of two blocks on top of block 1, replaced by the blocks 2 to 4
+        let stale1 = testutils::block(
+            blocks[1].header.hash,
+            1300,
+            vec![testutils::coinbase(
+                2,
+                &[(5000000000, testutils::p2pkh_script(20))],
+            )],
+        );
+        let stale2 = testutils::block(
+            stale1.header.hash,
+            1900,
+            vec![testutils::coinbase(
+                3,
+                &[(5000000000, testutils::p2pkh_script(21))],
+            )],
+        );
+        testutils::write_blocks_dir_with_stale(
+            blocks_dir.path(),
+            &blocks,
+            &[(2, &stale1), (3, &stale2)],
+        );
+
+        let options = options(blocks_dir.path(), Box::new(NullCallback));
+        let chain_storage = ChainStorage::new(&options).unwrap();
+        for (height, block) in blocks.iter().enumerate() {
+            assert_eq!(
+                chain_storage.block_hash(height as u64).unwrap(),
+                Some(block.hash())
+            );
+        }
+        assert_eq!(chain_storage.stale_blocks().len(), 2);
+
+        let matches = Reorgs::build_subcommand().get_matches_from([
+            "reorgs",
+            dump_folder.path().to_str().unwrap(),
+            "--stale-blocks",
+        ]);
+        run(blocks_dir.path(), Box::new(Reorgs::new(&matches).unwrap())).unwrap();
+        let csv = fs::read_to_string(dump_folder.path().join("reorgs-0-4.csv")).unwrap();
+        assert_eq!(csv, "height;reorg_depth;stale_block_count\n2;2;2\n");
+        let csv = fs::read_to_string(dump_folder.path().join("staleblocks-0-4.csv")).unwrap();
+        assert_eq!(
+            csv.lines().collect::<Vec<&str>>(),
+            vec![
+                String::from("height;block_hash;hashPrev;nTime;txCount;blocksize"),
+                format!(
+                    "2;{};{};1300;1;{}",
+                    stale1.hash(),
+                    blocks[1].hash(),
+                    stale1.size
+                ),
+                format!(
+                    "3;{};{};1900;1;{}",
+                    stale2.hash(),
+                    stale1.hash(),
+                    stale2.size
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_failed_branch() {
+        let blocks_dir = tempfile::tempdir().unwrap();
+        let blocks = chain(4);
+        // Longer branch on top of block 1, whose first block was rejected by the node
+        let mut prev_hash = blocks[1].header.hash;
+        let mut failed = Vec::new();
+        for height in 2..6 {
+            let block = testutils::block(
+                prev_hash,
+                height as u32 * 700,
+                vec![testutils::coinbase(
+                    height,
+                    &[(5000000000, testutils::p2pkh_script(30))],
+                )],
+            );
+            prev_hash = block.header.hash;
+            failed.push((height, block));
+        }
+        let failed: Vec<_> = failed
+            .iter()
+            .map(|(height, block)| (*height, block, *height > 2))
+            .collect();
+        testutils::write_blocks_dir_with_failed(blocks_dir.path(), &blocks, &failed);
+
+        let options = options(blocks_dir.path(), Box::new(NullCallback));
+        let chain_storage = ChainStorage::new(&options).unwrap();
+        assert_eq!(chain_storage.max_height(), 3);
+        for (height, block) in blocks.iter().enumerate() {
+            assert_eq!(
+                chain_storage.block_hash(height as u64).unwrap(),
+                Some(block.hash())
+            );
+        }
+        assert_eq!(chain_storage.stale_blocks().len(), 4);
+    }
+
+    #[test]
+    fn test_block_above_gap() {
+        let blocks_dir = tempfile::tempdir().unwrap();
+        let blocks = chain(4);
+        // Downloaded ahead of its parent, which is not in the index yet
+        let ahead = testutils::block(
+            sha256d::Hash::hash(&[6]),
+            6 * 600,
+            vec![testutils::coinbase(
+                6,
+                &[(5000000000, testutils::p2pkh_script(6))],
+            )],
+        );
+        testutils::write_blocks_dir_with_stale(blocks_dir.path(), &blocks, &[(6, &ahead)]);
+
+        let options = options(blocks_dir.path(), Box::new(NullCallback));
+        let chain_storage = ChainStorage::new(&options).unwrap();
+        assert_eq!(chain_storage.max_height(), 3);
+        for (height, block) in blocks.iter().enumerate() {
+            assert_eq!(
+                chain_storage.block_hash(height as u64).unwrap(),
+                Some(block.hash())
+            );
+        }
+        assert_eq!(chain_storage.stale_blocks().len(), 1);
+    }
+
+    #[test]
+    fn test_raw_blocks() {
+        let blocks_dir = tempfile::tempdir().unwrap();
+        let dump_folder = tempfile::tempdir().unwrap();
+        let blocks = chain(2);
+        testutils::write_blocks_dir(blocks_dir.path(), &blocks);
+        let matches = RawBlocks::build_subcommand()
+            .get_matches_from(["rawblocks", dump_folder.path().to_str().unwrap()]);
+        run(
+            blocks_dir.path(),
+            Box::new(RawBlocks::new(&matches).unwrap()),
+        )
+        .unwrap();
+
+        // Each block is stored after the magic and the size
+        let blk_data = fs::read(blocks_dir.path().join("blk00000.dat")).unwrap();
+        let mut offset = 8;
+        for (height, block) in blocks.iter().enumerate() {
+            let end = offset + block.size as usize;
+            let path = dump_folder
+                .path()
+                .join(format!("{}_{}.bin", height, block.hash()));
+            assert_eq!(fs::read(path).unwrap(), &blk_data[offset..end]);
+            offset = end + 8;
+        }
+    }
+
+    #[test]
+    fn test_remote_source() {
+        let blocks_dir = tempfile::tempdir().unwrap();
+        let dump_folder = tempfile::tempdir().unwrap();
+        let blocks = chain(3);
+        testutils::write_blocks_dir(blocks_dir.path(), &blocks);
+        // Only the block index is read locally
+        let blk_data = fs::read(blocks_dir.path().join("blk00000.dat")).unwrap();
+        fs::remove_file(blocks_dir.path().join("blk00000.dat")).unwrap();
+        let base_url = remote::tests::serve(vec![(String::from("blk00000.dat"), blk_data)]);
+
+        let mut options = options(blocks_dir.path(), csvdump(dump_folder.path()));
+        options.source = Some(remote::RemoteSource::parse(&base_url).unwrap());
+        let mut chain_storage = ChainStorage::new(&options).unwrap();
+        for (height, block) in blocks.iter().enumerate() {
+            let remote_block = chain_storage.get_block(height as u64).unwrap().unwrap();
+            assert_eq!(remote_block.hash(), block.hash());
         }
     }
+
+    #[test]
+    fn test_rpc_gap_filling() {
+        let blocks_dir = tempfile::tempdir().unwrap();
+        let dump_folder = tempfile::tempdir().unwrap();
+        let blocks = chain(5);
+        // The blk files lag behind the node by two blocks
+        testutils::write_blocks_dir(blocks_dir.path(), &blocks[..3]);
+        let url = rpc::tests::serve(&blocks);
+
+        let mut rpc_options = options(blocks_dir.path(), csvdump(dump_folder.path()));
+        rpc_options.rpc = Some(rpc::RpcClient::new(&url, None).unwrap());
+        // Verifies the prev hashes of the RPC blocks, the synthetic genesis block would fail
+        rpc_options.verify = true;
+        rpc_options.range = BlockHeightRange::new(1, None).unwrap();
+        rpc_options.incremental = false;
+        let chain_storage = ChainStorage::new(&rpc_options).unwrap();
+        assert_eq!(chain_storage.max_height(), 4);
+        BlockchainParser::new(rpc_options, Box::new(chain_storage))
+            .start()
+            .unwrap();
+
+        let csv = fs::read_to_string(dump_folder.path().join("blocks-1-4.csv")).unwrap();
+        let hashes = csv
+            .lines()
+            .map(|l| l.split(';').next().unwrap())
+            .collect::<Vec<&str>>();
+        let expected = blocks[1..]
+            .iter()
+            .map(|b| b.hash().to_string())
+            .collect::<Vec<String>>();
+        assert_eq!(hashes, expected);
+
+        // A node which stops answering fails the run instead of truncating the output
+        fs::remove_file(dump_folder.path().join("blocks-1-4.csv")).unwrap();
+        let url = rpc::tests::serve_failing_after(&blocks, 1);
+        let mut failing_options = options(blocks_dir.path(), csvdump(dump_folder.path()));
+        failing_options.rpc = Some(rpc::RpcClient::new(&url, None).unwrap());
+        failing_options.range = BlockHeightRange::new(1, None).unwrap();
+        failing_options.incremental = false;
+        let chain_storage = ChainStorage::new(&failing_options).unwrap();
+        let err = BlockchainParser::new(failing_options, Box::new(chain_storage))
+            .start()
+            .unwrap_err();
+        assert!(err.to_string().contains("Loading block index"), "{}", err);
+        assert!(!dump_folder.path().join("blocks-1-2.csv").exists());
+    }
+
+    #[test]
+    fn test_rpc_only() {
+        let dump_folder = tempfile::tempdir().unwrap();
+        let blocks = chain(5);
+        let url = rpc::tests::serve(&blocks);
+
+        // Neither blk files nor a block index exist in the blockchain dir
+        let mut rpc_options = options(
+            &dump_folder.path().join("missing"),
+            csvdump(dump_folder.path()),
+        );
+        rpc_options.rpc = Some(rpc::RpcClient::new(&url, None).unwrap());
+        rpc_options.rpc_only = true;
+        rpc_options.verify = true;
+        rpc_options.range = BlockHeightRange::new(2, None).unwrap();
+        rpc_options.incremental = false;
+        let mut source = blocksource::open(&rpc_options).unwrap();
+        assert_eq!(source.max_height(), 4);
+        assert_eq!(source.blk_file_count(), 0);
+        assert_eq!(source.block_hash(3).unwrap(), Some(blocks[3].hash()));
+        assert_eq!(source.block_hash(5).unwrap(), None);
+        source.validate_block(3).unwrap();
+        BlockchainParser::new(rpc_options, source).start().unwrap();
+
+        let csv = fs::read_to_string(dump_folder.path().join("blocks-2-4.csv")).unwrap();
+        let hashes = csv
+            .lines()
+            .map(|l| l.split(';').next().unwrap())
+            .collect::<Vec<&str>>();
+        let expected = blocks[2..]
+            .iter()
+            .map(|b| b.hash().to_string())
+            .collect::<Vec<String>>();
+        assert_eq!(hashes, expected);
+
+        // The undo data is only read from the rev files
+        let matches = Fees::build_subcommand().get_matches_from([
+            "fees",
+            "--undo",
+            dump_folder.path().to_str().unwrap(),
+        ]);
+        let mut undo_options = options(
+            &dump_folder.path().join("missing"),
+            Box::new(Fees::new(&matches).unwrap()),
+        );
+        undo_options.rpc = Some(rpc::RpcClient::new(&url, None).unwrap());
+        undo_options.rpc_only = true;
+        assert!(blocksource::open(&undo_options).is_err());
+
+        // A failing request stops the run instead of ending the chain early
+        fs::remove_file(dump_folder.path().join("blocks-2-4.csv")).unwrap();
+        let mut failing_options = options(
+            &dump_folder.path().join("missing"),
+            csvdump(dump_folder.path()),
+        );
+        // Answers getblockchaininfo, getblockcount and the first block
+        let url = rpc::tests::serve_failing_after(&blocks, 4);
+        failing_options.rpc = Some(rpc::RpcClient::new(&url, None).unwrap());
+        failing_options.rpc_only = true;
+        failing_options.incremental = false;
+        failing_options.range = BlockHeightRange::new(2, None).unwrap();
+        let source = blocksource::open(&failing_options).unwrap();
+        let err = BlockchainParser::new(failing_options, source)
+            .start()
+            .unwrap_err();
+        assert!(err.to_string().contains("Loading block index"), "{}", err);
+        assert!(!dump_folder.path().join("blocks-2-2.csv").exists());
+    }
+
+    #[test]
+    fn test_snapshot_interval() {
+        let blocks_dir = tempfile::tempdir().unwrap();
+        let dump_folder = tempfile::tempdir().unwrap();
+        testutils::write_blocks_dir(blocks_dir.path(), &chain(5));
+        let matches = Balances::build_subcommand()
+            .get_matches_from(["balances", dump_folder.path().to_str().unwrap()]);
+        let mut options = options(
+            blocks_dir.path(),
+            Box::new(Balances::new(&matches).unwrap()),
+        );
+        options.snapshot_interval = Some(2);
+        let chain_storage = ChainStorage::new(&options).unwrap();
+        BlockchainParser::new(options, Box::new(chain_storage))
+            .start()
+            .unwrap();
+
+        // Each block pays to a new address
+        let addresses = |name: &str| {
+            let csv = fs::read_to_string(dump_folder.path().join(name)).unwrap();
+            csv.lines().count() - 1
+        };
+        assert_eq!(addresses("balances-partial-0-1.csv"), 2);
+        assert_eq!(addresses("balances-partial-0-3.csv"), 4);
+        assert_eq!(addresses("balances-0-4.csv"), 5);
+        assert!(!dump_folder.path().join("balances-partial-0-4.csv").exists());
+    }
+
+    #[test]
+    fn test_snapshot_interval_output_config() {
+        let blocks_dir = tempfile::tempdir().unwrap();
+        let dump_folder = tempfile::tempdir().unwrap();
+        let output_dir = dump_folder.path().join("out");
+        fs::create_dir(&output_dir).unwrap();
+        testutils::write_blocks_dir(blocks_dir.path(), &chain(3));
+        let config = utils::OutputConfig::new(
+            Some(output_dir.clone()),
+            Some(String::from("run1_")),
+            Some(utils::Compression::Gzip),
+        )
+        .unwrap();
+        // Balances looks up the compression when it is created
+        utils::set_output_config(config.clone());
+        let matches = Balances::build_subcommand()
+            .get_matches_from(["balances", dump_folder.path().to_str().unwrap()]);
+        let mut options = options(
+            blocks_dir.path(),
+            Box::new(Balances::new(&matches).unwrap()),
+        );
+        options.output = config;
+        options.snapshot_interval = Some(2);
+        let chain_storage = ChainStorage::new(&options).unwrap();
+        BlockchainParser::new(options, Box::new(chain_storage))
+            .start()
+            .unwrap();
+        utils::set_output_config(utils::OutputConfig::default());
+
+        let file = fs::File::open(output_dir.join("run1_balances-partial-0-1.csv.gz")).unwrap();
+        let mut csv = String::new();
+        std::io::Read::read_to_string(&mut flate2::read::GzDecoder::new(file), &mut csv).unwrap();
+        assert_eq!(csv.lines().count() - 1, 2);
+        assert!(output_dir.join("run1_balances-0-2.csv.gz").exists());
+        assert!(!dump_folder
+            .path()
+            .join("balances-partial-0-1.csv.gz")
+            .exists());
+    }
+
+    /// Wraps csvdump and records the rows of `blocks.csv.tmp` on disk whenever it is flushed
+    struct FlushRecorder {
+        csvdump: Box<dyn Callback>,
+        dump_folder: PathBuf,
+        height: u64,
+        flushes: Rc<RefCell<Vec<(u64, usize)>>>,
+    }
+
+    impl Callback for FlushRecorder {
+        fn build_subcommand() -> clap::Command {
+            unimplemented!()
+        }
+
+        fn new(_: &clap::ArgMatches) -> OpResult<Self> {
+            unimplemented!()
+        }
+
+        fn on_start(&mut self, block_height: u64) -> OpResult<()> {
+            self.csvdump.on_start(block_height)
+        }
+
+        fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<Control> {
+            self.height = block_height;
+            self.csvdump.on_block(block, block_height)
+        }
+
+        fn flush(&mut self) -> OpResult<()> {
+            self.csvdump.flush()?;
+            let csv = fs::read_to_string(self.dump_folder.join("blocks.csv.tmp"))?;
+            self.flushes
+                .borrow_mut()
+                .push((self.height, csv.lines().count()));
+            Ok(())
+        }
+
+        fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+            self.csvdump.on_complete(block_height)
+        }
+    }
+
+    /// Wraps csvdump and lets the node append blocks once the given height is processed
+    struct GrowingChain {
+        csvdump: Box<dyn Callback>,
+        blocks_dir: PathBuf,
+        append_at: u64,
+        blocks: Vec<Block>,
+    }
+
+    impl Callback for GrowingChain {
+        fn build_subcommand() -> clap::Command {
+            unimplemented!()
+        }
+
+        fn new(_: &clap::ArgMatches) -> OpResult<Self> {
+            unimplemented!()
+        }
+
+        fn on_start(&mut self, block_height: u64) -> OpResult<()> {
+            self.csvdump.on_start(block_height)
+        }
+
+        fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<Control> {
+            if block_height == self.append_at {
+                testutils::write_blocks_dir(&self.blocks_dir, &self.blocks);
+            }
+            self.csvdump.on_block(block, block_height)
+        }
+
+        fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+            self.csvdump.on_complete(block_height)
+        }
+    }
+
+    /// Replaces the processed tip with a longer branch once the given height is processed,
+    /// records the blocks passed to on_orphan_block()
+    struct ReorgAt {
+        blocks_dir: PathBuf,
+        reorg_at: u64,
+        main_chain: Vec<Block>,
+        replaced: Vec<(u64, Block)>,
+        orphans: Rc<RefCell<Vec<(u64, sha256d::Hash)>>>,
+    }
+
+    impl Callback for ReorgAt {
+        fn build_subcommand() -> clap::Command {
+            unimplemented!()
+        }
+
+        fn new(_: &clap::ArgMatches) -> OpResult<Self> {
+            unimplemented!()
+        }
+
+        fn on_start(&mut self, _block_height: u64) -> OpResult<()> {
+            Ok(())
+        }
+
+        fn needs_orphan_blocks(&self) -> bool {
+            true
+        }
+
+        fn on_orphan_block(&mut self, block: &Block, block_height: u64) -> OpResult<()> {
+            self.orphans.borrow_mut().push((block_height, block.hash()));
+            Ok(())
+        }
+
+        fn on_block(&mut self, _block: &Block, block_height: u64) -> OpResult<Control> {
+            if block_height == self.reorg_at {
+                let replaced: Vec<(u64, &Block)> =
+                    self.replaced.iter().map(|(h, b)| (*h, b)).collect();
+                testutils::write_blocks_dir_with_stale(
+                    &self.blocks_dir,
+                    &self.main_chain,
+                    &replaced,
+                );
+            }
+            Ok(Control::Continue)
+        }
+
+        fn on_complete(&mut self, _block_height: u64) -> OpResult<()> {
+            Ok(())
+        }
+    }
+
+    /// Wraps unspentcsvdump and fails at the given height, like an interrupted run
+    struct FailAt {
+        unspentcsvdump: Box<dyn Callback>,
+        height: u64,
+    }
+
+    impl Callback for FailAt {
+        fn build_subcommand() -> clap::Command {
+            unimplemented!()
+        }
+
+        fn new(_: &clap::ArgMatches) -> OpResult<Self> {
+            unimplemented!()
+        }
+
+        fn on_start(&mut self, block_height: u64) -> OpResult<()> {
+            self.unspentcsvdump.on_start(block_height)
+        }
+
+        fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<Control> {
+            if block_height == self.height {
+                return Err(OpError::from(String::from("interrupted")));
+            }
+            self.unspentcsvdump.on_block(block, block_height)
+        }
+
+        fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+            self.unspentcsvdump.on_complete(block_height)
+        }
+
+        fn dump_folder(&self) -> Option<&Path> {
+            self.unspentcsvdump.dump_folder()
+        }
+
+        fn save_state(&self, folder: &Path) -> OpResult<()> {
+            self.unspentcsvdump.save_state(folder)
+        }
+
+        fn supports_checkpoints(&self) -> bool {
+            true
+        }
+    }
+
+    /// Records the heights it receives and stops once the given height is processed
+    struct StopAt {
+        height: u64,
+        received: Rc<RefCell<Vec<u64>>>,
+        completed: Rc<RefCell<Option<u64>>>,
+    }
+
+    impl Callback for StopAt {
+        fn build_subcommand() -> clap::Command {
+            unimplemented!()
+        }
+
+        fn new(_: &clap::ArgMatches) -> OpResult<Self> {
+            unimplemented!()
+        }
+
+        fn on_start(&mut self, _: u64) -> OpResult<()> {
+            Ok(())
+        }
+
+        fn on_block(&mut self, _: &Block, block_height: u64) -> OpResult<Control> {
+            self.received.borrow_mut().push(block_height);
+            match block_height == self.height {
+                true => Ok(Control::Stop),
+                false => Ok(Control::Continue),
+            }
+        }
+
+        fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+            *self.completed.borrow_mut() = Some(block_height);
+            Ok(())
+        }
+
+        fn supports_reverse(&self) -> bool {
+            true
+        }
+    }
+
+    #[test]
+    fn test_stop() {
+        let blocks_dir = tempfile::tempdir().unwrap();
+        testutils::write_blocks_dir(blocks_dir.path(), &chain(6));
+        let run = |height: u64, reverse: bool| {
+            let received = Rc::new(RefCell::new(Vec::new()));
+            let completed = Rc::new(RefCell::new(None));
+            let callback = StopAt {
+                height,
+                received: received.clone(),
+                completed: completed.clone(),
+            };
+            let mut options = options(blocks_dir.path(), Box::new(callback));
+            options.incremental = false;
+            options.reverse = reverse;
+            let chain_storage = ChainStorage::new(&options).unwrap();
+            BlockchainParser::new(options, Box::new(chain_storage))
+                .start()
+                .unwrap();
+            let received = received.borrow().clone();
+            let completed = *completed.borrow();
+            (received, completed)
+        };
+
+        assert_eq!(run(2, false), (vec![0, 1, 2], Some(2)));
+        assert_eq!(run(3, true), (vec![5, 4, 3], Some(3)));
+    }
+
+    #[test]
+    fn test_watch() {
+        let blocks_dir = tempfile::tempdir().unwrap();
+        let dump_folder = tempfile::tempdir().unwrap();
+        testutils::write_blocks_dir(blocks_dir.path(), &chain(3));
+        let callback = GrowingChain {
+            csvdump: csvdump(dump_folder.path()),
+            blocks_dir: blocks_dir.path().to_path_buf(),
+            append_at: 2,
+            blocks: chain(5),
+        };
+        let mut options = options(blocks_dir.path(), Box::new(callback));
+        options.watch_interval = Some(Duration::from_millis(10));
+        options.watch_timeout = Some(Duration::from_millis(200));
+        let chain_storage = ChainStorage::new(&options).unwrap();
+        BlockchainParser::new(options, Box::new(chain_storage))
+            .start()
+            .unwrap();
+
+        // The blocks appended while the tip was processed are picked up by polling
+        let csv = fs::read_to_string(dump_folder.path().join("blocks-0-4.csv")).unwrap();
+        assert_eq!(csv.lines().count(), 5);
+    }
+
+    #[test]
+    fn test_watch_reorg() {
+        let blocks_dir = tempfile::tempdir().unwrap();
+        let blocks = chain(4);
+        testutils::write_blocks_dir(blocks_dir.path(), &blocks);
+        // The blocks 2 and 3 get replaced by a longer branch on top of block 1
+        let mut main_chain: Vec<Block> = chain(2);
+        for height in 2..5 {
+            let prev_hash = main_chain.last().unwrap().header.hash;
+            main_chain.push(testutils::block(
+                prev_hash,
+                height as u32 * 700,
+                vec![testutils::coinbase(
+                    height,
+                    &[(5000000000, testutils::p2pkh_script(30 + height as u8))],
+                )],
+            ));
+        }
+        let (hash2, hash3) = (blocks[2].hash(), blocks[3].hash());
+        let mut blocks = blocks;
+        let replaced = vec![(3, blocks.pop().unwrap()), (2, blocks.pop().unwrap())];
+        let orphans = Rc::new(RefCell::new(Vec::new()));
+        let callback = ReorgAt {
+            blocks_dir: blocks_dir.path().to_path_buf(),
+            reorg_at: 3,
+            main_chain,
+            replaced,
+            orphans: orphans.clone(),
+        };
+        let mut options = options(blocks_dir.path(), Box::new(callback));
+        options.incremental = false;
+        options.watch_interval = Some(Duration::from_millis(10));
+        options.watch_timeout = Some(Duration::from_millis(500));
+        let chain_storage = ChainStorage::new(&options).unwrap();
+        BlockchainParser::new(options, Box::new(chain_storage))
+            .start()
+            .unwrap();
+
+        // The processed blocks are passed from the tip downward before the parser stops
+        assert_eq!(*orphans.borrow(), vec![(3, hash3), (2, hash2)]);
+    }
+
+    #[test]
+    fn test_flush_interval() {
+        let blocks_dir = tempfile::tempdir().unwrap();
+        let dump_folder = tempfile::tempdir().unwrap();
+        testutils::write_blocks_dir(blocks_dir.path(), &chain(7));
+        let flushes = Rc::new(RefCell::new(Vec::new()));
+        let callback = FlushRecorder {
+            csvdump: csvdump(dump_folder.path()),
+            dump_folder: dump_folder.path().to_path_buf(),
+            height: 0,
+            flushes: flushes.clone(),
+        };
+        let mut options = options(blocks_dir.path(), Box::new(callback));
+        options.flush_interval = Some(3);
+        let chain_storage = ChainStorage::new(&options).unwrap();
+        BlockchainParser::new(options, Box::new(chain_storage))
+            .start()
+            .unwrap();
+
+        // The buffered rows are on disk right after each interval
+        assert_eq!(*flushes.borrow(), vec![(2, 3), (5, 6)]);
+    }
+
+    #[test]
+    fn test_bench_report() {
+        let blocks_dir = tempfile::tempdir().unwrap();
+        let dump_folder = tempfile::tempdir().unwrap();
+        testutils::write_blocks_dir(blocks_dir.path(), &chain(4));
+        let report_path = dump_folder.path().join("bench.json");
+        let mut options = options(blocks_dir.path(), csvdump(dump_folder.path()));
+        options.bench_report = Some(report_path.clone());
+        let chain_storage = ChainStorage::new(&options).unwrap();
+        BlockchainParser::new(options, Box::new(chain_storage))
+            .start()
+            .unwrap();
+
+        let report: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(report_path).unwrap()).unwrap();
+        assert_eq!(report["blocks"], 4);
+        assert_eq!(report["transactions"], 4);
+        assert!(report["bytes"].as_u64().unwrap() > 0);
+        for stage in ["read", "deserialize", "script_parse", "hash", "callback"] {
+            assert!(
+                report["stages_secs"][stage].as_f64().unwrap() > 0.0,
+                "{}",
+                stage
+            );
+        }
+    }
+
+    #[test]
+    fn test_progress_json() {
+        let blocks_dir = tempfile::tempdir().unwrap();
+        let dump_folder = tempfile::tempdir().unwrap();
+        testutils::write_blocks_dir(blocks_dir.path(), &chain(4));
+        let status_path = dump_folder.path().join("status.json");
+        let mut opts = options(blocks_dir.path(), csvdump(dump_folder.path()));
+        opts.progress_json = Some(status_path.clone());
+        let chain_storage = ChainStorage::new(&opts).unwrap();
+        BlockchainParser::new(opts, Box::new(chain_storage))
+            .start()
+            .unwrap();
+
+        let status: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(&status_path).unwrap()).unwrap();
+        assert_eq!(status["state"], "completed");
+        assert_eq!(status["height"], 3);
+        assert_eq!(status["end_height"], 3);
+        assert_eq!(status["remaining"], 0);
+        assert_eq!(status["blocks_processed"], 4);
+        assert_eq!(status["counters"]["transactions"], 4);
+        assert_eq!(status["error"], serde_json::Value::Null);
+
+        // A failing run reports the error, here block 2 doesn't meet its target anymore
+        let blocks = chain(4);
+        let blk_path = blocks_dir.path().join("blk00000.dat");
+        let mut blk_data = fs::read(&blk_path).unwrap();
+        let offset = 2 * 8 + blocks[..2].iter().map(|b| b.size as usize).sum::<usize>() + 8;
+        blk_data[offset + 72..offset + 76].copy_from_slice(&0x1d00ffffu32.to_le_bytes());
+        fs::write(&blk_path, blk_data).unwrap();
+        let mut options = options(blocks_dir.path(), csvdump(dump_folder.path()));
+        options.range = BlockHeightRange::new(1, None).unwrap();
+        options.incremental = false;
+        options.verify = true;
+        options.progress_json = Some(status_path.clone());
+        let chain_storage = ChainStorage::new(&options).unwrap();
+        assert!(BlockchainParser::new(options, Box::new(chain_storage))
+            .start()
+            .is_err());
+        let status: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(&status_path).unwrap()).unwrap();
+        assert_eq!(status["state"], "failed");
+        assert_eq!(status["height"], 1);
+        assert!(status["error"].is_string());
+    }
+
+    #[test]
+    fn test_coin_config_file() {
+        let blocks_dir = tempfile::tempdir().unwrap();
+        let dump_folder = tempfile::tempdir().unwrap();
+        let config = dump_folder.path().join("coin.json");
+        fs::write(
+            &config,
+            r#"{"name": "Regtest", "magic": "fabfb5da", "pubkey_version": 111, "script_version": 196,
+                "genesis_hash": "0f9188f13cb7b2c71f2a335e3a4fc328bf5beb436012afca590b1a11466e2206",
+                "initial_subsidy": 5000000000, "halving_interval": 150, "coinbase_maturity": 100}"#,
+        )
+        .unwrap();
+        let coin = CoinType::from_config_file(&config).unwrap();
+        testutils::write_blocks_dir_with_magic(blocks_dir.path(), &chain(3), coin.magic);
+        let blk_files = BlkFile::from_path(blocks_dir.path(), false).unwrap();
+        assert!(BlkFile::has_magic(&blk_files, coin.magic));
+
+        let mut options = options(blocks_dir.path(), csvdump(dump_folder.path()));
+        options.coin = coin;
+        let chain_storage = ChainStorage::new(&options).unwrap();
+        BlockchainParser::new(options, Box::new(chain_storage))
+            .start()
+            .unwrap();
+        let csv = fs::read_to_string(dump_folder.path().join("blocks-0-2.csv")).unwrap();
+        assert_eq!(csv.lines().count(), 3);
+        assert!(dump_folder
+            .path()
+            .join(".rusty-blockparser-regtest.state")
+            .exists());
+    }
+
+    #[test]
+    fn test_callback_done() {
+        let blocks = chain(6);
+        let blocks_dir = tempfile::tempdir().unwrap();
+        testutils::write_blocks_dir(blocks_dir.path(), &blocks);
+        let txid = blocks[2].txs[0].hash.to_string();
+        let matches =
+            TxInfo::build_subcommand().get_matches_from(["txinfo", "--txid", &txid, "--stop"]);
+        let mut options = options(blocks_dir.path(), Box::new(TxInfo::new(&matches).unwrap()));
+        options.incremental = false;
+        // Stops even though it would wait for new blocks
+        options.watch_interval = Some(Duration::from_secs(1));
+        let chain_storage = ChainStorage::new(&options).unwrap();
+        let mut parser = BlockchainParser::new(options, Box::new(chain_storage));
+        parser.start().unwrap();
+        assert_eq!(parser.stats.n_blocks, 3);
+        assert_eq!(parser.callback.summary()["found_height"], 2);
+    }
+
+    #[test]
+    fn test_run() {
+        let blocks = chain(4);
+        let blocks_dir = tempfile::tempdir().unwrap();
+        testutils::write_blocks_dir(blocks_dir.path(), &blocks);
+        let txid = blocks[3].txs[0].hash.to_string();
+        let matches = TxInfo::build_subcommand().get_matches_from(["txinfo", "--txid", &txid]);
+        let callback = Box::new(TxInfo::new(&matches).unwrap());
+        let options = ParserOptions::new(blocks_dir.path().to_path_buf());
+        let callback = BlockchainParser::run(options, callback).unwrap();
+        assert_eq!(callback.summary()["found_height"], 3);
+    }
+
+    #[test]
+    fn test_validate_only() {
+        let blocks_dir = tempfile::tempdir().unwrap();
+        testutils::write_blocks_dir(blocks_dir.path(), &chain(4));
+        let validate = || {
+            let mut options = options(blocks_dir.path(), Box::new(NullCallback));
+            // The synthetic genesis block doesn't match the real one
+            options.range = BlockHeightRange::new(1, None).unwrap();
+            options.incremental = false;
+            options.validate_only = true;
+            let chain_storage = ChainStorage::new(&options)?;
+            BlockchainParser::new(options, Box::new(chain_storage)).start()
+        };
+        validate().unwrap();
+
+        // Changes the locktime of the last transaction, so the merkle root doesn't match anymore
+        let blk_path = blocks_dir.path().join("blk00000.dat");
+        let mut blk_data = fs::read(&blk_path).unwrap();
+        *blk_data.last_mut().unwrap() ^= 0x01;
+        fs::write(&blk_path, &blk_data).unwrap();
+        assert!(validate().is_err());
+
+        // A truncated last block is reported with its height
+        fs::write(&blk_path, &blk_data[..blk_data.len() - 10]).unwrap();
+        let validate_options = options(blocks_dir.path(), Box::new(NullCallback));
+        let mut chain_storage = ChainStorage::new(&validate_options).unwrap();
+        match chain_storage.validate_block(3).unwrap_err().kind {
+            OpErrorKind::DeserializeError(err) => assert_eq!(err.height, Some(3)),
+            kind => panic!("Unexpected error: {}", kind),
+        }
+        // A parsing run fails on it as well instead of ending the chain at height 2
+        for threads in [1, 2] {
+            let mut parse_options = options(blocks_dir.path(), Box::new(NullCallback));
+            parse_options.incremental = false;
+            parse_options.threads = threads;
+            let chain_storage = ChainStorage::new(&parse_options).unwrap();
+            let err = BlockchainParser::new(parse_options, Box::new(chain_storage))
+                .start()
+                .unwrap_err();
+            match err.kind {
+                OpErrorKind::DeserializeError(err) => assert_eq!(err.height, Some(3)),
+                kind => panic!("Unexpected error: {}", kind),
+            }
+        }
+    }
+
+    #[test]
+    fn test_verify() {
+        let blocks_dir = tempfile::tempdir().unwrap();
+        let dump_folder = tempfile::tempdir().unwrap();
+        let blocks = chain(4);
+        testutils::write_blocks_dir(blocks_dir.path(), &blocks);
+        let run = || {
+            let mut options = options(blocks_dir.path(), csvdump(dump_folder.path()));
+            // The synthetic genesis block doesn't match the real one
+            options.range = BlockHeightRange::new(1, None).unwrap();
+            options.incremental = false;
+            options.verify = true;
+            let chain_storage = ChainStorage::new(&options)?;
+            BlockchainParser::new(options, Box::new(chain_storage)).start()
+        };
+        run().unwrap();
+        assert!(dump_folder.path().join("blocks-1-3.csv").exists());
+        fs::remove_file(dump_folder.path().join("blocks-1-3.csv")).unwrap();
+
+        // Raises the difficulty of block 2 in its header, so its hash doesn't meet the target anymore
+        let blk_path = blocks_dir.path().join("blk00000.dat");
+        let mut blk_data = fs::read(&blk_path).unwrap();
+        let offset = 2 * 8 + blocks[..2].iter().map(|b| b.size as usize).sum::<usize>() + 8;
+        assert_eq!(
+            blk_data[offset + 72..offset + 76],
+            0x207fffffu32.to_le_bytes()
+        );
+        blk_data[offset + 72..offset + 76].copy_from_slice(&0x1d00ffffu32.to_le_bytes());
+        fs::write(&blk_path, &blk_data).unwrap();
+        let err = run().unwrap_err();
+        assert!(
+            err.to_string().contains("doesn't meet the target"),
+            "{}",
+            err
+        );
+        // The callback didn't complete, so there is no output from the corrupted block
+        assert!(!dump_folder.path().join("blocks-1-3.csv").exists());
+        assert!(!dump_folder.path().join("blocks-1-2.csv").exists());
+    }
+
+    #[test]
+    fn test_error_log() {
+        let blocks_dir = tempfile::tempdir().unwrap();
+        testutils::write_blocks_dir(blocks_dir.path(), &chain(4));
+        let blk_path = blocks_dir.path().join("blk00000.dat");
+        let mut blk_data = fs::read(&blk_path).unwrap();
+        *blk_data.last_mut().unwrap() ^= 0x01;
+        fs::write(&blk_path, &blk_data).unwrap();
+
+        let _ = SimpleLogger::init(LevelFilter::Off);
+        let log_dir = tempfile::tempdir().unwrap();
+        let log_path = log_dir.path().join("errors.jsonl");
+        errorlog::open(&log_path).unwrap();
+        let mut options = options(blocks_dir.path(), Box::new(NullCallback));
+        options.range = BlockHeightRange::new(1, None).unwrap();
+        options.incremental = false;
+        options.validate_only = true;
+        let chain_storage = ChainStorage::new(&options).unwrap();
+        assert!(BlockchainParser::new(options, Box::new(chain_storage))
+            .start()
+            .is_err());
+        errorlog::close().unwrap();
+
+        // Other tests may log concurrently
+        let records = fs::read_to_string(&log_path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .collect::<Vec<_>>();
+        assert!(records.iter().any(|record| record["height"] == 3
+            && record["level"] == "ERROR"
+            && record["category"] == "parser"
+            && record["detail"]
+                .as_str()
+                .unwrap()
+                .contains("Invalid merkle_root")));
+    }
+
+    #[test]
+    fn test_empty_blocks_dir() {
+        let blocks_dir = tempfile::tempdir().unwrap();
+        let dump_folder = tempfile::tempdir().unwrap();
+        let options = options(blocks_dir.path(), csvdump(dump_folder.path()));
+        let err = ChainStorage::new(&options).err().unwrap();
+        assert!(err.to_string().starts_with("No blk files found in"));
+        // The block index must not be created in the wrong directory
+        assert!(!blocks_dir.path().join("index").exists());
+    }
+
+    #[test]
+    fn test_undo() {
+        let mut blocks: Vec<Block> = Vec::new();
+        for height in 0..4 {
+            let prev_hash = blocks
+                .last()
+                .map_or(sha256d::Hash::all_zeros(), |b| b.header.hash);
+            let mut txs = vec![testutils::coinbase(
+                height,
+                &[(5000, testutils::p2pkh_script(0))],
+            )];
+            if let Some(prev) = blocks.last() {
+                txs.push(testutils::tx(
+                    &[(prev.txs[0].hash, 0)],
+                    &[(4000, testutils::p2pkh_script(1))],
+                ));
+            }
+            blocks.push(testutils::block(prev_hash, height as u32 * 600, txs));
+        }
+        let blocks_dir = tempfile::tempdir().unwrap();
+        testutils::write_blocks_dir_with_undo(blocks_dir.path(), &blocks);
+        let fees = |dump_folder: &Path| -> Box<dyn Callback> {
+            let matches = Fees::build_subcommand().get_matches_from([
+                "fees",
+                dump_folder.to_str().unwrap(),
+                "--undo",
+            ]);
+            Box::new(Fees::new(&matches).unwrap())
+        };
+
+        let dump_folder = tempfile::tempdir().unwrap();
+        let undos = testutils::block_undos(&blocks);
+        for threads in [1, 2] {
+            let mut undo_options = options(blocks_dir.path(), fees(dump_folder.path()));
+            undo_options.threads = threads;
+            let mut chain_storage = ChainStorage::new(&undo_options).unwrap();
+            for height in [0, 1, 3, 2] {
+                let block = chain_storage.get_block(height).unwrap().unwrap();
+                assert_eq!(block.undo.as_ref(), Some(&undos[height as usize]));
+            }
+        }
+        assert_eq!(undos[2].spent_value(1), Some(5000));
+        // The rev files are only read if the callback needs them
+        let null_options = options(blocks_dir.path(), Box::new(NullCallback));
+        let mut chain_storage = ChainStorage::new(&null_options).unwrap();
+        assert!(chain_storage.get_block(1).unwrap().unwrap().undo.is_none());
+
+        // Fees are known from any start height and in reverse
+        let mut reverse_options = options(blocks_dir.path(), fees(dump_folder.path()));
+        reverse_options.incremental = false;
+        reverse_options.range = BlockHeightRange::new(2, None).unwrap();
+        reverse_options.reverse = true;
+        let chain_storage = ChainStorage::new(&reverse_options).unwrap();
+        BlockchainParser::new(reverse_options, Box::new(chain_storage))
+            .start()
+            .unwrap();
+        let csv = fs::read_to_string(dump_folder.path().join("fees-3-2.csv")).unwrap();
+        let fees_column: Vec<&str> = csv
+            .lines()
+            .skip(1)
+            .map(|line| line.split(';').nth(2).unwrap())
+            .collect();
+        assert_eq!(fees_column, vec!["1000", "1000"]);
+
+        fs::remove_file(blocks_dir.path().join("rev00000.dat")).unwrap();
+        let missing_options = options(blocks_dir.path(), fees(dump_folder.path()));
+        let mut chain_storage = ChainStorage::new(&missing_options).unwrap();
+        assert!(chain_storage.get_block(0).unwrap().is_some());
+        let err = chain_storage.get_block(1).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("undo data of block 1 from rev00000.dat"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_threads() {
+        let blocks_dir = tempfile::tempdir().unwrap();
+        let blocks = chain(25);
+        testutils::write_blocks_dir(blocks_dir.path(), &blocks);
+        let dump = |threads: usize, reverse: bool| {
+            let dump_folder = tempfile::tempdir().unwrap();
+            let mut options = options(blocks_dir.path(), csvdump(dump_folder.path()));
+            options.incremental = false;
+            options.threads = threads;
+            options.reverse = reverse;
+            let chain_storage = ChainStorage::new(&options).unwrap();
+            BlockchainParser::new(options, Box::new(chain_storage))
+                .start()
+                .unwrap();
+            let name = if reverse {
+                "blocks-24-0.csv"
+            } else {
+                "blocks-0-24.csv"
+            };
+            fs::read_to_string(dump_folder.path().join(name)).unwrap()
+        };
+        assert_eq!(dump(4, false), dump(1, false));
+        assert_eq!(dump(3, true), dump(1, true));
+
+        // Blocks read ahead are dropped if another height is requested
+        let mut options = options(blocks_dir.path(), Box::new(NullCallback));
+        options.threads = 2;
+        let mut chain_storage = ChainStorage::new(&options).unwrap();
+        for height in [0, 1, 2, 10, 11, 5, 24] {
+            let block = chain_storage.get_block(height).unwrap().unwrap();
+            assert_eq!(block.hash(), blocks[height as usize].hash());
+        }
+        assert!(chain_storage.get_block(25).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_dry_run() {
+        let blocks_dir = tempfile::tempdir().unwrap();
+        let dump_folder = tempfile::tempdir().unwrap();
+        testutils::write_blocks_dir(blocks_dir.path(), &chain(3));
+
+        let mut options = options(blocks_dir.path(), csvdump(dump_folder.path()));
+        options.dry_run = true;
+        let chain_storage = ChainStorage::new(&options).unwrap();
+        let mut parser = BlockchainParser::new(options, Box::new(chain_storage));
+        parser.start().unwrap();
+
+        let plan = parser.dry_run_plan.as_ref().unwrap();
+        assert_eq!(plan.blk_files, 1);
+        assert_eq!(plan.block_count(), 3);
+        assert_eq!(plan.dump_folder.as_deref(), Some(dump_folder.path()));
+        // Nothing has been processed
+        assert_eq!(parser.cur_height, 0);
+        assert!(!dump_folder.path().join("blocks-0-2.csv").exists());
+        assert!(!ParserState::path(dump_folder.path(), &CoinType::from(Bitcoin)).exists());
+    }
+
+    #[test]
+    fn test_incremental_detects_reorg() {
+        let blocks_dir = tempfile::tempdir().unwrap();
+        let dump_folder = tempfile::tempdir().unwrap();
+        testutils::write_blocks_dir(blocks_dir.path(), &chain(2));
+        run(blocks_dir.path(), csvdump(dump_folder.path())).unwrap();
+
+        let state_path = ParserState::path(dump_folder.path(), &CoinType::from(Bitcoin));
+        let stale = ParserState {
+            height: 1,
+            block_hash: sha256d::Hash::hash(&[0xff]),
+        };
+        stale.save(&state_path).unwrap();
+
+        assert!(run(blocks_dir.path(), csvdump(dump_folder.path())).is_err());
+    }
+
+    #[test]
+    fn test_filename_template() {
+        let blocks_dir = tempfile::tempdir().unwrap();
+        let dump_folder = tempfile::tempdir().unwrap();
+        testutils::write_blocks_dir(blocks_dir.path(), &chain(3));
+        let mut options = options(blocks_dir.path(), csvdump(dump_folder.path()));
+        options.filename_template = Some(
+            utils::FilenameTemplate::new(
+                "{coin}_{network}_{callback}_{start}_{end}",
+                &options.coin,
+            )
+            .unwrap(),
+        );
+        let chain_storage = ChainStorage::new(&options).unwrap();
+        BlockchainParser::new(options, Box::new(chain_storage))
+            .start()
+            .unwrap();
+
+        let blocks = dump_folder.path().join("bitcoin_mainnet_blocks_0_2.csv");
+        assert_eq!(fs::read_to_string(blocks).unwrap().lines().count(), 3);
+        assert!(dump_folder
+            .path()
+            .join("bitcoin_mainnet_transactions_0_2.csv")
+            .exists());
+        assert!(!dump_folder.path().join("blocks-0-2.csv").exists());
+    }
+
+    #[test]
+    fn test_output_config() {
+        let blocks_dir = tempfile::tempdir().unwrap();
+        let dump_folder = tempfile::tempdir().unwrap();
+        let output_dir = dump_folder.path().join("out");
+        fs::create_dir(&output_dir).unwrap();
+        testutils::write_blocks_dir(blocks_dir.path(), &chain(3));
+        let mut options = options(blocks_dir.path(), csvdump(dump_folder.path()));
+        options.output = utils::OutputConfig::new(
+            Some(output_dir.clone()),
+            Some(String::from("run1_")),
+            Some(utils::Compression::Gzip),
+        )
+        .unwrap();
+        let chain_storage = ChainStorage::new(&options).unwrap();
+        BlockchainParser::new(options, Box::new(chain_storage))
+            .start()
+            .unwrap();
+
+        let file = fs::File::open(output_dir.join("run1_blocks-0-2.csv.gz")).unwrap();
+        let mut csv = String::new();
+        std::io::Read::read_to_string(&mut flate2::read::GzDecoder::new(file), &mut csv).unwrap();
+        assert_eq!(csv.lines().count(), 3);
+        assert!(!output_dir.join("run1_blocks-0-2.csv").exists());
+        assert!(output_dir.join("run1_transactions-0-2.csv.gz").exists());
+        assert!(!dump_folder.path().join("blocks-0-2.csv").exists());
+        // The summary stays in the dump folder
+        assert!(dump_folder.path().join("summary.json").exists());
+    }
 }
@@ -0,0 +1,159 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use crate::blockchain::parser::blkfile::RawBlock;
+use crate::blockchain::parser::types::CoinType;
+use crate::blockchain::proto::block::Block;
+use crate::common::errorlog;
+use crate::errors::{OpError, OpErrorKind, OpResult};
+
+/// Serialized block which is decoded by a worker
+struct Job {
+    generation: u64,
+    height: u64,
+    offset: u64,
+    block_size: u32,
+    raw: Vec<u8>,
+}
+
+struct Decoded {
+    generation: u64,
+    height: u64,
+    block: OpResult<Block>,
+}
+
+/// Decodes blocks ahead of the parser on a pool of worker threads (`--threads`).
+/// The blk files are still read by the parser thread, only the decoding of the raw bytes runs in parallel.
+/// Blocks are decoded in any order and handed out in the order they were requested,
+/// so the callback still sees them strictly by height.
+pub struct Prefetcher {
+    jobs: Option<Sender<Job>>,
+    results: Receiver<Decoded>,
+    workers: Vec<JoinHandle<()>>,
+    // Requested heights which haven't been taken yet, in request order
+    pending: VecDeque<u64>,
+    decoded: HashMap<u64, OpResult<Block>>,
+    // Incremented by `reset()`, results of jobs submitted before are dropped
+    generation: u64,
+    window: usize,
+}
+
+impl Prefetcher {
+    /// Starts the given number of workers. At most `window` blocks are read ahead.
+    pub fn new(threads: usize, window: usize, coin: &CoinType, keep_raw: bool) -> Self {
+        let (jobs, job_rx) = mpsc::channel::<Job>();
+        let (result_tx, results) = mpsc::channel();
+        let job_rx = Arc::new(Mutex::new(job_rx));
+        let workers = (0..threads)
+            .map(|_| {
+                let job_rx = Arc::clone(&job_rx);
+                let result_tx = result_tx.clone();
+                let coin = coin.clone();
+                thread::spawn(move || loop {
+                    let job = match job_rx.lock().map(|rx| rx.recv()) {
+                        Ok(Ok(job)) => job,
+                        // The prefetcher was dropped
+                        _ => break,
+                    };
+                    errorlog::set_height(Some(job.height));
+                    let raw_block = RawBlock {
+                        offset: job.offset,
+                        size: job.block_size,
+                        data: &job.raw,
+                    };
+                    let block = raw_block
+                        .decode(&coin, keep_raw)
+                        .map_err(|err| err.at_height(job.height));
+                    let decoded = Decoded {
+                        generation: job.generation,
+                        height: job.height,
+                        block,
+                    };
+                    if result_tx.send(decoded).is_err() {
+                        break;
+                    }
+                })
+            })
+            .collect();
+        Self {
+            jobs: Some(jobs),
+            results,
+            workers,
+            pending: VecDeque::with_capacity(window),
+            decoded: HashMap::with_capacity(window),
+            generation: 0,
+            window,
+        }
+    }
+
+    /// Returns true if another block can be read ahead
+    pub fn has_capacity(&self) -> bool {
+        self.pending.len() < self.window
+    }
+
+    /// Returns the last requested height which hasn't been taken yet
+    pub fn last_requested(&self) -> Option<u64> {
+        self.pending.back().copied()
+    }
+
+    /// Returns true if the given height is the next one to be taken
+    pub fn is_next(&self, height: u64) -> bool {
+        self.pending.front() == Some(&height)
+    }
+
+    /// Hands the serialized block at the given height and blk file offset to the workers
+    pub fn submit(&mut self, height: u64, offset: u64, block_size: u32, raw: Vec<u8>) {
+        let job = Job {
+            generation: self.generation,
+            height,
+            offset,
+            block_size,
+            raw,
+        };
+        self.pending.push_back(height);
+        // The workers only stop once the sender is dropped
+        self.jobs.as_ref().unwrap().send(job).unwrap();
+    }
+
+    /// Records a block which couldn't be read, the error is returned once the height is taken
+    pub fn submit_err(&mut self, height: u64, err: OpError) {
+        self.pending.push_back(height);
+        self.decoded.insert(height, Err(err));
+    }
+
+    /// Waits until the next requested block is decoded and returns it
+    pub fn take(&mut self) -> OpResult<Block> {
+        let height = self.pending.pop_front().ok_or_else(|| {
+            OpError::new(OpErrorKind::RuntimeError).join_msg("No block has been requested")
+        })?;
+        loop {
+            if let Some(block) = self.decoded.remove(&height) {
+                return block;
+            }
+            let decoded = self.results.recv().map_err(|_| {
+                OpError::new(OpErrorKind::RuntimeError).join_msg("Block decoder stopped")
+            })?;
+            if decoded.generation == self.generation {
+                self.decoded.insert(decoded.height, decoded.block);
+            }
+        }
+    }
+
+    /// Drops all blocks read ahead, e.g. if the parser continues at another height
+    pub fn reset(&mut self) {
+        self.generation += 1;
+        self.pending.clear();
+        self.decoded.clear();
+    }
+}
+
+impl Drop for Prefetcher {
+    fn drop(&mut self) {
+        self.jobs = None;
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
@@ -0,0 +1,249 @@
+//! Periodic progress log of the parser and the status file of `--progress-json`
+use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde_json::{json, Map, Value};
+
+use crate::errors::OpResult;
+
+/// Position of the parser which is reported
+pub struct Status {
+    pub height: u64,
+    pub end_height: u64,
+    pub remaining: u64,
+    pub n_blocks: u64,
+    pub n_txs: u64,
+}
+
+/// Logs the throughput, the estimated time left and the memory usage every `frame`,
+/// and writes the same as JSON for other tools to poll
+pub struct Progress {
+    started_at: Instant,
+    last_log: Instant,
+    last_blocks: u64,
+    last_txs: u64,
+    frame: Duration,
+    json_path: Option<PathBuf>,
+}
+
+impl Progress {
+    pub fn new(json_path: Option<PathBuf>) -> Self {
+        let now = Instant::now();
+        Self {
+            started_at: now,
+            last_log: now,
+            last_blocks: 0,
+            last_txs: 0,
+            frame: Duration::from_secs(10),
+            json_path,
+        }
+    }
+
+    /// Measures from now on, the first report follows after one frame
+    pub fn restart(&mut self) {
+        *self = Progress::new(self.json_path.take());
+    }
+
+    /// Whether the next report is due, so counters only need to be gathered once per frame
+    pub fn is_due(&self) -> bool {
+        Instant::now() - self.last_log > self.frame
+    }
+
+    /// Reports the status and the counters of the callback, `log` adds the log line
+    pub fn report(
+        &mut self,
+        status: &Status,
+        counters: &[(&'static str, u64)],
+        log: bool,
+    ) -> OpResult<()> {
+        let now = Instant::now();
+        let frame_secs = (now - self.last_log).as_secs_f64();
+        let per_sec = |n: u64| {
+            if frame_secs > 0.0 {
+                n as f64 / frame_secs
+            } else {
+                0.0
+            }
+        };
+        let blocks_per_sec = per_sec(status.n_blocks - self.last_blocks);
+        let txs_per_sec = per_sec(status.n_txs - self.last_txs);
+        let eta = self.eta(status);
+        let memory = memory_usage();
+
+        if log {
+            let mut extra = String::new();
+            if let Some(eta) = eta {
+                extra.push_str(&format!(", ETA: {}", format_duration(eta)));
+            }
+            if let Some(bytes) = memory {
+                extra.push_str(&format!(", memory: {} MB", bytes / 1_000_000));
+            }
+            for (name, value) in counters {
+                extra.push_str(&format!(", {}: {}", name, value));
+            }
+            info!(target: "parser", "Status: {:7} Blocks processed. (remaining: {:7}, speed: {:5.2} blocks/s, {:.0} tx/s{})",
+                  status.height, status.remaining, blocks_per_sec, txs_per_sec, extra);
+        }
+        self.write_json(
+            "running",
+            status,
+            counters,
+            blocks_per_sec,
+            txs_per_sec,
+            None,
+        )?;
+        self.last_log = now;
+        self.last_blocks = status.n_blocks;
+        self.last_txs = status.n_txs;
+        Ok(())
+    }
+
+    /// Writes the final status, with `error` if the run failed
+    pub fn finish(
+        &self,
+        status: &Status,
+        counters: &[(&'static str, u64)],
+        error: Option<String>,
+    ) -> OpResult<()> {
+        let secs = (Instant::now() - self.started_at).as_secs_f64();
+        let per_sec = |n: u64| if secs > 0.0 { n as f64 / secs } else { 0.0 };
+        let state = if error.is_some() {
+            "failed"
+        } else {
+            "completed"
+        };
+        self.write_json(
+            state,
+            status,
+            counters,
+            per_sec(status.n_blocks),
+            per_sec(status.n_txs),
+            error,
+        )
+    }
+
+    /// Remaining blocks at the average speed since the start
+    fn eta(&self, status: &Status) -> Option<Duration> {
+        let secs = (Instant::now() - self.started_at).as_secs_f64();
+        if status.n_blocks == 0 || secs <= 0.0 {
+            return None;
+        }
+        Some(Duration::from_secs_f64(
+            status.remaining as f64 * secs / status.n_blocks as f64,
+        ))
+    }
+
+    /// Replaces the status file, so readers never see a partially written one
+    fn write_json(
+        &self,
+        state: &str,
+        status: &Status,
+        counters: &[(&'static str, u64)],
+        blocks_per_sec: f64,
+        txs_per_sec: f64,
+        error: Option<String>,
+    ) -> OpResult<()> {
+        let path = match &self.json_path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let counters: Map<String, Value> = counters
+            .iter()
+            .map(|(name, value)| (String::from(*name), json!(value)))
+            .collect();
+        let eta = match state {
+            "running" => self.eta(status).map(|eta| eta.as_secs()),
+            _ => Some(0),
+        };
+        let updated_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        let report = json!({
+            "state": state,
+            "height": status.height,
+            "end_height": status.end_height,
+            "remaining": status.remaining,
+            "blocks_processed": status.n_blocks,
+            "transactions_processed": status.n_txs,
+            "blocks_per_sec": blocks_per_sec,
+            "transactions_per_sec": txs_per_sec,
+            "elapsed_secs": (Instant::now() - self.started_at).as_secs_f64(),
+            "eta_secs": eta,
+            "memory_bytes": memory_usage(),
+            "updated_at": updated_at,
+            "counters": counters,
+            "error": error,
+        });
+        let mut tmp_path = path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        fs::write(&tmp_path, serde_json::to_string_pretty(&report).unwrap())?;
+        fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+}
+
+/// Resident memory of the process, only known on Linux
+pub fn memory_usage() -> Option<u64> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+    let kb: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kb * 1024)
+}
+
+/// Formats a duration as `1h 02m 03s`, omitting leading zero units
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    let (hours, minutes, secs) = (secs / 3600, secs / 60 % 60, secs % 60);
+    match (hours, minutes) {
+        (0, 0) => format!("{}s", secs),
+        (0, _) => format!("{}m {:02}s", minutes, secs),
+        _ => format!("{}h {:02}m {:02}s", hours, minutes, secs),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!(format_duration(Duration::from_secs(0)), "0s");
+        assert_eq!(format_duration(Duration::from_secs(59)), "59s");
+        assert_eq!(format_duration(Duration::from_secs(62)), "1m 02s");
+        assert_eq!(format_duration(Duration::from_secs(3723)), "1h 02m 03s");
+        assert_eq!(format_duration(Duration::from_secs(90000)), "25h 00m 00s");
+    }
+
+    #[test]
+    fn test_progress_json() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("status.json");
+        let mut progress = Progress::new(Some(path.clone()));
+        let mut status = Status {
+            height: 10,
+            end_height: 30,
+            remaining: 20,
+            n_blocks: 11,
+            n_txs: 50,
+        };
+        progress.report(&status, &[("unspents", 7)], false).unwrap();
+        let report: Value = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(report["state"], "running");
+        assert_eq!(report["height"], 10);
+        assert_eq!(report["remaining"], 20);
+        assert_eq!(report["transactions_processed"], 50);
+        assert_eq!(report["counters"]["unspents"], 7);
+        assert!(report["eta_secs"].is_u64());
+
+        status.height = 30;
+        status.remaining = 0;
+        progress
+            .finish(&status, &[], Some(String::from("failed")))
+            .unwrap();
+        let report: Value = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(report["state"], "failed");
+        assert_eq!(report["eta_secs"], 0);
+        assert!(!dir.path().join("status.json.tmp").exists());
+    }
+}
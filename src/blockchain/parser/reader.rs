@@ -10,6 +10,7 @@ use crate::blockchain::proto::header::BlockHeader;
 use crate::blockchain::proto::tx::{RawTx, TxInput, TxOutpoint, TxOutput};
 use crate::blockchain::proto::varuint::VarUint;
 use crate::blockchain::proto::MerkleBranch;
+use crate::common::bench::{self, Stage};
 use crate::errors::OpResult;
 
 /// Trait for structured reading of blockchain data
@@ -26,18 +27,44 @@ pub trait BlockchainRead: io::Read {
         Ok(arr)
     }
 
+    /// Reads a VARINT as used by Bitcoin Core for its own storage formats (block index, chainstate, snapshots).
+    /// TODO: this is a wonky 1:1 translation from https://github.com/bitcoin/bitcoin
+    /// It is NOT the same as CompactSize.
+    fn read_core_varint(&mut self) -> OpResult<u64> {
+        let mut n = 0;
+        loop {
+            let ch_data = self.read_u8()?;
+            if n > u64::MAX >> 7 {
+                panic!("size too large");
+            }
+            n = (n << 7) | (ch_data & 0x7F) as u64;
+            if ch_data & 0x80 > 0 {
+                if n == u64::MAX {
+                    panic!("size too large");
+                }
+                n += 1;
+            } else {
+                break;
+            }
+        }
+        Ok(n)
+    }
+
     /// Reads a block as specified here: https://en.bitcoin.it/wiki/Protocol_specification#block
     fn read_block(&mut self, size: u32, coin: &CoinType) -> OpResult<Block> {
-        let header = self.read_block_header()?;
-        // Parse AuxPow data if present
-        let aux_pow_extension = match coin.aux_pow_activation_version {
-            Some(version) if header.version >= version => {
-                Some(self.read_aux_pow_extension(coin.version_id)?)
-            }
-            _ => None,
-        };
-        let tx_count = VarUint::read_from(self)?;
-        let txs = self.read_txs(tx_count.value, coin.version_id)?;
+        let (header, aux_pow_extension, tx_count, txs) = bench::time(Stage::Deserialize, || {
+            let header = self.read_block_header()?;
+            // Parse AuxPow data if present
+            let aux_pow_extension = match coin.aux_pow_activation_version {
+                Some(version) if header.version >= version => {
+                    Some(self.read_aux_pow_extension(coin)?)
+                }
+                _ => None,
+            };
+            let tx_count = VarUint::read_from(self)?;
+            let txs = self.read_txs(tx_count.value, coin)?;
+            OpResult::Ok((header, aux_pow_extension, tx_count, txs))
+        })?;
         Ok(Block::new(size, header, aux_pow_extension, tx_count, txs))
     }
 
@@ -59,12 +86,12 @@ pub trait BlockchainRead: io::Read {
         })
     }
 
-    fn read_txs(&mut self, tx_count: u64, version_id: u8) -> OpResult<Vec<RawTx>> {
-        (0..tx_count).map(|_| self.read_tx(version_id)).collect()
+    fn read_txs(&mut self, tx_count: u64, coin: &CoinType) -> OpResult<Vec<RawTx>> {
+        (0..tx_count).map(|_| self.read_tx(coin)).collect()
     }
 
     /// Reads a transaction as specified here: https://en.bitcoin.it/wiki/Protocol_specification#tx
-    fn read_tx(&mut self, version_id: u8) -> OpResult<RawTx> {
+    fn read_tx(&mut self, coin: &CoinType) -> OpResult<RawTx> {
         let mut flags = 0u8;
         let version = self.read_u32::<LittleEndian>()?;
 
@@ -75,7 +102,7 @@ pub trait BlockchainRead: io::Read {
             // TODO: handle segwit data
             in_count = VarUint::read_from(self)?
         }
-        let inputs = self.read_tx_inputs(in_count.value)?;
+        let mut inputs = self.read_tx_inputs(in_count.value)?;
 
         // Parse transaction outputs
         let out_count = VarUint::read_from(self)?;
@@ -83,11 +110,14 @@ pub trait BlockchainRead: io::Read {
 
         // Check if the witness flag is present
         if flags & 1 > 0 {
-            for _ in 0..in_count.value {
+            for input in inputs.iter_mut() {
                 let item_count = VarUint::read_from(self)?;
+                input.witness = Vec::with_capacity(item_count.value as usize);
                 for _ in 0..item_count.value {
                     let witness_len = VarUint::read_from(self)?;
-                    let _ = self.read_u8_vec(witness_len.value as u32)?;
+                    input
+                        .witness
+                        .push(self.read_u8_vec(witness_len.value as u32)?);
                 }
             }
         }
@@ -99,7 +129,8 @@ pub trait BlockchainRead: io::Read {
             out_count,
             outputs,
             locktime,
-            version_id,
+            version_id: coin.version_id,
+            encoder: coin.address_encoder.clone(),
         };
         Ok(tx)
     }
@@ -123,6 +154,7 @@ pub trait BlockchainRead: io::Read {
                 script_len,
                 script_sig,
                 seq_no,
+                witness: Vec::new(),
             });
         }
         Ok(inputs)
@@ -155,8 +187,8 @@ pub trait BlockchainRead: io::Read {
     }
 
     /// Reads the additional AuxPow fields as specified here https://en.bitcoin.it/wiki/Merged_mining_specification#Aux_proof-of-work_block
-    fn read_aux_pow_extension(&mut self, version_id: u8) -> OpResult<AuxPowExtension> {
-        let coinbase_tx = self.read_tx(version_id)?;
+    fn read_aux_pow_extension(&mut self, coin: &CoinType) -> OpResult<AuxPowExtension> {
+        let coinbase_tx = self.read_tx(coin)?;
         let block_hash = sha256d::Hash::from_byte_array(self.read_256hash()?);
 
         let coinbase_branch = self.read_merkle_branch()?;
@@ -317,12 +349,17 @@ mod tests {
 
         let script_pubkey = &block.txs[0].value.outputs[0].out.script_pubkey;
         assert_eq!("4104678afdb0fe5548271967f1a67130b7105cd6a828e03909a67962e0ea1f61deb649f6bc3f4cef38c4f35504e51ec112de5c384df7ba0b8d578a4c702b6bf11d5fac",
-                                utils::arr_to_hex(&script_pubkey));
+                                utils::arr_to_hex(script_pubkey));
         assert_eq!(0x00000000, block.txs[0].value.locktime);
 
         assert_eq!(
             Some(String::from("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa")),
-            script::eval_from_bytes(script_pubkey, Bitcoin.version_id()).address
+            script::eval_from_bytes(
+                script_pubkey,
+                Bitcoin.version_id(),
+                Bitcoin.address_encoder().as_ref()
+            )
+            .address
         );
     }
 
@@ -371,10 +408,10 @@ mod tests {
         let inner = Cursor::new(raw_data);
         let mut reader = BufReader::with_capacity(200, inner);
         let txs: Vec<EvaluatedTx> = reader
-            .read_txs(1, 0x00)
+            .read_txs(1, &CoinType::default())
             .unwrap()
             .into_iter()
-            .map(|raw| EvaluatedTx::from(raw))
+            .map(EvaluatedTx::from)
             .collect();
         assert_eq!(txs.len(), 1);
 
@@ -393,6 +430,9 @@ mod tests {
         assert_eq!(tx.inputs[0].outpoint.index, 3);
         assert_eq!(tx.inputs[0].script_len.value, 23);
         assert_eq!(tx.inputs[0].seq_no, 0xffffffff);
+        assert_eq!(tx.inputs[0].witness.len(), 2);
+        assert_eq!(tx.inputs[0].witness[0].len(), 72);
+        assert_eq!(tx.inputs[0].witness[1].len(), 33);
 
         // Assert outputs
         assert_eq!(tx.out_count.value, 1);
@@ -409,6 +449,10 @@ mod tests {
         );
 
         assert_eq!(tx.locktime, 0);
+
+        // 108 non-witness bytes and 110 witness bytes
+        assert_eq!(tx.weight(), 542);
+        assert_eq!(tx.vsize(), 136);
     }
 
     #[test]
@@ -614,7 +658,7 @@ mod tests {
 
         let script_pubkey = &block.txs[0].value.outputs[0].out.script_pubkey;
         assert_eq!("410489fe91e62847575c98deeab020f65fdff17a3a870ebb05820b414f3d8097218ec9a65f1e0ae0ac35af7247bd79ed1f2a24675fffb5aa6f9620e1920ad4bf5aa6ac",
-                                utils::arr_to_hex(&script_pubkey));
+                                utils::arr_to_hex(script_pubkey));
         assert_eq!(0x00000000, block.txs[0].value.locktime);
 
         /*
@@ -623,7 +667,8 @@ mod tests {
             Right: Some("NHk86XHZ77H2uNgESo4ut598orZq8rcVKL")
         assert_eq!(
             Some(String::from("N1hd3xArZM8BaX2PGGvoTWDr7C66Payv7b")),
-            script::eval_from_bytes(script_pubkey, Namecoin.version_id()).address
+            script::eval_from_bytes(script_pubkey, Namecoin.version_id(), Namecoin.address_encoder().as_ref())
+                .address
         );*/
     }
 
@@ -913,13 +958,18 @@ mod tests {
         let script_pubkey = &block.txs[0].value.outputs[0].out.script_pubkey;
         assert_eq!(
             "2103829125d35fad23dcc6526e73bce0b18aa7c0897e0fc5d39e75e43af96b950748ac",
-            utils::arr_to_hex(&script_pubkey)
+            utils::arr_to_hex(script_pubkey)
         );
         assert_eq!(0x00000000, block.txs[0].value.locktime);
 
         assert_eq!(
             Some(String::from("DEfXb18bE8RoC6edc9jXaMpEpuvVkcjJFq")),
-            script::eval_from_bytes(script_pubkey, Dogecoin.version_id()).address
+            script::eval_from_bytes(
+                script_pubkey,
+                Dogecoin.version_id(),
+                Dogecoin.address_encoder().as_ref()
+            )
+            .address
         );
     }
 }
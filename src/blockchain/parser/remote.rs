@@ -0,0 +1,286 @@
+use std::fmt;
+use std::io::{self, Read, Seek, SeekFrom};
+
+use crate::errors::{OpError, OpErrorKind, OpResult};
+
+/// Number of bytes fetched per range request. Large enough for every block
+/// and small compared to the size of a blk file (128 MiB).
+const CHUNK_SIZE: u64 = 8 * 1024 * 1024;
+
+/// Object store serving blk*.dat files over HTTP(S), either a plain web server
+/// supporting range requests or an S3-compatible bucket with anonymous read access
+#[derive(Clone)]
+pub struct RemoteSource {
+    base_url: String,
+    agent: ureq::Agent,
+}
+
+impl RemoteSource {
+    /// Parses `http(s)://host/prefix` or `s3://bucket/prefix`.
+    /// S3 URIs are resolved to virtual-hosted AWS URLs, or path-style URLs if `AWS_ENDPOINT_URL` is set.
+    pub fn parse(uri: &str) -> OpResult<Self> {
+        let uri = uri.trim_end_matches('/');
+        let base_url = if uri.starts_with("http://") || uri.starts_with("https://") {
+            String::from(uri)
+        } else if let Some(path) = uri.strip_prefix("s3://") {
+            let (bucket, prefix) = match path.split_once('/') {
+                Some((bucket, prefix)) => (bucket, format!("/{}", prefix)),
+                None => (path, String::new()),
+            };
+            if bucket.is_empty() {
+                let msg = format!("Missing bucket name in {}", uri);
+                return Err(OpError::new(OpErrorKind::InvalidArgsError).join_msg(&msg));
+            }
+            match std::env::var("AWS_ENDPOINT_URL") {
+                Ok(endpoint) => format!("{}/{}{}", endpoint.trim_end_matches('/'), bucket, prefix),
+                Err(_) => format!("https://{}.s3.amazonaws.com{}", bucket, prefix),
+            }
+        } else {
+            let msg = format!("Unsupported source {}, expected http(s):// or s3://", uri);
+            return Err(OpError::new(OpErrorKind::InvalidArgsError).join_msg(&msg));
+        };
+        Ok(Self {
+            base_url,
+            agent: ureq::Agent::new(),
+        })
+    }
+
+    /// Returns the URL of the given file name
+    pub fn url(&self, file_name: &str) -> String {
+        format!("{}/{}", self.base_url, file_name)
+    }
+
+    /// Returns the size of the given object or None if it doesn't exist
+    pub fn content_length(&self, url: &str) -> OpResult<Option<u64>> {
+        match self.agent.head(url).call() {
+            Ok(response) => match response.header("Content-Length").map(str::parse::<u64>) {
+                Some(Ok(length)) => Ok(Some(length)),
+                _ => {
+                    let msg = format!("Missing Content-Length for {}", url);
+                    Err(OpError::new(OpErrorKind::RuntimeError).join_msg(&msg))
+                }
+            },
+            Err(ureq::Error::Status(404, _)) => Ok(None),
+            Err(err) => Err(http_error(url, err)),
+        }
+    }
+
+    /// Fetches `len` bytes starting at `start` of the given object
+    pub fn read_range(&self, url: &str, start: u64, len: u64) -> OpResult<Vec<u8>> {
+        let range = format!("bytes={}-{}", start, start + len - 1);
+        let response = self
+            .agent
+            .get(url)
+            .set("Range", &range)
+            .call()
+            .map_err(|err| http_error(url, err))?;
+        if response.status() != 206 {
+            let msg = format!(
+                "{} doesn't support range requests (status {})",
+                url,
+                response.status()
+            );
+            return Err(OpError::new(OpErrorKind::RuntimeError).join_msg(&msg));
+        }
+        let mut data = Vec::with_capacity(len as usize);
+        response.into_reader().take(len).read_to_end(&mut data)?;
+        Ok(data)
+    }
+}
+
+impl fmt::Debug for RemoteSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RemoteSource")
+            .field("base_url", &self.base_url)
+            .finish()
+    }
+}
+
+impl fmt::Display for RemoteSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.base_url)
+    }
+}
+
+fn http_error(url: &str, err: ureq::Error) -> OpError {
+    let msg = format!("Request to {} failed: {}", url, err);
+    OpError::new(OpErrorKind::RuntimeError).join_msg(&msg)
+}
+
+/// Reads a remote object through range requests of CHUNK_SIZE bytes
+pub struct HttpReader {
+    source: RemoteSource,
+    url: String,
+    size: u64,
+    pos: u64,
+    buf: Vec<u8>,
+    buf_start: u64,
+}
+
+impl HttpReader {
+    pub fn new(source: RemoteSource, url: String, size: u64) -> Self {
+        Self {
+            source,
+            url,
+            size,
+            pos: 0,
+            buf: Vec::new(),
+            buf_start: 0,
+        }
+    }
+}
+
+impl fmt::Debug for HttpReader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("HttpReader")
+            .field("url", &self.url)
+            .field("size", &self.size)
+            .field("pos", &self.pos)
+            .finish()
+    }
+}
+
+impl Read for HttpReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.size || buf.is_empty() {
+            return Ok(0);
+        }
+        let buf_end = self.buf_start + self.buf.len() as u64;
+        if self.pos < self.buf_start || self.pos >= buf_end {
+            let len = CHUNK_SIZE.min(self.size - self.pos);
+            self.buf = self
+                .source
+                .read_range(&self.url, self.pos, len)
+                .map_err(|err| io::Error::other(err.to_string()))?;
+            self.buf_start = self.pos;
+            if self.buf.is_empty() {
+                return Ok(0);
+            }
+        }
+        let offset = (self.pos - self.buf_start) as usize;
+        let n = buf.len().min(self.buf.len() - offset);
+        buf[..n].copy_from_slice(&self.buf[offset..offset + n]);
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for HttpReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.size.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        };
+        match new_pos {
+            Some(new_pos) => {
+                self.pos = new_pos;
+                Ok(new_pos)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative position",
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    /// Serves the given files with HEAD and ranged GET requests on a random local port.
+    /// Returns the base URL.
+    pub fn serve(files: Vec<(String, Vec<u8>)>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request = String::new();
+                reader.read_line(&mut request).unwrap();
+                let mut range = None;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                    if let Some(value) = line.to_lowercase().strip_prefix("range: bytes=") {
+                        let (start, end) = value.trim().split_once('-').unwrap();
+                        range = Some((
+                            start.parse::<usize>().unwrap(),
+                            end.parse::<usize>().unwrap(),
+                        ));
+                    }
+                }
+                let parts: Vec<&str> = request.split_whitespace().collect();
+                let file = files
+                    .iter()
+                    .find(|(name, _)| parts[1] == format!("/blocks/{}", name));
+                let response = match (file, range) {
+                    (None, _) => b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_vec(),
+                    (Some((_, data)), _) if parts[0] == "HEAD" => format!(
+                        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                        data.len()
+                    )
+                    .into_bytes(),
+                    (Some((_, data)), Some((start, end))) => {
+                        let body = &data[start..=end.min(data.len() - 1)];
+                        let mut response = format!(
+                            "HTTP/1.1 206 Partial Content\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                            body.len()
+                        )
+                        .into_bytes();
+                        response.extend(body);
+                        response
+                    }
+                    (Some(_), None) => b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_vec(),
+                };
+                stream.write_all(&response).unwrap();
+            }
+        });
+        format!("http://{}/blocks", addr)
+    }
+
+    #[test]
+    fn test_parse_source() {
+        let source = RemoteSource::parse("https://example.com/blocks/").unwrap();
+        assert_eq!(
+            source.url("blk00000.dat"),
+            "https://example.com/blocks/blk00000.dat"
+        );
+        if std::env::var("AWS_ENDPOINT_URL").is_err() {
+            let source = RemoteSource::parse("s3://bucket/bitcoin/blocks").unwrap();
+            assert_eq!(
+                source.url("blk00000.dat"),
+                "https://bucket.s3.amazonaws.com/bitcoin/blocks/blk00000.dat"
+            );
+        }
+        assert!(RemoteSource::parse("ftp://example.com").is_err());
+        assert!(RemoteSource::parse("s3://").is_err());
+    }
+
+    #[test]
+    fn test_http_reader() {
+        let data = (0..=255u8).collect::<Vec<u8>>();
+        let base_url = serve(vec![(String::from("blk00000.dat"), data.clone())]);
+        let source = RemoteSource::parse(&base_url).unwrap();
+        let url = source.url("blk00000.dat");
+        assert_eq!(source.content_length(&url).unwrap(), Some(256));
+        assert_eq!(
+            source.content_length(&source.url("blk00001.dat")).unwrap(),
+            None
+        );
+
+        let mut reader = HttpReader::new(source, url, 256);
+        reader.seek(SeekFrom::Start(250)).unwrap();
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, &data[250..]);
+    }
+}
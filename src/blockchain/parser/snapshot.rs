@@ -0,0 +1,486 @@
+use bitcoin::hashes::{sha256, sha256d, Hash, HashEngine};
+use bitcoin::secp256k1::PublicKey;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::path::Path;
+
+use byteorder::{LittleEndian, ReadBytesExt};
+
+use crate::blockchain::parser::reader::BlockchainRead;
+use crate::blockchain::proto::tx::TxOutpoint;
+use crate::blockchain::proto::varuint::VarUint;
+use crate::blockchain::proto::ToRaw;
+use crate::errors::{OpError, OpErrorKind, OpResult};
+
+/// Magic bytes at the beginning of every snapshot written by `dumptxoutset`
+const SNAPSHOT_MAGIC_BYTES: [u8; 5] = [b'u', b't', b'x', b'o', 0xff];
+const SNAPSHOT_VERSION: u16 = 2;
+
+/// Header of a UTXO snapshot as written by Bitcoin Core's `dumptxoutset` (28.0+),
+/// see https://github.com/bitcoin/bitcoin/blob/master/src/node/utxo_snapshot.h
+#[derive(Debug)]
+pub struct SnapshotMetadata {
+    pub network_magic: u32,
+    pub base_block_hash: sha256d::Hash,
+    pub coins_count: u64,
+}
+
+/// A single unspent output contained in a snapshot
+pub struct SnapshotCoin {
+    pub outpoint: TxOutpoint,
+    pub height: u64,
+    pub is_coinbase: bool,
+    pub value: u64,
+    pub script_pubkey: Vec<u8>,
+}
+
+/// Streams all coins from a `dumptxoutset` file without loading it into memory.
+/// Coins are grouped by txid in the file, so the current txid is kept between reads.
+pub struct UtxoSnapshotReader<R: Read> {
+    reader: R,
+    pub metadata: SnapshotMetadata,
+    remaining_coins: u64,
+    cur_txid: sha256d::Hash,
+    remaining_in_tx: u64,
+}
+
+impl UtxoSnapshotReader<BufReader<File>> {
+    /// Opens the given snapshot file and reads its metadata
+    pub fn open(path: &Path) -> OpResult<Self> {
+        let file = File::open(path).map_err(|e| {
+            OpError::from(e).join_msg(&format!("Unable to open {}:", path.display()))
+        })?;
+        Self::new(BufReader::with_capacity(1 << 20, file))
+    }
+}
+
+impl<R: Read> UtxoSnapshotReader<R> {
+    pub fn new(mut reader: R) -> OpResult<Self> {
+        let mut magic = [0u8; 5];
+        reader.read_exact(&mut magic)?;
+        if magic != SNAPSHOT_MAGIC_BYTES {
+            return Err(OpError::new(OpErrorKind::InvalidArgsError).join_msg(
+                "Invalid UTXO snapshot, expected a dumptxoutset file from Bitcoin Core 28.0+.",
+            ));
+        }
+        let version = reader.read_u16::<LittleEndian>()?;
+        if version != SNAPSHOT_VERSION {
+            let msg = format!("Unsupported UTXO snapshot version {}.", version);
+            return Err(OpError::new(OpErrorKind::InvalidArgsError).join_msg(&msg));
+        }
+        let metadata = SnapshotMetadata {
+            network_magic: reader.read_u32::<LittleEndian>()?,
+            base_block_hash: sha256d::Hash::from_byte_array(reader.read_256hash()?),
+            coins_count: reader.read_u64::<LittleEndian>()?,
+        };
+        Ok(Self {
+            reader,
+            remaining_coins: metadata.coins_count,
+            metadata,
+            cur_txid: sha256d::Hash::all_zeros(),
+            remaining_in_tx: 0,
+        })
+    }
+
+    fn read_coin(&mut self) -> OpResult<SnapshotCoin> {
+        if self.remaining_in_tx == 0 {
+            self.cur_txid = sha256d::Hash::from_byte_array(self.reader.read_256hash()?);
+            self.remaining_in_tx = VarUint::read_from(&mut self.reader)?.value;
+        }
+        let index = VarUint::read_from(&mut self.reader)?.value as u32;
+        self.remaining_in_tx = self.remaining_in_tx.saturating_sub(1);
+
+        // Coin serialization: VARINT(height * 2 + coinbase), compressed amount, compressed script
+        let code = self.reader.read_core_varint()?;
+        let value = decompress_amount(self.reader.read_core_varint()?);
+        let script_pubkey = read_compressed_script(&mut self.reader)?;
+        Ok(SnapshotCoin {
+            outpoint: TxOutpoint::new(self.cur_txid, index),
+            height: code >> 1,
+            is_coinbase: code & 1 == 1,
+            value,
+            script_pubkey,
+        })
+    }
+}
+
+impl<R: Read> Iterator for UtxoSnapshotReader<R> {
+    type Item = OpResult<SnapshotCoin>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining_coins == 0 {
+            return None;
+        }
+        self.remaining_coins -= 1;
+        Some(self.read_coin())
+    }
+}
+
+/// Writes a UTXO snapshot in the format of `dumptxoutset` and computes its `hash_serialized_3`,
+/// which Bitcoin Core reports as `txoutset_hash` and checks against its assumeutxo parameters.
+/// Coins must be written ordered by txid and index, like Core iterates its chainstate.
+pub struct UtxoSnapshotWriter<W: Write> {
+    writer: W,
+    hasher: sha256::HashEngine,
+    remaining_coins: u64,
+    // Coins of the current txid, which are prefixed by their count
+    group: Vec<u8>,
+    group_txid: Option<sha256d::Hash>,
+    group_len: u64,
+}
+
+impl<W: Write> UtxoSnapshotWriter<W> {
+    /// Writes the metadata, exactly `metadata.coins_count` coins must follow
+    pub fn new(mut writer: W, metadata: &SnapshotMetadata) -> OpResult<Self> {
+        writer.write_all(&SNAPSHOT_MAGIC_BYTES)?;
+        writer.write_all(&SNAPSHOT_VERSION.to_le_bytes())?;
+        writer.write_all(&metadata.network_magic.to_le_bytes())?;
+        writer.write_all(metadata.base_block_hash.as_byte_array())?;
+        writer.write_all(&metadata.coins_count.to_le_bytes())?;
+        Ok(Self {
+            writer,
+            hasher: sha256d::Hash::engine(),
+            remaining_coins: metadata.coins_count,
+            group: Vec::new(),
+            group_txid: None,
+            group_len: 0,
+        })
+    }
+
+    pub fn write_coin(&mut self, coin: &SnapshotCoin) -> OpResult<()> {
+        if self.remaining_coins == 0 {
+            return Err(OpError::new(OpErrorKind::RuntimeError)
+                .join_msg("More coins written than announced in the snapshot metadata"));
+        }
+        self.remaining_coins -= 1;
+        if self.group_txid != Some(coin.outpoint.txid) {
+            self.write_group()?;
+            self.group_txid = Some(coin.outpoint.txid);
+        }
+        let code = coin.height * 2 + coin.is_coinbase as u64;
+        self.group
+            .extend(VarUint::minimal(coin.outpoint.index as u64).to_bytes());
+        write_core_varint(&mut self.group, code);
+        write_core_varint(&mut self.group, compress_amount(coin.value));
+        write_compressed_script(&mut self.group, &coin.script_pubkey);
+        self.group_len += 1;
+
+        // See `ApplyCoinHash()` in kernel/coinstats.cpp
+        self.hasher.input(&coin.outpoint.to_bytes());
+        self.hasher.input(&(code as u32).to_le_bytes());
+        self.hasher.input(&coin.value.to_le_bytes());
+        self.hasher
+            .input(&VarUint::minimal(coin.script_pubkey.len() as u64).to_bytes());
+        self.hasher.input(&coin.script_pubkey);
+        Ok(())
+    }
+
+    fn write_group(&mut self) -> OpResult<()> {
+        if let Some(txid) = self.group_txid.take() {
+            self.writer.write_all(txid.as_byte_array())?;
+            self.writer
+                .write_all(&VarUint::minimal(self.group_len).to_bytes())?;
+            self.writer.write_all(&self.group)?;
+            self.group.clear();
+            self.group_len = 0;
+        }
+        Ok(())
+    }
+
+    /// Writes the last coins and returns the hash of the serialized UTXO set
+    pub fn finish(mut self) -> OpResult<sha256d::Hash> {
+        if self.remaining_coins > 0 {
+            let msg = format!(
+                "{} coins less written than announced in the snapshot metadata",
+                self.remaining_coins
+            );
+            return Err(OpError::new(OpErrorKind::RuntimeError).join_msg(&msg));
+        }
+        self.write_group()?;
+        self.writer.flush()?;
+        Ok(sha256d::Hash::from_engine(self.hasher))
+    }
+}
+
+/// Reverses the amount compression used by Bitcoin Core, see `DecompressAmount()` in compressor.cpp
+pub fn decompress_amount(x: u64) -> u64 {
+    if x == 0 {
+        return 0;
+    }
+    let mut x = x - 1;
+    let mut e = x % 10;
+    x /= 10;
+    let mut n = if e < 9 {
+        let d = (x % 9) + 1;
+        x /= 9;
+        x * 10 + d
+    } else {
+        x + 1
+    };
+    while e > 0 {
+        n *= 10;
+        e -= 1;
+    }
+    n
+}
+
+/// Amount compression used by Bitcoin Core, see `CompressAmount()` in compressor.cpp
+pub fn compress_amount(mut n: u64) -> u64 {
+    if n == 0 {
+        return 0;
+    }
+    let mut e = 0;
+    while n.is_multiple_of(10) && e < 9 {
+        n /= 10;
+        e += 1;
+    }
+    if e < 9 {
+        let d = n % 10;
+        n /= 10;
+        1 + (n * 9 + d - 1) * 10 + e
+    } else {
+        1 + (n - 1) * 10 + 9
+    }
+}
+
+/// Writes a VARINT as used by Bitcoin Core, inverse of `read_core_varint()`
+pub fn write_core_varint(buf: &mut Vec<u8>, mut n: u64) {
+    let mut tmp = Vec::new();
+    loop {
+        tmp.push((n & 0x7F) as u8 | if tmp.is_empty() { 0x00 } else { 0x80 });
+        if n <= 0x7F {
+            break;
+        }
+        n = (n >> 7) - 1;
+    }
+    buf.extend(tmp.iter().rev());
+}
+
+/// Reads a script in the compressed format used by Bitcoin Core, see `ScriptCompression` in compressor.h
+pub(crate) fn read_compressed_script<R: Read + ?Sized>(reader: &mut R) -> OpResult<Vec<u8>> {
+    let size = reader.read_core_varint()?;
+    let script = match size {
+        0x00 => {
+            // P2PKH: OP_DUP OP_HASH160 <20 bytes> OP_EQUALVERIFY OP_CHECKSIG
+            let mut script = vec![0x76, 0xa9, 0x14];
+            script.extend(reader.read_u8_vec(20)?);
+            script.extend([0x88, 0xac]);
+            script
+        }
+        0x01 => {
+            // P2SH: OP_HASH160 <20 bytes> OP_EQUAL
+            let mut script = vec![0xa9, 0x14];
+            script.extend(reader.read_u8_vec(20)?);
+            script.push(0x87);
+            script
+        }
+        0x02 | 0x03 => {
+            // P2PK with compressed public key
+            let mut script = vec![0x21, size as u8];
+            script.extend(reader.read_u8_vec(32)?);
+            script.push(0xac);
+            script
+        }
+        0x04 | 0x05 => {
+            // P2PK with uncompressed public key, only the x coordinate is stored
+            let mut compressed = vec![size as u8 - 2];
+            compressed.extend(reader.read_u8_vec(32)?);
+            let pubkey = PublicKey::from_slice(&compressed).map_err(|e| {
+                OpError::from(io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
+            })?;
+            let mut script = vec![0x41];
+            script.extend(pubkey.serialize_uncompressed());
+            script.push(0xac);
+            script
+        }
+        n => reader.read_u8_vec((n - 6) as u32)?,
+    };
+    Ok(script)
+}
+
+/// Writes a script in the compressed format used by Bitcoin Core, inverse of `read_compressed_script()`
+pub(crate) fn write_compressed_script(buf: &mut Vec<u8>, script: &[u8]) {
+    match script {
+        [0x76, 0xa9, 0x14, hash @ .., 0x88, 0xac] if hash.len() == 20 => {
+            buf.push(0x00);
+            buf.extend(hash);
+        }
+        [0xa9, 0x14, hash @ .., 0x87] if hash.len() == 20 => {
+            buf.push(0x01);
+            buf.extend(hash);
+        }
+        [0x21, prefix @ (0x02 | 0x03), x @ .., 0xac] if x.len() == 32 => {
+            buf.push(*prefix);
+            buf.extend(x);
+        }
+        // Core only compresses uncompressed public keys which are valid points
+        [0x41, pubkey @ .., 0xac]
+            if pubkey.len() == 65 && PublicKey::from_slice(pubkey).is_ok() =>
+        {
+            buf.push(0x04 | (pubkey[64] & 0x01));
+            buf.extend(&pubkey[1..33]);
+        }
+        _ => {
+            write_core_varint(buf, script.len() as u64 + 6);
+            buf.extend(script);
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// Writes a snapshot containing the given (outpoint, height, value, script) entries.
+    /// Entries with the same txid must be adjacent.
+    pub fn write_snapshot(
+        base_block_hash: &sha256d::Hash,
+        coins: &[(TxOutpoint, u64, u64, Vec<u8>)],
+    ) -> Vec<u8> {
+        let mut buf = Vec::from(SNAPSHOT_MAGIC_BYTES);
+        buf.extend(SNAPSHOT_VERSION.to_le_bytes());
+        buf.extend(0xd9b4bef9u32.to_le_bytes());
+        buf.extend(base_block_hash.as_byte_array());
+        buf.extend((coins.len() as u64).to_le_bytes());
+        for (i, (outpoint, height, value, script)) in coins.iter().enumerate() {
+            if i == 0 || coins[i - 1].0.txid != outpoint.txid {
+                let group = coins.iter().filter(|c| c.0.txid == outpoint.txid).count();
+                buf.extend(outpoint.txid.as_byte_array());
+                buf.push(group as u8);
+            }
+            buf.push(outpoint.index as u8);
+            write_core_varint(&mut buf, height * 2);
+            write_core_varint(&mut buf, compress_amount(*value));
+            write_core_varint(&mut buf, script.len() as u64 + 6);
+            buf.extend(script);
+        }
+        buf
+    }
+
+    #[test]
+    fn test_decompress_amount() {
+        for value in [0, 1, 546, 100000000, 5000000000, 2099999997690000] {
+            assert_eq!(decompress_amount(compress_amount(value)), value);
+        }
+        assert_eq!(compress_amount(5000000000), 50);
+    }
+
+    #[test]
+    fn test_core_varint() {
+        for value in [0, 1, 127, 128, 255, 16383, 16384, 1 << 40] {
+            let mut buf = Vec::new();
+            write_core_varint(&mut buf, value);
+            assert_eq!(Cursor::new(buf).read_core_varint().unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn test_read_snapshot() {
+        let txid = sha256d::Hash::hash(&[0x01]);
+        let base = sha256d::Hash::hash(&[0x02]);
+        let mut raw = write_snapshot(
+            &base,
+            &[
+                (TxOutpoint::new(txid, 0), 10, 5000000000, vec![0x51]),
+                (TxOutpoint::new(txid, 3), 10, 546, vec![0x52, 0x53]),
+            ],
+        );
+        // Replace the raw script of the second coin with a compressed P2PKH script
+        raw.truncate(raw.len() - 3);
+        raw.push(0x00);
+        raw.extend([0xab; 20]);
+
+        let reader = UtxoSnapshotReader::new(Cursor::new(raw)).unwrap();
+        assert_eq!(reader.metadata.base_block_hash, base);
+        assert_eq!(reader.metadata.coins_count, 2);
+        let coins = reader.collect::<OpResult<Vec<SnapshotCoin>>>().unwrap();
+        assert_eq!(coins[0].outpoint, TxOutpoint::new(txid, 0));
+        assert_eq!(coins[0].height, 10);
+        assert_eq!(coins[0].value, 5000000000);
+        assert_eq!(coins[0].script_pubkey, vec![0x51]);
+        assert_eq!(coins[1].outpoint, TxOutpoint::new(txid, 3));
+        assert_eq!(coins[1].value, 546);
+        assert_eq!(coins[1].script_pubkey.len(), 25);
+        assert_eq!(&coins[1].script_pubkey[3..23], &[0xab; 20]);
+    }
+
+    #[test]
+    fn test_write_snapshot() {
+        let mut p2pkh = vec![0x76, 0xa9, 0x14];
+        p2pkh.extend([0x11; 20]);
+        p2pkh.extend([0x88, 0xac]);
+        let mut p2sh = vec![0xa9, 0x14];
+        p2sh.extend([0x22; 20]);
+        p2sh.push(0x87);
+        let secp = bitcoin::secp256k1::Secp256k1::new();
+        let key = bitcoin::secp256k1::SecretKey::from_slice(&[0x33; 32]).unwrap();
+        let pubkey = PublicKey::from_secret_key(&secp, &key);
+        let p2pk = [&[0x21][..], &pubkey.serialize(), &[0xac]].concat();
+        let p2pk_uncompressed = [&[0x41][..], &pubkey.serialize_uncompressed(), &[0xac]].concat();
+        let scripts = [p2pkh, p2sh, p2pk, p2pk_uncompressed, vec![0x51]];
+
+        let txids = [sha256d::Hash::hash(&[0x01]), sha256d::Hash::hash(&[0x02])];
+        let coins: Vec<SnapshotCoin> = scripts
+            .iter()
+            .enumerate()
+            .map(|(i, script)| SnapshotCoin {
+                outpoint: TxOutpoint::new(txids[i / 3], i as u32 * 100),
+                height: i as u64 + 1,
+                is_coinbase: i == 0,
+                value: 5000000000 - i as u64,
+                script_pubkey: script.clone(),
+            })
+            .collect();
+        let metadata = SnapshotMetadata {
+            network_magic: 0xd9b4bef9,
+            base_block_hash: sha256d::Hash::hash(&[0x03]),
+            coins_count: coins.len() as u64,
+        };
+        let mut raw = Vec::new();
+        let mut writer = UtxoSnapshotWriter::new(&mut raw, &metadata).unwrap();
+        for coin in &coins {
+            writer.write_coin(coin).unwrap();
+        }
+        let hash = writer.finish().unwrap();
+
+        let reader = UtxoSnapshotReader::new(Cursor::new(raw)).unwrap();
+        assert_eq!(reader.metadata.network_magic, metadata.network_magic);
+        assert_eq!(reader.metadata.base_block_hash, metadata.base_block_hash);
+        let read = reader.collect::<OpResult<Vec<SnapshotCoin>>>().unwrap();
+        assert_eq!(read.len(), coins.len());
+        for (read, coin) in read.iter().zip(&coins) {
+            assert_eq!(read.outpoint, coin.outpoint);
+            assert_eq!(read.height, coin.height);
+            assert_eq!(read.is_coinbase, coin.is_coinbase);
+            assert_eq!(read.value, coin.value);
+            assert_eq!(read.script_pubkey, coin.script_pubkey);
+        }
+
+        let mut serialized = Vec::new();
+        for coin in &coins {
+            serialized.extend(coin.outpoint.to_bytes());
+            serialized.extend(((coin.height * 2 + coin.is_coinbase as u64) as u32).to_le_bytes());
+            serialized.extend(coin.value.to_le_bytes());
+            serialized.push(coin.script_pubkey.len() as u8);
+            serialized.extend(&coin.script_pubkey);
+        }
+        assert_eq!(hash, sha256d::Hash::hash(&serialized));
+    }
+
+    #[test]
+    fn test_write_snapshot_count() {
+        let metadata = SnapshotMetadata {
+            network_magic: 0xd9b4bef9,
+            base_block_hash: sha256d::Hash::all_zeros(),
+            coins_count: 1,
+        };
+        let writer = UtxoSnapshotWriter::new(Vec::new(), &metadata).unwrap();
+        assert!(writer.finish().is_err());
+    }
+
+    #[test]
+    fn test_invalid_snapshot() {
+        assert!(UtxoSnapshotReader::new(Cursor::new(vec![0u8; 64])).is_err());
+    }
+}
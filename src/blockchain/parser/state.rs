@@ -0,0 +1,86 @@
+use bitcoin::hashes::sha256d;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::blockchain::parser::types::CoinType;
+use crate::common::utils;
+use crate::errors::{OpError, OpErrorKind, OpResult};
+
+/// Tip of the last processed block, persisted in the dump folder for incremental runs
+#[derive(Debug, PartialEq, Eq)]
+pub struct ParserState {
+    pub height: u64,
+    pub block_hash: sha256d::Hash,
+}
+
+impl ParserState {
+    /// State files are scoped by coin, so multiple chains can share a dump folder
+    pub fn path(dump_folder: &Path, coin: &CoinType) -> PathBuf {
+        dump_folder.join(format!(
+            ".rusty-blockparser-{}.state",
+            coin.name.to_lowercase()
+        ))
+    }
+
+    /// Loads the state from the given file, returns None if there is no previous run
+    pub fn load(path: &Path) -> OpResult<Option<Self>> {
+        if !path.exists() {
+            return Ok(None);
+        }
+        let content = fs::read_to_string(path)?;
+        let mut height = None;
+        let mut block_hash = None;
+        for line in content.lines() {
+            match line.split_once('=') {
+                Some(("height", v)) => height = v.trim().parse::<u64>().ok(),
+                Some(("block_hash", v)) => block_hash = sha256d::Hash::from_str(v.trim()).ok(),
+                _ => {}
+            }
+        }
+        match (height, block_hash) {
+            (Some(height), Some(block_hash)) => Ok(Some(Self { height, block_hash })),
+            _ => {
+                let msg = format!("Malformed state file: {}", path.display());
+                Err(OpError::new(OpErrorKind::RuntimeError).join_msg(&msg))
+            }
+        }
+    }
+
+    /// Writes the state atomically, a crash never leaves a truncated state file behind.
+    /// The hash is always stored in display order, regardless of `--hash-endian`.
+    pub fn save(&self, path: &Path) -> OpResult<()> {
+        let tmp_path = path.with_extension("state.tmp");
+        fs::write(
+            &tmp_path,
+            format!("height={}\nblock_hash={}\n", self.height, self.block_hash),
+        )?;
+        utils::rename(&tmp_path, path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::parser::types::Bitcoin;
+    use bitcoin::hashes::Hash;
+
+    #[test]
+    fn test_state_roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = ParserState::path(dir.path(), &CoinType::from(Bitcoin));
+        assert!(path.ends_with(".rusty-blockparser-bitcoin.state"));
+        assert_eq!(ParserState::load(&path).unwrap(), None);
+
+        let state = ParserState {
+            height: 42,
+            block_hash: sha256d::Hash::hash(&[0x42]),
+        };
+        state.save(&path).unwrap();
+        assert_eq!(ParserState::load(&path).unwrap(), Some(state));
+
+        fs::write(&path, "height=1\n").unwrap();
+        assert!(ParserState::load(&path).is_err());
+    }
+}
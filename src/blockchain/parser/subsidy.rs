@@ -0,0 +1,213 @@
+//! Block subsidy schedules of the supported coins
+use bitcoin::hashes::sha256d;
+
+const COIN: u64 = 100000000;
+
+/// Defines how many new coins the coinbase of a block may claim
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SubsidySchedule {
+    /// Starts at `initial_subsidy` and is halved every `halving_interval` blocks, like Bitcoin
+    Halving {
+        initial_subsidy: u64,
+        halving_interval: u64,
+    },
+    /// Random rewards derived from the previous block hash below height 145000,
+    /// then fixed rewards halving every 100000 blocks and 10000 DOGE per block from 600000 on,
+    /// see `GetDogecoinBlockSubsidy` in dogecoin.cpp
+    Dogecoin,
+}
+
+impl SubsidySchedule {
+    const DOGECOIN_HALVING_INTERVAL: u64 = 100000;
+    const DOGECOIN_RANDOM_END: u64 = 145000;
+
+    /// Returns the block subsidy for the given height. The previous block hash is only
+    /// used by schedules with random rewards.
+    pub fn block_subsidy(&self, height: u64, prev_hash: &sha256d::Hash) -> u64 {
+        match *self {
+            SubsidySchedule::Halving {
+                initial_subsidy,
+                halving_interval,
+            } => match height / halving_interval {
+                halvings if halvings >= 64 => 0,
+                halvings => initial_subsidy >> halvings,
+            },
+            SubsidySchedule::Dogecoin => {
+                let halvings = height / SubsidySchedule::DOGECOIN_HALVING_INTERVAL;
+                if height < SubsidySchedule::DOGECOIN_RANDOM_END {
+                    // The seed are the hex digits 7..14 of the previous block hash in display order
+                    let seed = u32::from_str_radix(&prev_hash.to_string()[7..14], 16).unwrap();
+                    let max_reward = (1000000 >> halvings) - 1;
+                    let rand = Mt19937::new(seed).uniform_int(1, max_reward);
+                    (1 + rand as u64) * COIN
+                } else if halvings < 6 {
+                    (500000 * COIN) >> halvings
+                } else {
+                    10000 * COIN
+                }
+            }
+        }
+    }
+
+    /// Returns true if the subsidy is reduced from the given height on
+    pub fn is_reduction(&self, height: u64) -> bool {
+        match *self {
+            SubsidySchedule::Halving {
+                halving_interval, ..
+            } => height > 0 && height.is_multiple_of(halving_interval),
+            SubsidySchedule::Dogecoin => {
+                height > 0
+                    && height <= 6 * SubsidySchedule::DOGECOIN_HALVING_INTERVAL
+                    && height.is_multiple_of(SubsidySchedule::DOGECOIN_HALVING_INTERVAL)
+            }
+        }
+    }
+
+    /// Returns the sum of all block subsidies from genesis up to the given height (inclusive),
+    /// None if it depends on the block hashes
+    pub fn issued_supply(&self, height: u64) -> Option<u64> {
+        match *self {
+            SubsidySchedule::Halving {
+                initial_subsidy,
+                halving_interval,
+            } => {
+                let mut supply = 0;
+                let mut era_start = 0;
+                while era_start <= height {
+                    let era_end = height.min(era_start + halving_interval - 1);
+                    let halvings = era_start / halving_interval;
+                    if halvings >= 64 {
+                        break;
+                    }
+                    supply += (era_end - era_start + 1) * (initial_subsidy >> halvings);
+                    era_start += halving_interval;
+                }
+                Some(supply)
+            }
+            SubsidySchedule::Dogecoin => None,
+        }
+    }
+}
+
+/// Mersenne Twister as `boost::mt19937`, which seeds the early Dogecoin rewards
+struct Mt19937 {
+    state: [u32; 624],
+    index: usize,
+}
+
+impl Mt19937 {
+    fn new(seed: u32) -> Self {
+        let mut state = [0u32; 624];
+        state[0] = seed;
+        for i in 1..state.len() {
+            let prev = state[i - 1];
+            state[i] = 1812433253u32
+                .wrapping_mul(prev ^ (prev >> 30))
+                .wrapping_add(i as u32);
+        }
+        Mt19937 { state, index: 624 }
+    }
+
+    fn twist(&mut self) {
+        for i in 0..624 {
+            let y = (self.state[i] & 0x80000000) | (self.state[(i + 1) % 624] & 0x7fffffff);
+            let mut next = self.state[(i + 397) % 624] ^ (y >> 1);
+            if y & 1 == 1 {
+                next ^= 0x9908b0df;
+            }
+            self.state[i] = next;
+        }
+        self.index = 0;
+    }
+
+    fn next_u32(&mut self) -> u32 {
+        if self.index >= 624 {
+            self.twist();
+        }
+        let mut y = self.state[self.index];
+        self.index += 1;
+        y ^= y >> 11;
+        y ^= (y << 7) & 0x9d2c5680;
+        y ^= (y << 15) & 0xefc60000;
+        y ^ (y >> 18)
+    }
+
+    /// Returns a number in [min, max] like `boost::uniform_int`, which divides the
+    /// output range into equal buckets and rejects numbers above the last one
+    fn uniform_int(&mut self, min: u32, max: u32) -> u32 {
+        let range = max - min;
+        let mut bucket_size = u32::MAX / (range + 1);
+        if u32::MAX % (range + 1) == range {
+            bucket_size += 1;
+        }
+        loop {
+            let result = self.next_u32() / bucket_size;
+            if result <= range {
+                return result + min;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::hashes::Hash;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_mt19937() {
+        // Required by the C++ standard for std::mt19937 with the default seed
+        let mut rng = Mt19937::new(5489);
+        assert_eq!(rng.next_u32(), 3499211612);
+        for _ in 1..9999 {
+            rng.next_u32();
+        }
+        assert_eq!(rng.next_u32(), 4123659995);
+
+        let mut rng = Mt19937::new(1);
+        assert!((0..1000).all(|_| (1..=10).contains(&rng.uniform_int(1, 10))));
+    }
+
+    #[test]
+    fn test_halving() {
+        let schedule = SubsidySchedule::Halving {
+            initial_subsidy: 50 * COIN,
+            halving_interval: 210000,
+        };
+        let zero = sha256d::Hash::all_zeros();
+        assert_eq!(schedule.block_subsidy(209999, &zero), 50 * COIN);
+        assert_eq!(schedule.block_subsidy(210000, &zero), 25 * COIN);
+        assert!(!schedule.is_reduction(0));
+        assert!(schedule.is_reduction(210000));
+        assert!(!schedule.is_reduction(210001));
+        assert_eq!(schedule.issued_supply(1), Some(100 * COIN));
+    }
+
+    #[test]
+    fn test_dogecoin() {
+        let schedule = SubsidySchedule::Dogecoin;
+        // The mainnet genesis hash seeds with its digits 7..14, 0xace36e2. The rewards were
+        // computed independently with the mt19937 of CPython's random module and boost's buckets.
+        let genesis = sha256d::Hash::from_str(
+            "1a91e3dace36e2be3bf030a65679fe821aa1d6ef92e7c9902eb318182c355691",
+        )
+        .unwrap();
+        assert_eq!(schedule.block_subsidy(1, &genesis), 68416 * COIN);
+        assert_eq!(schedule.block_subsidy(99999, &genesis), 68416 * COIN);
+        assert_eq!(schedule.block_subsidy(100000, &genesis), 34205 * COIN);
+        assert_eq!(schedule.block_subsidy(144999, &genesis), 34205 * COIN);
+
+        let zero = sha256d::Hash::all_zeros();
+        assert_eq!(schedule.block_subsidy(145000, &zero), 250000 * COIN);
+        assert_eq!(schedule.block_subsidy(200000, &zero), 125000 * COIN);
+        assert_eq!(schedule.block_subsidy(599999, &zero), 15625 * COIN);
+        assert_eq!(schedule.block_subsidy(600000, &zero), 10000 * COIN);
+        assert_eq!(schedule.block_subsidy(10000000, &zero), 10000 * COIN);
+
+        assert!(schedule.is_reduction(100000));
+        assert!(schedule.is_reduction(600000));
+        assert!(!schedule.is_reduction(700000));
+        assert_eq!(schedule.issued_supply(1), None);
+    }
+}
@@ -1,8 +1,15 @@
+use bitcoin::hashes::hex::FromHex;
 use bitcoin::hashes::sha256d;
+use serde_json::Value;
 use std::convert::From;
+use std::fs;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::sync::Arc;
 
+use crate::blockchain::parser::subsidy::SubsidySchedule;
+use crate::blockchain::proto::script::encoder::{AddressEncoder, AddressFormat, AddressScheme};
+use crate::common::toml;
 use crate::errors::{OpError, OpErrorKind, OpResult};
 
 /// Trait to specify the underlying coin of a blockchain
@@ -22,6 +29,34 @@ pub trait Coin {
     }
     // Default working directory to look for datadir, for example .bitcoin
     fn default_folder(&self) -> PathBuf;
+    // Whether the proof of work is the block hash (double SHA256 of the header), checked by --verify
+    fn sha256d_pow(&self) -> bool {
+        true
+    }
+    // Block subsidy at height 0 in base units
+    fn initial_subsidy(&self) -> u64 {
+        50 * 100000000
+    }
+    // Number of blocks after which the block subsidy is halved
+    fn halving_interval(&self) -> u64 {
+        210000
+    }
+    // Defaults to halving the initial subsidy every halving interval
+    fn subsidy_schedule(&self) -> SubsidySchedule {
+        SubsidySchedule::Halving {
+            initial_subsidy: self.initial_subsidy(),
+            halving_interval: self.halving_interval(),
+        }
+    }
+    // Number of confirmations required before coinbase outputs can be spent
+    fn coinbase_maturity(&self) -> u64 {
+        100
+    }
+    // Converts output scripts to addresses. Defaults to Base58Check with the version id
+    // for p2pkh and 0x05 for p2sh, override it for other address formats
+    fn address_encoder(&self) -> Arc<dyn AddressEncoder> {
+        Arc::new(AddressFormat::base58(self.version_id(), 0x05))
+    }
 }
 
 // Implemented blockchain types.
@@ -29,6 +64,9 @@ pub trait Coin {
 // and add the coin name to from_str() below
 pub struct Bitcoin;
 pub struct TestNet3;
+pub struct TestNet4;
+pub struct Signet;
+pub struct Regtest;
 pub struct Namecoin;
 pub struct Litecoin;
 pub struct Dogecoin;
@@ -54,6 +92,9 @@ impl Coin for Bitcoin {
     fn default_folder(&self) -> PathBuf {
         Path::new(".bitcoin").join("blocks")
     }
+    fn address_encoder(&self) -> Arc<dyn AddressEncoder> {
+        Arc::new(AddressFormat::base58(0x00, 0x05).with_hrp("bc"))
+    }
 }
 
 /// Bitcoin testnet3
@@ -74,6 +115,81 @@ impl Coin for TestNet3 {
     fn default_folder(&self) -> PathBuf {
         Path::new(".bitcoin").join("testnet3")
     }
+    fn address_encoder(&self) -> Arc<dyn AddressEncoder> {
+        Arc::new(AddressFormat::base58(0x6f, 0xc4).with_hrp("tb"))
+    }
+}
+
+/// Bitcoin testnet4 (BIP94)
+impl Coin for TestNet4 {
+    fn name(&self) -> String {
+        String::from("TestNet4")
+    }
+    fn magic(&self) -> u32 {
+        0x283f161c
+    }
+    fn version_id(&self) -> u8 {
+        0x6f
+    }
+    fn genesis(&self) -> sha256d::Hash {
+        sha256d::Hash::from_str("00000000da84f2bafbbc53dee25a72ae507ff4914b867c565be350b0da8bf043")
+            .unwrap()
+    }
+    fn default_folder(&self) -> PathBuf {
+        Path::new(".bitcoin").join("testnet4").join("blocks")
+    }
+    fn address_encoder(&self) -> Arc<dyn AddressEncoder> {
+        Arc::new(AddressFormat::base58(0x6f, 0xc4).with_hrp("tb"))
+    }
+}
+
+/// Default Bitcoin signet (BIP325), custom signets have another magic and genesis block
+impl Coin for Signet {
+    fn name(&self) -> String {
+        String::from("Signet")
+    }
+    fn magic(&self) -> u32 {
+        0x40cf030a
+    }
+    fn version_id(&self) -> u8 {
+        0x6f
+    }
+    fn genesis(&self) -> sha256d::Hash {
+        sha256d::Hash::from_str("00000008819873e925422c1ff0f99f7cc9bbb232af63a077a480a3633bee1ef6")
+            .unwrap()
+    }
+    fn default_folder(&self) -> PathBuf {
+        Path::new(".bitcoin").join("signet").join("blocks")
+    }
+    fn address_encoder(&self) -> Arc<dyn AddressEncoder> {
+        Arc::new(AddressFormat::base58(0x6f, 0xc4).with_hrp("tb"))
+    }
+}
+
+/// Bitcoin regression test network, the subsidy is halved every 150 blocks
+impl Coin for Regtest {
+    fn name(&self) -> String {
+        String::from("Regtest")
+    }
+    fn magic(&self) -> u32 {
+        0xdab5bffa
+    }
+    fn version_id(&self) -> u8 {
+        0x6f
+    }
+    fn genesis(&self) -> sha256d::Hash {
+        sha256d::Hash::from_str("0f9188f13cb7b2c71f2a335e3a4fc328bf5beb436012afca590b1a11466e2206")
+            .unwrap()
+    }
+    fn default_folder(&self) -> PathBuf {
+        Path::new(".bitcoin").join("regtest").join("blocks")
+    }
+    fn halving_interval(&self) -> u64 {
+        150
+    }
+    fn address_encoder(&self) -> Arc<dyn AddressEncoder> {
+        Arc::new(AddressFormat::base58(0x6f, 0xc4).with_hrp("bcrt"))
+    }
 }
 
 impl Coin for Namecoin {
@@ -115,6 +231,13 @@ impl Coin for Litecoin {
     fn default_folder(&self) -> PathBuf {
         Path::new(".litecoin").join("blocks")
     }
+    // Scrypt
+    fn sha256d_pow(&self) -> bool {
+        false
+    }
+    fn halving_interval(&self) -> u64 {
+        840000
+    }
 }
 
 impl Coin for Dogecoin {
@@ -137,6 +260,17 @@ impl Coin for Dogecoin {
     fn default_folder(&self) -> PathBuf {
         Path::new(".dogecoin").join("blocks")
     }
+    // Scrypt
+    fn sha256d_pow(&self) -> bool {
+        false
+    }
+    fn subsidy_schedule(&self) -> SubsidySchedule {
+        SubsidySchedule::Dogecoin
+    }
+    // Has been 30 blocks before height 145000
+    fn coinbase_maturity(&self) -> u64 {
+        240
+    }
 }
 
 impl Coin for Myriadcoin {
@@ -156,6 +290,16 @@ impl Coin for Myriadcoin {
     fn default_folder(&self) -> PathBuf {
         Path::new(".myriadcoin").join("blocks")
     }
+    // One of five algorithms, selected by the block version
+    fn sha256d_pow(&self) -> bool {
+        false
+    }
+    fn initial_subsidy(&self) -> u64 {
+        1000 * 100000000
+    }
+    fn halving_interval(&self) -> u64 {
+        967680
+    }
 }
 
 impl Coin for Unobtanium {
@@ -194,6 +338,9 @@ impl Coin for NoteBlockchain {
     fn default_folder(&self) -> PathBuf {
         Path::new(".notecoin").join("blocks")
     }
+    fn sha256d_pow(&self) -> bool {
+        false
+    }
 }
 
 /* TODO: implement X11
@@ -213,7 +360,143 @@ pub struct CoinType {
     pub version_id: u8,
     pub genesis_hash: sha256d::Hash,
     pub aux_pow_activation_version: Option<u32>,
+    pub sha256d_pow: bool,
     pub default_folder: PathBuf,
+    pub subsidy_schedule: SubsidySchedule,
+    pub coinbase_maturity: u64,
+    pub address_encoder: Arc<dyn AddressEncoder>,
+}
+
+impl CoinType {
+    /// Returns the block subsidy for the given height, see `SubsidySchedule::block_subsidy()`
+    pub fn block_subsidy(&self, height: u64, prev_hash: &sha256d::Hash) -> u64 {
+        self.subsidy_schedule.block_subsidy(height, prev_hash)
+    }
+
+    /// Returns the sum of all block subsidies from genesis up to the given height (inclusive),
+    /// None if the schedule depends on the block hashes
+    pub fn issued_supply(&self, height: u64) -> Option<u64> {
+        self.subsidy_schedule.issued_supply(height)
+    }
+}
+
+impl CoinType {
+    /// Loads a coin which is not built in from a JSON file or, with a `.toml` extension,
+    /// a TOML chain spec with the same keys, see `--coin-config-file`.
+    /// Required: name, magic, genesis_hash, pubkey_version, script_version, initial_subsidy,
+    /// halving_interval and coinbase_maturity.
+    /// Optional: bech32_hrp, address_scheme, aux_pow_activation_version, sha256d_pow (default: true)
+    /// and default_folder.
+    pub fn from_config_file(path: &Path) -> OpResult<Self> {
+        let fail = |msg: String| {
+            OpError::new(OpErrorKind::InvalidArgsError).join_msg(&format!(
+                "Invalid coin config {}: {}",
+                path.display(),
+                msg
+            ))
+        };
+        let text = fs::read_to_string(path)?;
+        let config: Value = if path.extension().is_some_and(|ext| ext == "toml") {
+            toml::parse(&text).map_err(fail)?
+        } else {
+            serde_json::from_str(&text).map_err(|e| fail(e.to_string()))?
+        };
+        if !config.is_object() {
+            return Err(fail(String::from("expected a JSON object")));
+        }
+        let field = |key: &str| match config.get(key) {
+            Some(Value::Null) | None => Err(fail(format!("missing field `{}`", key))),
+            Some(value) => Ok(value),
+        };
+        let invalid = |key: &str, expected: &str| fail(format!("`{}` must be {}", key, expected));
+        let string = |key: &str| field(key)?.as_str().ok_or_else(|| invalid(key, "a string"));
+        let number = |key: &str| field(key)?.as_u64().ok_or_else(|| invalid(key, "a number"));
+        let has = |key: &str| config.get(key).is_some_and(|v| !v.is_null());
+        // A single version byte or an array of them, e.g. [28, 184] for Zcash
+        let version_bytes = |key: &str| {
+            let byte = |v: &Value| v.as_u64().and_then(|b| u8::try_from(b).ok());
+            let bytes = match field(key)? {
+                Value::Array(values) => values.iter().map(byte).collect::<Option<Vec<u8>>>(),
+                value => byte(value).map(|b| vec![b]),
+            };
+            bytes
+                .filter(|b| !b.is_empty())
+                .ok_or_else(|| invalid(key, "a byte or a non-empty array of bytes"))
+        };
+
+        let name = string("name")?.to_string();
+        // Message start bytes in the order they appear in blk files, e.g. f9beb4d9 for Bitcoin
+        let magic = <[u8; 4]>::from_hex(string("magic")?)
+            .map(u32::from_le_bytes)
+            .map_err(|_| invalid("magic", "4 hex encoded bytes"))?;
+        let genesis_hash = sha256d::Hash::from_str(string("genesis_hash")?)
+            .map_err(|_| invalid("genesis_hash", "a hex encoded block hash"))?;
+        let pubkey_version = version_bytes("pubkey_version")?;
+        let script_version = version_bytes("script_version")?;
+        let initial_subsidy = number("initial_subsidy")?;
+        let halving_interval = number("halving_interval")?;
+        if halving_interval == 0 {
+            return Err(invalid("halving_interval", "greater than 0"));
+        }
+        let coinbase_maturity = number("coinbase_maturity")?;
+
+        let hrp = if has("bech32_hrp") {
+            Some(string("bech32_hrp")?.to_string())
+        } else {
+            None
+        };
+        let scheme = if has("address_scheme") {
+            match string("address_scheme")? {
+                "base58" => AddressScheme::Base58,
+                "cashaddr" => AddressScheme::CashAddr,
+                _ => return Err(invalid("address_scheme", "`base58` or `cashaddr`")),
+            }
+        } else {
+            AddressScheme::Base58
+        };
+        let aux_pow_activation_version = if has("aux_pow_activation_version") {
+            let version = number("aux_pow_activation_version")?;
+            Some(
+                u32::try_from(version)
+                    .map_err(|_| invalid("aux_pow_activation_version", "a 32 bit number"))?,
+            )
+        } else {
+            None
+        };
+        let sha256d_pow = if has("sha256d_pow") {
+            field("sha256d_pow")?
+                .as_bool()
+                .ok_or_else(|| invalid("sha256d_pow", "a boolean"))?
+        } else {
+            true
+        };
+        let default_folder = if has("default_folder") {
+            PathBuf::from(string("default_folder")?)
+        } else {
+            Path::new(&format!(".{}", name.to_lowercase())).join("blocks")
+        };
+
+        Ok(CoinType {
+            version_id: pubkey_version[0],
+            address_encoder: Arc::new(AddressFormat {
+                scheme,
+                pubkey_version,
+                script_version,
+                hrp,
+            }),
+            name,
+            magic,
+            genesis_hash,
+            aux_pow_activation_version,
+            sha256d_pow,
+            default_folder,
+            subsidy_schedule: SubsidySchedule::Halving {
+                initial_subsidy,
+                halving_interval,
+            },
+            coinbase_maturity,
+        })
+    }
 }
 
 impl Default for CoinType {
@@ -230,7 +513,11 @@ impl<T: Coin> From<T> for CoinType {
             version_id: coin.version_id(),
             genesis_hash: coin.genesis(),
             aux_pow_activation_version: coin.aux_pow_activation_version(),
+            sha256d_pow: coin.sha256d_pow(),
             default_folder: coin.default_folder(),
+            subsidy_schedule: coin.subsidy_schedule(),
+            coinbase_maturity: coin.coinbase_maturity(),
+            address_encoder: coin.address_encoder(),
         }
     }
 }
@@ -241,6 +528,9 @@ impl FromStr for CoinType {
         match coin_name {
             "bitcoin" => Ok(CoinType::from(Bitcoin)),
             "testnet3" => Ok(CoinType::from(TestNet3)),
+            "testnet4" => Ok(CoinType::from(TestNet4)),
+            "signet" => Ok(CoinType::from(Signet)),
+            "regtest" => Ok(CoinType::from(Regtest)),
             "namecoin" => Ok(CoinType::from(Namecoin)),
             "litecoin" => Ok(CoinType::from(Litecoin)),
             "dogecoin" => Ok(CoinType::from(Dogecoin)),
@@ -255,3 +545,217 @@ impl FromStr for CoinType {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::proto::script::encoder::AddressScheme;
+    use crate::blockchain::proto::tx::EvaluatedTx;
+    use crate::common::testutils;
+    use bitcoin::base58;
+    use bitcoin::hashes::Hash;
+
+    /// Coin with two byte version prefixes, like Zcash
+    struct TwoByteCoin;
+
+    impl Coin for TwoByteCoin {
+        fn name(&self) -> String {
+            String::from("TwoByteCoin")
+        }
+        fn magic(&self) -> u32 {
+            0x12345678
+        }
+        fn version_id(&self) -> u8 {
+            0x1c
+        }
+        fn genesis(&self) -> sha256d::Hash {
+            sha256d::Hash::all_zeros()
+        }
+        fn default_folder(&self) -> PathBuf {
+            PathBuf::from(".twobytecoin")
+        }
+        fn address_encoder(&self) -> Arc<dyn AddressEncoder> {
+            Arc::new(AddressFormat {
+                scheme: AddressScheme::Base58,
+                pubkey_version: vec![0x1c, 0xb8],
+                script_version: vec![0x1c, 0xbd],
+                hrp: None,
+            })
+        }
+    }
+
+    #[test]
+    fn test_custom_address_encoder() {
+        let coin = CoinType::from(TwoByteCoin);
+        let mut p2sh = vec![0xa9, 0x14];
+        p2sh.extend_from_slice(&[7; 20]);
+        p2sh.push(0x87);
+        let mut raw = testutils::coinbase(1, &[(1000, testutils::p2pkh_script(3)), (0, p2sh)]);
+        raw.version_id = coin.version_id;
+        raw.encoder = coin.address_encoder.clone();
+        let tx = EvaluatedTx::from(raw);
+
+        let decode = |i: usize| {
+            let address = tx.outputs[i].script.address.as_ref().unwrap();
+            base58::decode_check(address).unwrap()
+        };
+        assert_eq!(decode(0), [&[0x1c, 0xb8][..], &[3; 20]].concat());
+        assert_eq!(decode(1), [&[0x1c, 0xbd][..], &[7; 20]].concat());
+    }
+
+    #[test]
+    fn test_test_networks() {
+        let zero = sha256d::Hash::all_zeros();
+        let mut p2wpkh = vec![0x00, 0x14];
+        p2wpkh.extend_from_slice(&[9; 20]);
+        for (name, magic, hrp) in [
+            ("testnet4", [0x1c, 0x16, 0x3f, 0x28], "tb1q"),
+            ("signet", [0x0a, 0x03, 0xcf, 0x40], "tb1q"),
+            ("regtest", [0xfa, 0xbf, 0xb5, 0xda], "bcrt1q"),
+        ] {
+            let coin = CoinType::from_str(name).unwrap();
+            assert_eq!(coin.name.to_lowercase(), name);
+            assert_eq!(coin.magic.to_le_bytes(), magic);
+            assert!(coin
+                .default_folder
+                .starts_with(Path::new(".bitcoin").join(name)));
+
+            let mut raw = testutils::coinbase(
+                1,
+                &[(1000, testutils::p2pkh_script(3)), (0, p2wpkh.clone())],
+            );
+            raw.version_id = coin.version_id;
+            raw.encoder = coin.address_encoder.clone();
+            let tx = EvaluatedTx::from(raw);
+            let address = |i: usize| tx.outputs[i].script.address.clone().unwrap();
+            assert!(address(0).starts_with('m') || address(0).starts_with('n'));
+            assert!(address(1).starts_with(hrp));
+        }
+        let regtest = CoinType::from_str("regtest").unwrap();
+        assert_eq!(regtest.block_subsidy(149, &zero), 5000000000);
+        assert_eq!(regtest.block_subsidy(150, &zero), 2500000000);
+    }
+
+    #[test]
+    fn test_coin_config_file() {
+        let zero = sha256d::Hash::all_zeros();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("coin.json");
+        fs::write(
+            &path,
+            r#"{
+                "name": "Regtest",
+                "magic": "fabfb5da",
+                "genesis_hash": "0f9188f13cb7b2c71f2a335e3a4fc328bf5beb436012afca590b1a11466e2206",
+                "pubkey_version": 111,
+                "script_version": [196],
+                "bech32_hrp": "bcrt",
+                "initial_subsidy": 5000000000,
+                "halving_interval": 150,
+                "coinbase_maturity": 100
+            }"#,
+        )
+        .unwrap();
+        let coin = CoinType::from_config_file(&path).unwrap();
+        assert_eq!(coin.name, "Regtest");
+        assert_eq!(coin.magic, 0xdab5bffa);
+        assert_eq!(coin.version_id, 0x6f);
+        assert_eq!(coin.block_subsidy(150, &zero), 2500000000);
+        assert_eq!(coin.default_folder, Path::new(".regtest").join("blocks"));
+        assert!(coin.address_encoder.validate().is_ok());
+
+        let err = |config: &str| {
+            fs::write(&path, config).unwrap();
+            CoinType::from_config_file(&path).err().unwrap().to_string()
+        };
+        assert!(err(r#"{"name": "Regtest"}"#).contains("missing field `magic`"));
+        assert!(err(r#"{"name": "Regtest", "magic": "fabfb5"}"#).contains("`magic` must be"));
+        assert!(err("[]").contains("expected a JSON object"));
+        assert!(err("{").contains(&path.display().to_string()));
+    }
+
+    #[test]
+    fn test_chain_spec_toml() {
+        let zero = sha256d::Hash::all_zeros();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("chain.toml");
+        fs::write(
+            &path,
+            r#"
+            # Merged mined from version 0x10000 on, like Namecoin
+            name = "Mergecoin"
+            magic = "f9beb4fe"
+            genesis_hash = "000000000062b72c5e2ceb45fbc8587e807c155b0da735e6483dfba2f0a9c770"
+            pubkey_version = 52
+            script_version = 13
+            initial_subsidy = 5_000_000_000
+            halving_interval = 210_000
+            coinbase_maturity = 100
+            aux_pow_activation_version = 0x10000
+            sha256d_pow = false
+            default_folder = ".mergecoin/blocks"
+            "#,
+        )
+        .unwrap();
+        let coin = CoinType::from_config_file(&path).unwrap();
+        assert_eq!(coin.name, "Mergecoin");
+        assert_eq!(coin.magic, 0xfeb4bef9);
+        assert_eq!(coin.version_id, 52);
+        assert_eq!(coin.aux_pow_activation_version, Some(0x10000));
+        assert!(!coin.sha256d_pow);
+        assert!(CoinType::from(Bitcoin).sha256d_pow);
+        assert_eq!(coin.block_subsidy(210000, &zero), 2500000000);
+        assert_eq!(coin.default_folder, Path::new(".mergecoin/blocks"));
+
+        fs::write(&path, "name = \"Mergecoin\"\n[params]").unwrap();
+        let err = CoinType::from_config_file(&path).err().unwrap().to_string();
+        assert!(err.contains("line 2: tables are not supported"));
+        fs::write(&path, "name = \"Mergecoin\"").unwrap();
+        let err = CoinType::from_config_file(&path).err().unwrap().to_string();
+        assert!(err.contains("missing field `magic`"));
+    }
+
+    #[test]
+    fn test_block_subsidy() {
+        let zero = sha256d::Hash::all_zeros();
+        let bitcoin = CoinType::from(Bitcoin);
+        assert_eq!(bitcoin.block_subsidy(0, &zero), 5000000000);
+        assert_eq!(bitcoin.block_subsidy(6929999, &zero), 1);
+        assert_eq!(bitcoin.block_subsidy(6930000, &zero), 0);
+        assert_eq!(bitcoin.block_subsidy(64 * 210000, &zero), 0);
+
+        let myriadcoin = CoinType::from(Myriadcoin);
+        assert_eq!(myriadcoin.block_subsidy(967680, &zero), 50000000000);
+        assert_eq!(CoinType::from(Dogecoin).issued_supply(1), None);
+
+        let litecoin = CoinType::from(Litecoin);
+        assert_eq!(litecoin.block_subsidy(839999, &zero), 5000000000);
+        assert_eq!(litecoin.block_subsidy(840000, &zero), 2500000000);
+    }
+
+    #[test]
+    fn test_coinbase_maturity() {
+        assert_eq!(CoinType::from(Bitcoin).coinbase_maturity, 100);
+        assert_eq!(CoinType::from(Litecoin).coinbase_maturity, 100);
+        assert_eq!(CoinType::from(Dogecoin).coinbase_maturity, 240);
+        assert_eq!(
+            "dogecoin".parse::<CoinType>().unwrap().coinbase_maturity,
+            240
+        );
+    }
+
+    #[test]
+    fn test_issued_supply() {
+        let bitcoin = CoinType::from(Bitcoin);
+        assert_eq!(bitcoin.issued_supply(0), Some(5000000000));
+        assert_eq!(bitcoin.issued_supply(209999), Some(210000 * 5000000000));
+        // Supply right before the third halving: 18.375M BTC
+        assert_eq!(bitcoin.issued_supply(629999), Some(1837500000000000));
+        assert_eq!(bitcoin.issued_supply(630000), Some(1837500625000000));
+        // The schedule converges towards 21M BTC
+        assert_eq!(
+            bitcoin.issued_supply(u32::MAX as u64),
+            Some(2099999997690000)
+        );
+    }
+}
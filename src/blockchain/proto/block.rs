@@ -1,12 +1,17 @@
 use bitcoin::hashes::sha256d;
+use bitcoin::pow::{CompactTarget, Target};
+use bitcoin::BlockHash;
 use std::fmt;
 
 use rayon::iter::{IntoParallelIterator, ParallelIterator};
 
+use crate::blockchain::parser::types::CoinType;
 use crate::blockchain::proto::header::BlockHeader;
 use crate::blockchain::proto::tx::{EvaluatedTx, RawTx};
 use crate::blockchain::proto::varuint::VarUint;
 use crate::blockchain::proto::{Hashed, MerkleBranch};
+use crate::blockchain::undo::BlockUndo;
+use crate::common::bench::{self, Stage};
 use crate::common::utils;
 use crate::errors::{OpError, OpErrorKind, OpResult};
 
@@ -17,6 +22,10 @@ pub struct Block {
     pub aux_pow_extension: Option<AuxPowExtension>,
     pub tx_count: VarUint,
     pub txs: Vec<Hashed<EvaluatedTx>>,
+    /// Serialized block as stored in the blk file, only kept if the callback needs it
+    pub raw: Option<Vec<u8>>,
+    /// Outputs spent by the transactions as stored in the rev file, only read if the callback needs them
+    pub undo: Option<BlockUndo>,
 }
 
 impl Block {
@@ -29,17 +38,28 @@ impl Block {
     ) -> Block {
         let txs = txs
             .into_par_iter()
-            .map(|raw| Hashed::double_sha256(EvaluatedTx::from(raw)))
+            .map(|raw| {
+                let tx = bench::time(Stage::ScriptParse, || EvaluatedTx::from(raw));
+                bench::time(Stage::Hash, || Hashed::double_sha256(tx))
+            })
             .collect();
         Block {
             size,
-            header: Hashed::double_sha256(header),
+            header: bench::time(Stage::Hash, || Hashed::double_sha256(header)),
             aux_pow_extension,
             tx_count,
             txs,
+            raw: None,
+            undo: None,
         }
     }
 
+    /// Returns the block hash (double SHA256 of the header).
+    /// It is computed once when the block is parsed, so there is no need to cache it.
+    pub fn hash(&self) -> sha256d::Hash {
+        self.header.hash
+    }
+
     /// Computes merkle root for all containing transactions
     pub fn compute_merkle_root(&self) -> sha256d::Hash {
         let hashes = self
@@ -65,6 +85,21 @@ impl Block {
             Err(OpError::new(OpErrorKind::ValidationError).join_msg(&msg))
         }
     }
+
+    /// Verifies that the block hash meets the target encoded in the bits of the header.
+    /// Only meaningful for coins whose proof of work is the block hash and blocks without AuxPow.
+    pub fn verify_pow(&self) -> OpResult<()> {
+        let target = Target::from_compact(CompactTarget::from_consensus(self.header.value.bits));
+        if target.is_met_by(BlockHash::from_raw_hash(self.header.hash)) {
+            Ok(())
+        } else {
+            let msg = format!(
+                "Block hash doesn't meet the target!\n  -> bits: {:#010x}\n  -> target: {:x}\n  -> hash: {}\n",
+                self.header.value.bits, target, &self.header.hash
+            );
+            Err(OpError::new(OpErrorKind::ValidationError).join_msg(&msg))
+        }
+    }
 }
 
 impl fmt::Debug for Block {
@@ -86,23 +121,69 @@ pub struct AuxPowExtension {
     pub parent_block: BlockHeader,
 }
 
-/// Get block reward for given height
-pub fn get_base_reward(block_height: u64) -> u64 {
-    (50 * 100000000) >> (block_height / 210000)
+/// Get block reward for given height, the previous block hash seeds random reward schedules
+pub fn get_base_reward(block_height: u64, prev_hash: &sha256d::Hash, coin: &CoinType) -> u64 {
+    coin.block_subsidy(block_height, prev_hash)
 }
 
 #[cfg(test)]
 mod tests {
-    use super::get_base_reward;
+    use super::*;
+    use crate::blockchain::parser::types::{Bitcoin, Dogecoin};
+    use bitcoin::hashes::Hash;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_block_hash() {
+        // Mainnet block 1
+        let header = BlockHeader {
+            version: 1,
+            prev_hash: sha256d::Hash::from_str(
+                "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f",
+            )
+            .unwrap(),
+            merkle_root: sha256d::Hash::from_str(
+                "0e3e2357e806b6cdb1f70b54c3a3a17b6714ee1f0e68bebb44a74b1efd512098",
+            )
+            .unwrap(),
+            timestamp: 1231469665,
+            bits: 0x1d00ffff,
+            nonce: 2573394689,
+        };
+        let block = Block::new(215, header, None, VarUint::from(1u8), vec![]);
+        assert_eq!(
+            block.hash(),
+            sha256d::Hash::from_str(
+                "00000000839a8e6886ab5951d76f411475428afc90947ee320161bbf18eb6048"
+            )
+            .unwrap()
+        );
+        assert_eq!(block.hash(), block.header.hash);
+        assert!(block.verify_pow().is_ok());
+
+        // The same header with a lower target
+        let mut header = block.header.value.clone();
+        header.bits = 0x1c00ffff;
+        let block = Block::new(215, header, None, VarUint::from(1u8), vec![]);
+        assert!(block.verify_pow().is_err());
+    }
 
     #[test]
     fn test_get_base_reward() {
-        assert_eq!(get_base_reward(0), 5000000000);
-        assert_eq!(get_base_reward(209999), 5000000000);
-        assert_eq!(get_base_reward(210000), 2500000000);
-        assert_eq!(get_base_reward(419999), 2500000000);
-        assert_eq!(get_base_reward(420000), 1250000000);
-        assert_eq!(get_base_reward(629999), 1250000000);
-        assert_eq!(get_base_reward(630000), 0625000000);
+        let coin = CoinType::from(Bitcoin);
+        let zero = sha256d::Hash::all_zeros();
+        assert_eq!(get_base_reward(0, &zero, &coin), 5000000000);
+        assert_eq!(get_base_reward(209999, &zero, &coin), 5000000000);
+        assert_eq!(get_base_reward(210000, &zero, &coin), 2500000000);
+        assert_eq!(get_base_reward(419999, &zero, &coin), 2500000000);
+        assert_eq!(get_base_reward(420000, &zero, &coin), 1250000000);
+        assert_eq!(get_base_reward(629999, &zero, &coin), 1250000000);
+        assert_eq!(get_base_reward(630000, &zero, &coin), 625000000);
+
+        let dogecoin = CoinType::from(Dogecoin);
+        assert_eq!(
+            get_base_reward(145000, &zero, &dogecoin),
+            250000 * 100000000
+        );
     }
 }
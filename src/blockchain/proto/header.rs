@@ -3,6 +3,12 @@ use std::fmt;
 
 use crate::blockchain::proto::ToRaw;
 
+/// BIP-9 versions have the top 3 bits set to 001
+const VERSIONBITS_TOP_MASK: u32 = 0xe0000000;
+const VERSIONBITS_TOP_BITS: u32 = 0x20000000;
+/// Number of bits which can be used for deployments
+const VERSIONBITS_NUM_BITS: u8 = 29;
+
 /// Block Header definition. Exact 80 bytes long
 #[derive(Clone)]
 pub struct BlockHeader {
@@ -14,6 +20,25 @@ pub struct BlockHeader {
     pub nonce: u32,
 }
 
+impl BlockHeader {
+    /// Returns true if the version is interpreted as BIP-9 deployment bits.
+    /// Older blocks use plain version numbers (1 to 4), which are not signaling anything.
+    pub fn uses_version_bits(&self) -> bool {
+        self.version & VERSIONBITS_TOP_MASK == VERSIONBITS_TOP_BITS
+    }
+
+    /// Returns the deployment bits signaled by this block, in ascending order.
+    /// Empty for legacy version numbers.
+    pub fn signaled_bits(&self) -> Vec<u8> {
+        if !self.uses_version_bits() {
+            return Vec::new();
+        }
+        (0..VERSIONBITS_NUM_BITS)
+            .filter(|bit| self.version & (1 << bit) != 0)
+            .collect()
+    }
+}
+
 impl ToRaw for BlockHeader {
     fn to_bytes(&self) -> Vec<u8> {
         let mut bytes = Vec::with_capacity(80);
@@ -39,3 +64,40 @@ impl fmt::Debug for BlockHeader {
             .finish()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(version: u32) -> BlockHeader {
+        BlockHeader {
+            version,
+            prev_hash: sha256d::Hash::all_zeros(),
+            merkle_root: sha256d::Hash::all_zeros(),
+            timestamp: 0,
+            bits: 0,
+            nonce: 0,
+        }
+    }
+
+    #[test]
+    fn test_signaled_bits() {
+        // Legacy versions, even though 4 = 1 << 2
+        for version in [1, 2, 3, 4] {
+            assert!(!header(version).uses_version_bits());
+            assert!(header(version).signaled_bits().is_empty());
+        }
+        assert!(header(0x20000000).uses_version_bits());
+        assert!(header(0x20000000).signaled_bits().is_empty());
+        // Segwit (bit 1) and Taproot (bit 2)
+        assert_eq!(header(0x20000002).signaled_bits(), vec![1]);
+        assert_eq!(header(0x20000006).signaled_bits(), vec![1, 2]);
+        assert_eq!(
+            header(0x3fffffff).signaled_bits(),
+            (0..29).collect::<Vec<u8>>()
+        );
+        // Any other top bits are not BIP-9
+        assert!(!header(0x60000002).uses_version_bits());
+        assert!(header(0x60000002).signaled_bits().is_empty());
+    }
+}
@@ -1,6 +1,8 @@
-use bitcoin::hashes::{sha256d, Hash};
+use bitcoin::hashes::sha256d;
 use std::fmt;
 
+use crate::common::hashing;
+
 pub mod block;
 pub mod header;
 pub mod script;
@@ -21,7 +23,7 @@ pub struct Hashed<T> {
 impl<T: ToRaw> Hashed<T> {
     /// encapsulates T and creates double sha256 as hash
     pub fn double_sha256(value: T) -> Hashed<T> {
-        let hash = sha256d::Hash::hash(&value.to_bytes());
+        let hash = hashing::sha256d(&value.to_bytes());
         Hashed { hash, value }
     }
 }
@@ -0,0 +1,226 @@
+/// Classification of scripts by template, including the ones `ScriptPattern` doesn't tell apart:
+/// m-of-n multisig and timelocked scripts, e.g. revealed redeemScripts or witnessScripts.
+use bitcoin::blockdata::script::Instruction;
+use bitcoin::opcodes::all;
+use bitcoin::Script;
+use std::fmt;
+
+use crate::blockchain::proto::script::timelock::{find_timelocks, TimelockKind};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ScriptType {
+    PubKey,
+    PubKeyHash,
+    ScriptHash,
+    /// <m> <pubkey>.. <n> OP_CHECKMULTISIG
+    MultiSig {
+        required: u8,
+        total: u8,
+    },
+    WitnessPubKeyHash,
+    WitnessScriptHash,
+    Taproot,
+    /// Witness program of an undefined version or length
+    WitnessUnknown,
+    /// OP_RETURN <data>
+    NullData,
+    /// Any other script containing OP_CHECKLOCKTIMEVERIFY or OP_CHECKSEQUENCEVERIFY
+    Timelocked {
+        absolute: bool,
+        relative: bool,
+    },
+    NonStandard,
+}
+
+impl fmt::Display for ScriptType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ScriptType::PubKey => write!(f, "p2pk"),
+            ScriptType::PubKeyHash => write!(f, "p2pkh"),
+            ScriptType::ScriptHash => write!(f, "p2sh"),
+            ScriptType::MultiSig { required, total } => {
+                write!(f, "multisig_{}of{}", required, total)
+            }
+            ScriptType::WitnessPubKeyHash => write!(f, "p2wpkh"),
+            ScriptType::WitnessScriptHash => write!(f, "p2wsh"),
+            ScriptType::Taproot => write!(f, "p2tr"),
+            ScriptType::WitnessUnknown => write!(f, "witness_unknown"),
+            ScriptType::NullData => write!(f, "nulldata"),
+            ScriptType::Timelocked { absolute, relative } => match (absolute, relative) {
+                (true, true) => write!(f, "timelock_cltv_csv"),
+                (true, false) => write!(f, "timelock_cltv"),
+                _ => write!(f, "timelock_csv"),
+            },
+            ScriptType::NonStandard => write!(f, "nonstandard"),
+        }
+    }
+}
+
+/// Outpoint, scriptSig length and nSequence of an input with a scriptSig shorter than 253 bytes
+const INPUT_BASE_SIZE: u64 = 36 + 1 + 4;
+/// Push of a DER signature with sighash flag, at most 72 bytes
+const SIG_PUSH_SIZE: u64 = 1 + 72;
+
+impl ScriptType {
+    /// Estimated virtual size of an input spending an output with this scriptPubKey,
+    /// with the largest signatures and compressed public keys. P2SH and P2WSH assume a
+    /// 2-of-3 multisig redeemScript or witnessScript. None if the spending script is unknown.
+    pub fn input_vsize(&self) -> Option<u64> {
+        // Bytes of the scriptSig and weight units of the witness
+        let (script_sig, witness) = match *self {
+            ScriptType::PubKey => (SIG_PUSH_SIZE, 0),
+            ScriptType::PubKeyHash => (SIG_PUSH_SIZE + 1 + 33, 0),
+            // OP_0 <sig> <sig> OP_PUSHDATA1 <2 <pubkey> <pubkey> <pubkey> 3 OP_CHECKMULTISIG>
+            ScriptType::ScriptHash => (1 + 2 * SIG_PUSH_SIZE + 2 + 105, 0),
+            ScriptType::MultiSig { required, .. } => (1 + required as u64 * SIG_PUSH_SIZE, 0),
+            ScriptType::WitnessPubKeyHash => (0, 1 + SIG_PUSH_SIZE + 1 + 33),
+            ScriptType::WitnessScriptHash => (0, 1 + 1 + 2 * SIG_PUSH_SIZE + 1 + 105),
+            // Key path spend with a 64 byte Schnorr signature
+            ScriptType::Taproot => (0, 1 + 1 + 64),
+            _ => return None,
+        };
+        // The length of a scriptSig of 253 bytes or more takes 3 bytes
+        let script_len_size = if script_sig < 253 { 0 } else { 2 };
+        Some(INPUT_BASE_SIZE + script_len_size + script_sig + witness.div_ceil(4))
+    }
+}
+
+/// Classifies a scriptPubKey, redeemScript or witnessScript
+pub fn script_type(bytes: &[u8]) -> ScriptType {
+    let script = Script::from_bytes(bytes);
+    if script.is_op_return() {
+        ScriptType::NullData
+    } else if script.is_p2pk() {
+        ScriptType::PubKey
+    } else if script.is_p2pkh() {
+        ScriptType::PubKeyHash
+    } else if script.is_p2sh() {
+        ScriptType::ScriptHash
+    } else if script.is_v0_p2wpkh() {
+        ScriptType::WitnessPubKeyHash
+    } else if script.is_v0_p2wsh() {
+        ScriptType::WitnessScriptHash
+    } else if script.is_v1_p2tr() {
+        ScriptType::Taproot
+    } else if script.is_witness_program() {
+        ScriptType::WitnessUnknown
+    } else if let Some((required, total)) = multisig(script) {
+        ScriptType::MultiSig { required, total }
+    } else {
+        let timelocks = find_timelocks(bytes);
+        let has = |kind| timelocks.iter().any(|t| t.kind == kind);
+        match (has(TimelockKind::Absolute), has(TimelockKind::Relative)) {
+            (false, false) => ScriptType::NonStandard,
+            (absolute, relative) => ScriptType::Timelocked { absolute, relative },
+        }
+    }
+}
+
+/// Returns m and n of the bare multisig template, keys must have the size of a public key
+fn multisig(script: &Script) -> Option<(u8, u8)> {
+    let small_num = |instruction: Option<Result<Instruction, _>>| match instruction {
+        Some(Ok(Instruction::Op(op))) if (0x51..=0x60).contains(&op.to_u8()) => {
+            Some(op.to_u8() - 0x50)
+        }
+        _ => None,
+    };
+    let mut instructions = script.instructions();
+    let required = small_num(instructions.next())?;
+    let mut keys = 0;
+    let total = loop {
+        match instructions.next() {
+            Some(Ok(Instruction::PushBytes(data))) if matches!(data.len(), 33 | 65) => keys += 1,
+            instruction => break small_num(instruction)?,
+        }
+    };
+    match instructions.next() {
+        Some(Ok(Instruction::Op(all::OP_CHECKMULTISIG))) => {}
+        _ => return None,
+    }
+    if instructions.next().is_some() || keys != total || required > total {
+        return None;
+    }
+    Some((required, total))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::utils;
+
+    #[test]
+    fn test_script_type() {
+        let key = |seed: u8| {
+            let mut push = vec![0x21, 0x02];
+            push.extend([seed; 32]);
+            push
+        };
+        let mut multisig = vec![0x52];
+        multisig.extend(key(1));
+        multisig.extend(key(2));
+        multisig.extend(key(3));
+        multisig.extend([0x53, 0xae]);
+        assert_eq!(
+            script_type(&multisig),
+            ScriptType::MultiSig {
+                required: 2,
+                total: 3
+            }
+        );
+        assert_eq!(script_type(&multisig).to_string(), "multisig_2of3");
+        // Wrong key count
+        let n = multisig.len() - 2;
+        multisig[n] = 0x54;
+        assert_eq!(script_type(&multisig), ScriptType::NonStandard);
+
+        // <expiry> OP_CHECKLOCKTIMEVERIFY OP_DROP <pubkey> OP_CHECKSIG
+        let mut cltv = vec![0x03, 0x40, 0x0d, 0x03, 0xb1, 0x75];
+        cltv.extend(key(1));
+        cltv.push(0xac);
+        assert_eq!(script_type(&cltv).to_string(), "timelock_cltv");
+        assert_eq!(
+            script_type(&[0x52, 0xb2, 0x75, 0xb1]),
+            ScriptType::Timelocked {
+                absolute: true,
+                relative: true
+            }
+        );
+
+        let scripts = [
+            (
+                "76a91412ab8dc588ca9d5787dde7eb29569da63c3a238c88ac",
+                "p2pkh",
+            ),
+            ("a914e9c3dd0c07aac76179ebc76a6c78d4d67c6c160a87", "p2sh"),
+            ("0014751e76e8199196d454941c45d1b3a323f1433bd6", "p2wpkh"),
+            ("6a0401020304", "nulldata"),
+            ("520401020304", "witness_unknown"),
+            ("51", "nonstandard"),
+        ];
+        for (hex, name) in scripts {
+            assert_eq!(
+                script_type(&utils::hex_to_vec(hex)).to_string(),
+                name,
+                "{}",
+                hex
+            );
+        }
+    }
+
+    #[test]
+    fn test_input_vsize() {
+        assert_eq!(ScriptType::PubKey.input_vsize(), Some(114));
+        assert_eq!(ScriptType::PubKeyHash.input_vsize(), Some(148));
+        assert_eq!(ScriptType::ScriptHash.input_vsize(), Some(297));
+        let multisig = ScriptType::MultiSig {
+            required: 1,
+            total: 3,
+        };
+        assert_eq!(multisig.input_vsize(), Some(115));
+        assert_eq!(ScriptType::WitnessPubKeyHash.input_vsize(), Some(68));
+        assert_eq!(ScriptType::WitnessScriptHash.input_vsize(), Some(105));
+        assert_eq!(ScriptType::Taproot.input_vsize(), Some(58));
+        assert_eq!(ScriptType::NullData.input_vsize(), None);
+        assert_eq!(ScriptType::NonStandard.input_vsize(), None);
+    }
+}
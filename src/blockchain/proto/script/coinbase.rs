@@ -0,0 +1,131 @@
+/// Decodes the free-form coinbase scriptSig: the committed height (BIP34),
+/// the extranonce and the text miners put there, e.g. pool signatures like `/ViaBTC/`
+use bitcoin::blockdata::script::Instruction;
+use bitcoin::Script;
+
+use crate::blockchain::proto::script::script_num;
+
+/// Printable text shorter than this is most likely part of binary data
+const MIN_TEXT_LEN: usize = 4;
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct CoinbaseMessage {
+    /// Height pushed first, None if the first push doesn't match the height of the block
+    pub height: Option<u64>,
+    /// First push after the height, or after the nBits push of coinbases before BIP34, which isn't text
+    pub extranonce: Option<Vec<u8>>,
+    /// Runs of printable ASCII characters
+    pub text: Vec<String>,
+}
+
+impl CoinbaseMessage {
+    /// Decodes the scriptSig of the coinbase of the block at the given height.
+    /// Falls back to scanning the raw bytes for text if the script has an invalid push.
+    pub fn decode(script_sig: &[u8], block_height: u64) -> Self {
+        let mut pushes = Vec::new();
+        for instruction in Script::from_bytes(script_sig).instructions() {
+            match instruction {
+                Ok(Instruction::PushBytes(data)) => pushes.push(data.as_bytes().to_vec()),
+                // OP_1..OP_16 encode the heights 1 to 16
+                Ok(Instruction::Op(op)) if (0x51..=0x60).contains(&op.to_u8()) => {
+                    pushes.push(vec![op.to_u8() - 0x50])
+                }
+                Ok(Instruction::Op(_)) => {}
+                Err(_) => {
+                    return CoinbaseMessage {
+                        text: printable_runs(script_sig),
+                        ..Default::default()
+                    }
+                }
+            }
+        }
+
+        let mut message = CoinbaseMessage::default();
+        let mut rest = pushes.as_slice();
+        if let [first, tail @ ..] = pushes.as_slice() {
+            if first.len() <= 8 {
+                let height = script_num(first);
+                message.height =
+                    (height >= 0 && height as u64 == block_height).then_some(block_height);
+                rest = tail;
+            }
+        }
+        for push in rest {
+            let text = printable_runs(push);
+            if text.is_empty() {
+                if message.extranonce.is_none() {
+                    message.extranonce = Some(push.clone());
+                }
+            } else {
+                message.text.extend(text);
+            }
+        }
+        message
+    }
+
+    /// Tags enclosed in slashes, the convention of most pools, e.g. `Foundry USA Pool #dropgold`.
+    /// Only text starting with a slash is considered, so dates like `03/Jan/2009` aren't tags.
+    pub fn tags(&self) -> Vec<String> {
+        let mut tags = Vec::new();
+        for text in &self.text {
+            let parts: Vec<&str> = text.split('/').collect();
+            if parts.len() < 3 || !parts[0].is_empty() {
+                continue;
+            }
+            for tag in &parts[1..parts.len() - 1] {
+                let tag = tag.trim();
+                if !tag.is_empty() {
+                    tags.push(String::from(tag));
+                }
+            }
+        }
+        tags
+    }
+}
+
+/// Runs of at least `MIN_TEXT_LEN` printable ASCII characters
+fn printable_runs(data: &[u8]) -> Vec<String> {
+    data.split(|byte| !(0x20..0x7f).contains(byte))
+        .filter(|run| run.len() >= MIN_TEXT_LEN)
+        .map(|run| String::from_utf8_lossy(run).trim().to_string())
+        .filter(|run| !run.is_empty())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::utils;
+
+    #[test]
+    fn test_decode() {
+        // Genesis block: <nBits> <4> <text>
+        let genesis = utils::hex_to_vec("04ffff001d0104455468652054696d65732030332f4a616e2f32303039204368616e63656c6c6f72206f6e206272696e6b206f66207365636f6e64206261696c6f757420666f722062616e6b73");
+        let message = CoinbaseMessage::decode(&genesis, 0);
+        assert_eq!(message.height, None);
+        assert_eq!(message.extranonce, Some(vec![0x04]));
+        assert_eq!(
+            message.text,
+            vec!["The Times 03/Jan/2009 Chancellor on brink of second bailout for banks"]
+        );
+        assert!(message.tags().is_empty());
+
+        // <height> <pool tag> <extranonce>
+        let mut script_sig = vec![0x03, 0x40, 0x0d, 0x03, 0x1c];
+        script_sig.extend(b"/Foundry USA Pool #dropgold/");
+        script_sig.extend([0x08, 0xfa, 0xbe, 0x6d, 0x6d, 0x01, 0x02, 0x03, 0x04]);
+        let message = CoinbaseMessage::decode(&script_sig, 200000);
+        assert_eq!(message.height, Some(200000));
+        assert_eq!(
+            message.extranonce,
+            Some(vec![0xfa, 0xbe, 0x6d, 0x6d, 0x01, 0x02, 0x03, 0x04])
+        );
+        assert_eq!(message.tags(), vec!["Foundry USA Pool #dropgold"]);
+        assert_eq!(CoinbaseMessage::decode(&script_sig, 200001).height, None);
+
+        // The push overruns the script
+        let message = CoinbaseMessage::decode(b"\x4c\xffMined by AntPool\x00", 1);
+        assert_eq!(message.height, None);
+        assert_eq!(message.text, vec!["Mined by AntPool"]);
+    }
+}
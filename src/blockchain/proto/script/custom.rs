@@ -1,8 +1,8 @@
 /// This custom Script implementation is for all networks other than Bitcoin and Bitcoin Testnet
+use crate::blockchain::proto::script::encoder::AddressEncoder;
 use crate::blockchain::proto::script::{EvaluatedScript, ScriptError, ScriptPattern};
 use crate::common::utils;
-use bitcoin::base58;
-use bitcoin::hashes::{hash160, sha256d, Hash};
+use bitcoin::hashes::{hash160, Hash};
 use bitcoin::opcodes::{all, All, Class, ClassifyContext};
 use std::fmt;
 
@@ -74,7 +74,7 @@ struct ScriptEvaluator<'a> {
 }
 
 impl<'a> ScriptEvaluator<'a> {
-    pub fn new(bytes: &'a [u8]) -> ScriptEvaluator {
+    pub fn new(bytes: &'a [u8]) -> ScriptEvaluator<'a> {
         ScriptEvaluator {
             bytes,
             n_bytes: bytes.len(),
@@ -247,9 +247,9 @@ impl<'a> ScriptEvaluator<'a> {
     }
 }
 
-pub fn eval_from_bytes_custom(bytes: &[u8], version_id: u8) -> EvaluatedScript {
+pub fn eval_from_bytes_custom(bytes: &[u8], encoder: &dyn AddressEncoder) -> EvaluatedScript {
     match ScriptEvaluator::new(bytes).eval() {
-        Ok(stack) => eval_from_stack(stack, version_id),
+        Ok(stack) => eval_from_stack(stack, encoder),
         Err(ScriptError::UnexpectedEof) => EvaluatedScript {
             address: None,
             pattern: ScriptPattern::NotRecognised,
@@ -262,26 +262,29 @@ pub fn eval_from_bytes_custom(bytes: &[u8], version_id: u8) -> EvaluatedScript {
 }
 
 /// Extracts evaluated address from script stack
-fn compute_stack(stack: Stack, version_id: u8) -> Result<EvaluatedScript, ScriptError> {
+fn compute_stack(
+    stack: Stack,
+    encoder: &dyn AddressEncoder,
+) -> Result<EvaluatedScript, ScriptError> {
     let script = match stack.pattern {
         ref p @ ScriptPattern::Pay2PublicKey => {
             let pub_key = stack.elements[0].data()?;
             EvaluatedScript {
-                address: Some(public_key_to_addr(&pub_key, version_id)),
+                address: encoder.pubkey_hash(hash160::Hash::hash(&pub_key).as_byte_array()),
                 pattern: p.clone(),
             }
         }
         ref p @ ScriptPattern::Pay2PublicKeyHash => {
             let h160 = stack.elements[2].data()?;
             EvaluatedScript {
-                address: Some(hash_160_to_address(&h160, version_id)),
+                address: encoder.pubkey_hash(&h160),
                 pattern: p.clone(),
             }
         }
         ref p @ ScriptPattern::Pay2ScriptHash => {
             let h160 = stack.elements[1].data()?;
             EvaluatedScript {
-                address: Some(hash_160_to_address(&h160, 5)),
+                address: encoder.script_hash(&h160),
                 pattern: p.clone(),
             }
         }
@@ -309,8 +312,8 @@ fn compute_stack(stack: Stack, version_id: u8) -> Result<EvaluatedScript, Script
 }
 
 /// Extracts evaluated address from script stack
-fn eval_from_stack(stack: Stack, version_id: u8) -> EvaluatedScript {
-    match compute_stack(stack, version_id) {
+fn eval_from_stack(stack: Stack, encoder: &dyn AddressEncoder) -> EvaluatedScript {
+    match compute_stack(stack, encoder) {
         Ok(script) => script,
         Err(ScriptError::UnexpectedEof) => EvaluatedScript {
             address: None,
@@ -323,26 +326,10 @@ fn eval_from_stack(stack: Stack, version_id: u8) -> EvaluatedScript {
     }
 }
 
-/// Takes full ECDSA public key (65 bytes) and a version id
-fn public_key_to_addr(pub_key: &[u8], version: u8) -> String {
-    let hash = hash160::Hash::hash(pub_key);
-    hash_160_to_address(hash.as_byte_array(), version)
-}
-
-/// Takes 20 byte public key and version id
-fn hash_160_to_address(h160: &[u8], version: u8) -> String {
-    let mut hash = Vec::with_capacity(h160.len() + 5);
-    hash.push(version);
-    hash.extend_from_slice(h160);
-
-    let checksum = &sha256d::Hash::hash(&hash)[0..4];
-    hash.extend_from_slice(checksum);
-    base58::encode(&hash)
-}
-
 #[cfg(test)]
 mod tests {
     use super::{eval_from_bytes_custom, eval_from_stack, ScriptEvaluator, ScriptPattern};
+    use crate::blockchain::proto::script::encoder::AddressFormat;
     use crate::common::utils;
 
     #[test]
@@ -360,7 +347,7 @@ mod tests {
             format!("{:?}", stack)
         );
 
-        let script = eval_from_stack(stack, 0x00);
+        let script = eval_from_stack(stack, &AddressFormat::base58(0x00, 0x05));
         assert_eq!(
             script.address,
             Some(String::from("12higDjoCCNXSA95xZMWUdPvXNmkAduhWv"))
@@ -386,7 +373,7 @@ mod tests {
         assert_eq!("044bca633a91de10df85a63d0a24cb09783148fe0e16c92e937fc4491580c860757148effa0595a955f44078b48ba67fa198782e8bb68115da0daa8fde5301f7f9 OP_CHECKSIG",
             format!("{:?}", stack));
 
-        let script = eval_from_stack(stack, 0x00);
+        let script = eval_from_stack(stack, &AddressFormat::base58(0x00, 0x05));
         assert_eq!(
             script.address,
             Some(String::from("1LEWwJkDj8xriE87ALzQYcHjTmD8aqDj1f"))
@@ -438,7 +425,7 @@ mod tests {
             format!("{:?}", stack)
         );
 
-        let script = eval_from_stack(stack, 0x00);
+        let script = eval_from_stack(stack, &AddressFormat::base58(0x00, 0x05));
         assert_eq!(
             script.address,
             Some(String::from("3P14159f73E4gFr7JterCCQh9QjiTjiZrG"))
@@ -461,7 +448,7 @@ mod tests {
             format!("{:?}", stack)
         );
 
-        let script = eval_from_stack(stack, 0x00);
+        let script = eval_from_stack(stack, &AddressFormat::base58(0x00, 0x05));
         assert_eq!(script.address, None);
         assert_eq!(
             script.pattern,
@@ -481,7 +468,7 @@ mod tests {
             format!("{:?}", stack)
         );
 
-        let script = eval_from_stack(stack, 0x00);
+        let script = eval_from_stack(stack, &AddressFormat::base58(0x00, 0x05));
         assert_eq!(script.address, None);
         assert_eq!(script.pattern, ScriptPattern::NotRecognised);
     }
@@ -489,7 +476,7 @@ mod tests {
     #[test]
     fn test_bitcoin_bogus_script() {
         let bytes = [0x4c, 0xFF, 0x00];
-        let script = eval_from_bytes_custom(&bytes, 0x00);
+        let script = eval_from_bytes_custom(&bytes, &AddressFormat::base58(0x00, 0x05));
         assert_eq!(script.address, None);
         assert_eq!(script.pattern, ScriptPattern::NotRecognised);
     }
@@ -501,7 +488,7 @@ mod tests {
     #[test]
     fn test_namecoin_coinbase_script() {
         let script_pubkey = utils::hex_to_vec("41046a77fa46493d61985c1157a6e3e498b3b97c878c9c23e5b4729d354b574eb33a20c0483551308e2bd08295ce238e8ad09a7a2477732eb2e995a3e20455e9d137ac");
-        let script = eval_from_bytes_custom(&script_pubkey, 0x34);
+        let script = eval_from_bytes_custom(&script_pubkey, &AddressFormat::base58(0x34, 0x05));
         assert_eq!(
             script.address,
             Some(String::from("N3Jpya157nc2d48EPaxtcsbRr9V19U4hfW")),
@@ -512,7 +499,7 @@ mod tests {
     #[test]
     fn test_litecoin_coinbase_script() {
         let script_pubkey = utils::hex_to_vec("4104458bf7d944ce58c007d0f16fa54c0640694568954e162c06be0a0cba7275714b6672c589e7393fa48f8a5f6b6259061d394e9db005651d1bb28349d31339daa8ac");
-        let script = eval_from_bytes_custom(&script_pubkey, 0x30);
+        let script = eval_from_bytes_custom(&script_pubkey, &AddressFormat::base58(0x30, 0x05));
         assert_eq!(
             script.address,
             Some(String::from("LfcUcxALy1gSeqZLrixAm4ETZbEWA7GLat")),
@@ -524,7 +511,7 @@ mod tests {
         let script_pubkey = utils::hex_to_vec(
             "210338bf57d51a50184cf5ef0dc42ecd519fb19e24574c057620262cc1df94da2ae5ac",
         );
-        let script = eval_from_bytes_custom(&script_pubkey, 0x1e);
+        let script = eval_from_bytes_custom(&script_pubkey, &AddressFormat::base58(0x1e, 0x05));
         assert_eq!(
             script.address,
             Some(String::from("DLAznsPDLDRgsVcTFWRMYMG5uH6GddDtv8")),
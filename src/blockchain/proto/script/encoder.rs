@@ -0,0 +1,210 @@
+//! Address encodings of the supported coins. Coins with other formats can implement
+//! `AddressEncoder` and return it from `Coin::address_encoder()`.
+
+use bitcoin::base58;
+use bitcoin::bech32::{self, ToBase32, Variant};
+
+use crate::errors::{OpError, OpErrorKind, OpResult};
+
+/// Converts the hashes and witness programs of standard output scripts to addresses
+pub trait AddressEncoder: Send + Sync {
+    /// Address of a p2pkh output, also used for p2pk outputs with the hash160 of the public key
+    fn pubkey_hash(&self, hash: &[u8]) -> Option<String>;
+    /// Address of a p2sh output
+    fn script_hash(&self, hash: &[u8]) -> Option<String>;
+    /// Address of a native segwit output, None if the coin has no segwit addresses
+    fn witness_program(&self, _version: u8, _program: &[u8]) -> Option<String> {
+        None
+    }
+    /// Checks the configuration once before parsing, so invalid formats don't
+    /// silently result in outputs without addresses
+    fn validate(&self) -> OpResult<()> {
+        Ok(())
+    }
+}
+
+/// Encoding of p2pkh and p2sh addresses
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AddressScheme {
+    /// Base58Check with the version bytes as prefix
+    Base58,
+    /// CashAddr as used by Bitcoin Cash, the version bytes are ignored
+    CashAddr,
+}
+
+/// Address format described by version bytes and the human readable part of segwit addresses,
+/// covers Bitcoin and most of its forks
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AddressFormat {
+    pub scheme: AddressScheme,
+    /// Version bytes of p2pkh addresses, e.g. [0x00] for Bitcoin
+    pub pubkey_version: Vec<u8>,
+    /// Version bytes of p2sh addresses, e.g. [0x05] for Bitcoin
+    pub script_version: Vec<u8>,
+    /// Human readable part of segwit addresses, encoded as bech32 for version 0 and bech32m
+    /// for later versions (BIP350). For CashAddr it is the prefix, e.g. `bitcoincash`.
+    pub hrp: Option<String>,
+}
+
+impl AddressFormat {
+    pub fn base58(pubkey_version: u8, script_version: u8) -> Self {
+        Self {
+            scheme: AddressScheme::Base58,
+            pubkey_version: vec![pubkey_version],
+            script_version: vec![script_version],
+            hrp: None,
+        }
+    }
+
+    pub fn with_hrp(mut self, hrp: &str) -> Self {
+        self.hrp = Some(String::from(hrp));
+        self
+    }
+
+    fn encode_hash(&self, version: &[u8], type_bits: u8, hash: &[u8]) -> Option<String> {
+        match self.scheme {
+            AddressScheme::Base58 => {
+                let mut payload = Vec::with_capacity(version.len() + hash.len());
+                payload.extend_from_slice(version);
+                payload.extend_from_slice(hash);
+                Some(base58::encode_check(&payload))
+            }
+            AddressScheme::CashAddr => cashaddr_encode(self.hrp.as_deref()?, type_bits, hash),
+        }
+    }
+}
+
+impl AddressEncoder for AddressFormat {
+    fn validate(&self) -> OpResult<()> {
+        let fail = |msg: &str| {
+            Err(OpError::new(OpErrorKind::AddressEncodingError(
+                String::from(msg),
+            )))
+        };
+        match (self.scheme, self.hrp.as_deref()) {
+            (AddressScheme::Base58, _)
+                if self.pubkey_version.is_empty() || self.script_version.is_empty() =>
+            {
+                fail("Base58 addresses require version bytes")
+            }
+            (AddressScheme::CashAddr, None) => fail("CashAddr addresses require a prefix"),
+            (_, Some(hrp)) if bech32::encode(hrp, Vec::new(), Variant::Bech32).is_err() => {
+                fail(&format!("Invalid human readable part: {:?}", hrp))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn pubkey_hash(&self, hash: &[u8]) -> Option<String> {
+        self.encode_hash(&self.pubkey_version, 0, hash)
+    }
+
+    fn script_hash(&self, hash: &[u8]) -> Option<String> {
+        self.encode_hash(&self.script_version, 1, hash)
+    }
+
+    fn witness_program(&self, version: u8, program: &[u8]) -> Option<String> {
+        if self.scheme != AddressScheme::Base58 {
+            return None;
+        }
+        let hrp = self.hrp.as_deref()?;
+        let variant = if version == 0 {
+            Variant::Bech32
+        } else {
+            Variant::Bech32m
+        };
+        let mut data = vec![bech32::u5::try_from_u8(version).ok()?];
+        data.extend(program.to_base32());
+        bech32::encode(hrp, data, variant).ok()
+    }
+}
+
+/// Encodes a 160 bit hash as CashAddr, see
+/// https://github.com/bitcoincashorg/bitcoincash.org/blob/master/spec/cashaddr.md
+fn cashaddr_encode(prefix: &str, type_bits: u8, hash: &[u8]) -> Option<String> {
+    const CHARSET: &[u8; 32] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+    if hash.len() != 20 {
+        return None;
+    }
+    // Version byte: type in bits 3-6, size code 0 for 160 bit hashes
+    let mut payload = vec![type_bits << 3];
+    payload.extend_from_slice(hash);
+    let data: Vec<u8> = payload.to_base32().into_iter().map(|d| d.to_u8()).collect();
+
+    let mut checksum_input: Vec<u8> = prefix.bytes().map(|b| b & 0x1f).collect();
+    checksum_input.push(0);
+    checksum_input.extend_from_slice(&data);
+    checksum_input.extend_from_slice(&[0; 8]);
+    let checksum = cashaddr_polymod(&checksum_input);
+
+    let mut address = format!("{}:", prefix);
+    for d in data {
+        address.push(CHARSET[d as usize] as char);
+    }
+    for i in (0..8).rev() {
+        address.push(CHARSET[((checksum >> (5 * i)) & 0x1f) as usize] as char);
+    }
+    Some(address)
+}
+
+fn cashaddr_polymod(values: &[u8]) -> u64 {
+    const GENERATORS: [u64; 5] = [
+        0x98f2bc8e61,
+        0x79b76d99e2,
+        0xf33e5fb3c4,
+        0xae2eabe2a8,
+        0x1e4f43e470,
+    ];
+    let mut c: u64 = 1;
+    for value in values {
+        let c0 = c >> 35;
+        c = ((c & 0x07ffffffff) << 5) ^ *value as u64;
+        for (i, generator) in GENERATORS.iter().enumerate() {
+            if (c0 >> i) & 1 != 0 {
+                c ^= generator;
+            }
+        }
+    }
+    c ^ 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::utils;
+
+    #[test]
+    fn test_address_format() {
+        let hash = utils::hex_to_vec("76a04053bda0a88bda5177b86a15c3b29f559873");
+        let bitcoin = AddressFormat::base58(0x00, 0x05).with_hrp("bc");
+        assert_eq!(
+            bitcoin.pubkey_hash(&hash).unwrap(),
+            "1BpEi6DfDAUFd7GtittLSdBeYJvcoaVggu"
+        );
+        assert_eq!(
+            bitcoin.witness_program(0, &hash).unwrap(),
+            "bc1qw6syq5aa5z5ghkj3w7ux59wrk204txrn9k4rmm"
+        );
+
+        let cash = AddressFormat {
+            scheme: AddressScheme::CashAddr,
+            pubkey_version: vec![],
+            script_version: vec![],
+            hrp: Some(String::from("bitcoincash")),
+        };
+        assert_eq!(
+            cash.pubkey_hash(&hash).unwrap(),
+            "bitcoincash:qpm2qsznhks23z7629mms6s4cwef74vcwvy22gdx6a"
+        );
+        assert_eq!(cash.witness_program(0, &hash), None);
+
+        assert!(bitcoin.validate().is_ok());
+        assert!(cash.validate().is_ok());
+        assert!(AddressFormat::base58(0x00, 0x05)
+            .with_hrp("b c")
+            .validate()
+            .is_err());
+        let err = AddressFormat { hrp: None, ..cash }.validate().unwrap_err();
+        assert!(matches!(err.kind, OpErrorKind::AddressEncodingError(_)));
+    }
+}
@@ -0,0 +1,212 @@
+/// Detection of ord inscription envelopes (`OP_FALSE OP_IF "ord" ... OP_ENDIF`) in tapscripts
+use bitcoin::blockdata::script::Instruction;
+use bitcoin::opcodes::all;
+use bitcoin::Script;
+
+use crate::blockchain::proto::script::{is_control_block, strip_annex};
+use crate::blockchain::proto::tx::TxInput;
+
+const PROTOCOL_ID: &[u8] = b"ord";
+const TAG_BODY: &[u8] = &[];
+const TAG_CONTENT_TYPE: &[u8] = &[1];
+const TAG_POINTER: &[u8] = &[2];
+const TAG_CONTENT_ENCODING: &[u8] = &[9];
+
+/// Fields of an inscription envelope. Unknown fields are ignored, like duplicates of known ones.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Inscription {
+    pub content_type: Option<Vec<u8>>,
+    pub content_encoding: Option<Vec<u8>>,
+    /// Sat offset into the outputs of the reveal transaction where the inscription is placed
+    pub pointer: Option<u64>,
+    /// Concatenated data pushes following the body tag, None if the envelope has no body
+    pub body: Option<Vec<u8>>,
+}
+
+/// Returns the inscriptions of the tapscript revealed by a script path spend, in envelope order.
+pub fn find_inscriptions(input: &TxInput) -> Vec<Inscription> {
+    match strip_annex(&input.witness) {
+        [.., script, control] if is_control_block(control) => parse_envelopes(script),
+        _ => Vec::new(),
+    }
+}
+
+/// Scans the script for envelopes, up to the first invalid instruction.
+/// Envelopes containing other opcodes than pushes are skipped.
+fn parse_envelopes(script: &[u8]) -> Vec<Inscription> {
+    let mut inscriptions = Vec::new();
+    let mut instructions = Script::from_bytes(script).instructions();
+    // Last two instructions, to recognize OP_FALSE OP_IF
+    let (mut prev2, mut prev1) = (None, None);
+    while let Some(Ok(instruction)) = instructions.next() {
+        if let (Some(Instruction::PushBytes(start)), Some(Instruction::Op(all::OP_IF))) =
+            (prev2, prev1)
+        {
+            if start.is_empty() && push_bytes(&instruction).as_deref() == Some(PROTOCOL_ID) {
+                let mut pushes = Vec::new();
+                let mut valid = true;
+                for instruction in instructions.by_ref() {
+                    match instruction {
+                        Ok(Instruction::Op(all::OP_ENDIF)) => break,
+                        Ok(instruction) => match push_bytes(&instruction) {
+                            Some(data) => pushes.push(data),
+                            None => valid = false,
+                        },
+                        Err(_) => return inscriptions,
+                    }
+                }
+                if valid {
+                    inscriptions.push(from_pushes(pushes));
+                }
+                (prev2, prev1) = (None, None);
+                continue;
+            }
+        }
+        (prev2, prev1) = (prev1, Some(instruction));
+    }
+    inscriptions
+}
+
+/// Data pushed by the instruction, OP_1NEGATE and OP_1 to OP_16 push single bytes
+fn push_bytes(instruction: &Instruction) -> Option<Vec<u8>> {
+    match instruction {
+        Instruction::PushBytes(data) => Some(data.as_bytes().to_vec()),
+        Instruction::Op(op) => match op.to_u8() {
+            0x4f => Some(vec![0x81]),
+            n @ 0x51..=0x60 => Some(vec![n - 0x50]),
+            _ => None,
+        },
+    }
+}
+
+/// Pushes after the protocol id are tag value pairs, the body tag is followed by the content
+fn from_pushes(pushes: Vec<Vec<u8>>) -> Inscription {
+    let mut inscription = Inscription::default();
+    let mut pushes = pushes.into_iter();
+    while let Some(tag) = pushes.next() {
+        if tag == TAG_BODY {
+            inscription.body = Some(pushes.flatten().collect());
+            break;
+        }
+        let value = match pushes.next() {
+            Some(value) => value,
+            None => break,
+        };
+        match tag.as_slice() {
+            TAG_CONTENT_TYPE if inscription.content_type.is_none() => {
+                inscription.content_type = Some(value)
+            }
+            TAG_CONTENT_ENCODING if inscription.content_encoding.is_none() => {
+                inscription.content_encoding = Some(value)
+            }
+            TAG_POINTER if inscription.pointer.is_none() => {
+                inscription.pointer = parse_pointer(&value)
+            }
+            _ => {}
+        }
+    }
+    inscription
+}
+
+/// Little endian u64, trailing zero bytes are allowed. Larger values are ignored.
+fn parse_pointer(value: &[u8]) -> Option<u64> {
+    if value.iter().skip(8).any(|b| *b != 0) {
+        return None;
+    }
+    Some(
+        value
+            .iter()
+            .take(8)
+            .rev()
+            .fold(0, |pointer, b| pointer << 8 | *b as u64),
+    )
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::blockchain::proto::tx::TxOutpoint;
+    use crate::blockchain::proto::varuint::VarUint;
+    use bitcoin::blockdata::script::{Builder, PushBytesBuf};
+    use bitcoin::hashes::{sha256d, Hash};
+
+    fn push(builder: Builder, data: &[u8]) -> Builder {
+        builder.push_slice(PushBytesBuf::try_from(data.to_vec()).unwrap())
+    }
+
+    /// Tapscript with a key check and an envelope of the given pushes
+    pub fn envelope_script(pushes: &[&[u8]]) -> Vec<u8> {
+        let mut builder = push(Builder::new(), &[2; 32])
+            .push_opcode(all::OP_CHECKSIG)
+            .push_opcode(all::OP_PUSHBYTES_0)
+            .push_opcode(all::OP_IF);
+        builder = push(builder, PROTOCOL_ID);
+        for data in pushes {
+            builder = push(builder, data);
+        }
+        builder
+            .push_opcode(all::OP_ENDIF)
+            .into_script()
+            .into_bytes()
+    }
+
+    /// Witness of a script path spend: signature, tapscript and control block
+    pub fn reveal_witness(script: Vec<u8>) -> Vec<Vec<u8>> {
+        let mut control = vec![0xc0];
+        control.extend_from_slice(&[3; 32]);
+        vec![vec![7; 64], script, control]
+    }
+
+    fn input(witness: Vec<Vec<u8>>) -> TxInput {
+        TxInput {
+            outpoint: TxOutpoint::new(sha256d::Hash::all_zeros(), 0),
+            script_len: VarUint::from(0u8),
+            script_sig: Vec::new(),
+            seq_no: 0xffffffff,
+            witness,
+        }
+    }
+
+    #[test]
+    fn test_find_inscriptions() {
+        let script = envelope_script(&[
+            &[1],
+            b"text/plain;charset=utf-8",
+            &[],
+            b"Hello, ",
+            b"world!",
+        ]);
+        assert_eq!(
+            find_inscriptions(&input(reveal_witness(script.clone()))),
+            vec![Inscription {
+                content_type: Some(b"text/plain;charset=utf-8".to_vec()),
+                content_encoding: None,
+                pointer: None,
+                body: Some(b"Hello, world!".to_vec()),
+            }]
+        );
+
+        // Not a script path spend
+        assert!(find_inscriptions(&input(vec![script])).is_empty());
+
+        // Two envelopes, the first with content encoding and pointer but without body
+        let mut script = envelope_script(&[&[9], b"br", &[2], &[0x10, 0x27, 0, 0, 0, 0, 0, 0, 0]]);
+        script.extend(envelope_script(&[&[2], &[5], &[1], b"a", &[1], b"b"]));
+        let inscriptions = parse_envelopes(&script);
+        assert_eq!(inscriptions.len(), 2);
+        assert_eq!(inscriptions[0].content_encoding, Some(b"br".to_vec()));
+        assert_eq!(inscriptions[0].pointer, Some(10000));
+        assert_eq!(inscriptions[0].body, None);
+        assert_eq!(inscriptions[1].pointer, Some(5));
+        assert_eq!(inscriptions[1].content_type, Some(b"a".to_vec()));
+
+        // OP_1 pushes the content type tag, OP_CHECKSIG within the envelope invalidates it
+        let mut script = vec![0x00, 0x63, 0x03, b'o', b'r', b'd', 0x51, 0x01, b'x', 0x68];
+        script.extend_from_slice(&[0x00, 0x63, 0x03, b'o', b'r', b'd', 0xac, 0x68]);
+        let inscriptions = parse_envelopes(&script);
+        assert_eq!(inscriptions.len(), 1);
+        assert_eq!(inscriptions[0].content_type, Some(b"x".to_vec()));
+
+        assert_eq!(parse_pointer(&[1, 0, 0, 0, 0, 0, 0, 0, 1]), None);
+    }
+}
@@ -1,14 +1,23 @@
+pub mod classify;
+pub mod coinbase;
 mod custom;
+pub mod encoder;
+pub mod inscription;
+pub mod pubkey;
+pub mod signature;
+pub mod timelock;
+pub mod witness;
 
 use std::convert::From;
 use std::error::Error;
 use std::fmt;
 
 use crate::blockchain::proto::script::custom::eval_from_bytes_custom;
-use bitcoin::address::Payload;
+use crate::blockchain::proto::script::encoder::AddressEncoder;
+use crate::common::utils;
 use bitcoin::blockdata::script::Instruction;
 use bitcoin::hashes::{hash160, Hash};
-use bitcoin::{address, Address, Network, PubkeyHash, Script};
+use bitcoin::Script;
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub enum ScriptError {
@@ -77,6 +86,25 @@ pub enum ScriptPattern {
     Error(ScriptError),
 }
 
+impl ScriptPattern {
+    /// Short lowercase name as used for command line options
+    pub fn short_name(&self) -> &'static str {
+        match *self {
+            ScriptPattern::OpReturn(_) => "opreturn",
+            ScriptPattern::Pay2MultiSig => "multisig",
+            ScriptPattern::Pay2PublicKey => "p2pk",
+            ScriptPattern::Pay2PublicKeyHash => "p2pkh",
+            ScriptPattern::Pay2ScriptHash => "p2sh",
+            ScriptPattern::Pay2WitnessPublicKeyHash => "p2wpkh",
+            ScriptPattern::Pay2WitnessScriptHash => "p2wsh",
+            ScriptPattern::WitnessProgram => "witness",
+            ScriptPattern::Pay2Taproot => "p2tr",
+            ScriptPattern::Unspendable => "unspendable",
+            ScriptPattern::NotRecognised | ScriptPattern::Error(_) => "nonstandard",
+        }
+    }
+}
+
 impl fmt::Display for ScriptPattern {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
@@ -108,22 +136,21 @@ impl EvaluatedScript {
     }
 }
 
-/// Extracts evaluated address from ScriptPubKey
-pub fn eval_from_bytes(bytes: &[u8], version_id: u8) -> EvaluatedScript {
+/// Extracts evaluated address from ScriptPubKey.
+/// The version id selects the script classifier, the encoder converts the hashes to addresses.
+pub fn eval_from_bytes(
+    bytes: &[u8],
+    version_id: u8,
+    encoder: &dyn AddressEncoder,
+) -> EvaluatedScript {
     match version_id {
-        0x00 | 0x6f => eval_from_bytes_bitcoin(bytes, version_id),
-        _ => eval_from_bytes_custom(bytes, version_id),
+        0x00 | 0x6f => eval_from_bytes_bitcoin(bytes, encoder),
+        _ => eval_from_bytes_custom(bytes, encoder),
     }
 }
 
-/// Extracts evaluated address from script using `rust_bitcoin`
-pub fn eval_from_bytes_bitcoin(bytes: &[u8], version_id: u8) -> EvaluatedScript {
-    let network = match version_id {
-        0x00 => Network::Bitcoin,
-        0x6f => Network::Testnet,
-        _ => panic!("invalid network version"),
-    };
-
+/// Classifies the script using `rust_bitcoin`
+pub fn eval_from_bytes_bitcoin(bytes: &[u8], encoder: &dyn AddressEncoder) -> EvaluatedScript {
     let script = Script::from_bytes(bytes);
 
     // For OP_RETURN and provably unspendable scripts there is no point in parsing the address
@@ -136,41 +163,66 @@ pub fn eval_from_bytes_bitcoin(bytes: &[u8], version_id: u8) -> EvaluatedScript
         return EvaluatedScript::new(None, ScriptPattern::Unspendable);
     }
 
-    let address = match Address::from_script(script, network) {
-        Ok(address) => Some(format!("{}", address)),
-        Err(err) => {
-            if err != address::Error::UnrecognizedScript {
-                warn!(target: "script", "Unable to extract evaluated address: {}", err)
-            }
-            None
-        }
-    };
-
     if script.is_p2pk() {
         EvaluatedScript::new(
-            p2pk_to_string(script, network),
+            p2pk_to_string(script, encoder),
             ScriptPattern::Pay2PublicKey,
         )
     } else if script.is_p2pkh() {
+        // OP_DUP OP_HASH160 <20 bytes> OP_EQUALVERIFY OP_CHECKSIG
+        let address = encoder.pubkey_hash(&bytes[3..23]);
         EvaluatedScript::new(address, ScriptPattern::Pay2PublicKeyHash)
     } else if script.is_p2sh() {
+        // OP_HASH160 <20 bytes> OP_EQUAL
+        let address = encoder.script_hash(&bytes[2..22]);
         EvaluatedScript::new(address, ScriptPattern::Pay2ScriptHash)
-    } else if script.is_v0_p2wpkh() {
-        EvaluatedScript::new(address, ScriptPattern::Pay2WitnessPublicKeyHash)
-    } else if script.is_v0_p2wsh() {
-        EvaluatedScript::new(address, ScriptPattern::Pay2WitnessScriptHash)
-    } else if script.is_v1_p2tr() {
-        EvaluatedScript::new(address, ScriptPattern::Pay2Taproot)
     } else if script.is_witness_program() {
-        EvaluatedScript::new(address, ScriptPattern::WitnessProgram)
+        // <version> <2 to 40 bytes>
+        let address = script
+            .witness_version()
+            .and_then(|version| encoder.witness_program(version.to_num(), &bytes[2..]));
+        let pattern = if script.is_v0_p2wpkh() {
+            ScriptPattern::Pay2WitnessPublicKeyHash
+        } else if script.is_v0_p2wsh() {
+            ScriptPattern::Pay2WitnessScriptHash
+        } else if script.is_v1_p2tr() {
+            ScriptPattern::Pay2Taproot
+        } else {
+            ScriptPattern::WitnessProgram
+        };
+        EvaluatedScript::new(address, pattern)
     } else {
-        EvaluatedScript::new(address, ScriptPattern::NotRecognised)
+        EvaluatedScript::new(None, ScriptPattern::NotRecognised)
     }
 }
 
-/// Workaround to parse address from p2pk scripts
-/// See issue https://github.com/rust-bitcoin/rust-bitcoin/issues/441
-fn p2pk_to_string(script: &Script, network: Network) -> Option<String> {
+/// Compressed or uncompressed SEC1 encoded public key
+pub(crate) fn is_pubkey(item: &[u8]) -> bool {
+    (item.len() == 33 && (item[0] == 0x02 || item[0] == 0x03))
+        || (item.len() == 65 && item[0] == 0x04)
+}
+
+/// DER encoded signature followed by the sighash type
+pub(crate) fn is_signature(item: &[u8]) -> bool {
+    (9..=73).contains(&item.len()) && item[0] == 0x30 && item[1] as usize == item.len() - 3
+}
+
+/// Taproot control block (BIP341): leaf version, internal key and the merkle path
+pub(crate) fn is_control_block(item: &[u8]) -> bool {
+    item.len() >= 33 && (item.len() - 33).is_multiple_of(32) && item[0] & 0xfe == 0xc0
+}
+
+/// Drops the annex (BIP341) from a witness stack if present
+pub(crate) fn strip_annex(witness: &[Vec<u8>]) -> &[Vec<u8>] {
+    if witness.len() >= 2 && witness.last().unwrap().first() == Some(&0x50) {
+        &witness[..witness.len() - 1]
+    } else {
+        witness
+    }
+}
+
+/// Derives the p2pkh address from a p2pk script
+fn p2pk_to_string(script: &Script, encoder: &dyn AddressEncoder) -> Option<String> {
     debug_assert!(script.is_p2pk());
     let pk = match script.instructions().next() {
         Some(Ok(Instruction::PushBytes(bytes))) => bytes,
@@ -180,16 +232,86 @@ fn p2pk_to_string(script: &Script, network: Network) -> Option<String> {
         }
         _ => unreachable!(),
     };
+    encoder.pubkey_hash(hash160::Hash::hash(pk.as_bytes()).as_byte_array())
+}
 
-    let pkh = PubkeyHash::from_raw_hash(hash160::Hash::hash(pk.as_bytes()));
-    let address = Address::new(network, Payload::PubkeyHash(pkh));
-    Some(address.to_string())
+/// Returns the number of data bytes pushed after the OP_RETURN of a data output.
+/// Counting stops at the first invalid push.
+pub fn op_return_payload_len(bytes: &[u8]) -> usize {
+    op_return_pushes(bytes).map(|data| data.len()).sum()
+}
+
+/// Returns the data pushed after the OP_RETURN of a data output, all pushes concatenated.
+/// Stops at the first invalid push.
+pub fn op_return_payload(bytes: &[u8]) -> Vec<u8> {
+    op_return_pushes(bytes).flatten().copied().collect()
+}
+
+fn op_return_pushes(bytes: &[u8]) -> impl Iterator<Item = &[u8]> {
+    Script::from_bytes(bytes)
+        .instructions()
+        .skip(1)
+        .map_while(|instruction| instruction.ok())
+        .filter_map(|instruction| match instruction {
+            Instruction::PushBytes(data) => Some(data.as_bytes()),
+            Instruction::Op(_) => None,
+        })
+}
+
+/// Disassembles a scriptPubKey, scriptSig or witness script in the format of Bitcoin Core's
+/// `asm` fields: pushes of up to 4 bytes as numbers, larger ones as hex and opcodes by name.
+/// An invalid push ends the output with `[error]`.
+pub fn disasm(bytes: &[u8]) -> String {
+    let mut parts = Vec::new();
+    for instruction in Script::from_bytes(bytes).instructions() {
+        parts.push(match instruction {
+            Ok(Instruction::PushBytes(data)) if data.len() <= 4 => {
+                script_num(data.as_bytes()).to_string()
+            }
+            Ok(Instruction::PushBytes(data)) => utils::arr_to_hex(data.as_bytes()),
+            Ok(Instruction::Op(op)) => match op.to_u8() {
+                0x4f => String::from("-1"),
+                n @ 0x51..=0x60 => (n - 0x50).to_string(),
+                0xb1 => String::from("OP_CHECKLOCKTIMEVERIFY"),
+                0xb2 => String::from("OP_CHECKSEQUENCEVERIFY"),
+                _ => op.to_string(),
+            },
+            Err(_) => {
+                parts.push(String::from("[error]"));
+                break;
+            }
+        });
+    }
+    parts.join(" ")
+}
+
+/// Little endian number with the sign in the highest bit, non-minimal encodings are accepted
+pub(crate) fn script_num(data: &[u8]) -> i64 {
+    let mut value = data
+        .iter()
+        .rev()
+        .fold(0i64, |value, byte| value << 8 | *byte as i64);
+    if let Some(last) = data.last() {
+        if last & 0x80 != 0 {
+            value &= !(0x80 << (8 * (data.len() - 1)));
+            value = -value;
+        }
+    }
+    value
 }
 
 #[cfg(test)]
 mod tests {
     use super::ScriptPattern;
-    use crate::blockchain::proto::script::eval_from_bytes_bitcoin;
+    use crate::blockchain::proto::script::encoder::AddressFormat;
+    use crate::blockchain::proto::script::{
+        disasm, eval_from_bytes_bitcoin, op_return_payload, op_return_payload_len,
+    };
+    use crate::common::utils;
+
+    fn bitcoin() -> AddressFormat {
+        AddressFormat::base58(0x00, 0x05).with_hrp("bc")
+    }
 
     #[test]
     fn test_bitcoin_script_p2pkh() {
@@ -199,7 +321,7 @@ mod tests {
             0x76, 0xa9, 0x14, 0x12, 0xab, 0x8d, 0xc5, 0x88, 0xca, 0x9d, 0x57, 0x87, 0xdd, 0xe7,
             0xeb, 0x29, 0x56, 0x9d, 0xa6, 0x3c, 0x3a, 0x23, 0x8c, 0x88, 0xac,
         ];
-        let result = eval_from_bytes_bitcoin(&bytes, 0x00);
+        let result = eval_from_bytes_bitcoin(&bytes, &bitcoin());
         assert_eq!(
             result.address,
             Some(String::from("12higDjoCCNXSA95xZMWUdPvXNmkAduhWv"))
@@ -220,7 +342,7 @@ mod tests {
             0x40, 0x78, 0xb4, 0x8b, 0xa6, 0x7f, 0xa1, 0x98, 0x78, 0x2e, 0x8b, 0xb6, 0x81, 0x15,
             0xda, 0x0d, 0xaa, 0x8f, 0xde, 0x53, 0x01, 0xf7, 0xf9, 0xac,
         ]; // OP_CHECKSIG
-        let result = eval_from_bytes_bitcoin(&bytes, 0x00);
+        let result = eval_from_bytes_bitcoin(&bytes, &bitcoin());
         assert_eq!(
             result.address,
             Some(String::from("1LEWwJkDj8xriE87ALzQYcHjTmD8aqDj1f"))
@@ -247,7 +369,7 @@ mod tests {
             0x84, 0x92, 0x5d, 0xec, 0xd3, 0xfd, 0x21, 0xbc, 0x44, 0x57, 0x12, 0x57, 0x68, 0x73,
             0xfb, 0x8c, 0x6e, 0xbc, 0x18, 0x53, 0xae,
         ];
-        let result = eval_from_bytes_bitcoin(&bytes, 0x00);
+        let result = eval_from_bytes_bitcoin(&bytes, &bitcoin());
         assert_eq!(result.pattern, ScriptPattern::Pay2MultiSig);
     }
     */
@@ -261,7 +383,7 @@ mod tests {
             0xe9, 0xc3, 0xdd, 0x0c, 0x07, 0xaa, 0xc7, 0x61, 0x79, 0xeb, 0xc7, 0x6a, 0x6c, 0x78,
             0xd4, 0xd6, 0x7c, 0x6c, 0x16, 0x0a, 0x87,
         ]; // OP_EQUAL
-        let result = eval_from_bytes_bitcoin(&bytes, 0x00);
+        let result = eval_from_bytes_bitcoin(&bytes, &bitcoin());
         assert_eq!(
             result.address,
             Some(String::from("3P14159f73E4gFr7JterCCQh9QjiTjiZrG"))
@@ -277,12 +399,14 @@ mod tests {
             0x6a, 0x13, 0x63, 0x68, 0x61, 0x72, 0x6c, 0x65, 0x79, 0x20, 0x6c, 0x6f, 0x76, 0x65,
             0x73, 0x20, 0x68, 0x65, 0x69, 0x64, 0x69,
         ];
-        let result = eval_from_bytes_bitcoin(&bytes, 0x00);
+        let result = eval_from_bytes_bitcoin(&bytes, &bitcoin());
         assert_eq!(result.address, None);
         assert_eq!(
             result.pattern,
             ScriptPattern::OpReturn(String::from("charley loves heidi"))
         );
+        assert_eq!(op_return_payload_len(&bytes), 19);
+        assert_eq!(op_return_payload(&bytes), b"charley loves heidi");
     }
 
     #[test]
@@ -290,7 +414,7 @@ mod tests {
         // Raw output script: 736372697074
         //                    OP_IFDUP OP_IF OP_2SWAP OP_VERIFY OP_2OVER OP_DEPTH
         let bytes = [0x73, 0x63, 0x72, 0x69, 0x70, 0x74];
-        let result = eval_from_bytes_bitcoin(&bytes, 0x00);
+        let result = eval_from_bytes_bitcoin(&bytes, &bitcoin());
         assert_eq!(result.address, None);
         assert_eq!(result.pattern, ScriptPattern::NotRecognised);
     }
@@ -298,8 +422,24 @@ mod tests {
     #[test]
     fn test_bitcoin_bogus_script() {
         let bytes = [0x4c, 0xFF, 0x00];
-        let result = eval_from_bytes_bitcoin(&bytes, 0x00);
+        let result = eval_from_bytes_bitcoin(&bytes, &bitcoin());
         assert_eq!(result.address, None);
         assert_eq!(result.pattern, ScriptPattern::NotRecognised);
     }
+
+    #[test]
+    fn test_disasm() {
+        let p2pkh = utils::hex_to_vec("76a91412ab8dc588ca9d5787dde7eb29569da63c3a238c88ac");
+        assert_eq!(
+            disasm(&p2pkh),
+            "OP_DUP OP_HASH160 12ab8dc588ca9d5787dde7eb29569da63c3a238c OP_EQUALVERIFY OP_CHECKSIG"
+        );
+        // Numbers up to 4 bytes, including negative ones, and a CLTV timelock
+        assert_eq!(
+            disasm(&[0x03, 0x40, 0x0d, 0x03, 0xb1, 0x75, 0x00, 0x4f, 0x52, 0x02, 0xff, 0x80]),
+            "200000 OP_CHECKLOCKTIMEVERIFY OP_DROP 0 -1 2 -255"
+        );
+        assert_eq!(disasm(&[0x6a, 0x4c]), "OP_RETURN [error]");
+        assert_eq!(disasm(&[]), "");
+    }
 }
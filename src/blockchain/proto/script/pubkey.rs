@@ -0,0 +1,232 @@
+/// Extraction of public keys which are revealed on-chain, either in outputs or when spent
+use bitcoin::blockdata::script::Instruction;
+use bitcoin::opcodes::all;
+use bitcoin::Script;
+use std::fmt;
+
+use crate::blockchain::proto::script::timelock::revealed_scripts;
+use crate::blockchain::proto::script::{is_control_block, is_pubkey, is_signature, strip_annex};
+use crate::blockchain::proto::tx::TxInput;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PubkeySource {
+    /// Pubkey in a P2PK scriptPubKey
+    Pay2PublicKey,
+    /// Pubkey pushed by the scriptSig of a P2PKH spend
+    Pay2PublicKeyHash,
+    /// Pubkey in the witness of a P2WPKH spend
+    Pay2WitnessPublicKeyHash,
+    /// Pubkeys of a bare multisig output or a revealed multisig redeemScript/witnessScript
+    MultiSig,
+    /// Tweaked x-only output key of a P2TR scriptPubKey
+    Taproot,
+    /// X-only internal key from the control block of a taproot script path spend
+    TaprootInternal,
+}
+
+impl fmt::Display for PubkeySource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let str = match *self {
+            PubkeySource::Pay2PublicKey => "p2pk",
+            PubkeySource::Pay2PublicKeyHash => "p2pkh",
+            PubkeySource::Pay2WitnessPublicKeyHash => "p2wpkh",
+            PubkeySource::MultiSig => "multisig",
+            PubkeySource::Taproot => "p2tr",
+            PubkeySource::TaprootInternal => "p2tr_internal",
+        };
+        write!(f, "{}", str)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RevealedPubkey<'a> {
+    pub source: PubkeySource,
+    pub key: &'a [u8],
+}
+
+impl<'a> RevealedPubkey<'a> {
+    fn new(source: PubkeySource, key: &'a [u8]) -> Self {
+        Self { source, key }
+    }
+}
+
+/// Returns the pubkeys exposed by a scriptPubKey
+pub fn output_pubkeys(script_pubkey: &[u8]) -> Vec<RevealedPubkey<'_>> {
+    let script = Script::from_bytes(script_pubkey);
+    if script.is_p2pk() {
+        // <pubkey> OP_CHECKSIG
+        let len = script_pubkey.len();
+        vec![RevealedPubkey::new(
+            PubkeySource::Pay2PublicKey,
+            &script_pubkey[1..len - 1],
+        )]
+    } else if script.is_v1_p2tr() {
+        // OP_1 <32 byte output key>
+        vec![RevealedPubkey::new(
+            PubkeySource::Taproot,
+            &script_pubkey[2..],
+        )]
+    } else {
+        multisig_pubkeys(script_pubkey)
+    }
+}
+
+/// Returns the pubkeys revealed by spending an input.
+/// The type of the spent output is not known, so it is derived from the shape of scriptSig and witness.
+pub fn input_pubkeys(input: &TxInput) -> Vec<RevealedPubkey<'_>> {
+    let mut pubkeys = Vec::new();
+    // scriptSig: <sig> <pubkey>
+    if let Some([sig, pubkey]) = push_only(&input.script_sig).as_deref() {
+        if is_signature(sig) && is_pubkey(pubkey) {
+            pubkeys.push(RevealedPubkey::new(PubkeySource::Pay2PublicKeyHash, pubkey));
+        }
+    }
+
+    match strip_annex(&input.witness) {
+        // witness: <sig> <pubkey>
+        [sig, pubkey] if is_signature(sig) && is_pubkey(pubkey) => pubkeys.push(
+            RevealedPubkey::new(PubkeySource::Pay2WitnessPublicKeyHash, pubkey),
+        ),
+        // witness: [..] <script> <control block>
+        [.., _, control] if is_control_block(control) => pubkeys.push(RevealedPubkey::new(
+            PubkeySource::TaprootInternal,
+            &control[1..33],
+        )),
+        _ => {}
+    }
+
+    for script in revealed_scripts(input) {
+        pubkeys.extend(multisig_pubkeys(script));
+    }
+    pubkeys
+}
+
+/// Collects all pushed pubkeys if the script contains OP_CHECKMULTISIG(VERIFY)
+fn multisig_pubkeys(bytes: &[u8]) -> Vec<RevealedPubkey<'_>> {
+    let mut pubkeys = Vec::new();
+    let mut is_multisig = false;
+    for instruction in Script::from_bytes(bytes).instructions() {
+        match instruction {
+            Ok(Instruction::PushBytes(data)) if is_pubkey(data.as_bytes()) => {
+                pubkeys.push(RevealedPubkey::new(PubkeySource::MultiSig, data.as_bytes()))
+            }
+            Ok(Instruction::Op(op))
+                if op == all::OP_CHECKMULTISIG || op == all::OP_CHECKMULTISIGVERIFY =>
+            {
+                is_multisig = true
+            }
+            Ok(_) => {}
+            Err(_) => return Vec::new(),
+        }
+    }
+    if is_multisig {
+        pubkeys
+    } else {
+        Vec::new()
+    }
+}
+
+/// Returns all pushes of a push-only script, None otherwise
+pub(crate) fn push_only(script: &[u8]) -> Option<Vec<&[u8]>> {
+    Script::from_bytes(script)
+        .instructions()
+        .map(|instruction| match instruction {
+            Ok(Instruction::PushBytes(data)) => Some(data.as_bytes()),
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::proto::tx::TxOutpoint;
+    use crate::blockchain::proto::varuint::VarUint;
+    use bitcoin::hashes::{sha256d, Hash};
+
+    fn input(script_sig: Vec<u8>, witness: Vec<Vec<u8>>) -> TxInput {
+        TxInput {
+            outpoint: TxOutpoint::new(sha256d::Hash::all_zeros(), 0),
+            script_len: VarUint::from(script_sig.len() as u8),
+            script_sig,
+            seq_no: 0xffffffff,
+            witness,
+        }
+    }
+
+    fn signature() -> Vec<u8> {
+        let mut sig = vec![0x30, 0x44];
+        sig.extend([0x02; 69]);
+        sig
+    }
+
+    #[test]
+    fn test_output_pubkeys() {
+        let mut p2pk = vec![0x21];
+        p2pk.extend([0x02; 33]);
+        p2pk.push(0xac);
+        assert_eq!(
+            output_pubkeys(&p2pk),
+            vec![RevealedPubkey::new(
+                PubkeySource::Pay2PublicKey,
+                &[0x02; 33]
+            )]
+        );
+
+        let mut p2tr = vec![0x51, 0x20];
+        p2tr.extend([0xaa; 32]);
+        assert_eq!(
+            output_pubkeys(&p2tr),
+            vec![RevealedPubkey::new(PubkeySource::Taproot, &[0xaa; 32])]
+        );
+
+        // OP_DUP OP_HASH160 <pkh> OP_EQUALVERIFY OP_CHECKSIG
+        let mut p2pkh = vec![0x76, 0xa9, 0x14];
+        p2pkh.extend([0x00; 20]);
+        p2pkh.extend([0x88, 0xac]);
+        assert!(output_pubkeys(&p2pkh).is_empty());
+    }
+
+    #[test]
+    fn test_input_pubkeys_multisig_and_taproot() {
+        // OP_1 <pubkey> <pubkey> OP_2 OP_CHECKMULTISIG
+        let mut redeem_script = vec![0x51, 0x21];
+        redeem_script.extend([0x02; 33]);
+        redeem_script.push(0x21);
+        redeem_script.extend([0x03; 33]);
+        redeem_script.extend([0x52, 0xae]);
+        // OP_0 <sig> <redeemScript>
+        let sig = signature();
+        let mut script_sig = vec![0x00, sig.len() as u8];
+        script_sig.extend(&sig);
+        script_sig.extend([0x4c, redeem_script.len() as u8]);
+        script_sig.extend(&redeem_script);
+        let p2sh_input = input(script_sig, vec![]);
+        let keys = input_pubkeys(&p2sh_input);
+        assert_eq!(
+            keys,
+            vec![
+                RevealedPubkey::new(PubkeySource::MultiSig, &[0x02; 33]),
+                RevealedPubkey::new(PubkeySource::MultiSig, &[0x03; 33]),
+            ]
+        );
+
+        // Script path spend: <sig> <script> <control block>
+        let mut control = vec![0xc0];
+        control.extend([0xbb; 32]);
+        let tapscript = vec![0x51];
+        let tapscript_input = input(vec![], vec![vec![0x01; 64], tapscript, control]);
+        let keys = input_pubkeys(&tapscript_input);
+        assert_eq!(
+            keys,
+            vec![RevealedPubkey::new(
+                PubkeySource::TaprootInternal,
+                &[0xbb; 32]
+            )]
+        );
+
+        let p2wpkh_input = input(vec![], vec![sig, vec![0x03; 33]]);
+        let keys = input_pubkeys(&p2wpkh_input);
+        assert_eq!(keys[0].source, PubkeySource::Pay2WitnessPublicKeyHash);
+    }
+}
@@ -0,0 +1,189 @@
+/// Classification of the ECDSA signatures pushed by spending inputs
+use bitcoin::blockdata::script::Instruction;
+use bitcoin::Script;
+
+use crate::blockchain::proto::script::{is_signature, strip_annex};
+use crate::blockchain::proto::tx::TxInput;
+
+/// Half of the order of secp256k1, signatures with a larger S are malleable (BIP62/BIP146)
+const HALF_ORDER: [u8; 32] = [
+    0x7f, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+    0x5d, 0x57, 0x6e, 0x73, 0x57, 0xa4, 0x50, 0x1d, 0xdf, 0xe9, 0x2f, 0x46, 0x68, 0x1b, 0x20, 0xa0,
+];
+
+pub const SIGHASH_ALL: u8 = 0x01;
+pub const SIGHASH_NONE: u8 = 0x02;
+pub const SIGHASH_SINGLE: u8 = 0x03;
+pub const SIGHASH_ANYONECANPAY: u8 = 0x80;
+
+/// DER encoded ECDSA signature followed by the sighash type
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EcdsaSignature<'a> {
+    pub r: &'a [u8],
+    pub s: &'a [u8],
+    pub sighash: u8,
+    /// Encoding passes the strict DER checks of BIP66
+    pub strict_der: bool,
+}
+
+impl<'a> EcdsaSignature<'a> {
+    /// Extracts R and S from `0x30 len 0x02 rlen R 0x02 slen S sighash`.
+    /// Returns None if the lengths don't add up.
+    pub fn parse(item: &'a [u8]) -> Option<Self> {
+        if !is_signature(item) {
+            return None;
+        }
+        let der = &item[..item.len() - 1];
+        let (r, rest) = der_integer(&der[2..])?;
+        let (s, rest) = der_integer(rest)?;
+        if !rest.is_empty() {
+            return None;
+        }
+        Some(Self {
+            r,
+            s,
+            sighash: item[item.len() - 1],
+            strict_der: is_strict_der(item),
+        })
+    }
+
+    /// S is above half the curve order
+    pub fn is_high_s(&self) -> bool {
+        match to_u256(self.s) {
+            Some(s) => s > HALF_ORDER,
+            None => true,
+        }
+    }
+
+    /// R fits into 32 bytes of DER without padding, i.e. its highest bit is unset.
+    /// Bitcoin Core grinds for such signatures since 0.17 to save a byte.
+    pub fn is_low_r(&self) -> bool {
+        matches!(to_u256(self.r), Some(r) if r[0] < 0x80)
+    }
+
+    /// Sighash type without the ANYONECANPAY flag
+    pub fn base_type(&self) -> u8 {
+        self.sighash & !SIGHASH_ANYONECANPAY
+    }
+
+    pub fn is_anyonecanpay(&self) -> bool {
+        self.sighash & SIGHASH_ANYONECANPAY != 0
+    }
+}
+
+/// Returns the ECDSA signatures pushed by the scriptSig and the witness of an input.
+/// The spent output is not known, so every push which looks like a signature is returned.
+pub fn input_signatures(input: &TxInput) -> Vec<EcdsaSignature<'_>> {
+    let mut signatures = Vec::new();
+    for instruction in Script::from_bytes(&input.script_sig).instructions() {
+        match instruction {
+            Ok(Instruction::PushBytes(data)) => {
+                signatures.extend(EcdsaSignature::parse(data.as_bytes()))
+            }
+            Ok(Instruction::Op(_)) => {}
+            Err(_) => break,
+        }
+    }
+    for item in strip_annex(&input.witness) {
+        signatures.extend(EcdsaSignature::parse(item));
+    }
+    signatures
+}
+
+/// Splits `0x02 len value` from the front of the data
+fn der_integer(data: &[u8]) -> Option<(&[u8], &[u8])> {
+    match data {
+        [0x02, len, rest @ ..] if *len as usize <= rest.len() => Some(rest.split_at(*len as usize)),
+        _ => None,
+    }
+}
+
+/// Converts a big endian integer into 32 bytes, None if it is too large
+fn to_u256(value: &[u8]) -> Option<[u8; 32]> {
+    let start = value.iter().position(|b| *b != 0).unwrap_or(value.len());
+    let value = &value[start..];
+    if value.len() > 32 {
+        return None;
+    }
+    let mut bytes = [0u8; 32];
+    bytes[32 - value.len()..].copy_from_slice(value);
+    Some(bytes)
+}
+
+/// IsValidSignatureEncoding() of BIP66, the item includes the sighash byte
+fn is_strict_der(sig: &[u8]) -> bool {
+    if sig.len() < 9 || sig.len() > 73 || sig[0] != 0x30 || sig[1] as usize != sig.len() - 3 {
+        return false;
+    }
+    let len_r = sig[3] as usize;
+    if 5 + len_r >= sig.len() {
+        return false;
+    }
+    let len_s = sig[5 + len_r] as usize;
+    if len_r + len_s + 7 != sig.len() {
+        return false;
+    }
+    let integer_ok = |start: usize, len: usize| {
+        sig[start - 2] == 0x02
+            && len != 0
+            && sig[start] & 0x80 == 0
+            && !(len > 1 && sig[start] == 0x00 && sig[start + 1] & 0x80 == 0)
+    };
+    integer_ok(4, len_r) && integer_ok(6 + len_r, len_s)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds a signature from the DER integers R and S
+    fn signature(r: &[u8], s: &[u8], sighash: u8) -> Vec<u8> {
+        let mut sig = vec![0x30, (r.len() + s.len() + 4) as u8, 0x02, r.len() as u8];
+        sig.extend_from_slice(r);
+        sig.extend([0x02, s.len() as u8]);
+        sig.extend_from_slice(s);
+        sig.push(sighash);
+        sig
+    }
+
+    #[test]
+    fn test_classification() {
+        let sig = signature(&[0x11; 32], &[0x22; 32], SIGHASH_ALL);
+        let parsed = EcdsaSignature::parse(&sig).unwrap();
+        assert_eq!(parsed.r, &[0x11; 32]);
+        assert!(parsed.strict_der && parsed.is_low_r() && !parsed.is_high_s());
+        assert_eq!(parsed.base_type(), SIGHASH_ALL);
+        assert!(!parsed.is_anyonecanpay());
+
+        // The high bit of R needs a padding byte
+        let mut r = vec![0x00];
+        r.extend([0x80; 32]);
+        let mut s = vec![0x7f];
+        s.extend([0xff; 31]);
+        let sig = signature(&r, &s, SIGHASH_SINGLE | SIGHASH_ANYONECANPAY);
+        let parsed = EcdsaSignature::parse(&sig).unwrap();
+        assert!(parsed.strict_der && !parsed.is_low_r() && parsed.is_high_s());
+        assert_eq!(parsed.base_type(), SIGHASH_SINGLE);
+        assert!(parsed.is_anyonecanpay());
+
+        // HALF_ORDER itself is still low, one more is high
+        let parsed = signature(&[0x11; 32], &HALF_ORDER, SIGHASH_ALL);
+        assert!(!EcdsaSignature::parse(&parsed).unwrap().is_high_s());
+        let mut s = HALF_ORDER;
+        s[31] += 1;
+        let parsed = signature(&[0x11; 32], &s, SIGHASH_ALL);
+        assert!(EcdsaSignature::parse(&parsed).unwrap().is_high_s());
+
+        // Unnecessary padding of R violates BIP66
+        let mut r = vec![0x00];
+        r.extend([0x11; 32]);
+        let sig = signature(&r, &[0x22; 32], SIGHASH_ALL);
+        let parsed = EcdsaSignature::parse(&sig).unwrap();
+        assert!(!parsed.strict_der && parsed.is_low_r());
+
+        // Inconsistent integer lengths
+        let mut sig = signature(&[0x11; 32], &[0x22; 32], SIGHASH_ALL);
+        sig[3] = 0x30;
+        assert!(EcdsaSignature::parse(&sig).is_none());
+    }
+}
@@ -0,0 +1,187 @@
+/// Detection of script level timelocks (BIP65 OP_CHECKLOCKTIMEVERIFY, BIP112 OP_CHECKSEQUENCEVERIFY)
+use bitcoin::blockdata::script::{read_scriptint, Instruction};
+use bitcoin::opcodes::all;
+use bitcoin::Script;
+use std::fmt;
+
+use crate::blockchain::proto::script::{is_control_block, is_pubkey, is_signature, strip_annex};
+use crate::blockchain::proto::tx::TxInput;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TimelockKind {
+    /// OP_CHECKLOCKTIMEVERIFY, compared against nLockTime
+    Absolute,
+    /// OP_CHECKSEQUENCEVERIFY, compared against nSequence
+    Relative,
+}
+
+impl fmt::Display for TimelockKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TimelockKind::Absolute => write!(f, "OP_CHECKLOCKTIMEVERIFY"),
+            TimelockKind::Relative => write!(f, "OP_CHECKSEQUENCEVERIFY"),
+        }
+    }
+}
+
+/// A timelock opcode found in a script
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Timelock {
+    pub kind: TimelockKind,
+    /// The number pushed right before the opcode, None if it is computed at runtime
+    pub value: Option<i64>,
+}
+
+/// Disassembles the script and collects all timelock opcodes.
+/// Unparseable scripts are scanned up to the first invalid instruction.
+pub fn find_timelocks(bytes: &[u8]) -> Vec<Timelock> {
+    let mut timelocks = Vec::new();
+    let mut last_num = None;
+    for instruction in Script::from_bytes(bytes).instructions() {
+        let num = match instruction {
+            Ok(Instruction::PushBytes(data)) => read_scriptint(data.as_bytes()).ok(),
+            Ok(Instruction::Op(op)) if op == all::OP_CLTV || op == all::OP_CSV => {
+                let kind = if op == all::OP_CLTV {
+                    TimelockKind::Absolute
+                } else {
+                    TimelockKind::Relative
+                };
+                timelocks.push(Timelock {
+                    kind,
+                    value: last_num,
+                });
+                None
+            }
+            Ok(Instruction::Op(op)) => match op.to_u8() {
+                0x4f => Some(-1),
+                n @ 0x51..=0x60 => Some(n as i64 - 0x50),
+                _ => None,
+            },
+            Err(_) => break,
+        };
+        last_num = num;
+    }
+    timelocks
+}
+
+/// Returns the scripts revealed by an input: the redeemScript of a P2SH spend
+/// (last push of the scriptSig) and the witnessScript of a P2WSH or tapscript spend.
+pub fn revealed_scripts(input: &TxInput) -> Vec<&[u8]> {
+    let mut scripts = Vec::new();
+    if let Some(redeem_script) = last_push(&input.script_sig) {
+        scripts.push(redeem_script);
+    }
+
+    let witness = strip_annex(&input.witness);
+    match witness {
+        // Tapscript: [.., script, control block]
+        [.., script, control] if is_control_block(control) => scripts.push(script),
+        // P2WSH: [.., witnessScript]
+        [.., script] if witness.len() >= 2 && is_script_candidate(script) => scripts.push(script),
+        _ => {}
+    }
+    scripts
+}
+
+fn last_push(script_sig: &[u8]) -> Option<&[u8]> {
+    let mut last = None;
+    for instruction in Script::from_bytes(script_sig).instructions() {
+        match instruction {
+            Ok(Instruction::PushBytes(data)) => last = Some(data.as_bytes()),
+            // Only push-only scriptSigs can reveal a redeemScript
+            _ => return None,
+        }
+    }
+    last.filter(|data| is_script_candidate(data))
+}
+
+/// Signatures and public keys are pushed by regular key spends (P2PK, P2PKH, P2WPKH)
+/// and would be misinterpreted as scripts
+fn is_script_candidate(item: &[u8]) -> bool {
+    !item.is_empty() && !is_pubkey(item) && !is_signature(item)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::proto::tx::TxOutpoint;
+    use crate::blockchain::proto::varuint::VarUint;
+    use bitcoin::hashes::{sha256d, Hash};
+
+    /// <expiry> OP_CHECKLOCKTIMEVERIFY OP_DROP <pubkey> OP_CHECKSIG
+    fn cltv_redeem_script(expiry: &[u8]) -> Vec<u8> {
+        let mut script = vec![expiry.len() as u8];
+        script.extend(expiry);
+        script.extend([0xb1, 0x75, 0x21]);
+        script.extend([0x02; 33]);
+        script.push(0xac);
+        script
+    }
+
+    fn input(script_sig: Vec<u8>, witness: Vec<Vec<u8>>) -> TxInput {
+        TxInput {
+            outpoint: TxOutpoint::new(sha256d::Hash::all_zeros(), 0),
+            script_len: VarUint::from(script_sig.len() as u8),
+            script_sig,
+            seq_no: 0xfffffffe,
+            witness,
+        }
+    }
+
+    #[test]
+    fn test_find_timelocks() {
+        // Height 500000 as script number: 0x07a120
+        let script = cltv_redeem_script(&[0x20, 0xa1, 0x07]);
+        assert_eq!(
+            find_timelocks(&script),
+            vec![Timelock {
+                kind: TimelockKind::Absolute,
+                value: Some(500000)
+            }]
+        );
+        // OP_16 OP_CHECKSEQUENCEVERIFY
+        assert_eq!(
+            find_timelocks(&[0x60, 0xb2]),
+            vec![Timelock {
+                kind: TimelockKind::Relative,
+                value: Some(16)
+            }]
+        );
+        // OP_DUP OP_CHECKLOCKTIMEVERIFY, value not known statically
+        assert_eq!(find_timelocks(&[0x76, 0xb1])[0].value, None);
+        assert!(find_timelocks(&[0x76, 0xa9]).is_empty());
+    }
+
+    #[test]
+    fn test_cltv_redeem_script_spend() {
+        let redeem_script = cltv_redeem_script(&[0x20, 0xa1, 0x07]);
+        // scriptSig: <sig> <redeemScript>
+        let mut script_sig = vec![0x47];
+        script_sig.extend([0x30; 71]);
+        script_sig.push(redeem_script.len() as u8);
+        script_sig.extend(&redeem_script);
+
+        let p2sh_input = input(script_sig, vec![]);
+        let scripts = revealed_scripts(&p2sh_input);
+        assert_eq!(scripts, vec![redeem_script.as_slice()]);
+        let timelocks = find_timelocks(scripts[0]);
+        assert_eq!(timelocks.len(), 1);
+        assert_eq!(timelocks[0].kind, TimelockKind::Absolute);
+        assert_eq!(timelocks[0].value, Some(500000));
+
+        let p2wsh_input = input(vec![], vec![vec![0x30; 71], redeem_script.clone()]);
+        assert_eq!(
+            revealed_scripts(&p2wsh_input),
+            vec![redeem_script.as_slice()]
+        );
+
+        // P2WPKH spends don't reveal any script
+        let mut sig = vec![0x30, 0x44];
+        sig.extend([0x02; 69]);
+        let p2wpkh_input = input(vec![], vec![sig.clone(), vec![0x02; 33]]);
+        assert!(revealed_scripts(&p2wpkh_input).is_empty());
+        let mut p2pk_script_sig = vec![sig.len() as u8];
+        p2pk_script_sig.extend(&sig);
+        assert!(revealed_scripts(&input(p2pk_script_sig, vec![])).is_empty());
+    }
+}
@@ -0,0 +1,122 @@
+/// Classification of segwit spends by the shape of their witness stack
+use std::fmt;
+
+use crate::blockchain::proto::script::{is_control_block, is_signature, strip_annex};
+use crate::blockchain::proto::tx::TxInput;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum WitnessSpendType {
+    /// <signature> <compressed pubkey>
+    Pay2WitnessPublicKeyHash,
+    /// P2WPKH wrapped into P2SH, the scriptSig pushes the witness program
+    Pay2ScriptHashWitnessPublicKeyHash,
+    /// <items..> <witnessScript>
+    Pay2WitnessScriptHash,
+    /// P2WSH wrapped into P2SH
+    Pay2ScriptHashWitnessScriptHash,
+    /// A single schnorr signature
+    TaprootKeyPath,
+    /// <items..> <tapscript> <control block>
+    TaprootScriptPath,
+}
+
+impl fmt::Display for WitnessSpendType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let str = match *self {
+            WitnessSpendType::Pay2WitnessPublicKeyHash => "p2wpkh",
+            WitnessSpendType::Pay2ScriptHashWitnessPublicKeyHash => "p2sh_p2wpkh",
+            WitnessSpendType::Pay2WitnessScriptHash => "p2wsh",
+            WitnessSpendType::Pay2ScriptHashWitnessScriptHash => "p2sh_p2wsh",
+            WitnessSpendType::TaprootKeyPath => "p2tr_keypath",
+            WitnessSpendType::TaprootScriptPath => "p2tr_scriptpath",
+        };
+        write!(f, "{}", str)
+    }
+}
+
+/// Infers the type of the spent output from the witness stack, None for inputs without witness.
+/// The spent output is not known, so e.g. a P2WSH spend of a single 64 byte item
+/// is reported as taproot key path spend.
+pub fn spend_type(input: &TxInput) -> Option<WitnessSpendType> {
+    if input.witness.is_empty() {
+        return None;
+    }
+    let p2wpkh = matches!(input.witness.as_slice(), [sig, pubkey]
+        if is_signature(sig) && pubkey.len() == 33 && (pubkey[0] == 0x02 || pubkey[0] == 0x03));
+    // Only native witness programs can be taproot outputs
+    if !input.script_sig.is_empty() {
+        return Some(match p2wpkh {
+            true => WitnessSpendType::Pay2ScriptHashWitnessPublicKeyHash,
+            false => WitnessSpendType::Pay2ScriptHashWitnessScriptHash,
+        });
+    }
+    Some(match strip_annex(&input.witness) {
+        [sig] if sig.len() == 64 || sig.len() == 65 => WitnessSpendType::TaprootKeyPath,
+        [.., _, control] if is_control_block(control) => WitnessSpendType::TaprootScriptPath,
+        _ if p2wpkh => WitnessSpendType::Pay2WitnessPublicKeyHash,
+        _ => WitnessSpendType::Pay2WitnessScriptHash,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::proto::tx::TxOutpoint;
+    use crate::blockchain::proto::varuint::VarUint;
+    use bitcoin::hashes::{sha256d, Hash};
+
+    fn input(script_sig: Vec<u8>, witness: Vec<Vec<u8>>) -> TxInput {
+        TxInput {
+            outpoint: TxOutpoint::new(sha256d::Hash::all_zeros(), 0),
+            script_len: VarUint::from(script_sig.len() as u8),
+            script_sig,
+            seq_no: 0xffffffff,
+            witness,
+        }
+    }
+
+    #[test]
+    fn test_spend_type() {
+        let mut sig = vec![0x30, 0x44];
+        sig.extend([1; 0x44]);
+        sig.push(0x01);
+        let mut pubkey = vec![0x02];
+        pubkey.extend([2; 32]);
+        let mut control = vec![0xc1];
+        control.extend([3; 64]);
+        let p2wpkh = vec![sig.clone(), pubkey.clone()];
+        let nested = vec![0x16, 0x00, 0x14];
+
+        assert_eq!(spend_type(&input(Vec::new(), Vec::new())), None);
+        assert_eq!(
+            spend_type(&input(Vec::new(), p2wpkh.clone())),
+            Some(WitnessSpendType::Pay2WitnessPublicKeyHash)
+        );
+        assert_eq!(
+            spend_type(&input(nested.clone(), p2wpkh)),
+            Some(WitnessSpendType::Pay2ScriptHashWitnessPublicKeyHash)
+        );
+        let p2wsh = vec![Vec::new(), sig.clone(), vec![0x51, 0xae]];
+        assert_eq!(
+            spend_type(&input(Vec::new(), p2wsh.clone())),
+            Some(WitnessSpendType::Pay2WitnessScriptHash)
+        );
+        assert_eq!(
+            spend_type(&input(nested, p2wsh)),
+            Some(WitnessSpendType::Pay2ScriptHashWitnessScriptHash)
+        );
+        // Key path spends with and without annex
+        assert_eq!(
+            spend_type(&input(Vec::new(), vec![vec![4; 64]])),
+            Some(WitnessSpendType::TaprootKeyPath)
+        );
+        assert_eq!(
+            spend_type(&input(Vec::new(), vec![vec![4; 65], vec![0x50, 1]])),
+            Some(WitnessSpendType::TaprootKeyPath)
+        );
+        assert_eq!(
+            spend_type(&input(Vec::new(), vec![vec![4; 64], vec![0x51], control])),
+            Some(WitnessSpendType::TaprootScriptPath)
+        );
+    }
+}
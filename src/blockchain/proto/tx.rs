@@ -1,8 +1,10 @@
 use bitcoin::hashes::{sha256d, Hash};
 use rayon::iter::{IntoParallelIterator, ParallelIterator};
 use std::fmt;
+use std::sync::Arc;
 
 use crate::blockchain::proto::script;
+use crate::blockchain::proto::script::encoder::AddressEncoder;
 use crate::blockchain::proto::varuint::VarUint;
 use crate::blockchain::proto::ToRaw;
 use crate::common::utils;
@@ -15,6 +17,7 @@ pub struct RawTx {
     pub outputs: Vec<TxOutput>,
     pub locktime: u32,
     pub version_id: u8,
+    pub encoder: Arc<dyn AddressEncoder>,
 }
 
 /// Simple transaction struct
@@ -29,6 +32,7 @@ pub struct EvaluatedTx {
 }
 
 impl EvaluatedTx {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         version: u32,
         in_count: VarUint,
@@ -37,11 +41,12 @@ impl EvaluatedTx {
         outputs: Vec<TxOutput>,
         locktime: u32,
         version_id: u8,
+        encoder: &dyn AddressEncoder,
     ) -> Self {
         // Evaluate and wrap all outputs to process them later
         let outputs = outputs
             .into_par_iter()
-            .map(|o| EvaluatedTxOut::eval_script(o, version_id))
+            .map(|o| EvaluatedTxOut::eval_script(o, version_id, encoder))
             .collect();
         EvaluatedTx {
             version,
@@ -53,6 +58,8 @@ impl EvaluatedTx {
         }
     }
 
+    /// A coinbase has a single input spending the null outpoint. Consensus only allows it
+    /// as the first transaction of a block, so this is sufficient to identify it.
     pub fn is_coinbase(&self) -> bool {
         if self.in_count.value == 1 {
             let input = self.inputs.first().unwrap();
@@ -60,6 +67,44 @@ impl EvaluatedTx {
         }
         false
     }
+
+    /// Serialized size in bytes, including the witness data
+    pub fn size(&self) -> u64 {
+        self.to_bytes().len() as u64 + self.witness_size()
+    }
+
+    /// Transaction weight according to BIP141, witness bytes are discounted by factor 4
+    pub fn weight(&self) -> u64 {
+        self.to_bytes().len() as u64 * 4 + self.witness_size()
+    }
+
+    /// Size of the segwit marker, flag and all witnesses, 0 for legacy transactions
+    fn witness_size(&self) -> u64 {
+        let mut witness_size = 0;
+        if self.inputs.iter().any(|input| !input.witness.is_empty()) {
+            // segwit marker and flag
+            witness_size += 2;
+            for input in &self.inputs {
+                witness_size += input.witness_size();
+            }
+        }
+        witness_size
+    }
+
+    /// Virtual transaction size in vbytes
+    pub fn vsize(&self) -> u64 {
+        self.weight().div_ceil(4)
+    }
+}
+
+/// Length of the shortest CompactSize encoding of n
+fn compact_size_len(n: u64) -> u64 {
+    match n {
+        0..=0xfc => 1,
+        0xfd..=0xffff => 3,
+        0x10000..=0xffffffff => 5,
+        _ => 9,
+    }
 }
 
 impl fmt::Debug for EvaluatedTx {
@@ -83,6 +128,7 @@ impl From<RawTx> for EvaluatedTx {
             tx.outputs,
             tx.locktime,
             tx.version_id,
+            tx.encoder.as_ref(),
         )
     }
 }
@@ -110,6 +156,9 @@ impl ToRaw for EvaluatedTx {
     }
 }
 
+/// Bytes of a serialized `TxOutpoint`, txid and index
+pub type OutpointKey = [u8; 36];
+
 /// TxOutpoint references an existing transaction output
 #[derive(PartialEq, Eq, Hash)]
 pub struct TxOutpoint {
@@ -121,6 +170,14 @@ impl TxOutpoint {
     pub fn new(txid: sha256d::Hash, index: u32) -> Self {
         Self { txid, index }
     }
+
+    /// Same bytes as `to_bytes()` without allocating, used as key of the unspent outputs
+    pub fn key(&self) -> OutpointKey {
+        let mut key = [0u8; 36];
+        key[..32].copy_from_slice(self.txid.as_byte_array());
+        key[32..].copy_from_slice(&self.index.to_le_bytes());
+        key
+    }
 }
 
 impl ToRaw for TxOutpoint {
@@ -147,6 +204,20 @@ pub struct TxInput {
     pub script_len: VarUint,
     pub script_sig: Vec<u8>,
     pub seq_no: u32,
+    /// Witness stack items, empty for non-segwit inputs.
+    /// Not part of the legacy serialization, so `to_bytes()` still yields the txid preimage.
+    pub witness: Vec<Vec<u8>>,
+}
+
+impl TxInput {
+    /// Serialized size of the witness stack, including the item count and lengths
+    pub fn witness_size(&self) -> u64 {
+        let mut size = compact_size_len(self.witness.len() as u64);
+        for item in &self.witness {
+            size += compact_size_len(item.len() as u64) + item.len() as u64;
+        }
+        size
+    }
 }
 
 impl ToRaw for TxInput {
@@ -167,6 +238,7 @@ impl fmt::Debug for TxInput {
             .field("script_len", &self.script_len)
             .field("script_sig", &self.script_sig)
             .field("seq_no", &self.seq_no)
+            .field("witness_items", &self.witness.len())
             .finish()
     }
 }
@@ -178,9 +250,13 @@ pub struct EvaluatedTxOut {
 }
 
 impl EvaluatedTxOut {
-    pub fn eval_script(out: TxOutput, version_id: u8) -> EvaluatedTxOut {
+    pub fn eval_script(
+        out: TxOutput,
+        version_id: u8,
+        encoder: &dyn AddressEncoder,
+    ) -> EvaluatedTxOut {
         EvaluatedTxOut {
-            script: script::eval_from_bytes(&out.script_pubkey, version_id),
+            script: script::eval_from_bytes(&out.script_pubkey, version_id, encoder),
             out,
         }
     }
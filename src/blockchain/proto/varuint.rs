@@ -30,6 +30,16 @@ impl VarUint {
         };
         Ok(vint)
     }
+
+    /// Returns the shortest encoding of the value, which consensus serialization requires
+    pub fn minimal(value: u64) -> VarUint {
+        match value {
+            0..=0xfc => VarUint::from(value as u8),
+            0xfd..=0xffff => VarUint::from(value as u16),
+            0x10000..=0xffffffff => VarUint::from(value as u32),
+            _ => VarUint::from(value),
+        }
+    }
 }
 
 impl From<u8> for VarUint {
@@ -128,7 +138,7 @@ mod tests {
 
         let test = VarUint::from(v);
         assert_eq!(9000000000000000000, test.value);
-        assert_eq!(v, test.value as u64);
+        assert_eq!(v, test.value);
         assert_eq!(9, test.to_bytes().len());
         assert_eq!(
             vec![0xff, 0x00, 0x00, 0x84, 0xe2, 0x50, 0x6c, 0xe6, 0x7c],
@@ -136,6 +146,14 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_varuint_minimal() {
+        assert_eq!(VarUint::minimal(0xfc).to_bytes(), vec![0xfc]);
+        assert_eq!(VarUint::minimal(0xfd).to_bytes(), vec![0xfd, 0xfd, 0x00]);
+        assert_eq!(VarUint::minimal(0x10000).to_bytes().len(), 5);
+        assert_eq!(VarUint::minimal(1 << 32).to_bytes().len(), 9);
+    }
+
     #[test]
     fn test_varuint_read() {
         let mut cursor = io::Cursor::new([0xfe, 0x55, 0xa1, 0xae, 0xc6]);
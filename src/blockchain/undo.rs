@@ -0,0 +1,238 @@
+//! Undo data which Bitcoin Core writes to the rev*.dat files next to the blk files.
+//! For each transaction except the coinbase it holds the outputs spent by its inputs,
+//! which are required to disconnect the block again, see `CBlockUndo` in undo.h.
+use bitcoin::hashes::{sha256d, Hash};
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use byteorder::{LittleEndian, ReadBytesExt};
+
+use crate::blockchain::parser::reader::BlockchainRead;
+use crate::blockchain::parser::snapshot::{
+    compress_amount, decompress_amount, read_compressed_script, write_compressed_script,
+    write_core_varint,
+};
+use crate::blockchain::proto::block::Block;
+use crate::blockchain::proto::varuint::VarUint;
+use crate::blockchain::proto::ToRaw;
+use crate::errors::{OpError, OpErrorKind, OpResult};
+
+/// Output spent by a transaction input, along with the height and coinbase flag of its transaction
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SpentOutput {
+    pub height: u64,
+    pub is_coinbase: bool,
+    pub value: u64,
+    pub script_pubkey: Vec<u8>,
+}
+
+/// Outputs spent by a block, ordered like its transactions and inputs
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BlockUndo {
+    /// One entry per transaction, the coinbase is omitted
+    pub txs: Vec<Vec<SpentOutput>>,
+}
+
+impl BlockUndo {
+    pub fn read_from<R: Read + ?Sized>(reader: &mut R) -> OpResult<Self> {
+        let tx_count = VarUint::read_from(reader)?.value;
+        let mut txs = Vec::with_capacity(tx_count.min(100000) as usize);
+        for _ in 0..tx_count {
+            let input_count = VarUint::read_from(reader)?.value;
+            let mut spent = Vec::with_capacity(input_count.min(100000) as usize);
+            for _ in 0..input_count {
+                let code = reader.read_core_varint()?;
+                let height = code >> 1;
+                // Versions before 0.15 stored the version of the spent transaction, which is unused
+                if height > 0 {
+                    reader.read_core_varint()?;
+                }
+                spent.push(SpentOutput {
+                    height,
+                    is_coinbase: code & 1 == 1,
+                    value: decompress_amount(reader.read_core_varint()?),
+                    script_pubkey: read_compressed_script(reader)?,
+                });
+            }
+            txs.push(spent);
+        }
+        Ok(BlockUndo { txs })
+    }
+
+    /// Returns the outputs spent by the transaction at the given index of the block, None for the coinbase
+    pub fn spent_outputs(&self, tx_index: usize) -> Option<&[SpentOutput]> {
+        tx_index
+            .checked_sub(1)
+            .and_then(|i| self.txs.get(i))
+            .map(Vec::as_slice)
+    }
+
+    /// Returns the total value spent by the transaction at the given index of the block
+    pub fn spent_value(&self, tx_index: usize) -> Option<u64> {
+        self.spent_outputs(tx_index)
+            .map(|spent| spent.iter().map(|out| out.value).sum())
+    }
+
+    /// Checks that there is one spent output per input of the block
+    pub fn check(&self, block: &Block) -> OpResult<()> {
+        let matches = self.txs.len() + 1 == block.txs.len()
+            && self
+                .txs
+                .iter()
+                .zip(block.txs.iter().skip(1))
+                .all(|(spent, tx)| spent.len() == tx.value.inputs.len());
+        if !matches {
+            let msg = format!(
+                "Undo data for {} transactions doesn't match block {}",
+                self.txs.len(),
+                block.hash()
+            );
+            return Err(OpError::new(OpErrorKind::ValidationError).join_msg(&msg));
+        }
+        Ok(())
+    }
+}
+
+impl ToRaw for BlockUndo {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = VarUint::minimal(self.txs.len() as u64).to_bytes();
+        for spent in &self.txs {
+            bytes.extend(VarUint::minimal(spent.len() as u64).to_bytes());
+            for out in spent {
+                write_core_varint(&mut bytes, (out.height << 1) + out.is_coinbase as u64);
+                if out.height > 0 {
+                    write_core_varint(&mut bytes, 0);
+                }
+                write_core_varint(&mut bytes, compress_amount(out.value));
+                write_compressed_script(&mut bytes, &out.script_pubkey);
+            }
+        }
+        bytes
+    }
+}
+
+/// Returns the path of the rev file belonging to the blk file with the given index
+pub fn rev_path(blockchain_dir: &Path, blk_index: u64) -> PathBuf {
+    blockchain_dir.join(format!("rev{:05}.dat", blk_index))
+}
+
+/// Rev file which is opened on the first read
+pub struct RevFile {
+    pub path: PathBuf,
+    reader: Option<BufReader<File>>,
+}
+
+impl RevFile {
+    pub fn new(path: PathBuf) -> Self {
+        RevFile { path, reader: None }
+    }
+
+    /// Reads the undo data at the given offset of the block index (behind the magic and the size).
+    /// The record ends with the checksum sha256d(prev block hash + undo data), which is verified.
+    pub fn read_undo(&mut self, offset: u64, prev_hash: &sha256d::Hash) -> OpResult<BlockUndo> {
+        if self.reader.is_none() {
+            debug!(target: "undo", "Opening {} ...", &self.path.display());
+            let file = File::open(&self.path).map_err(|e| {
+                OpError::from(e).join_msg(&format!("Unable to open {}:", self.path.display()))
+            })?;
+            self.reader = Some(BufReader::new(file));
+        }
+        let reader = self.reader.as_mut().unwrap();
+        reader.seek(SeekFrom::Start(offset - 4))?;
+        let size = reader.read_u32::<LittleEndian>()?;
+        let data = reader.read_u8_vec(size)?;
+        let checksum = reader.read_256hash()?;
+
+        let mut preimage = prev_hash.to_byte_array().to_vec();
+        preimage.extend(&data);
+        if sha256d::Hash::hash(&preimage).to_byte_array() != checksum {
+            let msg = format!(
+                "Invalid checksum of the undo data at {} offset {}",
+                self.path.display(),
+                offset
+            );
+            return Err(OpError::new(OpErrorKind::ValidationError).join_msg(&msg));
+        }
+        BlockUndo::read_from(&mut data.as_slice())
+    }
+
+    /// Closes the file handle
+    pub fn close(&mut self) {
+        self.reader = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::testutils;
+    use std::fs;
+
+    fn spent(height: u64, value: u64, script_pubkey: Vec<u8>) -> SpentOutput {
+        SpentOutput {
+            height,
+            is_coinbase: height == 1,
+            value,
+            script_pubkey,
+        }
+    }
+
+    #[test]
+    fn test_read_undo() {
+        let undo = BlockUndo {
+            txs: vec![
+                vec![spent(1, 5000000000, testutils::p2pkh_script(1))],
+                vec![
+                    spent(2, 1234, vec![0x51]),
+                    spent(0, 0, vec![0xa9, 0x14, 0, 0x87]),
+                ],
+            ],
+        };
+        let prev_hash = sha256d::Hash::hash(&[1]);
+        let data = undo.to_bytes();
+        let mut rev = vec![0xff; 10];
+        rev.extend(0xd9b4bef9u32.to_le_bytes());
+        rev.extend((data.len() as u32).to_le_bytes());
+        let mut preimage = prev_hash.to_byte_array().to_vec();
+        preimage.extend(&data);
+        rev.extend(data);
+        rev.extend(sha256d::Hash::hash(&preimage).as_byte_array());
+
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(rev_path(dir.path(), 3), rev).unwrap();
+        let mut rev_file = RevFile::new(rev_path(dir.path(), 3));
+        assert!(rev_file.path.ends_with("rev00003.dat"));
+        let read = rev_file.read_undo(18, &prev_hash).unwrap();
+        assert_eq!(read, undo);
+        assert_eq!(read.spent_outputs(0), None);
+        assert_eq!(read.spent_value(1), Some(5000000000));
+        assert_eq!(read.spent_value(2), Some(1234));
+        assert_eq!(read.spent_value(3), None);
+
+        assert!(rev_file.read_undo(18, &sha256d::Hash::all_zeros()).is_err());
+    }
+
+    #[test]
+    fn test_check_undo() {
+        let block1 = testutils::block(
+            sha256d::Hash::all_zeros(),
+            0,
+            vec![testutils::coinbase(1, &[(50, testutils::p2pkh_script(0))])],
+        );
+        let block = testutils::block(
+            block1.header.hash,
+            600,
+            vec![
+                testutils::coinbase(2, &[(50, testutils::p2pkh_script(1))]),
+                testutils::tx(&[(block1.txs[0].hash, 0)], &[(40, vec![0x51])]),
+            ],
+        );
+        let undo = BlockUndo {
+            txs: vec![vec![spent(1, 50, testutils::p2pkh_script(0))]],
+        };
+        assert!(undo.check(&block).is_ok());
+        assert!(BlockUndo::default().check(&block).is_err());
+        assert!(undo.check(&block1).is_err());
+    }
+}
@@ -0,0 +1,297 @@
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use chrono::{Datelike, Duration, NaiveDate, NaiveDateTime};
+use clap::{Arg, ArgMatches, Command};
+
+use crate::blockchain::proto::block::Block;
+use crate::blockchain::proto::tx::OutpointKey;
+use crate::callbacks::{common, Callback, Control};
+use crate::common::hyperloglog::HyperLogLog;
+use crate::common::utils;
+use crate::errors::OpResult;
+
+/// Registers of the HyperLogLog in approximate mode, 16 KiB with a standard error of 0.8%
+const HLL_PRECISION: u32 = 14;
+
+/// Distinct addresses of the current window
+enum AddressSet {
+    Exact(HashSet<String>),
+    Approximate(HyperLogLog),
+}
+
+impl AddressSet {
+    fn insert(&mut self, address: &str) {
+        match self {
+            AddressSet::Exact(set) => {
+                if !set.contains(address) {
+                    set.insert(String::from(address));
+                }
+            }
+            AddressSet::Approximate(hll) => hll.insert(address),
+        }
+    }
+
+    fn count(&self) -> u64 {
+        match self {
+            AddressSet::Exact(set) => set.len() as u64,
+            AddressSet::Approximate(hll) => hll.count(),
+        }
+    }
+
+    fn clear(&mut self) {
+        match self {
+            AddressSet::Exact(set) => set.clear(),
+            AddressSet::Approximate(hll) => hll.clear(),
+        }
+    }
+}
+
+/// Dumps the number of distinct addresses sending or receiving within each day, week or month
+pub struct ActiveAddresses {
+    dump_folder: PathBuf,
+    writer: common::DumpWriter,
+    window: String,
+
+    // key: txid + index
+    unspents: HashMap<OutpointKey, common::UnspentValue>,
+    addresses: AddressSet,
+    // Highest block timestamp so far, block timestamps are not strictly increasing
+    max_timestamp: i64,
+    window_start: Option<NaiveDate>,
+
+    start_height: u64,
+    n_windows: u64,
+}
+
+impl ActiveAddresses {
+    /// Returns the first day (UTC) of the window containing the given timestamp.
+    /// Weeks start on Monday.
+    fn window_start(window: &str, timestamp: i64) -> NaiveDate {
+        let date = NaiveDateTime::from_timestamp_opt(timestamp, 0)
+            .unwrap_or_default()
+            .date();
+        match window {
+            "day" => date,
+            "week" => date - Duration::days(date.weekday().num_days_from_monday() as i64),
+            _ => date.with_day(1).unwrap(),
+        }
+    }
+
+    /// Writes the count of the current window, if any, and resets the address set
+    fn flush_window(&mut self) -> OpResult<()> {
+        if let Some(window_start) = self.window_start.take() {
+            self.writer
+                .write_all(format!("{};{}\n", window_start, self.addresses.count()).as_bytes())?;
+            self.addresses.clear();
+            self.n_windows += 1;
+        }
+        Ok(())
+    }
+}
+
+impl Callback for ActiveAddresses {
+    fn build_subcommand() -> Command
+    where
+        Self: Sized,
+    {
+        Command::new("activeaddresses")
+            .about("Dumps the number of distinct active addresses per day, week or month to CSV file")
+            .version("0.1")
+            .author("gcarq <egger.m@protonmail.com>")
+            .arg(
+                Arg::new("dump-folder")
+                    .help("Folder to store csv file")
+                    .index(1)
+                    .required(true),
+            )
+            .arg(
+                Arg::new("window")
+                    .long("window")
+                    .value_name("WINDOW")
+                    .value_parser(clap::builder::PossibleValuesParser::new([
+                        "day", "week", "month",
+                    ]))
+                    .default_value("week")
+                    .help("Length of the window the addresses are counted in"),
+            )
+            .arg(
+                Arg::new("approximate")
+                    .long("approximate")
+                    .action(clap::ArgAction::SetTrue)
+                    .help("Estimates the counts with a HyperLogLog (0.8% standard error) instead of keeping all addresses in memory"),
+            )
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let dump_folder = &PathBuf::from(matches.get_one::<String>("dump-folder").unwrap());
+        let addresses = if matches.get_flag("approximate") {
+            AddressSet::Approximate(HyperLogLog::new(HLL_PRECISION))
+        } else {
+            AddressSet::Exact(HashSet::new())
+        };
+        let cb = ActiveAddresses {
+            dump_folder: PathBuf::from(dump_folder),
+            writer: common::create_writer(dump_folder.join("activeaddresses.csv.tmp"))?,
+            window: matches.get_one::<String>("window").unwrap().clone(),
+            unspents: HashMap::with_capacity(10000000),
+            addresses,
+            max_timestamp: 0,
+            window_start: None,
+            start_height: 0,
+            n_windows: 0,
+        };
+        Ok(cb)
+    }
+
+    fn on_start(&mut self, block_height: u64) -> OpResult<()> {
+        self.start_height = block_height;
+        info!(target: "callback", "Executing activeaddresses with dump folder: {} (window: {}) ...",
+              &self.dump_folder.display(), self.window);
+        self.writer
+            .write_all(format!("{};{}\n", "window_start", "active_addresses").as_bytes())?;
+        Ok(())
+    }
+
+    fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<Control> {
+        self.max_timestamp = self.max_timestamp.max(block.header.value.timestamp as i64);
+        let window_start = ActiveAddresses::window_start(&self.window, self.max_timestamp);
+        if self.window_start != Some(window_start) {
+            self.flush_window()?;
+            self.window_start = Some(window_start);
+        }
+
+        for tx in &block.txs {
+            for spent in common::take_unspents(tx, &mut self.unspents) {
+                if !spent.address.is_empty() {
+                    self.addresses.insert(&spent.address.to_string());
+                }
+            }
+            for output in &tx.value.outputs {
+                if let Some(address) = &output.script.address {
+                    self.addresses.insert(address);
+                }
+            }
+            common::insert_unspents(tx, block_height, &mut self.unspents);
+        }
+        Ok(Control::Continue)
+    }
+
+    fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+        self.flush_window()?;
+        self.writer.flush()?;
+        utils::rename(
+            self.dump_folder.as_path().join("activeaddresses.csv.tmp"),
+            utils::output_path(
+                &self.dump_folder,
+                "activeaddresses",
+                self.start_height,
+                block_height,
+                "csv",
+            ),
+        )?;
+
+        info!(target: "callback", "Done.\nDumped {} windows from height {} to {}.",
+             self.n_windows, self.start_height, block_height);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> OpResult<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    fn dump_folder(&self) -> Option<&Path> {
+        Some(&self.dump_folder)
+    }
+
+    fn supports_incremental(&self) -> bool {
+        true
+    }
+
+    fn save_state(&self, folder: &Path) -> OpResult<()> {
+        common::save_unspents(&folder.join("activeaddresses.unspents"), &self.unspents)
+    }
+
+    fn load_state(&mut self, folder: &Path) -> OpResult<()> {
+        self.unspents = common::load_unspents(&folder.join("activeaddresses.unspents"))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::testutils;
+    use bitcoin::hashes::{sha256d, Hash};
+    use std::fs;
+
+    #[test]
+    fn test_window_start() {
+        // Wednesday, 2009-01-14
+        let timestamp = 1231891200 + 3600;
+        let start = |window| ActiveAddresses::window_start(window, timestamp).to_string();
+        assert_eq!(start("day"), "2009-01-14");
+        assert_eq!(start("week"), "2009-01-12");
+        assert_eq!(start("month"), "2009-01-01");
+    }
+
+    #[test]
+    fn test_activeaddresses_exact() {
+        let day = 24 * 60 * 60;
+        let block1 = testutils::block(
+            sha256d::Hash::all_zeros(),
+            1231891200,
+            vec![testutils::coinbase(
+                1,
+                &[
+                    (5000, testutils::p2pkh_script(1)),
+                    (5000, testutils::p2pkh_script(2)),
+                    (5000, testutils::p2pkh_script(1)),
+                ],
+            )],
+        );
+        // Same week, address 2 is active again
+        let block2 = testutils::block(
+            block1.header.hash,
+            1231891200 + day,
+            vec![
+                testutils::coinbase(2, &[(5000, testutils::p2pkh_script(3))]),
+                testutils::tx(
+                    &[(block1.txs[0].hash, 1)],
+                    &[(4000, testutils::p2pkh_script(1))],
+                ),
+            ],
+        );
+        // The next week, spends the output of address 1
+        let block3 = testutils::block(
+            block2.header.hash,
+            1231891200 + 7 * day,
+            vec![
+                testutils::coinbase(3, &[(5000, testutils::p2pkh_script(4))]),
+                testutils::tx(
+                    &[(block1.txs[0].hash, 0)],
+                    &[(4000, testutils::p2pkh_script(5))],
+                ),
+            ],
+        );
+
+        let dump_folder = tempfile::tempdir().unwrap();
+        let matches = ActiveAddresses::build_subcommand()
+            .get_matches_from(["activeaddresses", dump_folder.path().to_str().unwrap()]);
+        let mut cb = ActiveAddresses::new(&matches).unwrap();
+        cb.on_start(1).unwrap();
+        cb.on_block(&block1, 1).unwrap();
+        cb.on_block(&block2, 2).unwrap();
+        cb.on_block(&block3, 3).unwrap();
+        cb.on_complete(3).unwrap();
+
+        let csv = fs::read_to_string(dump_folder.path().join("activeaddresses-1-3.csv")).unwrap();
+        let rows: Vec<&str> = csv.lines().skip(1).collect();
+        assert_eq!(rows, vec!["2009-01-12;3", "2009-01-19;3"]);
+    }
+}
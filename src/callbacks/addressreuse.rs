@@ -0,0 +1,302 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::fs::File;
+use std::hash::{Hash, Hasher};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use clap::{Arg, ArgMatches, Command};
+use serde_json::{json, Value};
+
+use crate::blockchain::proto::block::Block;
+use crate::callbacks::{Callback, Control};
+use crate::common::utils;
+use crate::errors::{OpError, OpErrorKind, OpResult};
+
+/// Buckets of the distribution with their lower bound of uses
+const BUCKETS: [(&str, u32); 4] = [("1", 1), ("2", 2), ("3-10", 3), (">10", 11)];
+
+/// Number of outputs received by each address
+enum UseCounts {
+    Exact(HashMap<String, u32>),
+    // Keyed by a 64 bit hash of the address, colliding addresses are counted together
+    Approximate(HashMap<u64, u32>),
+}
+
+impl UseCounts {
+    fn increment(&mut self, address: &str) {
+        match self {
+            UseCounts::Exact(counts) => match counts.get_mut(address) {
+                Some(count) => *count += 1,
+                None => {
+                    counts.insert(String::from(address), 1);
+                }
+            },
+            UseCounts::Approximate(counts) => {
+                let mut hasher = DefaultHasher::new();
+                address.hash(&mut hasher);
+                *counts.entry(hasher.finish()).or_insert(0) += 1;
+            }
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            UseCounts::Exact(counts) => counts.len(),
+            UseCounts::Approximate(counts) => counts.len(),
+        }
+    }
+
+    /// Returns the number of addresses per bucket
+    fn distribution(&self) -> [u64; BUCKETS.len()] {
+        let mut distribution = [0; BUCKETS.len()];
+        let mut add = |uses: u32| {
+            if let Some(i) = BUCKETS.iter().rposition(|(_, min)| uses >= *min) {
+                distribution[i] += 1;
+            }
+        };
+        match self {
+            UseCounts::Exact(counts) => counts.values().copied().for_each(&mut add),
+            UseCounts::Approximate(counts) => counts.values().copied().for_each(&mut add),
+        }
+        distribution
+    }
+}
+
+/// Dumps how many addresses received outputs once, twice, 3 to 10 or more than 10 times.
+/// The distribution is dumped once all blocks are processed.
+pub struct AddressReuse {
+    dump_folder: PathBuf,
+
+    counts: UseCounts,
+
+    start_height: u64,
+    n_outputs: u64,
+}
+
+impl AddressReuse {
+    fn state_path(&self, folder: &Path) -> PathBuf {
+        match self.counts {
+            UseCounts::Exact(_) => folder.join("addressreuse.counts"),
+            UseCounts::Approximate(_) => folder.join("addressreuse.hashes"),
+        }
+    }
+}
+
+impl Callback for AddressReuse {
+    fn build_subcommand() -> Command
+    where
+        Self: Sized,
+    {
+        Command::new("addressreuse")
+            .about("Dumps the distribution of outputs received per address to CSV file")
+            .version("0.1")
+            .author("gcarq <egger.m@protonmail.com>")
+            .arg(
+                Arg::new("dump-folder")
+                    .help("Folder to store csv file")
+                    .index(1)
+                    .required(true),
+            )
+            .arg(
+                Arg::new("approximate")
+                    .long("approximate")
+                    .action(clap::ArgAction::SetTrue)
+                    .help("Counts the uses per 64 bit hash of the address instead of the address, which needs a fraction of the memory"),
+            )
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let counts = if matches.get_flag("approximate") {
+            UseCounts::Approximate(HashMap::with_capacity(10000000))
+        } else {
+            UseCounts::Exact(HashMap::with_capacity(10000000))
+        };
+        let cb = AddressReuse {
+            dump_folder: PathBuf::from(matches.get_one::<String>("dump-folder").unwrap()),
+            counts,
+            start_height: 0,
+            n_outputs: 0,
+        };
+        Ok(cb)
+    }
+
+    fn on_start(&mut self, block_height: u64) -> OpResult<()> {
+        self.start_height = block_height;
+        info!(target: "callback", "Executing addressreuse with dump folder: {} ...", &self.dump_folder.display());
+        Ok(())
+    }
+
+    /// Every output paying to an address counts as one use of it
+    fn on_block(&mut self, block: &Block, _: u64) -> OpResult<Control> {
+        for tx in &block.txs {
+            for output in &tx.value.outputs {
+                if let Some(address) = &output.script.address {
+                    self.counts.increment(address);
+                    self.n_outputs += 1;
+                }
+            }
+        }
+        Ok(Control::Continue)
+    }
+
+    fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+        let path = utils::output_path(
+            &self.dump_folder,
+            "addressreuse",
+            self.start_height,
+            block_height,
+            "csv",
+        );
+        let tmp_path = self.dump_folder.join("addressreuse.csv.tmp");
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        writer.write_all(format!("{};{}\n", "reuse_bucket", "address_count").as_bytes())?;
+        for ((bucket, _), count) in BUCKETS.iter().zip(self.counts.distribution()) {
+            writer.write_all(format!("{};{}\n", bucket, count).as_bytes())?;
+        }
+        writer.flush()?;
+        drop(writer);
+        utils::rename(tmp_path, path)?;
+
+        info!(target: "callback", "Done.\nCounted {} outputs to {} addresses up to height {}.",
+             self.n_outputs, self.counts.len(), block_height);
+        Ok(())
+    }
+
+    fn dump_folder(&self) -> Option<&Path> {
+        Some(&self.dump_folder)
+    }
+
+    fn supports_incremental(&self) -> bool {
+        true
+    }
+
+    fn summary(&self) -> Value {
+        json!({ "outputs": self.n_outputs, "addresses": self.counts.len() })
+    }
+
+    /// Stores `address;count` per line, or the hash and count pairs in approximate mode
+    fn save_state(&self, folder: &Path) -> OpResult<()> {
+        let path = self.state_path(folder);
+        let tmp_path = path.with_extension("tmp");
+        let mut writer = BufWriter::with_capacity(4000000, File::create(&tmp_path)?);
+        match &self.counts {
+            UseCounts::Exact(counts) => {
+                for (address, count) in counts {
+                    writer.write_all(format!("{};{}\n", address, count).as_bytes())?;
+                }
+            }
+            UseCounts::Approximate(counts) => {
+                writer.write_u64::<LittleEndian>(counts.len() as u64)?;
+                for (hash, count) in counts {
+                    writer.write_u64::<LittleEndian>(*hash)?;
+                    writer.write_u32::<LittleEndian>(*count)?;
+                }
+            }
+        }
+        writer.flush()?;
+        drop(writer);
+        utils::rename(&tmp_path, path)?;
+        Ok(())
+    }
+
+    fn load_state(&mut self, folder: &Path) -> OpResult<()> {
+        let path = self.state_path(folder);
+        if !path.exists() {
+            return Ok(());
+        }
+        let mut reader = BufReader::with_capacity(4000000, File::open(path)?);
+        match &mut self.counts {
+            UseCounts::Exact(counts) => {
+                for line in reader.lines() {
+                    let line = line?;
+                    let parsed = line
+                        .rsplit_once(';')
+                        .and_then(|(address, count)| Some((address, count.parse().ok()?)));
+                    let (address, count) = parsed.ok_or_else(|| {
+                        OpError::new(OpErrorKind::RuntimeError)
+                            .join_msg(&format!("Invalid addressreuse state: {}", line))
+                    })?;
+                    counts.insert(String::from(address), count);
+                }
+            }
+            UseCounts::Approximate(counts) => {
+                for _ in 0..reader.read_u64::<LittleEndian>()? {
+                    let hash = reader.read_u64::<LittleEndian>()?;
+                    counts.insert(hash, reader.read_u32::<LittleEndian>()?);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::testutils;
+    use bitcoin::hashes::{sha256d, Hash};
+    use std::fs;
+
+    #[test]
+    fn test_addressreuse() {
+        // Address 1 is used once, address 2 twice and address 3 five times
+        let block1 = testutils::block(
+            sha256d::Hash::all_zeros(),
+            0,
+            vec![testutils::coinbase(
+                1,
+                &[
+                    (5000, testutils::p2pkh_script(1)),
+                    (5000, testutils::p2pkh_script(2)),
+                    (5000, testutils::p2pkh_script(3)),
+                    (5000, testutils::p2pkh_script(3)),
+                ],
+            )],
+        );
+        let block2 = testutils::block(
+            block1.header.hash,
+            600,
+            vec![
+                testutils::coinbase(2, &[(5000, testutils::p2pkh_script(3))]),
+                testutils::tx(
+                    &[(block1.txs[0].hash, 0)],
+                    &[
+                        (1000, testutils::p2pkh_script(2)),
+                        (1000, testutils::p2pkh_script(3)),
+                        (1000, testutils::p2pkh_script(3)),
+                    ],
+                ),
+            ],
+        );
+
+        for approximate in [false, true] {
+            let dump_folder = tempfile::tempdir().unwrap();
+            let mut args = vec!["addressreuse", dump_folder.path().to_str().unwrap()];
+            if approximate {
+                args.push("--approximate");
+            }
+            let matches = AddressReuse::build_subcommand().get_matches_from(args);
+            let mut cb = AddressReuse::new(&matches).unwrap();
+            cb.on_start(1).unwrap();
+            cb.on_block(&block1, 1).unwrap();
+            cb.on_complete(1).unwrap();
+
+            // The counts survive an incremental run
+            cb.save_state(dump_folder.path()).unwrap();
+            let mut cb = AddressReuse::new(&matches).unwrap();
+            cb.load_state(dump_folder.path()).unwrap();
+            cb.on_start(2).unwrap();
+            cb.on_block(&block2, 2).unwrap();
+            cb.on_complete(2).unwrap();
+
+            let csv = fs::read_to_string(dump_folder.path().join("addressreuse-2-2.csv")).unwrap();
+            let rows: Vec<&str> = csv.lines().skip(1).collect();
+            assert_eq!(rows, vec!["1;1", "2;1", "3-10;1", ">10;0"]);
+        }
+    }
+}
@@ -0,0 +1,214 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use byteorder::{LittleEndian, WriteBytesExt};
+use clap::{Arg, ArgMatches, Command};
+
+use crate::blockchain::proto::block::Block;
+use crate::blockchain::proto::tx::OutpointKey;
+use crate::callbacks::{common, Callback, Control};
+use crate::common::address::CompactAddress;
+use crate::common::utils;
+use crate::errors::OpResult;
+
+/// Identifies the file format and its version
+const INDEX_MAGIC: &[u8; 8] = b"RBPBAL01";
+/// Magic plus the number of entries
+const HEADER_SIZE: u64 = 8 + 8;
+
+/// Writes all addresses with non-zero balance to a binary index with random access by address.
+///
+/// Format (all integers little endian):
+/// ```text
+/// magic      8 bytes  "RBPBAL01"
+/// count      u64      number of entries
+/// offsets    count * u64, absolute file offset of each entry
+/// entries    count * (u16 address length, address bytes, u64 balance), sorted by address
+/// ```
+pub struct BalanceIndex {
+    dump_folder: PathBuf,
+
+    // key: txid + index
+    unspents: HashMap<OutpointKey, common::UnspentValue>,
+
+    start_height: u64,
+}
+
+impl BalanceIndex {
+    /// Writes the index for the given balances, which are already sorted by address
+    fn write_index<W: Write>(writer: &mut W, balances: &BTreeMap<String, u64>) -> OpResult<()> {
+        writer.write_all(INDEX_MAGIC)?;
+        writer.write_u64::<LittleEndian>(balances.len() as u64)?;
+        let mut offset = HEADER_SIZE + 8 * balances.len() as u64;
+        for address in balances.keys() {
+            writer.write_u64::<LittleEndian>(offset)?;
+            offset += 2 + address.len() as u64 + 8;
+        }
+        for (address, balance) in balances {
+            writer.write_u16::<LittleEndian>(address.len() as u16)?;
+            writer.write_all(address.as_bytes())?;
+            writer.write_u64::<LittleEndian>(*balance)?;
+        }
+        Ok(())
+    }
+}
+
+impl Callback for BalanceIndex {
+    fn build_subcommand() -> Command
+    where
+        Self: Sized,
+    {
+        Command::new("balanceindex")
+            .about(
+                "Writes all addresses with non-zero balance to a binary index with random access",
+            )
+            .version("0.1")
+            .author("gcarq <egger.m@protonmail.com>")
+            .arg(
+                Arg::new("dump-folder")
+                    .help("Folder to store the index")
+                    .index(1)
+                    .required(true),
+            )
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let dump_folder = &PathBuf::from(matches.get_one::<String>("dump-folder").unwrap());
+        let cb = BalanceIndex {
+            dump_folder: PathBuf::from(dump_folder),
+            unspents: HashMap::with_capacity(10000000),
+            start_height: 0,
+        };
+        Ok(cb)
+    }
+
+    fn on_start(&mut self, block_height: u64) -> OpResult<()> {
+        self.start_height = block_height;
+        info!(target: "callback", "Executing balanceindex with dump folder: {} ...", &self.dump_folder.display());
+        Ok(())
+    }
+
+    fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<Control> {
+        for tx in &block.txs {
+            common::remove_unspents(tx, &mut self.unspents);
+            common::insert_unspents(tx, block_height, &mut self.unspents);
+        }
+        Ok(Control::Continue)
+    }
+
+    fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+        let mut sums: HashMap<&CompactAddress, u64> = HashMap::new();
+        for unspent in self.unspents.values() {
+            if unspent.address.is_empty() || unspent.value == 0 {
+                continue;
+            }
+            *sums.entry(&unspent.address).or_insert(0) += unspent.value;
+        }
+        let balances: BTreeMap<String, u64> = sums
+            .into_iter()
+            .map(|(address, balance)| (address.to_string(), balance))
+            .collect();
+
+        let tmp_path = self.dump_folder.join("balanceindex.bin.tmp");
+        let mut writer = BufWriter::with_capacity(4000000, File::create(&tmp_path)?);
+        BalanceIndex::write_index(&mut writer, &balances)?;
+        writer.flush()?;
+        utils::rename(
+            tmp_path,
+            utils::output_path(
+                &self.dump_folder,
+                "balanceindex",
+                self.start_height,
+                block_height,
+                "bin",
+            ),
+        )?;
+
+        info!(target: "callback", "Done.\nIndexed {} addresses.", balances.len());
+        Ok(())
+    }
+
+    fn dump_folder(&self) -> Option<&Path> {
+        Some(&self.dump_folder)
+    }
+
+    fn supports_incremental(&self) -> bool {
+        true
+    }
+
+    fn save_state(&self, folder: &Path) -> OpResult<()> {
+        common::save_unspents(&folder.join("balanceindex.unspents"), &self.unspents)
+    }
+
+    fn load_state(&mut self, folder: &Path) -> OpResult<()> {
+        self.unspents = common::load_unspents(&folder.join("balanceindex.unspents"))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::testutils;
+    use bitcoin::hashes::{sha256d, Hash};
+    use byteorder::ReadBytesExt;
+    use std::io::{Read, Seek, SeekFrom};
+
+    /// Looks up the balance of an address by binary search over the offset table
+    fn lookup(path: &Path, address: &str) -> Option<u64> {
+        let mut file = File::open(path).unwrap();
+        let mut magic = [0u8; 8];
+        file.read_exact(&mut magic).unwrap();
+        assert_eq!(&magic, INDEX_MAGIC);
+        let count = file.read_u64::<LittleEndian>().unwrap();
+
+        let (mut low, mut high) = (0, count);
+        while low < high {
+            let mid = (low + high) / 2;
+            file.seek(SeekFrom::Start(HEADER_SIZE + 8 * mid)).unwrap();
+            let offset = file.read_u64::<LittleEndian>().unwrap();
+            file.seek(SeekFrom::Start(offset)).unwrap();
+            let len = file.read_u16::<LittleEndian>().unwrap();
+            let mut entry = vec![0u8; len as usize];
+            file.read_exact(&mut entry).unwrap();
+            match entry.as_slice().cmp(address.as_bytes()) {
+                std::cmp::Ordering::Less => low = mid + 1,
+                std::cmp::Ordering::Greater => high = mid,
+                std::cmp::Ordering::Equal => return Some(file.read_u64::<LittleEndian>().unwrap()),
+            }
+        }
+        None
+    }
+
+    #[test]
+    fn test_balanceindex_lookup() {
+        let outputs = (0..20)
+            .map(|i| (1000 + i as u64, testutils::p2pkh_script(i)))
+            .collect::<Vec<(u64, Vec<u8>)>>();
+        let block = testutils::block(
+            sha256d::Hash::all_zeros(),
+            0,
+            vec![testutils::coinbase(1, &outputs)],
+        );
+
+        let dump_folder = tempfile::tempdir().unwrap();
+        let matches = BalanceIndex::build_subcommand()
+            .get_matches_from(["balanceindex", dump_folder.path().to_str().unwrap()]);
+        let mut cb = BalanceIndex::new(&matches).unwrap();
+        cb.on_start(1).unwrap();
+        cb.on_block(&block, 1).unwrap();
+        cb.on_complete(1).unwrap();
+
+        let path = dump_folder.path().join("balanceindex-1-1.bin");
+        for (i, output) in block.txs[0].value.outputs.iter().enumerate() {
+            let address = output.script.address.as_ref().unwrap();
+            assert_eq!(lookup(&path, address), Some(1000 + i as u64));
+        }
+        assert_eq!(lookup(&path, "1BitcoinEaterAddressDontSendf59kuE"), None);
+    }
+}
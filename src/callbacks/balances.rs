@@ -1,59 +1,271 @@
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
 use std::fs::{self, File};
-use std::io::{BufWriter, Write};
-use std::path::PathBuf;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
 
+use bitcoin::hashes::sha256d;
 use clap::{Arg, ArgMatches, Command};
+use serde_json::{json, Value};
 
-use crate::blockchain::proto::block::Block;
-use crate::callbacks::{common, Callback};
-use crate::errors::OpResult;
+use crate::blockchain::parser::snapshot::UtxoSnapshotReader;
+use crate::blockchain::parser::types::CoinType;
+use crate::blockchain::proto::block::{self, Block};
+use crate::blockchain::proto::script::ScriptPattern;
+use crate::blockchain::proto::tx::{EvaluatedTxOut, TxOutpoint, TxOutput};
+use crate::blockchain::proto::varuint::VarUint;
+use crate::callbacks::utxostore::{self, UtxoStore};
+use crate::callbacks::{common, Callback, Control, Metrics};
+use crate::common::address::CompactAddress;
+use crate::common::field::Field;
+use crate::common::utils;
+use crate::errors::{OpError, OpErrorKind, OpResult};
 
-use std::fs::OpenOptions;
-use std::error::Error;
-use csv::WriterBuilder;
+/// Log of the blocks whose miner claimed less than the block reward or which burned value.
+/// It covers all runs with the same dump folder, so it has no height range in its name.
+const LOST_LOG: &str = "lostvalue.csv";
+
+/// Table of `--format sqlite`, which holds the balances of the last run
+const TABLE: common::Table = common::Table {
+    columns: &["address TEXT NOT NULL", "balance INTEGER NOT NULL"],
+    indexes: &["address"],
+    replace: true,
+};
+
+/// Columns of the lost value log
+const LOST_LOG_HEADER: &str = "height;blockReward;inputValue;outputValue;unclaimedSubsidy;unclaimedFees;burned;destroyed;duplicateCoinbase;lost";
+
+/// Value which leaves the spendable supply, by cause
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct LostValue {
+    /// Subsidy the coinbase didn't claim, it is never issued
+    unclaimed_subsidy: u64,
+    /// Fees the coinbase didn't claim on top of the subsidy
+    unclaimed_fees: u64,
+    /// Value of OP_RETURN outputs
+    burned: u64,
+    /// Value of other provably unspendable outputs
+    destroyed: u64,
+    /// Coinbase outputs overwritten by a later coinbase with the same txid (BIP30)
+    duplicate_coinbase: u64,
+}
+
+impl LostValue {
+    fn total(&self) -> u64 {
+        self.unclaimed_subsidy
+            + self.unclaimed_fees
+            + self.burned
+            + self.destroyed
+            + self.duplicate_coinbase
+    }
+
+    fn add(&mut self, other: &LostValue) {
+        self.unclaimed_subsidy += other.unclaimed_subsidy;
+        self.unclaimed_fees += other.unclaimed_fees;
+        self.burned += other.burned;
+        self.destroyed += other.destroyed;
+        self.duplicate_coinbase += other.duplicate_coinbase;
+    }
+}
+
+/// Row order of the dumped balances (`--sort`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SortOrder {
+    /// HashMap order, which differs between runs
+    Unsorted,
+    Address,
+    /// Highest balance first, equal balances by address
+    Balance,
+}
 
 /// Dumps all addresses with non-zero balance in a csv file
 pub struct Balances {
+    coin: CoinType,
     dump_folder: PathBuf,
-    writer: BufWriter<File>,
+    writer: common::DumpWriter,
+    // Writes the snapshots in the format of the dump file
+    target: common::DumpTarget,
+    lost_writer: Option<common::DumpWriter>,
 
     // key: txid + index
-    unspents: HashMap<Vec<u8>, common::UnspentValue>,
-    lost_value: u64,
+    unspents: Box<dyn UtxoStore>,
+    lost: LostValue,
+    // Log the lost value every n blocks instead of per block, 0 disables the summary
+    lost_summary_interval: u64,
+    // Lost value and affected blocks since the last summary
+    summary_lost_value: u64,
+    summary_lost_blocks: u64,
+    // Outputs which don't match are tracked without an address, so they are not dumped
+    filter: common::OutputFilter,
+    // Only watched outputs are tracked, the lost value is not accounted then
+    watchlist: Option<common::Watchlist>,
+    // Assign outputs without an address to a pseudo-address derived from the scriptPubKey
+    bucket_nonstandard: bool,
+    sort: SortOrder,
+    // Heights after which the balances are dumped as well (`--snapshot-every`, `--snapshot-at`)
+    snapshot_every: Option<u64>,
+    snapshot_heights: BTreeSet<u64>,
+    n_snapshots: u64,
+    // Base block of the snapshot of `--load-utxo-snapshot`, the first parsed block must follow it
+    snapshot_base: Option<sha256d::Hash>,
+    muhash: Option<common::UtxoMuHash>,
 
     start_height: u64,
     end_height: u64,
+    n_addresses: u64,
 }
 
-fn block_reward(height: u64) -> u64 {
-    let initial_reward = 50 * 100000000;
+impl Balances {
+    /// Copies the rows of the existing lost value log below `start_height` to a temp file and
+    /// returns a writer appending to it. Rows from an overlapping range are written again by this run.
+    fn open_lost_log(&self, start_height: u64) -> OpResult<common::DumpWriter> {
+        let mut writer = common::create_writer(self.dump_folder.join(format!("{}.tmp", LOST_LOG)))?;
+        writer.write_all(format!("{}\n", LOST_LOG_HEADER).as_bytes())?;
+        let path = self.dump_folder.join(LOST_LOG);
+        if path.exists() {
+            let mut lines = BufReader::new(File::open(&path)?).lines();
+            if lines.next().transpose()?.as_deref() != Some(LOST_LOG_HEADER) {
+                warn!(target: "callback", "{} has different columns, its rows are not kept", path.display());
+                return Ok(writer);
+            }
+            for line in lines {
+                let line = line?;
+                let height = line.split(';').next().and_then(|h| h.parse::<u64>().ok());
+                if matches!(height, Some(height) if height < start_height) {
+                    writer.write_all(format!("{}\n", line).as_bytes())?;
+                }
+            }
+        }
+        Ok(writer)
+    }
 
-    let halving_interval = 210000;
+    /// Writes the balance of each address and returns the number of addresses
+    fn write_balances(
+        unspents: &mut dyn UtxoStore,
+        writer: &mut common::DumpWriter,
+        sort: SortOrder,
+    ) -> OpResult<u64> {
+        // Tables and Parquet files have named columns already
+        if writer.is_csv() {
+            writer.write_all(format!("{};{}\n", "address", "balance").as_bytes())?;
+        }
 
-    let halvings = height / halving_interval;
+        // Collect balances for each address
+        let mut balances: HashMap<CompactAddress, u64> = HashMap::new();
+        unspents.for_each(&mut |_, unspent| {
+            // Outputs without an evaluated address are only tracked for the value accounting,
+            // otherwise they would all add up to the balance of a single empty address
+            if unspent.address.is_empty() {
+                return Ok(());
+            }
+            match balances.get_mut(&unspent.address) {
+                Some(balance) => *balance += unspent.value,
+                None => {
+                    balances.insert(unspent.address.clone(), unspent.value);
+                }
+            }
+            Ok(())
+        })?;
 
-    let reward = initial_reward >> halvings;
+        // Sorting needs another 32 bytes per address, about 2 GB for 60 million addresses
+        let mut rows: Vec<(String, u64)> = balances
+            .into_iter()
+            .map(|(address, balance)| (address.to_string(), balance))
+            .collect();
+        match sort {
+            SortOrder::Unsorted => {}
+            SortOrder::Address => rows.sort_unstable(),
+            SortOrder::Balance => {
+                rows.sort_unstable_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)))
+            }
+        }
+        for (address, balance) in &rows {
+            writer.write_row(&[Field::Text(address), Field::Int(*balance as i64)])?;
+        }
+        Ok(rows.len() as u64)
+    }
 
-    reward
-}
+    /// Returns the address the output is tracked with, or None if it is not watched.
+    /// Outputs which don't match the filter are tracked with an empty address,
+    /// so they only count for the lost value accounting.
+    fn tracked_address(&self, output: &EvaluatedTxOut) -> Option<String> {
+        if matches!(&self.watchlist, Some(watchlist) if !watchlist.matches(output)) {
+            return None;
+        }
+        if !self.filter.matches(output) {
+            return Some(String::new());
+        }
+        match &output.script.address {
+            Some(address) => Some(address.clone()),
+            None if self.bucket_nonstandard => {
+                Some(common::nonstandard_bucket(&output.out.script_pubkey))
+            }
+            None => Some(String::new()),
+        }
+    }
 
-fn write_to_csv(block_height: u64, b_reward: i64, in_v: i64, out_v: i64, lost: i64) -> Result<(), Box<dyn Error>> {
-    let file = OpenOptions::new()
-        .append(true)
-        .create(true)
-        .open("output.csv")?;
+    /// Replaces the unspents with the coins of a Bitcoin Core `dumptxoutset` snapshot
+    /// and returns its base block
+    fn load_snapshot(&mut self, path: &Path) -> OpResult<sha256d::Hash> {
+        let reader = UtxoSnapshotReader::open(path)?;
+        if reader.metadata.network_magic != self.coin.magic {
+            let msg = format!(
+                "UTXO snapshot {} is not a {} snapshot",
+                path.display(),
+                self.coin.name
+            );
+            return Err(OpError::new(OpErrorKind::InvalidArgsError).join_msg(&msg));
+        }
+        let base_block_hash = reader.metadata.base_block_hash;
+        info!(target: "callback", "Loading {} coins from UTXO snapshot {} ...",
+              reader.metadata.coins_count, path.display());
+        self.unspents.clear()?;
+        for coin in reader {
+            let coin = coin?;
+            let out = TxOutput {
+                value: coin.value,
+                script_len: VarUint::minimal(coin.script_pubkey.len() as u64),
+                script_pubkey: coin.script_pubkey,
+            };
+            if let Some(muhash) = self.muhash.as_mut() {
+                muhash.insert_coin(&coin.outpoint, coin.height, coin.is_coinbase, &out);
+            }
+            let output = EvaluatedTxOut::eval_script(
+                out,
+                self.coin.version_id,
+                self.coin.address_encoder.as_ref(),
+            );
+            if let Some(address) = self.tracked_address(&output) {
+                let unspent = common::UnspentValue {
+                    block_height: coin.height,
+                    value: coin.value,
+                    address: CompactAddress::new(&address),
+                };
+                self.unspents.insert(coin.outpoint.key(), unspent)?;
+            }
+        }
+        Ok(base_block_hash)
+    }
 
-    let mut wtr = WriterBuilder::new().has_headers(false).from_writer(file);
-    wtr.write_record(&[block_height.to_string(), b_reward.to_string(), in_v.to_string(), out_v.to_string(), lost.to_string()])?;
-    wtr.flush()?;
+    /// Dumps the current balances through the temp file `tmp_name` to `path`
+    /// and returns the number of addresses
+    fn dump_snapshot(&mut self, tmp_name: &str, path: PathBuf) -> OpResult<u64> {
+        let tmp_path = self.dump_folder.join(tmp_name);
+        let mut writer = self.target.file_writer(tmp_path.clone(), &TABLE)?;
+        let n_addresses = Balances::write_balances(self.unspents.as_mut(), &mut writer, self.sort)?;
+        writer.finish()?;
+        drop(writer);
+        utils::rename(tmp_path, path)?;
+        Ok(n_addresses)
+    }
 
-    Ok(())
-}
+    fn is_snapshot_height(&self, block_height: u64) -> bool {
+        matches!(self.snapshot_every, Some(n) if block_height.is_multiple_of(n))
+            || self.snapshot_heights.contains(&block_height)
+    }
 
-impl Balances {
-    fn create_writer(cap: usize, path: PathBuf) -> OpResult<BufWriter<File>> {
-        Ok(BufWriter::with_capacity(cap, File::create(path)?))
+    fn is_summary_height(&self, block_height: u64) -> bool {
+        self.lost_summary_interval > 0
+            && (block_height + 1).is_multiple_of(self.lost_summary_interval)
     }
 }
 
@@ -72,6 +284,57 @@ impl Callback for Balances {
                     .index(1)
                     .required(true),
             )
+            .arg(
+                Arg::new("bucket-nonstandard")
+                    .long("bucket-nonstandard")
+                    .action(clap::ArgAction::SetTrue)
+                    .help(
+                        "Tracks outputs without an address as `nonstandard:<sha256(scriptPubKey)>`",
+                    ),
+            )
+            .arg(
+                Arg::new("lost-summary-interval")
+                    .long("lost-summary-interval")
+                    .value_name("N")
+                    .value_parser(clap::value_parser!(u64))
+                    .default_value("10000")
+                    .help("Logs the cumulative lost value every N blocks (0 to disable). Per block values are logged at debug level"),
+            )
+            .arg(
+                Arg::new("sort")
+                    .long("sort")
+                    .value_name("ORDER")
+                    .value_parser(clap::builder::PossibleValuesParser::new(["address", "balance"]))
+                    .help("Sorts the rows by address or by balance (descending), so the output of runs over the same blocks is identical"),
+            )
+            .arg(
+                Arg::new("load-utxo-snapshot")
+                    .long("load-utxo-snapshot")
+                    .value_name("FILE")
+                    .help("Starts from the UTXO set of a `bitcoin-cli dumptxoutset` snapshot, --start must be the height after its base block"),
+            )
+            .arg(
+                Arg::new("snapshot-every")
+                    .long("snapshot-every")
+                    .value_name("N")
+                    .value_parser(clap::value_parser!(u64).range(1..))
+                    .help("Also dumps the balances at each height which is a multiple of N to balances-<start>-<height>.csv"),
+            )
+            .arg(
+                Arg::new("snapshot-at")
+                    .long("snapshot-at")
+                    .value_name("HEIGHTS")
+                    .value_parser(clap::value_parser!(u64))
+                    .value_delimiter(',')
+                    .help("Also dumps the balances at the given heights (comma separated) to balances-<start>-<height>.csv"),
+            )
+            .arg(common::muhash_height_arg())
+            .args(utxostore::utxo_store_args())
+            .args(common::output_filter_args())
+            .arg(common::watch_addresses_arg())
+            .arg(common::gzip_arg())
+            .arg(common::format_arg().conflicts_with("gzip"))
+            .arg(common::stdout_json_arg().conflicts_with("gzip"))
     }
 
     fn new(matches: &ArgMatches) -> OpResult<Self>
@@ -79,19 +342,47 @@ impl Callback for Balances {
         Self: Sized,
     {
         let dump_folder = &PathBuf::from(matches.get_one::<String>("dump-folder").unwrap());
-        let cb = Balances {
+        let target = common::DumpTarget::from_matches(dump_folder, matches)?;
+        let mut cb = Balances {
+            coin: common::get_coin(matches),
             dump_folder: PathBuf::from(dump_folder),
-            writer: Balances::create_writer(4000000, dump_folder.join("balances.csv.tmp"))?,
-            unspents: HashMap::with_capacity(10000000),
+            writer: target.writer("balances", &TABLE)?,
+            target,
+            lost_writer: None,
+            unspents: utxostore::open(matches, dump_folder, "balances"),
             start_height: 0,
             end_height: 0,
-            lost_value: 0,
+            n_addresses: 0,
+            lost: LostValue::default(),
+            lost_summary_interval: *matches.get_one::<u64>("lost-summary-interval").unwrap(),
+            summary_lost_value: 0,
+            summary_lost_blocks: 0,
+            filter: common::OutputFilter::from_matches(matches),
+            watchlist: common::Watchlist::from_matches(matches)?,
+            bucket_nonstandard: matches.get_flag("bucket-nonstandard"),
+            sort: match matches.get_one::<String>("sort").map(String::as_str) {
+                Some("address") => SortOrder::Address,
+                Some("balance") => SortOrder::Balance,
+                _ => SortOrder::Unsorted,
+            },
+            snapshot_every: matches.get_one::<u64>("snapshot-every").copied(),
+            snapshot_heights: matches
+                .get_many::<u64>("snapshot-at")
+                .map(|heights| heights.copied().collect())
+                .unwrap_or_default(),
+            n_snapshots: 0,
+            snapshot_base: None,
+            muhash: common::UtxoMuHash::from_matches(matches),
         };
+        if let Some(path) = matches.get_one::<String>("load-utxo-snapshot") {
+            cb.snapshot_base = Some(cb.load_snapshot(Path::new(path))?);
+        }
         Ok(cb)
     }
 
     fn on_start(&mut self, block_height: u64) -> OpResult<()> {
         self.start_height = block_height;
+        self.lost_writer = Some(self.open_lost_log(block_height)?);
         info!(target: "callback", "Executing balances with dump folder: {} ...", &self.dump_folder.display());
         Ok(())
     }
@@ -99,64 +390,906 @@ impl Callback for Balances {
     /// For each transaction in the block
     ///   1. apply input transactions (remove (TxID == prevTxIDOut and prevOutID == spentOutID))
     ///   2. apply output transactions (add (TxID + curOutID -> HashMapVal))
+    ///
     /// For each address, retain:
     ///   * block height as "last modified"
     ///   * output_val
     ///   * address
-    fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<()> {
-        let mut in_v: i64 = 0;
-        let mut out_v: i64 = 0;
-        let b_reward: i64 = block_reward(block_height) as i64;
+    fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<Control> {
+        if let Some(base) = self.snapshot_base.take() {
+            if block.header.value.prev_hash != base {
+                let msg = format!(
+                    "Block {} at height {} does not follow the base block {} of the UTXO snapshot",
+                    utils::hash_to_string(&block.hash()),
+                    block_height,
+                    utils::hash_to_string(&base)
+                );
+                return Err(OpError::new(OpErrorKind::InvalidArgsError).join_msg(&msg));
+            }
+        }
+        let b_reward =
+            block::get_base_reward(block_height, &block.header.value.prev_hash, &self.coin);
+        let (mut in_v, mut out_v, mut new_v) = (0, 0, 0);
+        let mut coinbase_value = 0;
+        let mut lost = LostValue::default();
         for tx in &block.txs {
-            let (_in_count, spent_value) = common::remove_unspents(tx, &mut self.unspents);
-            let (_count, new_value) = common::insert_unspents(tx, block_height, &mut self.unspents);
-            in_v += spent_value as i64;
-            out_v += new_value as i64;
-        }
-        let lost = b_reward + in_v - out_v;
-        if lost > 0 {
-            println!("block {} b_reward {} in_v {} out_v {} lost {}", block_height, b_reward, in_v, out_v, lost);
-            if let Err(err) = write_to_csv(block_height, b_reward, in_v, out_v, lost) {
-                eprintln!("Failed to write to CSV: {}", err);
+            let (_in_count, spent_value) = self.unspents.remove_spent(tx)?;
+            let (_count, new_value) = self.unspents.insert_outputs(tx, block_height)?;
+            if let Some(muhash) = self.muhash.as_mut() {
+                muhash.apply(tx, block_height);
+            }
+            if self.watchlist.is_some() || self.filter.is_active() || self.bucket_nonstandard {
+                for (i, output) in tx.value.outputs.iter().enumerate() {
+                    let key = TxOutpoint::new(tx.hash, i as u32).key();
+                    match self.tracked_address(output) {
+                        Some(address) => {
+                            if let Some(mut unspent) = self.unspents.remove(&key)? {
+                                unspent.address = CompactAddress::new(&address);
+                                self.unspents.insert(key, unspent)?;
+                            }
+                        }
+                        None => {
+                            self.unspents.remove(&key)?;
+                        }
+                    }
+                }
+            }
+            for output in &tx.value.outputs {
+                match output.script.pattern {
+                    ScriptPattern::OpReturn(_) => lost.burned += output.out.value,
+                    ScriptPattern::Unspendable => lost.destroyed += output.out.value,
+                    _ => {}
+                }
+                out_v += output.out.value;
+                if tx.value.is_coinbase() {
+                    coinbase_value += output.out.value;
+                }
+            }
+            in_v += spent_value;
+            new_v += new_value;
+        }
+        if let Some(muhash) = self.muhash.as_mut() {
+            muhash.on_block_end(block_height);
+        }
+        if self.is_snapshot_height(block_height) {
+            let ext = self.target.extension();
+            let path = utils::output_path(
+                &self.dump_folder,
+                "balances",
+                self.start_height,
+                block_height,
+                ext,
+            );
+            let n_addresses =
+                self.dump_snapshot(&format!("balances-snapshot.{}.tmp", ext), path)?;
+            self.n_snapshots += 1;
+            info!(target: "callback", "Dumped {} addresses with their balance at height {}",
+                  n_addresses, block_height);
+        }
+        // The values of the spent outputs which are not watched are unknown
+        if self.watchlist.is_some() {
+            return Ok(Control::Continue);
+        }
+        // The coinbase claims the subsidy first, the rest of the block reward are the fees
+        let unclaimed = (b_reward + in_v).saturating_sub(out_v);
+        lost.unclaimed_subsidy = b_reward.saturating_sub(coinbase_value).min(unclaimed);
+        lost.unclaimed_fees = unclaimed - lost.unclaimed_subsidy;
+        lost.duplicate_coinbase = out_v - new_v;
+        if lost.total() > 0 {
+            debug!(target: "callback", "block {} b_reward {} in_v {} out_v {} lost {:?}",
+                   block_height, b_reward, in_v, out_v, lost);
+            if let Some(writer) = self.lost_writer.as_mut() {
+                writer.write_all(
+                    format!(
+                        "{};{};{};{};{};{};{};{};{};{}\n",
+                        block_height,
+                        b_reward,
+                        in_v,
+                        out_v,
+                        lost.unclaimed_subsidy,
+                        lost.unclaimed_fees,
+                        lost.burned,
+                        lost.destroyed,
+                        lost.duplicate_coinbase,
+                        lost.total()
+                    )
+                    .as_bytes(),
+                )?;
             }
+            self.summary_lost_blocks += 1;
+        }
+
+        self.lost.add(&lost);
+        self.summary_lost_value += lost.total();
+        if self.is_summary_height(block_height) {
+            info!(target: "callback", "Lost value up to height {}: {:.8} in {} of the last {} blocks, {:.8} in total",
+                  block_height, self.summary_lost_value as f64 * 1E-8, self.summary_lost_blocks,
+                  self.lost_summary_interval, self.lost.total() as f64 * 1E-8);
+            self.summary_lost_value = 0;
+            self.summary_lost_blocks = 0;
         }
+        Ok(Control::Continue)
+    }
 
-        self.lost_value += lost as u64; // 如果 self.lost_value 仍然是 u64 类型
+    /// Dumps the balances up to the given height to `balances-partial-<start>-<height>.csv`
+    fn on_interval(&mut self, block_height: u64) -> OpResult<()> {
+        let ext = self.target.extension();
+        let path = utils::output_path(
+            &self.dump_folder,
+            "balances-partial",
+            self.start_height,
+            block_height,
+            ext,
+        );
+        let n_addresses = self.dump_snapshot(&format!("balances-partial.{}.tmp", ext), path)?;
+        info!(target: "callback", "Dumped {} addresses with their balance at height {}",
+              n_addresses, block_height);
         Ok(())
     }
 
+    fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+        self.end_height = block_height;
 
+        self.n_addresses =
+            Balances::write_balances(self.unspents.as_mut(), &mut self.writer, self.sort)?;
+        self.writer.finish()?;
 
+        if self.writer.is_file() {
+            let ext = self.target.extension();
+            utils::rename(
+                self.dump_folder
+                    .as_path()
+                    .join(format!("balances.{}.tmp", ext)),
+                utils::output_path(
+                    &self.dump_folder,
+                    "balances",
+                    self.start_height,
+                    self.end_height,
+                    ext,
+                ),
+            )?;
+        }
 
-    fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
-        self.end_height = block_height;
+        if let Some(muhash) = &self.muhash {
+            muhash.finish();
+        }
 
-        self.writer
-            .write_all(format!("{};{}\n", "address", "balance").as_bytes())?;
+        if let Some(writer) = self.lost_writer.as_mut() {
+            writer.flush()?;
+            utils::rename(
+                self.dump_folder.as_path().join(format!("{}.tmp", LOST_LOG)),
+                self.dump_folder.as_path().join(LOST_LOG),
+            )?;
+        }
 
-        // Collect balances for each address
-        let mut balances: HashMap<&str, u64> = HashMap::new();
-        for unspent in self.unspents.values() {
-            let entry = balances.entry(&unspent.address).or_insert(0);
-            *entry += unspent.value
+        info!(target: "callback", "Done.\nDumped {} addresses.\n\
+                                   Lost value: {:.8}\n\
+                                   \t-> unclaimed subsidy:  {:.8}\n\
+                                   \t-> unclaimed fees:     {:.8}\n\
+                                   \t-> burned (OP_RETURN): {:.8}\n\
+                                   \t-> destroyed:          {:.8}\n\
+                                   \t-> duplicate coinbase: {:.8}",
+              self.n_addresses, self.lost.total() as f64 * 1E-8,
+              self.lost.unclaimed_subsidy as f64 * 1E-8, self.lost.unclaimed_fees as f64 * 1E-8,
+              self.lost.burned as f64 * 1E-8, self.lost.destroyed as f64 * 1E-8,
+              self.lost.duplicate_coinbase as f64 * 1E-8);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> OpResult<()> {
+        self.writer.flush()?;
+        if let Some(writer) = self.lost_writer.as_mut() {
+            writer.flush()?;
+        }
+        Ok(())
+    }
+
+    fn dump_folder(&self) -> Option<&Path> {
+        Some(&self.dump_folder)
+    }
+
+    fn supports_incremental(&self) -> bool {
+        true
+    }
+
+    fn writes_stdout(&self) -> bool {
+        self.writer.is_stdout()
+    }
+
+    fn metrics(&self) -> Option<&dyn Metrics> {
+        Some(self)
+    }
+
+    fn summary(&self) -> Value {
+        let mut summary = json!({
+            "addresses": self.n_addresses,
+            "snapshots": self.n_snapshots,
+            "lost_value": self.lost.total(),
+            "unclaimed_subsidy": self.lost.unclaimed_subsidy,
+            "unclaimed_fees": self.lost.unclaimed_fees,
+            "burned": self.lost.burned,
+            "destroyed": self.lost.destroyed,
+            "duplicate_coinbase": self.lost.duplicate_coinbase,
+        });
+        if let Some(muhash) = &self.muhash {
+            summary["muhash"] = json!(muhash.muhash());
         }
+        summary
+    }
 
-        for (address, balance) in balances.iter() {
-            self.writer
-                .write_all(format!("{};{}\n", address, balance).as_bytes())?;
+    /// Checkpoints of `--resume` are taken while the lost value log is still written,
+    /// so its rows so far are published as well. They are not written again after resuming.
+    fn save_state(&self, folder: &Path) -> OpResult<()> {
+        let tmp_log = folder.join(format!("{}.tmp", LOST_LOG));
+        if tmp_log.exists() {
+            let copy = folder.join(format!("{}.checkpoint", LOST_LOG));
+            fs::copy(&tmp_log, &copy)?;
+            utils::rename(&copy, folder.join(LOST_LOG))?;
         }
+        self.unspents.save(folder)
+    }
+
+    fn load_state(&mut self, folder: &Path) -> OpResult<()> {
+        // The checkpoint is later than the snapshot
+        self.snapshot_base = None;
+        common::UtxoMuHash::disable_on_resume(&mut self.muhash);
+        self.unspents.load(folder)
+    }
+
+    fn supports_checkpoints(&self) -> bool {
+        self.unspents.supports_checkpoints()
+    }
+}
+
+impl Metrics for Balances {
+    fn counters(&self) -> Vec<(&'static str, u64)> {
+        vec![
+            ("unspents", self.unspents.len()),
+            ("lost_value", self.lost.total()),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::testutils;
+    use bitcoin::hashes::{sha256d, Hash};
+    use std::fs;
+    use std::io::Read;
+
+    #[test]
+    fn test_balances_bucket_nonstandard() {
+        // OP_1 <pubkey> <pubkey> OP_2 OP_CHECKMULTISIG
+        let mut multisig = vec![0x51, 0x21];
+        multisig.extend([0x02; 33]);
+        multisig.push(0x21);
+        multisig.extend([0x03; 33]);
+        multisig.extend([0x52, 0xae]);
+        let nonstandard = vec![0x51];
+        let block = testutils::block(
+            sha256d::Hash::all_zeros(),
+            0,
+            vec![testutils::coinbase(
+                1,
+                &[
+                    (4000000000, testutils::p2pkh_script(1)),
+                    (600000000, multisig.clone()),
+                    (300000000, nonstandard.clone()),
+                    (100000000, nonstandard.clone()),
+                ],
+            )],
+        );
+
+        let dump_folder = tempfile::tempdir().unwrap();
+        let matches = Balances::build_subcommand().get_matches_from([
+            "balances",
+            dump_folder.path().to_str().unwrap(),
+            "--bucket-nonstandard",
+        ]);
+        let mut cb = Balances::new(&matches).unwrap();
+        cb.on_start(1).unwrap();
+        cb.on_block(&block, 1).unwrap();
+        cb.on_complete(1).unwrap();
+
+        let csv = fs::read_to_string(dump_folder.path().join("balances-1-1.csv")).unwrap();
+        let rows: HashMap<&str, u64> = csv
+            .lines()
+            .skip(1)
+            .map(|line| {
+                let (address, balance) = line.split_once(';').unwrap();
+                (address, balance.parse().unwrap())
+            })
+            .collect();
+        assert_eq!(rows.len(), 3);
+        assert_eq!(
+            rows[common::nonstandard_bucket(&multisig).as_str()],
+            600000000
+        );
+        assert_eq!(
+            rows[common::nonstandard_bucket(&nonstandard).as_str()],
+            400000000
+        );
+        assert_eq!(rows.values().sum::<u64>(), 5000000000);
+    }
+
+    #[test]
+    fn test_balances_sort() {
+        let outputs: Vec<(u64, Vec<u8>)> = [(3, 1000), (1, 5000), (2, 1000), (4, 2000)]
+            .iter()
+            .map(|(seed, value)| (*value, testutils::p2pkh_script(*seed)))
+            .collect();
+        let block = testutils::block(
+            sha256d::Hash::all_zeros(),
+            0,
+            vec![testutils::coinbase(1, &outputs)],
+        );
+        let run = |args: &[&str]| {
+            let dump_folder = tempfile::tempdir().unwrap();
+            let matches = Balances::build_subcommand().get_matches_from(
+                ["balances", dump_folder.path().to_str().unwrap()]
+                    .iter()
+                    .chain(args),
+            );
+            let mut cb = Balances::new(&matches).unwrap();
+            cb.on_start(1).unwrap();
+            cb.on_block(&block, 1).unwrap();
+            cb.on_complete(1).unwrap();
+            fs::read(dump_folder.path().join("balances-1-1.csv")).unwrap()
+        };
+
+        let csv = run(&["--sort", "address"]);
+        assert_eq!(csv, run(&["--sort", "address"]));
+        let rows: Vec<String> = String::from_utf8(csv)
+            .unwrap()
+            .lines()
+            .skip(1)
+            .map(String::from)
+            .collect();
+        let mut sorted = rows.clone();
+        sorted.sort();
+        assert_eq!(rows, sorted);
+
+        let csv = run(&["--sort", "balance"]);
+        assert_eq!(csv, run(&["--sort", "balance"]));
+        let by_balance: Vec<String> = String::from_utf8(csv)
+            .unwrap()
+            .lines()
+            .skip(1)
+            .map(String::from)
+            .collect();
+        let balances: Vec<&str> = by_balance
+            .iter()
+            .map(|row| row.split_once(';').unwrap().1)
+            .collect();
+        assert_eq!(balances, vec!["5000", "2000", "1000", "1000"]);
+        // Equal balances are ordered by address
+        let equal: Vec<&String> = rows.iter().filter(|row| row.ends_with(";1000")).collect();
+        assert_eq!(by_balance[2..].iter().collect::<Vec<_>>(), equal);
+    }
+
+    #[test]
+    fn test_balances_disk_store() {
+        let block1 = testutils::block(
+            sha256d::Hash::all_zeros(),
+            0,
+            vec![testutils::coinbase(
+                1,
+                &[
+                    (3000, testutils::p2pkh_script(1)),
+                    (2000, testutils::p2pkh_script(2)),
+                ],
+            )],
+        );
+        let block2 = testutils::block(
+            block1.header.hash,
+            0,
+            vec![
+                testutils::coinbase(2, &[(5000, testutils::p2pkh_script(2))]),
+                testutils::tx(
+                    &[(block1.txs[0].hash, 0)],
+                    &[(2500, testutils::p2pkh_script(3))],
+                ),
+            ],
+        );
+        let run = |args: &[&str]| {
+            let dump_folder = tempfile::tempdir().unwrap();
+            let matches = Balances::build_subcommand().get_matches_from(
+                [
+                    "balances",
+                    "--sort",
+                    "address",
+                    dump_folder.path().to_str().unwrap(),
+                ]
+                .iter()
+                .chain(args),
+            );
+            let mut cb = Balances::new(&matches).unwrap();
+            cb.on_start(1).unwrap();
+            cb.on_block(&block1, 1).unwrap();
+            cb.save_state(dump_folder.path()).unwrap();
+            drop(cb);
+
+            // Next incremental run
+            let mut cb = Balances::new(&matches).unwrap();
+            cb.load_state(dump_folder.path()).unwrap();
+            cb.on_start(2).unwrap();
+            cb.on_block(&block2, 2).unwrap();
+            assert_eq!(cb.unspents.len(), 3);
+            cb.on_complete(2).unwrap();
+            fs::read(dump_folder.path().join("balances-2-2.csv")).unwrap()
+        };
+
+        let csv = run(&["--utxo-store", "disk", "--utxo-cache", "1"]);
+        assert_eq!(csv, run(&[]));
+        assert_eq!(String::from_utf8(csv).unwrap().lines().count(), 3);
+    }
+
+    #[test]
+    fn test_balances_watch_addresses() {
+        let nonstandard = vec![0x51];
+        let block1 = testutils::block(
+            sha256d::Hash::all_zeros(),
+            0,
+            vec![testutils::coinbase(
+                1,
+                &[
+                    (4000, testutils::p2pkh_script(1)),
+                    (500, testutils::p2pkh_script(2)),
+                    (300, nonstandard.clone()),
+                ],
+            )],
+        );
+        let block2 = testutils::block(
+            block1.header.hash,
+            600,
+            vec![
+                testutils::coinbase(2, &[(1000, testutils::p2pkh_script(1))]),
+                testutils::tx(
+                    &[(block1.txs[0].hash, 1)],
+                    &[(400, testutils::p2pkh_script(3))],
+                ),
+            ],
+        );
+        let watched = block1.txs[0].value.outputs[0]
+            .script
+            .address
+            .clone()
+            .unwrap();
+        let bucket = common::nonstandard_bucket(&nonstandard);
+
+        let dump_folder = tempfile::tempdir().unwrap();
+        let watch_file = dump_folder.path().join("watch.txt");
+        fs::write(&watch_file, format!("{}\n{}\n", watched, bucket)).unwrap();
+        let matches = Balances::build_subcommand().get_matches_from([
+            "balances",
+            dump_folder.path().to_str().unwrap(),
+            "--bucket-nonstandard",
+            "--watch-addresses",
+            watch_file.to_str().unwrap(),
+        ]);
+        let mut cb = Balances::new(&matches).unwrap();
+        cb.on_start(1).unwrap();
+        cb.on_block(&block1, 1).unwrap();
+        cb.on_block(&block2, 2).unwrap();
+        // Only the watched outputs are tracked
+        assert_eq!(cb.unspents.len(), 3);
+        cb.on_complete(2).unwrap();
 
-        fs::rename(
-            self.dump_folder.as_path().join("balances.csv.tmp"),
-            self.dump_folder.as_path().join(format!(
-                "balances-{}-{}.csv",
-                self.start_height, self.end_height
-            )),
+        let csv = fs::read_to_string(dump_folder.path().join("balances-1-2.csv")).unwrap();
+        let rows: HashMap<&str, u64> = csv
+            .lines()
+            .skip(1)
+            .map(|line| {
+                let (address, balance) = line.split_once(';').unwrap();
+                (address, balance.parse().unwrap())
+            })
+            .collect();
+        assert_eq!(
+            rows,
+            HashMap::from([(watched.as_str(), 5000), (bucket.as_str(), 300)])
+        );
+        assert_eq!(cb.summary()["lost_value"], 0);
+    }
+
+    #[test]
+    fn test_balances_load_utxo_snapshot() {
+        use crate::blockchain::parser::snapshot::tests::write_snapshot;
+        use crate::blockchain::proto::script;
+
+        let txid = sha256d::Hash::hash(&[0x01]);
+        let base = sha256d::Hash::hash(&[0x02]);
+        let snapshot = write_snapshot(
+            &base,
+            &[
+                (
+                    TxOutpoint::new(txid, 0),
+                    5,
+                    5000,
+                    testutils::p2pkh_script(1),
+                ),
+                (
+                    TxOutpoint::new(txid, 1),
+                    5,
+                    3000,
+                    testutils::p2pkh_script(2),
+                ),
+            ],
+        );
+        let block = testutils::block(
+            base,
+            600,
+            vec![
+                testutils::coinbase(11, &[(5000000000, testutils::p2pkh_script(3))]),
+                testutils::tx(&[(txid, 0)], &[(4000, testutils::p2pkh_script(4))]),
+            ],
+        );
+
+        let dump_folder = tempfile::tempdir().unwrap();
+        let snapshot_path = dump_folder.path().join("utxo.dat");
+        fs::write(&snapshot_path, snapshot).unwrap();
+        let matches = Balances::build_subcommand().get_matches_from([
+            "balances",
+            dump_folder.path().to_str().unwrap(),
+            "--load-utxo-snapshot",
+            snapshot_path.to_str().unwrap(),
+        ]);
+        let mut cb = Balances::new(&matches).unwrap();
+        assert_eq!(cb.unspents.len(), 2);
+        cb.on_start(11).unwrap();
+        cb.on_block(&block, 11).unwrap();
+        cb.on_complete(11).unwrap();
+
+        let address = |seed: u8| {
+            script::eval_from_bytes(
+                &testutils::p2pkh_script(seed),
+                cb.coin.version_id,
+                cb.coin.address_encoder.as_ref(),
+            )
+            .address
+            .unwrap()
+        };
+        let csv = fs::read_to_string(dump_folder.path().join("balances-11-11.csv")).unwrap();
+        let rows: HashMap<String, u64> = csv
+            .lines()
+            .skip(1)
+            .map(|line| {
+                let (address, balance) = line.split_once(';').unwrap();
+                (String::from(address), balance.parse().unwrap())
+            })
+            .collect();
+        assert_eq!(
+            rows,
+            HashMap::from([
+                (address(2), 3000),
+                (address(3), 5000000000),
+                (address(4), 4000)
+            ])
+        );
+        assert_eq!(cb.summary()["lost_value"], 1000);
+
+        // The first block must follow the base block of the snapshot
+        let mut cb = Balances::new(&matches).unwrap();
+        cb.on_start(1).unwrap();
+        let other = testutils::block(
+            sha256d::Hash::all_zeros(),
+            0,
+            vec![testutils::coinbase(
+                1,
+                &[(5000, testutils::p2pkh_script(5))],
+            )],
+        );
+        assert!(cb.on_block(&other, 1).is_err());
+    }
+
+    #[test]
+    fn test_balances_lost_summary_interval() {
+        let dump_folder = tempfile::tempdir().unwrap();
+        let dump_folder = dump_folder.path().to_str().unwrap();
+        let cb = Balances::new(&Balances::build_subcommand().get_matches_from([
+            "balances",
+            dump_folder,
+            "--lost-summary-interval",
+            "10",
+        ]))
+        .unwrap();
+        let heights: Vec<u64> = (0..35).filter(|h| cb.is_summary_height(*h)).collect();
+        assert_eq!(heights, vec![9, 19, 29]);
+
+        let cb = Balances::new(
+            &Balances::build_subcommand().get_matches_from(["balances", dump_folder]),
         )
-        .expect("Unable to rename tmp file!");
+        .unwrap();
+        assert!(!cb.is_summary_height(9));
+        assert!(cb.is_summary_height(9999));
 
-        info!(target: "callback", "Done.\nDumped {} addresses.", balances.len());
-        println!("lost_value: {}",self.lost_value);
-        Ok(())
+        let cb = Balances::new(&Balances::build_subcommand().get_matches_from([
+            "balances",
+            dump_folder,
+            "--lost-summary-interval",
+            "0",
+        ]))
+        .unwrap();
+        assert!((0..20000).all(|h| !cb.is_summary_height(h)));
+    }
+
+    #[test]
+    fn test_balances_lost_log_overlap() {
+        // Each miner claims 1 BTC less than the block reward
+        let mut blocks: Vec<Block> = Vec::new();
+        for height in 0..4 {
+            let prev_hash = blocks
+                .last()
+                .map(|b| b.header.hash)
+                .unwrap_or(sha256d::Hash::all_zeros());
+            let txs = vec![testutils::coinbase(
+                height,
+                &[(4900000000, testutils::p2pkh_script(0))],
+            )];
+            blocks.push(testutils::block(prev_hash, height as u32 * 600, txs));
+        }
+
+        let dump_folder = tempfile::tempdir().unwrap();
+        let run = |start: u64, end: u64| {
+            let matches = Balances::build_subcommand()
+                .get_matches_from(["balances", dump_folder.path().to_str().unwrap()]);
+            let mut cb = Balances::new(&matches).unwrap();
+            cb.on_start(start).unwrap();
+            for height in start..=end {
+                cb.on_block(&blocks[height as usize], height).unwrap();
+            }
+            cb.on_complete(end).unwrap();
+        };
+        run(0, 2);
+        // Overlaps with the first run at height 1 and 2
+        run(1, 3);
+
+        let csv = fs::read_to_string(dump_folder.path().join(LOST_LOG)).unwrap();
+        let heights: Vec<&str> = csv
+            .lines()
+            .skip(1)
+            .map(|line| line.split(';').next().unwrap())
+            .collect();
+        assert_eq!(heights, vec!["0", "1", "2", "3"]);
+        assert!(csv.lines().skip(1).all(|line| line.ends_with(";100000000")));
+    }
+
+    #[test]
+    fn test_balances_lost_value() {
+        let block1 = testutils::block(
+            sha256d::Hash::all_zeros(),
+            0,
+            vec![testutils::coinbase(
+                1,
+                &[(5000000000, testutils::p2pkh_script(1))],
+            )],
+        );
+        // Burns 1000 sat via OP_RETURN, destroys 2000 sat and pays 3000 sat fee. The coinbase
+        // claims 4900001000 sat, which is all accounted to the subsidy
+        let spend = testutils::tx(
+            &[(block1.txs[0].hash, 0)],
+            &[
+                (4999994000, testutils::p2pkh_script(2)),
+                (1000, vec![0x6a, 0x01, 0x00]),
+                (2000, vec![0xff, 0x51]),
+            ],
+        );
+        let block2 = testutils::block(
+            block1.header.hash,
+            600,
+            vec![
+                testutils::coinbase(2, &[(4900001000, testutils::p2pkh_script(3))]),
+                spend,
+            ],
+        );
+        // The same txid again overwrites the unspent coinbase of block 2 (BIP30)
+        let block3 = testutils::block(
+            block2.header.hash,
+            1200,
+            vec![testutils::coinbase(
+                2,
+                &[(4900001000, testutils::p2pkh_script(3))],
+            )],
+        );
+
+        let dump_folder = tempfile::tempdir().unwrap();
+        let matches = Balances::build_subcommand()
+            .get_matches_from(["balances", dump_folder.path().to_str().unwrap()]);
+        let mut cb = Balances::new(&matches).unwrap();
+        cb.on_start(1).unwrap();
+        cb.on_block(&block1, 1).unwrap();
+        cb.on_block(&block2, 2).unwrap();
+        cb.on_block(&block3, 3).unwrap();
+        cb.on_complete(3).unwrap();
+
+        let csv = fs::read_to_string(dump_folder.path().join(LOST_LOG)).unwrap();
+        assert_eq!(
+            csv.lines().collect::<Vec<_>>(),
+            vec![
+                LOST_LOG_HEADER,
+                "2;5000000000;5000000000;9899998000;99999000;3000;1000;2000;0;100005000",
+                "3;5000000000;0;4900001000;99999000;0;0;0;4900001000;5000000000",
+            ]
+        );
+        assert_eq!(cb.summary()["unclaimed_subsidy"], 199998000);
+        assert_eq!(cb.summary()["unclaimed_fees"], 3000);
+        assert_eq!(cb.summary()["burned"], 1000);
+        assert_eq!(cb.summary()["destroyed"], 2000);
+        assert_eq!(cb.summary()["duplicate_coinbase"], 4900001000u64);
+        assert_eq!(cb.summary()["lost_value"], 5100005000u64);
+    }
+
+    #[test]
+    fn test_balances_parquet() {
+        let block = testutils::block(
+            sha256d::Hash::all_zeros(),
+            0,
+            vec![testutils::coinbase(
+                1,
+                &[(5000000000, testutils::p2pkh_script(1))],
+            )],
+        );
+        let address = block.txs[0].value.outputs[0]
+            .script
+            .address
+            .clone()
+            .unwrap();
+
+        let dump_folder = tempfile::tempdir().unwrap();
+        let matches = Balances::build_subcommand().get_matches_from([
+            "balances",
+            dump_folder.path().to_str().unwrap(),
+            "--format",
+            "parquet",
+        ]);
+        let mut cb = Balances::new(&matches).unwrap();
+        cb.on_start(1).unwrap();
+        cb.on_block(&block, 1).unwrap();
+        cb.on_interval(1).unwrap();
+        cb.on_complete(1).unwrap();
+
+        // Without csv header, the snapshots are Parquet files as well
+        let rows = vec![vec![address, String::from("5000000000")]];
+        for name in ["balances-1-1.parquet", "balances-partial-1-1.parquet"] {
+            let path = dump_folder.path().join(name);
+            assert_eq!(crate::common::parquet::reader::read_rows(&path), rows);
+        }
+        assert!(!dump_folder.path().join("balances.parquet.tmp").exists());
+    }
+
+    #[test]
+    fn test_balances_gzip() {
+        let block = testutils::block(
+            sha256d::Hash::all_zeros(),
+            0,
+            vec![testutils::coinbase(
+                1,
+                &[
+                    (4000000000, testutils::p2pkh_script(1)),
+                    (1000000000, testutils::p2pkh_script(1)),
+                ],
+            )],
+        );
+        let address = block.txs[0].value.outputs[0]
+            .script
+            .address
+            .clone()
+            .unwrap();
+
+        let dump_folder = tempfile::tempdir().unwrap();
+        let matches = Balances::build_subcommand().get_matches_from([
+            "balances",
+            dump_folder.path().to_str().unwrap(),
+            "--gzip",
+        ]);
+        let mut cb = Balances::new(&matches).unwrap();
+        cb.on_start(1).unwrap();
+        cb.on_block(&block, 1).unwrap();
+        cb.on_complete(1).unwrap();
+
+        assert!(!dump_folder.path().join("balances.csv.gz.tmp").exists());
+        let file = File::open(dump_folder.path().join("balances-1-1.csv.gz")).unwrap();
+        let mut csv = String::new();
+        flate2::read::GzDecoder::new(file)
+            .read_to_string(&mut csv)
+            .unwrap();
+        assert_eq!(csv, format!("address;balance\n{};5000000000\n", address));
+        assert_eq!(
+            cb.summary(),
+            json!({
+                "addresses": 1,
+                "snapshots": 0,
+                "lost_value": 0,
+                "unclaimed_subsidy": 0,
+                "unclaimed_fees": 0,
+                "burned": 0,
+                "destroyed": 0,
+                "duplicate_coinbase": 0,
+            })
+        );
+    }
+
+    #[test]
+    fn test_balances_snapshots() {
+        let block1 = testutils::block(
+            sha256d::Hash::all_zeros(),
+            0,
+            vec![testutils::coinbase(
+                1,
+                &[(5000000000, testutils::p2pkh_script(1))],
+            )],
+        );
+        let block2 = testutils::block(
+            block1.header.hash,
+            600,
+            vec![
+                testutils::coinbase(2, &[(5000000000, testutils::p2pkh_script(2))]),
+                testutils::tx(
+                    &[(block1.txs[0].hash, 0)],
+                    &[(5000000000, testutils::p2pkh_script(3))],
+                ),
+            ],
+        );
+        let block3 = testutils::block(
+            block2.header.hash,
+            1200,
+            vec![testutils::coinbase(
+                3,
+                &[(5000000000, testutils::p2pkh_script(1))],
+            )],
+        );
+        let address = |seed: u8| {
+            let block = testutils::block(
+                sha256d::Hash::all_zeros(),
+                0,
+                vec![testutils::coinbase(
+                    1,
+                    &[(0, testutils::p2pkh_script(seed))],
+                )],
+            );
+            block.txs[0].value.outputs[0]
+                .script
+                .address
+                .clone()
+                .unwrap()
+        };
+
+        let dump_folder = tempfile::tempdir().unwrap();
+        let matches = Balances::build_subcommand().get_matches_from([
+            "balances",
+            dump_folder.path().to_str().unwrap(),
+            "--sort",
+            "address",
+            "--snapshot-every",
+            "2",
+            "--snapshot-at",
+            "1",
+        ]);
+        let mut cb = Balances::new(&matches).unwrap();
+        cb.on_start(1).unwrap();
+        cb.on_block(&block1, 1).unwrap();
+        cb.on_block(&block2, 2).unwrap();
+        cb.on_block(&block3, 3).unwrap();
+        cb.on_complete(3).unwrap();
+
+        let rows = |name: &str| {
+            let csv = fs::read_to_string(dump_folder.path().join(name)).unwrap();
+            let mut rows: Vec<String> = csv.lines().skip(1).map(String::from).collect();
+            rows.sort();
+            rows
+        };
+        let row = |seed: u8, balance: u64| format!("{};{}", address(seed), balance);
+        assert_eq!(rows("balances-1-1.csv"), vec![row(1, 5000000000)]);
+        let mut expected = vec![row(2, 5000000000), row(3, 5000000000)];
+        expected.sort();
+        assert_eq!(rows("balances-1-2.csv"), expected);
+        expected.push(row(1, 5000000000));
+        expected.sort();
+        assert_eq!(rows("balances-1-3.csv"), expected);
+        assert!(!dump_folder
+            .path()
+            .join("balances-snapshot.csv.tmp")
+            .exists());
+        assert_eq!(cb.summary()["snapshots"], 2);
     }
 }
@@ -0,0 +1,297 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use bitcoin::hashes::{sha256d, Hash};
+use clap::{Arg, ArgMatches, Command};
+use serde_json::{json, Value};
+
+use crate::blockchain::proto::block::Block;
+use crate::blockchain::proto::tx::OutpointKey;
+use crate::callbacks::{common, Callback, Control};
+use crate::common::address::CompactAddress;
+use crate::common::{hashing, utils};
+use crate::errors::OpResult;
+
+/// Domain separation of leaves and inner nodes, so a leaf can't be passed off as a node
+const LEAF_PREFIX: u8 = 0x00;
+const NODE_PREFIX: u8 = 0x01;
+
+/// `SHA256d(0x00 || u16 LE address length || address || u64 LE balance)`
+pub fn leaf_hash(address: &str, balance: u64) -> sha256d::Hash {
+    let mut data = Vec::with_capacity(1 + 2 + address.len() + 8);
+    data.push(LEAF_PREFIX);
+    data.extend((address.len() as u16).to_le_bytes());
+    data.extend(address.as_bytes());
+    data.extend(balance.to_le_bytes());
+    hashing::sha256d(&data)
+}
+
+/// `SHA256d(0x01 || left || right)`
+fn node_hash(left: &sha256d::Hash, right: &sha256d::Hash) -> sha256d::Hash {
+    let mut data = [0u8; 65];
+    data[0] = NODE_PREFIX;
+    data[1..33].copy_from_slice(left.as_byte_array());
+    data[33..].copy_from_slice(right.as_byte_array());
+    hashing::sha256d(&data)
+}
+
+/// Builds all levels from the leaves up to the root. An odd node at the end of a level is
+/// moved up unchanged instead of being paired with itself, so no two leaf sets share a root.
+fn tree_levels(leaves: Vec<sha256d::Hash>) -> Vec<Vec<sha256d::Hash>> {
+    let mut levels = vec![leaves];
+    while levels.last().unwrap().len() > 1 {
+        let level = levels
+            .last()
+            .unwrap()
+            .chunks(2)
+            .map(|pair| match pair {
+                [left, right] => node_hash(left, right),
+                [single] => *single,
+                _ => unreachable!(),
+            })
+            .collect();
+        levels.push(level);
+    }
+    levels
+}
+
+/// Sibling hashes from the leaf up to the root, flagged with whether the sibling is on the left
+pub fn inclusion_proof(leaves: Vec<sha256d::Hash>, index: usize) -> Vec<(sha256d::Hash, bool)> {
+    let mut proof = Vec::new();
+    let mut index = index;
+    for level in tree_levels(leaves).iter().filter(|level| level.len() > 1) {
+        let sibling = index ^ 1;
+        if sibling < level.len() {
+            proof.push((level[sibling], sibling < index));
+        }
+        index /= 2;
+    }
+    proof
+}
+
+/// Checks that the balance of the address is part of the tree with the given root
+pub fn verify_proof(
+    root: &sha256d::Hash,
+    address: &str,
+    balance: u64,
+    proof: &[(sha256d::Hash, bool)],
+) -> bool {
+    let hash = proof
+        .iter()
+        .fold(leaf_hash(address, balance), |hash, (sibling, is_left)| {
+            if *is_left {
+                node_hash(sibling, &hash)
+            } else {
+                node_hash(&hash, sibling)
+            }
+        });
+    hash == *root
+}
+
+/// Dumps all addresses with non-zero balance, sorted by address, together with the root of a
+/// Merkle tree over them. Leaf i of the tree is row i of the csv file, see `leaf_hash()` and
+/// `tree_levels()`, so inclusion proofs for any address can be built from the dump.
+pub struct BalanceTree {
+    dump_folder: PathBuf,
+
+    // key: txid + index
+    unspents: HashMap<OutpointKey, common::UnspentValue>,
+    root: Option<sha256d::Hash>,
+
+    start_height: u64,
+    n_addresses: u64,
+}
+
+impl Callback for BalanceTree {
+    fn build_subcommand() -> Command
+    where
+        Self: Sized,
+    {
+        Command::new("balancetree")
+            .about("Dumps all addresses with non-zero balance along with the root of a Merkle tree over them")
+            .version("0.1")
+            .author("gcarq <egger.m@protonmail.com>")
+            .arg(
+                Arg::new("dump-folder")
+                    .help("Folder to store the csv and root file")
+                    .index(1)
+                    .required(true),
+            )
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let cb = BalanceTree {
+            dump_folder: PathBuf::from(matches.get_one::<String>("dump-folder").unwrap()),
+            unspents: HashMap::with_capacity(10000000),
+            root: None,
+            start_height: 0,
+            n_addresses: 0,
+        };
+        Ok(cb)
+    }
+
+    fn on_start(&mut self, block_height: u64) -> OpResult<()> {
+        self.start_height = block_height;
+        info!(target: "callback", "Executing balancetree with dump folder: {} ...", &self.dump_folder.display());
+        Ok(())
+    }
+
+    fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<Control> {
+        for tx in &block.txs {
+            common::remove_unspents(tx, &mut self.unspents);
+            common::insert_unspents(tx, block_height, &mut self.unspents);
+        }
+        Ok(Control::Continue)
+    }
+
+    fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+        let mut sums: HashMap<&CompactAddress, u64> = HashMap::new();
+        for unspent in self.unspents.values() {
+            if unspent.address.is_empty() || unspent.value == 0 {
+                continue;
+            }
+            *sums.entry(&unspent.address).or_insert(0) += unspent.value;
+        }
+        let balances: BTreeMap<String, u64> = sums
+            .into_iter()
+            .map(|(address, balance)| (address.to_string(), balance))
+            .collect();
+
+        let tmp_path = self.dump_folder.join("balancetree.csv.tmp");
+        let mut writer = BufWriter::with_capacity(4000000, File::create(&tmp_path)?);
+        writer.write_all(format!("{};{}\n", "address", "balance").as_bytes())?;
+        for (address, balance) in &balances {
+            writer.write_all(format!("{};{}\n", address, balance).as_bytes())?;
+        }
+        writer.flush()?;
+        drop(writer);
+        utils::rename(
+            tmp_path,
+            utils::output_path(
+                &self.dump_folder,
+                "balancetree",
+                self.start_height,
+                block_height,
+                "csv",
+            ),
+        )?;
+
+        let leaves = balances
+            .iter()
+            .map(|(address, balance)| leaf_hash(address, *balance))
+            .collect::<Vec<_>>();
+        self.n_addresses = leaves.len() as u64;
+        self.root = tree_levels(leaves).last().unwrap().first().copied();
+        let root = json!({
+            "root": self.root.map(|root| utils::arr_to_hex(root.as_byte_array())),
+            "leaves": self.n_addresses,
+            "height": block_height,
+        });
+        fs::write(
+            utils::output_path(
+                &self.dump_folder,
+                "balancetree",
+                self.start_height,
+                block_height,
+                "json",
+            ),
+            serde_json::to_string_pretty(&root).unwrap(),
+        )?;
+
+        info!(target: "callback", "Done.\nBuilt tree over {} addresses at height {} with root {}.",
+             self.n_addresses, block_height, root["root"]);
+        Ok(())
+    }
+
+    fn dump_folder(&self) -> Option<&Path> {
+        Some(&self.dump_folder)
+    }
+
+    fn supports_incremental(&self) -> bool {
+        true
+    }
+
+    fn summary(&self) -> Value {
+        json!({
+            "addresses": self.n_addresses,
+            "root": self.root.map(|root| utils::arr_to_hex(root.as_byte_array())),
+        })
+    }
+
+    fn save_state(&self, folder: &Path) -> OpResult<()> {
+        common::save_unspents(&folder.join("balancetree.unspents"), &self.unspents)
+    }
+
+    fn load_state(&mut self, folder: &Path) -> OpResult<()> {
+        self.unspents = common::load_unspents(&folder.join("balancetree.unspents"))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::testutils;
+
+    #[test]
+    fn test_balancetree_proof() {
+        let outputs = (0..7)
+            .map(|i| (1000 + i as u64, testutils::p2pkh_script(i)))
+            .collect::<Vec<(u64, Vec<u8>)>>();
+        let block = testutils::block(
+            sha256d::Hash::all_zeros(),
+            0,
+            vec![testutils::coinbase(1, &outputs)],
+        );
+
+        let dump_folder = tempfile::tempdir().unwrap();
+        let matches = BalanceTree::build_subcommand()
+            .get_matches_from(["balancetree", dump_folder.path().to_str().unwrap()]);
+        let mut cb = BalanceTree::new(&matches).unwrap();
+        cb.on_start(1).unwrap();
+        cb.on_block(&block, 1).unwrap();
+        cb.on_complete(1).unwrap();
+
+        // Rebuild the proofs from the published files only
+        let root: Value = serde_json::from_str(
+            &fs::read_to_string(dump_folder.path().join("balancetree-1-1.json")).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(root["leaves"], 7);
+        let root_bytes = utils::hex_to_vec(root["root"].as_str().unwrap());
+        let root = sha256d::Hash::from_slice(&root_bytes).unwrap();
+        let csv = fs::read_to_string(dump_folder.path().join("balancetree-1-1.csv")).unwrap();
+        let rows = csv
+            .lines()
+            .skip(1)
+            .map(|line| {
+                let (address, balance) = line.split_once(';').unwrap();
+                (String::from(address), balance.parse::<u64>().unwrap())
+            })
+            .collect::<Vec<_>>();
+        let leaves = rows
+            .iter()
+            .map(|(address, balance)| leaf_hash(address, *balance))
+            .collect::<Vec<_>>();
+
+        // The last leaf is carried up unpaired
+        for index in [2, 6] {
+            let (address, balance) = &rows[index];
+            let proof = inclusion_proof(leaves.clone(), index);
+            assert!(verify_proof(&root, address, *balance, &proof));
+            assert!(!verify_proof(&root, address, balance + 1, &proof));
+        }
+        let (address, balance) = &rows[2];
+        assert!(!verify_proof(
+            &root,
+            address,
+            *balance,
+            &inclusion_proof(leaves, 3)
+        ));
+    }
+}
@@ -0,0 +1,488 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use bitcoin::bip158::GcsFilterWriter;
+use bitcoin::hashes::{sha256d, Hash};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use clap::{Arg, ArgMatches, Command};
+use serde_json::{json, Value};
+
+use crate::blockchain::proto::block::Block;
+use crate::blockchain::proto::tx::{OutpointKey, TxOutpoint};
+use crate::blockchain::proto::varuint::VarUint;
+use crate::blockchain::proto::ToRaw;
+use crate::callbacks::{common, Callback, Control, Metrics};
+use crate::common::utils;
+use crate::errors::{OpError, OpErrorKind, OpResult};
+
+/// Golomb-Rice parameter and false positive rate of the BIP158 basic filter
+const FILTER_P: u8 = 19;
+const FILTER_M: u64 = 784931;
+/// Like Core, a new fltr file is started once an entry would exceed 16 MiB
+const MAX_FLTR_FILE_SIZE: u64 = 0x1000000;
+const STATE_FILE: &str = "blockfilters.state";
+
+/// Computes the BIP158 basic filter of each block. The filters are appended to
+/// `indexes/blockfilter/basic/fltrNNNNN.dat` like Core's blockfilter index, while the position,
+/// filter hash and filter header of each block are written to a CSV file instead of Core's LevelDB.
+pub struct BlockFilters {
+    dump_folder: PathBuf,
+    writer: common::DumpWriter,
+    filter_writer: Option<BufWriter<File>>,
+
+    // key: txid + index, value: scriptPubKey. Stays empty with `--undo`
+    unspents: HashMap<OutpointKey, Vec<u8>>,
+    undo: bool,
+
+    prev_header: sha256d::Hash,
+    has_prev_header: bool,
+    file_num: u32,
+    file_pos: u64,
+
+    start_height: u64,
+    n_filters: u64,
+    n_unknown_inputs: u64,
+    filter_bytes: u64,
+}
+
+impl BlockFilters {
+    fn filter_dir(&self) -> PathBuf {
+        self.dump_folder.join("indexes/blockfilter/basic")
+    }
+
+    fn state_path(folder: &Path) -> PathBuf {
+        folder.join(STATE_FILE)
+    }
+
+    /// Encodes the basic filter of a block with the given elements, see BIP158.
+    /// Empty elements are skipped and duplicates are only added once.
+    pub fn basic_filter<'a, I>(block_hash: &sha256d::Hash, elements: I) -> OpResult<Vec<u8>>
+    where
+        I: IntoIterator<Item = &'a [u8]>,
+    {
+        let hash = block_hash.as_byte_array();
+        let k0 = u64::from_le_bytes(hash[0..8].try_into().unwrap());
+        let k1 = u64::from_le_bytes(hash[8..16].try_into().unwrap());
+        let mut filter = Vec::new();
+        let mut writer = GcsFilterWriter::new(&mut filter, k0, k1, FILTER_M, FILTER_P);
+        for element in elements {
+            writer.add_element(element);
+        }
+        writer.finish()?;
+        Ok(filter)
+    }
+
+    /// Returns the filter hash and the filter header, which commits to all previous filters
+    pub fn filter_header(
+        filter: &[u8],
+        prev_header: &sha256d::Hash,
+    ) -> (sha256d::Hash, sha256d::Hash) {
+        let filter_hash = sha256d::Hash::hash(filter);
+        let mut preimage = filter_hash.to_byte_array().to_vec();
+        preimage.extend(prev_header.as_byte_array());
+        (filter_hash, sha256d::Hash::hash(&preimage))
+    }
+
+    /// Collects the output scripts, except OP_RETURN ones, and the scripts of all spent outputs
+    fn filter_elements(&mut self, block: &Block) -> Vec<Vec<u8>> {
+        let mut elements = Vec::new();
+        for (tx_index, tx) in block.txs.iter().enumerate() {
+            if let Some(undo) = &block.undo {
+                if let Some(spent) = tx_index.checked_sub(1).and_then(|i| undo.txs.get(i)) {
+                    elements.extend(spent.iter().map(|s| s.script_pubkey.clone()));
+                }
+            } else if !tx.value.is_coinbase() {
+                for input in &tx.value.inputs {
+                    match self.unspents.remove(&input.outpoint.key()) {
+                        Some(script) => elements.push(script),
+                        None => self.n_unknown_inputs += 1,
+                    }
+                }
+            }
+            for (i, output) in tx.value.outputs.iter().enumerate() {
+                let script = &output.out.script_pubkey;
+                if script.is_empty() || script[0] == 0x6a {
+                    continue;
+                }
+                elements.push(script.clone());
+                if !self.undo {
+                    self.unspents
+                        .insert(TxOutpoint::new(tx.hash, i as u32).key(), script.clone());
+                }
+            }
+        }
+        elements
+    }
+
+    /// Opens the current fltr file, dropping whatever has been appended after the last saved state
+    fn open_filter_file(&self) -> OpResult<BufWriter<File>> {
+        let path = self
+            .filter_dir()
+            .join(format!("fltr{:05}.dat", self.file_num));
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        file.set_len(self.file_pos)?;
+        Ok(BufWriter::with_capacity(4000000, file))
+    }
+
+    /// Appends the block hash and the filter to the fltr files, returns the file number and offset
+    fn append_filter(&mut self, block_hash: &sha256d::Hash, filter: &[u8]) -> OpResult<(u32, u64)> {
+        let len = VarUint::minimal(filter.len() as u64).to_bytes();
+        let size = (32 + len.len() + filter.len()) as u64;
+        if self.file_pos > 0 && self.file_pos + size > MAX_FLTR_FILE_SIZE {
+            if let Some(mut writer) = self.filter_writer.take() {
+                writer.flush()?;
+            }
+            self.file_num += 1;
+            self.file_pos = 0;
+        }
+        if self.filter_writer.is_none() {
+            self.filter_writer = Some(self.open_filter_file()?);
+        }
+        let writer = self.filter_writer.as_mut().unwrap();
+        writer.write_all(block_hash.as_byte_array())?;
+        writer.write_all(&len)?;
+        writer.write_all(filter)?;
+        let pos = (self.file_num, self.file_pos);
+        self.file_pos += size;
+        Ok(pos)
+    }
+}
+
+impl Callback for BlockFilters {
+    fn build_subcommand() -> Command
+    where
+        Self: Sized,
+    {
+        Command::new("blockfilters")
+            .about("Computes the BIP158 basic filter of each block and writes them like Core's blockfilter index")
+            .version("0.1")
+            .author("gcarq <egger.m@protonmail.com>")
+            .arg(
+                Arg::new("dump-folder")
+                    .help("Folder to store the filter and csv files")
+                    .index(1)
+                    .required(true),
+            )
+            .arg(common::undo_arg())
+            .arg(
+                Arg::new("prev-header")
+                    .long("prev-header")
+                    .value_name("HASH")
+                    .help("Filter header of the block before the start height, as shown by getblockfilter"),
+            )
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let dump_folder = &PathBuf::from(matches.get_one::<String>("dump-folder").unwrap());
+        let undo = matches.get_flag("undo");
+        let prev_header = match matches.get_one::<String>("prev-header") {
+            Some(hex) => Some(hex.parse::<sha256d::Hash>().map_err(|e| {
+                OpError::new(OpErrorKind::InvalidArgsError)
+                    .join_msg(&format!("Invalid filter header `{}`: {}", hex, e))
+            })?),
+            None => None,
+        };
+        let cb = BlockFilters {
+            dump_folder: PathBuf::from(dump_folder),
+            writer: common::create_writer(dump_folder.join("blockfilters.csv.tmp"))?,
+            filter_writer: None,
+            unspents: HashMap::with_capacity(if undo { 0 } else { 10000000 }),
+            undo,
+            prev_header: prev_header.unwrap_or_else(sha256d::Hash::all_zeros),
+            has_prev_header: prev_header.is_some(),
+            file_num: 0,
+            file_pos: 0,
+            start_height: 0,
+            n_filters: 0,
+            n_unknown_inputs: 0,
+            filter_bytes: 0,
+        };
+        Ok(cb)
+    }
+
+    fn on_start(&mut self, block_height: u64) -> OpResult<()> {
+        self.start_height = block_height;
+        if block_height > 0 && !self.undo {
+            warn!(target: "callback", "Filters of blocks spending outputs below height {} are incomplete!", block_height);
+        }
+        if block_height > 0 && !self.has_prev_header {
+            warn!(target: "callback", "No filter header of block {} given, the filter headers won't match Core!", block_height - 1);
+        }
+        fs::create_dir_all(self.filter_dir())?;
+        info!(target: "callback", "Executing blockfilters with dump folder: {} ...", &self.dump_folder.display());
+        self.writer.write_all(
+            format!(
+                "{};{};{};{};{};{};{}\n",
+                "height", "blockHash", "filterHash", "filterHeader", "file", "offset", "size"
+            )
+            .as_bytes(),
+        )?;
+        Ok(())
+    }
+
+    fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<Control> {
+        let elements = self.filter_elements(block);
+        let filter =
+            BlockFilters::basic_filter(&block.header.hash, elements.iter().map(|e| e.as_slice()))?;
+        let (filter_hash, header) = BlockFilters::filter_header(&filter, &self.prev_header);
+        let (file_num, offset) = self.append_filter(&block.header.hash, &filter)?;
+        self.writer.write_all(
+            format!(
+                "{};{};{};{};{};{};{}\n",
+                block_height,
+                utils::hash_to_string(&block.header.hash),
+                utils::hash_to_string(&filter_hash),
+                utils::hash_to_string(&header),
+                file_num,
+                offset,
+                filter.len()
+            )
+            .as_bytes(),
+        )?;
+        self.prev_header = header;
+        self.has_prev_header = true;
+        self.n_filters += 1;
+        self.filter_bytes += filter.len() as u64;
+        Ok(Control::Continue)
+    }
+
+    fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+        self.flush()?;
+        utils::rename(
+            self.dump_folder.as_path().join("blockfilters.csv.tmp"),
+            utils::output_path(
+                &self.dump_folder,
+                "blockfilters",
+                self.start_height,
+                block_height,
+                "csv",
+            ),
+        )?;
+
+        info!(target: "callback", "Done.\nComputed {} filters with {} bytes, last filter header: {}",
+              self.n_filters, self.filter_bytes, utils::hash_to_string(&self.prev_header));
+        if self.n_unknown_inputs > 0 {
+            warn!(target: "callback", "Scripts of {} spent outputs are unknown and missing in the filters!", self.n_unknown_inputs);
+        }
+        Ok(())
+    }
+
+    fn needs_undo(&self) -> bool {
+        self.undo
+    }
+
+    fn flush(&mut self) -> OpResult<()> {
+        self.writer.flush()?;
+        if let Some(writer) = self.filter_writer.as_mut() {
+            writer.flush()?;
+        }
+        Ok(())
+    }
+
+    fn dump_folder(&self) -> Option<&Path> {
+        Some(&self.dump_folder)
+    }
+
+    fn supports_incremental(&self) -> bool {
+        true
+    }
+
+    fn metrics(&self) -> Option<&dyn Metrics> {
+        Some(self)
+    }
+
+    fn summary(&self) -> Value {
+        json!({
+            "filters": self.n_filters,
+            "filter_bytes": self.filter_bytes,
+            "unknown_inputs": self.n_unknown_inputs,
+            "filter_header": utils::hash_to_string(&self.prev_header),
+        })
+    }
+
+    /// Stores the last filter header, the end of the fltr files and the tracked scripts
+    fn save_state(&self, folder: &Path) -> OpResult<()> {
+        let path = BlockFilters::state_path(folder);
+        let tmp_path = path.with_extension("tmp");
+        let mut writer = BufWriter::with_capacity(4000000, File::create(&tmp_path)?);
+        writer.write_all(self.prev_header.as_byte_array())?;
+        writer.write_u32::<LittleEndian>(self.file_num)?;
+        writer.write_u64::<LittleEndian>(self.file_pos)?;
+        writer.write_u64::<LittleEndian>(self.unspents.len() as u64)?;
+        for (key, script) in &self.unspents {
+            writer.write_all(key)?;
+            writer.write_u32::<LittleEndian>(script.len() as u32)?;
+            writer.write_all(script)?;
+        }
+        writer.flush()?;
+        drop(writer);
+        utils::rename(&tmp_path, path)?;
+        Ok(())
+    }
+
+    fn load_state(&mut self, folder: &Path) -> OpResult<()> {
+        let path = BlockFilters::state_path(folder);
+        if !path.exists() {
+            return Ok(());
+        }
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut header = [0u8; 32];
+        reader.read_exact(&mut header)?;
+        self.prev_header = sha256d::Hash::from_byte_array(header);
+        self.has_prev_header = true;
+        self.file_num = reader.read_u32::<LittleEndian>()?;
+        self.file_pos = reader.read_u64::<LittleEndian>()?;
+        let len = reader.read_u64::<LittleEndian>()?;
+        for _ in 0..len {
+            let mut key = [0u8; 36];
+            reader.read_exact(&mut key)?;
+            let mut script = vec![0u8; reader.read_u32::<LittleEndian>()? as usize];
+            reader.read_exact(&mut script)?;
+            self.unspents.insert(key, script);
+        }
+        Ok(())
+    }
+}
+
+impl Metrics for BlockFilters {
+    fn counters(&self) -> Vec<(&'static str, u64)> {
+        vec![
+            ("filters", self.n_filters),
+            ("unspents", self.unspents.len() as u64),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::testutils;
+
+    #[test]
+    fn test_basic_filter() {
+        // Testnet genesis block of the BIP158 test vectors
+        let block_hash: sha256d::Hash =
+            "000000000933ea01ad0ee984209779baaec3ced90fa3f408719526f8d77f4943"
+                .parse()
+                .unwrap();
+        let script = utils::hex_to_vec(
+            "4104678afdb0fe5548271967f1a67130b7105cd6a828e03909a67962e0ea1f61deb649f6bc3f4cef38c4f35504e51ec112de5c384df7ba0b8d578a4c702b6bf11d5fac",
+        );
+        let filter = BlockFilters::basic_filter(&block_hash, [script.as_slice()]).unwrap();
+        assert_eq!(utils::arr_to_hex(&filter), "019dfca8");
+        let (_, header) = BlockFilters::filter_header(&filter, &sha256d::Hash::all_zeros());
+        assert_eq!(
+            format!("{}", header),
+            "21584579b7eb08997773e5aeff3a7f932700042d0ed2a6129012b7d7ae81b750"
+        );
+
+        // Empty filters only contain the element count
+        let filter = BlockFilters::basic_filter(&block_hash, []).unwrap();
+        assert_eq!(filter, vec![0]);
+    }
+
+    #[test]
+    fn test_blockfilters() {
+        let block1 = testutils::block(
+            sha256d::Hash::all_zeros(),
+            0,
+            vec![testutils::coinbase(
+                1,
+                &[
+                    (4000, testutils::p2pkh_script(1)),
+                    (0, vec![0x6a, 0x01, 0x01]),
+                ],
+            )],
+        );
+        let spend = testutils::tx(
+            &[(block1.txs[0].hash, 0)],
+            &[(3000, testutils::p2pkh_script(2))],
+        );
+        let block2 = testutils::block(
+            block1.header.hash,
+            600,
+            vec![
+                testutils::coinbase(2, &[(90, testutils::p2pkh_script(3))]),
+                spend,
+            ],
+        );
+
+        let dump_folder = tempfile::tempdir().unwrap();
+        let matches = BlockFilters::build_subcommand()
+            .get_matches_from(["blockfilters", dump_folder.path().to_str().unwrap()]);
+        let mut cb = BlockFilters::new(&matches).unwrap();
+        cb.on_start(0).unwrap();
+        cb.on_block(&block1, 0).unwrap();
+        cb.on_block(&block2, 1).unwrap();
+        cb.on_complete(1).unwrap();
+
+        let filter1 = BlockFilters::basic_filter(
+            &block1.header.hash,
+            [testutils::p2pkh_script(1).as_slice()],
+        )
+        .unwrap();
+        let filter2 = BlockFilters::basic_filter(
+            &block2.header.hash,
+            [
+                testutils::p2pkh_script(1).as_slice(),
+                testutils::p2pkh_script(2).as_slice(),
+                testutils::p2pkh_script(3).as_slice(),
+            ],
+        )
+        .unwrap();
+        let (hash1, header1) = BlockFilters::filter_header(&filter1, &sha256d::Hash::all_zeros());
+        let (hash2, header2) = BlockFilters::filter_header(&filter2, &header1);
+
+        let csv = fs::read_to_string(dump_folder.path().join("blockfilters-0-1.csv")).unwrap();
+        let entry1 = 32 + 1 + filter1.len();
+        assert_eq!(
+            csv.lines().collect::<Vec<_>>(),
+            vec![
+                String::from("height;blockHash;filterHash;filterHeader;file;offset;size"),
+                format!(
+                    "0;{};{};{};0;0;{}",
+                    block1.header.hash,
+                    hash1,
+                    header1,
+                    filter1.len()
+                ),
+                format!(
+                    "1;{};{};{};0;{};{}",
+                    block2.header.hash,
+                    hash2,
+                    header2,
+                    entry1,
+                    filter2.len()
+                ),
+            ]
+        );
+
+        let mut expected = block1.header.hash.to_byte_array().to_vec();
+        expected.push(filter1.len() as u8);
+        expected.extend(&filter1);
+        expected.extend(block2.header.hash.as_byte_array());
+        expected.push(filter2.len() as u8);
+        expected.extend(&filter2);
+        let data = fs::read(
+            dump_folder
+                .path()
+                .join("indexes/blockfilter/basic/fltr00000.dat"),
+        )
+        .unwrap();
+        assert_eq!(data, expected);
+
+        // An incremental run continues the header chain and the fltr file
+        cb.save_state(dump_folder.path()).unwrap();
+        let mut cb = BlockFilters::new(&matches).unwrap();
+        cb.load_state(dump_folder.path()).unwrap();
+        assert_eq!(cb.prev_header, header2);
+        assert_eq!(cb.file_pos, expected.len() as u64);
+        assert_eq!(cb.unspents.len(), 2);
+        assert_eq!(cb.summary()["filters"], 0);
+    }
+}
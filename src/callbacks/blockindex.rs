@@ -0,0 +1,177 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use clap::{Arg, ArgMatches, Command};
+
+use crate::blockchain::proto::block::Block;
+use crate::callbacks::{common, Callback, Control};
+use crate::common::utils;
+use crate::errors::OpResult;
+
+/// Dumps a minimal per-block index (hash, merkle root, size, ...) to build height lookups
+pub struct BlockIndex {
+    dump_folder: PathBuf,
+    writer: common::DumpWriter,
+
+    start_height: u64,
+    n_blocks: u64,
+    total_size: u64,
+}
+
+impl Callback for BlockIndex {
+    fn build_subcommand() -> Command
+    where
+        Self: Sized,
+    {
+        Command::new("blockindex")
+            .about(
+                "Dumps hash, merkle root, timestamp, size and tx count of each block to CSV file",
+            )
+            .version("0.1")
+            .author("gcarq <egger.m@protonmail.com>")
+            .arg(
+                Arg::new("dump-folder")
+                    .help("Folder to store csv file")
+                    .index(1)
+                    .required(true),
+            )
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let dump_folder = &PathBuf::from(matches.get_one::<String>("dump-folder").unwrap());
+        let cb = BlockIndex {
+            dump_folder: PathBuf::from(dump_folder),
+            writer: common::create_writer(dump_folder.join("blockindex.csv.tmp"))?,
+            start_height: 0,
+            n_blocks: 0,
+            total_size: 0,
+        };
+        Ok(cb)
+    }
+
+    fn on_start(&mut self, block_height: u64) -> OpResult<()> {
+        self.start_height = block_height;
+        info!(target: "callback", "Executing blockindex with dump folder: {} ...", &self.dump_folder.display());
+        self.writer.write_all(
+            format!(
+                "{};{};{};{};{};{}\n",
+                "height", "blockHash", "merkleRoot", "timestamp", "size", "txCount"
+            )
+            .as_bytes(),
+        )?;
+        Ok(())
+    }
+
+    /// Only header fields and the serialized size are used, transactions are not inspected
+    fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<Control> {
+        self.writer.write_all(
+            format!(
+                "{};{};{};{};{};{}\n",
+                block_height,
+                utils::hash_to_string(&block.hash()),
+                utils::hash_to_string(&block.header.value.merkle_root),
+                block.header.value.timestamp,
+                block.size,
+                block.tx_count.value
+            )
+            .as_bytes(),
+        )?;
+        self.n_blocks += 1;
+        self.total_size += block.size as u64;
+        Ok(Control::Continue)
+    }
+
+    fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+        self.writer.flush()?;
+        utils::rename(
+            self.dump_folder.as_path().join("blockindex.csv.tmp"),
+            utils::output_path(
+                &self.dump_folder,
+                "blockindex",
+                self.start_height,
+                block_height,
+                "csv",
+            ),
+        )?;
+
+        info!(target: "callback", "Done.\nIndexed {} blocks from height {} to {} ({} bytes).",
+             self.n_blocks, self.start_height, block_height, self.total_size);
+        Ok(())
+    }
+
+    fn supports_reverse(&self) -> bool {
+        true
+    }
+
+    fn flush(&mut self) -> OpResult<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    fn dump_folder(&self) -> Option<&Path> {
+        Some(&self.dump_folder)
+    }
+
+    fn supports_incremental(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::parser::reader::BlockchainRead;
+    use crate::blockchain::parser::types::{Bitcoin, CoinType};
+    use crate::blockchain::proto::ToRaw;
+    use crate::common::testutils;
+    use bitcoin::hashes::{sha256d, Hash};
+    use std::fs;
+    use std::io::{BufReader, Cursor};
+
+    #[test]
+    fn test_blockindex_size() {
+        let spend = testutils::tx(
+            &[(sha256d::Hash::hash(&[0x01]), 0)],
+            &[(1000, testutils::p2pkh_script(1))],
+        );
+        let block = testutils::block(
+            sha256d::Hash::all_zeros(),
+            600,
+            vec![
+                testutils::coinbase(1, &[(5000000000, testutils::p2pkh_script(0))]),
+                spend,
+            ],
+        );
+        let mut raw_block = block.header.value.to_bytes();
+        raw_block.extend(block.tx_count.to_bytes());
+        for tx in &block.txs {
+            raw_block.extend(tx.value.to_bytes());
+        }
+        // Read it back like the parser does, the size is taken from the raw length
+        let mut reader = BufReader::new(Cursor::new(&raw_block));
+        let parsed = reader
+            .read_block(raw_block.len() as u32, &CoinType::from(Bitcoin))
+            .unwrap();
+
+        let dump_folder = tempfile::tempdir().unwrap();
+        let matches = BlockIndex::build_subcommand()
+            .get_matches_from(["blockindex", dump_folder.path().to_str().unwrap()]);
+        let mut cb = BlockIndex::new(&matches).unwrap();
+        cb.on_start(1).unwrap();
+        cb.on_block(&parsed, 1).unwrap();
+        cb.on_complete(1).unwrap();
+
+        let csv = fs::read_to_string(dump_folder.path().join("blockindex-1-1.csv")).unwrap();
+        let row: Vec<&str> = csv.lines().nth(1).unwrap().split(';').collect();
+        assert_eq!(row[0], "1");
+        assert_eq!(row[1], block.hash().to_string());
+        assert_eq!(row[2], block.header.value.merkle_root.to_string());
+        assert_eq!(row[3], "600");
+        assert_eq!(row[4], raw_block.len().to_string());
+        assert_eq!(row[5], "2");
+        assert_eq!(block.size as usize, raw_block.len());
+    }
+}
@@ -0,0 +1,289 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use clap::{Arg, ArgMatches, Command};
+
+use crate::blockchain::proto::block::Block;
+use crate::blockchain::proto::script::ScriptPattern;
+use crate::blockchain::proto::tx::{EvaluatedTxOut, OutpointKey, TxOutpoint};
+use crate::callbacks::{common, Callback, Control};
+use crate::common::address::CompactAddress;
+use crate::common::utils;
+use crate::errors::OpResult;
+
+/// Values which are a multiple of this are considered round (0.0001 BTC)
+const ROUND_VALUE: u64 = 10000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Heuristic {
+    /// The output pays back to an address of the inputs
+    AddressReuse,
+    /// All other outputs have a round value
+    RoundValue,
+    /// The output is the only one with the script type of the inputs
+    ScriptType,
+    /// The output is the only one paying to an address never seen before
+    FreshAddress,
+}
+
+impl fmt::Display for Heuristic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let str = match *self {
+            Heuristic::AddressReuse => "address_reuse",
+            Heuristic::RoundValue => "round_value",
+            Heuristic::ScriptType => "script_type",
+            Heuristic::FreshAddress => "fresh_address",
+        };
+        write!(f, "{}", str)
+    }
+}
+
+/// Script type and address of an unspent output, needed to compare the outputs with the inputs
+pub struct SpentOutput {
+    pub script_type: &'static str,
+    pub address: Option<CompactAddress>,
+}
+
+/// Labels the likely change output of each transaction using common change detection heuristics
+pub struct Change {
+    dump_folder: PathBuf,
+    writer: common::DumpWriter,
+
+    // key: txid + index
+    unspents: HashMap<OutpointKey, SpentOutput>,
+    // All addresses which received an output so far
+    seen_addresses: HashSet<CompactAddress>,
+
+    start_height: u64,
+    n_txs: u64,
+    n_labeled: u64,
+}
+
+impl Change {
+    /// Applies the heuristics in order and returns the vout of the likely change output
+    /// along with the first heuristic which singles out exactly one candidate.
+    /// Inputs spending outputs below the start height are unknown and passed as None.
+    /// `is_seen` returns whether an address received an output before.
+    pub fn likely_change(
+        outputs: &[EvaluatedTxOut],
+        inputs: &[Option<&SpentOutput>],
+        is_seen: &dyn Fn(&str) -> bool,
+    ) -> Option<(usize, Heuristic)> {
+        // OP_RETURN and unspendable outputs can't be change
+        let candidates = outputs
+            .iter()
+            .enumerate()
+            .filter(|(_, o)| {
+                !matches!(
+                    o.script.pattern,
+                    ScriptPattern::OpReturn(_) | ScriptPattern::Unspendable
+                )
+            })
+            .collect::<Vec<(usize, &EvaluatedTxOut)>>();
+        if candidates.len() < 2 {
+            return None;
+        }
+        let single = |pred: &dyn Fn(&EvaluatedTxOut) -> bool| {
+            let mut matching = candidates.iter().filter(|(_, o)| pred(o));
+            match (matching.next(), matching.next()) {
+                (Some((vout, _)), None) => Some(*vout),
+                _ => None,
+            }
+        };
+
+        let input_addresses = inputs
+            .iter()
+            .flatten()
+            .filter_map(|i| i.address.as_ref())
+            .collect::<HashSet<&CompactAddress>>();
+        if let Some(vout) = single(
+            &|o| matches!(&o.script.address, Some(a) if input_addresses.contains(&CompactAddress::new(a))),
+        ) {
+            return Some((vout, Heuristic::AddressReuse));
+        }
+
+        if let Some(vout) = single(&|o| !o.out.value.is_multiple_of(ROUND_VALUE)) {
+            return Some((vout, Heuristic::RoundValue));
+        }
+
+        // Only applicable if all inputs are known and share the same script type
+        let input_types = inputs
+            .iter()
+            .map(|i| i.map(|i| i.script_type))
+            .collect::<Option<HashSet<&str>>>()
+            .unwrap_or_default();
+        if input_types.len() == 1 {
+            if let Some(vout) = single(&|o| input_types.contains(o.script.pattern.short_name())) {
+                return Some((vout, Heuristic::ScriptType));
+            }
+        }
+
+        if let Some(vout) = single(&|o| matches!(&o.script.address, Some(a) if !is_seen(a))) {
+            return Some((vout, Heuristic::FreshAddress));
+        }
+        None
+    }
+}
+
+impl Callback for Change {
+    fn build_subcommand() -> Command
+    where
+        Self: Sized,
+    {
+        Command::new("change")
+            .about("Dumps the likely change output of each transaction to CSV file")
+            .version("0.1")
+            .author("gcarq <egger.m@protonmail.com>")
+            .arg(
+                Arg::new("dump-folder")
+                    .help("Folder to store csv file")
+                    .index(1)
+                    .required(true),
+            )
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let dump_folder = &PathBuf::from(matches.get_one::<String>("dump-folder").unwrap());
+        let cb = Change {
+            dump_folder: PathBuf::from(dump_folder),
+            writer: common::create_writer(dump_folder.join("change.csv.tmp"))?,
+            unspents: HashMap::with_capacity(10000000),
+            seen_addresses: HashSet::with_capacity(10000000),
+            start_height: 0,
+            n_txs: 0,
+            n_labeled: 0,
+        };
+        Ok(cb)
+    }
+
+    fn on_start(&mut self, block_height: u64) -> OpResult<()> {
+        self.start_height = block_height;
+        info!(target: "callback", "Executing change with dump folder: {} ...", &self.dump_folder.display());
+        self.writer.write_all(
+            format!(
+                "{};{};{};{}\n",
+                "height", "txid", "likely_change_vout", "heuristic"
+            )
+            .as_bytes(),
+        )?;
+        Ok(())
+    }
+
+    fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<Control> {
+        for tx in &block.txs {
+            if !tx.value.is_coinbase() {
+                let spent = tx
+                    .value
+                    .inputs
+                    .iter()
+                    .map(|input| self.unspents.remove(&input.outpoint.key()))
+                    .collect::<Vec<Option<SpentOutput>>>();
+                let inputs = spent.iter().map(Option::as_ref).collect::<Vec<_>>();
+                let seen_addresses = &self.seen_addresses;
+                let change = Change::likely_change(&tx.value.outputs, &inputs, &|a| {
+                    seen_addresses.contains(&CompactAddress::new(a))
+                });
+                if let Some((vout, heuristic)) = change {
+                    self.writer.write_all(
+                        format!(
+                            "{};{};{};{}\n",
+                            block_height,
+                            utils::hash_to_string(&tx.hash),
+                            vout,
+                            heuristic
+                        )
+                        .as_bytes(),
+                    )?;
+                    self.n_labeled += 1;
+                }
+                self.n_txs += 1;
+            }
+
+            for (i, output) in tx.value.outputs.iter().enumerate() {
+                if let Some(address) = &output.script.address {
+                    self.seen_addresses.insert(CompactAddress::new(address));
+                }
+                self.unspents.insert(
+                    TxOutpoint::new(tx.hash, i as u32).key(),
+                    SpentOutput {
+                        script_type: output.script.pattern.short_name(),
+                        address: output.script.address.as_deref().map(CompactAddress::new),
+                    },
+                );
+            }
+        }
+        Ok(Control::Continue)
+    }
+
+    fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+        self.writer.flush()?;
+        utils::rename(
+            self.dump_folder.as_path().join("change.csv.tmp"),
+            utils::output_path(
+                &self.dump_folder,
+                "change",
+                self.start_height,
+                block_height,
+                "csv",
+            ),
+        )?;
+
+        info!(target: "callback", "Done.\nLabeled the change of {} out of {} transactions from height {} to {}.",
+             self.n_labeled, self.n_txs, self.start_height, block_height);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> OpResult<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    fn dump_folder(&self) -> Option<&Path> {
+        Some(&self.dump_folder)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::testutils;
+    use bitcoin::hashes::{sha256d, Hash};
+    use std::fs;
+
+    #[test]
+    fn test_change_round_value() {
+        // Pays exactly 0.5 BTC, the remainder minus the fee goes back to a fresh address
+        let payment = testutils::tx(
+            &[(sha256d::Hash::hash(&[0x01]), 0)],
+            &[
+                (4499987654, testutils::p2pkh_script(1)),
+                (50000000, testutils::p2pkh_script(2)),
+            ],
+        );
+        let block = testutils::block(
+            sha256d::Hash::all_zeros(),
+            0,
+            vec![
+                testutils::coinbase(1, &[(5000000000, testutils::p2pkh_script(0))]),
+                payment,
+            ],
+        );
+
+        let dump_folder = tempfile::tempdir().unwrap();
+        let matches = Change::build_subcommand()
+            .get_matches_from(["change", dump_folder.path().to_str().unwrap()]);
+        let mut cb = Change::new(&matches).unwrap();
+        cb.on_start(1).unwrap();
+        cb.on_block(&block, 1).unwrap();
+        cb.on_complete(1).unwrap();
+
+        let csv = fs::read_to_string(dump_folder.path().join("change-1-1.csv")).unwrap();
+        let rows: Vec<&str> = csv.lines().skip(1).collect();
+        assert_eq!(rows, vec![format!("1;{};0;round_value", block.txs[1].hash)]);
+    }
+}
@@ -0,0 +1,467 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use clap::{Arg, ArgMatches, Command};
+use serde_json::{json, Value};
+
+use crate::blockchain::proto::block::Block;
+use crate::blockchain::proto::tx::{EvaluatedTxOut, OutpointKey, TxOutpoint};
+use crate::callbacks::change::{Change, SpentOutput};
+use crate::callbacks::{common, Callback, Control};
+use crate::common::address::CompactAddress;
+use crate::common::unionfind::UnionFind;
+use crate::common::utils;
+use crate::errors::OpResult;
+
+/// Unspent output, the script type (index in `common::SCRIPT_TYPES`) is used by the change heuristics
+struct ClusterOutput {
+    value: u64,
+    address: CompactAddress,
+    script_type: u8,
+}
+
+/// Clusters addresses with the common-input-ownership heuristic: all addresses spent
+/// by the same transaction are assumed to belong to the same entity.
+/// The clusters are dumped once all blocks are processed.
+pub struct Clusters {
+    dump_folder: PathBuf,
+
+    // key: txid + index
+    unspents: HashMap<OutpointKey, ClusterOutput>,
+    // Maps each clustered address to its item in the union-find
+    items: HashMap<CompactAddress, u32>,
+    sets: UnionFind,
+    // Only clusters containing an address of the watchlist are built
+    watchlist: bool,
+    // Adds the likely change output to the cluster of the inputs
+    change: bool,
+    // All addresses which received an output so far, only tracked with `change`
+    seen_addresses: HashSet<CompactAddress>,
+
+    start_height: u64,
+    n_clusters: u64,
+    n_change: u64,
+}
+
+impl Clusters {
+    /// Returns the union-find item of the address, adds it if it is unknown
+    fn item(&mut self, address: &CompactAddress) -> u32 {
+        match self.items.get(address) {
+            Some(item) => *item,
+            None => {
+                let item = self.sets.add();
+                self.items.insert(address.clone(), item);
+                item
+            }
+        }
+    }
+
+    /// Merges the clusters of all given addresses. With a watchlist only addresses
+    /// which are spent together with an already clustered address are added.
+    fn merge(&mut self, addresses: &[CompactAddress]) {
+        if self.watchlist && !addresses.iter().any(|a| self.items.contains_key(a)) {
+            return;
+        }
+        let first = self.item(&addresses[0]);
+        for address in &addresses[1..] {
+            let item = self.item(address);
+            self.sets.union(first, item);
+        }
+    }
+
+    /// Returns the address of the likely change output, see `Change::likely_change()`
+    fn change_address(
+        &self,
+        outputs: &[EvaluatedTxOut],
+        spent: &[Option<ClusterOutput>],
+    ) -> Option<CompactAddress> {
+        let inputs = spent
+            .iter()
+            .map(|spent| {
+                spent.as_ref().map(|s| SpentOutput {
+                    script_type: common::SCRIPT_TYPES[s.script_type as usize],
+                    address: (!s.address.is_empty()).then(|| s.address.clone()),
+                })
+            })
+            .collect::<Vec<Option<SpentOutput>>>();
+        let inputs = inputs.iter().map(Option::as_ref).collect::<Vec<_>>();
+        let (vout, _) = Change::likely_change(outputs, &inputs, &|a| {
+            self.seen_addresses.contains(&CompactAddress::new(a))
+        })?;
+        outputs[vout]
+            .script
+            .address
+            .as_deref()
+            .map(CompactAddress::new)
+    }
+}
+
+impl Callback for Clusters {
+    fn build_subcommand() -> Command
+    where
+        Self: Sized,
+    {
+        Command::new("clusters")
+            .about("Clusters addresses spent together (common-input-ownership) and dumps them to CSV file")
+            .version("0.1")
+            .author("gcarq <egger.m@protonmail.com>")
+            .arg(
+                Arg::new("dump-folder")
+                    .help("Folder to store csv file")
+                    .index(1)
+                    .required(true),
+            )
+            .arg(
+                Arg::new("watchlist")
+                    .long("watchlist")
+                    .value_name("FILE")
+                    .help("Only builds the clusters of the addresses in FILE (one per line)"),
+            )
+            .arg(
+                Arg::new("change")
+                    .long("change")
+                    .action(clap::ArgAction::SetTrue)
+                    .help("Also adds the likely change output of each transaction to the cluster of its inputs, see the change callback"),
+            )
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let dump_folder = &PathBuf::from(matches.get_one::<String>("dump-folder").unwrap());
+        let mut cb = Clusters {
+            dump_folder: PathBuf::from(dump_folder),
+            unspents: HashMap::with_capacity(10000000),
+            items: HashMap::new(),
+            sets: UnionFind::default(),
+            watchlist: false,
+            change: matches.get_flag("change"),
+            seen_addresses: HashSet::new(),
+            start_height: 0,
+            n_clusters: 0,
+            n_change: 0,
+        };
+        if let Some(path) = matches.get_one::<String>("watchlist") {
+            for address in common::read_watchlist(Path::new(path))? {
+                cb.item(&CompactAddress::new(&address));
+            }
+            cb.watchlist = true;
+        }
+        Ok(cb)
+    }
+
+    fn on_start(&mut self, block_height: u64) -> OpResult<()> {
+        self.start_height = block_height;
+        if self.watchlist {
+            info!(target: "callback", "Executing clusters for {} watched addresses with dump folder: {} ...",
+                  self.items.len(), &self.dump_folder.display());
+        } else {
+            info!(target: "callback", "Executing clusters with dump folder: {} ...", &self.dump_folder.display());
+        }
+        Ok(())
+    }
+
+    /// Inputs without an address or spending outputs below the start height are skipped
+    fn on_block(&mut self, block: &Block, _block_height: u64) -> OpResult<Control> {
+        for tx in &block.txs {
+            let spent = tx
+                .value
+                .inputs
+                .iter()
+                .map(|input| self.unspents.remove(&input.outpoint.key()))
+                .collect::<Vec<Option<ClusterOutput>>>();
+            let mut addresses: Vec<CompactAddress> = spent
+                .iter()
+                .flatten()
+                .filter(|spent| !spent.address.is_empty())
+                .map(|spent| spent.address.clone())
+                .collect();
+            if self.change && !addresses.is_empty() {
+                if let Some(address) = self.change_address(&tx.value.outputs, &spent) {
+                    addresses.push(address);
+                    self.n_change += 1;
+                }
+            }
+            addresses.sort_unstable_by(|a, b| a.as_bytes().cmp(b.as_bytes()));
+            addresses.dedup();
+            if !addresses.is_empty() {
+                self.merge(&addresses);
+            }
+
+            for (i, output) in tx.value.outputs.iter().enumerate() {
+                let address =
+                    CompactAddress::new(output.script.address.as_deref().unwrap_or_default());
+                if self.change && !address.is_empty() {
+                    self.seen_addresses.insert(address.clone());
+                }
+                let unspent = ClusterOutput {
+                    value: output.out.value,
+                    address,
+                    script_type: common::script_type(output),
+                };
+                self.unspents
+                    .insert(TxOutpoint::new(tx.hash, i as u32).key(), unspent);
+            }
+        }
+        Ok(Control::Continue)
+    }
+
+    /// Cluster ids are assigned in the order the clusters were first seen
+    fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+        let mut addresses: Vec<(&CompactAddress, u32)> =
+            self.items.iter().map(|(a, item)| (a, *item)).collect();
+        addresses.sort_unstable_by_key(|(_, item)| *item);
+
+        // Cluster id of each union-find item
+        let mut cluster_ids: HashMap<u32, u64> = HashMap::new();
+        let mut item_clusters: HashMap<u32, u64> = HashMap::with_capacity(addresses.len());
+        let mut rows: Vec<(u64, String)> = Vec::with_capacity(addresses.len());
+        for (address, item) in addresses {
+            let root = self.sets.find(item);
+            let next_id = cluster_ids.len() as u64;
+            let cluster_id = *cluster_ids.entry(root).or_insert(next_id);
+            item_clusters.insert(item, cluster_id);
+            rows.push((cluster_id, address.to_string()));
+        }
+        rows.sort_unstable();
+        self.n_clusters = cluster_ids.len() as u64;
+
+        // Address count and balance of each cluster
+        let mut aggregates: Vec<(u64, u64)> = vec![(0, 0); cluster_ids.len()];
+        for (cluster_id, _) in &rows {
+            aggregates[*cluster_id as usize].0 += 1;
+        }
+        for unspent in self.unspents.values() {
+            if let Some(item) = self.items.get(&unspent.address) {
+                aggregates[item_clusters[item] as usize].1 += unspent.value;
+            }
+        }
+
+        let tmp_path = self.dump_folder.join("clusters.csv.tmp");
+        let mut writer = BufWriter::with_capacity(4000000, File::create(&tmp_path)?);
+        writer.write_all(format!("{};{}\n", "cluster_id", "address").as_bytes())?;
+        for (cluster_id, address) in &rows {
+            writer.write_all(format!("{};{}\n", cluster_id, address).as_bytes())?;
+        }
+        writer.flush()?;
+        drop(writer);
+        utils::rename(
+            tmp_path,
+            utils::output_path(
+                &self.dump_folder,
+                "clusters",
+                self.start_height,
+                block_height,
+                "csv",
+            ),
+        )?;
+
+        let tmp_path = self.dump_folder.join("clusterbalances.csv.tmp");
+        let mut writer = BufWriter::with_capacity(4000000, File::create(&tmp_path)?);
+        writer.write_all(
+            format!("{};{};{}\n", "cluster_id", "address_count", "balance").as_bytes(),
+        )?;
+        for (cluster_id, (count, balance)) in aggregates.iter().enumerate() {
+            writer.write_all(format!("{};{};{}\n", cluster_id, count, balance).as_bytes())?;
+        }
+        writer.flush()?;
+        drop(writer);
+        utils::rename(
+            tmp_path,
+            utils::output_path(
+                &self.dump_folder,
+                "clusterbalances",
+                self.start_height,
+                block_height,
+                "csv",
+            ),
+        )?;
+
+        info!(target: "callback", "Done.\nDumped {} addresses in {} clusters from height {} to {}.",
+             rows.len(), self.n_clusters, self.start_height, block_height);
+        Ok(())
+    }
+
+    fn dump_folder(&self) -> Option<&Path> {
+        Some(&self.dump_folder)
+    }
+
+    fn summary(&self) -> Value {
+        let mut summary = json!({ "addresses": self.items.len(), "clusters": self.n_clusters });
+        if self.change {
+            summary["change_links"] = json!(self.n_change);
+        }
+        summary
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::testutils;
+    use bitcoin::hashes::{sha256d, Hash};
+    use std::fs;
+
+    /// Block 1 pays the addresses A, B, A, C and D. In block 2 the first transaction spends A + B,
+    /// the second one A + C and the third one D.
+    fn blocks() -> Vec<Block> {
+        let outputs: Vec<(u64, Vec<u8>)> = [1, 2, 1, 3, 4]
+            .iter()
+            .map(|i| (1000, testutils::p2pkh_script(*i)))
+            .collect();
+        let block1 = testutils::block(
+            sha256d::Hash::all_zeros(),
+            0,
+            vec![
+                testutils::coinbase(1, &[(5000, testutils::p2pkh_script(0))]),
+                testutils::tx(&[(sha256d::Hash::hash(&[1]), 0)], &outputs),
+            ],
+        );
+        let funding = block1.txs[1].hash;
+        let spend = |vouts: &[u32]| {
+            let inputs: Vec<(sha256d::Hash, u32)> = vouts.iter().map(|v| (funding, *v)).collect();
+            testutils::tx(&inputs, &[(500, testutils::p2pkh_script(9))])
+        };
+        let block2 = testutils::block(
+            block1.header.hash,
+            600,
+            vec![
+                testutils::coinbase(2, &[(5000, testutils::p2pkh_script(0))]),
+                spend(&[0, 1]),
+                spend(&[2, 3]),
+                spend(&[4]),
+            ],
+        );
+        vec![block1, block2]
+    }
+
+    /// Returns the address of `testutils::p2pkh_script(i)`
+    fn address(i: u8) -> String {
+        let block = testutils::block(
+            sha256d::Hash::all_zeros(),
+            0,
+            vec![testutils::coinbase(1, &[(1, testutils::p2pkh_script(i))])],
+        );
+        block.txs[0].value.outputs[0]
+            .script
+            .address
+            .clone()
+            .unwrap()
+    }
+
+    /// Returns the cluster ids of the addresses, None if an address is not clustered
+    fn run(dump_folder: &Path, args: &[&str], addresses: &[u8]) -> Vec<Option<u64>> {
+        let matches = Clusters::build_subcommand().get_matches_from(
+            ["clusters", dump_folder.to_str().unwrap()]
+                .iter()
+                .chain(args),
+        );
+        let mut cb = Clusters::new(&matches).unwrap();
+        cb.on_start(1).unwrap();
+        for (i, block) in blocks().iter().enumerate() {
+            cb.on_block(block, i as u64 + 1).unwrap();
+        }
+        cb.on_complete(2).unwrap();
+
+        let csv = fs::read_to_string(dump_folder.join("clusters-1-2.csv")).unwrap();
+        let rows: HashMap<&str, u64> = csv
+            .lines()
+            .skip(1)
+            .map(|line| {
+                let (id, address) = line.split_once(';').unwrap();
+                (address, id.parse().unwrap())
+            })
+            .collect();
+        assert_eq!(rows.len(), cb.items.len());
+        addresses
+            .iter()
+            .map(|i| rows.get(address(*i).as_str()).copied())
+            .collect()
+    }
+
+    #[test]
+    fn test_clusters() {
+        let dump_folder = tempfile::tempdir().unwrap();
+        // Both transactions spend from A, so A, B and C are one entity
+        assert_eq!(
+            run(dump_folder.path(), &[], &[1, 2, 3, 4, 9]),
+            vec![Some(0), Some(0), Some(0), Some(1), None]
+        );
+
+        // The watched address B pulls in A, which then pulls in C
+        let watchlist = dump_folder.path().join("watchlist.txt");
+        fs::write(&watchlist, format!("# entity\n{}\n", address(2))).unwrap();
+        let args = ["--watchlist", watchlist.to_str().unwrap()];
+        assert_eq!(
+            run(dump_folder.path(), &args, &[1, 2, 3, 4]),
+            vec![Some(0), Some(0), Some(0), None]
+        );
+
+        // C is linked to A by the second transaction only, B has been spent before
+        fs::write(&watchlist, address(3)).unwrap();
+        assert_eq!(
+            run(dump_folder.path(), &args, &[1, 2, 3, 4]),
+            vec![Some(0), None, Some(0), None]
+        );
+    }
+
+    #[test]
+    fn test_clusters_change_and_balances() {
+        let mut blocks = blocks();
+        // Spends one of the outputs to address 9 to a round payment and the change of 1234
+        let block3 = testutils::block(
+            blocks[1].header.hash,
+            1200,
+            vec![
+                testutils::coinbase(3, &[(5000, testutils::p2pkh_script(0))]),
+                testutils::tx(
+                    &[(blocks[1].txs[3].hash, 0)],
+                    &[
+                        (100000, testutils::p2pkh_script(5)),
+                        (1234, testutils::p2pkh_script(6)),
+                    ],
+                ),
+            ],
+        );
+        blocks.push(block3);
+
+        let run = |args: &[&str]| {
+            let dump_folder = tempfile::tempdir().unwrap();
+            let matches = Clusters::build_subcommand().get_matches_from(
+                ["clusters", dump_folder.path().to_str().unwrap()]
+                    .iter()
+                    .chain(args),
+            );
+            let mut cb = Clusters::new(&matches).unwrap();
+            cb.on_start(1).unwrap();
+            for (i, block) in blocks.iter().enumerate() {
+                cb.on_block(block, i as u64 + 1).unwrap();
+            }
+            cb.on_complete(3).unwrap();
+            let csv = fs::read_to_string(dump_folder.path().join("clusters-1-3.csv")).unwrap();
+            let balances =
+                fs::read_to_string(dump_folder.path().join("clusterbalances-1-3.csv")).unwrap();
+            (csv, balances, cb.summary())
+        };
+
+        let (csv, balances, _) = run(&[]);
+        assert!(!csv.contains(&address(6)));
+        // Address 9 still holds the two other outputs
+        assert_eq!(
+            balances,
+            "cluster_id;address_count;balance\n0;3;0\n1;1;0\n2;1;1000\n"
+        );
+
+        // Address 9 is clustered with its change address, which holds the change
+        let (csv, balances, summary) = run(&["--change"]);
+        assert!(csv.contains(&format!("2;{}\n", address(6))));
+        assert_eq!(
+            balances,
+            "cluster_id;address_count;balance\n0;3;0\n1;1;0\n2;2;2234\n"
+        );
+        assert_eq!(summary["change_links"], 1);
+    }
+}
@@ -0,0 +1,390 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use clap::{Arg, ArgMatches, Command};
+use serde_json::{json, Value};
+
+use crate::blockchain::parser::types::CoinType;
+use crate::blockchain::proto::block::Block;
+use crate::blockchain::proto::script::coinbase::CoinbaseMessage;
+use crate::blockchain::proto::script::ScriptPattern;
+use crate::blockchain::proto::tx::{OutpointKey, TxOutpoint};
+use crate::callbacks::{common, Callback, Control};
+use crate::common::utils;
+use crate::errors::{OpError, OpErrorKind, OpResult};
+
+/// Tags of well-known pools in their coinbase text (lowercase) and the pool name
+const MINER_TAGS: [(&str, &str); 20] = [
+    ("foundry usa", "Foundry USA"),
+    ("antpool", "AntPool"),
+    ("f2pool", "F2Pool"),
+    ("viabtc", "ViaBTC"),
+    ("binance", "Binance Pool"),
+    ("mara pool", "MARA Pool"),
+    ("spiderpool", "SpiderPool"),
+    ("poolin", "Poolin"),
+    ("btc.com", "BTC.com"),
+    ("slush", "Braiins Pool"),
+    ("braiins", "Braiins Pool"),
+    ("luxor", "Luxor"),
+    ("sbicrypto", "SBI Crypto"),
+    ("ocean.xyz", "OCEAN"),
+    ("ckpool", "CKPool"),
+    ("ghash.io", "GHash.IO"),
+    ("eligius", "Eligius"),
+    ("bitfury", "BitFury"),
+    ("btcguild", "BTC Guild"),
+    ("huobi", "Huobi Pool"),
+];
+
+/// Dumps the message of each coinbase: the committed height (BIP34), the extranonce,
+/// the text and the miner identified by its tag. Also compares the claimed value with the
+/// allowed subsidy + fees, the unclaimed rest is never issued and shows up as lost value in `balances`.
+pub struct CoinbaseDump {
+    coin: CoinType,
+    dump_folder: PathBuf,
+    writer: common::DumpWriter,
+
+    // key: txid + index, value: output value. Stays empty with `--undo`
+    unspents: HashMap<OutpointKey, u64>,
+    undo: bool,
+    /// Custom tags from --miner-tags first, then the built-in tags, all lowercase
+    miner_tags: Vec<(String, String)>,
+    only_underclaimed: bool,
+
+    start_height: u64,
+    n_blocks: u64,
+    n_underclaimed: u64,
+    unclaimed: u64,
+    /// Number of blocks per identified miner
+    miners: BTreeMap<String, u64>,
+}
+
+impl CoinbaseDump {
+    /// Reads `<tag>;<miner>` lines, empty lines and lines starting with # are skipped
+    fn read_miner_tags(path: &Path) -> OpResult<Vec<(String, String)>> {
+        let mut tags = Vec::new();
+        for line in common::read_watchlist(path)? {
+            match line.split_once(';') {
+                Some((tag, miner)) if !tag.trim().is_empty() => {
+                    tags.push((tag.trim().to_lowercase(), String::from(miner.trim())))
+                }
+                _ => {
+                    return Err(
+                        OpError::new(OpErrorKind::InvalidArgsError).join_msg(&format!(
+                            "Invalid line in {}, expected <tag>;<miner>: {}",
+                            path.display(),
+                            line
+                        )),
+                    )
+                }
+            }
+        }
+        Ok(tags)
+    }
+
+    /// Returns the miner of the first tag found in the coinbase text
+    fn miner(&self, text: &str) -> Option<&str> {
+        let text = text.to_lowercase();
+        self.miner_tags
+            .iter()
+            .find(|(tag, _)| text.contains(tag.as_str()))
+            .map(|(_, miner)| miner.as_str())
+    }
+}
+
+impl Callback for CoinbaseDump {
+    fn build_subcommand() -> Command
+    where
+        Self: Sized,
+    {
+        Command::new("coinbasedump")
+            .about("Dumps the coinbase message, BIP34 height, extranonce, miner tag and unclaimed value of each block to CSV file")
+            .version("0.1")
+            .author("gcarq <egger.m@protonmail.com>")
+            .arg(
+                Arg::new("dump-folder")
+                    .help("Folder to store csv file")
+                    .index(1)
+                    .required(true),
+            )
+            .arg(
+                Arg::new("miner-tags")
+                    .long("miner-tags")
+                    .value_name("FILE")
+                    .help("File with additional <tag>;<miner> lines, checked before the built-in pool tags"),
+            )
+            .arg(
+                Arg::new("only-underclaimed")
+                    .long("only-underclaimed")
+                    .action(clap::ArgAction::SetTrue)
+                    .help("Only dumps blocks whose coinbase claims less than the subsidy and fees"),
+            )
+            .arg(common::undo_arg())
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let dump_folder = &PathBuf::from(matches.get_one::<String>("dump-folder").unwrap());
+        let undo = matches.get_flag("undo");
+        let mut miner_tags = match matches.get_one::<String>("miner-tags") {
+            Some(path) => CoinbaseDump::read_miner_tags(Path::new(path))?,
+            None => Vec::new(),
+        };
+        miner_tags.extend(
+            MINER_TAGS
+                .iter()
+                .map(|(tag, miner)| (String::from(*tag), String::from(*miner))),
+        );
+        let cb = CoinbaseDump {
+            coin: common::get_coin(matches),
+            dump_folder: PathBuf::from(dump_folder),
+            writer: common::create_writer(dump_folder.join("coinbasedump.csv.tmp"))?,
+            unspents: HashMap::with_capacity(if undo { 0 } else { 10000000 }),
+            undo,
+            miner_tags,
+            only_underclaimed: matches.get_flag("only-underclaimed"),
+            start_height: 0,
+            n_blocks: 0,
+            n_underclaimed: 0,
+            unclaimed: 0,
+            miners: BTreeMap::new(),
+        };
+        Ok(cb)
+    }
+
+    fn on_start(&mut self, block_height: u64) -> OpResult<()> {
+        self.start_height = block_height;
+        if block_height > 0 && !self.undo {
+            warn!(target: "callback", "Fees of transactions spending outputs below height {} are unknown, the allowed value of their blocks is left empty!", block_height);
+        }
+        info!(target: "callback", "Executing coinbasedump with dump folder: {} ...", &self.dump_folder.display());
+        self.writer.write_all(
+            format!(
+                "{};{};{};{};{};{};{};{};{};{}\n",
+                "height",
+                "block_hash",
+                "bip34_height",
+                "extranonce",
+                "text",
+                "tags",
+                "miner",
+                "coinbase_value",
+                "allowed_value",
+                "unclaimed"
+            )
+            .as_bytes(),
+        )?;
+        Ok(())
+    }
+
+    /// The allowed value is the subsidy plus the fees of all transactions,
+    /// it stays empty if a transaction spends an unknown output
+    fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<Control> {
+        let mut fees = Some(0);
+        let mut coinbase_value = 0;
+        for (tx_index, tx) in block.txs.iter().enumerate() {
+            let is_coinbase = tx.value.is_coinbase();
+            let in_value = match is_coinbase {
+                true => Some(0),
+                false => common::take_spent_value(block, tx_index, &mut self.unspents),
+            };
+
+            let mut out_value = 0;
+            for (i, output) in tx.value.outputs.iter().enumerate() {
+                out_value += output.out.value;
+                if self.undo
+                    || matches!(
+                        output.script.pattern,
+                        ScriptPattern::OpReturn(_) | ScriptPattern::Unspendable
+                    )
+                {
+                    continue;
+                }
+                self.unspents
+                    .insert(TxOutpoint::new(tx.hash, i as u32).key(), output.out.value);
+            }
+
+            if is_coinbase {
+                coinbase_value = out_value;
+            } else {
+                fees = fees
+                    .zip(in_value)
+                    .map(|(f, v)| f + v.saturating_sub(out_value));
+            }
+        }
+        let allowed = fees.map(|fees| {
+            fees + self
+                .coin
+                .block_subsidy(block_height, &block.header.value.prev_hash)
+        });
+        let unclaimed = allowed.map(|allowed| allowed.saturating_sub(coinbase_value));
+
+        self.n_blocks += 1;
+        if let Some(unclaimed) = unclaimed.filter(|unclaimed| *unclaimed > 0) {
+            self.n_underclaimed += 1;
+            self.unclaimed += unclaimed;
+        } else if self.only_underclaimed {
+            return Ok(Control::Continue);
+        }
+
+        let message = match block.txs.first() {
+            Some(coinbase) => {
+                CoinbaseMessage::decode(&coinbase.value.inputs[0].script_sig, block_height)
+            }
+            None => CoinbaseMessage::default(),
+        };
+        let text = message.text.join(" ");
+        let miner = self.miner(&text).map(String::from);
+        if let Some(miner) = &miner {
+            *self.miners.entry(miner.clone()).or_insert(0) += 1;
+        }
+        let optional = |value: Option<u64>| value.map(|v| v.to_string()).unwrap_or_default();
+        self.writer.write_all(
+            format!(
+                "{};{};{};{};{};{};{};{};{};{}\n",
+                block_height,
+                utils::hash_to_string(&block.header.hash),
+                optional(message.height),
+                message
+                    .extranonce
+                    .as_deref()
+                    .map(utils::arr_to_hex)
+                    .unwrap_or_default(),
+                common::csv_quote(&text),
+                common::csv_quote(&message.tags().join(",")),
+                common::csv_quote(miner.as_deref().unwrap_or_default()),
+                coinbase_value,
+                optional(allowed),
+                optional(unclaimed)
+            )
+            .as_bytes(),
+        )?;
+        Ok(Control::Continue)
+    }
+
+    fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+        self.writer.flush()?;
+        utils::rename(
+            self.dump_folder.as_path().join("coinbasedump.csv.tmp"),
+            utils::output_path(
+                &self.dump_folder,
+                "coinbasedump",
+                self.start_height,
+                block_height,
+                "csv",
+            ),
+        )?;
+
+        info!(target: "callback", "Done.\nScanned the coinbases of {} blocks from height {} to {}:\n\
+                                   \t-> underclaimed blocks: {}\n\
+                                   \t-> unclaimed value:     {:.8}",
+             self.n_blocks, self.start_height, block_height, self.n_underclaimed,
+             self.unclaimed as f64 * 1E-8);
+        Ok(())
+    }
+
+    fn needs_undo(&self) -> bool {
+        self.undo
+    }
+
+    /// With the undo data each block is handled on its own
+    fn supports_reverse(&self) -> bool {
+        self.undo
+    }
+
+    fn flush(&mut self) -> OpResult<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    fn dump_folder(&self) -> Option<&Path> {
+        Some(&self.dump_folder)
+    }
+
+    fn summary(&self) -> Value {
+        json!({
+            "blocks": self.n_blocks,
+            "underclaimed_blocks": self.n_underclaimed,
+            "unclaimed": self.unclaimed,
+            "miners": self.miners,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::testutils;
+    use bitcoin::hashes::{sha256d, Hash};
+    use std::fs;
+
+    #[test]
+    fn test_coinbasedump() {
+        let block1 = testutils::block(
+            sha256d::Hash::all_zeros(),
+            0,
+            vec![testutils::coinbase(
+                1,
+                &[(5000000000, testutils::p2pkh_script(1))],
+            )],
+        );
+        // Pays 1000 sat fee, the coinbase claims 500 sat less than allowed
+        let spend = testutils::tx(
+            &[(block1.txs[0].hash, 0)],
+            &[(4999999000, testutils::p2pkh_script(2))],
+        );
+        let mut coinbase = testutils::coinbase(2, &[(5000000500, testutils::p2pkh_script(3))]);
+        let mut script_sig = vec![0x01, 0x02, 0x0c];
+        script_sig.extend(b"/Custom;OK/\x00");
+        script_sig.extend([0x04, 0xde, 0xad, 0xbe, 0xef]);
+        coinbase.inputs[0].script_len = (script_sig.len() as u8).into();
+        coinbase.inputs[0].script_sig = script_sig;
+        let block2 = testutils::block(block1.header.hash, 600, vec![coinbase, spend]);
+
+        let dump_folder = tempfile::tempdir().unwrap();
+        let tags = dump_folder.path().join("tags.txt");
+        fs::write(&tags, "# tag;miner\ncustom;Custom Pool\n").unwrap();
+        let matches = CoinbaseDump::build_subcommand().get_matches_from([
+            "coinbasedump",
+            dump_folder.path().to_str().unwrap(),
+            "--miner-tags",
+            tags.to_str().unwrap(),
+        ]);
+        let mut cb = CoinbaseDump::new(&matches).unwrap();
+        cb.on_start(1).unwrap();
+        cb.on_block(&block1, 1).unwrap();
+        cb.on_block(&block2, 2).unwrap();
+        cb.on_complete(2).unwrap();
+
+        let csv = fs::read_to_string(dump_folder.path().join("coinbasedump-1-2.csv")).unwrap();
+        assert_eq!(
+            csv.lines().skip(1).collect::<Vec<_>>(),
+            vec![
+                format!(
+                    "1;{};1;;;;;5000000000;5000000000;0",
+                    utils::hash_to_string(&block1.header.hash)
+                ),
+                format!(
+                    "2;{};2;deadbeef;\"/Custom;OK/\";\"Custom;OK\";Custom Pool;5000000500;5000001000;500",
+                    utils::hash_to_string(&block2.header.hash)
+                ),
+            ]
+        );
+        assert_eq!(
+            cb.summary(),
+            json!({
+                "blocks": 2,
+                "underclaimed_blocks": 1,
+                "unclaimed": 500,
+                "miners": { "Custom Pool": 1 },
+            })
+        );
+
+        fs::write(&tags, "no separator\n").unwrap();
+        assert!(CoinbaseDump::read_miner_tags(&tags).is_err());
+    }
+}
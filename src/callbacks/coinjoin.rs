@@ -0,0 +1,204 @@
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use clap::{Arg, ArgMatches, Command};
+
+use crate::blockchain::proto::block::Block;
+use crate::blockchain::proto::tx::EvaluatedTx;
+use crate::callbacks::{common, Callback, Control};
+use crate::common::utils;
+use crate::errors::OpResult;
+
+/// Flags transactions with many equal-value outputs, the typical shape of a CoinJoin
+pub struct CoinJoin {
+    dump_folder: PathBuf,
+    writer: common::DumpWriter,
+    min_participants: usize,
+
+    start_height: u64,
+    n_coinjoins: u64,
+}
+
+impl CoinJoin {
+    /// Returns each output value shared by at least `min_participants` outputs along with the count.
+    /// Outputs with a unique value (change, fees for the coordinator) are ignored.
+    fn equal_outputs(tx: &EvaluatedTx, min_participants: usize) -> Vec<(u64, usize)> {
+        let mut counts: BTreeMap<u64, usize> = BTreeMap::new();
+        for output in &tx.outputs {
+            // Skip OP_RETURN and other zero value outputs
+            if output.out.value > 0 {
+                *counts.entry(output.out.value).or_insert(0) += 1;
+            }
+        }
+        counts
+            .into_iter()
+            .filter(|(_, count)| *count >= min_participants.max(2))
+            .collect()
+    }
+}
+
+impl Callback for CoinJoin {
+    fn build_subcommand() -> Command
+    where
+        Self: Sized,
+    {
+        Command::new("coinjoin")
+            .about("Dumps transactions with many equal-value outputs (CoinJoin candidates) to CSV file")
+            .version("0.1")
+            .author("gcarq <egger.m@protonmail.com>")
+            .arg(
+                Arg::new("dump-folder")
+                    .help("Folder to store csv file")
+                    .index(1)
+                    .required(true),
+            )
+            .arg(
+                Arg::new("min-participants")
+                    .long("min-participants")
+                    .value_name("N")
+                    .value_parser(clap::value_parser!(u64).range(2..))
+                    .default_value("5")
+                    .help("Minimum number of outputs sharing the same value"),
+            )
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let dump_folder = &PathBuf::from(matches.get_one::<String>("dump-folder").unwrap());
+        let cb = CoinJoin {
+            dump_folder: PathBuf::from(dump_folder),
+            writer: common::create_writer(dump_folder.join("coinjoin.csv.tmp"))?,
+            min_participants: *matches.get_one::<u64>("min-participants").unwrap() as usize,
+            start_height: 0,
+            n_coinjoins: 0,
+        };
+        Ok(cb)
+    }
+
+    fn on_start(&mut self, block_height: u64) -> OpResult<()> {
+        self.start_height = block_height;
+        info!(target: "callback", "Executing coinjoin with dump folder: {} (min participants: {}) ...",
+              &self.dump_folder.display(), self.min_participants);
+        self.writer.write_all(
+            format!(
+                "{};{};{};{}\n",
+                "height", "txid", "equalValue", "participantCount"
+            )
+            .as_bytes(),
+        )?;
+        Ok(())
+    }
+
+    fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<Control> {
+        for tx in &block.txs {
+            if tx.value.is_coinbase() {
+                continue;
+            }
+            let groups = CoinJoin::equal_outputs(&tx.value, self.min_participants);
+            if groups.is_empty() {
+                continue;
+            }
+            let txid = utils::hash_to_string(&tx.hash);
+            for (value, count) in groups {
+                self.writer.write_all(
+                    format!("{};{};{};{}\n", block_height, txid, value, count).as_bytes(),
+                )?;
+            }
+            self.n_coinjoins += 1;
+        }
+        Ok(Control::Continue)
+    }
+
+    fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+        self.writer.flush()?;
+        utils::rename(
+            self.dump_folder.as_path().join("coinjoin.csv.tmp"),
+            utils::output_path(
+                &self.dump_folder,
+                "coinjoin",
+                self.start_height,
+                block_height,
+                "csv",
+            ),
+        )?;
+
+        info!(target: "callback", "Done.\nFound {} CoinJoin candidates from height {} to {}.",
+             self.n_coinjoins, self.start_height, block_height);
+        Ok(())
+    }
+
+    fn supports_reverse(&self) -> bool {
+        true
+    }
+
+    fn flush(&mut self) -> OpResult<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    fn dump_folder(&self) -> Option<&Path> {
+        Some(&self.dump_folder)
+    }
+
+    fn supports_incremental(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::testutils;
+    use bitcoin::hashes::{sha256d, Hash};
+    use std::fs;
+
+    #[test]
+    fn test_coinjoin_min_participants() {
+        // 5 participants with 0.1 BTC each plus two change outputs
+        let mut outputs = (0..5)
+            .map(|i| (10000000, testutils::p2pkh_script(i)))
+            .collect::<Vec<(u64, Vec<u8>)>>();
+        outputs.push((1234567, testutils::p2pkh_script(5)));
+        outputs.push((7654321, testutils::p2pkh_script(6)));
+        let coinjoin = testutils::tx(&[(sha256d::Hash::hash(&[0x01]), 0)], &outputs);
+        // Only 4 equal outputs
+        let below = testutils::tx(
+            &[(sha256d::Hash::hash(&[0x02]), 0)],
+            &(0..4)
+                .map(|i| (10000000, testutils::p2pkh_script(i)))
+                .collect::<Vec<(u64, Vec<u8>)>>(),
+        );
+        let block = testutils::block(
+            sha256d::Hash::all_zeros(),
+            0,
+            vec![
+                testutils::coinbase(1, &[(5000000000, testutils::p2pkh_script(0))]),
+                coinjoin,
+                below,
+            ],
+        );
+
+        let dump_folder = tempfile::tempdir().unwrap();
+        let matches = CoinJoin::build_subcommand().get_matches_from([
+            "coinjoin",
+            dump_folder.path().to_str().unwrap(),
+            "--min-participants",
+            "5",
+        ]);
+        let mut cb = CoinJoin::new(&matches).unwrap();
+        cb.on_start(1).unwrap();
+        cb.on_block(&block, 1).unwrap();
+        cb.on_complete(1).unwrap();
+
+        let csv = fs::read_to_string(dump_folder.path().join("coinjoin-1-1.csv")).unwrap();
+        let rows: Vec<&str> = csv.lines().skip(1).collect();
+        assert_eq!(rows, vec![format!("1;{};10000000;5", block.txs[1].hash)]);
+        assert_eq!(
+            CoinJoin::equal_outputs(&block.txs[2].value, 4),
+            vec![(10000000, 4)]
+        );
+    }
+}
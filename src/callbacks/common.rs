@@ -1,65 +1,1045 @@
-use std::collections::HashMap;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
 
-use crate::blockchain::proto::tx::EvaluatedTx;
-use crate::blockchain::proto::tx::TxOutpoint;
+use bitcoin::hashes::{sha256, Hash};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use clap::{Arg, ArgMatches};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde_json::Value;
+
+use crate::blockchain::parser::types::CoinType;
+
+use crate::blockchain::proto::block::Block;
+use crate::blockchain::proto::tx::{EvaluatedTx, EvaluatedTxOut, TxOutput};
+use crate::blockchain::proto::tx::{OutpointKey, TxOutpoint};
 use crate::blockchain::proto::Hashed;
 use crate::blockchain::proto::ToRaw;
+use crate::common::address::CompactAddress;
+use crate::common::field::{self, Field};
+use crate::common::muhash::MuHash3072;
+use crate::common::parquet::ParquetWriter;
+#[cfg(feature = "sqlite")]
+use crate::common::sqlite;
+use crate::common::utils;
+#[cfg(feature = "zstd")]
+use crate::common::zstd;
+use crate::errors::{OpError, OpErrorKind, OpResult};
+
+/// Returns the coin selected with the global `--coin` or `--coin-config-file` option,
+/// defaults to Bitcoin
+pub fn get_coin(matches: &ArgMatches) -> CoinType {
+    // The config file was already validated while parsing the global options
+    if let Ok(Some(path)) = matches.try_get_one::<String>("coin-config-file") {
+        return CoinType::from_config_file(Path::new(path)).unwrap_or_default();
+    }
+    match matches.try_get_one::<String>("coin") {
+        Ok(Some(name)) => name.parse().unwrap_or_default(),
+        _ => CoinType::default(),
+    }
+}
+
+/// Script types accepted by `--only-type`, see `ScriptPattern::short_name()`
+pub const SCRIPT_TYPES: [&str; 11] = [
+    "p2pk",
+    "p2pkh",
+    "p2sh",
+    "p2wpkh",
+    "p2wsh",
+    "p2tr",
+    "witness",
+    "multisig",
+    "opreturn",
+    "unspendable",
+    "nonstandard",
+];
+
+/// Returns the index in SCRIPT_TYPES of the script type of the output
+pub fn script_type(output: &EvaluatedTxOut) -> u8 {
+    let name = output.script.pattern.short_name();
+    SCRIPT_TYPES
+        .iter()
+        .position(|t| *t == name)
+        .unwrap_or(SCRIPT_TYPES.len() - 1) as u8
+}
+
+/// Arguments to restrict the outputs a callback considers, see `OutputFilter`
+pub fn output_filter_args() -> [Arg; 2] {
+    [
+        Arg::new("only-type")
+            .long("only-type")
+            .value_name("TYPE")
+            .value_delimiter(',')
+            .value_parser(SCRIPT_TYPES)
+            .help("Only considers outputs with the given script types (comma separated)"),
+        Arg::new("address-prefix")
+            .long("address-prefix")
+            .value_name("PREFIX")
+            .help("Only considers outputs with an address starting with PREFIX, e.g. bc1q"),
+    ]
+}
+
+/// Argument to restrict a callback to the transactions of a list of addresses, see `Watchlist`
+pub fn watch_addresses_arg() -> Arg {
+    Arg::new("watch-addresses")
+        .long("watch-addresses")
+        .value_name("FILE")
+        .help("Only considers outputs of the addresses or sha256(scriptPubKey) hashes listed in FILE, one per line")
+}
+
+/// Argument to compress the dump files, see `DumpWriter`
+pub fn gzip_arg() -> Arg {
+    Arg::new("gzip")
+        .long("gzip")
+        .action(clap::ArgAction::SetTrue)
+        .help("Writes gzip compressed .csv.gz files")
+}
+
+/// Compression of the dump files while writing, with `--gzip` or the global `--compress`
+pub fn dump_compression(matches: &ArgMatches) -> Option<utils::Compression> {
+    if matches!(matches.try_get_one::<bool>("gzip"), Ok(Some(true))) {
+        return Some(utils::Compression::Gzip);
+    }
+    utils::output_compression()
+}
+
+/// Argument to take the spent outputs from the rev files of the node, see `Callback::needs_undo()`
+pub fn undo_arg() -> Arg {
+    Arg::new("undo")
+        .long("undo")
+        .action(clap::ArgAction::SetTrue)
+        .help("Reads the spent outputs from the rev files instead of tracking all unspent outputs, which needs much less memory and works from any start height")
+}
+
+/// Argument to only track the outputs of the most recent blocks, see `UnspentWindow`
+pub fn utxo_window_arg() -> Arg {
+    Arg::new("utxo-window")
+        .long("utxo-window")
+        .value_name("K")
+        .value_parser(clap::value_parser!(u64).range(1..))
+        .help("Only tracks outputs created within the last K blocks, spends of older outputs are ignored")
+}
+
+/// Argument to select the output of the dump callbacks, see `DumpTarget`
+pub fn format_arg() -> Arg {
+    Arg::new("format")
+        .long("format")
+        .value_name("FORMAT")
+        .value_parser(["csv", "sqlite", "parquet"])
+        .default_value("csv")
+        .help("Writes csv files, Parquet files or inserts the rows into <coin>.sqlite in the dump folder (requires the sqlite feature)")
+}
+
+/// Argument to stream the rows of the dump callbacks to stdout, see `JsonLinesWriter`
+pub fn stdout_json_arg() -> Arg {
+    Arg::new("stdout-json")
+        .long("stdout-json")
+        .action(clap::ArgAction::SetTrue)
+        .conflicts_with("format")
+        .help("Writes the rows as JSON lines to stdout instead of files, the log goes to stderr")
+}
+
+/// SQLite table of a dump file, the columns are SQL definitions in the order of the csv fields.
+/// Parquet files and JSON lines take the names and types of their columns from them as well.
+#[cfg_attr(not(feature = "sqlite"), allow(dead_code))]
+pub struct Table {
+    pub columns: &'static [&'static str],
+    pub indexes: &'static [&'static str],
+    // The rows of a previous run are replaced instead of appended to
+    pub replace: bool,
+}
+
+/// Where the dump callbacks write their rows to, selected with `--format`
+pub enum DumpTarget {
+    Files {
+        folder: PathBuf,
+        compression: Option<utils::Compression>,
+    },
+    /// The pages are compressed with the codec of `--compress`
+    Parquet {
+        folder: PathBuf,
+        compression: Option<utils::Compression>,
+    },
+    #[cfg(feature = "sqlite")]
+    Sqlite(Rc<sqlite::Connection>),
+    /// All tables share the stream, so the rows keep the order in which they were written
+    Json(Rc<RefCell<Box<dyn Write>>>),
+}
+
+impl DumpTarget {
+    /// `--format sqlite` opens `<coin>.sqlite` in the dump folder, `--stdout-json` writes to stdout,
+    /// `--format parquet` writes a Parquet file per table
+    pub fn from_matches(dump_folder: &Path, matches: &ArgMatches) -> OpResult<Self> {
+        if matches!(matches.try_get_one::<bool>("stdout-json"), Ok(Some(true))) {
+            let stdout = BufWriter::with_capacity(1000000, std::io::stdout());
+            return Ok(DumpTarget::Json(Rc::new(RefCell::new(Box::new(stdout)))));
+        }
+        match matches.get_one::<String>("format").map(String::as_str) {
+            #[cfg(feature = "sqlite")]
+            Some("sqlite") => {
+                let name = format!("{}.sqlite", get_coin(matches).name.to_lowercase());
+                let conn = sqlite::Connection::open(&dump_folder.join(name))?;
+                Ok(DumpTarget::Sqlite(Rc::new(conn)))
+            }
+            #[cfg(not(feature = "sqlite"))]
+            Some("sqlite") => Err(OpError::new(OpErrorKind::InvalidArgsError).join_msg(
+                "--format sqlite requires a build with SQLite support: cargo build --release --features sqlite",
+            )),
+            Some("parquet") => Ok(DumpTarget::Parquet {
+                folder: dump_folder.to_path_buf(),
+                compression: utils::output_compression(),
+            }),
+            _ => Ok(DumpTarget::Files {
+                folder: dump_folder.to_path_buf(),
+                compression: dump_compression(matches),
+            }),
+        }
+    }
+
+    /// Returns the writer of the temporary dump file `<name>.<extension>.tmp` or of the table `name`
+    pub fn writer(&self, name: &str, table: &Table) -> OpResult<DumpWriter> {
+        match self {
+            DumpTarget::Files { folder, .. } | DumpTarget::Parquet { folder, .. } => {
+                let path = folder.join(format!("{}.{}.tmp", name, self.extension()));
+                self.file_writer(path, table)
+            }
+            #[cfg(feature = "sqlite")]
+            DumpTarget::Sqlite(conn) => Ok(DumpWriter::Sqlite(sqlite::TableWriter::new(
+                conn.clone(),
+                name,
+                table.columns,
+                table.indexes,
+                table.replace,
+            )?)),
+            DumpTarget::Json(sink) => Ok(DumpWriter::Json(JsonLinesWriter::new(
+                sink.clone(),
+                name,
+                table.columns,
+            ))),
+        }
+    }
+
+    /// Returns the extension of the dump files.
+    /// Files written next to a table or stdout, e.g. snapshots, are csv files.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            DumpTarget::Files { compression, .. } => csv_extension(*compression),
+            DumpTarget::Parquet { .. } => "parquet",
+            _ => csv_extension(utils::output_compression()),
+        }
+    }
+
+    /// Returns the writer of a dump file with the table at `path`, see `extension()`
+    pub fn file_writer(&self, path: PathBuf, table: &Table) -> OpResult<DumpWriter> {
+        match self {
+            DumpTarget::Files { compression, .. } => DumpWriter::create(path, *compression),
+            DumpTarget::Parquet { compression, .. } => Ok(DumpWriter::Parquet(
+                ParquetWriter::create(path, table.columns, *compression)?,
+            )),
+            _ => DumpWriter::create(path, utils::output_compression()),
+        }
+    }
+}
+
+/// Returns the extension of the csv files
+pub fn csv_extension(compression: Option<utils::Compression>) -> &'static str {
+    match compression {
+        None => "csv",
+        Some(utils::Compression::Gzip) => "csv.gz",
+        #[cfg(feature = "zstd")]
+        Some(utils::Compression::Zstd) => "csv.zst",
+    }
+}
+
+/// Options of `utils::OutputConfig`, shared by all callbacks
+pub fn output_args() -> [Arg; 3] {
+    [
+        Arg::new("output-dir")
+            .long("output-dir")
+            .value_name("DIR")
+            .help("Writes the final output files of the callback to DIR instead of the dump folder. Temp files and state stay in the dump folder"),
+        Arg::new("output-prefix")
+            .long("output-prefix")
+            .value_name("PREFIX")
+            .help("Prepends PREFIX to the name of each output file"),
+        Arg::new("compress")
+            .long("compress")
+            .value_name("CODEC")
+            .value_parser(["gzip", "zstd"])
+            .help("Compresses the output files, zstd requires the zstd feature. Callbacks with a --gzip option compress while writing, all others once the callback completed"),
+    ]
+}
+
+/// Compresses the output files handed out by `utils::output_path()` with the codec of `--compress`.
+/// Files which are compressed already, including Parquet files, and files the callback didn't write are skipped.
+pub fn compress_outputs() -> OpResult<()> {
+    let paths = utils::take_output_paths();
+    let compression = match utils::output_compression() {
+        Some(compression) => compression,
+        None => return Ok(()),
+    };
+    for path in paths {
+        let compressed = ["gz", "zst", "parquet"];
+        if !path.is_file()
+            || path
+                .extension()
+                .is_some_and(|ext| compressed.iter().any(|c| ext == *c))
+        {
+            continue;
+        }
+        let target = PathBuf::from(format!("{}.{}", path.display(), compression.extension()));
+        let tmp_path = PathBuf::from(format!("{}.tmp", target.display()));
+        let mut writer = DumpWriter::create(tmp_path.clone(), Some(compression))?;
+        std::io::copy(&mut File::open(&path)?, &mut writer)?;
+        writer.finish()?;
+        drop(writer);
+        utils::rename(&tmp_path, &target)?;
+        std::fs::remove_file(&path)?;
+        debug!(target: "callback", "Compressed {}", target.display());
+    }
+    Ok(())
+}
+
+/// Quotes free text containing the separator, quotes or line breaks
+pub fn csv_quote(text: &str) -> String {
+    if text.contains([';', '"', '\n', '\r']) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        String::from(text)
+    }
+}
+
+/// Creates the temp file of a dump, e.g. `<name>.csv.tmp` in the dump folder.
+/// It is renamed to `utils::output_path()` once finished, `--compress` compresses it afterwards.
+pub fn create_writer(path: PathBuf) -> OpResult<DumpWriter> {
+    DumpWriter::create(path, None)
+}
+
+/// Buffered writer of a csv file, optionally gzip or zstd compressed, of a Parquet file or of a SQLite table.
+/// Rows go through `write_row()`, which keeps the types of the fields for tables.
+/// Files can also be written to directly, e.g. for the csv header.
+pub enum DumpWriter {
+    Plain(BufWriter<File>),
+    Gzip(BufWriter<GzEncoder<File>>),
+    #[cfg(feature = "zstd")]
+    Zstd(BufWriter<zstd::Encoder<File>>),
+    Parquet(ParquetWriter),
+    #[cfg(feature = "sqlite")]
+    Sqlite(sqlite::TableWriter),
+    Json(JsonLinesWriter),
+}
+
+impl DumpWriter {
+    pub fn create(path: PathBuf, compression: Option<utils::Compression>) -> OpResult<Self> {
+        let file = File::create(path)?;
+        let cap = 4000000;
+        match compression {
+            None => Ok(DumpWriter::Plain(BufWriter::with_capacity(cap, file))),
+            Some(utils::Compression::Gzip) => {
+                let encoder = GzEncoder::new(file, Compression::default());
+                Ok(DumpWriter::Gzip(BufWriter::with_capacity(cap, encoder)))
+            }
+            #[cfg(feature = "zstd")]
+            Some(utils::Compression::Zstd) => {
+                let encoder = zstd::Encoder::new(file);
+                Ok(DumpWriter::Zstd(BufWriter::with_capacity(cap, encoder)))
+            }
+        }
+    }
+
+    /// Writes a row with a field for each column of the table, files get it as csv line
+    pub fn write_row(&mut self, fields: &[Field]) -> OpResult<()> {
+        match self {
+            #[cfg(feature = "sqlite")]
+            DumpWriter::Sqlite(writer) => writer.insert(fields),
+            DumpWriter::Json(writer) => Ok(writer.write_row(fields)?),
+            DumpWriter::Parquet(writer) => writer.write_row(fields),
+            _ => Ok(self.write_all(field::csv_line(fields).as_bytes())?),
+        }
+    }
+
+    /// Flushes the buffer and completes the gzip or zstd stream or the Parquet file, so the file can be renamed.
+    /// A SQLite table gets its indexes and the rows are committed.
+    /// Nothing must be written afterwards.
+    pub fn finish(&mut self) -> OpResult<()> {
+        match self {
+            DumpWriter::Plain(writer) => writer.flush()?,
+            DumpWriter::Gzip(writer) => {
+                writer.flush()?;
+                writer.get_mut().try_finish()?;
+            }
+            #[cfg(feature = "zstd")]
+            DumpWriter::Zstd(writer) => {
+                writer.flush()?;
+                writer.get_mut().try_finish()?;
+            }
+            DumpWriter::Parquet(writer) => writer.finish()?,
+            #[cfg(feature = "sqlite")]
+            DumpWriter::Sqlite(writer) => writer.finish()?,
+            DumpWriter::Json(writer) => writer.flush()?,
+        }
+        Ok(())
+    }
+
+    /// Returns true if the output is a file, which has to be renamed once finished
+    pub fn is_file(&self) -> bool {
+        match self {
+            DumpWriter::Plain(_) | DumpWriter::Gzip(_) => true,
+            #[cfg(feature = "zstd")]
+            DumpWriter::Zstd(_) => true,
+            DumpWriter::Parquet(_) => true,
+            #[cfg(feature = "sqlite")]
+            DumpWriter::Sqlite(_) => false,
+            DumpWriter::Json(_) => false,
+        }
+    }
+
+    /// Returns true if the output is a csv file, which gets a header instead of named columns
+    pub fn is_csv(&self) -> bool {
+        self.is_file() && !matches!(self, DumpWriter::Parquet(_))
+    }
+
+    /// Returns true if the rows go to stdout
+    pub fn is_stdout(&self) -> bool {
+        matches!(self, DumpWriter::Json(_))
+    }
+}
+
+impl Write for DumpWriter {
+    /// Tables only take typed rows, see `write_row()`
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            DumpWriter::Plain(writer) => writer.write(buf),
+            DumpWriter::Gzip(writer) => writer.write(buf),
+            #[cfg(feature = "zstd")]
+            DumpWriter::Zstd(writer) => writer.write(buf),
+            _ => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Rows of tables must be written with write_row()",
+            )),
+        }
+    }
+
+    /// Commits the rows of a SQLite table written so far, e.g. for `--flush-interval`.
+    /// Parquet files get a row group of the rows written so far.
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            DumpWriter::Plain(writer) => writer.flush(),
+            DumpWriter::Gzip(writer) => writer.flush(),
+            #[cfg(feature = "zstd")]
+            DumpWriter::Zstd(writer) => writer.flush(),
+            DumpWriter::Parquet(writer) => writer.flush().map_err(io::Error::other),
+            #[cfg(feature = "sqlite")]
+            DumpWriter::Sqlite(writer) => writer.commit().map_err(io::Error::other),
+            DumpWriter::Json(writer) => writer.flush(),
+        }
+    }
+}
+
+/// Writes rows as JSON objects, one line per row.
+/// The keys are the column names of the table, led by `"table"` with the table name.
+pub struct JsonLinesWriter {
+    sink: Rc<RefCell<Box<dyn Write>>>,
+    // Prefix of each object: {"table":"<name>"
+    prefix: String,
+    // Names encoded as JSON strings
+    columns: Vec<String>,
+}
+
+impl JsonLinesWriter {
+    pub fn new(sink: Rc<RefCell<Box<dyn Write>>>, table: &str, columns: &[&str]) -> Self {
+        let columns = columns
+            .iter()
+            .map(|column| {
+                let name = column.split_whitespace().next().unwrap_or_default();
+                Value::from(name).to_string()
+            })
+            .collect();
+        JsonLinesWriter {
+            sink,
+            prefix: format!("{{\"table\":{}", Value::from(table)),
+            columns,
+        }
+    }
+
+    pub fn write_row(&mut self, fields: &[Field]) -> std::io::Result<()> {
+        let mut object = self.prefix.clone();
+        for (i, field) in fields.iter().enumerate() {
+            let name = match self.columns.get(i) {
+                Some(name) => name.clone(),
+                None => Value::from(format!("field{}", i)).to_string(),
+            };
+            object.push(',');
+            object.push_str(&name);
+            object.push(':');
+            object.push_str(&field.to_json().to_string());
+        }
+        object.push_str("}\n");
+        self.sink.borrow_mut().write_all(object.as_bytes())
+    }
+
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        self.sink.borrow_mut().flush()
+    }
+}
+
+/// Restricts the outputs considered by a callback by script type and address prefix
+pub struct OutputFilter {
+    types: Option<Vec<String>>,
+    address_prefix: Option<String>,
+}
+
+impl OutputFilter {
+    pub fn from_matches(matches: &ArgMatches) -> Self {
+        Self {
+            types: matches
+                .get_many::<String>("only-type")
+                .map(|types| types.cloned().collect()),
+            address_prefix: matches.get_one::<String>("address-prefix").cloned(),
+        }
+    }
+
+    /// Returns true if any criterion is configured
+    pub fn is_active(&self) -> bool {
+        self.types.is_some() || self.address_prefix.is_some()
+    }
 
+    /// Returns true if the output meets all configured criteria
+    pub fn matches(&self, output: &EvaluatedTxOut) -> bool {
+        if let Some(types) = &self.types {
+            let name = output.script.pattern.short_name();
+            if !types.iter().any(|t| t == name) {
+                return false;
+            }
+        }
+        match (&self.address_prefix, &output.script.address) {
+            (Some(prefix), Some(address)) => address.starts_with(prefix.as_str()),
+            (Some(_), None) => false,
+            (None, _) => true,
+        }
+    }
+}
+
+/// Addresses of `--watch-addresses`. Outputs without an address (bare multisig, non-standard, ...)
+/// are listed by the sha256 of their scriptPubKey, optionally as `nonstandard:<hash>` like in the balances.
+/// The watched outputs are remembered until they are spent, so the spending transactions are recognized as well.
+/// This requires the blocks in ascending order, spends of outputs created before the first parsed block are missed.
+pub struct Watchlist {
+    addresses: HashSet<String>,
+    script_hashes: HashSet<sha256::Hash>,
+    // key: txid + index
+    unspents: HashSet<OutpointKey>,
+}
+
+impl Watchlist {
+    /// Returns None if `--watch-addresses` is not set
+    pub fn from_matches(matches: &ArgMatches) -> OpResult<Option<Self>> {
+        match matches.get_one::<String>("watch-addresses") {
+            Some(path) => Ok(Some(Self::read(Path::new(path))?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Reads one address or hash per line, empty lines and lines starting with # are skipped
+    pub fn read(path: &Path) -> OpResult<Self> {
+        let mut watchlist = Self {
+            addresses: HashSet::new(),
+            script_hashes: HashSet::new(),
+            unspents: HashSet::new(),
+        };
+        for line in BufReader::new(File::open(path)?).lines() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let hash = line.strip_prefix("nonstandard:").unwrap_or(line);
+            match hash.len() {
+                64 => match hash.parse::<sha256::Hash>() {
+                    Ok(hash) => watchlist.script_hashes.insert(hash),
+                    Err(_) => watchlist.addresses.insert(String::from(line)),
+                },
+                _ => watchlist.addresses.insert(String::from(line)),
+            };
+        }
+        info!(target: "callback", "Watching {} addresses and {} script hashes from {}",
+              watchlist.addresses.len(), watchlist.script_hashes.len(), path.display());
+        Ok(watchlist)
+    }
+
+    /// Returns true if the output pays to a watched address or script
+    pub fn matches(&self, output: &EvaluatedTxOut) -> bool {
+        match &output.script.address {
+            Some(address) if self.addresses.contains(address) => true,
+            _ => {
+                !self.script_hashes.is_empty()
+                    && self
+                        .script_hashes
+                        .contains(&sha256::Hash::hash(&output.out.script_pubkey))
+            }
+        }
+    }
+
+    /// Returns true if the transaction spends or creates a watched output.
+    /// Must be called for each transaction in block order.
+    pub fn touches(&mut self, tx: &Hashed<EvaluatedTx>) -> bool {
+        let mut touched = false;
+        for input in &tx.value.inputs {
+            touched |= self.unspents.remove(&input.outpoint.key());
+        }
+        for (i, output) in tx.value.outputs.iter().enumerate() {
+            if self.matches(output) {
+                self.unspents
+                    .insert(TxOutpoint::new(tx.hash, i as u32).key());
+                touched = true;
+            }
+        }
+        touched
+    }
+}
+
+/// Global argument to pass callback specific options, see `CallbackArgs`
+pub fn callback_args_arg() -> Arg {
+    Arg::new("callback-args")
+        .long("callback-args")
+        .value_name("KEY=VAL,...")
+        .global(true)
+        .help("Passes options to the callback as comma separated key=value pairs, see the callback's documentation for its keys")
+}
+
+/// Callback specific options of the global `--callback-args "key=val,key=val"` option.
+/// Each callback declares the keys it accepts, so a typo is reported instead of being ignored.
+/// Values can't contain commas.
+#[derive(Debug, Default)]
+pub struct CallbackArgs {
+    args: HashMap<String, String>,
+}
+
+impl CallbackArgs {
+    /// Parses the `--callback-args` of the matches and rejects keys not in `keys`.
+    /// Returns empty args if the option wasn't given.
+    pub fn from_matches(matches: &ArgMatches, keys: &[&str]) -> OpResult<Self> {
+        match matches.try_get_one::<String>("callback-args") {
+            Ok(Some(args)) => Self::parse(args, keys),
+            _ => Ok(Self::default()),
+        }
+    }
+
+    pub fn parse(args: &str, keys: &[&str]) -> OpResult<Self> {
+        let invalid = |msg: String| Err(OpError::new(OpErrorKind::InvalidArgsError).join_msg(&msg));
+        let mut parsed = HashMap::new();
+        for pair in args.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let (key, value) = match pair.split_once('=') {
+                Some((key, value)) => (key.trim(), value.trim()),
+                None => {
+                    return invalid(format!(
+                        "--callback-args: expected key=value, got '{}'",
+                        pair
+                    ))
+                }
+            };
+            if !keys.contains(&key) {
+                return invalid(format!(
+                    "--callback-args: unknown key '{}', expected one of: {}",
+                    key,
+                    keys.join(", ")
+                ));
+            }
+            if parsed
+                .insert(String::from(key), String::from(value))
+                .is_some()
+            {
+                return invalid(format!("--callback-args: duplicate key '{}'", key));
+            }
+        }
+        Ok(Self { args: parsed })
+    }
+
+    /// Returns the parsed value of the key, None if it wasn't given
+    pub fn get<T: std::str::FromStr>(&self, key: &str) -> OpResult<Option<T>> {
+        match self.args.get(key) {
+            Some(value) => value.parse().map(Some).map_err(|_| {
+                OpError::new(OpErrorKind::InvalidArgsError).join_msg(&format!(
+                    "--callback-args: invalid value '{}' for key '{}'",
+                    value, key
+                ))
+            }),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Reads one address per line of a `--watchlist` file, empty lines and lines starting with # are skipped
+pub fn read_watchlist(path: &Path) -> OpResult<Vec<String>> {
+    let mut addresses = Vec::new();
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        let line = line.trim();
+        if !line.is_empty() && !line.starts_with('#') {
+            addresses.push(String::from(line));
+        }
+    }
+    Ok(addresses)
+}
+
+/// Unspent output in the maps keyed by `TxOutpoint::key()`. 32 bytes plus the address,
+/// which is kept compact and only encoded as string when it is dumped.
+#[derive(Clone, Debug, PartialEq)]
 pub struct UnspentValue {
     pub block_height: u64,
     pub value: u64,
-    pub address: String,
+    pub address: CompactAddress,
+}
+
+/// Returns a synthetic address for scriptPubKeys without an address (bare multisig, non-standard, ...),
+/// so their value can still be aggregated per script
+pub fn nonstandard_bucket(script_pubkey: &[u8]) -> String {
+    format!("nonstandard:{}", sha256::Hash::hash(script_pubkey))
+}
+
+/// Returns the value spent by the transaction at the given index of the block, None if a spent output is unknown.
+/// Takes it from the undo data if the block has it, otherwise removes the spent outputs from `unspents`.
+pub fn take_spent_value(
+    block: &Block,
+    tx_index: usize,
+    unspents: &mut HashMap<OutpointKey, u64>,
+) -> Option<u64> {
+    if let Some(undo) = &block.undo {
+        return undo.spent_value(tx_index);
+    }
+    let mut value = Some(0);
+    for input in &block.txs[tx_index].value.inputs {
+        value = match unspents.remove(&input.outpoint.key()) {
+            Some(spent) => value.map(|v| v + spent),
+            None => None,
+        };
+    }
+    value
 }
 
 /// Iterates over transaction inputs and removes spent outputs from HashMap.
 /// Returns the total number of processed inputs.
 pub fn remove_unspents(
     tx: &Hashed<EvaluatedTx>,
-    unspents: &mut HashMap<Vec<u8>, UnspentValue>,
-) -> (u64,u64) {
-    let mut spent_value = 0;
-    for input in &tx.value.inputs {
-        let key = input.outpoint.to_bytes();
-        if let Some(unspent) = unspents.remove(&key) {
-            spent_value += unspent.value;
-        }
-    }
-    (tx.value.in_count.value,spent_value)
+    unspents: &mut HashMap<OutpointKey, UnspentValue>,
+) -> (u64, u64) {
+    let spent_value = take_unspents(tx, unspents)
+        .iter()
+        .map(|unspent| unspent.value)
+        .sum();
+    (tx.value.in_count.value, spent_value)
 }
 
-fn bytes_to_hex_string(bytes: &[u8]) -> String {
-    let hex_chars: Vec<String> = bytes.iter()
-        .map(|byte| format!("{:02x}", byte)) // 格式化每个字节为两位十六进制
-        .collect();
-
-    hex_chars.join("") // 连接所有的十六进制字符
+/// Like `remove_unspents`, but returns the removed outputs.
+/// Inputs spending unknown outputs are skipped.
+pub fn take_unspents(
+    tx: &Hashed<EvaluatedTx>,
+    unspents: &mut HashMap<OutpointKey, UnspentValue>,
+) -> Vec<UnspentValue> {
+    tx.value
+        .inputs
+        .iter()
+        .filter_map(|input| unspents.remove(&input.outpoint.key()))
+        .collect()
 }
 
 /// Iterates over transaction outputs and adds valid unspents to HashMap.
 /// Returns the total number of valid outputs.
+/// The evaluated address is stored, as all callbacks aggregate by address, outputs without one
+/// get an empty address. Callbacks which need the script, like `balances --bucket-nonstandard`, read it from the output.
 pub fn insert_unspents(
     tx: &Hashed<EvaluatedTx>,
     block_height: u64,
-    unspents: &mut HashMap<Vec<u8>, UnspentValue>,
-) -> (u64,u64) {
+    unspents: &mut HashMap<OutpointKey, UnspentValue>,
+) -> (u64, u64) {
     let mut count = 0;
     let mut new_value = 0;
     for (i, output) in tx.value.outputs.iter().enumerate() {
         let unspent = UnspentValue {
             block_height,
-            address: bytes_to_hex_string(&output.out.script_pubkey),
+            address: CompactAddress::new(output.script.address.as_deref().unwrap_or_default()),
             value: output.out.value,
         };
 
-
-        let key = TxOutpoint::new(tx.hash, i as u32).to_bytes();
+        let key = TxOutpoint::new(tx.hash, i as u32).key();
         if !unspents.contains_key(&key) {
             new_value += unspent.value;
         }
         unspents.insert(key, unspent);
         count += 1;
     }
-    (count,new_value)
+    (count, new_value)
+}
+
+/// Core does not keep unspendable outputs and the genesis coinbase in its UTXO set.
+/// Like `CScript::IsUnspendable()` only scripts starting with OP_RETURN or longer than 10000 bytes
+/// are unspendable, other invalid opcodes (e.g. OP_RESERVED) only fail once the output is spent.
+pub fn is_tracked_by_core(script_pubkey: &[u8], block_height: u64) -> bool {
+    script_pubkey.first() != Some(&0x6a) && script_pubkey.len() <= 10000 && block_height > 0
+}
+
+/// Argument to compute the MuHash of the UTXO set, see `UtxoMuHash`
+pub fn muhash_height_arg() -> Arg {
+    Arg::new("muhash-height")
+        .long("muhash-height")
+        .value_name("HEIGHT")
+        .value_parser(clap::value_parser!(u64))
+        .help("Computes the MuHash of the UTXO set after the block at HEIGHT, compare it with `bitcoin-cli gettxoutsetinfo muhash HEIGHT`")
+}
+
+/// MuHash of the UTXO set at `--muhash-height`, as shown by `gettxoutsetinfo muhash` of Bitcoin Core.
+/// Callbacks tracking unspents pass each transaction to it, it keeps the SHA256 of every coin in Core's UTXO set
+/// until the height is reached, which needs about 100 bytes per unspent output.
+pub struct UtxoMuHash {
+    height: u64,
+    // key: txid + index, value: SHA256 of the coin serialized like in Core
+    digests: HashMap<OutpointKey, [u8; 32]>,
+    muhash: Option<String>,
+}
+
+impl UtxoMuHash {
+    /// Returns None if `--muhash-height` is not set
+    pub fn from_matches(matches: &ArgMatches) -> Option<Self> {
+        matches
+            .get_one::<u64>("muhash-height")
+            .map(|height| UtxoMuHash::new(*height))
+    }
+
+    pub fn new(height: u64) -> Self {
+        Self {
+            height,
+            digests: HashMap::new(),
+            muhash: None,
+        }
+    }
+
+    /// Adds a coin, e.g. of a UTXO snapshot. See `TxOutSer()` in kernel/coinstats.cpp for the serialization.
+    pub fn insert_coin(
+        &mut self,
+        outpoint: &TxOutpoint,
+        height: u64,
+        is_coinbase: bool,
+        out: &TxOutput,
+    ) {
+        let key = outpoint.key();
+        let mut data = key.to_vec();
+        data.extend(((height << 1) as u32 + is_coinbase as u32).to_le_bytes());
+        data.extend(out.to_bytes());
+        self.digests
+            .insert(key, sha256::Hash::hash(&data).to_byte_array());
+    }
+
+    /// Removes the spent and adds the created coins of the transaction.
+    /// Must be called for each transaction in block order, blocks above the height are ignored.
+    pub fn apply(&mut self, tx: &Hashed<EvaluatedTx>, block_height: u64) {
+        if block_height > self.height {
+            return;
+        }
+        let is_coinbase = tx.value.is_coinbase();
+        if !is_coinbase {
+            for input in &tx.value.inputs {
+                self.digests.remove(&input.outpoint.key());
+            }
+        }
+        for (i, output) in tx.value.outputs.iter().enumerate() {
+            if is_tracked_by_core(&output.out.script_pubkey, block_height) {
+                let outpoint = TxOutpoint::new(tx.hash, i as u32);
+                self.insert_coin(&outpoint, block_height, is_coinbase, &output.out);
+            }
+        }
+    }
+
+    /// Computes the hash once all transactions of the block at the height are applied
+    pub fn on_block_end(&mut self, block_height: u64) {
+        if block_height != self.height || self.muhash.is_some() {
+            return;
+        }
+        let mut muhash = MuHash3072::new();
+        for digest in self.digests.values() {
+            muhash.insert_digest(digest);
+        }
+        let mut hash = muhash.finalize();
+        // Shown in reversed byte order like a uint256 in Core
+        hash.reverse();
+        let hash = utils::arr_to_hex(&hash);
+        info!(target: "callback", "MuHash of the UTXO set with {} coins at height {}: {}",
+              self.digests.len(), block_height, hash);
+        self.digests = HashMap::new();
+        self.muhash = Some(hash);
+    }
+
+    /// Returns the hash if the height was reached
+    pub fn muhash(&self) -> Option<&str> {
+        self.muhash.as_deref()
+    }
+
+    /// The coins are not part of the checkpoints of `--resume`, so the hash can't be computed after resuming
+    pub fn disable_on_resume(muhash: &mut Option<Self>) {
+        if muhash.take().is_some() {
+            warn!(target: "callback", "--muhash-height is not supported when resuming from a checkpoint, no MuHash computed.");
+        }
+    }
+
+    /// Warns if the height was not reached
+    pub fn finish(&self) {
+        if self.muhash.is_none() {
+            warn!(target: "callback", "Height {} of --muhash-height was not reached, no MuHash computed.", self.height);
+        }
+    }
+}
+
+/// Sliding window which evicts unspents older than `size` blocks from a HashMap.
+/// Bounds the memory regardless of the chain length, which suits analyses of short-lived outputs.
+/// Spends of evicted outputs are not matched anymore, so this must not be used for balances.
+pub struct UnspentWindow {
+    size: u64,
+    // Keys of the outputs created per height, oldest first
+    created: VecDeque<(u64, Vec<OutpointKey>)>,
+}
+
+impl UnspentWindow {
+    /// Returns None if `--utxo-window` is not set
+    pub fn from_matches(matches: &ArgMatches) -> Option<Self> {
+        matches
+            .get_one::<u64>("utxo-window")
+            .map(|size| UnspentWindow::new(*size))
+    }
+
+    pub fn new(size: u64) -> Self {
+        Self {
+            size,
+            created: VecDeque::new(),
+        }
+    }
+
+    /// Remembers the outputs of the given transaction to evict them later on.
+    /// Must be called for each transaction passed to `insert_unspents()`.
+    pub fn track(&mut self, tx: &Hashed<EvaluatedTx>, block_height: u64) {
+        let keys = (0..tx.value.outputs.len()).map(|i| TxOutpoint::new(tx.hash, i as u32).key());
+        match self.created.back_mut() {
+            Some((height, created)) if *height == block_height => created.extend(keys),
+            _ => self.created.push_back((block_height, keys.collect())),
+        }
+    }
+
+    /// Removes the unspents created more than `size` blocks before the given height,
+    /// call it before the block is processed. Returns the number of evicted outputs.
+    pub fn evict(
+        &mut self,
+        block_height: u64,
+        unspents: &mut HashMap<OutpointKey, UnspentValue>,
+    ) -> u64 {
+        let mut count = 0;
+        while let Some((height, _)) = self.created.front() {
+            if height + self.size >= block_height {
+                break;
+            }
+            let (_, keys) = self.created.pop_front().unwrap();
+            count += keys
+                .iter()
+                .filter(|key| unspents.remove(*key).is_some())
+                .count() as u64;
+        }
+        count
+    }
+}
+
+/// Magic bytes and version at the beginning of files written by `save_unspents()`
+const UNSPENTS_MAGIC: [u8; 4] = *b"rbpu";
+const UNSPENTS_VERSION: u16 = 1;
+/// Size of an entry with an empty address
+const MIN_UNSPENT_SIZE: u64 = 36 + 4 + 8 + 2;
+
+/// Persists the unspents into a binary file, so an incremental or resumed run can continue with them.
+/// Header: magic bytes, version (u16), entry count (u64).
+/// Format per entry: key (36 bytes), block height (u32), value (u64), length of the compact address (u16), compact address
+pub fn save_unspents(path: &Path, unspents: &HashMap<OutpointKey, UnspentValue>) -> OpResult<()> {
+    let tmp_path = path.with_extension("tmp");
+    let mut writer = BufWriter::with_capacity(4000000, File::create(&tmp_path)?);
+    writer.write_all(&UNSPENTS_MAGIC)?;
+    writer.write_u16::<LittleEndian>(UNSPENTS_VERSION)?;
+    writer.write_u64::<LittleEndian>(unspents.len() as u64)?;
+    for (key, unspent) in unspents {
+        writer.write_all(key)?;
+        writer.write_u32::<LittleEndian>(unspent.block_height as u32)?;
+        writer.write_u64::<LittleEndian>(unspent.value)?;
+        let address = unspent.address.as_bytes();
+        writer.write_u16::<LittleEndian>(address.len() as u16)?;
+        writer.write_all(address)?;
+    }
+    writer.flush()?;
+    drop(writer);
+    utils::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+/// Loads unspents written by `save_unspents()`. Returns an empty map if the file doesn't exist.
+pub fn load_unspents(path: &Path) -> OpResult<HashMap<OutpointKey, UnspentValue>> {
+    if !path.exists() {
+        return Ok(HashMap::new());
+    }
+    let file = File::open(path)?;
+    let file_len = file.metadata()?.len();
+    let mut reader = BufReader::with_capacity(4000000, file);
+    let mut magic = [0u8; 4];
+    reader.read_exact(&mut magic)?;
+    if magic != UNSPENTS_MAGIC {
+        let msg = format!("{} is no unspents file", path.display());
+        return Err(OpError::new(OpErrorKind::InvalidArgsError).join_msg(&msg));
+    }
+    let version = reader.read_u16::<LittleEndian>()?;
+    if version != UNSPENTS_VERSION {
+        let msg = format!(
+            "Unsupported version {} of {}, remove it to start over",
+            version,
+            path.display()
+        );
+        return Err(OpError::new(OpErrorKind::InvalidArgsError).join_msg(&msg));
+    }
+    let count = reader.read_u64::<LittleEndian>()?;
+    let invalid = |msg: &str| {
+        let msg = format!("{} {}, remove it to start over", msg, path.display());
+        OpError::new(OpErrorKind::RuntimeError).join_msg(&msg)
+    };
+    // A corrupt count must not allocate more than the file can hold
+    if count > file_len / MIN_UNSPENT_SIZE {
+        return Err(invalid(&format!("{} unspents don't fit into", count)));
+    }
+    let mut unspents = HashMap::with_capacity(count as usize);
+    for _ in 0..count {
+        let (key, unspent) = read_unspent(&mut reader).map_err(|err| match err.kind {
+            OpErrorKind::IoError(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                invalid(&format!("Only {} of {} unspents in", unspents.len(), count))
+            }
+            _ => err,
+        })?;
+        unspents.insert(key, unspent);
+    }
+    if !reader.fill_buf()?.is_empty() {
+        return Err(invalid(&format!("More than {} unspents in", count)));
+    }
+    Ok(unspents)
+}
+
+/// Reads a single entry of `save_unspents()`
+fn read_unspent<R: Read>(reader: &mut R) -> OpResult<(OutpointKey, UnspentValue)> {
+    let mut key = [0u8; 36];
+    reader.read_exact(&mut key)?;
+    let block_height = reader.read_u32::<LittleEndian>()? as u64;
+    let value = reader.read_u64::<LittleEndian>()?;
+    let mut address = vec![0u8; reader.read_u16::<LittleEndian>()? as usize];
+    reader.read_exact(&mut address)?;
+    let address = CompactAddress::from_bytes(address)?;
+    let unspent = UnspentValue {
+        block_height,
+        value,
+        address,
+    };
+    Ok((key, unspent))
 }
 
 #[cfg(test)]
@@ -75,7 +1055,7 @@ mod tests {
 
     #[test]
     fn test_callback() {
-        let mut unspents: HashMap<Vec<u8>, UnspentValue> = HashMap::new();
+        let mut unspents: HashMap<OutpointKey, UnspentValue> = HashMap::new();
         let header = BlockHeader {
             version: 0,
             prev_hash: sha256d::Hash::all_zeros(),
@@ -109,15 +1089,15 @@ mod tests {
             0x7c, 0x88, 0xac, 0x00, 0x00, 0x00, 0x00,
         ];
         let mut reader = BufReader::new(Cursor::new(raw_data));
-        let txs = reader.read_txs(1, 0x00).unwrap();
+        let txs = reader.read_txs(1, &CoinType::default()).unwrap();
         let block1 = Block::new(0, header.clone(), None, VarUint::from(1u8), txs);
 
         for tx in &block1.txs {
-            remove_unspents(&tx, &mut unspents);
-            insert_unspents(&tx, 100000, &mut unspents);
+            remove_unspents(tx, &mut unspents);
+            insert_unspents(tx, 100000, &mut unspents);
         }
         let value = unspents
-            .get(&TxOutpoint::new(block1.txs[0].hash, 0).to_bytes())
+            .get(&TxOutpoint::new(block1.txs[0].hash, 0).key())
             .unwrap();
         assert_eq!(value.block_height, 100000);
         assert_eq!(value.value, 556000000);
@@ -247,25 +1227,309 @@ mod tests {
             0x72, 0xdc, 0x35, 0x92, 0x88, 0xac, 0x00, 0x00, 0x00, 0x00,
         ];
         let mut reader = BufReader::new(Cursor::new(raw_data));
-        let txs = reader.read_txs(1, 0x00).unwrap();
+        let txs = reader.read_txs(1, &CoinType::default()).unwrap();
         let block2 = Block::new(0, header.clone(), None, VarUint::from(1u8), txs);
 
         for tx in &block2.txs {
-            remove_unspents(&tx, &mut unspents);
-            insert_unspents(&tx, 105001, &mut unspents);
+            remove_unspents(tx, &mut unspents);
+            insert_unspents(tx, 105001, &mut unspents);
         }
 
         // Original unspent should no longer exist in the hashmap
-        assert!(unspents
-            .get(&TxOutpoint::new(block1.txs[0].hash, 0).to_bytes())
-            .is_none());
+        assert!(!unspents.contains_key(&TxOutpoint::new(block1.txs[0].hash, 0).key()));
 
         let value = unspents
-            .get(&TxOutpoint::new(block2.txs[0].hash, 0).to_bytes())
+            .get(&TxOutpoint::new(block2.txs[0].hash, 0).key())
             .unwrap();
 
         assert_eq!(value.block_height, 105001);
         assert_eq!(value.value, 9070000000);
         assert_eq!(value.address, "1EYXXHs5gV4pc7QAddmDj5z7m14QPHGvWL");
     }
+
+    #[test]
+    fn test_save_load_unspents() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("unspents.state");
+        assert!(load_unspents(&path).unwrap().is_empty());
+
+        let mut unspents = HashMap::new();
+        let key = TxOutpoint::new(sha256d::Hash::hash(&[0x01]), 7).key();
+        let unspent = UnspentValue {
+            block_height: 10,
+            value: 546,
+            address: CompactAddress::new("1JqDybm2nWTENrHvMyafbSXXtTk5Uv5QAn"),
+        };
+        unspents.insert(key, unspent);
+        save_unspents(&path, &unspents).unwrap();
+
+        let loaded = load_unspents(&path).unwrap();
+        assert_eq!(loaded.len(), 1);
+        let value = loaded.get(&key).unwrap();
+        assert_eq!(value.block_height, 10);
+        assert_eq!(value.value, 546);
+        assert_eq!(value.address, "1JqDybm2nWTENrHvMyafbSXXtTk5Uv5QAn");
+        assert_eq!(&std::fs::read(&path).unwrap()[..4], b"rbpu");
+
+        // Unknown versions and files without header are rejected
+        let mut data = std::fs::read(&path).unwrap();
+        data[4..6].copy_from_slice(&2u16.to_le_bytes());
+        std::fs::write(&path, &data).unwrap();
+        let err = load_unspents(&path).unwrap_err();
+        assert!(matches!(err.kind, OpErrorKind::InvalidArgsError), "{}", err);
+        assert!(err.to_string().contains("Unsupported version 2"), "{}", err);
+        std::fs::write(&path, &data[6..]).unwrap();
+        let err = load_unspents(&path).unwrap_err();
+        assert!(matches!(err.kind, OpErrorKind::InvalidArgsError), "{}", err);
+
+        // Corrupt counts are rejected instead of allocating for them
+        save_unspents(&path, &unspents).unwrap();
+        let data = std::fs::read(&path).unwrap();
+        for count in [u64::MAX, 2, 0] {
+            let mut corrupt = data.clone();
+            corrupt[6..14].copy_from_slice(&count.to_le_bytes());
+            std::fs::write(&path, corrupt).unwrap();
+            assert!(load_unspents(&path).is_err());
+        }
+        std::fs::write(&path, &data[..data.len() - 1]).unwrap();
+        let err = load_unspents(&path).unwrap_err();
+        assert!(err.to_string().contains("Only 0 of 1 unspents"), "{}", err);
+    }
+
+    #[test]
+    fn test_get_coin() {
+        let command = clap::Command::new("rusty-blockparser")
+            .arg(clap::Arg::new("coin").long("coin").global(true))
+            .subcommand(clap::Command::new("callback"));
+        let matches =
+            command.get_matches_from(["rusty-blockparser", "--coin", "litecoin", "callback"]);
+        let matches = matches.subcommand_matches("callback").unwrap();
+        assert_eq!(get_coin(matches).name, "Litecoin");
+
+        // Callbacks instantiated without the global option fall back to Bitcoin
+        let matches = clap::Command::new("callback").get_matches_from(["callback"]);
+        assert_eq!(get_coin(&matches).name, "Bitcoin");
+    }
+
+    #[test]
+    fn test_is_tracked_by_core() {
+        let p2pkh = crate::common::testutils::p2pkh_script(1);
+        assert!(is_tracked_by_core(&p2pkh, 1));
+        assert!(!is_tracked_by_core(&p2pkh, 0));
+        assert!(!is_tracked_by_core(&[0x6a, 0x01, 0xff], 1));
+        assert!(!is_tracked_by_core(&vec![0x51; 10001], 1));
+        // Core keeps outputs with other invalid opcodes, they only fail once spent
+        for op in [0x50, 0x62, 0x89, 0xff] {
+            assert!(is_tracked_by_core(&[op, 0x51], 1));
+        }
+    }
+
+    #[test]
+    fn test_callback_args() {
+        let args =
+            CallbackArgs::parse(" limit=10, name = x ,", &["limit", "name", "flag"]).unwrap();
+        assert_eq!(args.get::<u64>("limit").unwrap(), Some(10));
+        assert_eq!(args.get::<String>("name").unwrap(), Some(String::from("x")));
+        assert_eq!(args.get::<bool>("flag").unwrap(), None);
+        assert!(args.get::<bool>("name").is_err());
+
+        assert!(CallbackArgs::parse("limit", &["limit"]).is_err());
+        assert!(CallbackArgs::parse("limti=1", &["limit"]).is_err());
+        assert!(CallbackArgs::parse("limit=1,limit=2", &["limit"]).is_err());
+
+        let matches = clap::Command::new("callback").get_matches_from(["callback"]);
+        assert!(CallbackArgs::from_matches(&matches, &[])
+            .unwrap()
+            .args
+            .is_empty());
+    }
+
+    #[test]
+    fn test_utxo_muhash() {
+        use crate::common::testutils;
+
+        let block0 = testutils::block(
+            sha256d::Hash::all_zeros(),
+            0,
+            vec![testutils::coinbase(0, &[(50, testutils::p2pkh_script(0))])],
+        );
+        let block1 = testutils::block(
+            block0.header.hash,
+            600,
+            vec![testutils::coinbase(1, &[(50, testutils::p2pkh_script(1))])],
+        );
+        let block2 = testutils::block(
+            block1.header.hash,
+            1200,
+            vec![
+                testutils::coinbase(2, &[(50, testutils::p2pkh_script(2))]),
+                testutils::tx(
+                    &[(block1.txs[0].hash, 0)],
+                    &[
+                        (40, testutils::p2pkh_script(3)),
+                        (0, vec![0x6a]),
+                        (7, vec![0x50, 0x51]),
+                    ],
+                ),
+            ],
+        );
+
+        let mut tracker = UtxoMuHash::new(2);
+        for (height, block) in [&block0, &block1, &block2].iter().enumerate() {
+            for tx in &block.txs {
+                tracker.apply(tx, height as u64);
+            }
+            tracker.on_block_end(height as u64);
+        }
+
+        // The genesis output and OP_RETURN are not part of the UTXO set,
+        // the output starting with OP_RESERVED is like in Core
+        let mut expected = MuHash3072::new();
+        for (tx, vout, code) in [
+            (&block2.txs[0], 0, 5),
+            (&block2.txs[1], 0, 4),
+            (&block2.txs[1], 2, 4),
+        ] {
+            let mut data = TxOutpoint::new(tx.hash, vout as u32).to_bytes();
+            data.extend((code as u32).to_le_bytes());
+            data.extend(tx.value.outputs[vout].out.to_bytes());
+            expected.insert(&data);
+        }
+        let mut hash = expected.finalize();
+        hash.reverse();
+        assert_eq!(tracker.muhash(), Some(utils::arr_to_hex(&hash).as_str()));
+
+        let mut resumed = Some(UtxoMuHash::new(2));
+        UtxoMuHash::disable_on_resume(&mut resumed);
+        assert!(resumed.is_none());
+    }
+
+    /// Stream whose written bytes stay readable through the other handle
+    struct SharedBuf(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_json_lines_writer() {
+        let out = Rc::new(RefCell::new(Vec::new()));
+        let sink: Rc<RefCell<Box<dyn Write>>> =
+            Rc::new(RefCell::new(Box::new(SharedBuf(out.clone()))));
+        let mut outputs = DumpWriter::Json(JsonLinesWriter::new(
+            sink.clone(),
+            "tx_out",
+            &[
+                "txid TEXT NOT NULL",
+                "value INTEGER NOT NULL",
+                "address TEXT",
+                "type TEXT",
+            ],
+        ));
+        let mut blocks = DumpWriter::Json(JsonLinesWriter::new(
+            sink,
+            "blocks",
+            &["hash TEXT NOT NULL", "height INTEGER NOT NULL"],
+        ));
+        assert!(outputs.is_stdout() && !outputs.is_file());
+        blocks
+            .write_row(&[Field::Text("00ab"), Field::Int(1)])
+            .unwrap();
+        outputs
+            .write_row(&[
+                Field::Text("aa"),
+                Field::Int(5000000000),
+                Field::Text("1A\"B"),
+                Field::Text("p2pkh"),
+            ])
+            .unwrap();
+        // Tables only take typed rows
+        assert!(outputs.write_all(b"bb;0;;op_return\n").is_err());
+        blocks
+            .write_row(&[Field::Text("00cd"), Field::Int(2)])
+            .unwrap();
+        outputs
+            .write_row(&[
+                Field::Text("bb"),
+                Field::Int(0),
+                Field::Null,
+                Field::Text("op_return"),
+            ])
+            .unwrap();
+        outputs.finish().unwrap();
+        blocks.finish().unwrap();
+
+        let lines = String::from_utf8(out.borrow().clone()).unwrap();
+        assert_eq!(
+            lines.lines().collect::<Vec<&str>>(),
+            vec![
+                r#"{"table":"blocks","hash":"00ab","height":1}"#,
+                r#"{"table":"tx_out","txid":"aa","value":5000000000,"address":"1A\"B","type":"p2pkh"}"#,
+                r#"{"table":"blocks","hash":"00cd","height":2}"#,
+                r#"{"table":"tx_out","txid":"bb","value":0,"address":null,"type":"op_return"}"#,
+            ]
+        );
+        for line in lines.lines() {
+            serde_json::from_str::<Value>(line).unwrap();
+        }
+
+        let command = clap::Command::new("callback")
+            .arg(format_arg())
+            .arg(stdout_json_arg());
+        let dir = tempfile::tempdir().unwrap();
+        let matches = command
+            .clone()
+            .get_matches_from(["callback", "--stdout-json"]);
+        assert!(matches!(
+            DumpTarget::from_matches(dir.path(), &matches).unwrap(),
+            DumpTarget::Json(_)
+        ));
+        let args = ["callback", "--stdout-json", "--format", "csv"];
+        assert!(command.try_get_matches_from(args).is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "zstd")]
+    fn test_compress_outputs_zstd() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = utils::OutputConfig::new(None, None, Some(utils::Compression::Zstd)).unwrap();
+        utils::set_output_config(config);
+        let path = utils::output_path(dir.path(), "fees", 0, 10, "csv");
+        std::fs::write(&path, "height;fee\n0;0\n").unwrap();
+        let compressed = utils::output_path(dir.path(), "balances", 0, 10, "csv.zst");
+        std::fs::write(&compressed, "x").unwrap();
+        compress_outputs().unwrap();
+        utils::set_output_config(utils::OutputConfig::default());
+
+        assert!(!path.exists());
+        let data = std::fs::read(dir.path().join("fees-0-10.csv.zst")).unwrap();
+        assert_eq!(zstd::decompress(&data), b"height;fee\n0;0\n");
+        // Files compressed while writing are kept as they are
+        assert_eq!(std::fs::read(&compressed).unwrap(), b"x");
+    }
+
+    #[test]
+    #[cfg(not(feature = "sqlite"))]
+    fn test_dump_target_without_sqlite() {
+        let command = clap::Command::new("callback").arg(format_arg());
+        let dir = tempfile::tempdir().unwrap();
+        let matches = command
+            .clone()
+            .get_matches_from(["callback", "--format", "sqlite"]);
+        assert!(DumpTarget::from_matches(dir.path(), &matches).is_err());
+        let matches = command.get_matches_from(["callback"]);
+        assert!(matches!(
+            DumpTarget::from_matches(dir.path(), &matches).unwrap(),
+            DumpTarget::Files {
+                compression: None,
+                ..
+            }
+        ));
+    }
 }
@@ -1,24 +1,135 @@
-use std::fs::{self, File};
-use std::io::{BufWriter, Write};
-use std::path::PathBuf;
+use std::io::Write;
+use std::path::{Path, PathBuf};
 
 use clap::{Arg, ArgMatches, Command};
+use serde_json::{json, Value};
 
 use crate::blockchain::proto::block::Block;
+use crate::blockchain::proto::script;
 use crate::blockchain::proto::tx::{EvaluatedTx, EvaluatedTxOut, TxInput};
 use crate::blockchain::proto::Hashed;
-use crate::callbacks::Callback;
+use crate::callbacks::common::{self, DumpTarget, DumpWriter, OutputFilter, Table, Watchlist};
+use crate::callbacks::{Callback, Control, Metrics};
+use crate::common::field::Field;
 use crate::common::utils;
 use crate::errors::OpResult;
 
+/// Output files, or SQLite tables with `--format sqlite`. Keep in sync with the `write_row()` functions.
+const TABLES: [(&str, Table); 4] = [
+    (
+        "blocks",
+        Table {
+            columns: &[
+                "hash TEXT NOT NULL",
+                "height INTEGER NOT NULL",
+                "version INTEGER NOT NULL",
+                "blocksize INTEGER NOT NULL",
+                "hashPrev TEXT NOT NULL",
+                "hashMerkleRoot TEXT NOT NULL",
+                "nTime INTEGER NOT NULL",
+                "nBits INTEGER NOT NULL",
+                "nNonce INTEGER NOT NULL",
+            ],
+            indexes: &["hash", "height"],
+            replace: false,
+        },
+    ),
+    (
+        "transactions",
+        Table {
+            columns: &[
+                "txid TEXT NOT NULL",
+                "hashBlock TEXT NOT NULL",
+                "version INTEGER NOT NULL",
+                "lockTime INTEGER NOT NULL",
+                "isCoinbase INTEGER NOT NULL",
+            ],
+            indexes: &["txid", "hashBlock"],
+            replace: false,
+        },
+    ),
+    (
+        "tx_in",
+        Table {
+            columns: &[
+                "txid TEXT NOT NULL",
+                "hashPrevOut TEXT NOT NULL",
+                "indexPrevOut INTEGER NOT NULL",
+                "scriptSig TEXT",
+                "sequence INTEGER NOT NULL",
+            ],
+            indexes: &["txid", "hashPrevOut"],
+            replace: false,
+        },
+    ),
+    (
+        "tx_out",
+        Table {
+            columns: &[
+                "txid TEXT NOT NULL",
+                "indexOut INTEGER NOT NULL",
+                "value INTEGER NOT NULL",
+                "scriptPubKey TEXT",
+                "address TEXT",
+                "type TEXT NOT NULL",
+            ],
+            indexes: &["txid", "address"],
+            replace: false,
+        },
+    ),
+];
+
+/// tx_in and tx_out with `--with-asm`, the disassembled script is appended to the other columns
+const ASM_TABLES: [(&str, Table); 2] = [
+    (
+        "tx_in",
+        Table {
+            columns: &[
+                "txid TEXT NOT NULL",
+                "hashPrevOut TEXT NOT NULL",
+                "indexPrevOut INTEGER NOT NULL",
+                "scriptSig TEXT",
+                "sequence INTEGER NOT NULL",
+                "scriptSigAsm TEXT",
+            ],
+            indexes: &["txid", "hashPrevOut"],
+            replace: false,
+        },
+    ),
+    (
+        "tx_out",
+        Table {
+            columns: &[
+                "txid TEXT NOT NULL",
+                "indexOut INTEGER NOT NULL",
+                "value INTEGER NOT NULL",
+                "scriptPubKey TEXT",
+                "address TEXT",
+                "type TEXT NOT NULL",
+                "scriptPubKeyAsm TEXT",
+            ],
+            indexes: &["txid", "address"],
+            replace: false,
+        },
+    ),
+];
+
 /// Dumps the whole blockchain into csv files
 pub struct CsvDump {
     // Each structure gets stored in a separate csv file
     dump_folder: PathBuf,
-    block_writer: BufWriter<File>,
-    tx_writer: BufWriter<File>,
-    txin_writer: BufWriter<File>,
-    txout_writer: BufWriter<File>,
+    block_writer: DumpWriter,
+    tx_writer: DumpWriter,
+    txin_writer: DumpWriter,
+    txout_writer: DumpWriter,
+    // Of the dump files, see `DumpTarget::extension()`
+    extension: &'static str,
+    // Outputs which don't match are not dumped
+    filter: OutputFilter,
+    // Only transactions creating or spending watched outputs are dumped
+    watchlist: Option<Watchlist>,
+    // Appends the disassembled scripts to inputs and outputs
+    with_asm: bool,
 
     start_height: u64,
     tx_count: u64,
@@ -26,12 +137,6 @@ pub struct CsvDump {
     out_count: u64,
 }
 
-impl CsvDump {
-    fn create_writer(cap: usize, path: PathBuf) -> OpResult<BufWriter<File>> {
-        Ok(BufWriter::with_capacity(cap, File::create(path)?))
-    }
-}
-
 impl Callback for CsvDump {
     fn build_subcommand() -> Command
     where
@@ -47,6 +152,17 @@ impl Callback for CsvDump {
                     .index(1)
                     .required(true),
             )
+            .args(common::output_filter_args())
+            .arg(common::watch_addresses_arg())
+            .arg(
+                Arg::new("with-asm")
+                    .long("with-asm")
+                    .action(clap::ArgAction::SetTrue)
+                    .help("Adds the disassembled scriptSig and scriptPubKey as last column of tx_in and tx_out"),
+            )
+            .arg(common::gzip_arg())
+            .arg(common::format_arg().conflicts_with("gzip"))
+            .arg(common::stdout_json_arg().conflicts_with("gzip"))
     }
 
     fn new(matches: &ArgMatches) -> OpResult<Self>
@@ -54,13 +170,23 @@ impl Callback for CsvDump {
         Self: Sized,
     {
         let dump_folder = &PathBuf::from(matches.get_one::<String>("dump-folder").unwrap());
-        let cap = 4000000;
+        let target = DumpTarget::from_matches(dump_folder, matches)?;
+        let with_asm = matches.get_flag("with-asm");
+        let [blocks, transactions, tx_in, tx_out] = &TABLES;
+        let (tx_in, tx_out) = match with_asm {
+            true => (&ASM_TABLES[0], &ASM_TABLES[1]),
+            false => (tx_in, tx_out),
+        };
         let cb = CsvDump {
             dump_folder: PathBuf::from(dump_folder),
-            block_writer: CsvDump::create_writer(cap, dump_folder.join("blocks.csv.tmp"))?,
-            tx_writer: CsvDump::create_writer(cap, dump_folder.join("transactions.csv.tmp"))?,
-            txin_writer: CsvDump::create_writer(cap, dump_folder.join("tx_in.csv.tmp"))?,
-            txout_writer: CsvDump::create_writer(cap, dump_folder.join("tx_out.csv.tmp"))?,
+            block_writer: target.writer(blocks.0, &blocks.1)?,
+            tx_writer: target.writer(transactions.0, &transactions.1)?,
+            txin_writer: target.writer(tx_in.0, &tx_in.1)?,
+            txout_writer: target.writer(tx_out.0, &tx_out.1)?,
+            extension: target.extension(),
+            filter: OutputFilter::from_matches(matches),
+            watchlist: Watchlist::from_matches(matches)?,
+            with_asm,
             start_height: 0,
             tx_count: 0,
             in_count: 0,
@@ -75,46 +201,61 @@ impl Callback for CsvDump {
         Ok(())
     }
 
-    fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<()> {
+    fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<Control> {
         // serialize block
-        self.block_writer
-            .write_all(block.as_csv(block_height).as_bytes())?;
+        block.write_row(&mut self.block_writer, block_height)?;
 
         // serialize transaction
-        let block_hash = format!("{}", &block.header.hash);
+        let block_hash = utils::hash_to_string(&block.hash());
         for tx in &block.txs {
-            self.tx_writer
-                .write_all(tx.as_csv(&block_hash).as_bytes())?;
-            let txid_str = format!("{}", &tx.hash);
+            if let Some(watchlist) = self.watchlist.as_mut() {
+                if !watchlist.touches(tx) {
+                    continue;
+                }
+            }
+            tx.write_row(&mut self.tx_writer, &block_hash)?;
+            let txid_str = utils::hash_to_string(&tx.hash);
 
             // serialize inputs
             for input in &tx.value.inputs {
-                self.txin_writer
-                    .write_all(input.as_csv(&txid_str).as_bytes())?;
+                input.write_row(&mut self.txin_writer, &txid_str, self.with_asm)?;
             }
             self.in_count += tx.value.in_count.value;
 
             // serialize outputs
             for (i, output) in tx.value.outputs.iter().enumerate() {
-                self.txout_writer
-                    .write_all(output.as_csv(&txid_str, i as u32).as_bytes())?;
+                if !self.filter.matches(output) {
+                    continue;
+                }
+                output.write_row(&mut self.txout_writer, &txid_str, i as u32, self.with_asm)?;
+                self.out_count += 1;
             }
-            self.out_count += tx.value.out_count.value;
+            self.tx_count += 1;
         }
-        self.tx_count += block.tx_count.value;
-        Ok(())
+        Ok(Control::Continue)
     }
 
     fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
-        // Keep in sync with c'tor
-        for f in ["blocks", "transactions", "tx_in", "tx_out"] {
-            // Rename temp files
-            fs::rename(
-                self.dump_folder.as_path().join(format!("{}.csv.tmp", f)),
-                self.dump_folder
-                    .as_path()
-                    .join(format!("{}-{}-{}.csv", f, self.start_height, block_height)),
-            )?;
+        for writer in [
+            &mut self.block_writer,
+            &mut self.tx_writer,
+            &mut self.txin_writer,
+            &mut self.txout_writer,
+        ] {
+            writer.finish()?;
+        }
+
+        // Rename temp files, the rows of --format sqlite are committed already
+        if self.block_writer.is_file() {
+            let ext = self.extension;
+            for (f, _) in &TABLES {
+                utils::rename(
+                    self.dump_folder
+                        .as_path()
+                        .join(format!("{}.{}.tmp", f, ext)),
+                    utils::output_path(&self.dump_folder, f, self.start_height, block_height, ext),
+                )?;
+            }
         }
 
         info!(target: "callback", "Done.\nDumped blocks from height {} to {}:\n\
@@ -124,68 +265,489 @@ impl Callback for CsvDump {
              self.start_height, block_height, self.tx_count, self.in_count, self.out_count);
         Ok(())
     }
+
+    /// Spends of watched outputs are only recognized in ascending order
+    fn supports_reverse(&self) -> bool {
+        self.watchlist.is_none()
+    }
+
+    fn flush(&mut self) -> OpResult<()> {
+        self.block_writer.flush()?;
+        self.tx_writer.flush()?;
+        self.txin_writer.flush()?;
+        self.txout_writer.flush()?;
+        Ok(())
+    }
+
+    fn dump_folder(&self) -> Option<&Path> {
+        Some(&self.dump_folder)
+    }
+
+    /// Watched outputs are not persisted, so their spends would be missed by the next run
+    fn supports_incremental(&self) -> bool {
+        self.watchlist.is_none()
+    }
+
+    fn writes_stdout(&self) -> bool {
+        self.block_writer.is_stdout()
+    }
+
+    fn metrics(&self) -> Option<&dyn Metrics> {
+        Some(self)
+    }
+
+    fn summary(&self) -> Value {
+        json!({
+            "transactions": self.tx_count,
+            "inputs": self.in_count,
+            "outputs": self.out_count,
+        })
+    }
 }
 
 impl Block {
-    fn as_csv(&self, block_height: u64) -> String {
+    fn write_row(&self, writer: &mut DumpWriter, block_height: u64) -> OpResult<()> {
         // (@hash, height, version, blocksize, @hashPrev, @hashMerkleRoot, nTime, nBits, nNonce)
-        format!(
-            "{};{};{};{};{};{};{};{};{}\n",
-            &self.header.hash,
-            &block_height,
-            &self.header.value.version,
-            &self.size,
-            &self.header.value.prev_hash,
-            &self.header.value.merkle_root,
-            &self.header.value.timestamp,
-            &self.header.value.bits,
-            &self.header.value.nonce
-        )
+        writer.write_row(&[
+            Field::Text(&utils::hash_to_string(&self.header.hash)),
+            Field::Int(block_height as i64),
+            Field::Int(self.header.value.version as i64),
+            Field::Int(self.size as i64),
+            Field::Text(&utils::hash_to_string(&self.header.value.prev_hash)),
+            Field::Text(&utils::hash_to_string(&self.header.value.merkle_root)),
+            Field::Int(self.header.value.timestamp as i64),
+            Field::Int(self.header.value.bits as i64),
+            Field::Int(self.header.value.nonce as i64),
+        ])
     }
 }
 
 impl Hashed<EvaluatedTx> {
-    fn as_csv(&self, block_hash: &str) -> String {
-        // (@txid, @hashBlock, version, lockTime)
-        format!(
-            "{};{};{};{}\n",
-            &self.hash, &block_hash, &self.value.version, &self.value.locktime
-        )
+    fn write_row(&self, writer: &mut DumpWriter, block_hash: &str) -> OpResult<()> {
+        // (@txid, @hashBlock, version, lockTime, isCoinbase)
+        writer.write_row(&[
+            Field::Text(&utils::hash_to_string(&self.hash)),
+            Field::Text(block_hash),
+            Field::Int(self.value.version as i64),
+            Field::Int(self.value.locktime as i64),
+            Field::Int(self.value.is_coinbase() as i64),
+        ])
     }
 }
 
 impl TxInput {
-    fn as_csv(&self, txid: &str) -> String {
-        // (@txid, @hashPrevOut, indexPrevOut, scriptSig, sequence)
-        format!(
-            "{};{};{};{};{}\n",
-            &txid,
-            &self.outpoint.txid,
-            &self.outpoint.index,
-            &utils::arr_to_hex(&self.script_sig),
-            &self.seq_no
-        )
+    fn write_row(&self, writer: &mut DumpWriter, txid: &str, with_asm: bool) -> OpResult<()> {
+        // (@txid, @hashPrevOut, indexPrevOut, scriptSig, sequence[, scriptSigAsm])
+        let prev_txid = utils::hash_to_string(&self.outpoint.txid);
+        let script_sig = utils::arr_to_hex(&self.script_sig);
+        let asm = with_asm.then(|| script::disasm(&self.script_sig));
+        let mut fields = vec![
+            Field::Text(txid),
+            Field::Text(&prev_txid),
+            Field::Int(self.outpoint.index as i64),
+            Field::Text(&script_sig),
+            Field::Int(self.seq_no as i64),
+        ];
+        if let Some(asm) = &asm {
+            fields.push(Field::Text(asm));
+        }
+        writer.write_row(&fields)
     }
 }
 
 impl EvaluatedTxOut {
-    fn as_csv(&self, txid: &str, index: u32) -> String {
-        let address = match self.script.address.clone() {
-            Some(address) => address,
+    fn write_row(
+        &self,
+        writer: &mut DumpWriter,
+        txid: &str,
+        index: u32,
+        with_asm: bool,
+    ) -> OpResult<()> {
+        let address = match &self.script.address {
+            Some(address) => Field::Text(address),
             None => {
                 debug!(target: "csvdump", "Unable to evaluate address for utxo in txid: {} ({})", txid, self.script.pattern);
-                String::new()
+                Field::Null
             }
         };
 
-        // (@txid, indexOut, value, @scriptPubKey, address)
-        format!(
-            "{};{};{};{};{}\n",
-            &txid,
-            &index,
-            &self.out.value,
-            &utils::arr_to_hex(&self.out.script_pubkey),
-            &address
-        )
+        // (@txid, indexOut, value, @scriptPubKey, address, type[, scriptPubKeyAsm])
+        let script_pubkey = utils::arr_to_hex(&self.out.script_pubkey);
+        let asm = with_asm.then(|| script::disasm(&self.out.script_pubkey));
+        let mut fields = vec![
+            Field::Text(txid),
+            Field::Int(index as i64),
+            Field::Int(self.out.value as i64),
+            Field::Text(&script_pubkey),
+            address,
+            Field::Text(self.script.pattern.short_name()),
+        ];
+        if let Some(asm) = &asm {
+            fields.push(Field::Text(asm));
+        }
+        writer.write_row(&fields)
+    }
+}
+
+impl Metrics for CsvDump {
+    fn counters(&self) -> Vec<(&'static str, u64)> {
+        vec![
+            ("transactions", self.tx_count),
+            ("inputs", self.in_count),
+            ("outputs", self.out_count),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::testutils;
+    use bitcoin::hashes::{sha256d, Hash};
+    use std::fs;
+
+    #[test]
+    fn test_csvdump_only_type() {
+        let mut p2wpkh = vec![0x00, 0x14];
+        p2wpkh.extend([0x11; 20]);
+        let mut p2tr = vec![0x51, 0x20];
+        p2tr.extend([0x22; 32]);
+        let block = testutils::block(
+            sha256d::Hash::all_zeros(),
+            0,
+            vec![testutils::coinbase(
+                1,
+                &[
+                    (1000, testutils::p2pkh_script(1)),
+                    (2000, p2wpkh),
+                    (3000, p2tr.clone()),
+                    (0, vec![0x6a, 0x01, 0x00]),
+                ],
+            )],
+        );
+
+        let dump_folder = tempfile::tempdir().unwrap();
+        let matches = CsvDump::build_subcommand().get_matches_from([
+            "csvdump",
+            dump_folder.path().to_str().unwrap(),
+            "--only-type",
+            "p2tr",
+        ]);
+        let mut cb = CsvDump::new(&matches).unwrap();
+        cb.on_start(1).unwrap();
+        cb.on_block(&block, 1).unwrap();
+        cb.on_complete(1).unwrap();
+        drop(cb);
+
+        let tx_out = fs::read_to_string(dump_folder.path().join("tx_out-1-1.csv")).unwrap();
+        let rows = tx_out.lines().collect::<Vec<&str>>();
+        assert_eq!(rows.len(), 1);
+        let fields = rows[0].split(';').collect::<Vec<&str>>();
+        assert_eq!(fields[1], "2");
+        assert_eq!(fields[3], utils::arr_to_hex(&p2tr));
+        assert!(fields[4].starts_with("bc1p"));
+        assert_eq!(fields[5], "p2tr");
+    }
+
+    #[test]
+    fn test_csvdump_compress() {
+        let block = testutils::block(
+            sha256d::Hash::all_zeros(),
+            0,
+            vec![testutils::coinbase(
+                1,
+                &[(1000, testutils::p2pkh_script(1))],
+            )],
+        );
+
+        let dump_folder = tempfile::tempdir().unwrap();
+        let matches = CsvDump::build_subcommand()
+            .get_matches_from(["csvdump", dump_folder.path().to_str().unwrap()]);
+        utils::set_output_config(
+            utils::OutputConfig::new(None, None, Some(utils::Compression::Gzip)).unwrap(),
+        );
+        let mut cb = CsvDump::new(&matches).unwrap();
+        utils::set_output_config(utils::OutputConfig::default());
+        // The rows are compressed while writing, no uncompressed temp file is created
+        assert!(dump_folder.path().join("blocks.csv.gz.tmp").exists());
+        assert!(!dump_folder.path().join("blocks.csv.tmp").exists());
+        cb.on_start(1).unwrap();
+        cb.on_block(&block, 1).unwrap();
+        cb.on_complete(1).unwrap();
+        drop(cb);
+
+        let file = fs::File::open(dump_folder.path().join("tx_out-1-1.csv.gz")).unwrap();
+        let mut tx_out = String::new();
+        std::io::Read::read_to_string(&mut flate2::read::GzDecoder::new(file), &mut tx_out)
+            .unwrap();
+        assert_eq!(tx_out.lines().count(), 1);
+    }
+
+    #[test]
+    #[cfg(feature = "zstd")]
+    fn test_csvdump_zstd() {
+        let block = testutils::block(
+            sha256d::Hash::all_zeros(),
+            0,
+            vec![testutils::coinbase(
+                1,
+                &[(1000, testutils::p2pkh_script(1))],
+            )],
+        );
+
+        let dump_folder = tempfile::tempdir().unwrap();
+        let matches = CsvDump::build_subcommand()
+            .get_matches_from(["csvdump", dump_folder.path().to_str().unwrap()]);
+        utils::set_output_config(
+            utils::OutputConfig::new(None, None, Some(utils::Compression::Zstd)).unwrap(),
+        );
+        let mut cb = CsvDump::new(&matches).unwrap();
+        utils::set_output_config(utils::OutputConfig::default());
+        assert!(dump_folder.path().join("blocks.csv.zst.tmp").exists());
+        cb.on_start(1).unwrap();
+        cb.on_block(&block, 1).unwrap();
+        cb.on_complete(1).unwrap();
+        drop(cb);
+
+        let data = fs::read(dump_folder.path().join("tx_out-1-1.csv.zst")).unwrap();
+        let tx_out = String::from_utf8(crate::common::zstd::decompress(&data)).unwrap();
+        assert_eq!(tx_out.lines().count(), 1);
+        assert!(tx_out.contains(&utils::arr_to_hex(&testutils::p2pkh_script(1))));
+        assert!(!dump_folder.path().join("tx_out-1-1.csv").exists());
+    }
+
+    #[test]
+    fn test_csvdump_with_asm() {
+        let spend = testutils::tx(
+            &[(sha256d::Hash::hash(&[0x01]), 0)],
+            &[(4000, testutils::p2pkh_script(1))],
+        );
+        let block = testutils::block(sha256d::Hash::all_zeros(), 0, vec![spend]);
+
+        let dump_folder = tempfile::tempdir().unwrap();
+        let matches = CsvDump::build_subcommand().get_matches_from([
+            "csvdump",
+            dump_folder.path().to_str().unwrap(),
+            "--with-asm",
+        ]);
+        let mut cb = CsvDump::new(&matches).unwrap();
+        cb.on_start(1).unwrap();
+        cb.on_block(&block, 1).unwrap();
+        cb.on_complete(1).unwrap();
+        drop(cb);
+
+        let tx_in = fs::read_to_string(dump_folder.path().join("tx_in-1-1.csv")).unwrap();
+        let script_sig = &block.txs[0].value.inputs[0].script_sig;
+        assert!(tx_in.ends_with(&format!(";{}\n", script::disasm(script_sig))));
+        let tx_out = fs::read_to_string(dump_folder.path().join("tx_out-1-1.csv")).unwrap();
+        let fields = tx_out.trim_end().split(';').collect::<Vec<&str>>();
+        assert_eq!(fields.len(), 7);
+        assert!(fields[6].starts_with("OP_DUP OP_HASH160 "));
+        assert!(fields[6].ends_with(" OP_EQUALVERIFY OP_CHECKSIG"));
+    }
+
+    #[test]
+    fn test_csvdump_is_coinbase() {
+        let coinbase = testutils::coinbase(1, &[(5000, testutils::p2pkh_script(0))]);
+        let spend = testutils::tx(
+            &[(sha256d::Hash::hash(&[0x01]), 0)],
+            &[(4000, testutils::p2pkh_script(1))],
+        );
+        let block = testutils::block(sha256d::Hash::all_zeros(), 0, vec![coinbase, spend]);
+
+        let dump_folder = tempfile::tempdir().unwrap();
+        let matches = CsvDump::build_subcommand()
+            .get_matches_from(["csvdump", dump_folder.path().to_str().unwrap()]);
+        let mut cb = CsvDump::new(&matches).unwrap();
+        cb.on_start(1).unwrap();
+        cb.on_block(&block, 1).unwrap();
+        cb.on_complete(1).unwrap();
+        drop(cb);
+
+        let txs = fs::read_to_string(dump_folder.path().join("transactions-1-1.csv")).unwrap();
+        let flags = txs
+            .lines()
+            .map(|row| row.rsplit(';').next().unwrap())
+            .collect::<Vec<&str>>();
+        assert_eq!(flags, vec!["1", "0"]);
+    }
+
+    #[test]
+    fn test_csvdump_watch_addresses() {
+        let block1 = testutils::block(
+            sha256d::Hash::all_zeros(),
+            0,
+            vec![testutils::coinbase(
+                1,
+                &[
+                    (5000, testutils::p2pkh_script(0)),
+                    (3000, testutils::p2pkh_script(3)),
+                ],
+            )],
+        );
+        let block2 = testutils::block(
+            block1.header.hash,
+            600,
+            vec![
+                testutils::coinbase(2, &[(5000, testutils::p2pkh_script(1))]),
+                testutils::tx(
+                    &[(block1.txs[0].hash, 1)],
+                    &[(2000, testutils::p2pkh_script(4))],
+                ),
+                testutils::tx(
+                    &[(block1.txs[0].hash, 0)],
+                    &[(4000, testutils::p2pkh_script(2))],
+                ),
+            ],
+        );
+        let watched = block1.txs[0].value.outputs[0]
+            .script
+            .address
+            .clone()
+            .unwrap();
+
+        let dump_folder = tempfile::tempdir().unwrap();
+        let watch_file = dump_folder.path().join("watch.txt");
+        fs::write(&watch_file, format!("# watched\n\n{}\n", watched)).unwrap();
+        let matches = CsvDump::build_subcommand().get_matches_from([
+            "csvdump",
+            dump_folder.path().to_str().unwrap(),
+            "--watch-addresses",
+            watch_file.to_str().unwrap(),
+        ]);
+        let mut cb = CsvDump::new(&matches).unwrap();
+        assert!(!cb.supports_reverse());
+        cb.on_start(1).unwrap();
+        cb.on_block(&block1, 1).unwrap();
+        cb.on_block(&block2, 2).unwrap();
+        cb.on_complete(2).unwrap();
+        drop(cb);
+
+        // The transaction creating and the one spending the watched output
+        let txs = fs::read_to_string(dump_folder.path().join("transactions-1-2.csv")).unwrap();
+        let txids = txs
+            .lines()
+            .map(|row| row.split(';').next().unwrap())
+            .collect::<Vec<&str>>();
+        assert_eq!(
+            txids,
+            vec![
+                utils::hash_to_string(&block1.txs[0].hash),
+                utils::hash_to_string(&block2.txs[2].hash)
+            ]
+        );
+        let tx_in = fs::read_to_string(dump_folder.path().join("tx_in-1-2.csv")).unwrap();
+        assert_eq!(tx_in.lines().count(), 2);
+        let blocks = fs::read_to_string(dump_folder.path().join("blocks-1-2.csv")).unwrap();
+        assert_eq!(blocks.lines().count(), 2);
+    }
+
+    #[test]
+    fn test_csvdump_parquet() {
+        let block = testutils::block(
+            sha256d::Hash::all_zeros(),
+            0,
+            vec![testutils::coinbase(
+                1,
+                &[(5000, testutils::p2pkh_script(0))],
+            )],
+        );
+
+        let dump_folder = tempfile::tempdir().unwrap();
+        let matches = CsvDump::build_subcommand().get_matches_from([
+            "csvdump",
+            dump_folder.path().to_str().unwrap(),
+            "--format",
+            "parquet",
+        ]);
+        let mut cb = CsvDump::new(&matches).unwrap();
+        cb.on_start(1).unwrap();
+        cb.on_block(&block, 1).unwrap();
+        cb.on_complete(1).unwrap();
+
+        let rows =
+            |name: &str| crate::common::parquet::reader::read_rows(&dump_folder.path().join(name));
+        let tx = &block.txs[0];
+        let txid = utils::hash_to_string(&tx.hash);
+        assert_eq!(rows("blocks-1-1.parquet")[0][1], "1");
+        assert_eq!(
+            rows("transactions-1-1.parquet"),
+            vec![vec![
+                txid.clone(),
+                utils::hash_to_string(&block.header.hash),
+                tx.value.version.to_string(),
+                String::from("0"),
+                String::from("1"),
+            ]]
+        );
+        assert_eq!(rows("tx_in-1-1.parquet").len(), 1);
+        let tx_out = rows("tx_out-1-1.parquet");
+        assert_eq!(
+            tx_out[0][..3],
+            [txid, String::from("0"), String::from("5000")]
+        );
+        assert_eq!(tx_out[0][5], "p2pkh");
+        assert!(!dump_folder.path().join("tx_out-1-1.csv").exists());
+    }
+
+    #[test]
+    #[cfg(feature = "sqlite")]
+    fn test_csvdump_sqlite() {
+        let block1 = testutils::block(
+            sha256d::Hash::all_zeros(),
+            0,
+            vec![testutils::coinbase(
+                1,
+                &[(5000, testutils::p2pkh_script(0))],
+            )],
+        );
+        let block2 = testutils::block(
+            block1.header.hash,
+            600,
+            vec![
+                testutils::coinbase(2, &[(5000, testutils::p2pkh_script(1))]),
+                testutils::tx(
+                    &[(block1.txs[0].hash, 0)],
+                    &[(4000, testutils::p2pkh_script(2))],
+                ),
+            ],
+        );
+
+        // The second run appends to the tables of the first one
+        let dump_folder = tempfile::tempdir().unwrap();
+        for (height, block) in [(1, &block1), (2, &block2)] {
+            let matches = CsvDump::build_subcommand().get_matches_from([
+                "csvdump",
+                dump_folder.path().to_str().unwrap(),
+                "--format",
+                "sqlite",
+            ]);
+            let mut cb = CsvDump::new(&matches).unwrap();
+            cb.on_start(height).unwrap();
+            cb.on_block(block, height).unwrap();
+            cb.on_complete(height).unwrap();
+        }
+        assert!(!dump_folder.path().join("blocks-1-1.csv").exists());
+
+        let conn =
+            crate::common::sqlite::Connection::open(&dump_folder.path().join("bitcoin.sqlite"))
+                .unwrap();
+        let count = |table: &str| {
+            conn.query(&format!("SELECT count(*) FROM {}", table))
+                .unwrap()
+        };
+        assert_eq!(count("blocks"), vec![vec!["2"]]);
+        assert_eq!(count("transactions"), vec![vec!["3"]]);
+        assert_eq!(count("tx_in"), vec![vec!["3"]]);
+        assert_eq!(
+            conn.query("SELECT sum(value), type FROM tx_out GROUP BY type")
+                .unwrap(),
+            vec![vec!["14000", "p2pkh"]]
+        );
+        assert_eq!(
+            conn.query("SELECT indexPrevOut FROM tx_in WHERE hashPrevOut = (SELECT txid FROM transactions WHERE isCoinbase = 1 LIMIT 1)")
+                .unwrap(),
+            vec![vec!["0"]]
+        );
     }
 }
@@ -0,0 +1,206 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use clap::{Arg, ArgMatches, Command};
+
+use crate::blockchain::proto::block::Block;
+use crate::blockchain::proto::script::{self, ScriptPattern};
+use crate::blockchain::proto::tx::EvaluatedTx;
+use crate::callbacks::{common, Callback, Control};
+use crate::common::utils;
+use crate::errors::OpResult;
+
+/// Dumps the transactions whose OP_RETURN outputs violate the historical datacarrier policy
+/// of Bitcoin Core: at most one OP_RETURN output with up to 80 bytes of data
+pub struct DataCarrier {
+    dump_folder: PathBuf,
+    writer: common::DumpWriter,
+    max_payload: usize,
+
+    start_height: u64,
+    n_flagged: u64,
+}
+
+impl DataCarrier {
+    /// Returns the number of OP_RETURN outputs and the largest payload
+    fn op_returns(tx: &EvaluatedTx) -> (usize, usize) {
+        tx.outputs
+            .iter()
+            .filter(|o| matches!(o.script.pattern, ScriptPattern::OpReturn(_)))
+            .fold((0, 0), |(count, max_len), o| {
+                let len = script::op_return_payload_len(&o.out.script_pubkey);
+                (count + 1, max_len.max(len))
+            })
+    }
+}
+
+impl Callback for DataCarrier {
+    fn build_subcommand() -> Command
+    where
+        Self: Sized,
+    {
+        Command::new("datacarrier")
+            .about(
+                "Dumps transactions with more than one or oversized OP_RETURN outputs to CSV file",
+            )
+            .version("0.1")
+            .author("gcarq <egger.m@protonmail.com>")
+            .arg(
+                Arg::new("dump-folder")
+                    .help("Folder to store csv file")
+                    .index(1)
+                    .required(true),
+            )
+            .arg(
+                Arg::new("max-payload")
+                    .long("max-payload")
+                    .value_name("BYTES")
+                    .value_parser(clap::value_parser!(u64))
+                    .default_value("80")
+                    .help("Largest standard OP_RETURN payload"),
+            )
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let dump_folder = &PathBuf::from(matches.get_one::<String>("dump-folder").unwrap());
+        let cb = DataCarrier {
+            dump_folder: PathBuf::from(dump_folder),
+            writer: common::create_writer(dump_folder.join("datacarrier.csv.tmp"))?,
+            max_payload: *matches.get_one::<u64>("max-payload").unwrap() as usize,
+            start_height: 0,
+            n_flagged: 0,
+        };
+        Ok(cb)
+    }
+
+    fn on_start(&mut self, block_height: u64) -> OpResult<()> {
+        self.start_height = block_height;
+        info!(target: "callback", "Executing datacarrier with dump folder: {} ...", &self.dump_folder.display());
+        self.writer.write_all(
+            format!(
+                "{};{};{};{}\n",
+                "height", "txid", "op_return_count", "max_payload_len"
+            )
+            .as_bytes(),
+        )?;
+        Ok(())
+    }
+
+    /// Coinbases are not relayed, so the policy doesn't apply to them
+    fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<Control> {
+        for tx in block.txs.iter().skip(1) {
+            let (count, max_len) = DataCarrier::op_returns(&tx.value);
+            if count > 1 || max_len > self.max_payload {
+                self.writer.write_all(
+                    format!(
+                        "{};{};{};{}\n",
+                        block_height,
+                        utils::hash_to_string(&tx.hash),
+                        count,
+                        max_len
+                    )
+                    .as_bytes(),
+                )?;
+                self.n_flagged += 1;
+            }
+        }
+        Ok(Control::Continue)
+    }
+
+    fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+        self.writer.flush()?;
+        utils::rename(
+            self.dump_folder.as_path().join("datacarrier.csv.tmp"),
+            utils::output_path(
+                &self.dump_folder,
+                "datacarrier",
+                self.start_height,
+                block_height,
+                "csv",
+            ),
+        )?;
+
+        info!(target: "callback", "Done.\nFound {} nonstandard OP_RETURN transactions from height {} to {}.",
+             self.n_flagged, self.start_height, block_height);
+        Ok(())
+    }
+
+    fn supports_reverse(&self) -> bool {
+        true
+    }
+
+    fn flush(&mut self) -> OpResult<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    fn dump_folder(&self) -> Option<&Path> {
+        Some(&self.dump_folder)
+    }
+
+    fn supports_incremental(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::testutils;
+    use bitcoin::hashes::{sha256d, Hash};
+    use std::fs;
+
+    fn op_return(len: usize) -> Vec<u8> {
+        let mut script = vec![0x6a, 0x4c, len as u8];
+        script.extend(vec![0x61; len]);
+        script
+    }
+
+    #[test]
+    fn test_datacarrier() {
+        let outpoint = |i: u8| (sha256d::Hash::hash(&[i]), 0);
+        let output = (1000, testutils::p2pkh_script(1));
+        let block = testutils::block(
+            sha256d::Hash::all_zeros(),
+            0,
+            vec![
+                testutils::coinbase(
+                    1,
+                    &[
+                        (5000, testutils::p2pkh_script(0)),
+                        (0, op_return(36)),
+                        (0, op_return(36)),
+                    ],
+                ),
+                // Standard
+                testutils::tx(&[outpoint(1)], &[output.clone(), (0, op_return(80))]),
+                // Two OP_RETURN outputs
+                testutils::tx(
+                    &[outpoint(2)],
+                    &[(0, op_return(10)), output.clone(), (0, op_return(20))],
+                ),
+                // Oversized payload
+                testutils::tx(&[outpoint(3)], &[(0, op_return(81))]),
+            ],
+        );
+
+        let dump_folder = tempfile::tempdir().unwrap();
+        let matches = DataCarrier::build_subcommand()
+            .get_matches_from(["datacarrier", dump_folder.path().to_str().unwrap()]);
+        let mut cb = DataCarrier::new(&matches).unwrap();
+        cb.on_start(1).unwrap();
+        cb.on_block(&block, 1).unwrap();
+        cb.on_complete(1).unwrap();
+
+        let csv = fs::read_to_string(dump_folder.path().join("datacarrier-1-1.csv")).unwrap();
+        let rows: Vec<&str> = csv.lines().skip(1).collect();
+        let txid = |i: usize| utils::hash_to_string(&block.txs[i].hash);
+        assert_eq!(
+            rows,
+            vec![format!("1;{};2;20", txid(2)), format!("1;{};1;81", txid(3))]
+        );
+    }
+}
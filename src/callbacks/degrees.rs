@@ -0,0 +1,256 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use clap::{Arg, ArgMatches, Command};
+use serde_json::{json, Value};
+
+use crate::blockchain::proto::block::Block;
+use crate::blockchain::proto::tx::{OutpointKey, TxOutpoint};
+use crate::callbacks::{common, Callback, Control};
+use crate::common::address::CompactAddress;
+use crate::common::utils;
+use crate::errors::OpResult;
+
+/// Number of distinct transactions paying and funded by an address
+#[derive(Default)]
+struct Degree {
+    in_degree: u64,
+    out_degree: u64,
+}
+
+/// Dumps the in-degree (transactions paying the address) and out-degree
+/// (transactions spending from the address) of each address in the transaction graph.
+/// Several outputs or inputs of the same transaction count once.
+pub struct Degrees {
+    dump_folder: PathBuf,
+
+    // key: txid + index, value: address. Only outputs of counted addresses are kept
+    unspents: HashMap<OutpointKey, CompactAddress>,
+    degrees: HashMap<String, Degree>,
+    // Only the addresses of the watchlist are counted
+    watchlist: bool,
+
+    start_height: u64,
+}
+
+impl Degrees {
+    fn is_counted(&self, address: &str) -> bool {
+        !self.watchlist || self.degrees.contains_key(address)
+    }
+}
+
+impl Callback for Degrees {
+    fn build_subcommand() -> Command
+    where
+        Self: Sized,
+    {
+        Command::new("degrees")
+            .about("Dumps the in-degree and out-degree of each address in the transaction graph to CSV file")
+            .version("0.1")
+            .author("gcarq <egger.m@protonmail.com>")
+            .arg(
+                Arg::new("dump-folder")
+                    .help("Folder to store csv file")
+                    .index(1)
+                    .required(true),
+            )
+            .arg(
+                Arg::new("watchlist")
+                    .long("watchlist")
+                    .value_name("FILE")
+                    .help("Only counts the addresses in FILE (one per line), which saves memory"),
+            )
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let mut cb = Degrees {
+            dump_folder: PathBuf::from(matches.get_one::<String>("dump-folder").unwrap()),
+            unspents: HashMap::with_capacity(10000000),
+            degrees: HashMap::new(),
+            watchlist: false,
+            start_height: 0,
+        };
+        if let Some(path) = matches.get_one::<String>("watchlist") {
+            for address in common::read_watchlist(Path::new(path))? {
+                cb.degrees.insert(address, Degree::default());
+            }
+            cb.watchlist = true;
+        }
+        Ok(cb)
+    }
+
+    fn on_start(&mut self, block_height: u64) -> OpResult<()> {
+        self.start_height = block_height;
+        if self.watchlist {
+            info!(target: "callback", "Executing degrees for {} watched addresses with dump folder: {} ...",
+                  self.degrees.len(), &self.dump_folder.display());
+        } else {
+            info!(target: "callback", "Executing degrees with dump folder: {} ...", &self.dump_folder.display());
+        }
+        Ok(())
+    }
+
+    /// Inputs spending outputs below the start height are not counted
+    fn on_block(&mut self, block: &Block, _block_height: u64) -> OpResult<Control> {
+        for tx in &block.txs {
+            let mut spenders: Vec<String> = tx
+                .value
+                .inputs
+                .iter()
+                .filter_map(|input| self.unspents.remove(&input.outpoint.key()))
+                .map(|address| address.to_string())
+                .collect();
+            spenders.sort_unstable();
+            spenders.dedup();
+            for address in spenders {
+                self.degrees.entry(address).or_default().out_degree += 1;
+            }
+
+            let mut receivers: Vec<&String> = Vec::new();
+            for (i, output) in tx.value.outputs.iter().enumerate() {
+                let address = match &output.script.address {
+                    Some(address) if self.is_counted(address) => address,
+                    _ => continue,
+                };
+                let key = TxOutpoint::new(tx.hash, i as u32).key();
+                self.unspents.insert(key, CompactAddress::new(address));
+                receivers.push(address);
+            }
+            receivers.sort_unstable();
+            receivers.dedup();
+            for address in receivers {
+                match self.degrees.get_mut(address) {
+                    Some(degree) => degree.in_degree += 1,
+                    None => {
+                        let degree = Degree {
+                            in_degree: 1,
+                            out_degree: 0,
+                        };
+                        self.degrees.insert(address.clone(), degree);
+                    }
+                }
+            }
+        }
+        Ok(Control::Continue)
+    }
+
+    fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+        let mut rows: Vec<(&String, &Degree)> = self.degrees.iter().collect();
+        rows.sort_unstable_by_key(|(address, _)| *address);
+
+        let tmp_path = self.dump_folder.join("degrees.csv.tmp");
+        let mut writer = BufWriter::with_capacity(4000000, File::create(&tmp_path)?);
+        writer.write_all(format!("{};{};{}\n", "address", "in_degree", "out_degree").as_bytes())?;
+        for (address, degree) in &rows {
+            writer.write_all(
+                format!("{};{};{}\n", address, degree.in_degree, degree.out_degree).as_bytes(),
+            )?;
+        }
+        writer.flush()?;
+        drop(writer);
+        utils::rename(
+            tmp_path,
+            utils::output_path(
+                &self.dump_folder,
+                "degrees",
+                self.start_height,
+                block_height,
+                "csv",
+            ),
+        )?;
+
+        info!(target: "callback", "Done.\nDumped the degrees of {} addresses from height {} to {}.",
+             rows.len(), self.start_height, block_height);
+        Ok(())
+    }
+
+    fn dump_folder(&self) -> Option<&Path> {
+        Some(&self.dump_folder)
+    }
+
+    fn summary(&self) -> Value {
+        json!({ "addresses": self.degrees.len() })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::testutils;
+    use bitcoin::hashes::{sha256d, Hash};
+    use std::fs;
+
+    #[test]
+    fn test_degrees() {
+        // Address 1 is paid twice by the coinbase, which counts once
+        let block1 = testutils::block(
+            sha256d::Hash::all_zeros(),
+            0,
+            vec![testutils::coinbase(
+                1,
+                &[
+                    (3000, testutils::p2pkh_script(1)),
+                    (2000, testutils::p2pkh_script(1)),
+                ],
+            )],
+        );
+        let coinbase = block1.txs[0].hash;
+        // Address 1 spends both outputs in one transaction and is paid by another one
+        let block2 = testutils::block(
+            block1.header.hash,
+            600,
+            vec![
+                testutils::coinbase(2, &[(5000, testutils::p2pkh_script(1))]),
+                testutils::tx(
+                    &[(coinbase, 0), (coinbase, 1)],
+                    &[(4900, testutils::p2pkh_script(2))],
+                ),
+            ],
+        );
+        let address1 = block1.txs[0].value.outputs[0]
+            .script
+            .address
+            .clone()
+            .unwrap();
+        let address2 = block2.txs[1].value.outputs[0]
+            .script
+            .address
+            .clone()
+            .unwrap();
+
+        let run = |args: &[&str]| {
+            let dump_folder = tempfile::tempdir().unwrap();
+            let matches = Degrees::build_subcommand().get_matches_from(
+                ["degrees", dump_folder.path().to_str().unwrap()]
+                    .iter()
+                    .chain(args),
+            );
+            let mut cb = Degrees::new(&matches).unwrap();
+            cb.on_start(1).unwrap();
+            cb.on_block(&block1, 1).unwrap();
+            cb.on_block(&block2, 2).unwrap();
+            cb.on_complete(2).unwrap();
+            fs::read_to_string(dump_folder.path().join("degrees-1-2.csv")).unwrap()
+        };
+
+        let mut expected = vec![format!("{};2;1", address1), format!("{};1;0", address2)];
+        expected.sort();
+        assert_eq!(run(&[]).lines().skip(1).collect::<Vec<_>>(), expected);
+
+        let watchlist_dir = tempfile::tempdir().unwrap();
+        let watchlist = watchlist_dir.path().join("watchlist.txt");
+        fs::write(&watchlist, &address2).unwrap();
+        assert_eq!(
+            run(&["--watchlist", watchlist.to_str().unwrap()])
+                .lines()
+                .skip(1)
+                .collect::<Vec<_>>(),
+            vec![format!("{};1;0", address2)]
+        );
+    }
+}
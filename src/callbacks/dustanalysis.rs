@@ -0,0 +1,408 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use clap::{Arg, ArgMatches, Command};
+use serde_json::{json, Value};
+
+use crate::blockchain::proto::block::Block;
+use crate::blockchain::proto::script::classify::{script_type, ScriptType};
+use crate::blockchain::proto::script::ScriptPattern;
+use crate::blockchain::proto::tx::{OutpointKey, TxOutpoint};
+use crate::callbacks::{common, Callback, Control};
+use crate::common::utils;
+use crate::errors::OpResult;
+
+/// Type name of provably unspendable outputs, which are never added to the unspents
+const UNSPENDABLE: &str = "unspendable";
+
+/// Number and value of outputs
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct Usage {
+    count: u64,
+    value: u64,
+}
+
+impl Usage {
+    fn add(&mut self, value: u64) {
+        self.count += 1;
+        self.value += value;
+    }
+}
+
+/// Unspent outputs of one output type
+struct TypeUsage {
+    /// Estimated vsize of an input spending them, see `ScriptType::input_vsize()`
+    input_vsize: Option<u64>,
+    /// Indexed by `DustAnalysis::bucket()`
+    buckets: Vec<Usage>,
+    /// Outputs worth at most their spend cost, in the order of `--feerates`
+    uneconomical: Vec<Usage>,
+}
+
+/// Classifies the UTXO set at the end height by output type and value bucket
+/// and counts the outputs whose value doesn't cover the fee of spending them at the given feerates
+pub struct DustAnalysis {
+    dump_folder: PathBuf,
+    writer: common::DumpWriter,
+    feerate_writer: common::DumpWriter,
+    feerates: Vec<f64>,
+
+    // Value and type of the unspent outputs, key: txid + index
+    unspents: HashMap<OutpointKey, (u64, ScriptType)>,
+    unspendable: Usage,
+    // Uneconomical outputs per feerate for the summary, in the order of `feerates`
+    uneconomical: Vec<Usage>,
+
+    start_height: u64,
+}
+
+impl DustAnalysis {
+    /// Index of the value bucket `[10^i, 10^(i+1))` sat, the first one starts at 0
+    fn bucket(value: u64) -> usize {
+        value.max(1).ilog10() as usize
+    }
+
+    fn percentage(part: u64, total: u64) -> String {
+        match total {
+            0 => String::new(),
+            total => format!("{:.2}", part as f64 * 100.0 / total as f64),
+        }
+    }
+
+    /// Writes the buckets of one output type to `dustanalysis.csv.tmp`
+    fn write_buckets(&mut self, name: &str, buckets: &[Usage]) -> OpResult<()> {
+        for (i, usage) in buckets.iter().enumerate() {
+            if usage.count == 0 {
+                continue;
+            }
+            let min = 10u64.pow(i as u32);
+            self.writer.write_all(
+                format!(
+                    "{};{};{};{};{}\n",
+                    name,
+                    if i == 0 { 0 } else { min },
+                    min.saturating_mul(10) - 1,
+                    usage.count,
+                    usage.value
+                )
+                .as_bytes(),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl Callback for DustAnalysis {
+    fn build_subcommand() -> Command
+    where
+        Self: Sized,
+    {
+        Command::new("dustanalysis")
+            .about("Dumps the UTXO set by output type and value bucket and the uneconomical outputs at given feerates to CSV file")
+            .version("0.1")
+            .author("gcarq <egger.m@protonmail.com>")
+            .arg(
+                Arg::new("dump-folder")
+                    .help("Folder to store csv file")
+                    .index(1)
+                    .required(true),
+            )
+            .arg(
+                Arg::new("feerates")
+                    .long("feerates")
+                    .value_name("RATES")
+                    .value_parser(clap::value_parser!(f64))
+                    .value_delimiter(',')
+                    .default_value("1,5,10,20,50,100")
+                    .help("Feerates in sat/vB (comma separated) at which the spend cost of each output is compared to its value"),
+            )
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let dump_folder = &PathBuf::from(matches.get_one::<String>("dump-folder").unwrap());
+        let feerates: Vec<f64> = matches
+            .get_many::<f64>("feerates")
+            .unwrap()
+            .copied()
+            .collect();
+        let cb = DustAnalysis {
+            dump_folder: PathBuf::from(dump_folder),
+            writer: common::create_writer(dump_folder.join("dustanalysis.csv.tmp"))?,
+            feerate_writer: common::create_writer(
+                dump_folder.join("dustanalysis-feerates.csv.tmp"),
+            )?,
+            uneconomical: vec![Usage::default(); feerates.len()],
+            feerates,
+            unspents: HashMap::with_capacity(10000000),
+            unspendable: Usage::default(),
+            start_height: 0,
+        };
+        Ok(cb)
+    }
+
+    fn on_start(&mut self, block_height: u64) -> OpResult<()> {
+        self.start_height = block_height;
+        info!(target: "callback", "Executing dustanalysis with dump folder: {} ...", &self.dump_folder.display());
+        self.writer.write_all(
+            format!(
+                "{};{};{};{};{}\n",
+                "type", "minValue", "maxValue", "count", "value"
+            )
+            .as_bytes(),
+        )?;
+        self.feerate_writer.write_all(
+            format!(
+                "{};{};{};{};{};{};{};{}\n",
+                "type",
+                "inputVsize",
+                "feerate",
+                "count",
+                "value",
+                "uneconomicalCount",
+                "uneconomicalValue",
+                "uneconomicalShare"
+            )
+            .as_bytes(),
+        )?;
+        Ok(())
+    }
+
+    fn on_block(&mut self, block: &Block, _block_height: u64) -> OpResult<Control> {
+        for tx in &block.txs {
+            for input in &tx.value.inputs {
+                self.unspents.remove(&input.outpoint.key());
+            }
+            for (i, output) in tx.value.outputs.iter().enumerate() {
+                if matches!(
+                    output.script.pattern,
+                    ScriptPattern::OpReturn(_) | ScriptPattern::Unspendable
+                ) {
+                    self.unspendable.add(output.out.value);
+                    continue;
+                }
+                self.unspents.insert(
+                    TxOutpoint::new(tx.hash, i as u32).key(),
+                    (output.out.value, script_type(&output.out.script_pubkey)),
+                );
+            }
+        }
+        Ok(Control::Continue)
+    }
+
+    fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+        let mut types: BTreeMap<String, TypeUsage> = BTreeMap::new();
+        for (value, kind) in self.unspents.values() {
+            let usage = types.entry(kind.to_string()).or_insert_with(|| TypeUsage {
+                input_vsize: kind.input_vsize(),
+                buckets: Vec::new(),
+                uneconomical: vec![Usage::default(); self.feerates.len()],
+            });
+            let i = DustAnalysis::bucket(*value);
+            if usage.buckets.len() <= i {
+                usage.buckets.resize(i + 1, Usage::default());
+            }
+            usage.buckets[i].add(*value);
+            if let Some(vsize) = usage.input_vsize {
+                for (feerate, usage) in self.feerates.iter().zip(usage.uneconomical.iter_mut()) {
+                    if *value as f64 <= vsize as f64 * feerate {
+                        usage.add(*value);
+                    }
+                }
+            }
+        }
+
+        for (name, usage) in &types {
+            self.write_buckets(name, &usage.buckets)?;
+            let total = Usage {
+                count: usage.buckets.iter().map(|u| u.count).sum(),
+                value: usage.buckets.iter().map(|u| u.value).sum(),
+            };
+            let (vsize, uneconomical) = (usage.input_vsize, &usage.uneconomical);
+            for (j, feerate) in self.feerates.iter().enumerate() {
+                // The spend cost of outputs with an unknown spending script is unknown
+                let columns = match vsize {
+                    Some(_) => format!(
+                        "{};{};{}",
+                        uneconomical[j].count,
+                        uneconomical[j].value,
+                        DustAnalysis::percentage(uneconomical[j].count, total.count)
+                    ),
+                    None => String::from(";;"),
+                };
+                self.feerate_writer.write_all(
+                    format!(
+                        "{};{};{};{};{};{}\n",
+                        name,
+                        vsize.map(|v| v.to_string()).unwrap_or_default(),
+                        feerate,
+                        total.count,
+                        total.value,
+                        columns
+                    )
+                    .as_bytes(),
+                )?;
+                self.uneconomical[j].count += uneconomical[j].count;
+                self.uneconomical[j].value += uneconomical[j].value;
+            }
+        }
+
+        // Unspendable outputs can't be spent at any feerate
+        if self.unspendable.count > 0 {
+            self.writer.write_all(
+                format!(
+                    "{};;;{};{}\n",
+                    UNSPENDABLE, self.unspendable.count, self.unspendable.value
+                )
+                .as_bytes(),
+            )?;
+            for feerate in &self.feerates {
+                self.feerate_writer.write_all(
+                    format!(
+                        "{};;{};{};{};{};{};100.00\n",
+                        UNSPENDABLE,
+                        feerate,
+                        self.unspendable.count,
+                        self.unspendable.value,
+                        self.unspendable.count,
+                        self.unspendable.value
+                    )
+                    .as_bytes(),
+                )?;
+            }
+        }
+
+        self.writer.flush()?;
+        self.feerate_writer.flush()?;
+        for name in ["dustanalysis", "dustanalysis-feerates"] {
+            utils::rename(
+                self.dump_folder.as_path().join(format!("{}.csv.tmp", name)),
+                utils::output_path(
+                    &self.dump_folder,
+                    name,
+                    self.start_height,
+                    block_height,
+                    "csv",
+                ),
+            )?;
+        }
+
+        info!(target: "callback", "Done.\nClassified {} unspent outputs from height {} to {}, {} unspendable outputs.",
+             self.unspents.len(), self.start_height, block_height, self.unspendable.count);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> OpResult<()> {
+        self.writer.flush()?;
+        self.feerate_writer.flush()?;
+        Ok(())
+    }
+
+    fn dump_folder(&self) -> Option<&Path> {
+        Some(&self.dump_folder)
+    }
+
+    fn summary(&self) -> Value {
+        let uneconomical: serde_json::Map<String, Value> = self
+            .feerates
+            .iter()
+            .zip(&self.uneconomical)
+            .map(|(feerate, usage)| (feerate.to_string(), json!(usage.count)))
+            .collect();
+        json!({
+            "unspents": self.unspents.len(),
+            "unspendable": self.unspendable.count,
+            "uneconomical": uneconomical,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::testutils;
+    use bitcoin::hashes::{sha256d, Hash};
+    use std::fs;
+
+    #[test]
+    fn test_dustanalysis() {
+        let mut p2wpkh = vec![0x00, 0x14];
+        p2wpkh.extend([0x11; 20]);
+        let mut p2tr = vec![0x51, 0x20];
+        p2tr.extend([0x22; 32]);
+        let block1 = testutils::block(
+            sha256d::Hash::all_zeros(),
+            0,
+            vec![testutils::coinbase(
+                1,
+                &[
+                    (100, testutils::p2pkh_script(1)),
+                    (5000, testutils::p2pkh_script(2)),
+                    (300, p2wpkh),
+                    (0, vec![0x6a, 0x01, 0x00]),
+                    (50, vec![0x51]),
+                ],
+            )],
+        );
+        let spend = testutils::tx(&[(block1.txs[0].hash, 1)], &[(4000, p2tr)]);
+        let block2 = testutils::block(
+            block1.header.hash,
+            600,
+            vec![testutils::coinbase(2, &[]), spend],
+        );
+
+        let dump_folder = tempfile::tempdir().unwrap();
+        let matches = DustAnalysis::build_subcommand().get_matches_from([
+            "dustanalysis",
+            dump_folder.path().to_str().unwrap(),
+            "--feerates",
+            "1,10",
+        ]);
+        let mut cb = DustAnalysis::new(&matches).unwrap();
+        cb.on_start(1).unwrap();
+        cb.on_block(&block1, 1).unwrap();
+        cb.on_block(&block2, 2).unwrap();
+        cb.on_complete(2).unwrap();
+
+        let csv = fs::read_to_string(dump_folder.path().join("dustanalysis-1-2.csv")).unwrap();
+        assert_eq!(
+            csv.lines().collect::<Vec<_>>(),
+            vec![
+                "type;minValue;maxValue;count;value",
+                "nonstandard;10;99;1;50",
+                "p2pkh;100;999;1;100",
+                "p2tr;1000;9999;1;4000",
+                "p2wpkh;100;999;1;300",
+                "unspendable;;;1;0",
+            ]
+        );
+        let csv =
+            fs::read_to_string(dump_folder.path().join("dustanalysis-feerates-1-2.csv")).unwrap();
+        assert_eq!(
+            csv.lines().skip(1).collect::<Vec<_>>(),
+            vec![
+                "nonstandard;;1;1;50;;;",
+                "nonstandard;;10;1;50;;;",
+                "p2pkh;148;1;1;100;1;100;100.00",
+                "p2pkh;148;10;1;100;1;100;100.00",
+                "p2tr;58;1;1;4000;0;0;0.00",
+                "p2tr;58;10;1;4000;0;0;0.00",
+                "p2wpkh;68;1;1;300;0;0;0.00",
+                "p2wpkh;68;10;1;300;1;300;100.00",
+                "unspendable;;1;1;0;1;0;100.00",
+                "unspendable;;10;1;0;1;0;100.00",
+            ]
+        );
+        assert_eq!(
+            cb.summary(),
+            json!({
+                "unspents": 4,
+                "unspendable": 1,
+                "uneconomical": { "1": 1, "10": 2 },
+            })
+        );
+    }
+}
@@ -0,0 +1,429 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use clap::{Arg, ArgMatches, Command};
+
+use crate::blockchain::proto::block::Block;
+use crate::blockchain::proto::script::ScriptPattern;
+use crate::blockchain::proto::tx::{OutpointKey, TxOutpoint};
+use crate::callbacks::{common, Callback, Control, Metrics};
+use crate::common::utils;
+use crate::errors::OpResult;
+
+/// Percentiles of the fee rate distribution which are dumped for each block
+const PERCENTILES: [u64; 5] = [10, 25, 50, 75, 90];
+
+/// Dumps fee rate percentiles (sat/vB) per block, weighted by transaction count and by vsize.
+/// Also dumps the median and mean absolute fee (sat) and the median transaction value
+/// (sum of the outputs) per block to `feevalues-<start>-<end>.csv`.
+pub struct Fees {
+    dump_folder: PathBuf,
+    writer: common::DumpWriter,
+    value_writer: common::DumpWriter,
+
+    // key: txid + index, value: output value. Stays empty with `--undo`
+    unspents: HashMap<OutpointKey, u64>,
+    undo: bool,
+
+    start_height: u64,
+    n_txs: u64,
+    n_unknown_txs: u64,
+    total_fees: u64,
+}
+
+impl Fees {
+    /// Nearest-rank percentile where each transaction counts once.
+    /// Expects (fee rate, vsize) tuples sorted by fee rate.
+    pub(crate) fn count_weighted_percentile(rates: &[(f64, u64)], percentile: u64) -> f64 {
+        let rank = (percentile * rates.len() as u64).div_ceil(100).max(1);
+        rates[rank as usize - 1].0
+    }
+
+    /// Percentile where each transaction counts with its vsize, i.e. the fee rate
+    /// paid for the given share of the block space.
+    /// Expects (fee rate, vsize) tuples sorted by fee rate.
+    fn vsize_weighted_percentile(rates: &[(f64, u64)], percentile: u64) -> f64 {
+        let total_vsize: u64 = rates.iter().map(|(_, vsize)| vsize).sum();
+        let threshold = total_vsize as f64 * percentile as f64 / 100.0;
+        let mut cumulative = 0;
+        for (rate, vsize) in rates {
+            cumulative += vsize;
+            if cumulative as f64 >= threshold {
+                return *rate;
+            }
+        }
+        rates.last().unwrap().0
+    }
+
+    /// Nearest-rank median, i.e. the lower one of the two middle values for an even count.
+    /// Expects sorted values, is left empty if there are none.
+    fn fmt_median(values: &[u64]) -> String {
+        match values.is_empty() {
+            true => String::new(),
+            false => values[(values.len() - 1) / 2].to_string(),
+        }
+    }
+
+    /// Formats all PERCENTILES as csv fields, which are left empty for blocks without fee rates
+    fn fmt_percentiles(
+        rates: &[(f64, u64)],
+        percentile_fn: fn(&[(f64, u64)], u64) -> f64,
+    ) -> String {
+        PERCENTILES
+            .iter()
+            .map(|p| {
+                if rates.is_empty() {
+                    String::new()
+                } else {
+                    format!("{:.2}", percentile_fn(rates, *p))
+                }
+            })
+            .collect::<Vec<String>>()
+            .join(";")
+    }
+}
+
+impl Callback for Fees {
+    fn build_subcommand() -> Command
+    where
+        Self: Sized,
+    {
+        Command::new("fees")
+            .about("Dumps fee rate percentiles per block to CSV file")
+            .version("0.1")
+            .author("gcarq <egger.m@protonmail.com>")
+            .arg(
+                Arg::new("dump-folder")
+                    .help("Folder to store csv file")
+                    .index(1)
+                    .required(true),
+            )
+            .arg(common::undo_arg())
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let dump_folder = &PathBuf::from(matches.get_one::<String>("dump-folder").unwrap());
+        let undo = matches.get_flag("undo");
+        let cb = Fees {
+            dump_folder: PathBuf::from(dump_folder),
+            writer: common::create_writer(dump_folder.join("fees.csv.tmp"))?,
+            value_writer: common::create_writer(dump_folder.join("feevalues.csv.tmp"))?,
+            unspents: HashMap::with_capacity(if undo { 0 } else { 10000000 }),
+            undo,
+            start_height: 0,
+            n_txs: 0,
+            n_unknown_txs: 0,
+            total_fees: 0,
+        };
+        Ok(cb)
+    }
+
+    fn on_start(&mut self, block_height: u64) -> OpResult<()> {
+        self.start_height = block_height;
+        if block_height > 0 && !self.undo {
+            warn!(target: "callback", "Fees of transactions spending outputs below height {} are unknown!", block_height);
+        }
+        info!(target: "callback", "Executing fees with dump folder: {} ...", &self.dump_folder.display());
+        let columns = PERCENTILES
+            .iter()
+            .map(|p| format!("p{}", p))
+            .chain(PERCENTILES.iter().map(|p| format!("weightedP{}", p)))
+            .collect::<Vec<String>>()
+            .join(";");
+        self.writer.write_all(
+            format!("{};{};{};{}\n", "height", "txCount", "totalFee", columns).as_bytes(),
+        )?;
+        self.value_writer.write_all(
+            format!(
+                "{};{};{};{}\n",
+                "height", "median_fee", "mean_fee", "median_tx_value"
+            )
+            .as_bytes(),
+        )?;
+        Ok(())
+    }
+
+    /// Looks up the spent values of each transaction to calculate its fee rate.
+    /// Transactions spending unknown outputs are excluded from the percentiles and value statistics.
+    fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<Control> {
+        let mut rates = Vec::with_capacity(block.txs.len());
+        let mut fees = Vec::with_capacity(block.txs.len());
+        let mut values = Vec::with_capacity(block.txs.len());
+        let mut block_fees = 0;
+        for (tx_index, tx) in block.txs.iter().enumerate() {
+            let is_coinbase = tx.value.is_coinbase();
+            let in_value = match is_coinbase {
+                true => Some(0),
+                false => common::take_spent_value(block, tx_index, &mut self.unspents),
+            };
+
+            let mut out_value = 0;
+            for (i, output) in tx.value.outputs.iter().enumerate() {
+                out_value += output.out.value;
+                if self.undo
+                    || matches!(
+                        output.script.pattern,
+                        ScriptPattern::OpReturn(_) | ScriptPattern::Unspendable
+                    )
+                {
+                    continue;
+                }
+                self.unspents
+                    .insert(TxOutpoint::new(tx.hash, i as u32).key(), output.out.value);
+            }
+
+            if is_coinbase {
+                continue;
+            }
+            let in_value = match in_value {
+                Some(value) => value,
+                None => {
+                    self.n_unknown_txs += 1;
+                    continue;
+                }
+            };
+            let fee = in_value.saturating_sub(out_value);
+            block_fees += fee;
+            let vsize = tx.value.vsize();
+            rates.push((fee as f64 / vsize as f64, vsize));
+            fees.push(fee);
+            values.push(out_value);
+        }
+        rates.sort_by(|a, b| a.0.total_cmp(&b.0));
+        fees.sort_unstable();
+        values.sort_unstable();
+
+        self.writer.write_all(
+            format!(
+                "{};{};{};{};{}\n",
+                block_height,
+                rates.len(),
+                block_fees,
+                Fees::fmt_percentiles(&rates, Fees::count_weighted_percentile),
+                Fees::fmt_percentiles(&rates, Fees::vsize_weighted_percentile)
+            )
+            .as_bytes(),
+        )?;
+        let mean_fee = match fees.is_empty() {
+            true => String::new(),
+            false => format!("{:.2}", block_fees as f64 / fees.len() as f64),
+        };
+        self.value_writer.write_all(
+            format!(
+                "{};{};{};{}\n",
+                block_height,
+                Fees::fmt_median(&fees),
+                mean_fee,
+                Fees::fmt_median(&values)
+            )
+            .as_bytes(),
+        )?;
+        self.n_txs += rates.len() as u64;
+        self.total_fees += block_fees;
+        Ok(Control::Continue)
+    }
+
+    fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+        self.writer.flush()?;
+        utils::rename(
+            self.dump_folder.as_path().join("fees.csv.tmp"),
+            utils::output_path(
+                &self.dump_folder,
+                "fees",
+                self.start_height,
+                block_height,
+                "csv",
+            ),
+        )?;
+        self.value_writer.flush()?;
+        utils::rename(
+            self.dump_folder.as_path().join("feevalues.csv.tmp"),
+            utils::output_path(
+                &self.dump_folder,
+                "feevalues",
+                self.start_height,
+                block_height,
+                "csv",
+            ),
+        )?;
+
+        info!(target: "callback", "Done.\nScanned blocks from height {} to {}:\n\
+                                   \t-> transactions: {:9}\n\
+                                   \t-> unknown fees: {:9}\n\
+                                   \t-> total fees:   {:.8}",
+             self.start_height, block_height, self.n_txs, self.n_unknown_txs,
+             self.total_fees as f64 * 1E-8);
+        Ok(())
+    }
+
+    fn needs_undo(&self) -> bool {
+        self.undo
+    }
+
+    /// With the undo data each block is handled on its own
+    fn supports_reverse(&self) -> bool {
+        self.undo
+    }
+
+    fn flush(&mut self) -> OpResult<()> {
+        self.writer.flush()?;
+        self.value_writer.flush()?;
+        Ok(())
+    }
+
+    fn metrics(&self) -> Option<&dyn Metrics> {
+        Some(self)
+    }
+
+    fn dump_folder(&self) -> Option<&Path> {
+        Some(&self.dump_folder)
+    }
+
+    /// Without `--undo` the unspent outputs of previous runs would be unknown
+    fn supports_incremental(&self) -> bool {
+        self.undo
+    }
+}
+
+impl Metrics for Fees {
+    fn counters(&self) -> Vec<(&'static str, u64)> {
+        vec![
+            ("unspents", self.unspents.len() as u64),
+            ("unknown_fees", self.n_unknown_txs),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::proto::tx::{EvaluatedTx, RawTx};
+    use crate::common::testutils;
+    use bitcoin::hashes::{sha256d, Hash};
+    use std::fs;
+
+    /// Spends the given 100000 sat outpoint with n outputs, paying the given fee rate
+    fn paying(txid: sha256d::Hash, index: u32, n_outputs: u64, rate: u64) -> RawTx {
+        let build = |fee: u64| {
+            let mut outputs = vec![(
+                100000 - fee - (n_outputs - 1) * 1000,
+                testutils::p2pkh_script(1),
+            )];
+            for i in 1..n_outputs {
+                outputs.push((1000, testutils::p2pkh_script(i as u8)));
+            }
+            testutils::tx(&[(txid, index)], &outputs)
+        };
+        let vsize = EvaluatedTx::from(build(0)).vsize();
+        build(rate * vsize)
+    }
+
+    #[test]
+    fn test_fees_percentiles() {
+        let funding = testutils::block(
+            sha256d::Hash::all_zeros(),
+            0,
+            vec![testutils::coinbase(
+                1,
+                &[
+                    (100000, testutils::p2pkh_script(0)),
+                    (100000, testutils::p2pkh_script(0)),
+                    (100000, testutils::p2pkh_script(0)),
+                ],
+            )],
+        );
+        let cb_txid = funding.txs[0].hash;
+        // The large transaction occupies most of the block space at the lowest fee rate
+        let block = testutils::block(
+            funding.header.hash,
+            600,
+            vec![
+                testutils::coinbase(2, &[(5000000000, testutils::p2pkh_script(0))]),
+                paying(cb_txid, 0, 20, 1),
+                paying(cb_txid, 1, 1, 10),
+                paying(cb_txid, 2, 1, 100),
+            ],
+        );
+        assert!(block.txs[1].value.vsize() > block.txs[2].value.vsize() * 2);
+
+        let dump_folder = tempfile::tempdir().unwrap();
+        let matches = Fees::build_subcommand()
+            .get_matches_from(["fees", dump_folder.path().to_str().unwrap()]);
+        let mut cb = Fees::new(&matches).unwrap();
+        cb.on_start(1).unwrap();
+        cb.on_block(&funding, 1).unwrap();
+        cb.on_block(&block, 2).unwrap();
+        cb.on_complete(2).unwrap();
+
+        let csv = fs::read_to_string(dump_folder.path().join("fees-1-2.csv")).unwrap();
+        let mut lines = csv.lines().skip(1);
+        assert_eq!(lines.next().unwrap(), "1;0;0;;;;;;;;;;");
+        let row: Vec<&str> = lines.next().unwrap().split(';').collect();
+        assert_eq!(row[1], "3");
+        // Count weighted median
+        assert_eq!(row[5], "10.00");
+        // vsize weighted median
+        assert_eq!(row[10], "1.00");
+        assert_eq!(row[7], "100.00");
+        assert_eq!(row[12], "10.00");
+    }
+
+    #[test]
+    fn test_fees_values() {
+        let funding = testutils::block(
+            sha256d::Hash::all_zeros(),
+            0,
+            vec![testutils::coinbase(
+                1,
+                &[
+                    (100000, testutils::p2pkh_script(0)),
+                    (100000, testutils::p2pkh_script(0)),
+                    (100000, testutils::p2pkh_script(0)),
+                    (100000, testutils::p2pkh_script(0)),
+                ],
+            )],
+        );
+        let cb_txid = funding.txs[0].hash;
+        // Fees of 1000, 3000, 500 and 9500 sat
+        let block = testutils::block(
+            funding.header.hash,
+            600,
+            vec![
+                testutils::coinbase(2, &[(5000000000, testutils::p2pkh_script(0))]),
+                testutils::tx(&[(cb_txid, 0)], &[(99000, testutils::p2pkh_script(1))]),
+                testutils::tx(&[(cb_txid, 1)], &[(97000, testutils::p2pkh_script(2))]),
+                testutils::tx(
+                    &[(cb_txid, 2)],
+                    &[
+                        (49500, testutils::p2pkh_script(3)),
+                        (50000, testutils::p2pkh_script(4)),
+                    ],
+                ),
+                testutils::tx(&[(cb_txid, 3)], &[(90500, testutils::p2pkh_script(5))]),
+            ],
+        );
+
+        let dump_folder = tempfile::tempdir().unwrap();
+        let matches = Fees::build_subcommand()
+            .get_matches_from(["fees", dump_folder.path().to_str().unwrap()]);
+        let mut cb = Fees::new(&matches).unwrap();
+        cb.on_start(1).unwrap();
+        cb.on_block(&funding, 1).unwrap();
+        cb.on_block(&block, 2).unwrap();
+        cb.on_complete(2).unwrap();
+
+        let csv = fs::read_to_string(dump_folder.path().join("feevalues-1-2.csv")).unwrap();
+        assert_eq!(
+            csv.lines().collect::<Vec<_>>(),
+            vec![
+                "height;median_fee;mean_fee;median_tx_value",
+                "1;;;",
+                "2;1000;3500.00;97000"
+            ]
+        );
+    }
+}
@@ -0,0 +1,306 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use clap::{Arg, ArgMatches, Command};
+use serde_json::{json, Value};
+
+use crate::blockchain::parser::types::CoinType;
+use crate::blockchain::proto::block::Block;
+use crate::blockchain::proto::script::ScriptPattern;
+use crate::blockchain::proto::tx::{OutpointKey, TxOutpoint};
+use crate::callbacks::fees::Fees;
+use crate::callbacks::minerrevenue::MinerRevenue;
+use crate::callbacks::{common, Callback, Control, Metrics};
+use crate::common::utils;
+use crate::errors::OpResult;
+
+/// Dumps one row of fee statistics per block: the total, smallest and largest fee (sat),
+/// the median and average fee rate (sat/vB) and the subsidy along with the fee share of the miner revenue
+pub struct FeeStats {
+    coin: CoinType,
+    dump_folder: PathBuf,
+    writer: common::DumpWriter,
+
+    // key: txid + index, value: output value. Stays empty with `--undo`
+    unspents: HashMap<OutpointKey, u64>,
+    undo: bool,
+
+    start_height: u64,
+    n_txs: u64,
+    n_unknown_txs: u64,
+    total_fees: u64,
+}
+
+impl Callback for FeeStats {
+    fn build_subcommand() -> Command
+    where
+        Self: Sized,
+    {
+        Command::new("feestats")
+            .about("Dumps fee totals, fee rates and the fee share of the miner revenue per block to CSV file")
+            .version("0.1")
+            .author("gcarq <egger.m@protonmail.com>")
+            .arg(
+                Arg::new("dump-folder")
+                    .help("Folder to store csv file")
+                    .index(1)
+                    .required(true),
+            )
+            .arg(common::undo_arg())
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let dump_folder = &PathBuf::from(matches.get_one::<String>("dump-folder").unwrap());
+        let undo = matches.get_flag("undo");
+        let cb = FeeStats {
+            coin: common::get_coin(matches),
+            dump_folder: PathBuf::from(dump_folder),
+            writer: common::create_writer(dump_folder.join("feestats.csv.tmp"))?,
+            unspents: HashMap::with_capacity(if undo { 0 } else { 10000000 }),
+            undo,
+            start_height: 0,
+            n_txs: 0,
+            n_unknown_txs: 0,
+            total_fees: 0,
+        };
+        Ok(cb)
+    }
+
+    fn on_start(&mut self, block_height: u64) -> OpResult<()> {
+        self.start_height = block_height;
+        if block_height > 0 && !self.undo {
+            warn!(target: "callback", "Fees of transactions spending outputs below height {} are unknown!", block_height);
+        }
+        info!(target: "callback", "Executing feestats with dump folder: {} ...", &self.dump_folder.display());
+        self.writer.write_all(
+            format!(
+                "{};{};{};{};{};{};{};{};{}\n",
+                "height",
+                "txCount",
+                "totalFee",
+                "minFee",
+                "maxFee",
+                "medianFeeRate",
+                "avgFeeRate",
+                "subsidy",
+                "feeShare"
+            )
+            .as_bytes(),
+        )?;
+        Ok(())
+    }
+
+    /// Transactions spending unknown outputs are excluded from all fee columns.
+    /// The average fee rate is the total fee divided by the total vsize, i.e. weighted by vsize.
+    fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<Control> {
+        let mut rates = Vec::with_capacity(block.txs.len());
+        let (mut block_fees, mut block_vsize) = (0, 0);
+        let (mut min_fee, mut max_fee) = (u64::MAX, 0);
+        for (tx_index, tx) in block.txs.iter().enumerate() {
+            let is_coinbase = tx.value.is_coinbase();
+            let in_value = match is_coinbase {
+                true => Some(0),
+                false => common::take_spent_value(block, tx_index, &mut self.unspents),
+            };
+
+            let mut out_value = 0;
+            for (i, output) in tx.value.outputs.iter().enumerate() {
+                out_value += output.out.value;
+                if self.undo
+                    || matches!(
+                        output.script.pattern,
+                        ScriptPattern::OpReturn(_) | ScriptPattern::Unspendable
+                    )
+                {
+                    continue;
+                }
+                self.unspents
+                    .insert(TxOutpoint::new(tx.hash, i as u32).key(), output.out.value);
+            }
+
+            if is_coinbase {
+                continue;
+            }
+            let in_value = match in_value {
+                Some(value) => value,
+                None => {
+                    self.n_unknown_txs += 1;
+                    continue;
+                }
+            };
+            let fee = in_value.saturating_sub(out_value);
+            let vsize = tx.value.vsize();
+            block_fees += fee;
+            block_vsize += vsize;
+            min_fee = min_fee.min(fee);
+            max_fee = max_fee.max(fee);
+            rates.push((fee as f64 / vsize as f64, vsize));
+        }
+        rates.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+        // Fee columns are left empty for blocks without known fees
+        let fee_columns = match rates.is_empty() {
+            true => String::from(";;;"),
+            false => format!(
+                "{};{};{:.2};{:.2}",
+                min_fee,
+                max_fee,
+                Fees::count_weighted_percentile(&rates, 50),
+                block_fees as f64 / block_vsize as f64
+            ),
+        };
+        let subsidy = self
+            .coin
+            .block_subsidy(block_height, &block.header.value.prev_hash);
+        self.writer.write_all(
+            format!(
+                "{};{};{};{};{};{}\n",
+                block_height,
+                rates.len(),
+                block_fees,
+                fee_columns,
+                subsidy,
+                MinerRevenue::fee_percentage(subsidy, block_fees)
+            )
+            .as_bytes(),
+        )?;
+        self.n_txs += rates.len() as u64;
+        self.total_fees += block_fees;
+        Ok(Control::Continue)
+    }
+
+    fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+        self.writer.flush()?;
+        utils::rename(
+            self.dump_folder.as_path().join("feestats.csv.tmp"),
+            utils::output_path(
+                &self.dump_folder,
+                "feestats",
+                self.start_height,
+                block_height,
+                "csv",
+            ),
+        )?;
+
+        info!(target: "callback", "Done.\nScanned blocks from height {} to {}:\n\
+                                   \t-> transactions: {:9}\n\
+                                   \t-> unknown fees: {:9}\n\
+                                   \t-> total fees:   {:.8}",
+             self.start_height, block_height, self.n_txs, self.n_unknown_txs,
+             self.total_fees as f64 * 1E-8);
+        Ok(())
+    }
+
+    fn needs_undo(&self) -> bool {
+        self.undo
+    }
+
+    /// With the undo data each block is handled on its own
+    fn supports_reverse(&self) -> bool {
+        self.undo
+    }
+
+    fn flush(&mut self) -> OpResult<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    fn dump_folder(&self) -> Option<&Path> {
+        Some(&self.dump_folder)
+    }
+
+    /// Without `--undo` the unspent outputs of previous runs would be unknown
+    fn supports_incremental(&self) -> bool {
+        self.undo
+    }
+
+    fn metrics(&self) -> Option<&dyn Metrics> {
+        Some(self)
+    }
+
+    fn summary(&self) -> Value {
+        json!({
+            "transactions": self.n_txs,
+            "unknown_fees": self.n_unknown_txs,
+            "total_fees": self.total_fees,
+        })
+    }
+}
+
+impl Metrics for FeeStats {
+    fn counters(&self) -> Vec<(&'static str, u64)> {
+        vec![
+            ("unspents", self.unspents.len() as u64),
+            ("unknown_fees", self.n_unknown_txs),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::testutils;
+    use bitcoin::hashes::{sha256d, Hash};
+    use std::fs;
+
+    #[test]
+    fn test_feestats() {
+        let funding = testutils::block(
+            sha256d::Hash::all_zeros(),
+            0,
+            vec![testutils::coinbase(
+                1,
+                &[
+                    (100000, testutils::p2pkh_script(0)),
+                    (100000, testutils::p2pkh_script(0)),
+                    (100000, testutils::p2pkh_script(0)),
+                ],
+            )],
+        );
+        let cb_txid = funding.txs[0].hash;
+        // Fees of 1000, 3000 and 500 sat, the last one spends an unknown output
+        let block = testutils::block(
+            funding.header.hash,
+            600,
+            vec![
+                testutils::coinbase(2, &[(5000004000, testutils::p2pkh_script(0))]),
+                testutils::tx(&[(cb_txid, 0)], &[(99000, testutils::p2pkh_script(1))]),
+                testutils::tx(&[(cb_txid, 1)], &[(97000, testutils::p2pkh_script(2))]),
+                testutils::tx(
+                    &[(sha256d::Hash::hash(&[1]), 0)],
+                    &[(99500, testutils::p2pkh_script(3))],
+                ),
+            ],
+        );
+        let vsize = block.txs[1].value.vsize();
+        assert_eq!(block.txs[2].value.vsize(), vsize);
+
+        let dump_folder = tempfile::tempdir().unwrap();
+        let matches = FeeStats::build_subcommand()
+            .get_matches_from(["feestats", dump_folder.path().to_str().unwrap()]);
+        let mut cb = FeeStats::new(&matches).unwrap();
+        cb.on_start(1).unwrap();
+        cb.on_block(&funding, 1).unwrap();
+        cb.on_block(&block, 2).unwrap();
+        cb.on_complete(2).unwrap();
+
+        let csv = fs::read_to_string(dump_folder.path().join("feestats-1-2.csv")).unwrap();
+        assert_eq!(
+            csv.lines().collect::<Vec<_>>(),
+            vec![
+                String::from("height;txCount;totalFee;minFee;maxFee;medianFeeRate;avgFeeRate;subsidy;feeShare"),
+                String::from("1;0;0;;;;;5000000000;0.00"),
+                format!(
+                    "2;2;4000;1000;3000;{:.2};{:.2};5000000000;0.00",
+                    1000.0 / vsize as f64,
+                    2000.0 / vsize as f64
+                ),
+            ]
+        );
+        assert_eq!(cb.summary()["unknown_fees"], 1);
+    }
+}
@@ -0,0 +1,229 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use clap::{Arg, ArgMatches, Command};
+
+use crate::blockchain::proto::block::Block;
+use crate::blockchain::proto::tx::OutpointKey;
+use crate::callbacks::{common, Callback, Control};
+use crate::common::utils;
+use crate::errors::OpResult;
+
+const DAY: u64 = 24 * 60 * 60;
+const MONTH: u64 = 30 * DAY;
+const YEAR: u64 = 365 * DAY;
+
+/// Age bands with their exclusive upper bound in seconds, the last band is open-ended
+const AGE_BANDS: [(&str, u64); 11] = [
+    ("<1d", DAY),
+    ("1d-1w", 7 * DAY),
+    ("1w-1m", MONTH),
+    ("1m-3m", 3 * MONTH),
+    ("3m-6m", 6 * MONTH),
+    ("6m-12m", YEAR),
+    ("1y-2y", 2 * YEAR),
+    ("2y-3y", 3 * YEAR),
+    ("3y-5y", 5 * YEAR),
+    ("5y-10y", 10 * YEAR),
+    (">10y", u64::MAX),
+];
+
+/// Dumps the value of the UTXO set bucketed by the age of each UTXO ("HODL waves")
+pub struct HodlWaves {
+    dump_folder: PathBuf,
+
+    // key: txid + index
+    unspents: HashMap<OutpointKey, common::UnspentValue>,
+    // Block timestamp by height, used to convert the creation height of UTXOs to an age
+    timestamps: HashMap<u64, u32>,
+
+    start_height: u64,
+}
+
+impl HodlWaves {
+    /// Returns the index of the age band for the given age in seconds
+    fn age_band(age: u64) -> usize {
+        AGE_BANDS
+            .iter()
+            .position(|(_, upper)| age < *upper)
+            .unwrap_or(AGE_BANDS.len() - 1)
+    }
+
+    /// Sums up the value of all UTXOs per age band, relative to the given timestamp.
+    /// Block timestamps are not strictly increasing, so negative ages are treated as 0.
+    fn band_values(&self, end_timestamp: u32) -> [u64; AGE_BANDS.len()] {
+        let mut values = [0; AGE_BANDS.len()];
+        for unspent in self.unspents.values() {
+            let created = match self.timestamps.get(&unspent.block_height) {
+                Some(timestamp) => *timestamp,
+                None => continue,
+            };
+            let age = end_timestamp.saturating_sub(created) as u64;
+            values[HodlWaves::age_band(age)] += unspent.value;
+        }
+        values
+    }
+}
+
+impl Callback for HodlWaves {
+    fn build_subcommand() -> Command
+    where
+        Self: Sized,
+    {
+        Command::new("hodlwaves")
+            .about("Dumps the value of the UTXO set per age band (HODL waves) to CSV file")
+            .version("0.1")
+            .author("gcarq <egger.m@protonmail.com>")
+            .arg(
+                Arg::new("dump-folder")
+                    .help("Folder to store csv file")
+                    .index(1)
+                    .required(true),
+            )
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let dump_folder = &PathBuf::from(matches.get_one::<String>("dump-folder").unwrap());
+        let cb = HodlWaves {
+            dump_folder: PathBuf::from(dump_folder),
+            unspents: HashMap::with_capacity(10000000),
+            timestamps: HashMap::with_capacity(1000000),
+            start_height: 0,
+        };
+        Ok(cb)
+    }
+
+    fn on_start(&mut self, block_height: u64) -> OpResult<()> {
+        self.start_height = block_height;
+        info!(target: "callback", "Executing hodlwaves with dump folder: {} ...", &self.dump_folder.display());
+        Ok(())
+    }
+
+    fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<Control> {
+        self.timestamps
+            .insert(block_height, block.header.value.timestamp);
+        for tx in &block.txs {
+            common::remove_unspents(tx, &mut self.unspents);
+            common::insert_unspents(tx, block_height, &mut self.unspents);
+        }
+        Ok(Control::Continue)
+    }
+
+    /// Ages are relative to the timestamp of the last parsed block
+    fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+        let end_timestamp = *self.timestamps.get(&block_height).unwrap_or(&0);
+        let values = self.band_values(end_timestamp);
+        let total: u64 = values.iter().sum();
+
+        let path = utils::output_path(
+            &self.dump_folder,
+            "hodlwaves",
+            self.start_height,
+            block_height,
+            "csv",
+        );
+        let mut writer = BufWriter::new(File::create(path.with_extension("csv.tmp"))?);
+        writer.write_all(format!("{};{};{}\n", "ageBand", "value", "share").as_bytes())?;
+        for ((band, _), value) in AGE_BANDS.iter().zip(values) {
+            let share = if total > 0 {
+                value as f64 / total as f64
+            } else {
+                0.0
+            };
+            writer.write_all(format!("{};{};{:.6}\n", band, value, share).as_bytes())?;
+        }
+        writer.flush()?;
+        utils::rename(path.with_extension("csv.tmp"), &path)?;
+
+        info!(target: "callback", "Done.\nDumped {} age bands of {:.8} unspent value at height {}.",
+             AGE_BANDS.len(), total as f64 * 1E-8, block_height);
+        Ok(())
+    }
+
+    fn dump_folder(&self) -> Option<&Path> {
+        Some(&self.dump_folder)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::testutils;
+    use bitcoin::hashes::{sha256d, Hash};
+    use std::fs;
+
+    #[test]
+    fn test_age_band() {
+        assert_eq!(AGE_BANDS[HodlWaves::age_band(0)].0, "<1d");
+        assert_eq!(AGE_BANDS[HodlWaves::age_band(DAY)].0, "1d-1w");
+        assert_eq!(AGE_BANDS[HodlWaves::age_band(40 * DAY)].0, "1m-3m");
+        assert_eq!(AGE_BANDS[HodlWaves::age_band(4 * YEAR)].0, "3y-5y");
+        assert_eq!(AGE_BANDS[HodlWaves::age_band(20 * YEAR)].0, ">10y");
+    }
+
+    #[test]
+    fn test_hodlwaves() {
+        let end = 2 * YEAR as u32;
+        // Created 18 months, 2 weeks and 1 hour before the last block
+        let ages = [18 * MONTH as u32, 14 * DAY as u32, 3600];
+        let mut blocks: Vec<Block> = Vec::new();
+        for (i, age) in ages.iter().enumerate() {
+            let prev_hash = blocks
+                .last()
+                .map(|b| b.header.hash)
+                .unwrap_or(sha256d::Hash::all_zeros());
+            let value = 1000 * (i as u64 + 1);
+            blocks.push(testutils::block(
+                prev_hash,
+                end - age,
+                vec![testutils::coinbase(
+                    i as u64 + 1,
+                    &[(value, testutils::p2pkh_script(0))],
+                )],
+            ));
+        }
+        // The youngest UTXO is spent in the last block
+        let spend = testutils::tx(
+            &[(blocks[2].txs[0].hash, 0)],
+            &[(500, testutils::p2pkh_script(1))],
+        );
+        blocks.push(testutils::block(
+            blocks[2].header.hash,
+            end,
+            vec![
+                testutils::coinbase(4, &[(4000, testutils::p2pkh_script(0))]),
+                spend,
+            ],
+        ));
+
+        let dump_folder = tempfile::tempdir().unwrap();
+        let matches = HodlWaves::build_subcommand()
+            .get_matches_from(["hodlwaves", dump_folder.path().to_str().unwrap()]);
+        let mut cb = HodlWaves::new(&matches).unwrap();
+        cb.on_start(1).unwrap();
+        for (i, block) in blocks.iter().enumerate() {
+            cb.on_block(block, i as u64 + 1).unwrap();
+        }
+        cb.on_complete(4).unwrap();
+
+        let csv = fs::read_to_string(dump_folder.path().join("hodlwaves-1-4.csv")).unwrap();
+        let values: HashMap<&str, &str> = csv
+            .lines()
+            .skip(1)
+            .map(|line| {
+                let fields: Vec<&str> = line.split(';').collect();
+                (fields[0], fields[1])
+            })
+            .collect();
+        assert_eq!(values.len(), AGE_BANDS.len());
+        assert_eq!(values["<1d"], "4500");
+        assert_eq!(values["1w-1m"], "2000");
+        assert_eq!(values["1y-2y"], "1000");
+        assert_eq!(values["1d-1w"], "0");
+    }
+}
@@ -0,0 +1,232 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use clap::{Arg, ArgMatches, Command};
+
+use crate::blockchain::parser::types::CoinType;
+use crate::blockchain::proto::block::Block;
+use crate::blockchain::proto::script::ScriptPattern;
+use crate::callbacks::{common, Callback, Control};
+use crate::common::utils;
+use crate::errors::OpResult;
+
+/// Dumps the value of the coinbase outputs which can not be spent yet per block.
+/// Consensus rejects spends within the maturity window, so this only needs the coinbase values
+/// of the last `coinbase_maturity` blocks and no UTXO set.
+pub struct ImmatureCoinbase {
+    coin: CoinType,
+    dump_folder: PathBuf,
+    writer: common::DumpWriter,
+
+    /// Spendable coinbase value of the most recent blocks, oldest first
+    window: VecDeque<u64>,
+    /// Sum of the window
+    immature_value: u64,
+
+    start_height: u64,
+}
+
+impl ImmatureCoinbase {
+    fn state_path(folder: &Path) -> PathBuf {
+        folder.join("immaturecoinbase.window")
+    }
+}
+
+impl Callback for ImmatureCoinbase {
+    fn build_subcommand() -> Command
+    where
+        Self: Sized,
+    {
+        Command::new("immaturecoinbase")
+            .about("Dumps the value of immature coinbase outputs per block to CSV file")
+            .version("0.1")
+            .author("gcarq <egger.m@protonmail.com>")
+            .arg(
+                Arg::new("dump-folder")
+                    .help("Folder to store csv file")
+                    .index(1)
+                    .required(true),
+            )
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let dump_folder = &PathBuf::from(matches.get_one::<String>("dump-folder").unwrap());
+        let coin = common::get_coin(matches);
+        let cb = ImmatureCoinbase {
+            window: VecDeque::with_capacity(coin.coinbase_maturity as usize + 1),
+            coin,
+            dump_folder: PathBuf::from(dump_folder),
+            writer: common::create_writer(dump_folder.join("immaturecoinbase.csv.tmp"))?,
+            immature_value: 0,
+            start_height: 0,
+        };
+        Ok(cb)
+    }
+
+    fn on_start(&mut self, block_height: u64) -> OpResult<()> {
+        self.start_height = block_height;
+        if block_height > 0 && self.window.is_empty() {
+            warn!(target: "callback", "Coinbases below height {} are unknown, the first {} values are too low!",
+                  block_height, self.coin.coinbase_maturity);
+        }
+        info!(target: "callback", "Executing immaturecoinbase with dump folder: {} ...", &self.dump_folder.display());
+        self.writer
+            .write_all(format!("{};{}\n", "height", "immature_coinbase_value").as_bytes())?;
+        Ok(())
+    }
+
+    /// A coinbase created at height c can be spent from height c + maturity on,
+    /// so at height h the coinbases of the heights h - maturity + 1 to h are immature.
+    fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<Control> {
+        let value = block
+            .txs
+            .first()
+            .filter(|tx| tx.value.is_coinbase())
+            .map_or(0, |tx| {
+                tx.value
+                    .outputs
+                    .iter()
+                    .filter(|o| {
+                        !matches!(
+                            o.script.pattern,
+                            ScriptPattern::OpReturn(_) | ScriptPattern::Unspendable
+                        )
+                    })
+                    .map(|o| o.out.value)
+                    .sum()
+            });
+        self.window.push_back(value);
+        self.immature_value += value;
+        while self.window.len() as u64 > self.coin.coinbase_maturity {
+            self.immature_value -= self.window.pop_front().unwrap();
+        }
+
+        self.writer
+            .write_all(format!("{};{}\n", block_height, self.immature_value).as_bytes())?;
+        Ok(Control::Continue)
+    }
+
+    fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+        self.writer.flush()?;
+        utils::rename(
+            self.dump_folder.as_path().join("immaturecoinbase.csv.tmp"),
+            utils::output_path(
+                &self.dump_folder,
+                "immaturecoinbase",
+                self.start_height,
+                block_height,
+                "csv",
+            ),
+        )?;
+
+        info!(target: "callback", "Done.\nImmature coinbase value at height {}: {:.8}",
+             block_height, self.immature_value as f64 * 1E-8);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> OpResult<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    fn dump_folder(&self) -> Option<&Path> {
+        Some(&self.dump_folder)
+    }
+
+    fn supports_incremental(&self) -> bool {
+        true
+    }
+
+    /// Format: value count (u64), values (u64) oldest first
+    fn save_state(&self, folder: &Path) -> OpResult<()> {
+        let path = ImmatureCoinbase::state_path(folder);
+        let tmp_path = path.with_extension("tmp");
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        writer.write_u64::<LittleEndian>(self.window.len() as u64)?;
+        for value in &self.window {
+            writer.write_u64::<LittleEndian>(*value)?;
+        }
+        writer.flush()?;
+        drop(writer);
+        utils::rename(&tmp_path, path)?;
+        Ok(())
+    }
+
+    fn load_state(&mut self, folder: &Path) -> OpResult<()> {
+        let path = ImmatureCoinbase::state_path(folder);
+        if !path.exists() {
+            return Ok(());
+        }
+        let mut reader = BufReader::new(File::open(path)?);
+        let len = reader.read_u64::<LittleEndian>()?;
+        self.window = (0..len)
+            .map(|_| reader.read_u64::<LittleEndian>())
+            .collect::<Result<_, _>>()?;
+        self.immature_value = self.window.iter().sum();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::testutils;
+    use bitcoin::hashes::{sha256d, Hash};
+    use std::fs;
+
+    #[test]
+    fn test_immaturecoinbase() {
+        // The first three coinbases claim 50 BTC, all later ones 1 BTC
+        let mut blocks: Vec<Block> = Vec::new();
+        for height in 0..103u64 {
+            let prev_hash = blocks
+                .last()
+                .map_or(sha256d::Hash::all_zeros(), |b| b.header.hash);
+            let value = if height < 3 { 5000000000 } else { 100000000 };
+            let coinbase = testutils::coinbase(height, &[(value, testutils::p2pkh_script(0))]);
+            blocks.push(testutils::block(
+                prev_hash,
+                height as u32 * 600,
+                vec![coinbase],
+            ));
+        }
+
+        let dump_folder = tempfile::tempdir().unwrap();
+        let matches = ImmatureCoinbase::build_subcommand()
+            .get_matches_from(["immaturecoinbase", dump_folder.path().to_str().unwrap()]);
+        let mut cb = ImmatureCoinbase::new(&matches).unwrap();
+        cb.on_start(0).unwrap();
+        for (height, block) in blocks.iter().enumerate() {
+            cb.on_block(block, height as u64).unwrap();
+        }
+        cb.on_complete(102).unwrap();
+
+        let csv =
+            fs::read_to_string(dump_folder.path().join("immaturecoinbase-0-102.csv")).unwrap();
+        let values: Vec<u64> = csv
+            .lines()
+            .skip(1)
+            .map(|line| line.split(';').nth(1).unwrap().parse().unwrap())
+            .collect();
+        assert_eq!(values[0], 5000000000);
+        assert_eq!(values[2], 15000000000);
+        assert_eq!(values[99], 15000000000 + 97 * 100000000);
+        // The 50 BTC coinbases mature one after another
+        assert_eq!(values[100], 10000000000 + 98 * 100000000);
+        assert_eq!(values[101], 5000000000 + 99 * 100000000);
+        assert_eq!(values[102], 100 * 100000000);
+
+        // The window survives an incremental run
+        cb.save_state(dump_folder.path()).unwrap();
+        let mut cb = ImmatureCoinbase::new(&matches).unwrap();
+        cb.load_state(dump_folder.path()).unwrap();
+        assert_eq!(cb.immature_value, 100 * 100000000);
+        assert_eq!(cb.window.len(), 100);
+    }
+}
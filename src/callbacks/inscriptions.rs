@@ -0,0 +1,269 @@
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use clap::{Arg, ArgMatches, Command};
+use serde_json::{json, Value};
+
+use crate::blockchain::proto::block::Block;
+use crate::blockchain::proto::script::inscription::{self, Inscription};
+use crate::blockchain::proto::tx::EvaluatedTx;
+use crate::callbacks::{common, Callback, Control};
+use crate::common::utils;
+use crate::errors::OpResult;
+
+/// Dumps the ord inscriptions revealed in tapscript witnesses.
+/// The sat offset of an inscription without pointer on a later input depends on the values
+/// of the previous inputs, which are unknown here, so its location is left empty.
+pub struct Inscriptions {
+    dump_folder: PathBuf,
+    writer: common::DumpWriter,
+    /// Folder to write the content of each inscription to
+    content_folder: Option<PathBuf>,
+
+    start_height: u64,
+    n_inscriptions: u64,
+}
+
+impl Inscriptions {
+    /// Output index and offset within it of the sat the inscription is placed on,
+    /// None if it is unknown or the sat goes to the fees
+    fn location(tx: &EvaluatedTx, vin: usize, inscription: &Inscription) -> Option<(usize, u64)> {
+        let total: u64 = tx.outputs.iter().map(|o| o.out.value).sum();
+        let offset = match inscription.pointer {
+            Some(pointer) if pointer < total => pointer,
+            // The first input starts at the first sat of the first output
+            _ if vin == 0 => 0,
+            _ => return None,
+        };
+        let mut start = 0;
+        for (vout, output) in tx.outputs.iter().enumerate() {
+            if offset < start + output.out.value {
+                return Some((vout, offset - start));
+            }
+            start += output.out.value;
+        }
+        None
+    }
+
+    /// Content types like `text/plain;charset=utf-8` contain the separator and are quoted
+    fn csv_text(field: &Option<Vec<u8>>) -> String {
+        match field {
+            Some(bytes) => common::csv_quote(&String::from_utf8_lossy(bytes)),
+            None => String::new(),
+        }
+    }
+}
+
+impl Callback for Inscriptions {
+    fn build_subcommand() -> Command
+    where
+        Self: Sized,
+    {
+        Command::new("inscriptions")
+            .about("Dumps ord inscriptions revealed in taproot witnesses to CSV file")
+            .version("0.1")
+            .author("gcarq <egger.m@protonmail.com>")
+            .arg(
+                Arg::new("dump-folder")
+                    .help("Folder to store csv file")
+                    .index(1)
+                    .required(true),
+            )
+            .arg(
+                Arg::new("dump-content")
+                    .long("dump-content")
+                    .action(clap::ArgAction::SetTrue)
+                    .help("Writes the content of each inscription to inscriptions/<inscription_id> in the dump folder"),
+            )
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let dump_folder = &PathBuf::from(matches.get_one::<String>("dump-folder").unwrap());
+        let content_folder = match matches.get_flag("dump-content") {
+            true => {
+                let folder = dump_folder.join("inscriptions");
+                fs::create_dir_all(&folder)?;
+                Some(folder)
+            }
+            false => None,
+        };
+        let cb = Inscriptions {
+            dump_folder: PathBuf::from(dump_folder),
+            writer: common::create_writer(dump_folder.join("inscriptions.csv.tmp"))?,
+            content_folder,
+            start_height: 0,
+            n_inscriptions: 0,
+        };
+        Ok(cb)
+    }
+
+    fn on_start(&mut self, block_height: u64) -> OpResult<()> {
+        self.start_height = block_height;
+        info!(target: "callback", "Executing inscriptions with dump folder: {} ...", &self.dump_folder.display());
+        self.writer.write_all(
+            format!(
+                "{};{};{};{};{};{};{};{};{}\n",
+                "height",
+                "txid",
+                "inscription_id",
+                "vin",
+                "content_type",
+                "content_encoding",
+                "content_length",
+                "vout",
+                "offset"
+            )
+            .as_bytes(),
+        )?;
+        Ok(())
+    }
+
+    fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<Control> {
+        for tx in &block.txs {
+            let txid = utils::hash_to_string(&tx.hash);
+            // Inscriptions are numbered across all inputs of the reveal transaction
+            let mut index = 0;
+            for (vin, input) in tx.value.inputs.iter().enumerate() {
+                for inscription in inscription::find_inscriptions(input) {
+                    let id = format!("{}i{}", txid, index);
+                    let (vout, offset) = match Inscriptions::location(&tx.value, vin, &inscription)
+                    {
+                        Some((vout, offset)) => (vout.to_string(), offset.to_string()),
+                        None => (String::new(), String::new()),
+                    };
+                    let length = inscription.body.as_ref().map(Vec::len);
+                    self.writer.write_all(
+                        format!(
+                            "{};{};{};{};{};{};{};{};{}\n",
+                            block_height,
+                            txid,
+                            id,
+                            vin,
+                            Inscriptions::csv_text(&inscription.content_type),
+                            Inscriptions::csv_text(&inscription.content_encoding),
+                            length.map(|l| l.to_string()).unwrap_or_default(),
+                            vout,
+                            offset
+                        )
+                        .as_bytes(),
+                    )?;
+                    if let (Some(folder), Some(body)) = (&self.content_folder, &inscription.body) {
+                        fs::write(folder.join(&id), body)?;
+                    }
+                    index += 1;
+                    self.n_inscriptions += 1;
+                }
+            }
+        }
+        Ok(Control::Continue)
+    }
+
+    fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+        self.writer.flush()?;
+        utils::rename(
+            self.dump_folder.as_path().join("inscriptions.csv.tmp"),
+            utils::output_path(
+                &self.dump_folder,
+                "inscriptions",
+                self.start_height,
+                block_height,
+                "csv",
+            ),
+        )?;
+
+        info!(target: "callback", "Done.\nFound {} inscriptions from height {} to {}.",
+             self.n_inscriptions, self.start_height, block_height);
+        Ok(())
+    }
+
+    fn supports_reverse(&self) -> bool {
+        true
+    }
+
+    fn flush(&mut self) -> OpResult<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    fn dump_folder(&self) -> Option<&Path> {
+        Some(&self.dump_folder)
+    }
+
+    fn supports_incremental(&self) -> bool {
+        true
+    }
+
+    fn summary(&self) -> Value {
+        json!({ "inscriptions": self.n_inscriptions })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::proto::script::inscription::tests::{envelope_script, reveal_witness};
+    use crate::common::testutils;
+    use bitcoin::hashes::{sha256d, Hash};
+
+    #[test]
+    fn test_inscriptions() {
+        let outpoint = |i: u8| (sha256d::Hash::hash(&[i]), 0);
+        // Two inscriptions on the first input, one on the second with a pointer into output 1
+        let mut reveal = testutils::tx(
+            &[outpoint(1), outpoint(2), outpoint(3)],
+            &[
+                (546, testutils::p2pkh_script(1)),
+                (1000, testutils::p2pkh_script(2)),
+            ],
+        );
+        let mut script = envelope_script(&[&[1], b"text/plain;charset=utf-8", &[], b"Hello"]);
+        script.extend(envelope_script(&[&[1], b"image/png"]));
+        reveal.inputs[0].witness = reveal_witness(script);
+        reveal.inputs[1].witness =
+            reveal_witness(envelope_script(&[&[2], &[0x2c, 0x02], &[], b"a"]));
+        // No pointer on a later input
+        reveal.inputs[2].witness = reveal_witness(envelope_script(&[&[], b"b"]));
+        let block = testutils::block(
+            sha256d::Hash::all_zeros(),
+            0,
+            vec![
+                testutils::coinbase(1, &[(5000, testutils::p2pkh_script(0))]),
+                reveal,
+            ],
+        );
+
+        let dump_folder = tempfile::tempdir().unwrap();
+        let matches = Inscriptions::build_subcommand().get_matches_from([
+            "inscriptions",
+            dump_folder.path().to_str().unwrap(),
+            "--dump-content",
+        ]);
+        let mut cb = Inscriptions::new(&matches).unwrap();
+        cb.on_start(1).unwrap();
+        cb.on_block(&block, 1).unwrap();
+        cb.on_complete(1).unwrap();
+
+        let txid = utils::hash_to_string(&block.txs[1].hash);
+        let csv = fs::read_to_string(dump_folder.path().join("inscriptions-1-1.csv")).unwrap();
+        assert_eq!(
+            csv.lines().skip(1).collect::<Vec<_>>(),
+            vec![
+                format!("1;{txid};{txid}i0;0;\"text/plain;charset=utf-8\";;5;0;0"),
+                format!("1;{txid};{txid}i1;0;image/png;;;0;0"),
+                format!("1;{txid};{txid}i2;1;;;1;1;10"),
+                format!("1;{txid};{txid}i3;2;;;1;;"),
+            ]
+        );
+        let content = dump_folder.path().join("inscriptions");
+        assert_eq!(
+            fs::read(content.join(format!("{txid}i0"))).unwrap(),
+            b"Hello"
+        );
+        assert!(!content.join(format!("{txid}i1")).exists());
+        assert_eq!(cb.summary(), json!({ "inscriptions": 4 }));
+    }
+}
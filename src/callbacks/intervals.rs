@@ -0,0 +1,154 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use clap::{Arg, ArgMatches, Command};
+
+use crate::blockchain::proto::block::Block;
+use crate::callbacks::{common, Callback, Control};
+use crate::common::utils;
+use crate::errors::OpResult;
+
+/// Dumps the time between each block and its predecessor
+pub struct Intervals {
+    dump_folder: PathBuf,
+    writer: common::DumpWriter,
+
+    // Timestamp of the previous block, None before the first block
+    prev_timestamp: Option<u32>,
+
+    start_height: u64,
+    n_negative: u64,
+}
+
+impl Callback for Intervals {
+    fn build_subcommand() -> Command
+    where
+        Self: Sized,
+    {
+        Command::new("intervals")
+            .about("Dumps the time since the previous block for each block to CSV file")
+            .version("0.1")
+            .author("gcarq <egger.m@protonmail.com>")
+            .arg(
+                Arg::new("dump-folder")
+                    .help("Folder to store csv file")
+                    .index(1)
+                    .required(true),
+            )
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let dump_folder = &PathBuf::from(matches.get_one::<String>("dump-folder").unwrap());
+        let cb = Intervals {
+            dump_folder: PathBuf::from(dump_folder),
+            writer: common::create_writer(dump_folder.join("intervals.csv.tmp"))?,
+            prev_timestamp: None,
+            start_height: 0,
+            n_negative: 0,
+        };
+        Ok(cb)
+    }
+
+    fn on_start(&mut self, block_height: u64) -> OpResult<()> {
+        self.start_height = block_height;
+        info!(target: "callback", "Executing intervals with dump folder: {} ...", &self.dump_folder.display());
+        self.writer.write_all(
+            format!("{};{};{}\n", "height", "timestamp", "interval_seconds").as_bytes(),
+        )?;
+        Ok(())
+    }
+
+    /// Block timestamps are not strictly increasing, so the interval can be negative.
+    /// The interval of the first block is left empty.
+    fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<Control> {
+        let timestamp = block.header.value.timestamp;
+        let interval = match self.prev_timestamp {
+            Some(prev) => {
+                let interval = timestamp as i64 - prev as i64;
+                if interval < 0 {
+                    self.n_negative += 1;
+                }
+                interval.to_string()
+            }
+            None => String::new(),
+        };
+        self.writer
+            .write_all(format!("{};{};{}\n", block_height, timestamp, interval).as_bytes())?;
+        self.prev_timestamp = Some(timestamp);
+        Ok(Control::Continue)
+    }
+
+    fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+        self.writer.flush()?;
+        utils::rename(
+            self.dump_folder.as_path().join("intervals.csv.tmp"),
+            utils::output_path(
+                &self.dump_folder,
+                "intervals",
+                self.start_height,
+                block_height,
+                "csv",
+            ),
+        )?;
+
+        info!(target: "callback", "Done.\nDumped intervals from height {} to {} ({} negative).",
+             self.start_height, block_height, self.n_negative);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> OpResult<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    fn dump_folder(&self) -> Option<&Path> {
+        Some(&self.dump_folder)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::testutils;
+    use bitcoin::hashes::{sha256d, Hash};
+    use std::fs;
+
+    #[test]
+    fn test_intervals_negative() {
+        let block1 = testutils::block(
+            sha256d::Hash::all_zeros(),
+            1000,
+            vec![testutils::coinbase(1, &[(50, testutils::p2pkh_script(0))])],
+        );
+        let block2 = testutils::block(
+            block1.header.hash,
+            1600,
+            vec![testutils::coinbase(2, &[(50, testutils::p2pkh_script(0))])],
+        );
+        // Timestamps only need to be above the median of the last 11 blocks
+        let block3 = testutils::block(
+            block2.header.hash,
+            1300,
+            vec![testutils::coinbase(3, &[(50, testutils::p2pkh_script(0))])],
+        );
+
+        let dump_folder = tempfile::tempdir().unwrap();
+        let matches = Intervals::build_subcommand()
+            .get_matches_from(["intervals", dump_folder.path().to_str().unwrap()]);
+        let mut cb = Intervals::new(&matches).unwrap();
+        cb.on_start(1).unwrap();
+        cb.on_block(&block1, 1).unwrap();
+        cb.on_block(&block2, 2).unwrap();
+        cb.on_block(&block3, 3).unwrap();
+        cb.on_complete(3).unwrap();
+
+        let csv = fs::read_to_string(dump_folder.path().join("intervals-1-3.csv")).unwrap();
+        assert_eq!(
+            csv,
+            "height;timestamp;interval_seconds\n1;1000;\n2;1600;600\n3;1300;-300\n"
+        );
+    }
+}
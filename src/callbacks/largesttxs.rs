@@ -0,0 +1,253 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use bitcoin::hashes::sha256d;
+use clap::{Arg, ArgMatches, Command};
+
+use crate::blockchain::proto::block::Block;
+use crate::callbacks::common::{self, CallbackArgs};
+use crate::callbacks::{Callback, Control};
+use crate::common::utils;
+use crate::errors::OpResult;
+
+/// Transaction with the highest value of a metric, the first one wins on ties
+#[derive(Default)]
+struct Max {
+    tx: Option<(sha256d::Hash, u64)>,
+}
+
+impl Max {
+    fn update(&mut self, txid: &sha256d::Hash, value: u64) {
+        match self.tx {
+            Some((_, max)) if max >= value => {}
+            _ => self.tx = Some((*txid, value)),
+        }
+    }
+
+    /// Formats txid and value as csv fields, both are empty if there was no transaction
+    fn fmt(&self) -> String {
+        match &self.tx {
+            Some((txid, value)) => format!("{};{}", utils::hash_to_string(txid), value),
+            None => String::from(";"),
+        }
+    }
+}
+
+/// Dumps the largest transaction of each block by input count, output count and output value.
+/// Accepts `--callback-args coinbase=true` to consider the coinbase as well.
+pub struct LargestTxs {
+    dump_folder: PathBuf,
+    writer: common::DumpWriter,
+    include_coinbase: bool,
+
+    start_height: u64,
+    n_blocks: u64,
+}
+
+impl Callback for LargestTxs {
+    fn build_subcommand() -> Command
+    where
+        Self: Sized,
+    {
+        Command::new("largesttxs")
+            .about(
+                "Dumps the largest transaction by inputs, outputs and value per block to CSV file",
+            )
+            .version("0.1")
+            .author("gcarq <egger.m@protonmail.com>")
+            .arg(
+                Arg::new("dump-folder")
+                    .help("Folder to store csv file")
+                    .index(1)
+                    .required(true),
+            )
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let dump_folder = &PathBuf::from(matches.get_one::<String>("dump-folder").unwrap());
+        let args = CallbackArgs::from_matches(matches, &["coinbase"])?;
+        let cb = LargestTxs {
+            dump_folder: PathBuf::from(dump_folder),
+            writer: common::create_writer(dump_folder.join("largesttxs.csv.tmp"))?,
+            include_coinbase: args.get("coinbase")?.unwrap_or(false),
+            start_height: 0,
+            n_blocks: 0,
+        };
+        Ok(cb)
+    }
+
+    fn on_start(&mut self, block_height: u64) -> OpResult<()> {
+        self.start_height = block_height;
+        info!(target: "callback", "Executing largesttxs with dump folder: {} ...", &self.dump_folder.display());
+        self.writer.write_all(
+            format!(
+                "{};{};{};{};{};{};{}\n",
+                "height",
+                "max_inputs_txid",
+                "max_inputs",
+                "max_outputs_txid",
+                "max_outputs",
+                "max_value_txid",
+                "max_value"
+            )
+            .as_bytes(),
+        )?;
+        Ok(())
+    }
+
+    /// The coinbase is skipped for all metrics by default, its value is the block reward
+    /// and not a transfer. Blocks without other transactions get empty fields.
+    fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<Control> {
+        let (mut inputs, mut outputs, mut value) = (Max::default(), Max::default(), Max::default());
+        for tx in block
+            .txs
+            .iter()
+            .filter(|tx| self.include_coinbase || !tx.value.is_coinbase())
+        {
+            inputs.update(&tx.hash, tx.value.in_count.value);
+            outputs.update(&tx.hash, tx.value.out_count.value);
+            value.update(&tx.hash, tx.value.outputs.iter().map(|o| o.out.value).sum());
+        }
+        self.writer.write_all(
+            format!(
+                "{};{};{};{}\n",
+                block_height,
+                inputs.fmt(),
+                outputs.fmt(),
+                value.fmt()
+            )
+            .as_bytes(),
+        )?;
+        self.n_blocks += 1;
+        Ok(Control::Continue)
+    }
+
+    fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+        self.writer.flush()?;
+        utils::rename(
+            self.dump_folder.as_path().join("largesttxs.csv.tmp"),
+            utils::output_path(
+                &self.dump_folder,
+                "largesttxs",
+                self.start_height,
+                block_height,
+                "csv",
+            ),
+        )?;
+
+        info!(target: "callback", "Done.\nDumped the largest transactions of {} blocks from height {} to {}.",
+             self.n_blocks, self.start_height, block_height);
+        Ok(())
+    }
+
+    fn supports_reverse(&self) -> bool {
+        true
+    }
+
+    fn flush(&mut self) -> OpResult<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    fn dump_folder(&self) -> Option<&Path> {
+        Some(&self.dump_folder)
+    }
+
+    fn supports_incremental(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::callbacks::common;
+    use crate::common::testutils;
+    use bitcoin::hashes::Hash;
+    use std::fs;
+
+    #[test]
+    fn test_largesttxs() {
+        let outpoint = |i: u8| (sha256d::Hash::hash(&[i]), 0);
+        let output = |value: u64| (value, testutils::p2pkh_script(1));
+        let block1 = testutils::block(
+            sha256d::Hash::all_zeros(),
+            0,
+            vec![testutils::coinbase(1, &[output(5000000000)])],
+        );
+        let block2 = testutils::block(
+            block1.header.hash,
+            600,
+            vec![
+                testutils::coinbase(2, &[output(5000000000)]),
+                testutils::tx(&[outpoint(1), outpoint(2), outpoint(3)], &[output(1000)]),
+                testutils::tx(&[outpoint(4)], &[output(1000), output(2000)]),
+                testutils::tx(&[outpoint(5)], &[output(9000)]),
+                // Ties with the second transaction
+                testutils::tx(&[outpoint(6)], &[output(1), output(1)]),
+            ],
+        );
+
+        let dump_folder = tempfile::tempdir().unwrap();
+        let matches = LargestTxs::build_subcommand()
+            .get_matches_from(["largesttxs", dump_folder.path().to_str().unwrap()]);
+        let mut cb = LargestTxs::new(&matches).unwrap();
+        cb.on_start(1).unwrap();
+        cb.on_block(&block1, 1).unwrap();
+        cb.on_block(&block2, 2).unwrap();
+        cb.on_complete(2).unwrap();
+
+        let csv = fs::read_to_string(dump_folder.path().join("largesttxs-1-2.csv")).unwrap();
+        let rows: Vec<&str> = csv.lines().skip(1).collect();
+        let txid = |i: usize| utils::hash_to_string(&block2.txs[i].hash);
+        assert_eq!(
+            rows,
+            vec![
+                String::from("1;;;;;;"),
+                format!("2;{};3;{};2;{};9000", txid(1), txid(2), txid(3)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_largesttxs_include_coinbase() {
+        let block = testutils::block(
+            sha256d::Hash::all_zeros(),
+            0,
+            vec![
+                testutils::coinbase(1, &[(5000000000, testutils::p2pkh_script(1))]),
+                testutils::tx(
+                    &[(sha256d::Hash::hash(&[1]), 0)],
+                    &[(1000, testutils::p2pkh_script(2))],
+                ),
+            ],
+        );
+
+        // --callback-args is a global option, parse it along with the subcommand
+        let dump_folder = tempfile::tempdir().unwrap();
+        let command = Command::new("rusty-blockparser")
+            .arg(common::callback_args_arg())
+            .subcommand(LargestTxs::build_subcommand());
+        let matches = command.get_matches_from([
+            "rusty-blockparser",
+            "--callback-args",
+            "coinbase=true",
+            "largesttxs",
+            dump_folder.path().to_str().unwrap(),
+        ]);
+        let mut cb = LargestTxs::new(matches.subcommand_matches("largesttxs").unwrap()).unwrap();
+        cb.on_start(1).unwrap();
+        cb.on_block(&block, 1).unwrap();
+        cb.on_complete(1).unwrap();
+
+        let csv = fs::read_to_string(dump_folder.path().join("largesttxs-1-1.csv")).unwrap();
+        let txid = |i: usize| utils::hash_to_string(&block.txs[i].hash);
+        assert_eq!(
+            csv.lines().nth(1).unwrap(),
+            format!("1;{};1;{};1;{};5000000000", txid(0), txid(0), txid(0))
+        );
+    }
+}
@@ -0,0 +1,211 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use clap::{Arg, ArgMatches, Command};
+use serde_json::{json, Value};
+
+use crate::blockchain::proto::block::Block;
+use crate::blockchain::proto::tx::OutpointKey;
+use crate::callbacks::{common, Callback, Control};
+use crate::common::address::CompactAddress;
+use crate::common::utils;
+use crate::errors::OpResult;
+
+/// Dumps the balance and the height of the most recent activity of each address with non-zero balance,
+/// to find dormant addresses. The activity is the creation height (`UnspentValue::block_height`) of its
+/// youngest UTXO, so spends without change back to the address don't count.
+pub struct LastActive {
+    dump_folder: PathBuf,
+
+    // key: txid + index
+    unspents: HashMap<OutpointKey, common::UnspentValue>,
+
+    start_height: u64,
+    n_addresses: u64,
+}
+
+impl Callback for LastActive {
+    fn build_subcommand() -> Command
+    where
+        Self: Sized,
+    {
+        Command::new("lastactive")
+            .about("Dumps the height of the last activity of each address with non-zero balance to CSV file")
+            .version("0.1")
+            .author("gcarq <egger.m@protonmail.com>")
+            .arg(
+                Arg::new("dump-folder")
+                    .help("Folder to store csv file")
+                    .index(1)
+                    .required(true),
+            )
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let cb = LastActive {
+            dump_folder: PathBuf::from(matches.get_one::<String>("dump-folder").unwrap()),
+            unspents: HashMap::with_capacity(10000000),
+            start_height: 0,
+            n_addresses: 0,
+        };
+        Ok(cb)
+    }
+
+    fn on_start(&mut self, block_height: u64) -> OpResult<()> {
+        self.start_height = block_height;
+        info!(target: "callback", "Executing lastactive with dump folder: {} ...", &self.dump_folder.display());
+        Ok(())
+    }
+
+    fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<Control> {
+        for tx in &block.txs {
+            common::remove_unspents(tx, &mut self.unspents);
+            common::insert_unspents(tx, block_height, &mut self.unspents);
+        }
+        Ok(Control::Continue)
+    }
+
+    fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+        // Balance and height of the youngest UTXO for each address
+        let mut addresses: HashMap<&CompactAddress, (u64, u64)> = HashMap::new();
+        for unspent in self.unspents.values() {
+            if unspent.address.is_empty() {
+                continue;
+            }
+            let entry = addresses.entry(&unspent.address).or_insert((0, 0));
+            entry.0 += unspent.value;
+            entry.1 = entry.1.max(unspent.block_height);
+        }
+        let mut rows: Vec<(String, (u64, u64))> = addresses
+            .into_iter()
+            .filter(|(_, (balance, _))| *balance > 0)
+            .map(|(address, activity)| (address.to_string(), activity))
+            .collect();
+        rows.sort_unstable();
+
+        let tmp_path = self.dump_folder.join("lastactive.csv.tmp");
+        let mut writer = BufWriter::with_capacity(4000000, File::create(&tmp_path)?);
+        writer.write_all(
+            format!(
+                "{};{};{};{}\n",
+                "address", "balance", "last_active_height", "blocks_since_active"
+            )
+            .as_bytes(),
+        )?;
+        for (address, (balance, last_active)) in &rows {
+            writer.write_all(
+                format!(
+                    "{};{};{};{}\n",
+                    address,
+                    balance,
+                    last_active,
+                    block_height - last_active
+                )
+                .as_bytes(),
+            )?;
+        }
+        writer.flush()?;
+        drop(writer);
+        utils::rename(
+            tmp_path,
+            utils::output_path(
+                &self.dump_folder,
+                "lastactive",
+                self.start_height,
+                block_height,
+                "csv",
+            ),
+        )?;
+        self.n_addresses = rows.len() as u64;
+
+        info!(target: "callback", "Done.\nDumped the last activity of {} addresses from height {} to {}.",
+             self.n_addresses, self.start_height, block_height);
+        Ok(())
+    }
+
+    fn dump_folder(&self) -> Option<&Path> {
+        Some(&self.dump_folder)
+    }
+
+    fn supports_incremental(&self) -> bool {
+        true
+    }
+
+    fn save_state(&self, folder: &Path) -> OpResult<()> {
+        common::save_unspents(&folder.join("lastactive.unspents"), &self.unspents)
+    }
+
+    fn load_state(&mut self, folder: &Path) -> OpResult<()> {
+        self.unspents = common::load_unspents(&folder.join("lastactive.unspents"))?;
+        Ok(())
+    }
+
+    fn summary(&self) -> Value {
+        json!({ "addresses": self.n_addresses })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::testutils;
+    use bitcoin::hashes::{sha256d, Hash};
+    use std::fs;
+
+    #[test]
+    fn test_lastactive() {
+        let block1 = testutils::block(
+            sha256d::Hash::all_zeros(),
+            0,
+            vec![testutils::coinbase(
+                1,
+                &[
+                    (1000, testutils::p2pkh_script(1)),
+                    (4000, testutils::p2pkh_script(2)),
+                ],
+            )],
+        );
+        // Address 1 receives again at height 5, address 2 spends its whole balance
+        let spend = testutils::tx(
+            &[(block1.txs[0].hash, 1)],
+            &[(3000, testutils::p2pkh_script(1))],
+        );
+        let block2 = testutils::block(
+            block1.header.hash,
+            600,
+            vec![
+                testutils::coinbase(2, &[(5000, testutils::p2pkh_script(3))]),
+                spend,
+            ],
+        );
+        let address = |block: &Block, tx: usize| {
+            block.txs[tx].value.outputs[0]
+                .script
+                .address
+                .clone()
+                .unwrap()
+        };
+
+        let dump_folder = tempfile::tempdir().unwrap();
+        let matches = LastActive::build_subcommand()
+            .get_matches_from(["lastactive", dump_folder.path().to_str().unwrap()]);
+        let mut cb = LastActive::new(&matches).unwrap();
+        cb.on_start(1).unwrap();
+        cb.on_block(&block1, 1).unwrap();
+        cb.on_block(&block2, 5).unwrap();
+        cb.on_complete(10).unwrap();
+
+        let csv = fs::read_to_string(dump_folder.path().join("lastactive-1-10.csv")).unwrap();
+        let mut expected = vec![
+            format!("{};4000;5;5", address(&block1, 0)),
+            format!("{};5000;5;5", address(&block2, 0)),
+        ];
+        expected.sort();
+        assert_eq!(csv.lines().skip(1).collect::<Vec<_>>(), expected);
+    }
+}
@@ -0,0 +1,269 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use clap::{Arg, ArgMatches, Command};
+use serde_json::{json, Value};
+
+use crate::blockchain::proto::block::Block;
+use crate::blockchain::proto::tx::{OutpointKey, TxOutpoint};
+use crate::callbacks::{common, Callback, Control};
+use crate::common::utils;
+use crate::errors::OpResult;
+
+/// Satoshi-seconds per BTC-day
+const COIN_DAY: f64 = 1E8 * 86400.0;
+
+/// Value and creation time of an unspent output
+struct AgedOutput {
+    value: u64,
+    created: u32,
+}
+
+/// Dumps the cumulative coin-days destroyed, liveliness and dormancy per block.
+///
+/// Coin-days are accumulated exactly as satoshi-seconds in u128, so there is no rounding error
+/// over the whole chain. The coin-days created so far are the destroyed ones plus the age of
+/// all unspent outputs, which is `now * unspent value - sum(value * creation time)`.
+/// Block timestamps are not strictly increasing, so the highest timestamp so far is used as the time.
+pub struct Liveliness {
+    dump_folder: PathBuf,
+    writer: common::DumpWriter,
+
+    // key: txid + index
+    unspents: HashMap<OutpointKey, AgedOutput>,
+    // Highest block timestamp so far
+    max_timestamp: u32,
+    unspent_value: u128,
+    // sum(value * creation time) of the unspent outputs
+    unspent_value_time: u128,
+    destroyed: u128,
+
+    start_height: u64,
+}
+
+impl Liveliness {
+    /// Coin-days created so far, in satoshi-seconds
+    fn created(&self) -> u128 {
+        self.destroyed + self.max_timestamp as u128 * self.unspent_value - self.unspent_value_time
+    }
+
+    /// Cumulative coin-days destroyed over coin-days created, 0 before any coin aged
+    fn liveliness(&self) -> f64 {
+        match self.created() {
+            0 => 0.0,
+            created => self.destroyed as f64 / created as f64,
+        }
+    }
+}
+
+impl Callback for Liveliness {
+    fn build_subcommand() -> Command
+    where
+        Self: Sized,
+    {
+        Command::new("liveliness")
+            .about("Dumps cumulative coin-days destroyed, liveliness and dormancy per block to CSV file")
+            .version("0.1")
+            .author("gcarq <egger.m@protonmail.com>")
+            .arg(
+                Arg::new("dump-folder")
+                    .help("Folder to store csv file")
+                    .index(1)
+                    .required(true),
+            )
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let dump_folder = &PathBuf::from(matches.get_one::<String>("dump-folder").unwrap());
+        let cb = Liveliness {
+            dump_folder: PathBuf::from(dump_folder),
+            writer: common::create_writer(dump_folder.join("liveliness.csv.tmp"))?,
+            unspents: HashMap::with_capacity(10000000),
+            max_timestamp: 0,
+            unspent_value: 0,
+            unspent_value_time: 0,
+            destroyed: 0,
+            start_height: 0,
+        };
+        Ok(cb)
+    }
+
+    fn on_start(&mut self, block_height: u64) -> OpResult<()> {
+        self.start_height = block_height;
+        if block_height > 0 {
+            warn!(target: "callback", "Outputs below height {} are unknown, their coin-days are not accounted!", block_height);
+        }
+        info!(target: "callback", "Executing liveliness with dump folder: {} ...", &self.dump_folder.display());
+        self.writer.write_all(
+            format!(
+                "{};{};{};{}\n",
+                "height", "cumulative_cdd", "liveliness", "dormancy"
+            )
+            .as_bytes(),
+        )?;
+        Ok(())
+    }
+
+    /// Dormancy is the average age in days of the coins spent in the block
+    fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<Control> {
+        self.max_timestamp = self.max_timestamp.max(block.header.value.timestamp);
+        let now = self.max_timestamp as u128;
+        let (mut block_destroyed, mut spent_value) = (0u128, 0u128);
+        for tx in &block.txs {
+            for input in &tx.value.inputs {
+                if let Some(spent) = self.unspents.remove(&input.outpoint.key()) {
+                    let value = spent.value as u128;
+                    block_destroyed += value * (now - spent.created as u128);
+                    spent_value += value;
+                    self.unspent_value -= value;
+                    self.unspent_value_time -= value * spent.created as u128;
+                }
+            }
+            for (i, output) in tx.value.outputs.iter().enumerate() {
+                let key = TxOutpoint::new(tx.hash, i as u32).key();
+                let value = output.out.value;
+                let created = self.max_timestamp;
+                // Duplicate coinbases (BIP30) replace the earlier output
+                if let Some(old) = self.unspents.insert(key, AgedOutput { value, created }) {
+                    self.unspent_value -= old.value as u128;
+                    self.unspent_value_time -= old.value as u128 * old.created as u128;
+                }
+                self.unspent_value += value as u128;
+                self.unspent_value_time += value as u128 * created as u128;
+            }
+        }
+        self.destroyed += block_destroyed;
+
+        let dormancy = match spent_value {
+            0 => 0.0,
+            value => block_destroyed as f64 / value as f64 / 86400.0,
+        };
+        self.writer.write_all(
+            format!(
+                "{};{:.4};{:.6};{:.4}\n",
+                block_height,
+                self.destroyed as f64 / COIN_DAY,
+                self.liveliness(),
+                dormancy
+            )
+            .as_bytes(),
+        )?;
+        Ok(Control::Continue)
+    }
+
+    fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+        self.writer.flush()?;
+        utils::rename(
+            self.dump_folder.as_path().join("liveliness.csv.tmp"),
+            utils::output_path(
+                &self.dump_folder,
+                "liveliness",
+                self.start_height,
+                block_height,
+                "csv",
+            ),
+        )?;
+
+        info!(target: "callback", "Done.\nDestroyed {:.2} of {:.2} coin-days created up to height {}.",
+             self.destroyed as f64 / COIN_DAY, self.created() as f64 / COIN_DAY, block_height);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> OpResult<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    fn dump_folder(&self) -> Option<&Path> {
+        Some(&self.dump_folder)
+    }
+
+    fn summary(&self) -> Value {
+        json!({
+            "cumulative_cdd": self.destroyed as f64 / COIN_DAY,
+            "liveliness": self.liveliness(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::testutils;
+    use bitcoin::hashes::{sha256d, Hash};
+    use std::fs;
+
+    #[test]
+    fn test_liveliness() {
+        let day = 86400;
+        let start = 1_300_000_000;
+        let block1 = testutils::block(
+            sha256d::Hash::all_zeros(),
+            start,
+            vec![testutils::coinbase(
+                1,
+                &[(100000000, testutils::p2pkh_script(1))],
+            )],
+        );
+        let block2 = testutils::block(
+            block1.header.hash,
+            start + 10 * day,
+            vec![testutils::coinbase(
+                2,
+                &[(100000000, testutils::p2pkh_script(2))],
+            )],
+        );
+        // The first coin is spent after 20 days, the second one held for 10 days so far
+        let block3 = testutils::block(
+            block2.header.hash,
+            start + 20 * day,
+            vec![
+                testutils::coinbase(3, &[(100000000, testutils::p2pkh_script(3))]),
+                testutils::tx(
+                    &[(block1.txs[0].hash, 0)],
+                    &[(100000000, testutils::p2pkh_script(4))],
+                ),
+            ],
+        );
+        // Timestamps below the maximum don't let coins age backwards
+        let block4 = testutils::block(
+            block3.header.hash,
+            start + 15 * day,
+            vec![
+                testutils::coinbase(4, &[(100000000, testutils::p2pkh_script(5))]),
+                testutils::tx(
+                    &[(block2.txs[0].hash, 0)],
+                    &[(100000000, testutils::p2pkh_script(6))],
+                ),
+            ],
+        );
+
+        let dump_folder = tempfile::tempdir().unwrap();
+        let matches = Liveliness::build_subcommand()
+            .get_matches_from(["liveliness", dump_folder.path().to_str().unwrap()]);
+        let mut cb = Liveliness::new(&matches).unwrap();
+        cb.on_start(0).unwrap();
+        for (height, block) in [block1, block2, block3, block4].iter().enumerate() {
+            cb.on_block(block, height as u64).unwrap();
+        }
+        cb.on_complete(3).unwrap();
+
+        let csv = fs::read_to_string(dump_folder.path().join("liveliness-0-3.csv")).unwrap();
+        let rows: Vec<&str> = csv.lines().skip(1).collect();
+        assert_eq!(
+            rows,
+            vec![
+                "0;0.0000;0.000000;0.0000",
+                "1;0.0000;0.000000;0.0000",
+                // 20 of 20 + 10 coin-days
+                "2;20.0000;0.666667;20.0000",
+                // 30 of 30 + 0 coin-days of the coins created at day 20
+                "3;30.0000;1.000000;10.0000",
+            ]
+        );
+    }
+}
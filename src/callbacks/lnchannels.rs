@@ -0,0 +1,254 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use clap::{Arg, ArgMatches, Command};
+use serde_json::{json, Value};
+
+use crate::blockchain::proto::block::Block;
+use crate::blockchain::proto::script::ScriptPattern;
+use crate::blockchain::proto::tx::{OutpointKey, TxOutpoint};
+use crate::callbacks::{common, Callback, Control};
+use crate::common::utils;
+use crate::errors::OpResult;
+
+/// Returns true for `OP_2 <33 byte pubkey> <33 byte pubkey> OP_2 OP_CHECKMULTISIG`,
+/// the funding script of a Lightning channel (BOLT 3)
+fn is_2_of_2_multisig(script: &[u8]) -> bool {
+    script.len() == 71
+        && script[0] == 0x52
+        && script[1] == 0x21
+        && script[35] == 0x21
+        && script[69] == 0x52
+        && script[70] == 0xae
+}
+
+/// Height and value of an unspent P2WSH output
+struct FundingOutput {
+    block_height: u64,
+    value: u64,
+}
+
+/// Dumps candidates for Lightning channels: P2WSH outputs which turn out to be 2-of-2 multisig
+/// once they are spent. The witness script is only revealed by the spend, so all P2WSH outputs
+/// are tracked and a channel is reported when it is closed. This is a heuristic, any other
+/// 2-of-2 multisig P2WSH output is reported as well.
+pub struct LnChannels {
+    dump_folder: PathBuf,
+    writer: common::DumpWriter,
+
+    // key: txid + index
+    funding_outputs: HashMap<OutpointKey, FundingOutput>,
+
+    start_height: u64,
+    n_channels: u64,
+    channel_value: u64,
+}
+
+impl Callback for LnChannels {
+    fn build_subcommand() -> Command
+    where
+        Self: Sized,
+    {
+        Command::new("lnchannels")
+            .about("Dumps opened and closed 2-of-2 multisig P2WSH outputs (Lightning channel candidates) to CSV file")
+            .version("0.1")
+            .author("gcarq <egger.m@protonmail.com>")
+            .arg(
+                Arg::new("dump-folder")
+                    .help("Folder to store csv file")
+                    .index(1)
+                    .required(true),
+            )
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let dump_folder = &PathBuf::from(matches.get_one::<String>("dump-folder").unwrap());
+        let cb = LnChannels {
+            dump_folder: PathBuf::from(dump_folder),
+            writer: common::create_writer(dump_folder.join("lnchannels.csv.tmp"))?,
+            funding_outputs: HashMap::new(),
+            start_height: 0,
+            n_channels: 0,
+            channel_value: 0,
+        };
+        Ok(cb)
+    }
+
+    fn on_start(&mut self, block_height: u64) -> OpResult<()> {
+        self.start_height = block_height;
+        if block_height > 0 {
+            warn!(target: "callback", "Channels opened below height {} are not detected!", block_height);
+        }
+        info!(target: "callback", "Executing lnchannels with dump folder: {} ...", &self.dump_folder.display());
+        self.writer.write_all(
+            format!(
+                "{};{};{};{};{};{}\n",
+                "open_txid", "open_vout", "value", "close_txid", "close_height", "lifetime_blocks"
+            )
+            .as_bytes(),
+        )?;
+        Ok(())
+    }
+
+    /// The witness script is the last item of the witness of a P2WSH spend
+    fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<Control> {
+        for tx in &block.txs {
+            for input in &tx.value.inputs {
+                let funding = match self.funding_outputs.remove(&input.outpoint.key()) {
+                    Some(funding) => funding,
+                    None => continue,
+                };
+                if !matches!(input.witness.last(), Some(script) if is_2_of_2_multisig(script)) {
+                    continue;
+                }
+                self.writer.write_all(
+                    format!(
+                        "{};{};{};{};{};{}\n",
+                        utils::hash_to_string(&input.outpoint.txid),
+                        input.outpoint.index,
+                        funding.value,
+                        utils::hash_to_string(&tx.hash),
+                        block_height,
+                        block_height - funding.block_height
+                    )
+                    .as_bytes(),
+                )?;
+                self.n_channels += 1;
+                self.channel_value += funding.value;
+            }
+            for (i, output) in tx.value.outputs.iter().enumerate() {
+                if output.script.pattern == ScriptPattern::Pay2WitnessScriptHash {
+                    self.funding_outputs.insert(
+                        TxOutpoint::new(tx.hash, i as u32).key(),
+                        FundingOutput {
+                            block_height,
+                            value: output.out.value,
+                        },
+                    );
+                }
+            }
+        }
+        Ok(Control::Continue)
+    }
+
+    fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+        self.writer.flush()?;
+        utils::rename(
+            self.dump_folder.as_path().join("lnchannels.csv.tmp"),
+            utils::output_path(
+                &self.dump_folder,
+                "lnchannels",
+                self.start_height,
+                block_height,
+                "csv",
+            ),
+        )?;
+
+        info!(target: "callback", "Done.\nFound {} closed channel candidates with {:.8} from height {} to {}.",
+             self.n_channels, self.channel_value as f64 * 1E-8, self.start_height, block_height);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> OpResult<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    fn dump_folder(&self) -> Option<&Path> {
+        Some(&self.dump_folder)
+    }
+
+    fn summary(&self) -> Value {
+        json!({
+            "channels": self.n_channels,
+            "channel_value": self.channel_value,
+            "unspent_p2wsh_outputs": self.funding_outputs.len(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::testutils;
+    use bitcoin::hashes::{sha256, sha256d, Hash};
+    use std::fs;
+
+    #[test]
+    fn test_lnchannels() {
+        let mut funding_script = vec![0x52, 0x21];
+        funding_script.extend([0x02; 33]);
+        funding_script.push(0x21);
+        funding_script.extend([0x03; 33]);
+        funding_script.extend([0x52, 0xae]);
+        // 1-of-2 multisig, which is no channel
+        let mut other_script = funding_script.clone();
+        other_script[0] = 0x51;
+        let p2wsh = |script: &[u8]| {
+            let mut script_pubkey = vec![0x00, 0x20];
+            script_pubkey.extend(sha256::Hash::hash(script).to_byte_array());
+            script_pubkey
+        };
+
+        let block1 = testutils::block(
+            sha256d::Hash::all_zeros(),
+            0,
+            vec![testutils::coinbase(
+                1,
+                &[
+                    (5000, testutils::p2pkh_script(1)),
+                    (100000, p2wsh(&funding_script)),
+                    (200000, p2wsh(&other_script)),
+                ],
+            )],
+        );
+        let funding_txid = block1.txs[0].hash;
+        let mut close = testutils::tx(
+            &[(funding_txid, 1)],
+            &[
+                (60000, testutils::p2pkh_script(2)),
+                (39000, testutils::p2pkh_script(3)),
+            ],
+        );
+        close.inputs[0].witness = vec![vec![], vec![0x30; 71], vec![0x30; 72], funding_script];
+        let mut other = testutils::tx(
+            &[(funding_txid, 2)],
+            &[(199000, testutils::p2pkh_script(4))],
+        );
+        other.inputs[0].witness = vec![vec![], vec![0x30; 71], other_script];
+        let block2 = testutils::block(
+            block1.header.hash,
+            600,
+            vec![
+                testutils::coinbase(2, &[(5000, testutils::p2pkh_script(0))]),
+                close,
+                other,
+            ],
+        );
+
+        let dump_folder = tempfile::tempdir().unwrap();
+        let matches = LnChannels::build_subcommand()
+            .get_matches_from(["lnchannels", dump_folder.path().to_str().unwrap()]);
+        let mut cb = LnChannels::new(&matches).unwrap();
+        cb.on_start(0).unwrap();
+        cb.on_block(&block1, 0).unwrap();
+        cb.on_block(&block2, 144).unwrap();
+        cb.on_complete(144).unwrap();
+
+        let csv = fs::read_to_string(dump_folder.path().join("lnchannels-0-144.csv")).unwrap();
+        let rows: Vec<&str> = csv.lines().skip(1).collect();
+        assert_eq!(
+            rows,
+            vec![format!(
+                "{};1;100000;{};144;144",
+                utils::hash_to_string(&funding_txid),
+                utils::hash_to_string(&block2.txs[1].hash)
+            )]
+        );
+        assert!(cb.funding_outputs.is_empty());
+    }
+}
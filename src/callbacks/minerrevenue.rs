@@ -0,0 +1,222 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use clap::{Arg, ArgMatches, Command};
+
+use crate::blockchain::parser::types::CoinType;
+use crate::blockchain::proto::block::Block;
+use crate::blockchain::proto::script::ScriptPattern;
+use crate::blockchain::proto::tx::{OutpointKey, TxOutpoint};
+use crate::callbacks::{common, Callback, Control};
+use crate::common::utils;
+use crate::errors::OpResult;
+
+/// Dumps the split of the miner revenue into block subsidy and fees per block
+pub struct MinerRevenue {
+    coin: CoinType,
+    dump_folder: PathBuf,
+    writer: common::DumpWriter,
+
+    // key: txid + index, value: output value
+    unspents: HashMap<OutpointKey, u64>,
+
+    start_height: u64,
+    total_subsidy: u64,
+    total_fees: u64,
+}
+
+impl MinerRevenue {
+    /// Share of the fees in the revenue, empty if there is no revenue at all
+    pub(crate) fn fee_percentage(subsidy: u64, fees: u64) -> String {
+        match subsidy + fees {
+            0 => String::new(),
+            revenue => format!("{:.2}", fees as f64 * 100.0 / revenue as f64),
+        }
+    }
+}
+
+impl Callback for MinerRevenue {
+    fn build_subcommand() -> Command
+    where
+        Self: Sized,
+    {
+        Command::new("minerrevenue")
+            .about("Dumps the subsidy and fee share of the miner revenue per block to CSV file")
+            .version("0.1")
+            .author("gcarq <egger.m@protonmail.com>")
+            .arg(
+                Arg::new("dump-folder")
+                    .help("Folder to store csv file")
+                    .index(1)
+                    .required(true),
+            )
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let dump_folder = &PathBuf::from(matches.get_one::<String>("dump-folder").unwrap());
+        let cb = MinerRevenue {
+            coin: common::get_coin(matches),
+            dump_folder: PathBuf::from(dump_folder),
+            writer: common::create_writer(dump_folder.join("minerrevenue.csv.tmp"))?,
+            unspents: HashMap::with_capacity(10000000),
+            start_height: 0,
+            total_subsidy: 0,
+            total_fees: 0,
+        };
+        Ok(cb)
+    }
+
+    fn on_start(&mut self, block_height: u64) -> OpResult<()> {
+        self.start_height = block_height;
+        if block_height > 0 {
+            warn!(target: "callback", "Fees of transactions spending outputs below height {} are unknown!", block_height);
+        }
+        info!(target: "callback", "Executing minerrevenue with dump folder: {} ...", &self.dump_folder.display());
+        self.writer.write_all(
+            format!(
+                "{};{};{};{}\n",
+                "height", "subsidy", "fees", "fee_percentage"
+            )
+            .as_bytes(),
+        )?;
+        Ok(())
+    }
+
+    /// The subsidy follows the schedule of the coin, the fees are the difference between
+    /// the spent and the created values of all regular transactions
+    fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<Control> {
+        let subsidy = self
+            .coin
+            .block_subsidy(block_height, &block.header.value.prev_hash);
+        let mut fees = 0;
+        for tx in &block.txs {
+            let is_coinbase = tx.value.is_coinbase();
+            let mut in_value = 0;
+            if !is_coinbase {
+                for input in &tx.value.inputs {
+                    in_value += self
+                        .unspents
+                        .remove(&input.outpoint.key())
+                        .unwrap_or_default();
+                }
+            }
+
+            let mut out_value = 0;
+            for (i, output) in tx.value.outputs.iter().enumerate() {
+                out_value += output.out.value;
+                if matches!(
+                    output.script.pattern,
+                    ScriptPattern::OpReturn(_) | ScriptPattern::Unspendable
+                ) {
+                    continue;
+                }
+                self.unspents
+                    .insert(TxOutpoint::new(tx.hash, i as u32).key(), output.out.value);
+            }
+
+            if !is_coinbase {
+                fees += in_value.saturating_sub(out_value);
+            }
+        }
+
+        self.writer.write_all(
+            format!(
+                "{};{};{};{}\n",
+                block_height,
+                subsidy,
+                fees,
+                MinerRevenue::fee_percentage(subsidy, fees)
+            )
+            .as_bytes(),
+        )?;
+        self.total_subsidy += subsidy;
+        self.total_fees += fees;
+        Ok(Control::Continue)
+    }
+
+    fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+        self.writer.flush()?;
+        utils::rename(
+            self.dump_folder.as_path().join("minerrevenue.csv.tmp"),
+            utils::output_path(
+                &self.dump_folder,
+                "minerrevenue",
+                self.start_height,
+                block_height,
+                "csv",
+            ),
+        )?;
+
+        info!(target: "callback", "Done.\nMiner revenue from height {} to {}:\n\
+                                   \t-> subsidy: {:.8}\n\
+                                   \t-> fees:    {:.8} ({}%)",
+             self.start_height, block_height, self.total_subsidy as f64 * 1E-8,
+             self.total_fees as f64 * 1E-8,
+             MinerRevenue::fee_percentage(self.total_subsidy, self.total_fees));
+        Ok(())
+    }
+
+    fn flush(&mut self) -> OpResult<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    fn dump_folder(&self) -> Option<&Path> {
+        Some(&self.dump_folder)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::testutils;
+    use bitcoin::hashes::{sha256d, Hash};
+    use std::fs;
+
+    #[test]
+    fn test_minerrevenue() {
+        let block1 = testutils::block(
+            sha256d::Hash::all_zeros(),
+            0,
+            vec![testutils::coinbase(
+                1,
+                &[(5000000000, testutils::p2pkh_script(1))],
+            )],
+        );
+        // Pays 0.5 BTC fee on top of the 50 BTC subsidy
+        let spend = testutils::tx(
+            &[(block1.txs[0].hash, 0)],
+            &[(4950000000, testutils::p2pkh_script(2))],
+        );
+        let block2 = testutils::block(
+            block1.header.hash,
+            600,
+            vec![
+                testutils::coinbase(2, &[(5050000000, testutils::p2pkh_script(3))]),
+                spend,
+            ],
+        );
+
+        let dump_folder = tempfile::tempdir().unwrap();
+        let matches = MinerRevenue::build_subcommand()
+            .get_matches_from(["minerrevenue", dump_folder.path().to_str().unwrap()]);
+        let mut cb = MinerRevenue::new(&matches).unwrap();
+        cb.on_start(1).unwrap();
+        cb.on_block(&block1, 1).unwrap();
+        cb.on_block(&block2, 2).unwrap();
+        cb.on_complete(2).unwrap();
+
+        let csv = fs::read_to_string(dump_folder.path().join("minerrevenue-1-2.csv")).unwrap();
+        let rows: Vec<&str> = csv.lines().skip(1).collect();
+        assert_eq!(
+            rows,
+            vec!["1;5000000000;0;0.00", "2;5000000000;50000000;0.99"]
+        );
+        assert_eq!(MinerRevenue::fee_percentage(0, 0), "");
+        assert_eq!(MinerRevenue::fee_percentage(0, 1000), "100.00");
+    }
+}
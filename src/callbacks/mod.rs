@@ -1,14 +1,93 @@
 use clap::{ArgMatches, Command};
+use serde_json::{json, Value};
+use std::path::Path;
 
+use crate::blockchain::parser::index::StaleBlock;
 use crate::blockchain::proto::block::Block;
 use crate::errors::OpResult;
 
+pub mod activeaddresses;
+pub mod addressreuse;
+pub mod balanceindex;
 pub mod balances;
-mod common;
+pub mod balancetree;
+pub mod blockfilters;
+pub mod blockindex;
+pub mod change;
+pub mod clusters;
+pub mod coinbasedump;
+pub mod coinjoin;
+pub mod common;
 pub mod csvdump;
+pub mod datacarrier;
+pub mod degrees;
+pub mod dustanalysis;
+pub mod fees;
+pub mod feestats;
+pub mod hodlwaves;
+pub mod immaturecoinbase;
+pub mod inscriptions;
+pub mod intervals;
+pub mod largesttxs;
+pub mod lastactive;
+pub mod liveliness;
+pub mod lnchannels;
+pub mod minerrevenue;
+pub mod monthlysummary;
+pub mod multisigstats;
+pub mod newaddresses;
 pub mod opreturn;
+pub mod payjoin;
+pub mod pgdump;
+pub mod pubkeys;
+pub mod publish;
+pub mod rawblocks;
+pub mod registry;
+pub mod reorgs;
+pub mod richlist;
+pub mod segwitinputs;
+pub mod signatures;
 pub mod simplestats;
+pub mod spendlatency;
+pub mod supply;
+pub mod supplyyears;
+pub mod sweeps;
+pub mod timelocks;
+pub mod txgraph;
+pub mod txinfo;
+pub mod txsizes;
+pub mod txversions;
+pub mod typefees;
+pub mod typeflow;
+pub mod typeshare;
 pub mod unspentcsvdump;
+pub mod utxocounts;
+pub mod utxodelta;
+pub mod utxodiff;
+pub mod utxosnapshot;
+pub mod utxostore;
+pub mod valuecheck;
+pub mod versionbits;
+pub mod walletscan;
+pub mod watchscripts;
+pub mod witnessdump;
+pub mod witnesses;
+pub mod witnessversions;
+
+/// Returned by `Callback::on_block()` to tell the parser whether to go on with the next block
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Control {
+    Continue,
+    /// Stops the parser after the current block, even while watching for new blocks.
+    /// on_complete() gets called with the height of the current block.
+    Stop,
+}
+
+/// Counters a callback adds to the progress log and the `--progress-json` status,
+/// e.g. the number of tracked unspent outputs. Gathered once per progress report.
+pub trait Metrics {
+    fn counters(&self) -> Vec<(&'static str, u64)>;
+}
 
 /// Implement this trait for a custom Callback.
 /// The parser ensures that the blocks arrive in the correct order.
@@ -28,8 +107,43 @@ pub trait Callback {
     /// Gets called shortly before the blocks are parsed.
     fn on_start(&mut self, block_height: u64) -> OpResult<()>;
 
+    /// Gets called after on_start() with the stale blocks of the index within the parsed range,
+    /// i.e. blocks in the blk files which are not part of the main chain.
+    fn on_stale_blocks(&mut self, _stale_blocks: &[StaleBlock]) -> OpResult<()> {
+        Ok(())
+    }
+
+    /// Return true to receive the stale blocks in on_orphan_block().
+    /// Reading them costs additional I/O, so it is disabled by default.
+    fn needs_orphan_blocks(&self) -> bool {
+        false
+    }
+
+    /// Gets called after on_stale_blocks() with each stale block, ordered by height.
+    /// While watching, blocks which were passed to on_block() and got replaced by a reorg
+    /// are passed here as well, from the tip downward, before the parser stops.
+    /// Stale blocks are never passed to on_block(), so they don't affect the accounting of the main chain.
+    fn on_orphan_block(&mut self, _block: &Block, _block_height: u64) -> OpResult<()> {
+        Ok(())
+    }
+
     /// Gets called if a new block is available.
-    fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<()>;
+    /// Return `Control::Stop` once no more blocks are needed.
+    fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<Control>;
+
+    /// Gets called after on_block() every `--snapshot-interval` blocks.
+    /// Long running callbacks which only write their output in on_complete() can dump
+    /// an intermediate snapshot here, without finalizing anything.
+    fn on_interval(&mut self, _block_height: u64) -> OpResult<()> {
+        Ok(())
+    }
+
+    /// Gets called after on_block() every `--flush-interval` blocks.
+    /// Callbacks writing rows while parsing flush their buffered writers here,
+    /// so a crash loses at most the output of one interval.
+    fn flush(&mut self) -> OpResult<()> {
+        Ok(())
+    }
 
     /// Gets called if the parser has finished and all blocks are handled
     fn on_complete(&mut self, block_height: u64) -> OpResult<()>;
@@ -38,4 +152,102 @@ pub trait Callback {
     fn show_progress(&self) -> bool {
         true
     }
+
+    /// Returns the counters of the callback which are reported along with the progress, see `Metrics`
+    fn metrics(&self) -> Option<&dyn Metrics> {
+        None
+    }
+
+    /// Return true to receive the serialized block in `Block::raw`.
+    /// Costs an additional copy of each block, so it is disabled by default.
+    fn needs_raw_block(&self) -> bool {
+        false
+    }
+
+    /// Return true to receive the outputs spent by each transaction in `Block::undo`,
+    /// read from the rev files of the node. Callbacks which only need the values of the inputs
+    /// don't have to track the unspent outputs then, but can't run on blocks fetched via RPC.
+    fn needs_undo(&self) -> bool {
+        false
+    }
+
+    /// Return true if each block can be handled on its own, which allows `--reverse` runs.
+    /// Callbacks which depend on previous blocks (like an UTXO set) keep the default.
+    fn supports_reverse(&self) -> bool {
+        false
+    }
+
+    /// Returns the folder the callback writes to. Required for incremental runs,
+    /// as the parser state is stored in there.
+    fn dump_folder(&self) -> Option<&Path> {
+        None
+    }
+
+    /// Gets called after on_complete() of an incremental run.
+    /// Callbacks which depend on previous blocks (like an UTXO set) persist their state here.
+    fn save_state(&self, _folder: &Path) -> OpResult<()> {
+        Ok(())
+    }
+
+    /// Gets called before on_start() if an incremental run continues a previous one.
+    /// Restores whatever has been written by save_state().
+    fn load_state(&mut self, _folder: &Path) -> OpResult<()> {
+        Ok(())
+    }
+
+    /// Return true if an incremental run can continue after the previous one. Callbacks which depend
+    /// on earlier blocks (like an UTXO set) need to persist them with save_state(), callbacks
+    /// which only write rows derived from the parsed blocks don't need any state.
+    fn supports_incremental(&self) -> bool {
+        false
+    }
+
+    /// Return true if the whole output is derived from the state persisted by save_state()
+    /// in on_complete(), which allows `--resume` to continue from an intermediate checkpoint.
+    /// Callbacks writing rows while parsing would lose them and keep the default.
+    fn supports_checkpoints(&self) -> bool {
+        false
+    }
+
+    /// Return true if the callback writes its rows to stdout, which moves the log to stderr
+    fn writes_stdout(&self) -> bool {
+        false
+    }
+
+    /// Callback specific totals, which are added to the `summary.json` in the dump folder
+    /// once on_complete() returned.
+    fn summary(&self) -> Value {
+        json!({})
+    }
+}
+
+/// Callback which ignores all blocks, used with `--validate-only`
+pub struct NullCallback;
+
+impl Callback for NullCallback {
+    fn build_subcommand() -> Command
+    where
+        Self: Sized,
+    {
+        Command::new("null")
+    }
+
+    fn new(_matches: &ArgMatches) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        Ok(NullCallback)
+    }
+
+    fn on_start(&mut self, _block_height: u64) -> OpResult<()> {
+        Ok(())
+    }
+
+    fn on_block(&mut self, _block: &Block, _block_height: u64) -> OpResult<Control> {
+        Ok(Control::Continue)
+    }
+
+    fn on_complete(&mut self, _block_height: u64) -> OpResult<()> {
+        Ok(())
+    }
 }
@@ -0,0 +1,221 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use chrono::{Datelike, NaiveDate, NaiveDateTime};
+use clap::{Arg, ArgMatches, Command};
+
+use crate::blockchain::proto::block::Block;
+use crate::callbacks::{common, Callback, Control};
+use crate::common::utils;
+use crate::errors::OpResult;
+
+/// Totals of the current month
+#[derive(Default)]
+struct MonthTotals {
+    n_blocks: u64,
+    n_txs: u64,
+    output_value: u64,
+    size: u64,
+}
+
+/// Dumps the number of blocks and transactions, the output value and the average block size per month
+pub struct MonthlySummary {
+    dump_folder: PathBuf,
+    writer: common::DumpWriter,
+
+    totals: MonthTotals,
+    // Highest block timestamp so far, block timestamps are not strictly increasing
+    max_timestamp: i64,
+    month: Option<NaiveDate>,
+
+    start_height: u64,
+    n_months: u64,
+}
+
+impl MonthlySummary {
+    /// Returns the first day (UTC) of the month containing the given timestamp
+    fn month_start(timestamp: i64) -> NaiveDate {
+        NaiveDateTime::from_timestamp_opt(timestamp, 0)
+            .unwrap_or_default()
+            .date()
+            .with_day(1)
+            .unwrap()
+    }
+
+    /// Writes the totals of the current month, if any, and resets them
+    fn flush_month(&mut self) -> OpResult<()> {
+        if let Some(month) = self.month.take() {
+            let totals = std::mem::take(&mut self.totals);
+            self.writer.write_all(
+                format!(
+                    "{};{};{};{};{}\n",
+                    month.format("%Y-%m"),
+                    totals.n_blocks,
+                    totals.n_txs,
+                    totals.output_value,
+                    totals.size / totals.n_blocks
+                )
+                .as_bytes(),
+            )?;
+            self.n_months += 1;
+        }
+        Ok(())
+    }
+}
+
+impl Callback for MonthlySummary {
+    fn build_subcommand() -> Command
+    where
+        Self: Sized,
+    {
+        Command::new("monthlysummary")
+            .about("Dumps block and transaction counts, output value and block size per month to CSV file")
+            .version("0.1")
+            .author("gcarq <egger.m@protonmail.com>")
+            .arg(
+                Arg::new("dump-folder")
+                    .help("Folder to store csv file")
+                    .index(1)
+                    .required(true),
+            )
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let dump_folder = &PathBuf::from(matches.get_one::<String>("dump-folder").unwrap());
+        let cb = MonthlySummary {
+            dump_folder: PathBuf::from(dump_folder),
+            writer: common::create_writer(dump_folder.join("monthlysummary.csv.tmp"))?,
+            totals: MonthTotals::default(),
+            max_timestamp: 0,
+            month: None,
+            start_height: 0,
+            n_months: 0,
+        };
+        Ok(cb)
+    }
+
+    fn on_start(&mut self, block_height: u64) -> OpResult<()> {
+        self.start_height = block_height;
+        info!(target: "callback", "Executing monthlysummary with dump folder: {} ...", &self.dump_folder.display());
+        self.writer.write_all(
+            format!(
+                "{};{};{};{};{}\n",
+                "month", "block_count", "tx_count", "total_output_value", "avg_block_size"
+            )
+            .as_bytes(),
+        )?;
+        Ok(())
+    }
+
+    /// A block belongs to the month of the highest timestamp seen so far,
+    /// so a block with an earlier timestamp never reopens a previous month.
+    fn on_block(&mut self, block: &Block, _block_height: u64) -> OpResult<Control> {
+        self.max_timestamp = self.max_timestamp.max(block.header.value.timestamp as i64);
+        let month = MonthlySummary::month_start(self.max_timestamp);
+        if self.month != Some(month) {
+            self.flush_month()?;
+            self.month = Some(month);
+        }
+
+        self.totals.n_blocks += 1;
+        self.totals.n_txs += block.tx_count.value;
+        self.totals.size += block.size as u64;
+        self.totals.output_value += block
+            .txs
+            .iter()
+            .flat_map(|tx| tx.value.outputs.iter())
+            .map(|o| o.out.value)
+            .sum::<u64>();
+        Ok(Control::Continue)
+    }
+
+    fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+        self.flush_month()?;
+        self.writer.flush()?;
+        utils::rename(
+            self.dump_folder.as_path().join("monthlysummary.csv.tmp"),
+            utils::output_path(
+                &self.dump_folder,
+                "monthlysummary",
+                self.start_height,
+                block_height,
+                "csv",
+            ),
+        )?;
+
+        info!(target: "callback", "Done.\nDumped {} months from height {} to {}.",
+             self.n_months, self.start_height, block_height);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> OpResult<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    fn dump_folder(&self) -> Option<&Path> {
+        Some(&self.dump_folder)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::testutils;
+    use bitcoin::hashes::{sha256d, Hash};
+    use std::fs;
+
+    #[test]
+    fn test_monthlysummary() {
+        // 2009-01-31 23:00, 2009-01-31 23:30, 2009-02-01 00:30 and 2009-01-31 23:50 UTC
+        let timestamps = [1233442800, 1233444600, 1233448200, 1233445800];
+        let mut blocks: Vec<Block> = Vec::new();
+        for (height, timestamp) in timestamps.iter().enumerate() {
+            let prev_hash = blocks
+                .last()
+                .map_or(sha256d::Hash::all_zeros(), |b| b.header.hash);
+            let mut txs = vec![testutils::coinbase(
+                height as u64,
+                &[(5000, testutils::p2pkh_script(0))],
+            )];
+            if height == 1 {
+                txs.push(testutils::tx(
+                    &[(blocks[0].txs[0].hash, 0)],
+                    &[(4000, testutils::p2pkh_script(1))],
+                ));
+            }
+            blocks.push(testutils::block(prev_hash, *timestamp, txs));
+        }
+
+        let dump_folder = tempfile::tempdir().unwrap();
+        let matches = MonthlySummary::build_subcommand()
+            .get_matches_from(["monthlysummary", dump_folder.path().to_str().unwrap()]);
+        let mut cb = MonthlySummary::new(&matches).unwrap();
+        cb.on_start(0).unwrap();
+        for (height, block) in blocks.iter().enumerate() {
+            cb.on_block(block, height as u64).unwrap();
+        }
+        cb.on_complete(3).unwrap();
+
+        let csv = fs::read_to_string(dump_folder.path().join("monthlysummary-0-3.csv")).unwrap();
+        let rows: Vec<&str> = csv.lines().skip(1).collect();
+        let size = |range: std::ops::Range<usize>| {
+            blocks[range.clone()]
+                .iter()
+                .map(|b| b.size as u64)
+                .sum::<u64>()
+                / range.len() as u64
+        };
+        // The last block is earlier than its predecessor and stays in February
+        assert_eq!(
+            rows,
+            vec![
+                format!("2009-01;2;3;14000;{}", size(0..2)),
+                format!("2009-02;2;2;10000;{}", size(2..4)),
+            ]
+        );
+    }
+}
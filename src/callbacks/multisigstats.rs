@@ -0,0 +1,311 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use bitcoin::Script;
+use clap::{Arg, ArgMatches, Command};
+use serde_json::{json, Value};
+
+use crate::blockchain::proto::block::Block;
+use crate::blockchain::proto::script::classify::{script_type, ScriptType};
+use crate::blockchain::proto::script::pubkey::push_only;
+use crate::blockchain::proto::script::ScriptPattern;
+use crate::blockchain::proto::tx::{OutpointKey, TxInput, TxOutpoint};
+use crate::callbacks::{common, Callback, Control};
+use crate::common::utils;
+use crate::errors::OpResult;
+
+/// Number and value of multisig outputs of one kind and m-of-n
+#[derive(Default)]
+struct Usage {
+    count: u64,
+    value: u64,
+}
+
+/// Dumps per block range the number and value of multisig outputs by kind and m-of-n.
+/// Bare multisig outputs are counted when they are created. P2SH and P2WSH outputs only reveal
+/// the multisig script when they are spent, so they are counted then, with the value of the
+/// spent output. Outputs created below the start height are counted without value.
+pub struct MultisigStats {
+    dump_folder: PathBuf,
+    writer: common::DumpWriter,
+    blocks: u64,
+
+    // Values of the unspent P2SH and P2WSH outputs, key: txid + index
+    unspents: HashMap<OutpointKey, u64>,
+    // Current range, aligned to --blocks, and its usage per kind, m and n
+    range: Option<u64>,
+    usage: BTreeMap<(&'static str, u8, u8), Usage>,
+    // Totals per kind for the summary
+    totals: BTreeMap<&'static str, u64>,
+
+    start_height: u64,
+}
+
+impl MultisigStats {
+    /// Kind, m and n of the multisig redeemScript or witnessScript revealed by the input
+    fn revealed_multisig(input: &TxInput) -> Option<(&'static str, u8, u8)> {
+        let witness_script = match input.witness.as_slice() {
+            [_, .., script] => Some(script.as_slice()),
+            _ => None,
+        };
+        let (kind, script) = match push_only(&input.script_sig)?.last() {
+            None => ("p2wsh", witness_script?),
+            Some(redeem_script) if Script::from_bytes(redeem_script).is_v0_p2wsh() => {
+                ("p2sh_p2wsh", witness_script?)
+            }
+            Some(redeem_script) => ("p2sh", *redeem_script),
+        };
+        match script_type(script) {
+            ScriptType::MultiSig { required, total } => Some((kind, required, total)),
+            _ => None,
+        }
+    }
+
+    fn add(&mut self, kind: &'static str, required: u8, total: u8, value: u64) {
+        let usage = self.usage.entry((kind, required, total)).or_default();
+        usage.count += 1;
+        usage.value += value;
+        *self.totals.entry(kind).or_insert(0) += 1;
+    }
+
+    /// Writes the usage of the current range, if any, and resets it
+    fn flush_range(&mut self, end_height: u64) -> OpResult<()> {
+        let range = match self.range.take() {
+            Some(range) => range,
+            None => return Ok(()),
+        };
+        for ((kind, required, total), usage) in &self.usage {
+            self.writer.write_all(
+                format!(
+                    "{};{};{};{};{};{};{}\n",
+                    range.max(self.start_height),
+                    end_height,
+                    kind,
+                    required,
+                    total,
+                    usage.count,
+                    usage.value
+                )
+                .as_bytes(),
+            )?;
+        }
+        self.usage.clear();
+        Ok(())
+    }
+}
+
+impl Callback for MultisigStats {
+    fn build_subcommand() -> Command
+    where
+        Self: Sized,
+    {
+        Command::new("multisigstats")
+            .about("Dumps the number and value of bare, P2SH and P2WSH multisig outputs by m-of-n per block range to CSV file")
+            .version("0.1")
+            .author("gcarq <egger.m@protonmail.com>")
+            .arg(
+                Arg::new("dump-folder")
+                    .help("Folder to store csv file")
+                    .index(1)
+                    .required(true),
+            )
+            .arg(
+                Arg::new("blocks")
+                    .long("blocks")
+                    .value_name("N")
+                    .value_parser(clap::value_parser!(u64).range(1..))
+                    .default_value("2016")
+                    .help("Number of blocks per range"),
+            )
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let dump_folder = &PathBuf::from(matches.get_one::<String>("dump-folder").unwrap());
+        let cb = MultisigStats {
+            dump_folder: PathBuf::from(dump_folder),
+            writer: common::create_writer(dump_folder.join("multisigstats.csv.tmp"))?,
+            blocks: *matches.get_one::<u64>("blocks").unwrap(),
+            unspents: HashMap::with_capacity(10000000),
+            range: None,
+            usage: BTreeMap::new(),
+            totals: BTreeMap::new(),
+            start_height: 0,
+        };
+        Ok(cb)
+    }
+
+    fn on_start(&mut self, block_height: u64) -> OpResult<()> {
+        self.start_height = block_height;
+        if block_height > 0 {
+            warn!(target: "callback", "Values of P2SH and P2WSH outputs below height {} are unknown, their spends are counted without value!", block_height);
+        }
+        info!(target: "callback", "Executing multisigstats with dump folder: {} ...", &self.dump_folder.display());
+        self.writer.write_all(
+            format!(
+                "{};{};{};{};{};{};{}\n",
+                "start_height", "end_height", "kind", "m", "n", "count", "value"
+            )
+            .as_bytes(),
+        )?;
+        Ok(())
+    }
+
+    fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<Control> {
+        let range = block_height / self.blocks * self.blocks;
+        if self.range != Some(range) {
+            self.flush_range(block_height.saturating_sub(1))?;
+            self.range = Some(range);
+        }
+
+        for tx in &block.txs {
+            for input in &tx.value.inputs {
+                let value = self.unspents.remove(&input.outpoint.key());
+                if let Some((kind, required, total)) = MultisigStats::revealed_multisig(input) {
+                    self.add(kind, required, total, value.unwrap_or(0));
+                }
+            }
+            for (i, output) in tx.value.outputs.iter().enumerate() {
+                match output.script.pattern {
+                    ScriptPattern::Pay2ScriptHash | ScriptPattern::Pay2WitnessScriptHash => {
+                        let key = TxOutpoint::new(tx.hash, i as u32).key();
+                        self.unspents.insert(key, output.out.value);
+                    }
+                    _ => {
+                        if let ScriptType::MultiSig { required, total } =
+                            script_type(&output.out.script_pubkey)
+                        {
+                            self.add("bare", required, total, output.out.value);
+                        }
+                    }
+                }
+            }
+        }
+        Ok(Control::Continue)
+    }
+
+    fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+        self.flush_range(block_height)?;
+        self.writer.flush()?;
+        utils::rename(
+            self.dump_folder.as_path().join("multisigstats.csv.tmp"),
+            utils::output_path(
+                &self.dump_folder,
+                "multisigstats",
+                self.start_height,
+                block_height,
+                "csv",
+            ),
+        )?;
+
+        info!(target: "callback", "Done.\nCounted {} multisig outputs from height {} to {}.",
+             self.totals.values().sum::<u64>(), self.start_height, block_height);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> OpResult<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    fn dump_folder(&self) -> Option<&Path> {
+        Some(&self.dump_folder)
+    }
+
+    fn summary(&self) -> Value {
+        json!({ "multisig": self.totals })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::testutils;
+    use bitcoin::hashes::{hash160, sha256, sha256d, Hash};
+    use std::fs;
+
+    /// <m> <pubkey>.. <n> OP_CHECKMULTISIG
+    fn multisig(required: u8, total: u8) -> Vec<u8> {
+        let mut script = vec![0x50 + required];
+        for i in 0..total {
+            script.push(0x21);
+            script.push(0x02);
+            script.extend([i; 32]);
+        }
+        script.extend([0x50 + total, 0xae]);
+        script
+    }
+
+    #[test]
+    fn test_multisigstats() {
+        let redeem_script = multisig(2, 3);
+        let witness_script = multisig(1, 2);
+        let mut p2sh = vec![0xa9, 0x14];
+        p2sh.extend(hash160::Hash::hash(&redeem_script).as_byte_array());
+        p2sh.push(0x87);
+        let mut p2wsh = vec![0x00, 0x20];
+        p2wsh.extend(sha256::Hash::hash(&witness_script).as_byte_array());
+
+        let block1 = testutils::block(
+            sha256d::Hash::all_zeros(),
+            0,
+            vec![testutils::coinbase(
+                1,
+                &[
+                    (1000, multisig(1, 3)),
+                    (2000, multisig(1, 3)),
+                    (3000, p2sh),
+                    (4000, p2wsh),
+                ],
+            )],
+        );
+        let funding = block1.txs[0].hash;
+        let mut script_sig = vec![0x00, 0x47];
+        script_sig.extend([0x30; 0x47]);
+        script_sig.extend([0x4c, redeem_script.len() as u8]);
+        script_sig.extend(&redeem_script);
+        let mut spend = testutils::tx(
+            &[(funding, 2), (funding, 3)],
+            &[(6000, testutils::p2pkh_script(1))],
+        );
+        spend.inputs[0].script_len = (script_sig.len() as u8).into();
+        spend.inputs[0].script_sig = script_sig;
+        spend.inputs[1].witness = vec![Vec::new(), vec![0x30; 0x47], witness_script];
+        let block2 = testutils::block(
+            block1.header.hash,
+            600,
+            vec![testutils::coinbase(2, &[(5000, multisig(2, 2))]), spend],
+        );
+
+        let dump_folder = tempfile::tempdir().unwrap();
+        let matches = MultisigStats::build_subcommand().get_matches_from([
+            "multisigstats",
+            dump_folder.path().to_str().unwrap(),
+            "--blocks",
+            "2",
+        ]);
+        let mut cb = MultisigStats::new(&matches).unwrap();
+        cb.on_start(1).unwrap();
+        cb.on_block(&block1, 1).unwrap();
+        cb.on_block(&block2, 2).unwrap();
+        cb.on_complete(2).unwrap();
+
+        let csv = fs::read_to_string(dump_folder.path().join("multisigstats-1-2.csv")).unwrap();
+        assert_eq!(
+            csv.lines().skip(1).collect::<Vec<_>>(),
+            vec![
+                "1;1;bare;1;3;2;3000",
+                "2;2;bare;2;2;1;5000",
+                "2;2;p2sh;2;3;1;3000",
+                "2;2;p2wsh;1;2;1;4000",
+            ]
+        );
+        assert_eq!(
+            cb.summary(),
+            json!({ "multisig": { "bare": 3, "p2sh": 1, "p2wsh": 1 } })
+        );
+    }
+}
@@ -0,0 +1,267 @@
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use clap::{Arg, ArgMatches, Command};
+
+use crate::blockchain::proto::block::Block;
+use crate::callbacks::{common, Callback, Control};
+use crate::common::bloom::BloomFilter;
+use crate::common::utils;
+use crate::errors::OpResult;
+
+/// False positive rate of the Bloom filter in approximate mode
+const FP_RATE: f64 = 0.01;
+
+/// All addresses seen so far
+enum SeenAddresses {
+    Exact(HashSet<String>),
+    Approximate(BloomFilter),
+}
+
+impl SeenAddresses {
+    /// Returns true if the address wasn't seen before
+    fn insert(&mut self, address: &str) -> bool {
+        match self {
+            SeenAddresses::Exact(set) => {
+                !set.contains(address) && set.insert(String::from(address))
+            }
+            SeenAddresses::Approximate(bloom) => bloom.insert(address),
+        }
+    }
+}
+
+/// Dumps the addresses appearing for the first time in each block
+pub struct NewAddresses {
+    dump_folder: PathBuf,
+    writer: common::DumpWriter,
+
+    seen: SeenAddresses,
+
+    start_height: u64,
+    n_addresses: u64,
+}
+
+impl Callback for NewAddresses {
+    fn build_subcommand() -> Command
+    where
+        Self: Sized,
+    {
+        Command::new("newaddresses")
+            .about("Dumps the addresses appearing for the first time in each block to CSV file")
+            .version("0.1")
+            .author("gcarq <egger.m@protonmail.com>")
+            .arg(
+                Arg::new("dump-folder")
+                    .help("Folder to store csv file")
+                    .index(1)
+                    .required(true),
+            )
+            .arg(
+                Arg::new("approximate")
+                    .long("approximate")
+                    .action(clap::ArgAction::SetTrue)
+                    .help("Keeps the seen addresses in a Bloom filter instead of a set. About 1% of the new addresses are missed"),
+            )
+            .arg(
+                Arg::new("capacity")
+                    .long("capacity")
+                    .value_name("N")
+                    .value_parser(clap::value_parser!(u64).range(1..))
+                    .default_value("1000000000")
+                    .requires("approximate")
+                    .help("Expected number of distinct addresses, used to size the Bloom filter (1.2 GB for the default)"),
+            )
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let dump_folder = &PathBuf::from(matches.get_one::<String>("dump-folder").unwrap());
+        let seen = if matches.get_flag("approximate") {
+            let capacity = *matches.get_one::<u64>("capacity").unwrap();
+            SeenAddresses::Approximate(BloomFilter::new(capacity, FP_RATE))
+        } else {
+            SeenAddresses::Exact(HashSet::with_capacity(10000000))
+        };
+        let cb = NewAddresses {
+            dump_folder: PathBuf::from(dump_folder),
+            writer: common::create_writer(dump_folder.join("newaddresses.csv.tmp"))?,
+            seen,
+            start_height: 0,
+            n_addresses: 0,
+        };
+        Ok(cb)
+    }
+
+    fn on_start(&mut self, block_height: u64) -> OpResult<()> {
+        self.start_height = block_height;
+        info!(target: "callback", "Executing newaddresses with dump folder: {} ...", &self.dump_folder.display());
+        if let SeenAddresses::Approximate(bloom) = &self.seen {
+            info!(target: "callback", "Using a Bloom filter of {} MB", bloom.size() / 1000000);
+        }
+        self.writer
+            .write_all(format!("{};{}\n", "height", "new_address").as_bytes())?;
+        Ok(())
+    }
+
+    /// Addresses first appear in outputs, so inputs don't need to be resolved
+    fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<Control> {
+        for tx in &block.txs {
+            for output in &tx.value.outputs {
+                if let Some(address) = &output.script.address {
+                    if self.seen.insert(address) {
+                        self.writer
+                            .write_all(format!("{};{}\n", block_height, address).as_bytes())?;
+                        self.n_addresses += 1;
+                    }
+                }
+            }
+        }
+        Ok(Control::Continue)
+    }
+
+    fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+        self.writer.flush()?;
+        utils::rename(
+            self.dump_folder.as_path().join("newaddresses.csv.tmp"),
+            utils::output_path(
+                &self.dump_folder,
+                "newaddresses",
+                self.start_height,
+                block_height,
+                "csv",
+            ),
+        )?;
+
+        info!(target: "callback", "Done.\nDumped {} new addresses from height {} to {}.",
+             self.n_addresses, self.start_height, block_height);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> OpResult<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    fn dump_folder(&self) -> Option<&Path> {
+        Some(&self.dump_folder)
+    }
+
+    fn supports_incremental(&self) -> bool {
+        true
+    }
+
+    /// Stores the seen addresses one per line, or the bits of the Bloom filter
+    fn save_state(&self, folder: &Path) -> OpResult<()> {
+        match &self.seen {
+            SeenAddresses::Exact(set) => {
+                let path = folder.join("newaddresses.seen");
+                let tmp_path = path.with_extension("tmp");
+                let mut writer = BufWriter::with_capacity(4000000, File::create(&tmp_path)?);
+                for address in set {
+                    writer.write_all(address.as_bytes())?;
+                    writer.write_all(b"\n")?;
+                }
+                writer.flush()?;
+                drop(writer);
+                utils::rename(&tmp_path, path)?;
+                Ok(())
+            }
+            SeenAddresses::Approximate(bloom) => bloom.save(&folder.join("newaddresses.bloom")),
+        }
+    }
+
+    fn load_state(&mut self, folder: &Path) -> OpResult<()> {
+        match &mut self.seen {
+            SeenAddresses::Exact(set) => {
+                let path = folder.join("newaddresses.seen");
+                if path.exists() {
+                    for line in BufReader::with_capacity(4000000, File::open(path)?).lines() {
+                        set.insert(line?);
+                    }
+                }
+            }
+            SeenAddresses::Approximate(bloom) => {
+                let path = folder.join("newaddresses.bloom");
+                if path.exists() {
+                    *bloom = BloomFilter::load(&path)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::testutils;
+    use bitcoin::hashes::{sha256d, Hash};
+    use std::fs;
+
+    #[test]
+    fn test_newaddresses() {
+        let block1 = testutils::block(
+            sha256d::Hash::all_zeros(),
+            0,
+            vec![testutils::coinbase(
+                1,
+                &[(5000, testutils::p2pkh_script(1))],
+            )],
+        );
+        // Address 2 debuts
+        let block2 = testutils::block(
+            block1.header.hash,
+            600,
+            vec![testutils::coinbase(
+                2,
+                &[
+                    (5000, testutils::p2pkh_script(2)),
+                    (5000, testutils::p2pkh_script(1)),
+                ],
+            )],
+        );
+        // Address 2 reappears
+        let block3 = testutils::block(
+            block2.header.hash,
+            1200,
+            vec![
+                testutils::coinbase(3, &[(5000, testutils::p2pkh_script(2))]),
+                testutils::tx(
+                    &[(block2.txs[0].hash, 0)],
+                    &[(4000, testutils::p2pkh_script(2))],
+                ),
+            ],
+        );
+
+        let dump_folder = tempfile::tempdir().unwrap();
+        let matches = NewAddresses::build_subcommand()
+            .get_matches_from(["newaddresses", dump_folder.path().to_str().unwrap()]);
+        let mut cb = NewAddresses::new(&matches).unwrap();
+        cb.on_start(1).unwrap();
+        cb.on_block(&block1, 1).unwrap();
+        cb.on_block(&block2, 2).unwrap();
+        cb.on_block(&block3, 3).unwrap();
+        cb.on_complete(3).unwrap();
+
+        let address = |b: &Block, i: usize| b.txs[0].value.outputs[i].script.address.clone();
+        let csv = fs::read_to_string(dump_folder.path().join("newaddresses-1-3.csv")).unwrap();
+        let rows: Vec<&str> = csv.lines().skip(1).collect();
+        assert_eq!(
+            rows,
+            vec![
+                format!("1;{}", address(&block1, 0).unwrap()),
+                format!("2;{}", address(&block2, 0).unwrap()),
+            ]
+        );
+
+        // The seen addresses survive an incremental run
+        cb.save_state(dump_folder.path()).unwrap();
+        let mut cb = NewAddresses::new(&matches).unwrap();
+        cb.load_state(dump_folder.path()).unwrap();
+        assert!(!cb.seen.insert(&address(&block2, 0).unwrap()));
+    }
+}
@@ -1,12 +1,67 @@
-use clap::{ArgMatches, Command};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use clap::{Arg, ArgMatches, Command};
+use serde_json::{json, Value};
 
 use crate::blockchain::proto::block::Block;
-use crate::blockchain::proto::script::ScriptPattern;
-use crate::callbacks::Callback;
+use crate::blockchain::proto::script::{self, ScriptPattern};
+use crate::callbacks::{Callback, Control};
+use crate::common::utils;
 use crate::errors::OpResult;
 
-#[derive(Default)]
-pub struct OpReturn;
+/// Known payload prefixes, matched against the start of the payload
+const PREFIXES: [(&[u8], &str); 4] = [
+    (b"omni", "omni"),
+    (b"DOCPROOF", "proof_of_existence"),
+    (b"CNTRPRTY", "counterparty"),
+    // Segwit commitment of the coinbase (BIP141)
+    (&[0xaa, 0x21, 0xa9, 0xed], "witness_commitment"),
+];
+
+// OP_RETURN OP_13 marks a runestone
+const RUNESTONE: [u8; 2] = [0x6a, 0x5d];
+
+/// Without a dump folder the OP_RETURN data representable as UTF8 is shown on stdout.
+/// With a dump folder all OP_RETURN outputs are written to a csv file along with the detected protocol.
+pub struct OpReturn {
+    dump: Option<(PathBuf, BufWriter<File>)>,
+
+    start_height: u64,
+    n_outputs: u64,
+}
+
+impl OpReturn {
+    /// Returns the protocol of the data output, or an empty string if it is unknown
+    fn protocol(script_pubkey: &[u8], payload: &[u8]) -> &'static str {
+        if script_pubkey.starts_with(&RUNESTONE) {
+            return "runes";
+        }
+        PREFIXES
+            .iter()
+            .find(|(prefix, _)| payload.starts_with(prefix))
+            .map_or("", |(_, name)| name)
+    }
+
+    fn print(block: &Block, block_height: u64) {
+        for tx in &block.txs {
+            for out in tx.value.outputs.iter() {
+                if let ScriptPattern::OpReturn(data) = &out.script.pattern {
+                    if data.is_empty() {
+                        continue;
+                    }
+                    println!(
+                        "height: {: <9} txid: {}    data: {}",
+                        block_height,
+                        utils::hash_to_string(&tx.hash),
+                        data
+                    );
+                }
+            }
+        }
+    }
+}
 
 impl Callback for OpReturn {
     fn build_subcommand() -> Command
@@ -14,45 +69,178 @@ impl Callback for OpReturn {
         Self: Sized,
     {
         Command::new("opreturn")
-            .about("Shows embedded OP_RETURN data that is representable as UTF8")
+            .about("Shows embedded OP_RETURN data that is representable as UTF8, or dumps all OP_RETURN outputs to CSV file")
             .version("0.1")
             .author("gcarq <egger.m@protonmail.com>")
+            .arg(
+                Arg::new("dump-folder")
+                    .help("Folder to store the csv file, prints to stdout if omitted")
+                    .index(1),
+            )
     }
 
-    fn new(_: &ArgMatches) -> OpResult<Self>
+    fn new(matches: &ArgMatches) -> OpResult<Self>
     where
         Self: Sized,
     {
-        Ok(OpReturn::default())
+        let dump = match matches.get_one::<String>("dump-folder") {
+            Some(folder) => {
+                let folder = PathBuf::from(folder);
+                let file = File::create(folder.join("opreturn.csv.tmp"))?;
+                Some((folder, BufWriter::with_capacity(4000000, file)))
+            }
+            None => None,
+        };
+        Ok(OpReturn {
+            dump,
+            start_height: 0,
+            n_outputs: 0,
+        })
     }
 
-    fn on_start(&mut self, _: u64) -> OpResult<()> {
+    fn on_start(&mut self, block_height: u64) -> OpResult<()> {
+        self.start_height = block_height;
         info!(target: "callback", "Executing OpReturn ...");
+        if let Some((_, writer)) = self.dump.as_mut() {
+            writer.write_all(
+                format!(
+                    "{};{};{};{};{};{}\n",
+                    "txid", "indexOut", "height", "timestamp", "protocol", "payload"
+                )
+                .as_bytes(),
+            )?;
+        }
         Ok(())
     }
 
-    fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<()> {
+    fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<Control> {
+        let writer = match self.dump.as_mut() {
+            Some((_, writer)) => writer,
+            None => {
+                OpReturn::print(block, block_height);
+                return Ok(Control::Continue);
+            }
+        };
         for tx in &block.txs {
-            for out in tx.value.outputs.iter() {
-                if let ScriptPattern::OpReturn(data) = &out.script.pattern {
-                    if data.is_empty() {
-                        continue;
-                    }
-                    println!(
-                        "height: {: <9} txid: {}    data: {}",
-                        block_height, &tx.hash, data
-                    );
+            for (i, out) in tx.value.outputs.iter().enumerate() {
+                if !matches!(out.script.pattern, ScriptPattern::OpReturn(_)) {
+                    continue;
                 }
+                let payload = script::op_return_payload(&out.out.script_pubkey);
+                writer.write_all(
+                    format!(
+                        "{};{};{};{};{};{}\n",
+                        utils::hash_to_string(&tx.hash),
+                        i,
+                        block_height,
+                        block.header.value.timestamp,
+                        OpReturn::protocol(&out.out.script_pubkey, &payload),
+                        utils::arr_to_hex(&payload)
+                    )
+                    .as_bytes(),
+                )?;
+                self.n_outputs += 1;
             }
         }
-        Ok(())
+        Ok(Control::Continue)
     }
 
-    fn on_complete(&mut self, _: u64) -> OpResult<()> {
+    fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+        if let Some((folder, writer)) = self.dump.as_mut() {
+            writer.flush()?;
+            utils::rename(
+                folder.join("opreturn.csv.tmp"),
+                utils::output_path(folder, "opreturn", self.start_height, block_height, "csv"),
+            )?;
+            info!(target: "callback", "Done.\nDumped {} OP_RETURN outputs from height {} to {}.",
+                  self.n_outputs, self.start_height, block_height);
+        }
         Ok(())
     }
 
     fn show_progress(&self) -> bool {
-        false
+        self.dump.is_some()
+    }
+
+    fn supports_reverse(&self) -> bool {
+        true
+    }
+
+    fn flush(&mut self) -> OpResult<()> {
+        if let Some((_, writer)) = self.dump.as_mut() {
+            writer.flush()?;
+        }
+        Ok(())
+    }
+
+    fn dump_folder(&self) -> Option<&Path> {
+        self.dump.as_ref().map(|(folder, _)| folder.as_path())
+    }
+
+    fn supports_incremental(&self) -> bool {
+        true
+    }
+
+    fn summary(&self) -> Value {
+        json!({ "outputs": self.n_outputs })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::testutils;
+    use bitcoin::hashes::{sha256d, Hash};
+    use std::fs;
+
+    #[test]
+    fn test_opreturn_dump() {
+        let omni = [&[0x6a, 0x14][..], b"omni", &[0; 16]].concat();
+        let commitment = [&[0x6a, 0x24, 0xaa, 0x21, 0xa9, 0xed][..], &[0; 32]].concat();
+        let runestone = vec![0x6a, 0x5d, 0x02, 0x14, 0x01];
+        let block = testutils::block(
+            sha256d::Hash::all_zeros(),
+            600,
+            vec![
+                testutils::coinbase(1, &[(5000, testutils::p2pkh_script(0)), (0, commitment)]),
+                testutils::tx(
+                    &[(sha256d::Hash::hash(&[1]), 0)],
+                    &[(1000, testutils::p2pkh_script(1)), (0, omni)],
+                ),
+                testutils::tx(
+                    &[(sha256d::Hash::hash(&[2]), 0)],
+                    &[(0, runestone), (0, vec![0x6a, 0x03, 0x61, 0x62, 0x63])],
+                ),
+            ],
+        );
+
+        let dump_folder = tempfile::tempdir().unwrap();
+        let matches = OpReturn::build_subcommand()
+            .get_matches_from(["opreturn", dump_folder.path().to_str().unwrap()]);
+        let mut cb = OpReturn::new(&matches).unwrap();
+        cb.on_start(7).unwrap();
+        cb.on_block(&block, 7).unwrap();
+        cb.on_complete(7).unwrap();
+
+        let csv = fs::read_to_string(dump_folder.path().join("opreturn-7-7.csv")).unwrap();
+        let txid = |i: usize| utils::hash_to_string(&block.txs[i].hash);
+        assert_eq!(
+            csv.lines().collect::<Vec<&str>>(),
+            vec![
+                String::from("txid;indexOut;height;timestamp;protocol;payload"),
+                format!(
+                    "{};1;7;600;witness_commitment;aa21a9ed{}",
+                    txid(0),
+                    "00".repeat(32)
+                ),
+                format!(
+                    "{};1;7;600;omni;6f6d6e6900000000000000000000000000000000",
+                    txid(1)
+                ),
+                format!("{};0;7;600;runes;1401", txid(2)),
+                format!("{};1;7;600;;616263", txid(2)),
+            ]
+        );
+        assert_eq!(cb.summary()["outputs"], 4);
     }
 }
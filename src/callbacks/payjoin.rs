@@ -0,0 +1,278 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use clap::{Arg, ArgMatches, Command};
+
+use crate::blockchain::proto::block::Block;
+use crate::blockchain::proto::tx::{EvaluatedTxOut, OutpointKey, TxOutpoint};
+use crate::callbacks::{common, Callback, Control};
+use crate::common::address::CompactAddress;
+use crate::common::utils;
+use crate::errors::OpResult;
+
+/// Value, script type and address of an unspent output, needed to compare the outputs with the inputs
+struct SpentOutput {
+    value: u64,
+    script_type: &'static str,
+    address: Option<CompactAddress>,
+}
+
+/// Counts transactions shaped like a PayJoin, where the receiver contributes an input
+pub struct PayJoin {
+    dump_folder: PathBuf,
+    writer: common::DumpWriter,
+    min_inputs: usize,
+    max_inputs: usize,
+    allow_mixed_types: bool,
+
+    // key: txid + index
+    unspents: HashMap<OutpointKey, SpentOutput>,
+
+    start_height: u64,
+    n_candidates: u64,
+}
+
+impl PayJoin {
+    /// Returns true if all rules match, see README
+    fn is_candidate(&self, outputs: &[EvaluatedTxOut], inputs: &[Option<SpentOutput>]) -> bool {
+        // 1. input and output count
+        if inputs.len() < self.min_inputs || inputs.len() > self.max_inputs || outputs.len() != 2 {
+            return false;
+        }
+        // 2. all inputs are known
+        let inputs = match inputs
+            .iter()
+            .map(Option::as_ref)
+            .collect::<Option<Vec<&SpentOutput>>>()
+        {
+            Some(inputs) => inputs,
+            None => return false,
+        };
+        // 3. all inputs and outputs share the script type
+        let script_type = inputs[0].script_type;
+        if !self.allow_mixed_types
+            && (inputs.iter().any(|i| i.script_type != script_type)
+                || outputs
+                    .iter()
+                    .any(|o| o.script.pattern.short_name() != script_type))
+        {
+            return false;
+        }
+        // 4. no output pays back to an input address
+        if outputs.iter().any(|o| {
+            let address = o.script.address.as_deref().map(CompactAddress::new);
+            address.is_some() && inputs.iter().any(|i| i.address == address)
+        }) {
+            return false;
+        }
+        // 5. the output values differ, otherwise it looks like a CoinJoin
+        let (a, b) = (outputs[0].out.value, outputs[1].out.value);
+        if a == b {
+            return false;
+        }
+        // 6. unnecessary input: the smallest input doesn't exceed the smallest output,
+        //    so the transaction could have been funded without it
+        let min_input = inputs.iter().map(|i| i.value).min().unwrap();
+        min_input <= a.min(b)
+    }
+}
+
+impl Callback for PayJoin {
+    fn build_subcommand() -> Command
+    where
+        Self: Sized,
+    {
+        Command::new("payjoin")
+            .about("Dumps the number of PayJoin-like transactions per block to CSV file")
+            .version("0.1")
+            .author("gcarq <egger.m@protonmail.com>")
+            .arg(
+                Arg::new("dump-folder")
+                    .help("Folder to store csv file")
+                    .index(1)
+                    .required(true),
+            )
+            .arg(
+                Arg::new("min-inputs")
+                    .long("min-inputs")
+                    .value_name("N")
+                    .value_parser(clap::value_parser!(u64).range(2..))
+                    .default_value("2")
+                    .help("Minimum number of inputs"),
+            )
+            .arg(
+                Arg::new("max-inputs")
+                    .long("max-inputs")
+                    .value_name("N")
+                    .value_parser(clap::value_parser!(u64).range(2..))
+                    .default_value("10")
+                    .help("Maximum number of inputs"),
+            )
+            .arg(
+                Arg::new("allow-mixed-types")
+                    .long("allow-mixed-types")
+                    .action(clap::ArgAction::SetTrue)
+                    .help("Don't require all inputs and outputs to share the script type"),
+            )
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let dump_folder = &PathBuf::from(matches.get_one::<String>("dump-folder").unwrap());
+        let cb = PayJoin {
+            dump_folder: PathBuf::from(dump_folder),
+            writer: common::create_writer(dump_folder.join("payjoin.csv.tmp"))?,
+            min_inputs: *matches.get_one::<u64>("min-inputs").unwrap() as usize,
+            max_inputs: *matches.get_one::<u64>("max-inputs").unwrap() as usize,
+            allow_mixed_types: matches.get_flag("allow-mixed-types"),
+            unspents: HashMap::with_capacity(10000000),
+            start_height: 0,
+            n_candidates: 0,
+        };
+        Ok(cb)
+    }
+
+    fn on_start(&mut self, block_height: u64) -> OpResult<()> {
+        self.start_height = block_height;
+        info!(target: "callback", "Executing payjoin with dump folder: {} ...", &self.dump_folder.display());
+        self.writer
+            .write_all(format!("{};{};{}\n", "height", "txCount", "candidates").as_bytes())?;
+        Ok(())
+    }
+
+    fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<Control> {
+        let mut candidates = 0;
+        for tx in &block.txs {
+            if !tx.value.is_coinbase() {
+                let inputs = tx
+                    .value
+                    .inputs
+                    .iter()
+                    .map(|input| self.unspents.remove(&input.outpoint.key()))
+                    .collect::<Vec<Option<SpentOutput>>>();
+                if self.is_candidate(&tx.value.outputs, &inputs) {
+                    candidates += 1;
+                }
+            }
+
+            for (i, output) in tx.value.outputs.iter().enumerate() {
+                self.unspents.insert(
+                    TxOutpoint::new(tx.hash, i as u32).key(),
+                    SpentOutput {
+                        value: output.out.value,
+                        script_type: output.script.pattern.short_name(),
+                        address: output.script.address.as_deref().map(CompactAddress::new),
+                    },
+                );
+            }
+        }
+        self.writer.write_all(
+            format!("{};{};{}\n", block_height, block.tx_count.value, candidates).as_bytes(),
+        )?;
+        self.n_candidates += candidates;
+        Ok(Control::Continue)
+    }
+
+    fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+        self.writer.flush()?;
+        utils::rename(
+            self.dump_folder.as_path().join("payjoin.csv.tmp"),
+            utils::output_path(
+                &self.dump_folder,
+                "payjoin",
+                self.start_height,
+                block_height,
+                "csv",
+            ),
+        )?;
+
+        info!(target: "callback", "Done.\nFound {} PayJoin candidates from height {} to {}.",
+             self.n_candidates, self.start_height, block_height);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> OpResult<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    fn dump_folder(&self) -> Option<&Path> {
+        Some(&self.dump_folder)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::testutils;
+    use bitcoin::hashes::{sha256d, Hash};
+    use std::fs;
+
+    fn p2wpkh(seed: u8) -> Vec<u8> {
+        let mut script = vec![0x00, 0x14];
+        script.extend([seed; 20]);
+        script
+    }
+
+    #[test]
+    fn test_payjoin() {
+        // Outputs of the sender (0, 1) and the receiver (2)
+        let block1 = testutils::block(
+            sha256d::Hash::all_zeros(),
+            0,
+            vec![testutils::coinbase(
+                1,
+                &[(10000, p2wpkh(1)), (4000, p2wpkh(2)), (6000, p2wpkh(3))],
+            )],
+        );
+        let coinbase = block1.txs[0].hash;
+        // The sender pays 2500 and gets 7000 back, the receiver adds its 6000 input
+        let payjoin = testutils::tx(
+            &[(coinbase, 0), (coinbase, 2)],
+            &[(7000, p2wpkh(4)), (8500, p2wpkh(5))],
+        );
+        // A regular payment with a single input
+        let payment = testutils::tx(&[(coinbase, 1)], &[(1000, p2wpkh(6)), (2500, p2wpkh(7))]);
+        let block2 = testutils::block(
+            block1.header.hash,
+            600,
+            vec![
+                testutils::coinbase(2, &[(5000, p2wpkh(0))]),
+                payjoin,
+                payment,
+            ],
+        );
+
+        let dump_folder = tempfile::tempdir().unwrap();
+        let matches = PayJoin::build_subcommand()
+            .get_matches_from(["payjoin", dump_folder.path().to_str().unwrap()]);
+        let mut cb = PayJoin::new(&matches).unwrap();
+        cb.on_start(1).unwrap();
+        cb.on_block(&block1, 1).unwrap();
+        cb.on_block(&block2, 2).unwrap();
+        cb.on_complete(2).unwrap();
+
+        let csv = fs::read_to_string(dump_folder.path().join("payjoin-1-2.csv")).unwrap();
+        let rows: Vec<&str> = csv.lines().skip(1).collect();
+        assert_eq!(rows, vec!["1;1;0", "2;3;1"]);
+
+        // The smallest input is needed if the sender gets less change back
+        let outputs = &block2.txs[1].value.outputs;
+        let inputs = vec![
+            Some(SpentOutput {
+                value: 10000,
+                script_type: "p2wpkh",
+                address: None,
+            }),
+            Some(SpentOutput {
+                value: 7500,
+                script_type: "p2wpkh",
+                address: None,
+            }),
+        ];
+        assert!(!cb.is_candidate(outputs, &inputs));
+    }
+}
@@ -0,0 +1,223 @@
+use std::collections::HashSet;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use clap::{Arg, ArgMatches, Command};
+
+use crate::blockchain::proto::block::Block;
+use crate::blockchain::proto::script::pubkey::{self, RevealedPubkey};
+use crate::callbacks::{common, Callback, Control};
+use crate::common::utils;
+use crate::errors::OpResult;
+
+/// Dumps all public keys exposed on-chain by outputs and spending inputs
+pub struct Pubkeys {
+    dump_folder: PathBuf,
+    writer: common::DumpWriter,
+
+    // Pubkeys which have already been written, only used with `--unique`
+    seen: Option<HashSet<Vec<u8>>>,
+
+    start_height: u64,
+    n_pubkeys: u64,
+}
+
+impl Pubkeys {
+    fn write_pubkeys(
+        &mut self,
+        block_height: u64,
+        txid: &str,
+        is_coinbase: bool,
+        pubkeys: Vec<RevealedPubkey>,
+    ) -> OpResult<()> {
+        for pubkey in pubkeys {
+            if let Some(seen) = self.seen.as_mut() {
+                if !seen.insert(pubkey.key.to_vec()) {
+                    continue;
+                }
+            }
+            self.writer.write_all(
+                format!(
+                    "{};{};{};{};{}\n",
+                    block_height,
+                    txid,
+                    is_coinbase as u8,
+                    pubkey.source,
+                    utils::arr_to_hex(pubkey.key)
+                )
+                .as_bytes(),
+            )?;
+            self.n_pubkeys += 1;
+        }
+        Ok(())
+    }
+}
+
+impl Callback for Pubkeys {
+    fn build_subcommand() -> Command
+    where
+        Self: Sized,
+    {
+        Command::new("pubkeys")
+            .about("Dumps all public keys revealed on-chain to CSV file")
+            .version("0.1")
+            .author("gcarq <egger.m@protonmail.com>")
+            .arg(
+                Arg::new("dump-folder")
+                    .help("Folder to store csv file")
+                    .index(1)
+                    .required(true),
+            )
+            .arg(
+                Arg::new("unique")
+                    .long("unique")
+                    .action(clap::ArgAction::SetTrue)
+                    .help("Only dumps the first occurrence of each public key (keeps all keys in memory)"),
+            )
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let dump_folder = &PathBuf::from(matches.get_one::<String>("dump-folder").unwrap());
+        let cb = Pubkeys {
+            dump_folder: PathBuf::from(dump_folder),
+            writer: common::create_writer(dump_folder.join("pubkeys.csv.tmp"))?,
+            seen: matches.get_flag("unique").then(HashSet::new),
+            start_height: 0,
+            n_pubkeys: 0,
+        };
+        Ok(cb)
+    }
+
+    fn on_start(&mut self, block_height: u64) -> OpResult<()> {
+        self.start_height = block_height;
+        info!(target: "callback", "Executing pubkeys with dump folder: {} ...", &self.dump_folder.display());
+        self.writer.write_all(
+            format!(
+                "{};{};{};{};{}\n",
+                "height", "txid", "isCoinbase", "sourceType", "pubkey"
+            )
+            .as_bytes(),
+        )?;
+        Ok(())
+    }
+
+    fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<Control> {
+        for tx in &block.txs {
+            let txid = utils::hash_to_string(&tx.hash);
+            let is_coinbase = tx.value.is_coinbase();
+            if !is_coinbase {
+                for input in &tx.value.inputs {
+                    let pubkeys = pubkey::input_pubkeys(input);
+                    self.write_pubkeys(block_height, &txid, is_coinbase, pubkeys)?;
+                }
+            }
+            for output in &tx.value.outputs {
+                let pubkeys = pubkey::output_pubkeys(&output.out.script_pubkey);
+                self.write_pubkeys(block_height, &txid, is_coinbase, pubkeys)?;
+            }
+        }
+        Ok(Control::Continue)
+    }
+
+    fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+        self.writer.flush()?;
+        utils::rename(
+            self.dump_folder.as_path().join("pubkeys.csv.tmp"),
+            utils::output_path(
+                &self.dump_folder,
+                "pubkeys",
+                self.start_height,
+                block_height,
+                "csv",
+            ),
+        )?;
+
+        info!(target: "callback", "Done.\nDumped {} public keys from height {} to {}.",
+             self.n_pubkeys, self.start_height, block_height);
+        Ok(())
+    }
+
+    /// With --unique the first occurrence of a key depends on the order of the blocks
+    fn supports_reverse(&self) -> bool {
+        self.seen.is_none()
+    }
+
+    fn flush(&mut self) -> OpResult<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    fn dump_folder(&self) -> Option<&Path> {
+        Some(&self.dump_folder)
+    }
+
+    /// The pubkeys seen with `--unique` are not persisted
+    fn supports_incremental(&self) -> bool {
+        self.seen.is_none()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::proto::tx::RawTx;
+    use crate::blockchain::proto::varuint::VarUint;
+    use crate::common::testutils;
+    use bitcoin::hashes::{sha256d, Hash};
+    use std::fs;
+
+    /// Spends the given outpoint with a P2PKH scriptSig: <sig> <pubkey>
+    fn p2pkh_spend(txid: sha256d::Hash, pubkey: &[u8]) -> RawTx {
+        let mut sig = vec![0x30, 0x44];
+        sig.extend([0x02; 69]);
+        let mut script_sig = vec![sig.len() as u8];
+        script_sig.extend(&sig);
+        script_sig.push(pubkey.len() as u8);
+        script_sig.extend(pubkey);
+        let mut tx = testutils::tx(&[(txid, 0)], &[(1000, testutils::p2pkh_script(9))]);
+        tx.inputs[0].script_len = VarUint::from(script_sig.len() as u8);
+        tx.inputs[0].script_sig = script_sig;
+        tx
+    }
+
+    #[test]
+    fn test_pubkeys_p2pkh_spend() {
+        let mut pubkey = vec![0x03];
+        pubkey.extend([0x5a; 32]);
+        let block = testutils::block(
+            sha256d::Hash::all_zeros(),
+            0,
+            vec![
+                testutils::coinbase(1, &[(5000000000, testutils::p2pkh_script(0))]),
+                p2pkh_spend(sha256d::Hash::hash(&[0x01]), &pubkey),
+                p2pkh_spend(sha256d::Hash::hash(&[0x02]), &pubkey),
+            ],
+        );
+
+        let dump_folder = tempfile::tempdir().unwrap();
+        let matches = Pubkeys::build_subcommand().get_matches_from([
+            "pubkeys",
+            dump_folder.path().to_str().unwrap(),
+            "--unique",
+        ]);
+        let mut cb = Pubkeys::new(&matches).unwrap();
+        cb.on_start(1).unwrap();
+        cb.on_block(&block, 1).unwrap();
+        cb.on_complete(1).unwrap();
+
+        let csv = fs::read_to_string(dump_folder.path().join("pubkeys-1-1.csv")).unwrap();
+        let rows: Vec<&str> = csv.lines().skip(1).collect();
+        // The second spend reuses the same key
+        assert_eq!(
+            rows,
+            vec![format!(
+                "1;{};0;p2pkh;{}",
+                block.txs[1].hash,
+                utils::arr_to_hex(&pubkey)
+            )]
+        );
+    }
+}
@@ -0,0 +1,248 @@
+use clap::{Arg, ArgMatches, Command};
+use serde_json::{json, Value};
+
+use crate::blockchain::proto::block::Block;
+use crate::blockchain::proto::tx::EvaluatedTx;
+use crate::blockchain::proto::Hashed;
+use crate::callbacks::{Callback, Control};
+use crate::common::sink::{self, MessageSink};
+use crate::common::utils;
+use crate::errors::{OpError, OpErrorKind, OpResult};
+
+/// Publishes each block or transaction as message to Kafka or NATS, keyed by its hash
+pub struct Publish {
+    sink: Box<dyn MessageSink>,
+    uri: String,
+    txs: bool,
+    // Publishes the serialized blocks instead of JSON
+    raw: bool,
+
+    n_messages: u64,
+    n_bytes: u64,
+}
+
+impl Publish {
+    fn block_json(block: &Block, block_height: u64) -> Value {
+        let header = &block.header.value;
+        json!({
+            "height": block_height,
+            "hash": utils::hash_to_string(&block.header.hash),
+            "prev_hash": utils::hash_to_string(&header.prev_hash),
+            "merkle_root": utils::hash_to_string(&header.merkle_root),
+            "version": header.version,
+            "time": header.timestamp,
+            "bits": header.bits,
+            "nonce": header.nonce,
+            "size": block.size,
+            "tx_count": block.tx_count.value,
+            "txids": block.txs.iter().map(|tx| utils::hash_to_string(&tx.hash)).collect::<Vec<_>>(),
+        })
+    }
+
+    fn tx_json(tx: &Hashed<EvaluatedTx>, block: &Block, block_height: u64) -> Value {
+        let inputs: Vec<Value> = tx
+            .value
+            .inputs
+            .iter()
+            .map(|input| {
+                json!({
+                    "txid": utils::hash_to_string(&input.outpoint.txid),
+                    "index": input.outpoint.index,
+                    "script_sig": utils::arr_to_hex(&input.script_sig),
+                    "sequence": input.seq_no,
+                    "witness": input.witness.iter().map(|item| utils::arr_to_hex(item)).collect::<Vec<_>>(),
+                })
+            })
+            .collect();
+        let outputs: Vec<Value> = tx
+            .value
+            .outputs
+            .iter()
+            .map(|output| {
+                json!({
+                    "value": output.out.value,
+                    "script_pubkey": utils::arr_to_hex(&output.out.script_pubkey),
+                    "address": output.script.address,
+                    "type": output.script.pattern.short_name(),
+                })
+            })
+            .collect();
+        json!({
+            "height": block_height,
+            "block_hash": utils::hash_to_string(&block.header.hash),
+            "txid": utils::hash_to_string(&tx.hash),
+            "version": tx.value.version,
+            "locktime": tx.value.locktime,
+            "is_coinbase": tx.value.is_coinbase(),
+            "size": tx.value.size(),
+            "vsize": tx.value.vsize(),
+            "inputs": inputs,
+            "outputs": outputs,
+        })
+    }
+
+    fn send(&mut self, key: &str, payload: &[u8]) -> OpResult<()> {
+        self.sink.send(key.as_bytes(), payload)?;
+        self.n_messages += 1;
+        self.n_bytes += payload.len() as u64;
+        Ok(())
+    }
+}
+
+impl Callback for Publish {
+    fn build_subcommand() -> Command
+    where
+        Self: Sized,
+    {
+        Command::new("publish")
+            .about("Publishes the blocks or transactions as JSON messages to Kafka or NATS")
+            .version("0.1")
+            .author("gcarq <egger.m@protonmail.com>")
+            .arg(
+                Arg::new("sink")
+                    .long("sink")
+                    .value_name("URI")
+                    .required(true)
+                    .help("kafka://host[:port]/topic[?partition=N] or nats://[user:pass@]host[:port]/subject"),
+            )
+            .arg(
+                Arg::new("messages")
+                    .long("messages")
+                    .value_parser(["blocks", "txs"])
+                    .default_value("blocks")
+                    .help("Publishes one message per block or per transaction"),
+            )
+            .arg(
+                Arg::new("raw")
+                    .long("raw")
+                    .action(clap::ArgAction::SetTrue)
+                    .help("Publishes the serialized blocks as stored in the blk files instead of JSON. \
+                           Kafka rejects blocks above 900 KB"),
+            )
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let uri = matches.get_one::<String>("sink").unwrap().clone();
+        let txs = matches.get_one::<String>("messages").unwrap() == "txs";
+        let raw = matches.get_flag("raw");
+        if txs && raw {
+            return Err(OpError::new(OpErrorKind::InvalidArgsError)
+                .join_msg("--raw is only supported with --messages blocks"));
+        }
+        let cb = Publish {
+            sink: sink::open(&uri)?,
+            uri,
+            txs,
+            raw,
+            n_messages: 0,
+            n_bytes: 0,
+        };
+        Ok(cb)
+    }
+
+    fn on_start(&mut self, _: u64) -> OpResult<()> {
+        info!(target: "callback", "Executing publish with sink: {} ...", &self.uri);
+        Ok(())
+    }
+
+    fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<Control> {
+        if self.txs {
+            for tx in &block.txs {
+                let payload = Publish::tx_json(tx, block, block_height).to_string();
+                self.send(&utils::hash_to_string(&tx.hash), payload.as_bytes())?;
+            }
+            return Ok(Control::Continue);
+        }
+        let key = utils::hash_to_string(&block.header.hash);
+        if self.raw {
+            let raw = match &block.raw {
+                Some(raw) => raw,
+                None => {
+                    return Err(OpError::new(OpErrorKind::CallbackError)
+                        .join_msg("Raw block bytes are not available!"))
+                }
+            };
+            self.send(&key, raw)?;
+        } else {
+            let payload = Publish::block_json(block, block_height).to_string();
+            self.send(&key, payload.as_bytes())?;
+        }
+        Ok(Control::Continue)
+    }
+
+    fn on_complete(&mut self, _: u64) -> OpResult<()> {
+        self.sink.flush()?;
+        info!(target: "callback", "Done.\nPublished {} messages ({} bytes) to {}.",
+             self.n_messages, self.n_bytes, self.uri);
+        Ok(())
+    }
+
+    fn needs_raw_block(&self) -> bool {
+        self.raw
+    }
+
+    fn supports_reverse(&self) -> bool {
+        true
+    }
+
+    fn flush(&mut self) -> OpResult<()> {
+        self.sink.flush()
+    }
+
+    fn summary(&self) -> Value {
+        json!({ "messages": self.n_messages, "bytes": self.n_bytes })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::testutils;
+    use bitcoin::hashes::{sha256d, Hash};
+
+    #[test]
+    fn test_publish_txs() {
+        let block = testutils::block(
+            sha256d::Hash::all_zeros(),
+            0,
+            vec![testutils::coinbase(1, &[(50, testutils::p2pkh_script(0))])],
+        );
+        let (port, server) = testutils::nats_server();
+        let uri = format!("nats://127.0.0.1:{}/chain.txs", port);
+        let matches = Publish::build_subcommand().get_matches_from([
+            "publish",
+            "--sink",
+            &uri,
+            "--messages",
+            "txs",
+        ]);
+        let mut cb = Publish::new(&matches).unwrap();
+        cb.on_start(0).unwrap();
+        cb.on_block(&block, 0).unwrap();
+        cb.on_complete(0).unwrap();
+        assert_eq!(cb.summary()["messages"], 1);
+        drop(cb);
+
+        let received = server.join().unwrap();
+        assert_eq!(received[2], format!("PUB chain.txs {}", received[3].len()));
+        let tx: Value = serde_json::from_str(&received[3]).unwrap();
+        assert_eq!(tx["txid"], utils::hash_to_string(&block.txs[0].hash));
+        assert_eq!(tx["block_hash"], utils::hash_to_string(&block.header.hash));
+        assert_eq!(tx["is_coinbase"], true);
+        assert_eq!(tx["outputs"][0]["value"], 50);
+        assert_eq!(tx["outputs"][0]["type"], "p2pkh");
+
+        let matches = Publish::build_subcommand().get_matches_from([
+            "publish",
+            "--sink",
+            &uri,
+            "--messages",
+            "txs",
+            "--raw",
+        ]);
+        assert!(Publish::new(&matches).is_err());
+    }
+}
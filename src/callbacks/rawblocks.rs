@@ -0,0 +1,187 @@
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use clap::{Arg, ArgMatches, Command};
+
+use crate::blockchain::parser::types::CoinType;
+use crate::blockchain::proto::block::Block;
+use crate::callbacks::{common, Callback, Control};
+use crate::common::utils;
+use crate::errors::{OpError, OpErrorKind, OpResult};
+
+/// Writes the serialized blocks as stored in the blk files
+pub struct RawBlocks {
+    coin: CoinType,
+    dump_folder: PathBuf,
+    // Only set with `--concat`, all blocks are written to a single file
+    writer: Option<common::DumpWriter>,
+
+    start_height: u64,
+    n_blocks: u64,
+    n_bytes: u64,
+}
+
+impl Callback for RawBlocks {
+    fn build_subcommand() -> Command
+    where
+        Self: Sized,
+    {
+        Command::new("rawblocks")
+            .about("Writes the raw bytes of each block to a file")
+            .version("0.1")
+            .author("gcarq <egger.m@protonmail.com>")
+            .arg(
+                Arg::new("dump-folder")
+                    .help("Folder to store the block files")
+                    .index(1)
+                    .required(true),
+            )
+            .arg(
+                Arg::new("concat")
+                    .long("concat")
+                    .action(clap::ArgAction::SetTrue)
+                    .help("Writes all blocks to a single file in blk file format (magic, size, block)"),
+            )
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let dump_folder = &PathBuf::from(matches.get_one::<String>("dump-folder").unwrap());
+        let writer = if matches.get_flag("concat") {
+            Some(common::create_writer(dump_folder.join("blocks.bin.tmp"))?)
+        } else {
+            None
+        };
+        let cb = RawBlocks {
+            coin: common::get_coin(matches),
+            dump_folder: PathBuf::from(dump_folder),
+            writer,
+            start_height: 0,
+            n_blocks: 0,
+            n_bytes: 0,
+        };
+        Ok(cb)
+    }
+
+    fn on_start(&mut self, block_height: u64) -> OpResult<()> {
+        self.start_height = block_height;
+        info!(target: "callback", "Executing rawblocks with dump folder: {} ...", &self.dump_folder.display());
+        Ok(())
+    }
+
+    fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<Control> {
+        let raw = match &block.raw {
+            Some(raw) => raw,
+            None => {
+                return Err(OpError::new(OpErrorKind::CallbackError)
+                    .join_msg("Raw block bytes are not available!"))
+            }
+        };
+        match self.writer.as_mut() {
+            Some(writer) => {
+                writer.write_all(&self.coin.magic.to_le_bytes())?;
+                writer.write_all(&(raw.len() as u32).to_le_bytes())?;
+                writer.write_all(raw)?;
+            }
+            None => {
+                let file_name = format!(
+                    "{}_{}.bin",
+                    block_height,
+                    utils::hash_to_string(&block.hash())
+                );
+                fs::write(self.dump_folder.join(file_name), raw)?;
+            }
+        }
+        self.n_blocks += 1;
+        self.n_bytes += raw.len() as u64;
+        Ok(Control::Continue)
+    }
+
+    fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+        if let Some(writer) = self.writer.as_mut() {
+            writer.flush()?;
+            utils::rename(
+                self.dump_folder.as_path().join("blocks.bin.tmp"),
+                utils::output_path(
+                    &self.dump_folder,
+                    "blocks",
+                    self.start_height,
+                    block_height,
+                    "bin",
+                ),
+            )?;
+        }
+
+        info!(target: "callback", "Done.\nWrote {} blocks ({} bytes) from height {} to {}.",
+             self.n_blocks, self.n_bytes, self.start_height, block_height);
+        Ok(())
+    }
+
+    fn needs_raw_block(&self) -> bool {
+        true
+    }
+
+    fn supports_reverse(&self) -> bool {
+        true
+    }
+
+    fn flush(&mut self) -> OpResult<()> {
+        if let Some(writer) = self.writer.as_mut() {
+            writer.flush()?;
+        }
+        Ok(())
+    }
+
+    fn dump_folder(&self) -> Option<&Path> {
+        Some(&self.dump_folder)
+    }
+
+    fn supports_incremental(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::testutils;
+    use bitcoin::hashes::{sha256d, Hash};
+
+    #[test]
+    fn test_rawblocks_concat() {
+        let mut block = testutils::block(
+            sha256d::Hash::all_zeros(),
+            0,
+            vec![testutils::coinbase(1, &[(50, testutils::p2pkh_script(0))])],
+        );
+        block.raw = Some(vec![0xaa; 3]);
+
+        let dump_folder = tempfile::tempdir().unwrap();
+        let matches = RawBlocks::build_subcommand().get_matches_from([
+            "rawblocks",
+            dump_folder.path().to_str().unwrap(),
+            "--concat",
+        ]);
+        let mut cb = RawBlocks::new(&matches).unwrap();
+        cb.on_start(1).unwrap();
+        cb.on_block(&block, 1).unwrap();
+        cb.on_block(&block, 2).unwrap();
+        cb.on_complete(2).unwrap();
+
+        let frame = [
+            &0xd9b4bef9u32.to_le_bytes()[..],
+            &3u32.to_le_bytes(),
+            &[0xaa; 3],
+        ]
+        .concat();
+        let data = fs::read(dump_folder.path().join("blocks-1-2.bin")).unwrap();
+        assert_eq!(data, [frame.clone(), frame].concat());
+
+        // Fails if the parser didn't keep the raw block
+        block.raw = None;
+        assert!(cb.on_block(&block, 3).is_err());
+    }
+}
@@ -0,0 +1,265 @@
+use clap::{ArgMatches, Command};
+
+use crate::callbacks::activeaddresses::ActiveAddresses;
+use crate::callbacks::addressreuse::AddressReuse;
+use crate::callbacks::balanceindex::BalanceIndex;
+use crate::callbacks::balances::Balances;
+use crate::callbacks::balancetree::BalanceTree;
+use crate::callbacks::blockfilters::BlockFilters;
+use crate::callbacks::blockindex::BlockIndex;
+use crate::callbacks::change::Change;
+use crate::callbacks::clusters::Clusters;
+use crate::callbacks::coinbasedump::CoinbaseDump;
+use crate::callbacks::coinjoin::CoinJoin;
+use crate::callbacks::csvdump::CsvDump;
+use crate::callbacks::datacarrier::DataCarrier;
+use crate::callbacks::degrees::Degrees;
+use crate::callbacks::dustanalysis::DustAnalysis;
+use crate::callbacks::fees::Fees;
+use crate::callbacks::feestats::FeeStats;
+use crate::callbacks::hodlwaves::HodlWaves;
+use crate::callbacks::immaturecoinbase::ImmatureCoinbase;
+use crate::callbacks::inscriptions::Inscriptions;
+use crate::callbacks::intervals::Intervals;
+use crate::callbacks::largesttxs::LargestTxs;
+use crate::callbacks::lastactive::LastActive;
+use crate::callbacks::liveliness::Liveliness;
+use crate::callbacks::lnchannels::LnChannels;
+use crate::callbacks::minerrevenue::MinerRevenue;
+use crate::callbacks::monthlysummary::MonthlySummary;
+use crate::callbacks::multisigstats::MultisigStats;
+use crate::callbacks::newaddresses::NewAddresses;
+use crate::callbacks::opreturn::OpReturn;
+use crate::callbacks::payjoin::PayJoin;
+use crate::callbacks::pgdump::PgDump;
+use crate::callbacks::pubkeys::Pubkeys;
+use crate::callbacks::publish::Publish;
+use crate::callbacks::rawblocks::RawBlocks;
+use crate::callbacks::reorgs::Reorgs;
+use crate::callbacks::richlist::RichList;
+use crate::callbacks::segwitinputs::SegwitInputs;
+use crate::callbacks::signatures::Signatures;
+use crate::callbacks::simplestats::SimpleStats;
+use crate::callbacks::spendlatency::SpendLatency;
+use crate::callbacks::supply::Supply;
+use crate::callbacks::supplyyears::SupplyYears;
+use crate::callbacks::sweeps::Sweeps;
+use crate::callbacks::timelocks::Timelocks;
+use crate::callbacks::txgraph::TxGraph;
+use crate::callbacks::txinfo::TxInfo;
+use crate::callbacks::txsizes::TxSizes;
+use crate::callbacks::txversions::TxVersions;
+use crate::callbacks::typefees::TypeFees;
+use crate::callbacks::typeflow::TypeFlow;
+use crate::callbacks::typeshare::TypeShare;
+use crate::callbacks::unspentcsvdump::UnspentCsvDump;
+use crate::callbacks::utxocounts::UtxoCounts;
+use crate::callbacks::utxodelta::UtxoDelta;
+use crate::callbacks::utxodiff::UtxoDiff;
+use crate::callbacks::utxosnapshot::UtxoSnapshot;
+use crate::callbacks::valuecheck::ValueCheck;
+use crate::callbacks::versionbits::VersionBits;
+use crate::callbacks::walletscan::WalletScan;
+use crate::callbacks::watchscripts::WatchScripts;
+use crate::callbacks::witnessdump::WitnessDump;
+use crate::callbacks::witnesses::Witnesses;
+use crate::callbacks::witnessversions::WitnessVersions;
+use crate::callbacks::Callback;
+use crate::errors::OpResult;
+
+/// Instantiates a callback from the matches of its subcommand
+type Factory = fn(&ArgMatches) -> OpResult<Box<dyn Callback>>;
+
+fn create<C: Callback + 'static>(matches: &ArgMatches) -> OpResult<Box<dyn Callback>> {
+    Ok(Box::new(C::new(matches)?))
+}
+
+/// Callbacks which can be selected as subcommand on the command line.
+/// A program embedding the parser registers its own callbacks along with (or instead of) the built-in ones
+/// and passes the registry to `cli::run()`, which adds the subcommands and runs the selected callback.
+#[derive(Default)]
+pub struct CallbackRegistry {
+    // In the order of `--help`
+    entries: Vec<(Command, Factory)>,
+}
+
+impl CallbackRegistry {
+    /// Registry without any callbacks
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registry with all callbacks of this crate
+    pub fn builtin() -> Self {
+        let mut registry = Self::new();
+        registry
+            .register::<UnspentCsvDump>()
+            .register::<CsvDump>()
+            .register::<SimpleStats>()
+            .register::<Balances>()
+            .register::<OpReturn>()
+            .register::<UtxoDiff>()
+            .register::<Timelocks>()
+            .register::<Supply>()
+            .register::<BlockIndex>()
+            .register::<Pubkeys>()
+            .register::<Fees>()
+            .register::<CoinJoin>()
+            .register::<UtxoCounts>()
+            .register::<Change>()
+            .register::<Intervals>()
+            .register::<HodlWaves>()
+            .register::<SpendLatency>()
+            .register::<ValueCheck>()
+            .register::<RawBlocks>()
+            .register::<BalanceIndex>()
+            .register::<TypeShare>()
+            .register::<TxVersions>()
+            .register::<PayJoin>()
+            .register::<ActiveAddresses>()
+            .register::<NewAddresses>()
+            .register::<TxSizes>()
+            .register::<DataCarrier>()
+            .register::<MinerRevenue>()
+            .register::<Witnesses>()
+            .register::<LargestTxs>()
+            .register::<ImmatureCoinbase>()
+            .register::<WatchScripts>()
+            .register::<VersionBits>()
+            .register::<MonthlySummary>()
+            .register::<Clusters>()
+            .register::<Reorgs>()
+            .register::<AddressReuse>()
+            .register::<Signatures>()
+            .register::<TypeFlow>()
+            .register::<Sweeps>()
+            .register::<BalanceTree>()
+            .register::<SegwitInputs>()
+            .register::<Liveliness>()
+            .register::<Degrees>()
+            .register::<TypeFees>()
+            .register::<TxInfo>()
+            .register::<UtxoDelta>()
+            .register::<LnChannels>()
+            .register::<LastActive>()
+            .register::<SupplyYears>()
+            .register::<WitnessVersions>()
+            .register::<FeeStats>()
+            .register::<WalletScan>()
+            .register::<UtxoSnapshot>()
+            .register::<Publish>()
+            .register::<PgDump>()
+            .register::<TxGraph>()
+            .register::<Inscriptions>()
+            .register::<WitnessDump>()
+            .register::<MultisigStats>()
+            .register::<CoinbaseDump>()
+            .register::<RichList>()
+            .register::<DustAnalysis>()
+            .register::<BlockFilters>();
+        registry
+    }
+
+    /// Adds the callback under the name of its subcommand, replacing a callback registered with the same name
+    pub fn register<C: Callback + 'static>(&mut self) -> &mut Self {
+        let subcommand = C::build_subcommand();
+        let entry = (subcommand, create::<C> as Factory);
+        match self.position(entry.0.get_name()) {
+            Some(index) => self.entries[index] = entry,
+            None => self.entries.push(entry),
+        }
+        self
+    }
+
+    /// Returns the names of all registered callbacks
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.entries
+            .iter()
+            .map(|(subcommand, _)| subcommand.get_name())
+    }
+
+    /// Adds the subcommands of all registered callbacks to the command
+    pub fn add_subcommands(&self, command: Command) -> Command {
+        command.subcommands(
+            self.entries
+                .iter()
+                .map(|(subcommand, _)| subcommand.clone()),
+        )
+    }
+
+    /// Instantiates the callback of the selected subcommand, None if no registered callback was selected
+    pub fn create(&self, matches: &ArgMatches) -> Option<OpResult<Box<dyn Callback>>> {
+        let (name, matches) = matches.subcommand()?;
+        let index = self.position(name)?;
+        Some((self.entries[index].1)(matches))
+    }
+
+    fn position(&self, name: &str) -> Option<usize> {
+        self.entries
+            .iter()
+            .position(|(subcommand, _)| subcommand.get_name() == name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::proto::block::Block;
+    use crate::callbacks::Control;
+    use crate::cli;
+    use serde_json::{json, Value};
+
+    struct Custom {
+        name: String,
+    }
+
+    impl Callback for Custom {
+        fn build_subcommand() -> Command {
+            Command::new("simplestats").arg(clap::Arg::new("name").required(true))
+        }
+
+        fn new(matches: &ArgMatches) -> OpResult<Self> {
+            let name = matches.get_one::<String>("name").unwrap().clone();
+            Ok(Self { name })
+        }
+
+        fn on_start(&mut self, _block_height: u64) -> OpResult<()> {
+            Ok(())
+        }
+
+        fn on_block(&mut self, _block: &Block, _block_height: u64) -> OpResult<Control> {
+            Ok(Control::Continue)
+        }
+
+        fn on_complete(&mut self, _block_height: u64) -> OpResult<()> {
+            Ok(())
+        }
+
+        fn summary(&self) -> Value {
+            json!({ "name": self.name })
+        }
+    }
+
+    #[test]
+    fn test_register() {
+        let mut registry = CallbackRegistry::builtin();
+        let n_builtin = registry.names().count();
+        assert!(registry.names().any(|name| name == "csvdump"));
+
+        // Replaces the built-in callback with the same name, keeping its position
+        registry.register::<Custom>();
+        assert_eq!(registry.names().count(), n_builtin);
+        let args = ["rusty-blockparser", "simplestats", "custom"];
+        let options =
+            cli::parse_args(cli::command(&registry).get_matches_from(args), &registry).unwrap();
+        assert_eq!(options.callback_name, "simplestats");
+        assert_eq!(options.callback.summary()["name"], "custom");
+
+        // Only registered callbacks are subcommands
+        let registry = CallbackRegistry::new();
+        let args = ["rusty-blockparser", "csvdump", "/tmp"];
+        assert!(cli::command(&registry).try_get_matches_from(args).is_err());
+        let matches = cli::command(&registry).get_matches_from(["rusty-blockparser"]);
+        assert!(registry.create(&matches).is_none());
+    }
+}
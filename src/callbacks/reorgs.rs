@@ -0,0 +1,233 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use bitcoin::hashes::sha256d;
+use clap::{Arg, ArgAction, ArgMatches, Command};
+use serde_json::{json, Value};
+
+use crate::blockchain::parser::index::StaleBlock;
+use crate::blockchain::proto::block::Block;
+use crate::callbacks::{Callback, Control};
+use crate::common::utils;
+use crate::errors::OpResult;
+
+/// Stale branches starting at the same height
+struct Fork {
+    // Length of the longest stale branch
+    depth: u64,
+    n_stale_blocks: u64,
+}
+
+/// Dumps the depth of the historical reorgs, reconstructed from the stale blocks in the blk files.
+/// A stale branch starts at the first block which doesn't extend the main chain,
+/// its depth is the number of blocks the competing chain had to replace.
+/// With `--stale-blocks` the stale blocks themselves are dumped to `staleblocks-<start>-<end>.csv`.
+pub struct Reorgs {
+    dump_folder: PathBuf,
+    // key: height of the first block of the stale branches
+    forks: BTreeMap<u64, Fork>,
+    // Rows of the stale blocks, only collected with `--stale-blocks`
+    stale_rows: Option<Vec<String>>,
+
+    start_height: u64,
+}
+
+impl Reorgs {
+    /// Groups the stale blocks by the height of the first block of their branch
+    fn forks(stale_blocks: &[StaleBlock]) -> BTreeMap<u64, Fork> {
+        let by_hash: HashMap<sha256d::Hash, &StaleBlock> =
+            stale_blocks.iter().map(|b| (b.hash, b)).collect();
+        let mut forks: BTreeMap<u64, Fork> = BTreeMap::new();
+        for block in stale_blocks {
+            let mut first = block;
+            while let Some(prev) = by_hash.get(&first.prev_hash) {
+                first = prev;
+            }
+            let fork = forks.entry(first.height).or_insert(Fork {
+                depth: 0,
+                n_stale_blocks: 0,
+            });
+            fork.depth = fork.depth.max(block.height - first.height + 1);
+            fork.n_stale_blocks += 1;
+        }
+        forks
+    }
+}
+
+impl Callback for Reorgs {
+    fn build_subcommand() -> Command
+    where
+        Self: Sized,
+    {
+        Command::new("reorgs")
+            .about("Dumps the depth of reorgs reconstructed from stale blocks to CSV file")
+            .version("0.1")
+            .author("gcarq <egger.m@protonmail.com>")
+            .arg(
+                Arg::new("dump-folder")
+                    .help("Folder to store csv file")
+                    .index(1)
+                    .required(true),
+            )
+            .arg(
+                Arg::new("stale-blocks")
+                    .long("stale-blocks")
+                    .action(ArgAction::SetTrue)
+                    .help("Also reads the stale blocks and dumps their header fields and transaction count"),
+            )
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let cb = Reorgs {
+            dump_folder: PathBuf::from(matches.get_one::<String>("dump-folder").unwrap()),
+            forks: BTreeMap::new(),
+            stale_rows: matches.get_flag("stale-blocks").then(Vec::new),
+            start_height: 0,
+        };
+        Ok(cb)
+    }
+
+    fn on_start(&mut self, block_height: u64) -> OpResult<()> {
+        self.start_height = block_height;
+        info!(target: "callback", "Executing reorgs with dump folder: {} ...", &self.dump_folder.display());
+        Ok(())
+    }
+
+    fn on_stale_blocks(&mut self, stale_blocks: &[StaleBlock]) -> OpResult<()> {
+        self.forks = Reorgs::forks(stale_blocks);
+        Ok(())
+    }
+
+    fn needs_orphan_blocks(&self) -> bool {
+        self.stale_rows.is_some()
+    }
+
+    fn on_orphan_block(&mut self, block: &Block, block_height: u64) -> OpResult<()> {
+        if let Some(rows) = self.stale_rows.as_mut() {
+            rows.push(format!(
+                "{};{};{};{};{};{}\n",
+                block_height,
+                utils::hash_to_string(&block.hash()),
+                utils::hash_to_string(&block.header.value.prev_hash),
+                block.header.value.timestamp,
+                block.tx_count.value,
+                block.size
+            ));
+        }
+        Ok(())
+    }
+
+    fn on_block(&mut self, _block: &Block, _block_height: u64) -> OpResult<Control> {
+        Ok(Control::Continue)
+    }
+
+    fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+        let tmp_path = self.dump_folder.join("reorgs.csv.tmp");
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        writer.write_all(
+            format!("{};{};{}\n", "height", "reorg_depth", "stale_block_count").as_bytes(),
+        )?;
+        for (height, fork) in &self.forks {
+            writer.write_all(
+                format!("{};{};{}\n", height, fork.depth, fork.n_stale_blocks).as_bytes(),
+            )?;
+        }
+        writer.flush()?;
+        drop(writer);
+        utils::rename(
+            tmp_path,
+            utils::output_path(
+                &self.dump_folder,
+                "reorgs",
+                self.start_height,
+                block_height,
+                "csv",
+            ),
+        )?;
+
+        if let Some(rows) = &self.stale_rows {
+            let tmp_path = self.dump_folder.join("staleblocks.csv.tmp");
+            let mut writer = BufWriter::new(File::create(&tmp_path)?);
+            writer.write_all(
+                format!(
+                    "{};{};{};{};{};{}\n",
+                    "height", "block_hash", "hashPrev", "nTime", "txCount", "blocksize"
+                )
+                .as_bytes(),
+            )?;
+            for row in rows {
+                writer.write_all(row.as_bytes())?;
+            }
+            writer.flush()?;
+            drop(writer);
+            utils::rename(
+                tmp_path,
+                utils::output_path(
+                    &self.dump_folder,
+                    "staleblocks",
+                    self.start_height,
+                    block_height,
+                    "csv",
+                ),
+            )?;
+        }
+
+        let max_depth = self.forks.values().map(|f| f.depth).max().unwrap_or(0);
+        info!(target: "callback", "Done.\nFound {} reorgs with a maximum depth of {} from height {} to {}.",
+             self.forks.len(), max_depth, self.start_height, block_height);
+        Ok(())
+    }
+
+    fn supports_reverse(&self) -> bool {
+        true
+    }
+
+    fn dump_folder(&self) -> Option<&Path> {
+        Some(&self.dump_folder)
+    }
+
+    fn supports_incremental(&self) -> bool {
+        true
+    }
+
+    fn summary(&self) -> Value {
+        let max_depth = self.forks.values().map(|f| f.depth).max().unwrap_or(0);
+        json!({ "reorgs": self.forks.len(), "max_depth": max_depth })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::hashes::Hash;
+
+    #[test]
+    fn test_forks() {
+        let hash = |i: u8| sha256d::Hash::hash(&[i]);
+        let stale = |height: u64, i: u8, prev: u8| StaleBlock {
+            height,
+            hash: hash(i),
+            prev_hash: hash(prev),
+            blk_index: 0,
+            data_offset: 0,
+        };
+        // Two competing blocks at height 5, one of them has been extended.
+        // The stale block at height 9 builds on the main chain (hash 100).
+        let forks = Reorgs::forks(&[
+            stale(5, 1, 100),
+            stale(5, 2, 100),
+            stale(6, 3, 1),
+            stale(9, 4, 100),
+        ]);
+        let rows: Vec<(u64, u64, u64)> = forks
+            .iter()
+            .map(|(height, f)| (*height, f.depth, f.n_stale_blocks))
+            .collect();
+        assert_eq!(rows, vec![(5, 2, 3), (9, 1, 1)]);
+    }
+}
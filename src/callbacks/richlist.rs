@@ -0,0 +1,357 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use clap::{Arg, ArgMatches, Command};
+use serde_json::{json, Value};
+
+use crate::blockchain::proto::block::Block;
+use crate::callbacks::utxostore::{self, UtxoStore};
+use crate::callbacks::{common, Callback, Control};
+use crate::common::address::CompactAddress;
+use crate::common::utils;
+use crate::errors::OpResult;
+
+/// Number of addresses and their total balance within one balance bucket
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct Bucket {
+    addresses: u64,
+    balance: u64,
+}
+
+/// Dumps the K addresses with the highest balance and the distribution of all balances
+/// over logarithmic buckets, along with the Gini coefficient of the balances
+pub struct RichList {
+    dump_folder: PathBuf,
+    top: usize,
+
+    // key: txid + index
+    unspents: Box<dyn UtxoStore>,
+
+    start_height: u64,
+    n_addresses: u64,
+    total_balance: u64,
+    gini: f64,
+}
+
+impl RichList {
+    /// Index of the bucket `[10^i, 10^(i+1))` sat of the balance
+    fn bucket(balance: u64) -> usize {
+        balance.max(1).ilog10() as usize
+    }
+
+    /// Gini coefficient of the balances sorted in ascending order,
+    /// 0 if all are equal and close to 1 if a single address holds everything
+    fn gini(sorted: &[u64]) -> f64 {
+        let total: u128 = sorted.iter().map(|b| *b as u128).sum();
+        if total == 0 {
+            return 0.0;
+        }
+        let n = sorted.len() as f64;
+        let weighted: f64 = sorted
+            .iter()
+            .enumerate()
+            .map(|(i, b)| (2.0 * (i + 1) as f64 - n - 1.0) * *b as f64)
+            .sum();
+        weighted / (n * total as f64)
+    }
+
+    fn percentage(part: u64, total: u64) -> String {
+        match total {
+            0 => String::new(),
+            total => format!("{:.4}", part as f64 * 100.0 / total as f64),
+        }
+    }
+
+    /// Writes `richlist.csv.tmp` and `richlist-distribution.csv.tmp`
+    fn write_report(&mut self) -> OpResult<()> {
+        let mut balances: HashMap<CompactAddress, u64> = HashMap::new();
+        self.unspents.for_each(&mut |_, unspent| {
+            if !unspent.address.is_empty() {
+                *balances.entry(unspent.address.clone()).or_insert(0) += unspent.value;
+            }
+            Ok(())
+        })?;
+
+        // Keeps the top K in a min-heap, equal balances are ranked by address
+        let mut heap: BinaryHeap<Reverse<(u64, Reverse<String>)>> =
+            BinaryHeap::with_capacity(self.top + 1);
+        let mut buckets: Vec<Bucket> = Vec::new();
+        let mut sorted: Vec<u64> = Vec::with_capacity(balances.len());
+        for (address, balance) in balances {
+            let i = RichList::bucket(balance);
+            if buckets.len() <= i {
+                buckets.resize(i + 1, Bucket::default());
+            }
+            buckets[i].addresses += 1;
+            buckets[i].balance += balance;
+            sorted.push(balance);
+
+            if heap.len() < self.top
+                || matches!(heap.peek(), Some(Reverse((min, _))) if balance >= *min)
+            {
+                heap.push(Reverse((balance, Reverse(address.to_string()))));
+                if heap.len() > self.top {
+                    heap.pop();
+                }
+            }
+        }
+        sorted.sort_unstable();
+        self.n_addresses = sorted.len() as u64;
+        self.total_balance = sorted.iter().sum();
+        self.gini = RichList::gini(&sorted);
+
+        let mut writer = common::create_writer(self.dump_folder.join("richlist.csv.tmp"))?;
+        writer.write_all(
+            format!("{};{};{};{}\n", "rank", "address", "balance", "share").as_bytes(),
+        )?;
+        for (rank, Reverse((balance, Reverse(address)))) in
+            heap.into_sorted_vec().into_iter().enumerate()
+        {
+            writer.write_all(
+                format!(
+                    "{};{};{};{}\n",
+                    rank + 1,
+                    address,
+                    balance,
+                    RichList::percentage(balance, self.total_balance)
+                )
+                .as_bytes(),
+            )?;
+        }
+        writer.flush()?;
+
+        let mut writer =
+            common::create_writer(self.dump_folder.join("richlist-distribution.csv.tmp"))?;
+        writer.write_all(
+            format!(
+                "{};{};{};{};{};{}\n",
+                "minBalance", "maxBalance", "addresses", "balance", "addressShare", "balanceShare"
+            )
+            .as_bytes(),
+        )?;
+        for (i, bucket) in buckets.iter().enumerate() {
+            let min = 10u64.pow(i as u32);
+            writer.write_all(
+                format!(
+                    "{};{};{};{};{};{}\n",
+                    if i == 0 { 1 } else { min },
+                    min.saturating_mul(10) - 1,
+                    bucket.addresses,
+                    bucket.balance,
+                    RichList::percentage(bucket.addresses, self.n_addresses),
+                    RichList::percentage(bucket.balance, self.total_balance)
+                )
+                .as_bytes(),
+            )?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+impl Callback for RichList {
+    fn build_subcommand() -> Command
+    where
+        Self: Sized,
+    {
+        Command::new("richlist")
+            .about("Dumps the addresses with the highest balance and the balance distribution to CSV file")
+            .version("0.1")
+            .author("gcarq <egger.m@protonmail.com>")
+            .arg(
+                Arg::new("dump-folder")
+                    .help("Folder to store csv file")
+                    .index(1)
+                    .required(true),
+            )
+            .arg(
+                Arg::new("top")
+                    .long("top")
+                    .value_name("K")
+                    .value_parser(clap::value_parser!(u64).range(1..))
+                    .default_value("1000")
+                    .help("Number of addresses in the rich list"),
+            )
+            .args(utxostore::utxo_store_args())
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let dump_folder = &PathBuf::from(matches.get_one::<String>("dump-folder").unwrap());
+        let cb = RichList {
+            dump_folder: PathBuf::from(dump_folder),
+            top: *matches.get_one::<u64>("top").unwrap() as usize,
+            unspents: utxostore::open(matches, dump_folder, "richlist"),
+            start_height: 0,
+            n_addresses: 0,
+            total_balance: 0,
+            gini: 0.0,
+        };
+        Ok(cb)
+    }
+
+    fn on_start(&mut self, block_height: u64) -> OpResult<()> {
+        self.start_height = block_height;
+        info!(target: "callback", "Executing richlist with dump folder: {} ...", &self.dump_folder.display());
+        Ok(())
+    }
+
+    fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<Control> {
+        for tx in &block.txs {
+            self.unspents.remove_spent(tx)?;
+            self.unspents.insert_outputs(tx, block_height)?;
+        }
+        Ok(Control::Continue)
+    }
+
+    fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+        self.write_report()?;
+        for name in ["richlist", "richlist-distribution"] {
+            utils::rename(
+                self.dump_folder.as_path().join(format!("{}.csv.tmp", name)),
+                utils::output_path(
+                    &self.dump_folder,
+                    name,
+                    self.start_height,
+                    block_height,
+                    "csv",
+                ),
+            )?;
+        }
+
+        info!(target: "callback", "Done.\nRanked {} addresses holding {:.8}, Gini coefficient: {:.4}",
+              self.n_addresses, self.total_balance as f64 * 1E-8, self.gini);
+        Ok(())
+    }
+
+    fn dump_folder(&self) -> Option<&Path> {
+        Some(&self.dump_folder)
+    }
+
+    fn supports_incremental(&self) -> bool {
+        true
+    }
+
+    fn summary(&self) -> Value {
+        json!({
+            "addresses": self.n_addresses,
+            "balance": self.total_balance,
+            "gini": self.gini,
+        })
+    }
+
+    fn save_state(&self, folder: &Path) -> OpResult<()> {
+        self.unspents.save(folder)
+    }
+
+    fn load_state(&mut self, folder: &Path) -> OpResult<()> {
+        self.unspents.load(folder)
+    }
+
+    fn supports_checkpoints(&self) -> bool {
+        self.unspents.supports_checkpoints()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::testutils;
+    use bitcoin::hashes::{sha256d, Hash};
+    use std::fs;
+
+    #[test]
+    fn test_gini() {
+        assert_eq!(RichList::gini(&[]), 0.0);
+        assert_eq!(RichList::gini(&[5, 5, 5, 5]), 0.0);
+        assert_eq!(RichList::gini(&[0, 0, 0, 8]), 0.75);
+        assert_eq!(RichList::bucket(1), 0);
+        assert_eq!(RichList::bucket(9), 0);
+        assert_eq!(RichList::bucket(10), 1);
+        assert_eq!(RichList::bucket(5000000000), 9);
+    }
+
+    #[test]
+    fn test_richlist() {
+        let block1 = testutils::block(
+            sha256d::Hash::all_zeros(),
+            0,
+            vec![testutils::coinbase(
+                1,
+                &[
+                    (4000, testutils::p2pkh_script(1)),
+                    (3000, testutils::p2pkh_script(2)),
+                    (5, testutils::p2pkh_script(3)),
+                    (3000, testutils::p2pkh_script(4)),
+                ],
+            )],
+        );
+        // Moves the balance of the first address to the third one
+        let spend = testutils::tx(
+            &[(block1.txs[0].hash, 0)],
+            &[(4000, testutils::p2pkh_script(3))],
+        );
+        let block2 = testutils::block(
+            block1.header.hash,
+            600,
+            vec![
+                testutils::coinbase(2, &[(90, testutils::p2pkh_script(5))]),
+                spend,
+            ],
+        );
+        let address = |i: usize| {
+            block1.txs[0].value.outputs[i]
+                .script
+                .address
+                .clone()
+                .unwrap()
+        };
+
+        let dump_folder = tempfile::tempdir().unwrap();
+        let matches = RichList::build_subcommand().get_matches_from([
+            "richlist",
+            dump_folder.path().to_str().unwrap(),
+            "--top",
+            "3",
+        ]);
+        let mut cb = RichList::new(&matches).unwrap();
+        cb.on_start(1).unwrap();
+        cb.on_block(&block1, 1).unwrap();
+        cb.on_block(&block2, 2).unwrap();
+        cb.on_complete(2).unwrap();
+
+        let csv = fs::read_to_string(dump_folder.path().join("richlist-1-2.csv")).unwrap();
+        let (second, third) = match address(1) < address(3) {
+            true => (address(1), address(3)),
+            false => (address(3), address(1)),
+        };
+        assert_eq!(
+            csv.lines().collect::<Vec<_>>(),
+            vec![
+                String::from("rank;address;balance;share"),
+                format!("1;{};4005;39.6731", address(2)),
+                format!("2;{};3000;29.7177", second),
+                format!("3;{};3000;29.7177", third),
+            ]
+        );
+
+        let csv =
+            fs::read_to_string(dump_folder.path().join("richlist-distribution-1-2.csv")).unwrap();
+        assert_eq!(
+            csv.lines().collect::<Vec<_>>(),
+            vec![
+                "minBalance;maxBalance;addresses;balance;addressShare;balanceShare",
+                "1;9;0;0;0.0000;0.0000",
+                "10;99;1;90;25.0000;0.8915",
+                "100;999;0;0;0.0000;0.0000",
+                "1000;9999;3;10005;75.0000;99.1085",
+            ]
+        );
+        assert_eq!(cb.summary()["addresses"], 4);
+        assert_eq!(cb.summary()["balance"], 10095);
+    }
+}
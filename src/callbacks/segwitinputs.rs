@@ -0,0 +1,166 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use clap::{Arg, ArgMatches, Command};
+use serde_json::{json, Value};
+
+use crate::blockchain::proto::block::Block;
+use crate::callbacks::{common, Callback, Control};
+use crate::common::utils;
+use crate::errors::OpResult;
+
+/// Dumps per block how many inputs carry a witness and how many don't.
+/// The coinbase input is left out, its witness only holds the commitment reserved value.
+pub struct SegwitInputs {
+    dump_folder: PathBuf,
+    writer: common::DumpWriter,
+
+    start_height: u64,
+    n_witness_inputs: u64,
+    n_legacy_inputs: u64,
+}
+
+impl Callback for SegwitInputs {
+    fn build_subcommand() -> Command
+    where
+        Self: Sized,
+    {
+        Command::new("segwitinputs")
+            .about("Dumps the number of inputs with and without witness per block to CSV file")
+            .version("0.1")
+            .author("gcarq <egger.m@protonmail.com>")
+            .arg(
+                Arg::new("dump-folder")
+                    .help("Folder to store csv file")
+                    .index(1)
+                    .required(true),
+            )
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let dump_folder = &PathBuf::from(matches.get_one::<String>("dump-folder").unwrap());
+        let cb = SegwitInputs {
+            dump_folder: PathBuf::from(dump_folder),
+            writer: common::create_writer(dump_folder.join("segwitinputs.csv.tmp"))?,
+            start_height: 0,
+            n_witness_inputs: 0,
+            n_legacy_inputs: 0,
+        };
+        Ok(cb)
+    }
+
+    fn on_start(&mut self, block_height: u64) -> OpResult<()> {
+        self.start_height = block_height;
+        info!(target: "callback", "Executing segwitinputs with dump folder: {} ...", &self.dump_folder.display());
+        self.writer.write_all(
+            format!("{};{};{}\n", "height", "witness_inputs", "legacy_inputs").as_bytes(),
+        )?;
+        Ok(())
+    }
+
+    fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<Control> {
+        let (mut witness_inputs, mut legacy_inputs) = (0, 0);
+        for tx in block.txs.iter().filter(|tx| !tx.value.is_coinbase()) {
+            for input in &tx.value.inputs {
+                if input.witness.is_empty() {
+                    legacy_inputs += 1;
+                } else {
+                    witness_inputs += 1;
+                }
+            }
+        }
+        self.writer.write_all(
+            format!("{};{};{}\n", block_height, witness_inputs, legacy_inputs).as_bytes(),
+        )?;
+        self.n_witness_inputs += witness_inputs;
+        self.n_legacy_inputs += legacy_inputs;
+        Ok(Control::Continue)
+    }
+
+    fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+        self.writer.flush()?;
+        utils::rename(
+            self.dump_folder.as_path().join("segwitinputs.csv.tmp"),
+            utils::output_path(
+                &self.dump_folder,
+                "segwitinputs",
+                self.start_height,
+                block_height,
+                "csv",
+            ),
+        )?;
+
+        info!(target: "callback", "Done.\nCounted {} witness and {} legacy inputs from height {} to {}.",
+             self.n_witness_inputs, self.n_legacy_inputs, self.start_height, block_height);
+        Ok(())
+    }
+
+    fn supports_reverse(&self) -> bool {
+        true
+    }
+
+    fn flush(&mut self) -> OpResult<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    fn dump_folder(&self) -> Option<&Path> {
+        Some(&self.dump_folder)
+    }
+
+    fn supports_incremental(&self) -> bool {
+        true
+    }
+
+    fn summary(&self) -> Value {
+        json!({
+            "witness_inputs": self.n_witness_inputs,
+            "legacy_inputs": self.n_legacy_inputs,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::testutils;
+    use bitcoin::hashes::{sha256d, Hash};
+    use std::fs;
+
+    #[test]
+    fn test_segwitinputs() {
+        // One legacy input and two inputs with a P2WPKH witness
+        let mut mixed = testutils::tx(
+            &[
+                (sha256d::Hash::hash(&[1]), 0),
+                (sha256d::Hash::hash(&[2]), 0),
+                (sha256d::Hash::hash(&[3]), 1),
+            ],
+            &[(1000, testutils::p2pkh_script(1))],
+        );
+        mixed.inputs[1].witness = vec![vec![0x30; 71], vec![0x02; 33]];
+        mixed.inputs[2].witness = vec![vec![0x30; 72], vec![0x03; 33]];
+        let mut coinbase = testutils::coinbase(1, &[(5000, testutils::p2pkh_script(0))]);
+        coinbase.inputs[0].witness = vec![vec![0; 32]];
+        let legacy = testutils::tx(
+            &[(sha256d::Hash::hash(&[4]), 0)],
+            &[(1000, testutils::p2pkh_script(2))],
+        );
+        let block = testutils::block(sha256d::Hash::all_zeros(), 0, vec![coinbase, mixed, legacy]);
+
+        let dump_folder = tempfile::tempdir().unwrap();
+        let matches = SegwitInputs::build_subcommand()
+            .get_matches_from(["segwitinputs", dump_folder.path().to_str().unwrap()]);
+        let mut cb = SegwitInputs::new(&matches).unwrap();
+        cb.on_start(1).unwrap();
+        cb.on_block(&block, 1).unwrap();
+        cb.on_complete(1).unwrap();
+
+        let csv = fs::read_to_string(dump_folder.path().join("segwitinputs-1-1.csv")).unwrap();
+        let rows: Vec<&str> = csv.lines().skip(1).collect();
+        assert_eq!(rows, vec!["1;2;2"]);
+    }
+}
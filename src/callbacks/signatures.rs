@@ -0,0 +1,248 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use clap::{Arg, ArgMatches, Command};
+use serde_json::{json, Value};
+
+use crate::blockchain::proto::block::Block;
+use crate::blockchain::proto::script::signature::{
+    self, EcdsaSignature, SIGHASH_ALL, SIGHASH_NONE, SIGHASH_SINGLE,
+};
+use crate::callbacks::{common, Callback, Control};
+use crate::common::utils;
+use crate::errors::OpResult;
+
+/// Signature counts of a block
+#[derive(Default)]
+struct SignatureStats {
+    signatures: u64,
+    high_s: u64,
+    high_r: u64,
+    non_strict_der: u64,
+    // Indexed by the base sighash type, 0 for undefined types
+    sighash: [u64; 4],
+    anyonecanpay: u64,
+}
+
+impl SignatureStats {
+    fn add(&mut self, sig: &EcdsaSignature) {
+        self.signatures += 1;
+        self.high_s += sig.is_high_s() as u64;
+        self.high_r += !sig.is_low_r() as u64;
+        self.non_strict_der += !sig.strict_der as u64;
+        match sig.base_type() {
+            base @ (SIGHASH_ALL | SIGHASH_NONE | SIGHASH_SINGLE) => {
+                self.sighash[base as usize] += 1
+            }
+            _ => self.sighash[0] += 1,
+        }
+        self.anyonecanpay += sig.is_anyonecanpay() as u64;
+    }
+
+    fn merge(&mut self, other: &SignatureStats) {
+        self.signatures += other.signatures;
+        self.high_s += other.high_s;
+        self.high_r += other.high_r;
+        self.non_strict_der += other.non_strict_der;
+        for (total, count) in self.sighash.iter_mut().zip(other.sighash) {
+            *total += count;
+        }
+        self.anyonecanpay += other.anyonecanpay;
+    }
+}
+
+/// Dumps per block how many input signatures have a high S or R value, violate strict DER
+/// and which sighash types they use. Only ECDSA signatures are considered.
+pub struct Signatures {
+    dump_folder: PathBuf,
+    writer: common::DumpWriter,
+
+    totals: SignatureStats,
+
+    start_height: u64,
+}
+
+impl Callback for Signatures {
+    fn build_subcommand() -> Command
+    where
+        Self: Sized,
+    {
+        Command::new("signatures")
+            .about("Dumps high-S, high-R and non-DER signature counts and sighash types per block to CSV file")
+            .version("0.1")
+            .author("gcarq <egger.m@protonmail.com>")
+            .arg(
+                Arg::new("dump-folder")
+                    .help("Folder to store csv file")
+                    .index(1)
+                    .required(true),
+            )
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let dump_folder = &PathBuf::from(matches.get_one::<String>("dump-folder").unwrap());
+        let cb = Signatures {
+            dump_folder: PathBuf::from(dump_folder),
+            writer: common::create_writer(dump_folder.join("signatures.csv.tmp"))?,
+            totals: SignatureStats::default(),
+            start_height: 0,
+        };
+        Ok(cb)
+    }
+
+    fn on_start(&mut self, block_height: u64) -> OpResult<()> {
+        self.start_height = block_height;
+        info!(target: "callback", "Executing signatures with dump folder: {} ...", &self.dump_folder.display());
+        self.writer.write_all(
+            format!(
+                "{};{};{};{};{};{};{};{};{};{}\n",
+                "height",
+                "signatures",
+                "high_s",
+                "high_r",
+                "non_strict_der",
+                "sighash_all",
+                "sighash_none",
+                "sighash_single",
+                "sighash_other",
+                "anyonecanpay"
+            )
+            .as_bytes(),
+        )?;
+        Ok(())
+    }
+
+    fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<Control> {
+        let mut stats = SignatureStats::default();
+        for tx in block.txs.iter().filter(|tx| !tx.value.is_coinbase()) {
+            for input in &tx.value.inputs {
+                for sig in signature::input_signatures(input) {
+                    stats.add(&sig);
+                }
+            }
+        }
+        self.writer.write_all(
+            format!(
+                "{};{};{};{};{};{};{};{};{};{}\n",
+                block_height,
+                stats.signatures,
+                stats.high_s,
+                stats.high_r,
+                stats.non_strict_der,
+                stats.sighash[SIGHASH_ALL as usize],
+                stats.sighash[SIGHASH_NONE as usize],
+                stats.sighash[SIGHASH_SINGLE as usize],
+                stats.sighash[0],
+                stats.anyonecanpay
+            )
+            .as_bytes(),
+        )?;
+        self.totals.merge(&stats);
+        Ok(Control::Continue)
+    }
+
+    fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+        self.writer.flush()?;
+        utils::rename(
+            self.dump_folder.as_path().join("signatures.csv.tmp"),
+            utils::output_path(
+                &self.dump_folder,
+                "signatures",
+                self.start_height,
+                block_height,
+                "csv",
+            ),
+        )?;
+
+        info!(target: "callback", "Done.\nFound {} signatures from height {} to {}: {} high-S, {} high-R, {} not strict DER.",
+             self.totals.signatures, self.start_height, block_height, self.totals.high_s,
+             self.totals.high_r, self.totals.non_strict_der);
+        Ok(())
+    }
+
+    fn supports_reverse(&self) -> bool {
+        true
+    }
+
+    fn flush(&mut self) -> OpResult<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    fn dump_folder(&self) -> Option<&Path> {
+        Some(&self.dump_folder)
+    }
+
+    fn supports_incremental(&self) -> bool {
+        true
+    }
+
+    fn summary(&self) -> Value {
+        json!({
+            "signatures": self.totals.signatures,
+            "high_s": self.totals.high_s,
+            "high_r": self.totals.high_r,
+            "non_strict_der": self.totals.non_strict_der,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::proto::varuint::VarUint;
+    use crate::common::testutils;
+    use bitcoin::hashes::{sha256d, Hash};
+    use std::fs;
+
+    #[test]
+    fn test_signatures() {
+        // Low-R and high-S, signed with SIGHASH_SINGLE | SIGHASH_ANYONECANPAY
+        let mut sig = vec![0x30, 0x44, 0x02, 0x20];
+        sig.extend([0x11; 32]);
+        sig.extend([0x02, 0x20, 0x7f]);
+        sig.extend([0xff; 31]);
+        sig.push(0x83);
+        // scriptSig: <sig> <pubkey>
+        let mut script_sig = vec![sig.len() as u8];
+        script_sig.extend(&sig);
+        script_sig.push(0x21);
+        script_sig.extend([0x02; 33]);
+
+        let mut spending = testutils::tx(
+            &[
+                (sha256d::Hash::hash(&[1]), 0),
+                (sha256d::Hash::hash(&[2]), 0),
+            ],
+            &[(1000, testutils::p2pkh_script(1))],
+        );
+        spending.inputs[0].script_len = VarUint::from(script_sig.len() as u8);
+        spending.inputs[0].script_sig = script_sig;
+        // P2WPKH spend with the same signature, but SIGHASH_ALL
+        *sig.last_mut().unwrap() = 0x01;
+        spending.inputs[1].witness = vec![sig, vec![0x02; 33]];
+        let block = testutils::block(
+            sha256d::Hash::all_zeros(),
+            0,
+            vec![
+                testutils::coinbase(1, &[(5000, testutils::p2pkh_script(0))]),
+                spending,
+            ],
+        );
+
+        let dump_folder = tempfile::tempdir().unwrap();
+        let matches = Signatures::build_subcommand()
+            .get_matches_from(["signatures", dump_folder.path().to_str().unwrap()]);
+        let mut cb = Signatures::new(&matches).unwrap();
+        cb.on_start(1).unwrap();
+        cb.on_block(&block, 1).unwrap();
+        cb.on_complete(1).unwrap();
+
+        let csv = fs::read_to_string(dump_folder.path().join("signatures-1-1.csv")).unwrap();
+        let rows: Vec<&str> = csv.lines().skip(1).collect();
+        assert_eq!(rows, vec!["1;2;2;0;0;1;0;1;0;1"]);
+    }
+}
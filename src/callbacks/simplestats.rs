@@ -4,14 +4,16 @@ use std::io::{self, Write};
 
 use clap::{ArgMatches, Command};
 
+use crate::blockchain::parser::types::CoinType;
 use crate::blockchain::proto::block::{self, Block};
 use crate::blockchain::proto::script::ScriptPattern;
 use crate::blockchain::proto::ToRaw;
-use crate::callbacks::Callback;
+use crate::callbacks::{common, Callback, Control};
 use crate::common::utils;
 use crate::errors::OpResult;
 
 pub struct SimpleStats {
+    coin: CoinType,
     n_valid_blocks: u64,
     block_sizes: Vec<u32>,
 
@@ -39,6 +41,7 @@ pub struct SimpleStats {
 impl Default for SimpleStats {
     fn default() -> Self {
         SimpleStats {
+            coin: CoinType::default(),
             n_valid_blocks: 0,
             block_sizes: vec![],
             n_tx: 0,
@@ -147,14 +150,16 @@ impl SimpleStats {
         writeln!(
             buffer,
             "        seen in block #{}, txid: {}\n",
-            height, &txid
+            height,
+            utils::hash_to_string(&txid)
         )?;
         let (value, height, txid) = self.tx_biggest_size;
         writeln!(buffer, "   -> biggest size tx:\t\t{} bytes", value,)?;
         writeln!(
             buffer,
             "        seen in block #{}, txid: {}\n",
-            height, &txid
+            height,
+            utils::hash_to_string(&txid)
         )?;
         Ok(())
     }
@@ -174,7 +179,8 @@ impl SimpleStats {
             writeln!(
                 buffer,
                 "        first seen in block #{}, txid: {}\n",
-                pos.0, &pos.1
+                pos.0,
+                utils::hash_to_string(&pos.1)
             )?;
         }
         Ok(())
@@ -192,11 +198,14 @@ impl Callback for SimpleStats {
             .author("gcarq <egger.m@protonmail.com>")
     }
 
-    fn new(_: &ArgMatches) -> OpResult<Self>
+    fn new(matches: &ArgMatches) -> OpResult<Self>
     where
         Self: Sized,
     {
-        Ok(SimpleStats::default())
+        Ok(SimpleStats {
+            coin: common::get_coin(matches),
+            ..Default::default()
+        })
     }
 
     fn on_start(&mut self, _: u64) -> OpResult<()> {
@@ -204,7 +213,7 @@ impl Callback for SimpleStats {
         Ok(())
     }
 
-    fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<()> {
+    fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<Control> {
         self.n_valid_blocks += 1;
         self.n_tx += block.tx_count.value;
         self.block_sizes.push(block.size);
@@ -212,11 +221,15 @@ impl Callback for SimpleStats {
         for tx in &block.txs {
             // Collect fee rewards
             if tx.value.is_coinbase() {
-                self.n_tx_total_fee += tx.value.outputs[0]
-                    .out
-                    .value
-                    .checked_sub(block::get_base_reward(block_height))
-                    .unwrap_or_default();
+                self.n_tx_total_fee +=
+                    tx.value.outputs[0]
+                        .out
+                        .value
+                        .saturating_sub(block::get_base_reward(
+                            block_height,
+                            &block.header.value.prev_hash,
+                            &self.coin,
+                        ));
             }
 
             self.n_tx_inputs += tx.value.in_count.value;
@@ -247,12 +260,11 @@ impl Callback for SimpleStats {
                 .header
                 .value
                 .timestamp
-                .checked_sub(self.last_timestamp)
-                .unwrap_or_default();
+                .saturating_sub(self.last_timestamp);
             self.t_between_blocks.push(diff);
         }
         self.last_timestamp = block.header.value.timestamp;
-        Ok(())
+        Ok(Control::Continue)
     }
 
     fn on_complete(&mut self, _: u64) -> OpResult<()> {
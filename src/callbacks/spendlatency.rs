@@ -0,0 +1,263 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use clap::{Arg, ArgMatches, Command};
+
+use crate::blockchain::proto::block::Block;
+use crate::blockchain::proto::tx::OutpointKey;
+use crate::callbacks::{common, Callback, Control};
+use crate::common::utils;
+use crate::errors::OpResult;
+
+/// Histogram buckets of blocks to spend with their inclusive upper bound.
+/// About 144 blocks are mined per day.
+const LATENCY_BUCKETS: [(&str, u64); 8] = [
+    ("0", 0),
+    ("1", 1),
+    ("2-6", 6),
+    ("7-144", 144),
+    ("145-1008", 1008),
+    ("1009-4320", 4320),
+    ("4321-52560", 52560),
+    (">52560", u64::MAX),
+];
+
+/// Dumps the number of blocks between the creation and the spend of each output
+pub struct SpendLatency {
+    dump_folder: PathBuf,
+    writer: common::DumpWriter,
+
+    // key: txid + index
+    unspents: HashMap<OutpointKey, common::UnspentValue>,
+    // Only set with --utxo-window
+    window: Option<common::UnspentWindow>,
+    n_evicted: u64,
+    // Number of spent outputs per bucket of LATENCY_BUCKETS
+    histogram: [u64; LATENCY_BUCKETS.len()],
+
+    start_height: u64,
+}
+
+impl SpendLatency {
+    /// Returns the index of the histogram bucket for the given number of blocks
+    fn bucket(blocks_to_spend: u64) -> usize {
+        LATENCY_BUCKETS
+            .iter()
+            .position(|(_, upper)| blocks_to_spend <= *upper)
+            .unwrap_or(LATENCY_BUCKETS.len() - 1)
+    }
+}
+
+impl Callback for SpendLatency {
+    fn build_subcommand() -> Command
+    where
+        Self: Sized,
+    {
+        Command::new("spendlatency")
+            .about("Dumps the number of blocks between creation and spend of outputs to CSV files")
+            .version("0.1")
+            .author("gcarq <egger.m@protonmail.com>")
+            .arg(
+                Arg::new("dump-folder")
+                    .help("Folder to store csv files")
+                    .index(1)
+                    .required(true),
+            )
+            .arg(common::utxo_window_arg())
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let dump_folder = &PathBuf::from(matches.get_one::<String>("dump-folder").unwrap());
+        let cb = SpendLatency {
+            dump_folder: PathBuf::from(dump_folder),
+            writer: common::create_writer(dump_folder.join("spendlatency.csv.tmp"))?,
+            unspents: HashMap::with_capacity(10000000),
+            window: common::UnspentWindow::from_matches(matches),
+            n_evicted: 0,
+            histogram: [0; LATENCY_BUCKETS.len()],
+            start_height: 0,
+        };
+        Ok(cb)
+    }
+
+    fn on_start(&mut self, block_height: u64) -> OpResult<()> {
+        self.start_height = block_height;
+        info!(target: "callback", "Executing spendlatency with dump folder: {} ...", &self.dump_folder.display());
+        self.writer.write_all(
+            format!(
+                "{};{};{}\n",
+                "created_height", "spent_height", "blocks_to_spend"
+            )
+            .as_bytes(),
+        )?;
+        Ok(())
+    }
+
+    /// Outputs created below the start height or evicted from the window are unknown and skipped
+    fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<Control> {
+        if let Some(window) = &mut self.window {
+            self.n_evicted += window.evict(block_height, &mut self.unspents);
+        }
+        for tx in &block.txs {
+            for spent in common::take_unspents(tx, &mut self.unspents) {
+                let blocks_to_spend = block_height - spent.block_height;
+                self.writer.write_all(
+                    format!(
+                        "{};{};{}\n",
+                        spent.block_height, block_height, blocks_to_spend
+                    )
+                    .as_bytes(),
+                )?;
+                self.histogram[SpendLatency::bucket(blocks_to_spend)] += 1;
+            }
+            common::insert_unspents(tx, block_height, &mut self.unspents);
+            if let Some(window) = &mut self.window {
+                window.track(tx, block_height);
+            }
+        }
+        Ok(Control::Continue)
+    }
+
+    fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+        self.writer.flush()?;
+        utils::rename(
+            self.dump_folder.as_path().join("spendlatency.csv.tmp"),
+            utils::output_path(
+                &self.dump_folder,
+                "spendlatency",
+                self.start_height,
+                block_height,
+                "csv",
+            ),
+        )?;
+
+        let mut writer = BufWriter::new(File::create(
+            self.dump_folder
+                .as_path()
+                .join("spendlatency-histogram.csv.tmp"),
+        )?);
+        writer.write_all(format!("{};{}\n", "blocksToSpend", "count").as_bytes())?;
+        for ((bucket, _), count) in LATENCY_BUCKETS.iter().zip(self.histogram) {
+            writer.write_all(format!("{};{}\n", bucket, count).as_bytes())?;
+        }
+        writer.write_all(format!("{};{}\n", "unspent", self.unspents.len()).as_bytes())?;
+        writer.flush()?;
+        utils::rename(
+            self.dump_folder
+                .as_path()
+                .join("spendlatency-histogram.csv.tmp"),
+            utils::output_path(
+                &self.dump_folder,
+                "spendlatency-histogram",
+                self.start_height,
+                block_height,
+                "csv",
+            ),
+        )?;
+
+        info!(target: "callback", "Done.\nDumped {} spent outputs from height {} to {}, {} are still unspent.",
+             self.histogram.iter().sum::<u64>(), self.start_height, block_height, self.unspents.len());
+        if self.n_evicted > 0 {
+            info!(target: "callback", "{} unspent outputs have been evicted from the window.", self.n_evicted);
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> OpResult<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    fn dump_folder(&self) -> Option<&Path> {
+        Some(&self.dump_folder)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::testutils;
+    use bitcoin::hashes::{sha256d, Hash};
+    use std::fs;
+
+    /// Blocks of the heights 1 to 11
+    fn chain() -> Vec<Block> {
+        let mut blocks: Vec<Block> = Vec::new();
+        for height in 1..=11 {
+            let prev_hash = blocks
+                .last()
+                .map(|b| b.header.hash)
+                .unwrap_or(sha256d::Hash::all_zeros());
+            let mut txs = vec![testutils::coinbase(
+                height,
+                &[(5000, testutils::p2pkh_script(0))],
+            )];
+            // Spends the coinbase of the first block after 10 blocks
+            if height == 11 {
+                txs.push(testutils::tx(
+                    &[(blocks[0].txs[0].hash, 0)],
+                    &[(4000, testutils::p2pkh_script(1))],
+                ));
+            }
+            blocks.push(testutils::block(prev_hash, height as u32 * 600, txs));
+        }
+        blocks
+    }
+
+    fn run(dump_folder: &Path, args: &[&str]) {
+        let matches = SpendLatency::build_subcommand().get_matches_from(
+            ["spendlatency", dump_folder.to_str().unwrap()]
+                .iter()
+                .chain(args),
+        );
+        let mut cb = SpendLatency::new(&matches).unwrap();
+        cb.on_start(1).unwrap();
+        for (i, block) in chain().iter().enumerate() {
+            cb.on_block(block, i as u64 + 1).unwrap();
+        }
+        cb.on_complete(11).unwrap();
+    }
+
+    #[test]
+    fn test_spendlatency() {
+        let dump_folder = tempfile::tempdir().unwrap();
+        run(dump_folder.path(), &[]);
+
+        let csv = fs::read_to_string(dump_folder.path().join("spendlatency-1-11.csv")).unwrap();
+        assert_eq!(
+            csv,
+            "created_height;spent_height;blocks_to_spend\n1;11;10\n"
+        );
+
+        let histogram =
+            fs::read_to_string(dump_folder.path().join("spendlatency-histogram-1-11.csv")).unwrap();
+        let rows: Vec<&str> = histogram.lines().skip(1).collect();
+        assert_eq!(rows[3], "7-144;1");
+        // 10 remaining coinbases and the new output
+        assert_eq!(rows.last().unwrap(), &"unspent;11");
+    }
+
+    #[test]
+    fn test_spendlatency_window() {
+        // The spent output is exactly 10 blocks old and still in the window
+        let dump_folder = tempfile::tempdir().unwrap();
+        run(dump_folder.path(), &["--utxo-window", "10"]);
+        let csv = fs::read_to_string(dump_folder.path().join("spendlatency-1-11.csv")).unwrap();
+        assert_eq!(csv.lines().count(), 2);
+
+        // Created K + 1 blocks before the spend, so it has been evicted
+        let dump_folder = tempfile::tempdir().unwrap();
+        run(dump_folder.path(), &["--utxo-window", "9"]);
+        let csv = fs::read_to_string(dump_folder.path().join("spendlatency-1-11.csv")).unwrap();
+        assert_eq!(csv, "created_height;spent_height;blocks_to_spend\n");
+        let histogram =
+            fs::read_to_string(dump_folder.path().join("spendlatency-histogram-1-11.csv")).unwrap();
+        // The coinbases of the blocks 2 to 11 and the new output
+        assert_eq!(histogram.lines().last().unwrap(), "unspent;11");
+    }
+}
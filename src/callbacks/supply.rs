@@ -0,0 +1,299 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use clap::{Arg, ArgMatches, Command};
+
+use crate::blockchain::parser::types::CoinType;
+use crate::blockchain::proto::block::Block;
+use crate::blockchain::proto::script::ScriptPattern;
+use crate::blockchain::proto::tx::{OutpointKey, TxOutpoint};
+use crate::callbacks::{common, Callback, Control};
+use crate::common::utils;
+use crate::errors::OpResult;
+
+/// Tracks the issued supply according to the subsidy schedule of the coin
+/// and the actual spendable supply per block
+pub struct Supply {
+    coin: CoinType,
+    dump_folder: PathBuf,
+    writer: common::DumpWriter,
+
+    // key: txid + index, value: output value
+    unspents: HashMap<OutpointKey, u64>,
+
+    start_height: u64,
+    /// Sum of all block subsidies according to the schedule
+    issued: u64,
+    /// Value sent to OP_RETURN or otherwise provably unspendable outputs
+    burned: u64,
+    /// Coinbase outputs which can never be spent: the genesis coinbase
+    /// and coinbases overwritten by a duplicate txid (BIP30)
+    unspendable_coinbase: u64,
+    /// Subsidy which has not been claimed by miners
+    unclaimed: u64,
+}
+
+impl Supply {
+    /// Returns the supply which can still be spent
+    fn spendable(&self) -> u64 {
+        self.issued - self.burned - self.unspendable_coinbase - self.unclaimed
+    }
+
+    fn is_burned(pattern: &ScriptPattern) -> bool {
+        matches!(
+            pattern,
+            ScriptPattern::OpReturn(_) | ScriptPattern::Unspendable
+        )
+    }
+}
+
+impl Callback for Supply {
+    fn build_subcommand() -> Command
+    where
+        Self: Sized,
+    {
+        Command::new("supply")
+            .about("Dumps the issued and the spendable supply per block to CSV file")
+            .version("0.1")
+            .author("gcarq <egger.m@protonmail.com>")
+            .arg(
+                Arg::new("dump-folder")
+                    .help("Folder to store csv file")
+                    .index(1)
+                    .required(true),
+            )
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let dump_folder = &PathBuf::from(matches.get_one::<String>("dump-folder").unwrap());
+        let cb = Supply {
+            coin: common::get_coin(matches),
+            dump_folder: PathBuf::from(dump_folder),
+            writer: common::create_writer(dump_folder.join("supply.csv.tmp"))?,
+            unspents: HashMap::with_capacity(10000000),
+            start_height: 0,
+            issued: 0,
+            burned: 0,
+            unspendable_coinbase: 0,
+            unclaimed: 0,
+        };
+        Ok(cb)
+    }
+
+    fn on_start(&mut self, block_height: u64) -> OpResult<()> {
+        self.start_height = block_height;
+        if block_height > 0 {
+            warn!(target: "callback", "Supply is only accurate if parsing starts at the genesis block!");
+        }
+        info!(target: "callback", "Executing supply with dump folder: {} ...", &self.dump_folder.display());
+        self.writer.write_all(
+            format!(
+                "{};{};{};{};{};{};{};{}\n",
+                "height",
+                "subsidy",
+                "issued",
+                "burned",
+                "unspendableCoinbase",
+                "unclaimed",
+                "spendable",
+                "delta"
+            )
+            .as_bytes(),
+        )?;
+        Ok(())
+    }
+
+    /// For each transaction in the block
+    ///   1. look up the spent values to calculate the fees
+    ///   2. track new outputs, burned outputs are accounted right away
+    ///
+    /// The coinbase can claim subsidy + fees, everything below is never issued.
+    fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<Control> {
+        let subsidy = self
+            .coin
+            .block_subsidy(block_height, &block.header.value.prev_hash);
+        self.issued += subsidy;
+
+        let mut fees = 0;
+        let mut coinbase_value = 0;
+        for tx in &block.txs {
+            let is_coinbase = tx.value.is_coinbase();
+            let mut in_value = 0;
+            if !is_coinbase {
+                for input in &tx.value.inputs {
+                    in_value += self
+                        .unspents
+                        .remove(&input.outpoint.key())
+                        .unwrap_or_default();
+                }
+            }
+
+            let mut out_value = 0;
+            for (i, output) in tx.value.outputs.iter().enumerate() {
+                let value = output.out.value;
+                out_value += value;
+                if Supply::is_burned(&output.script.pattern) {
+                    self.burned += value;
+                    continue;
+                }
+                // The genesis coinbase is not part of the UTXO set
+                if is_coinbase && block_height == 0 {
+                    self.unspendable_coinbase += value;
+                    continue;
+                }
+                let key = TxOutpoint::new(tx.hash, i as u32).key();
+                if let Some(overwritten) = self.unspents.insert(key, value) {
+                    debug!(target: "callback", "Duplicate txid {} at height {} (BIP30)", &tx.hash, block_height);
+                    self.unspendable_coinbase += overwritten;
+                }
+            }
+
+            if is_coinbase {
+                coinbase_value = out_value;
+            } else {
+                fees += in_value.saturating_sub(out_value);
+            }
+        }
+        self.unclaimed += (subsidy + fees).saturating_sub(coinbase_value);
+
+        let spendable = self.spendable();
+        self.writer.write_all(
+            format!(
+                "{};{};{};{};{};{};{};{}\n",
+                block_height,
+                subsidy,
+                self.issued,
+                self.burned,
+                self.unspendable_coinbase,
+                self.unclaimed,
+                spendable,
+                self.issued - spendable
+            )
+            .as_bytes(),
+        )?;
+
+        if self.coin.subsidy_schedule.is_reduction(block_height + 1) {
+            info!(target: "callback", "Supply before halving at height {}: issued {:.8}, spendable {:.8}, delta {:.8}",
+                  block_height + 1, self.issued as f64 * 1E-8, spendable as f64 * 1E-8,
+                  (self.issued - spendable) as f64 * 1E-8);
+        }
+        Ok(Control::Continue)
+    }
+
+    fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+        self.writer.flush()?;
+        utils::rename(
+            self.dump_folder.as_path().join("supply.csv.tmp"),
+            utils::output_path(
+                &self.dump_folder,
+                "supply",
+                self.start_height,
+                block_height,
+                "csv",
+            ),
+        )?;
+
+        let spendable = self.spendable();
+        info!(target: "callback", "Done.\nSupply at height {}:\n\
+                                   \t-> issued:               {:.8}\n\
+                                   \t-> burned:               {:.8}\n\
+                                   \t-> unspendable coinbase: {:.8}\n\
+                                   \t-> unclaimed:            {:.8}\n\
+                                   \t-> spendable:            {:.8}\n\
+                                   \t-> delta:                {:.8}",
+             block_height, self.issued as f64 * 1E-8, self.burned as f64 * 1E-8,
+             self.unspendable_coinbase as f64 * 1E-8, self.unclaimed as f64 * 1E-8,
+             spendable as f64 * 1E-8, (self.issued - spendable) as f64 * 1E-8);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> OpResult<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    fn dump_folder(&self) -> Option<&Path> {
+        Some(&self.dump_folder)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::proto::tx::RawTx;
+    use crate::common::testutils;
+    use bitcoin::hashes::{sha256d, Hash};
+    use std::fs;
+
+    fn push_block(blocks: &mut Vec<Block>, txs: Vec<RawTx>) {
+        let prev_hash = blocks
+            .last()
+            .map_or(sha256d::Hash::all_zeros(), |b| b.header.hash);
+        let timestamp = blocks.len() as u32 * 600;
+        blocks.push(testutils::block(prev_hash, timestamp, txs));
+    }
+
+    #[test]
+    fn test_supply() {
+        let mut blocks = Vec::new();
+        for height in 0..3 {
+            let script = testutils::p2pkh_script(height as u8);
+            push_block(
+                &mut blocks,
+                vec![testutils::coinbase(height, &[(5000000000, script)])],
+            );
+        }
+        // Burns 1 BTC and pays 1 BTC fee, which is only partially claimed
+        let spend = testutils::tx(
+            &[(blocks[2].txs[0].hash, 0)],
+            &[
+                (4800000000, testutils::p2pkh_script(3)),
+                (100000000, vec![0x6a, 0x01, 0x00]),
+            ],
+        );
+        push_block(
+            &mut blocks,
+            vec![
+                testutils::coinbase(3, &[(5050000000, testutils::p2pkh_script(4))]),
+                spend,
+            ],
+        );
+        // Duplicates the still unspent coinbase of block 1 (BIP30)
+        push_block(
+            &mut blocks,
+            vec![testutils::coinbase(
+                1,
+                &[(5000000000, testutils::p2pkh_script(1))],
+            )],
+        );
+
+        let dump_folder = tempfile::tempdir().unwrap();
+        let matches = Supply::build_subcommand()
+            .get_matches_from(["supply", dump_folder.path().to_str().unwrap()]);
+        let mut cb = Supply::new(&matches).unwrap();
+        cb.on_start(0).unwrap();
+        for (height, block) in blocks.iter().enumerate() {
+            cb.on_block(block, height as u64).unwrap();
+        }
+        cb.on_complete(4).unwrap();
+
+        assert_eq!(cb.issued, 5 * 5000000000);
+        assert_eq!(cb.burned, 100000000);
+        // Genesis coinbase + the overwritten coinbase of block 1
+        assert_eq!(cb.unspendable_coinbase, 2 * 5000000000);
+        assert_eq!(cb.unclaimed, 50000000);
+        // Spendable supply must match the UTXO set
+        assert_eq!(cb.spendable(), 14850000000);
+        assert_eq!(cb.spendable(), cb.unspents.values().sum::<u64>());
+
+        let csv = fs::read_to_string(dump_folder.path().join("supply-0-4.csv")).unwrap();
+        assert_eq!(
+            csv.lines().nth(4).unwrap(),
+            "3;5000000000;20000000000;100000000;5000000000;50000000;14850000000;5150000000"
+        );
+    }
+}
@@ -0,0 +1,190 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use chrono::{Datelike, NaiveDateTime};
+use clap::{Arg, ArgMatches, Command};
+use serde_json::{json, Value};
+
+use crate::blockchain::proto::block::Block;
+use crate::blockchain::proto::tx::OutpointKey;
+use crate::callbacks::{common, Callback, Control};
+use crate::common::utils;
+use crate::errors::OpResult;
+
+/// Dumps the value of the UTXO set at the last parsed block grouped by the calendar year (UTC)
+/// in which each UTXO was created, i.e. the year its coins were last moved.
+/// Like HODL waves, but with fixed epochs instead of age bands relative to the last block.
+pub struct SupplyYears {
+    dump_folder: PathBuf,
+
+    // key: txid + index
+    unspents: HashMap<OutpointKey, common::UnspentValue>,
+    // Block timestamp by height, used to convert the creation height of UTXOs to a year
+    timestamps: HashMap<u64, u32>,
+
+    start_height: u64,
+    total_value: u64,
+}
+
+impl SupplyYears {
+    fn year(timestamp: u32) -> i32 {
+        NaiveDateTime::from_timestamp_opt(timestamp as i64, 0)
+            .unwrap_or_default()
+            .year()
+    }
+
+    /// Sums up the value of all UTXOs per creation year
+    fn year_values(&self) -> BTreeMap<i32, u64> {
+        let mut values = BTreeMap::new();
+        for unspent in self.unspents.values() {
+            if let Some(timestamp) = self.timestamps.get(&unspent.block_height) {
+                *values.entry(SupplyYears::year(*timestamp)).or_insert(0) += unspent.value;
+            }
+        }
+        values
+    }
+}
+
+impl Callback for SupplyYears {
+    fn build_subcommand() -> Command
+    where
+        Self: Sized,
+    {
+        Command::new("supplyyears")
+            .about(
+                "Dumps the value of the UTXO set per year in which it was last moved to CSV file",
+            )
+            .version("0.1")
+            .author("gcarq <egger.m@protonmail.com>")
+            .arg(
+                Arg::new("dump-folder")
+                    .help("Folder to store csv file")
+                    .index(1)
+                    .required(true),
+            )
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let cb = SupplyYears {
+            dump_folder: PathBuf::from(matches.get_one::<String>("dump-folder").unwrap()),
+            unspents: HashMap::with_capacity(10000000),
+            timestamps: HashMap::with_capacity(1000000),
+            start_height: 0,
+            total_value: 0,
+        };
+        Ok(cb)
+    }
+
+    fn on_start(&mut self, block_height: u64) -> OpResult<()> {
+        self.start_height = block_height;
+        info!(target: "callback", "Executing supplyyears with dump folder: {} ...", &self.dump_folder.display());
+        Ok(())
+    }
+
+    fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<Control> {
+        self.timestamps
+            .insert(block_height, block.header.value.timestamp);
+        for tx in &block.txs {
+            common::remove_unspents(tx, &mut self.unspents);
+            common::insert_unspents(tx, block_height, &mut self.unspents);
+        }
+        Ok(Control::Continue)
+    }
+
+    /// Only years with unspent value are written
+    fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+        let values = self.year_values();
+        self.total_value = values.values().sum();
+
+        let tmp_path = self.dump_folder.join("supplyyears.csv.tmp");
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        writer.write_all(
+            format!("{};{};{}\n", "year", "supply_value", "supply_fraction").as_bytes(),
+        )?;
+        for (year, value) in &values {
+            let fraction = *value as f64 / self.total_value as f64;
+            writer.write_all(format!("{};{};{:.6}\n", year, value, fraction).as_bytes())?;
+        }
+        writer.flush()?;
+        drop(writer);
+        utils::rename(
+            tmp_path,
+            utils::output_path(
+                &self.dump_folder,
+                "supplyyears",
+                self.start_height,
+                block_height,
+                "csv",
+            ),
+        )?;
+
+        info!(target: "callback", "Done.\nDumped {:.8} unspent value created in {} years at height {}.",
+             self.total_value as f64 * 1E-8, values.len(), block_height);
+        Ok(())
+    }
+
+    fn dump_folder(&self) -> Option<&Path> {
+        Some(&self.dump_folder)
+    }
+
+    fn summary(&self) -> Value {
+        json!({ "supply_value": self.total_value })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::testutils;
+    use bitcoin::hashes::{sha256d, Hash};
+    use std::fs;
+
+    #[test]
+    fn test_supplyyears() {
+        // 2015-06-01 and 2017-03-01
+        let block1 = testutils::block(
+            sha256d::Hash::all_zeros(),
+            1433116800,
+            vec![testutils::coinbase(
+                1,
+                &[
+                    (1000, testutils::p2pkh_script(1)),
+                    (2000, testutils::p2pkh_script(2)),
+                ],
+            )],
+        );
+        // Moves the output of address 2, which then counts for 2017
+        let spend = testutils::tx(
+            &[(block1.txs[0].hash, 1)],
+            &[(2000, testutils::p2pkh_script(3))],
+        );
+        let block2 = testutils::block(
+            block1.header.hash,
+            1488326400,
+            vec![
+                testutils::coinbase(2, &[(1000, testutils::p2pkh_script(0))]),
+                spend,
+            ],
+        );
+
+        let dump_folder = tempfile::tempdir().unwrap();
+        let matches = SupplyYears::build_subcommand()
+            .get_matches_from(["supplyyears", dump_folder.path().to_str().unwrap()]);
+        let mut cb = SupplyYears::new(&matches).unwrap();
+        cb.on_start(1).unwrap();
+        cb.on_block(&block1, 1).unwrap();
+        cb.on_block(&block2, 2).unwrap();
+        cb.on_complete(2).unwrap();
+
+        let csv = fs::read_to_string(dump_folder.path().join("supplyyears-1-2.csv")).unwrap();
+        assert_eq!(
+            csv.lines().skip(1).collect::<Vec<_>>(),
+            vec!["2015;1000;0.250000", "2017;3000;0.750000"]
+        );
+    }
+}
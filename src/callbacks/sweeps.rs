@@ -0,0 +1,267 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use clap::{Arg, ArgMatches, Command};
+use serde_json::{json, Value};
+
+use crate::blockchain::proto::block::Block;
+use crate::blockchain::proto::tx::OutpointKey;
+use crate::callbacks::{common, Callback, Control};
+use crate::common::utils;
+use crate::errors::OpResult;
+
+/// Dumps the transactions which spend the whole balance of an address.
+/// Addresses which receive coins again within the same transaction are not swept.
+pub struct Sweeps {
+    dump_folder: PathBuf,
+    writer: common::DumpWriter,
+
+    // key: txid + index
+    unspents: HashMap<OutpointKey, common::UnspentValue>,
+    // Addresses with non-zero balance
+    balances: HashMap<String, u64>,
+
+    start_height: u64,
+    n_sweeps: u64,
+    swept_value: u64,
+}
+
+impl Sweeps {
+    /// Deducts the value from the balance of the address and returns the remaining balance
+    fn debit(&mut self, address: &str, value: u64) -> u64 {
+        match self.balances.get_mut(address) {
+            Some(balance) => {
+                *balance = balance.saturating_sub(value);
+                *balance
+            }
+            None => 0,
+        }
+    }
+}
+
+impl Callback for Sweeps {
+    fn build_subcommand() -> Command
+    where
+        Self: Sized,
+    {
+        Command::new("sweeps")
+            .about("Dumps transactions spending the whole balance of an address to CSV file")
+            .version("0.1")
+            .author("gcarq <egger.m@protonmail.com>")
+            .arg(
+                Arg::new("dump-folder")
+                    .help("Folder to store csv file")
+                    .index(1)
+                    .required(true),
+            )
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let dump_folder = &PathBuf::from(matches.get_one::<String>("dump-folder").unwrap());
+        let cb = Sweeps {
+            dump_folder: PathBuf::from(dump_folder),
+            writer: common::create_writer(dump_folder.join("sweeps.csv.tmp"))?,
+            unspents: HashMap::with_capacity(10000000),
+            balances: HashMap::with_capacity(10000000),
+            start_height: 0,
+            n_sweeps: 0,
+            swept_value: 0,
+        };
+        Ok(cb)
+    }
+
+    fn on_start(&mut self, block_height: u64) -> OpResult<()> {
+        self.start_height = block_height;
+        info!(target: "callback", "Executing sweeps with dump folder: {} ...", &self.dump_folder.display());
+        self.writer.write_all(
+            format!(
+                "{};{};{};{}\n",
+                "height", "txid", "swept_address", "swept_value"
+            )
+            .as_bytes(),
+        )?;
+        Ok(())
+    }
+
+    fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<Control> {
+        for tx in &block.txs {
+            // Value spent per address, in the order of the inputs
+            let mut spent: Vec<(String, u64)> = Vec::new();
+            for unspent in common::take_unspents(tx, &mut self.unspents) {
+                if unspent.address.is_empty() {
+                    continue;
+                }
+                let address = unspent.address.to_string();
+                match spent.iter_mut().find(|(a, _)| *a == address) {
+                    Some((_, value)) => *value += unspent.value,
+                    None => spent.push((address, unspent.value)),
+                }
+            }
+            for output in &tx.value.outputs {
+                if let Some(address) = &output.script.address {
+                    *self.balances.entry(address.clone()).or_insert(0) += output.out.value;
+                }
+            }
+
+            // The outputs are credited first, so change to a spent address keeps it non-zero
+            for (address, value) in spent {
+                if self.debit(&address, value) > 0 {
+                    continue;
+                }
+                self.writer.write_all(
+                    format!(
+                        "{};{};{};{}\n",
+                        block_height,
+                        utils::hash_to_string(&tx.hash),
+                        address,
+                        value
+                    )
+                    .as_bytes(),
+                )?;
+                self.balances.remove(&address);
+                self.n_sweeps += 1;
+                self.swept_value += value;
+            }
+            common::insert_unspents(tx, block_height, &mut self.unspents);
+        }
+        Ok(Control::Continue)
+    }
+
+    fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+        self.writer.flush()?;
+        utils::rename(
+            self.dump_folder.as_path().join("sweeps.csv.tmp"),
+            utils::output_path(
+                &self.dump_folder,
+                "sweeps",
+                self.start_height,
+                block_height,
+                "csv",
+            ),
+        )?;
+
+        info!(target: "callback", "Done.\nFound {} sweeps with {} swept from height {} to {}.",
+             self.n_sweeps, self.swept_value, self.start_height, block_height);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> OpResult<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    fn dump_folder(&self) -> Option<&Path> {
+        Some(&self.dump_folder)
+    }
+
+    fn supports_incremental(&self) -> bool {
+        true
+    }
+
+    fn summary(&self) -> Value {
+        json!({ "sweeps": self.n_sweeps, "swept_value": self.swept_value })
+    }
+
+    fn save_state(&self, folder: &Path) -> OpResult<()> {
+        common::save_unspents(&folder.join("sweeps.unspents"), &self.unspents)
+    }
+
+    /// The balances are derived from the restored unspents
+    fn load_state(&mut self, folder: &Path) -> OpResult<()> {
+        self.unspents = common::load_unspents(&folder.join("sweeps.unspents"))?;
+        self.balances.clear();
+        for unspent in self.unspents.values() {
+            if !unspent.address.is_empty() {
+                *self
+                    .balances
+                    .entry(unspent.address.to_string())
+                    .or_insert(0) += unspent.value;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::testutils;
+    use bitcoin::hashes::{sha256d, Hash};
+    use std::fs;
+
+    #[test]
+    fn test_sweeps() {
+        // Address 1 receives two outputs, address 2 one
+        let block1 = testutils::block(
+            sha256d::Hash::all_zeros(),
+            0,
+            vec![testutils::coinbase(
+                1,
+                &[
+                    (3000, testutils::p2pkh_script(1)),
+                    (2000, testutils::p2pkh_script(1)),
+                    (4000, testutils::p2pkh_script(2)),
+                ],
+            )],
+        );
+        let coinbase = block1.txs[0].hash;
+        let block2 = testutils::block(
+            block1.header.hash,
+            600,
+            vec![
+                testutils::coinbase(2, &[(5000, testutils::p2pkh_script(0))]),
+                // Spends both outputs of address 1
+                testutils::tx(
+                    &[(coinbase, 0), (coinbase, 1)],
+                    &[(4900, testutils::p2pkh_script(3))],
+                ),
+                // Address 2 receives its change, so it is not swept
+                testutils::tx(
+                    &[(coinbase, 2)],
+                    &[
+                        (1000, testutils::p2pkh_script(3)),
+                        (2900, testutils::p2pkh_script(2)),
+                    ],
+                ),
+            ],
+        );
+
+        let dump_folder = tempfile::tempdir().unwrap();
+        let matches = Sweeps::build_subcommand()
+            .get_matches_from(["sweeps", dump_folder.path().to_str().unwrap()]);
+        let mut cb = Sweeps::new(&matches).unwrap();
+        cb.on_start(1).unwrap();
+        cb.on_block(&block1, 1).unwrap();
+        cb.on_block(&block2, 2).unwrap();
+        cb.on_complete(2).unwrap();
+
+        let csv = fs::read_to_string(dump_folder.path().join("sweeps-1-2.csv")).unwrap();
+        let rows: Vec<&str> = csv.lines().skip(1).collect();
+        let address = block1.txs[0].value.outputs[0]
+            .script
+            .address
+            .clone()
+            .unwrap();
+        assert_eq!(
+            rows,
+            vec![format!(
+                "2;{};{};5000",
+                utils::hash_to_string(&block2.txs[1].hash),
+                address
+            )]
+        );
+        assert_eq!(cb.balances.get(&address), None);
+        assert_eq!(
+            cb.balances[&block1.txs[0].value.outputs[2]
+                .script
+                .address
+                .clone()
+                .unwrap()],
+            2900
+        );
+    }
+}
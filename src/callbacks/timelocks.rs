@@ -0,0 +1,229 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use clap::{Arg, ArgMatches, Command};
+
+use crate::blockchain::proto::block::Block;
+use crate::blockchain::proto::script::timelock::{self, TimelockKind};
+use crate::callbacks::{common, Callback, Control};
+use crate::common::utils;
+use crate::errors::OpResult;
+
+/// Counts OP_CHECKLOCKTIMEVERIFY and OP_CHECKSEQUENCEVERIFY usage in scriptPubKeys
+/// and revealed redeem/witness scripts
+pub struct Timelocks {
+    dump_folder: PathBuf,
+    block_writer: common::DumpWriter,
+    spend_writer: common::DumpWriter,
+
+    start_height: u64,
+    n_cltv_outputs: u64,
+    n_csv_outputs: u64,
+    n_cltv_spends: u64,
+    n_csv_spends: u64,
+}
+
+impl Callback for Timelocks {
+    fn build_subcommand() -> Command
+    where
+        Self: Sized,
+    {
+        Command::new("timelocks")
+            .about("Dumps CLTV/CSV usage per block and the locktimes of timelocked spends to CSV files")
+            .version("0.1")
+            .author("gcarq <egger.m@protonmail.com>")
+            .arg(
+                Arg::new("dump-folder")
+                    .help("Folder to store csv files")
+                    .index(1)
+                    .required(true),
+            )
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let dump_folder = &PathBuf::from(matches.get_one::<String>("dump-folder").unwrap());
+        let cb = Timelocks {
+            dump_folder: PathBuf::from(dump_folder),
+            block_writer: common::create_writer(dump_folder.join("timelocks.csv.tmp"))?,
+            spend_writer: common::create_writer(dump_folder.join("timelock_spends.csv.tmp"))?,
+            start_height: 0,
+            n_cltv_outputs: 0,
+            n_csv_outputs: 0,
+            n_cltv_spends: 0,
+            n_csv_spends: 0,
+        };
+        Ok(cb)
+    }
+
+    fn on_start(&mut self, block_height: u64) -> OpResult<()> {
+        self.start_height = block_height;
+        info!(target: "callback", "Executing timelocks with dump folder: {} ...", &self.dump_folder.display());
+        self.block_writer.write_all(
+            format!(
+                "{};{};{};{};{}\n",
+                "height", "cltvOutputs", "csvOutputs", "cltvSpends", "csvSpends"
+            )
+            .as_bytes(),
+        )?;
+        self.spend_writer.write_all(
+            format!(
+                "{};{};{};{};{};{};{}\n",
+                "height", "txid", "indexIn", "opcode", "scriptValue", "lockTime", "sequence"
+            )
+            .as_bytes(),
+        )?;
+        Ok(())
+    }
+
+    fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<Control> {
+        let (mut cltv_outputs, mut csv_outputs, mut cltv_spends, mut csv_spends) = (0, 0, 0, 0);
+        for tx in &block.txs {
+            for output in &tx.value.outputs {
+                for lock in timelock::find_timelocks(&output.out.script_pubkey) {
+                    match lock.kind {
+                        TimelockKind::Absolute => cltv_outputs += 1,
+                        TimelockKind::Relative => csv_outputs += 1,
+                    }
+                }
+            }
+
+            if tx.value.is_coinbase() {
+                continue;
+            }
+            let txid = utils::hash_to_string(&tx.hash);
+            for (i, input) in tx.value.inputs.iter().enumerate() {
+                for script in timelock::revealed_scripts(input) {
+                    for lock in timelock::find_timelocks(script) {
+                        match lock.kind {
+                            TimelockKind::Absolute => cltv_spends += 1,
+                            TimelockKind::Relative => csv_spends += 1,
+                        }
+                        self.spend_writer.write_all(
+                            format!(
+                                "{};{};{};{};{};{};{}\n",
+                                block_height,
+                                txid,
+                                i,
+                                lock.kind,
+                                lock.value.map(|v| v.to_string()).unwrap_or_default(),
+                                tx.value.locktime,
+                                input.seq_no
+                            )
+                            .as_bytes(),
+                        )?;
+                    }
+                }
+            }
+        }
+        self.block_writer.write_all(
+            format!(
+                "{};{};{};{};{}\n",
+                block_height, cltv_outputs, csv_outputs, cltv_spends, csv_spends
+            )
+            .as_bytes(),
+        )?;
+        self.n_cltv_outputs += cltv_outputs;
+        self.n_csv_outputs += csv_outputs;
+        self.n_cltv_spends += cltv_spends;
+        self.n_csv_spends += csv_spends;
+        Ok(Control::Continue)
+    }
+
+    fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+        self.block_writer.flush()?;
+        self.spend_writer.flush()?;
+        // Keep in sync with c'tor
+        for f in ["timelocks", "timelock_spends"] {
+            utils::rename(
+                self.dump_folder.as_path().join(format!("{}.csv.tmp", f)),
+                utils::output_path(&self.dump_folder, f, self.start_height, block_height, "csv"),
+            )?;
+        }
+
+        info!(target: "callback", "Done.\nScanned blocks from height {} to {}:\n\
+                                   \t-> CLTV outputs: {:9}\n\
+                                   \t-> CSV outputs:  {:9}\n\
+                                   \t-> CLTV spends:  {:9}\n\
+                                   \t-> CSV spends:   {:9}",
+             self.start_height, block_height, self.n_cltv_outputs, self.n_csv_outputs,
+             self.n_cltv_spends, self.n_csv_spends);
+        Ok(())
+    }
+
+    fn supports_reverse(&self) -> bool {
+        true
+    }
+
+    fn flush(&mut self) -> OpResult<()> {
+        self.block_writer.flush()?;
+        self.spend_writer.flush()?;
+        Ok(())
+    }
+
+    fn dump_folder(&self) -> Option<&Path> {
+        Some(&self.dump_folder)
+    }
+
+    fn supports_incremental(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::proto::varuint::VarUint;
+    use crate::common::testutils;
+    use bitcoin::hashes::{sha256d, Hash};
+    use std::fs;
+
+    #[test]
+    fn test_timelocks_cltv_spend() {
+        // 500000 OP_CHECKLOCKTIMEVERIFY OP_DROP OP_TRUE
+        let redeem_script = vec![0x03, 0x20, 0xa1, 0x07, 0xb1, 0x75, 0x51];
+        let mut script_sig = vec![redeem_script.len() as u8];
+        script_sig.extend(&redeem_script);
+        let mut spend = testutils::tx(
+            &[(sha256d::Hash::hash(&[0x01]), 0)],
+            &[(1000, testutils::p2pkh_script(1))],
+        );
+        spend.inputs[0].script_len = VarUint::from(script_sig.len() as u8);
+        spend.inputs[0].script_sig = script_sig;
+        spend.locktime = 500000;
+        let bare = testutils::tx(
+            &[(sha256d::Hash::hash(&[0x02]), 0)],
+            &[(1000, vec![0x60, 0xb2, 0x75, 0x51])],
+        );
+        let block = testutils::block(
+            sha256d::Hash::all_zeros(),
+            0,
+            vec![testutils::coinbase(1, &[(50, vec![0x51])]), spend, bare],
+        );
+
+        let dump_folder = tempfile::tempdir().unwrap();
+        let matches = Timelocks::build_subcommand()
+            .get_matches_from(["timelocks", dump_folder.path().to_str().unwrap()]);
+        let mut cb = Timelocks::new(&matches).unwrap();
+        cb.on_start(1).unwrap();
+        cb.on_block(&block, 1).unwrap();
+        cb.on_complete(1).unwrap();
+        assert_eq!(cb.n_cltv_spends, 1);
+        assert_eq!(cb.n_csv_outputs, 1);
+
+        let spends =
+            fs::read_to_string(dump_folder.path().join("timelock_spends-1-1.csv")).unwrap();
+        let row = spends.lines().nth(1).unwrap();
+        assert_eq!(
+            row,
+            format!(
+                "1;{};0;OP_CHECKLOCKTIMEVERIFY;500000;500000;4294967295",
+                block.txs[1].hash
+            )
+        );
+        let blocks = fs::read_to_string(dump_folder.path().join("timelocks-1-1.csv")).unwrap();
+        assert_eq!(blocks.lines().nth(1).unwrap(), "1;0;1;1;0");
+    }
+}
@@ -0,0 +1,417 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use bitcoin::hashes::sha256d;
+use clap::{Arg, ArgMatches, Command};
+use serde_json::{json, Value};
+
+use crate::blockchain::proto::block::Block;
+use crate::blockchain::proto::tx::{OutpointKey, TxOutpoint};
+use crate::callbacks::{common, Callback, Control};
+use crate::common::utils;
+use crate::errors::OpResult;
+
+/// Layout of the exported graph
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum GraphFormat {
+    /// One csv row per spent output
+    Edges,
+    /// Node and relationship files for `neo4j-admin database import`
+    Neo4j,
+    /// Graphviz digraph, only useful for small ranges
+    Dot,
+}
+
+/// Output funding a later transaction
+struct FundingOutput {
+    value: u64,
+    height: u32,
+}
+
+/// Exports the transaction graph: each input spending an output of an earlier
+/// transaction is an edge from the funding to the spending transaction.
+pub struct TxGraph {
+    dump_folder: PathBuf,
+    format: GraphFormat,
+    // Edges (and with neo4j nodes) files
+    writers: Vec<BufWriter<File>>,
+
+    // key: txid + index
+    unspents: HashMap<OutpointKey, FundingOutput>,
+    // Edges spending less are skipped
+    min_value: u64,
+    // Only edges of transactions which spend or create a watched output are exported
+    watchlist: Option<common::Watchlist>,
+    // Transactions already written to the nodes file of neo4j
+    nodes: HashSet<sha256d::Hash>,
+
+    start_height: u64,
+    n_edges: u64,
+}
+
+impl TxGraph {
+    /// Names of the files written for the format, without extension
+    fn file_names(&self) -> &'static [&'static str] {
+        match self.format {
+            GraphFormat::Edges => &["txgraph"],
+            GraphFormat::Neo4j => &["txgraph-nodes", "txgraph-relationships"],
+            GraphFormat::Dot => &["txgraph"],
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match self.format {
+            GraphFormat::Dot => "dot",
+            _ => "csv",
+        }
+    }
+
+    /// Writes the transaction to the neo4j nodes file unless it is there already
+    fn write_node(&mut self, txid: &sha256d::Hash, height: u64) -> OpResult<()> {
+        if self.nodes.insert(*txid) {
+            self.writers[0].write_all(
+                format!("{},{},Transaction\n", utils::hash_to_string(txid), height).as_bytes(),
+            )?;
+        }
+        Ok(())
+    }
+
+    fn write_edge(
+        &mut self,
+        spender: &sha256d::Hash,
+        block_height: u64,
+        funding: &sha256d::Hash,
+        vout: u32,
+        output: &FundingOutput,
+    ) -> OpResult<()> {
+        let (spender_hex, funding_hex) = (
+            utils::hash_to_string(spender),
+            utils::hash_to_string(funding),
+        );
+        match self.format {
+            GraphFormat::Edges => self.writers[0].write_all(
+                format!(
+                    "{};{};{};{}\n",
+                    spender_hex, funding_hex, vout, output.value
+                )
+                .as_bytes(),
+            )?,
+            GraphFormat::Neo4j => {
+                self.write_node(funding, output.height as u64)?;
+                self.write_node(spender, block_height)?;
+                self.writers[1].write_all(
+                    format!(
+                        "{},{},{},{},FUNDS\n",
+                        funding_hex, spender_hex, vout, output.value
+                    )
+                    .as_bytes(),
+                )?;
+            }
+            GraphFormat::Dot => self.writers[0].write_all(
+                format!(
+                    "  \"{}\" -> \"{}\" [label=\"{}:{}\"];\n",
+                    funding_hex, spender_hex, vout, output.value
+                )
+                .as_bytes(),
+            )?,
+        }
+        self.n_edges += 1;
+        Ok(())
+    }
+}
+
+impl Callback for TxGraph {
+    fn build_subcommand() -> Command
+    where
+        Self: Sized,
+    {
+        Command::new("txgraph")
+            .about("Exports the transaction graph as edge list, Neo4j import CSV or Graphviz file")
+            .version("0.1")
+            .author("gcarq <egger.m@protonmail.com>")
+            .arg(
+                Arg::new("dump-folder")
+                    .help("Folder to store the graph files")
+                    .index(1)
+                    .required(true),
+            )
+            .arg(
+                Arg::new("graph-format")
+                    .long("graph-format")
+                    .value_parser(["edges", "neo4j", "dot"])
+                    .default_value("edges")
+                    .help("Writes an edge list, node and relationship files for neo4j-admin import or a Graphviz digraph"),
+            )
+            .arg(
+                Arg::new("min-value")
+                    .long("min-value")
+                    .value_name("SATOSHIS")
+                    .value_parser(clap::value_parser!(u64))
+                    .default_value("0")
+                    .help("Skips edges spending outputs below this value"),
+            )
+            .arg(common::watch_addresses_arg().help(
+                "Only exports the edges of transactions which spend or create an output of the addresses or sha256(scriptPubKey) hashes listed in FILE",
+            ))
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let dump_folder = &PathBuf::from(matches.get_one::<String>("dump-folder").unwrap());
+        let format = match matches
+            .get_one::<String>("graph-format")
+            .map(String::as_str)
+        {
+            Some("neo4j") => GraphFormat::Neo4j,
+            Some("dot") => GraphFormat::Dot,
+            _ => GraphFormat::Edges,
+        };
+        let mut cb = TxGraph {
+            dump_folder: PathBuf::from(dump_folder),
+            format,
+            writers: Vec::new(),
+            unspents: HashMap::with_capacity(10000000),
+            min_value: *matches.get_one::<u64>("min-value").unwrap(),
+            watchlist: common::Watchlist::from_matches(matches)?,
+            nodes: HashSet::new(),
+            start_height: 0,
+            n_edges: 0,
+        };
+        for name in cb.file_names() {
+            let path = dump_folder.join(format!("{}.{}.tmp", name, cb.extension()));
+            cb.writers
+                .push(BufWriter::with_capacity(4000000, File::create(path)?));
+        }
+        Ok(cb)
+    }
+
+    fn on_start(&mut self, block_height: u64) -> OpResult<()> {
+        self.start_height = block_height;
+        info!(target: "callback", "Executing txgraph with dump folder: {} ...", &self.dump_folder.display());
+        let headers: &[&str] = match self.format {
+            GraphFormat::Edges => &["spender_txid;funding_txid;vout;value\n"],
+            GraphFormat::Neo4j => &[
+                "txid:ID(Transaction),height:long,:LABEL\n",
+                ":START_ID(Transaction),:END_ID(Transaction),vout:int,value:long,:TYPE\n",
+            ],
+            GraphFormat::Dot => &["digraph txgraph {\n"],
+        };
+        for (writer, header) in self.writers.iter_mut().zip(headers) {
+            writer.write_all(header.as_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Inputs spending outputs below the start height are skipped, their value is unknown
+    fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<Control> {
+        for tx in &block.txs {
+            let exported = match self.watchlist.as_mut() {
+                Some(watchlist) => watchlist.touches(tx),
+                None => true,
+            };
+            for input in &tx.value.inputs {
+                let output = match self.unspents.remove(&input.outpoint.key()) {
+                    Some(output) => output,
+                    None => continue,
+                };
+                if exported && output.value >= self.min_value {
+                    let outpoint = &input.outpoint;
+                    self.write_edge(
+                        &tx.hash,
+                        block_height,
+                        &outpoint.txid,
+                        outpoint.index,
+                        &output,
+                    )?;
+                }
+            }
+            for (i, output) in tx.value.outputs.iter().enumerate() {
+                let funding = FundingOutput {
+                    value: output.out.value,
+                    height: block_height as u32,
+                };
+                self.unspents
+                    .insert(TxOutpoint::new(tx.hash, i as u32).key(), funding);
+            }
+        }
+        Ok(Control::Continue)
+    }
+
+    fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+        if self.format == GraphFormat::Dot {
+            self.writers[0].write_all(b"}\n")?;
+        }
+        for writer in &mut self.writers {
+            writer.flush()?;
+        }
+        let extension = self.extension();
+        for name in self.file_names() {
+            utils::rename(
+                self.dump_folder.join(format!("{}.{}.tmp", name, extension)),
+                utils::output_path(
+                    &self.dump_folder,
+                    name,
+                    self.start_height,
+                    block_height,
+                    extension,
+                ),
+            )?;
+        }
+        info!(target: "callback", "Done.\nExported {} edges from height {} to {}.",
+             self.n_edges, self.start_height, block_height);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> OpResult<()> {
+        for writer in &mut self.writers {
+            writer.flush()?;
+        }
+        Ok(())
+    }
+
+    fn dump_folder(&self) -> Option<&Path> {
+        Some(&self.dump_folder)
+    }
+
+    fn summary(&self) -> Value {
+        json!({ "edges": self.n_edges })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::testutils;
+    use bitcoin::hashes::Hash;
+    use std::fs;
+
+    /// Block 1 funds two outputs, block 2 spends both of them and block 3
+    /// spends the small change of block 2
+    fn blocks() -> Vec<Block> {
+        let block1 = testutils::block(
+            sha256d::Hash::all_zeros(),
+            0,
+            vec![testutils::coinbase(
+                1,
+                &[
+                    (5000, testutils::p2pkh_script(1)),
+                    (300, testutils::p2pkh_script(2)),
+                ],
+            )],
+        );
+        let funding = block1.txs[0].hash;
+        let spend = testutils::tx(
+            &[(funding, 0), (funding, 1)],
+            &[
+                (4000, testutils::p2pkh_script(3)),
+                (200, testutils::p2pkh_script(4)),
+            ],
+        );
+        let block2 = testutils::block(
+            block1.header.hash,
+            600,
+            vec![
+                testutils::coinbase(2, &[(5000, testutils::p2pkh_script(0))]),
+                spend,
+            ],
+        );
+        let change = testutils::tx(
+            &[(block2.txs[1].hash, 1)],
+            &[(100, testutils::p2pkh_script(5))],
+        );
+        let block3 = testutils::block(
+            block2.header.hash,
+            1200,
+            vec![
+                testutils::coinbase(3, &[(5000, testutils::p2pkh_script(0))]),
+                change,
+            ],
+        );
+        vec![block1, block2, block3]
+    }
+
+    fn run(args: &[&str]) -> (Vec<String>, Vec<Block>) {
+        let dump_folder = tempfile::tempdir().unwrap();
+        let matches = TxGraph::build_subcommand().get_matches_from(
+            ["txgraph", dump_folder.path().to_str().unwrap()]
+                .iter()
+                .chain(args),
+        );
+        let mut cb = TxGraph::new(&matches).unwrap();
+        cb.on_start(1).unwrap();
+        let blocks = blocks();
+        for (i, block) in blocks.iter().enumerate() {
+            cb.on_block(block, i as u64 + 1).unwrap();
+        }
+        cb.on_complete(3).unwrap();
+        let files = cb
+            .file_names()
+            .iter()
+            .map(|name| {
+                let path = dump_folder
+                    .path()
+                    .join(format!("{}-1-3.{}", name, cb.extension()));
+                fs::read_to_string(path).unwrap()
+            })
+            .collect();
+        (files, blocks)
+    }
+
+    #[test]
+    fn test_txgraph_edges() {
+        let (files, blocks) = run(&[]);
+        let coinbase = utils::hash_to_string(&blocks[0].txs[0].hash);
+        let spend = utils::hash_to_string(&blocks[1].txs[1].hash);
+        let change = utils::hash_to_string(&blocks[2].txs[1].hash);
+        assert_eq!(
+            files[0],
+            format!(
+                "spender_txid;funding_txid;vout;value\n\
+                 {spend};{coinbase};0;5000\n\
+                 {spend};{coinbase};1;300\n\
+                 {change};{spend};1;200\n"
+            )
+        );
+
+        let (files, _) = run(&["--min-value", "250"]);
+        assert_eq!(files[0].lines().count(), 3);
+
+        // Address 4 is paid by the spend of block 2 and spent by block 3
+        let dump_folder = tempfile::tempdir().unwrap();
+        let watchlist = dump_folder.path().join("watchlist.txt");
+        let address = blocks[1].txs[1].value.outputs[1].script.address.clone();
+        fs::write(&watchlist, address.unwrap()).unwrap();
+        let (files, _) = run(&["--watch-addresses", watchlist.to_str().unwrap()]);
+        assert_eq!(files[0].lines().count(), 4);
+    }
+
+    #[test]
+    fn test_txgraph_neo4j_and_dot() {
+        let (files, blocks) = run(&["--graph-format", "neo4j"]);
+        let coinbase = utils::hash_to_string(&blocks[0].txs[0].hash);
+        let spend = utils::hash_to_string(&blocks[1].txs[1].hash);
+        let change = utils::hash_to_string(&blocks[2].txs[1].hash);
+        assert_eq!(
+            files[0],
+            format!(
+                "txid:ID(Transaction),height:long,:LABEL\n\
+                 {coinbase},1,Transaction\n\
+                 {spend},2,Transaction\n\
+                 {change},3,Transaction\n"
+            )
+        );
+        assert_eq!(files[1].lines().count(), 4);
+        assert!(files[1].contains(&format!("{spend},{change},1,200,FUNDS\n")));
+
+        let (files, _) = run(&["--graph-format", "dot"]);
+        assert!(files[0].starts_with("digraph txgraph {\n"));
+        assert!(files[0].contains(&format!(
+            "  \"{spend}\" -> \"{change}\" [label=\"1:200\"];\n"
+        )));
+        assert!(files[0].ends_with("}\n"));
+    }
+}
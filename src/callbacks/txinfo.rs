@@ -0,0 +1,259 @@
+use std::fmt::Write;
+use std::str::FromStr;
+
+use bitcoin::hashes::sha256d;
+use bitcoin::Script;
+use clap::{Arg, ArgMatches, Command};
+use serde_json::{json, Value};
+
+use crate::blockchain::proto::block::Block;
+use crate::blockchain::proto::tx::EvaluatedTx;
+use crate::blockchain::proto::Hashed;
+use crate::callbacks::{Callback, Control};
+use crate::common::utils;
+use crate::errors::{OpError, OpErrorKind, OpResult};
+
+/// Prints the decoded structure of a single transaction to stdout, for debugging.
+/// With `--stop` the parser stops as soon as the transaction has been found.
+pub struct TxInfo {
+    txid: sha256d::Hash,
+    stop: bool,
+
+    // Height of the block containing the transaction, once found
+    found_height: Option<u64>,
+    dump: Option<String>,
+}
+
+impl TxInfo {
+    /// Renders the inputs with scriptSig and witness and the outputs with scriptPubKey, script type and address
+    fn describe(tx: &Hashed<EvaluatedTx>, block: &Block, block_height: u64) -> String {
+        let mut dump = String::new();
+        writeln!(
+            dump,
+            "Transaction {} in block {} ({})",
+            utils::hash_to_string(&tx.hash),
+            block_height,
+            utils::hash_to_string(&block.header.hash)
+        )
+        .unwrap();
+        writeln!(
+            dump,
+            "  version: {}, locktime: {}, size: {}, vsize: {}",
+            tx.value.version,
+            tx.value.locktime,
+            tx.value.size(),
+            tx.value.vsize()
+        )
+        .unwrap();
+        for (i, input) in tx.value.inputs.iter().enumerate() {
+            if tx.value.is_coinbase() {
+                writeln!(
+                    dump,
+                    "  input {}: coinbase, sequence: {:#010x}",
+                    i, input.seq_no
+                )
+                .unwrap();
+            } else {
+                writeln!(
+                    dump,
+                    "  input {}: {}:{}, sequence: {:#010x}",
+                    i,
+                    utils::hash_to_string(&input.outpoint.txid),
+                    input.outpoint.index,
+                    input.seq_no
+                )
+                .unwrap();
+            }
+            writeln!(
+                dump,
+                "    scriptSig: {}",
+                Script::from_bytes(&input.script_sig).to_asm_string()
+            )
+            .unwrap();
+            if !input.witness.is_empty() {
+                let items: Vec<String> =
+                    input.witness.iter().map(|w| utils::arr_to_hex(w)).collect();
+                writeln!(dump, "    witness: [{}]", items.join(", ")).unwrap();
+            }
+        }
+        for (i, output) in tx.value.outputs.iter().enumerate() {
+            writeln!(dump, "  output {}: {} sat", i, output.out.value).unwrap();
+            writeln!(
+                dump,
+                "    scriptPubKey: {}",
+                Script::from_bytes(&output.out.script_pubkey).to_asm_string()
+            )
+            .unwrap();
+            writeln!(
+                dump,
+                "    type: {}, address: {}",
+                output.script.pattern.short_name(),
+                output.script.address.as_deref().unwrap_or("-")
+            )
+            .unwrap();
+        }
+        dump
+    }
+}
+
+impl Callback for TxInfo {
+    fn build_subcommand() -> Command
+    where
+        Self: Sized,
+    {
+        Command::new("txinfo")
+            .about("Prints the decoded inputs and outputs of a single transaction")
+            .version("0.1")
+            .author("gcarq <egger.m@protonmail.com>")
+            .arg(
+                Arg::new("txid")
+                    .long("txid")
+                    .value_name("HEX")
+                    .required(true)
+                    .help("Id of the transaction (as displayed by block explorers)"),
+            )
+            .arg(
+                Arg::new("stop")
+                    .long("stop")
+                    .action(clap::ArgAction::SetTrue)
+                    .help("Stops parsing once the transaction has been found"),
+            )
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let txid = matches.get_one::<String>("txid").unwrap();
+        let txid = sha256d::Hash::from_str(txid).map_err(|_| {
+            OpError::new(OpErrorKind::InvalidArgsError)
+                .join_msg(&format!("Invalid txid `{}`!", txid))
+        })?;
+        let cb = TxInfo {
+            txid,
+            stop: matches.get_flag("stop"),
+            found_height: None,
+            dump: None,
+        };
+        Ok(cb)
+    }
+
+    fn on_start(&mut self, _: u64) -> OpResult<()> {
+        info!(target: "callback", "Executing txinfo for {} ...", self.txid);
+        Ok(())
+    }
+
+    /// Duplicate txids (BIP30) are printed for each occurrence, unless the parser is stopped
+    fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<Control> {
+        for tx in block.txs.iter().filter(|tx| tx.hash == self.txid) {
+            let dump = TxInfo::describe(tx, block, block_height);
+            print!("{}", dump);
+            self.found_height = Some(block_height);
+            self.dump = Some(dump);
+        }
+        match self.stop && self.found_height.is_some() {
+            true => Ok(Control::Stop),
+            false => Ok(Control::Continue),
+        }
+    }
+
+    fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+        match self.found_height {
+            Some(height) => {
+                info!(target: "callback", "Done.\nFound transaction {} at height {}.", self.txid, height)
+            }
+            None => {
+                warn!(target: "callback", "Transaction {} not found up to height {}!", self.txid, block_height)
+            }
+        }
+        Ok(())
+    }
+
+    fn show_progress(&self) -> bool {
+        false
+    }
+
+    fn supports_reverse(&self) -> bool {
+        true
+    }
+
+    fn summary(&self) -> Value {
+        json!({ "found_height": self.found_height })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::testutils;
+    use bitcoin::hashes::Hash;
+
+    #[test]
+    fn test_txinfo() {
+        let block1 = testutils::block(
+            sha256d::Hash::all_zeros(),
+            0,
+            vec![testutils::coinbase(
+                1,
+                &[(5000, testutils::p2pkh_script(1))],
+            )],
+        );
+        let mut spend = testutils::tx(
+            &[(block1.txs[0].hash, 0)],
+            &[(4000, testutils::p2pkh_script(2))],
+        );
+        spend.inputs[0].witness = vec![vec![0xab; 2], vec![]];
+        let block2 = testutils::block(
+            block1.header.hash,
+            600,
+            vec![
+                testutils::coinbase(2, &[(5000, testutils::p2pkh_script(0))]),
+                spend,
+            ],
+        );
+        let txid = block2.txs[1].hash;
+
+        let matches = TxInfo::build_subcommand().get_matches_from([
+            "txinfo",
+            "--txid",
+            &txid.to_string(),
+            "--stop",
+        ]);
+        let mut cb = TxInfo::new(&matches).unwrap();
+        cb.on_start(0).unwrap();
+        assert_eq!(cb.on_block(&block1, 0).unwrap(), Control::Continue);
+        assert_eq!(cb.on_block(&block2, 1).unwrap(), Control::Stop);
+
+        let dump = cb.dump.unwrap();
+        let lines: Vec<&str> = dump.lines().collect();
+        assert_eq!(
+            lines[0],
+            format!(
+                "Transaction {} in block 1 ({})",
+                utils::hash_to_string(&txid),
+                utils::hash_to_string(&block2.header.hash)
+            )
+        );
+        assert_eq!(
+            lines[2],
+            format!(
+                "  input 0: {}:0, sequence: 0xffffffff",
+                utils::hash_to_string(&block1.txs[0].hash)
+            )
+        );
+        assert_eq!(lines[3], "    scriptSig: ");
+        assert_eq!(lines[4], "    witness: [abab, ]");
+        assert_eq!(lines[5], "  output 0: 4000 sat");
+        assert_eq!(
+            lines[6],
+            format!(
+                "    scriptPubKey: OP_DUP OP_HASH160 OP_PUSHBYTES_20 {} OP_EQUALVERIFY OP_CHECKSIG",
+                "02".repeat(20)
+            )
+        );
+        assert!(lines[7].starts_with("    type: p2pkh, address: 1"));
+
+        let matches = TxInfo::build_subcommand().get_matches_from(["txinfo", "--txid", "xyz"]);
+        assert!(TxInfo::new(&matches).is_err());
+    }
+}
@@ -0,0 +1,243 @@
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use clap::{Arg, ArgMatches, Command};
+
+use crate::blockchain::proto::block::Block;
+use crate::callbacks::{common, Callback, Control};
+use crate::common::utils;
+use crate::errors::OpResult;
+
+/// Number of regular transactions and coinbases per bucket
+#[derive(Default)]
+struct BucketCount {
+    tx_count: u64,
+    coinbase_count: u64,
+}
+
+/// Histograms of the serialized sizes and vsizes of all transactions
+pub struct TxSizes {
+    dump_folder: PathBuf,
+    writer: common::DumpWriter,
+
+    // key: lower bound of the bucket
+    sizes: BTreeMap<u64, BucketCount>,
+    vsizes: BTreeMap<u64, BucketCount>,
+
+    start_height: u64,
+    tx_count: u64,
+}
+
+impl TxSizes {
+    /// Buckets are powers of two, a bucket contains the sizes from its lower bound
+    /// up to the next power of two (exclusive)
+    fn bucket(size: u64) -> u64 {
+        if size == 0 {
+            0
+        } else {
+            1 << (63 - size.leading_zeros())
+        }
+    }
+
+    fn add(histogram: &mut BTreeMap<u64, BucketCount>, size: u64, is_coinbase: bool) {
+        let count = histogram.entry(TxSizes::bucket(size)).or_default();
+        if is_coinbase {
+            count.coinbase_count += 1;
+        } else {
+            count.tx_count += 1;
+        }
+    }
+
+    /// Writes all buckets between the smallest and largest one, including the empty ones
+    fn write_histogram(
+        writer: &mut common::DumpWriter,
+        metric: &str,
+        histogram: &BTreeMap<u64, BucketCount>,
+    ) -> OpResult<()> {
+        let (first, last) = match (histogram.keys().next(), histogram.keys().last()) {
+            (Some(first), Some(last)) => (*first, *last),
+            _ => return Ok(()),
+        };
+        let mut bucket = first;
+        loop {
+            let count = histogram.get(&bucket);
+            writer.write_all(
+                format!(
+                    "{};{};{};{}\n",
+                    metric,
+                    bucket,
+                    count.map_or(0, |c| c.tx_count),
+                    count.map_or(0, |c| c.coinbase_count)
+                )
+                .as_bytes(),
+            )?;
+            if bucket >= last {
+                break;
+            }
+            bucket = (bucket * 2).max(1);
+        }
+        Ok(())
+    }
+}
+
+impl Callback for TxSizes {
+    fn build_subcommand() -> Command
+    where
+        Self: Sized,
+    {
+        Command::new("txsizes")
+            .about("Dumps histograms of the transaction sizes and vsizes to CSV file")
+            .version("0.1")
+            .author("gcarq <egger.m@protonmail.com>")
+            .arg(
+                Arg::new("dump-folder")
+                    .help("Folder to store csv file")
+                    .index(1)
+                    .required(true),
+            )
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let dump_folder = &PathBuf::from(matches.get_one::<String>("dump-folder").unwrap());
+        let cb = TxSizes {
+            dump_folder: PathBuf::from(dump_folder),
+            writer: common::create_writer(dump_folder.join("txsizes.csv.tmp"))?,
+            sizes: BTreeMap::new(),
+            vsizes: BTreeMap::new(),
+            start_height: 0,
+            tx_count: 0,
+        };
+        Ok(cb)
+    }
+
+    fn on_start(&mut self, block_height: u64) -> OpResult<()> {
+        self.start_height = block_height;
+        info!(target: "callback", "Executing txsizes with dump folder: {} ...", &self.dump_folder.display());
+        Ok(())
+    }
+
+    fn on_block(&mut self, block: &Block, _: u64) -> OpResult<Control> {
+        for tx in &block.txs {
+            let is_coinbase = tx.value.is_coinbase();
+            TxSizes::add(&mut self.sizes, tx.value.size(), is_coinbase);
+            TxSizes::add(&mut self.vsizes, tx.value.vsize(), is_coinbase);
+        }
+        self.tx_count += block.tx_count.value;
+        Ok(Control::Continue)
+    }
+
+    fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+        self.writer.write_all(
+            format!(
+                "{};{};{};{}\n",
+                "metric", "bucket", "txCount", "coinbaseCount"
+            )
+            .as_bytes(),
+        )?;
+        TxSizes::write_histogram(&mut self.writer, "size", &self.sizes)?;
+        TxSizes::write_histogram(&mut self.writer, "vsize", &self.vsizes)?;
+        self.writer.flush()?;
+        utils::rename(
+            self.dump_folder.as_path().join("txsizes.csv.tmp"),
+            utils::output_path(
+                &self.dump_folder,
+                "txsizes",
+                self.start_height,
+                block_height,
+                "csv",
+            ),
+        )?;
+
+        info!(target: "callback", "Done.\nDumped the sizes of {} transactions from height {} to {}.",
+             self.tx_count, self.start_height, block_height);
+        Ok(())
+    }
+
+    fn supports_reverse(&self) -> bool {
+        true
+    }
+
+    fn flush(&mut self) -> OpResult<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    fn dump_folder(&self) -> Option<&Path> {
+        Some(&self.dump_folder)
+    }
+
+    fn supports_incremental(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::testutils;
+    use bitcoin::hashes::{sha256d, Hash};
+    use std::fs;
+
+    #[test]
+    fn test_txsizes() {
+        // 1 input, 1 output: 85 bytes
+        let small = testutils::tx(
+            &[(sha256d::Hash::hash(&[1]), 0)],
+            &[(1000, testutils::p2pkh_script(1))],
+        );
+        // 1 input, 100 outputs and a witness of 5000 bytes: 8457 bytes, 4703 vbytes
+        let outputs: Vec<(u64, Vec<u8>)> = (0..100)
+            .map(|i| (1000, testutils::p2pkh_script(i)))
+            .collect();
+        let mut large = testutils::tx(&[(sha256d::Hash::hash(&[2]), 0)], &outputs);
+        large.inputs[0].witness = vec![vec![0; 5000]];
+        let block = testutils::block(
+            sha256d::Hash::all_zeros(),
+            0,
+            vec![
+                testutils::coinbase(1, &[(5000, testutils::p2pkh_script(0))]),
+                small,
+                large,
+            ],
+        );
+        assert_eq!(block.txs[1].value.size(), 85);
+        assert_eq!(block.txs[2].value.size(), 8457);
+        assert_eq!(block.txs[2].value.vsize(), 4703);
+
+        let dump_folder = tempfile::tempdir().unwrap();
+        let matches = TxSizes::build_subcommand()
+            .get_matches_from(["txsizes", dump_folder.path().to_str().unwrap()]);
+        let mut cb = TxSizes::new(&matches).unwrap();
+        cb.on_start(1).unwrap();
+        cb.on_block(&block, 1).unwrap();
+        cb.on_complete(1).unwrap();
+
+        let csv = fs::read_to_string(dump_folder.path().join("txsizes-1-1.csv")).unwrap();
+        let rows: Vec<&str> = csv.lines().skip(1).collect();
+        assert_eq!(
+            rows,
+            vec![
+                // The coinbase (94 bytes) and the small transaction share a bucket
+                "size;64;1;1",
+                "size;128;0;0",
+                "size;256;0;0",
+                "size;512;0;0",
+                "size;1024;0;0",
+                "size;2048;0;0",
+                "size;4096;0;0",
+                "size;8192;1;0",
+                "vsize;64;1;1",
+                "vsize;128;0;0",
+                "vsize;256;0;0",
+                "vsize;512;0;0",
+                "vsize;1024;0;0",
+                "vsize;2048;0;0",
+                "vsize;4096;1;0",
+            ]
+        );
+    }
+}
@@ -0,0 +1,153 @@
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use clap::{Arg, ArgMatches, Command};
+
+use crate::blockchain::proto::block::Block;
+use crate::callbacks::{common, Callback, Control};
+use crate::common::utils;
+use crate::errors::OpResult;
+
+/// Dumps the number of transactions per version in each block
+pub struct TxVersions {
+    dump_folder: PathBuf,
+    writer: common::DumpWriter,
+
+    start_height: u64,
+    // Number of transactions per version over all blocks, only used for the summary
+    totals: BTreeMap<u32, u64>,
+}
+
+impl Callback for TxVersions {
+    fn build_subcommand() -> Command
+    where
+        Self: Sized,
+    {
+        Command::new("txversions")
+            .about("Dumps the number of transactions per version in each block to CSV file")
+            .version("0.1")
+            .author("gcarq <egger.m@protonmail.com>")
+            .arg(
+                Arg::new("dump-folder")
+                    .help("Folder to store csv file")
+                    .index(1)
+                    .required(true),
+            )
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let dump_folder = &PathBuf::from(matches.get_one::<String>("dump-folder").unwrap());
+        let cb = TxVersions {
+            dump_folder: PathBuf::from(dump_folder),
+            writer: common::create_writer(dump_folder.join("txversions.csv.tmp"))?,
+            start_height: 0,
+            totals: BTreeMap::new(),
+        };
+        Ok(cb)
+    }
+
+    fn on_start(&mut self, block_height: u64) -> OpResult<()> {
+        self.start_height = block_height;
+        info!(target: "callback", "Executing txversions with dump folder: {} ...", &self.dump_folder.display());
+        self.writer
+            .write_all(format!("{};{};{}\n", "height", "version", "txCount").as_bytes())?;
+        Ok(())
+    }
+
+    /// Writes one row for each version occurring in the block
+    fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<Control> {
+        let mut counts: BTreeMap<u32, u64> = BTreeMap::new();
+        for tx in &block.txs {
+            *counts.entry(tx.value.version).or_insert(0) += 1;
+        }
+        for (version, count) in counts {
+            self.writer
+                .write_all(format!("{};{};{}\n", block_height, version, count).as_bytes())?;
+            *self.totals.entry(version).or_insert(0) += count;
+        }
+        Ok(Control::Continue)
+    }
+
+    fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+        self.writer.flush()?;
+        utils::rename(
+            self.dump_folder.as_path().join("txversions.csv.tmp"),
+            utils::output_path(
+                &self.dump_folder,
+                "txversions",
+                self.start_height,
+                block_height,
+                "csv",
+            ),
+        )?;
+
+        let summary = self
+            .totals
+            .iter()
+            .map(|(version, count)| format!("\t-> version {}: {}", version, count))
+            .collect::<Vec<String>>()
+            .join("\n");
+        info!(target: "callback", "Done.\nDumped transaction versions from height {} to {}:\n{}",
+             self.start_height, block_height, summary);
+        Ok(())
+    }
+
+    fn supports_reverse(&self) -> bool {
+        true
+    }
+
+    fn flush(&mut self) -> OpResult<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    fn dump_folder(&self) -> Option<&Path> {
+        Some(&self.dump_folder)
+    }
+
+    fn supports_incremental(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::testutils;
+    use bitcoin::hashes::{sha256d, Hash};
+    use std::fs;
+
+    #[test]
+    fn test_txversions() {
+        let mut txs = vec![testutils::coinbase(
+            1,
+            &[(5000, testutils::p2pkh_script(0))],
+        )];
+        for (i, version) in [1, 2, 2, 2, 1].iter().enumerate() {
+            let mut tx = testutils::tx(
+                &[(sha256d::Hash::hash(&[i as u8]), 0)],
+                &[(1000, testutils::p2pkh_script(1))],
+            );
+            tx.version = *version;
+            txs.push(tx);
+        }
+        let block = testutils::block(sha256d::Hash::all_zeros(), 0, txs);
+
+        let dump_folder = tempfile::tempdir().unwrap();
+        let matches = TxVersions::build_subcommand()
+            .get_matches_from(["txversions", dump_folder.path().to_str().unwrap()]);
+        let mut cb = TxVersions::new(&matches).unwrap();
+        cb.on_start(1).unwrap();
+        cb.on_block(&block, 1).unwrap();
+        cb.on_complete(1).unwrap();
+
+        let csv = fs::read_to_string(dump_folder.path().join("txversions-1-1.csv")).unwrap();
+        let rows: Vec<&str> = csv.lines().skip(1).collect();
+        // Including the coinbase, which has version 1
+        assert_eq!(rows, vec!["1;1;3", "1;2;3"]);
+    }
+}
@@ -0,0 +1,283 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use clap::{Arg, ArgMatches, Command};
+use serde_json::{json, Value};
+
+use crate::blockchain::proto::block::Block;
+use crate::blockchain::proto::tx::{OutpointKey, TxOutpoint};
+use crate::callbacks::{common, Callback, Control};
+use crate::common::utils;
+use crate::errors::OpResult;
+
+const N_TYPES: usize = common::SCRIPT_TYPES.len();
+
+/// Lower bounds of the fee rate buckets in sat/vB, the last bucket is open-ended
+const BUCKETS: [f64; 12] = [
+    0.0, 1.0, 2.0, 3.0, 5.0, 10.0, 20.0, 50.0, 100.0, 200.0, 500.0, 1000.0,
+];
+
+/// Value and index in SCRIPT_TYPES of an unspent output
+struct TypedOutput {
+    value: u64,
+    script_type: u8,
+}
+
+/// Transactions, vsize and fees of one fee rate bucket
+#[derive(Clone, Copy, Default)]
+struct Bucket {
+    txs: u64,
+    vsize: u64,
+    fees: u64,
+}
+
+/// Dumps the distribution of the fee rates (sat/vB) by the predominant input type of the transactions,
+/// e.g. to compare what segwit and taproot spends pay compared to legacy ones.
+pub struct TypeFees {
+    dump_folder: PathBuf,
+
+    // key: txid + index
+    unspents: HashMap<OutpointKey, TypedOutput>,
+    // histograms[input type][bucket]
+    histograms: [[Bucket; BUCKETS.len()]; N_TYPES],
+
+    start_height: u64,
+    n_txs: u64,
+    n_unknown_txs: u64,
+}
+
+impl TypeFees {
+    /// The type spent by most inputs, ties go to the type with the higher input value
+    fn predominant_type(inputs: &[TypedOutput]) -> usize {
+        let mut types = [(0u64, 0u64); N_TYPES];
+        for input in inputs {
+            let (count, value) = &mut types[input.script_type as usize];
+            *count += 1;
+            *value += input.value;
+        }
+        (0..N_TYPES).max_by_key(|t| types[*t]).unwrap()
+    }
+
+    fn bucket(rate: f64) -> usize {
+        BUCKETS.iter().rposition(|min| rate >= *min).unwrap_or(0)
+    }
+}
+
+impl Callback for TypeFees {
+    fn build_subcommand() -> Command
+    where
+        Self: Sized,
+    {
+        Command::new("typefees")
+            .about("Dumps the distribution of fee rates by predominant input type to CSV file")
+            .version("0.1")
+            .author("gcarq <egger.m@protonmail.com>")
+            .arg(
+                Arg::new("dump-folder")
+                    .help("Folder to store csv file")
+                    .index(1)
+                    .required(true),
+            )
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let cb = TypeFees {
+            dump_folder: PathBuf::from(matches.get_one::<String>("dump-folder").unwrap()),
+            unspents: HashMap::with_capacity(10000000),
+            histograms: [[Bucket::default(); BUCKETS.len()]; N_TYPES],
+            start_height: 0,
+            n_txs: 0,
+            n_unknown_txs: 0,
+        };
+        Ok(cb)
+    }
+
+    fn on_start(&mut self, block_height: u64) -> OpResult<()> {
+        self.start_height = block_height;
+        if block_height > 0 {
+            warn!(target: "callback", "Fees of transactions spending outputs below height {} are unknown!", block_height);
+        }
+        info!(target: "callback", "Executing typefees with dump folder: {} ...", &self.dump_folder.display());
+        Ok(())
+    }
+
+    /// Transactions spending unknown outputs are skipped
+    fn on_block(&mut self, block: &Block, _: u64) -> OpResult<Control> {
+        for tx in &block.txs {
+            if !tx.value.is_coinbase() {
+                let inputs = tx
+                    .value
+                    .inputs
+                    .iter()
+                    .map(|input| self.unspents.remove(&input.outpoint.key()))
+                    .collect::<Option<Vec<TypedOutput>>>();
+                match inputs {
+                    Some(inputs) => {
+                        let in_value: u64 = inputs.iter().map(|input| input.value).sum();
+                        let out_value: u64 = tx.value.outputs.iter().map(|o| o.out.value).sum();
+                        let fee = in_value.saturating_sub(out_value);
+                        let vsize = tx.value.vsize();
+                        let bucket = &mut self.histograms[TypeFees::predominant_type(&inputs)]
+                            [TypeFees::bucket(fee as f64 / vsize as f64)];
+                        bucket.txs += 1;
+                        bucket.vsize += vsize;
+                        bucket.fees += fee;
+                        self.n_txs += 1;
+                    }
+                    None => self.n_unknown_txs += 1,
+                }
+            }
+            for (i, output) in tx.value.outputs.iter().enumerate() {
+                self.unspents.insert(
+                    TxOutpoint::new(tx.hash, i as u32).key(),
+                    TypedOutput {
+                        value: output.out.value,
+                        script_type: common::script_type(output),
+                    },
+                );
+            }
+        }
+        Ok(Control::Continue)
+    }
+
+    /// Writes the non-empty buckets, max_rate is left empty for the last one
+    fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+        let path = utils::output_path(
+            &self.dump_folder,
+            "typefees",
+            self.start_height,
+            block_height,
+            "csv",
+        );
+        let tmp_path = self.dump_folder.join("typefees.csv.tmp");
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        writer.write_all(
+            format!(
+                "{};{};{};{};{};{}\n",
+                "input_type", "min_rate", "max_rate", "txs", "vsize", "fees"
+            )
+            .as_bytes(),
+        )?;
+        for (script_type, histogram) in self.histograms.iter().enumerate() {
+            for (i, bucket) in histogram.iter().enumerate().filter(|(_, b)| b.txs > 0) {
+                let max_rate = BUCKETS
+                    .get(i + 1)
+                    .map(|r| r.to_string())
+                    .unwrap_or_default();
+                writer.write_all(
+                    format!(
+                        "{};{};{};{};{};{}\n",
+                        common::SCRIPT_TYPES[script_type],
+                        BUCKETS[i],
+                        max_rate,
+                        bucket.txs,
+                        bucket.vsize,
+                        bucket.fees
+                    )
+                    .as_bytes(),
+                )?;
+            }
+        }
+        writer.flush()?;
+        drop(writer);
+        utils::rename(tmp_path, path)?;
+
+        info!(target: "callback", "Done.\nBucketed the fee rates of {} transactions from height {} to {} ({} with unknown inputs skipped).",
+             self.n_txs, self.start_height, block_height, self.n_unknown_txs);
+        Ok(())
+    }
+
+    fn dump_folder(&self) -> Option<&Path> {
+        Some(&self.dump_folder)
+    }
+
+    /// Average fee rate per input type
+    fn summary(&self) -> Value {
+        let mut rates = serde_json::Map::new();
+        for (script_type, histogram) in self.histograms.iter().enumerate() {
+            let vsize: u64 = histogram.iter().map(|b| b.vsize).sum();
+            let fees: u64 = histogram.iter().map(|b| b.fees).sum();
+            if vsize > 0 {
+                rates.insert(
+                    String::from(common::SCRIPT_TYPES[script_type]),
+                    json!(fees as f64 / vsize as f64),
+                );
+            }
+        }
+        json!({
+            "transactions": self.n_txs,
+            "unknown": self.n_unknown_txs,
+            "avg_fee_rates": rates,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::proto::tx::{EvaluatedTx, RawTx};
+    use crate::common::testutils;
+    use bitcoin::hashes::{sha256d, Hash};
+    use std::fs;
+
+    /// Spends the given 100000 sat outpoint, paying the given fee rate
+    fn paying(txid: sha256d::Hash, index: u32, rate: u64) -> RawTx {
+        let build = |fee: u64| {
+            testutils::tx(
+                &[(txid, index)],
+                &[(100000 - fee, testutils::p2pkh_script(9))],
+            )
+        };
+        let vsize = EvaluatedTx::from(build(0)).vsize();
+        build(rate * vsize)
+    }
+
+    #[test]
+    fn test_typefees() {
+        let mut p2wpkh = vec![0x00, 0x14];
+        p2wpkh.extend([0x11; 20]);
+        let funding = testutils::block(
+            sha256d::Hash::all_zeros(),
+            0,
+            vec![testutils::coinbase(
+                1,
+                &[(100000, testutils::p2pkh_script(1)), (100000, p2wpkh)],
+            )],
+        );
+        let cb_txid = funding.txs[0].hash;
+        let block = testutils::block(
+            funding.header.hash,
+            600,
+            vec![
+                testutils::coinbase(2, &[(5000000000, testutils::p2pkh_script(0))]),
+                paying(cb_txid, 0, 25),
+                paying(cb_txid, 1, 4),
+            ],
+        );
+        let vsize = |i: usize| block.txs[i].value.vsize();
+
+        let dump_folder = tempfile::tempdir().unwrap();
+        let matches = TypeFees::build_subcommand()
+            .get_matches_from(["typefees", dump_folder.path().to_str().unwrap()]);
+        let mut cb = TypeFees::new(&matches).unwrap();
+        cb.on_start(1).unwrap();
+        cb.on_block(&funding, 1).unwrap();
+        cb.on_block(&block, 2).unwrap();
+        cb.on_complete(2).unwrap();
+
+        let csv = fs::read_to_string(dump_folder.path().join("typefees-1-2.csv")).unwrap();
+        assert_eq!(
+            csv.lines().skip(1).collect::<Vec<_>>(),
+            vec![
+                format!("p2pkh;20;50;1;{};{}", vsize(1), 25 * vsize(1)),
+                format!("p2wpkh;3;5;1;{};{}", vsize(2), 4 * vsize(2)),
+            ]
+        );
+        assert_eq!(cb.summary()["avg_fee_rates"]["p2wpkh"], 4.0);
+    }
+}
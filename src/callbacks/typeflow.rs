@@ -0,0 +1,288 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use clap::{Arg, ArgMatches, Command};
+use serde_json::{json, Value};
+
+use crate::blockchain::proto::block::Block;
+use crate::blockchain::proto::tx::{EvaluatedTxOut, OutpointKey, TxOutpoint};
+use crate::callbacks::{common, Callback, Control};
+use crate::common::address::CompactAddress;
+use crate::common::utils;
+use crate::errors::OpResult;
+
+const N_TYPES: usize = common::SCRIPT_TYPES.len();
+
+/// Value and index in SCRIPT_TYPES of an unspent output
+struct TypedOutput {
+    value: u64,
+    script_type: u8,
+}
+
+/// Accumulates the value flowing from the script types of the spent outputs
+/// to the script types of the created outputs over the whole range
+pub struct TypeFlow {
+    dump_folder: PathBuf,
+
+    // key: txid + index
+    unspents: HashMap<OutpointKey, TypedOutput>,
+    // flows[from][to] in base units
+    flows: [[u64; N_TYPES]; N_TYPES],
+
+    start_height: u64,
+    n_txs: u64,
+    n_skipped: u64,
+}
+
+impl TypeFlow {
+    /// Each output is attributed to the input types according to their share of the input value,
+    /// so the flows of a transaction sum up to its output value and the fee is left out
+    fn add_flows(&mut self, inputs: &[TypedOutput], outputs: &[EvaluatedTxOut]) {
+        let mut input_values = [0u64; N_TYPES];
+        for input in inputs {
+            input_values[input.script_type as usize] += input.value;
+        }
+        let total_in: u64 = input_values.iter().sum();
+        if total_in == 0 {
+            return;
+        }
+        for output in outputs {
+            let to = common::script_type(output) as usize;
+            for (from, value) in input_values.iter().enumerate().filter(|(_, v)| **v > 0) {
+                let flow = output.out.value as u128 * *value as u128 / total_in as u128;
+                self.flows[from][to] += flow as u64;
+            }
+        }
+    }
+}
+
+impl Callback for TypeFlow {
+    fn build_subcommand() -> Command
+    where
+        Self: Sized,
+    {
+        Command::new("typeflow")
+            .about("Dumps the value flowing between script types of spent and created outputs to CSV file")
+            .version("0.1")
+            .author("gcarq <egger.m@protonmail.com>")
+            .arg(
+                Arg::new("dump-folder")
+                    .help("Folder to store csv file")
+                    .index(1)
+                    .required(true),
+            )
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let cb = TypeFlow {
+            dump_folder: PathBuf::from(matches.get_one::<String>("dump-folder").unwrap()),
+            unspents: HashMap::with_capacity(10000000),
+            flows: [[0; N_TYPES]; N_TYPES],
+            start_height: 0,
+            n_txs: 0,
+            n_skipped: 0,
+        };
+        Ok(cb)
+    }
+
+    fn on_start(&mut self, block_height: u64) -> OpResult<()> {
+        self.start_height = block_height;
+        if block_height > 0 && self.unspents.is_empty() {
+            warn!(target: "callback", "Outputs below height {} are unknown, transactions spending them are skipped!", block_height);
+        }
+        info!(target: "callback", "Executing typeflow with dump folder: {} ...", &self.dump_folder.display());
+        Ok(())
+    }
+
+    /// Coinbases create new coins and are not part of any flow
+    fn on_block(&mut self, block: &Block, _: u64) -> OpResult<Control> {
+        for tx in &block.txs {
+            if !tx.value.is_coinbase() {
+                let inputs = tx
+                    .value
+                    .inputs
+                    .iter()
+                    .map(|input| self.unspents.remove(&input.outpoint.key()))
+                    .collect::<Option<Vec<TypedOutput>>>();
+                match inputs {
+                    Some(inputs) => {
+                        self.add_flows(&inputs, &tx.value.outputs);
+                        self.n_txs += 1;
+                    }
+                    None => self.n_skipped += 1,
+                }
+            }
+            for (i, output) in tx.value.outputs.iter().enumerate() {
+                self.unspents.insert(
+                    TxOutpoint::new(tx.hash, i as u32).key(),
+                    TypedOutput {
+                        value: output.out.value,
+                        script_type: common::script_type(output),
+                    },
+                );
+            }
+        }
+        Ok(Control::Continue)
+    }
+
+    fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+        let path = utils::output_path(
+            &self.dump_folder,
+            "typeflow",
+            self.start_height,
+            block_height,
+            "csv",
+        );
+        let tmp_path = self.dump_folder.join("typeflow.csv.tmp");
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        writer.write_all(format!("{};{};{}\n", "from_type", "to_type", "value").as_bytes())?;
+        for (from, row) in self.flows.iter().enumerate() {
+            for (to, value) in row.iter().enumerate().filter(|(_, v)| **v > 0) {
+                writer.write_all(
+                    format!(
+                        "{};{};{}\n",
+                        common::SCRIPT_TYPES[from],
+                        common::SCRIPT_TYPES[to],
+                        value
+                    )
+                    .as_bytes(),
+                )?;
+            }
+        }
+        writer.flush()?;
+        drop(writer);
+        utils::rename(tmp_path, path)?;
+
+        info!(target: "callback", "Done.\nAttributed the value of {} transactions from height {} to {} ({} with unknown inputs skipped).",
+             self.n_txs, self.start_height, block_height, self.n_skipped);
+        Ok(())
+    }
+
+    fn dump_folder(&self) -> Option<&Path> {
+        Some(&self.dump_folder)
+    }
+
+    fn supports_incremental(&self) -> bool {
+        true
+    }
+
+    fn summary(&self) -> Value {
+        json!({ "transactions": self.n_txs, "skipped": self.n_skipped })
+    }
+
+    /// The script type is stored in place of the address
+    fn save_state(&self, folder: &Path) -> OpResult<()> {
+        let unspents = self
+            .unspents
+            .iter()
+            .map(|(key, output)| {
+                let unspent = common::UnspentValue {
+                    block_height: 0,
+                    value: output.value,
+                    address: CompactAddress::new(common::SCRIPT_TYPES[output.script_type as usize]),
+                };
+                (*key, unspent)
+            })
+            .collect();
+        common::save_unspents(&folder.join("typeflow.unspents"), &unspents)
+    }
+
+    fn load_state(&mut self, folder: &Path) -> OpResult<()> {
+        let path = folder.join("typeflow.unspents");
+        if !path.exists() {
+            return Ok(());
+        }
+        for (key, unspent) in common::load_unspents(&path)? {
+            let script_type = common::SCRIPT_TYPES
+                .iter()
+                .position(|t| unspent.address == *t)
+                .unwrap_or(N_TYPES - 1) as u8;
+            let output = TypedOutput {
+                value: unspent.value,
+                script_type,
+            };
+            self.unspents.insert(key, output);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::testutils;
+    use bitcoin::hashes::{sha256d, Hash};
+    use std::fs;
+
+    #[test]
+    fn test_typeflow() {
+        let mut p2wpkh = vec![0x00, 0x14];
+        p2wpkh.extend([0x11; 20]);
+        let block1 = testutils::block(
+            sha256d::Hash::all_zeros(),
+            0,
+            vec![testutils::coinbase(
+                1,
+                &[(6000, testutils::p2pkh_script(1)), (2000, p2wpkh.clone())],
+            )],
+        );
+        // p2pkh to p2wpkh with change back to p2pkh and a fee of 1000
+        let legacy = testutils::tx(
+            &[(block1.txs[0].hash, 0)],
+            &[(4000, p2wpkh.clone()), (1000, testutils::p2pkh_script(2))],
+        );
+        let block2 = testutils::block(
+            block1.header.hash,
+            600,
+            vec![
+                testutils::coinbase(2, &[(5000, testutils::p2pkh_script(0))]),
+                legacy,
+            ],
+        );
+        // The spent value is split 2:1 between p2wpkh and p2pkh
+        let mixed = testutils::tx(
+            &[(block2.txs[1].hash, 1), (block1.txs[0].hash, 1)],
+            &[(3000, p2wpkh)],
+        );
+        let block3 = testutils::block(
+            block2.header.hash,
+            1200,
+            vec![
+                testutils::coinbase(3, &[(5000, testutils::p2pkh_script(0))]),
+                mixed,
+            ],
+        );
+
+        let dump_folder = tempfile::tempdir().unwrap();
+        let matches = TypeFlow::build_subcommand()
+            .get_matches_from(["typeflow", dump_folder.path().to_str().unwrap()]);
+        let mut cb = TypeFlow::new(&matches).unwrap();
+        cb.on_start(1).unwrap();
+        cb.on_block(&block1, 1).unwrap();
+        let flow = |cb: &TypeFlow, from: &str, to: &str| {
+            let index = |t: &str| common::SCRIPT_TYPES.iter().position(|s| *s == t).unwrap();
+            cb.flows[index(from)][index(to)]
+        };
+        assert_eq!(flow(&cb, "p2pkh", "p2wpkh"), 0);
+        cb.on_block(&block2, 2).unwrap();
+        assert_eq!(flow(&cb, "p2pkh", "p2wpkh"), 4000);
+        cb.on_block(&block3, 3).unwrap();
+        cb.on_complete(3).unwrap();
+
+        let csv = fs::read_to_string(dump_folder.path().join("typeflow-1-3.csv")).unwrap();
+        let rows: Vec<&str> = csv.lines().skip(1).collect();
+        assert_eq!(
+            rows,
+            vec![
+                "p2pkh;p2pkh;1000",
+                "p2pkh;p2wpkh;5000",
+                "p2wpkh;p2wpkh;2000"
+            ]
+        );
+    }
+}
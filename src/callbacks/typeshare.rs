@@ -0,0 +1,219 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use clap::{Arg, ArgMatches, Command};
+
+use crate::blockchain::proto::block::Block;
+use crate::callbacks::{common, Callback, Control};
+use crate::common::utils;
+use crate::errors::OpResult;
+
+const DAY: u64 = 24 * 60 * 60;
+/// Blocks per difficulty adjustment period
+const EPOCH: u64 = 2016;
+
+/// Dumps the output value per script type and day or difficulty epoch
+pub struct TypeShare {
+    dump_folder: PathBuf,
+    writer: common::DumpWriter,
+    by_epoch: bool,
+
+    // Highest block timestamp so far, block timestamps are not strictly increasing
+    max_timestamp: u64,
+    // Current bucket and its output value per type of SCRIPT_TYPES
+    bucket: Option<u64>,
+    values: [u64; common::SCRIPT_TYPES.len()],
+
+    start_height: u64,
+    n_buckets: u64,
+}
+
+impl TypeShare {
+    /// Writes the values of the current bucket, if any, and resets them
+    fn flush_bucket(&mut self) -> OpResult<()> {
+        let bucket = match self.bucket.take() {
+            Some(bucket) => bucket,
+            None => return Ok(()),
+        };
+        let total: u64 = self.values.iter().sum();
+        for (script_type, value) in common::SCRIPT_TYPES.iter().zip(self.values) {
+            let share = if total > 0 {
+                value as f64 / total as f64
+            } else {
+                0.0
+            };
+            self.writer.write_all(
+                format!("{};{};{};{:.6}\n", bucket, script_type, value, share).as_bytes(),
+            )?;
+        }
+        self.values = [0; common::SCRIPT_TYPES.len()];
+        self.n_buckets += 1;
+        Ok(())
+    }
+}
+
+impl Callback for TypeShare {
+    fn build_subcommand() -> Command
+    where
+        Self: Sized,
+    {
+        Command::new("typeshare")
+            .about("Dumps the share of output value per script type over time to CSV file")
+            .version("0.1")
+            .author("gcarq <egger.m@protonmail.com>")
+            .arg(
+                Arg::new("dump-folder")
+                    .help("Folder to store csv file")
+                    .index(1)
+                    .required(true),
+            )
+            .arg(
+                Arg::new("bucket")
+                    .long("bucket")
+                    .value_name("BUCKET")
+                    .value_parser(clap::builder::PossibleValuesParser::new(["day", "epoch"]))
+                    .default_value("day")
+                    .help("Aggregates by UTC day or by difficulty epoch of 2016 blocks"),
+            )
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let dump_folder = &PathBuf::from(matches.get_one::<String>("dump-folder").unwrap());
+        let cb = TypeShare {
+            dump_folder: PathBuf::from(dump_folder),
+            writer: common::create_writer(dump_folder.join("typeshare.csv.tmp"))?,
+            by_epoch: matches.get_one::<String>("bucket").unwrap() == "epoch",
+            max_timestamp: 0,
+            bucket: None,
+            values: [0; common::SCRIPT_TYPES.len()],
+            start_height: 0,
+            n_buckets: 0,
+        };
+        Ok(cb)
+    }
+
+    fn on_start(&mut self, block_height: u64) -> OpResult<()> {
+        self.start_height = block_height;
+        info!(target: "callback", "Executing typeshare with dump folder: {} ...", &self.dump_folder.display());
+        self.writer.write_all(
+            format!("{};{};{};{}\n", "bucket", "scriptType", "value", "share").as_bytes(),
+        )?;
+        Ok(())
+    }
+
+    fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<Control> {
+        self.max_timestamp = self.max_timestamp.max(block.header.value.timestamp as u64);
+        let bucket = if self.by_epoch {
+            block_height / EPOCH
+        } else {
+            self.max_timestamp / DAY * DAY
+        };
+        if self.bucket != Some(bucket) {
+            self.flush_bucket()?;
+            self.bucket = Some(bucket);
+        }
+
+        for tx in &block.txs {
+            for output in &tx.value.outputs {
+                let script_type = output.script.pattern.short_name();
+                if let Some(i) = common::SCRIPT_TYPES.iter().position(|t| *t == script_type) {
+                    self.values[i] += output.out.value;
+                }
+            }
+        }
+        Ok(Control::Continue)
+    }
+
+    fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+        self.flush_bucket()?;
+        self.writer.flush()?;
+        utils::rename(
+            self.dump_folder.as_path().join("typeshare.csv.tmp"),
+            utils::output_path(
+                &self.dump_folder,
+                "typeshare",
+                self.start_height,
+                block_height,
+                "csv",
+            ),
+        )?;
+
+        info!(target: "callback", "Done.\nDumped {} buckets from height {} to {}.",
+             self.n_buckets, self.start_height, block_height);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> OpResult<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    fn dump_folder(&self) -> Option<&Path> {
+        Some(&self.dump_folder)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::testutils;
+    use bitcoin::hashes::{sha256d, Hash};
+    use std::collections::HashMap;
+    use std::fs;
+
+    #[test]
+    fn test_typeshare() {
+        let mut p2wpkh = vec![0x00, 0x14];
+        p2wpkh.extend([0x11; 20]);
+        let mut p2tr = vec![0x51, 0x20];
+        p2tr.extend([0x22; 32]);
+        let block1 = testutils::block(
+            sha256d::Hash::all_zeros(),
+            DAY as u32 + 600,
+            vec![testutils::coinbase(
+                1,
+                &[
+                    (5000, testutils::p2pkh_script(1)),
+                    (3000, p2wpkh),
+                    (2000, p2tr.clone()),
+                ],
+            )],
+        );
+        // The next day
+        let block2 = testutils::block(
+            block1.header.hash,
+            2 * DAY as u32 + 600,
+            vec![testutils::coinbase(2, &[(1000, p2tr)])],
+        );
+
+        let dump_folder = tempfile::tempdir().unwrap();
+        let matches = TypeShare::build_subcommand()
+            .get_matches_from(["typeshare", dump_folder.path().to_str().unwrap()]);
+        let mut cb = TypeShare::new(&matches).unwrap();
+        cb.on_start(1).unwrap();
+        cb.on_block(&block1, 1).unwrap();
+        cb.on_block(&block2, 2).unwrap();
+        cb.on_complete(2).unwrap();
+
+        let csv = fs::read_to_string(dump_folder.path().join("typeshare-1-2.csv")).unwrap();
+        let rows: HashMap<(&str, &str), &str> = csv
+            .lines()
+            .skip(1)
+            .map(|line| {
+                let fields: Vec<&str> = line.split(';').collect();
+                ((fields[0], fields[1]), fields[3])
+            })
+            .collect();
+        assert_eq!(rows.len(), 2 * common::SCRIPT_TYPES.len());
+        let day1 = DAY.to_string();
+        assert_eq!(rows[&(day1.as_str(), "p2pkh")], "0.500000");
+        assert_eq!(rows[&(day1.as_str(), "p2wpkh")], "0.300000");
+        assert_eq!(rows[&(day1.as_str(), "p2tr")], "0.200000");
+        assert_eq!(rows[&(day1.as_str(), "p2sh")], "0.000000");
+        let day2 = (2 * DAY).to_string();
+        assert_eq!(rows[&(day2.as_str(), "p2tr")], "1.000000");
+    }
+}
@@ -1,23 +1,41 @@
 use bitcoin::hashes::{sha256d, Hash};
-use std::collections::HashMap;
-use std::fs::{self, File};
-use std::io::{BufWriter, Write};
-use std::path::PathBuf;
+use std::io::Write;
+use std::path::{Path, PathBuf};
 
 use byteorder::{LittleEndian, ReadBytesExt};
 use clap::{Arg, ArgMatches, Command};
 
 use crate::blockchain::proto::block::Block;
-use crate::callbacks::{common, Callback};
+use crate::callbacks::common::{DumpTarget, DumpWriter, Table};
+use crate::callbacks::utxostore::{self, UtxoStore};
+use crate::callbacks::{common, Callback, Control};
+use crate::common::field::Field;
+use crate::common::utils;
 use crate::errors::OpResult;
 
+/// Table of `--format sqlite`, which holds the unspents of the last run
+const TABLE: Table = Table {
+    columns: &[
+        "txid TEXT NOT NULL",
+        "indexOut INTEGER NOT NULL",
+        "height INTEGER NOT NULL",
+        "value INTEGER NOT NULL",
+        "address TEXT",
+    ],
+    indexes: &["txid", "address"],
+    replace: true,
+};
+
 /// Dumps the UTXOs along with address in a csv file
 pub struct UnspentCsvDump {
     dump_folder: PathBuf,
-    writer: BufWriter<File>,
+    writer: DumpWriter,
+    // Of the dump file, see `DumpTarget::extension()`
+    extension: &'static str,
 
     // key: txid + index
-    unspents: HashMap<Vec<u8>, common::UnspentValue>,
+    unspents: Box<dyn UtxoStore>,
+    muhash: Option<common::UtxoMuHash>,
 
     start_height: u64,
     tx_count: u64,
@@ -25,12 +43,6 @@ pub struct UnspentCsvDump {
     out_count: u64,
 }
 
-impl UnspentCsvDump {
-    fn create_writer(cap: usize, path: PathBuf) -> OpResult<BufWriter<File>> {
-        Ok(BufWriter::with_capacity(cap, File::create(path)?))
-    }
-}
-
 impl Callback for UnspentCsvDump {
     fn build_subcommand() -> Command
     where
@@ -46,6 +58,10 @@ impl Callback for UnspentCsvDump {
                     .index(1)
                     .required(true),
             )
+            .arg(common::format_arg())
+            .arg(common::stdout_json_arg())
+            .arg(common::muhash_height_arg())
+            .args(utxostore::utxo_store_args())
     }
 
     fn new(matches: &ArgMatches) -> OpResult<Self>
@@ -53,10 +69,13 @@ impl Callback for UnspentCsvDump {
         Self: Sized,
     {
         let dump_folder = &PathBuf::from(matches.get_one::<String>("dump-folder").unwrap());
+        let target = DumpTarget::from_matches(dump_folder, matches)?;
         let cb = UnspentCsvDump {
             dump_folder: PathBuf::from(dump_folder),
-            writer: UnspentCsvDump::create_writer(4000000, dump_folder.join("unspent.csv.tmp"))?,
-            unspents: HashMap::with_capacity(10000000),
+            writer: target.writer("unspent", &TABLE)?,
+            extension: target.extension(),
+            unspents: utxostore::open(matches, dump_folder, "unspentcsvdump"),
+            muhash: common::UtxoMuHash::from_matches(matches),
             start_height: 0,
             tx_count: 0,
             in_count: 0,
@@ -74,52 +93,75 @@ impl Callback for UnspentCsvDump {
     /// For each transaction in the block
     ///   1. apply input transactions (remove (TxID == prevTxIDOut and prevOutID == spentOutID))
     ///   2. apply output transactions (add (TxID + curOutID -> HashMapVal))
+    ///
     /// For each address, retain:
     ///   * block height as "last modified"
     ///   * output_val
     ///   * address
-    fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<()> {
+    fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<Control> {
         for tx in &block.txs {
-            let (in_count, _spent_value) = common::remove_unspents(tx, &mut self.unspents);
+            let (in_count, _spent_value) = self.unspents.remove_spent(tx)?;
             self.in_count += in_count;
-            let (out_count, _new_value) = common::insert_unspents(tx, block_height, &mut self.unspents);
+            let (out_count, _new_value) = self.unspents.insert_outputs(tx, block_height)?;
             self.out_count += out_count;
+            if let Some(muhash) = self.muhash.as_mut() {
+                muhash.apply(tx, block_height);
+            }
+        }
+        if let Some(muhash) = self.muhash.as_mut() {
+            muhash.on_block_end(block_height);
         }
         self.tx_count += block.tx_count.value;
-        Ok(())
+        Ok(Control::Continue)
     }
 
     fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
-        self.writer.write_all(
-            format!(
-                "{};{};{};{};{}\n",
-                "txid", "indexOut", "height", "value", "address"
-            )
-            .as_bytes(),
-        )?;
-        for (key, value) in self.unspents.iter() {
-            let txid = sha256d::Hash::from_slice(&key[0..32]).unwrap();
-            let mut index = &key[32..];
+        if self.writer.is_csv() {
             self.writer.write_all(
                 format!(
                     "{};{};{};{};{}\n",
-                    txid,
-                    index.read_u32::<LittleEndian>()?,
-                    value.block_height,
-                    value.value,
-                    value.address
+                    "txid", "indexOut", "height", "value", "address"
                 )
                 .as_bytes(),
             )?;
         }
+        let writer = &mut self.writer;
+        self.unspents.for_each(&mut |key, value| {
+            let txid = sha256d::Hash::from_slice(&key[0..32]).unwrap();
+            let mut index = &key[32..];
+            let address = value.address.to_string();
+            writer.write_row(&[
+                Field::Text(&utils::hash_to_string(&txid)),
+                Field::Int(index.read_u32::<LittleEndian>()? as i64),
+                Field::Int(value.block_height as i64),
+                Field::Int(value.value as i64),
+                match address.is_empty() {
+                    true => Field::Null,
+                    false => Field::Text(&address),
+                },
+            ])?;
+            Ok(())
+        })?;
+        self.writer.finish()?;
+        if let Some(muhash) = &self.muhash {
+            muhash.finish();
+        }
 
-        fs::rename(
-            self.dump_folder.as_path().join("unspent.csv.tmp"),
-            self.dump_folder.as_path().join(format!(
-                "unspent-{}-{}.csv",
-                self.start_height, block_height
-            )),
-        )?;
+        if self.writer.is_file() {
+            let ext = self.extension;
+            utils::rename(
+                self.dump_folder
+                    .as_path()
+                    .join(format!("unspent.{}.tmp", ext)),
+                utils::output_path(
+                    &self.dump_folder,
+                    "unspent",
+                    self.start_height,
+                    block_height,
+                    ext,
+                ),
+            )?;
+        }
 
         info!(target: "callback", "Done.\nDumped blocks from height {} to {}:\n\
                                    \t-> transactions: {:9}\n\
@@ -128,4 +170,34 @@ impl Callback for UnspentCsvDump {
              self.start_height, block_height, self.tx_count, self.in_count, self.out_count);
         Ok(())
     }
+
+    fn flush(&mut self) -> OpResult<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    fn dump_folder(&self) -> Option<&Path> {
+        Some(&self.dump_folder)
+    }
+
+    fn supports_incremental(&self) -> bool {
+        true
+    }
+
+    fn writes_stdout(&self) -> bool {
+        self.writer.is_stdout()
+    }
+
+    fn save_state(&self, folder: &Path) -> OpResult<()> {
+        self.unspents.save(folder)
+    }
+
+    fn load_state(&mut self, folder: &Path) -> OpResult<()> {
+        common::UtxoMuHash::disable_on_resume(&mut self.muhash);
+        self.unspents.load(folder)
+    }
+
+    fn supports_checkpoints(&self) -> bool {
+        self.unspents.supports_checkpoints()
+    }
 }
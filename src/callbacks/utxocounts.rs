@@ -0,0 +1,183 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use clap::{Arg, ArgMatches, Command};
+
+use crate::blockchain::proto::block::Block;
+use crate::blockchain::proto::tx::OutpointKey;
+use crate::callbacks::{common, Callback, Control};
+use crate::common::address::CompactAddress;
+use crate::common::utils;
+use crate::errors::OpResult;
+
+/// Dumps the number of UTXOs and the balance of each address in a csv file
+pub struct UtxoCounts {
+    dump_folder: PathBuf,
+    writer: common::DumpWriter,
+
+    // key: txid + index
+    unspents: HashMap<OutpointKey, common::UnspentValue>,
+
+    start_height: u64,
+}
+
+impl Callback for UtxoCounts {
+    fn build_subcommand() -> Command
+    where
+        Self: Sized,
+    {
+        Command::new("utxocounts")
+            .about("Dumps the number of UTXOs and the balance of each address to CSV file")
+            .version("0.1")
+            .author("gcarq <egger.m@protonmail.com>")
+            .arg(
+                Arg::new("dump-folder")
+                    .help("Folder to store csv file")
+                    .index(1)
+                    .required(true),
+            )
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let dump_folder = &PathBuf::from(matches.get_one::<String>("dump-folder").unwrap());
+        let cb = UtxoCounts {
+            dump_folder: PathBuf::from(dump_folder),
+            writer: common::create_writer(dump_folder.join("utxocounts.csv.tmp"))?,
+            unspents: HashMap::with_capacity(10000000),
+            start_height: 0,
+        };
+        Ok(cb)
+    }
+
+    fn on_start(&mut self, block_height: u64) -> OpResult<()> {
+        self.start_height = block_height;
+        info!(target: "callback", "Executing utxocounts with dump folder: {} ...", &self.dump_folder.display());
+        Ok(())
+    }
+
+    fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<Control> {
+        for tx in &block.txs {
+            common::remove_unspents(tx, &mut self.unspents);
+            common::insert_unspents(tx, block_height, &mut self.unspents);
+        }
+        Ok(Control::Continue)
+    }
+
+    fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+        self.writer
+            .write_all(format!("{};{};{}\n", "address", "utxoCount", "balance").as_bytes())?;
+
+        // Collect utxo count and balance for each address
+        let mut addresses: HashMap<&CompactAddress, (u64, u64)> = HashMap::new();
+        for unspent in self.unspents.values() {
+            if unspent.address.is_empty() {
+                continue;
+            }
+            let entry = addresses.entry(&unspent.address).or_insert((0, 0));
+            entry.0 += 1;
+            entry.1 += unspent.value;
+        }
+
+        for (address, (count, balance)) in addresses.iter() {
+            self.writer
+                .write_all(format!("{};{};{}\n", address, count, balance).as_bytes())?;
+        }
+        self.writer.flush()?;
+
+        utils::rename(
+            self.dump_folder.as_path().join("utxocounts.csv.tmp"),
+            utils::output_path(
+                &self.dump_folder,
+                "utxocounts",
+                self.start_height,
+                block_height,
+                "csv",
+            ),
+        )?;
+
+        info!(target: "callback", "Done.\nDumped {} addresses.", addresses.len());
+        Ok(())
+    }
+
+    fn flush(&mut self) -> OpResult<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    fn dump_folder(&self) -> Option<&Path> {
+        Some(&self.dump_folder)
+    }
+
+    fn supports_incremental(&self) -> bool {
+        true
+    }
+
+    fn save_state(&self, folder: &Path) -> OpResult<()> {
+        common::save_unspents(&folder.join("utxocounts.unspents"), &self.unspents)
+    }
+
+    fn load_state(&mut self, folder: &Path) -> OpResult<()> {
+        self.unspents = common::load_unspents(&folder.join("utxocounts.unspents"))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::testutils;
+    use bitcoin::hashes::{sha256d, Hash};
+    use std::fs;
+
+    #[test]
+    fn test_utxocounts() {
+        let block1 = testutils::block(
+            sha256d::Hash::all_zeros(),
+            0,
+            vec![testutils::coinbase(
+                1,
+                &[
+                    (1000, testutils::p2pkh_script(1)),
+                    (2000, testutils::p2pkh_script(1)),
+                    (4000, testutils::p2pkh_script(2)),
+                ],
+            )],
+        );
+        // Spends the UTXO of the second address and pays the first one again
+        let spend = testutils::tx(
+            &[(block1.txs[0].hash, 2)],
+            &[(3000, testutils::p2pkh_script(1))],
+        );
+        let block2 = testutils::block(
+            block1.header.hash,
+            600,
+            vec![
+                testutils::coinbase(2, &[(5000, testutils::p2pkh_script(3))]),
+                spend,
+            ],
+        );
+
+        let dump_folder = tempfile::tempdir().unwrap();
+        let matches = UtxoCounts::build_subcommand()
+            .get_matches_from(["utxocounts", dump_folder.path().to_str().unwrap()]);
+        let mut cb = UtxoCounts::new(&matches).unwrap();
+        cb.on_start(1).unwrap();
+        cb.on_block(&block1, 1).unwrap();
+        cb.on_block(&block2, 2).unwrap();
+        cb.on_complete(2).unwrap();
+
+        let address = block1.txs[0].value.outputs[0]
+            .script
+            .address
+            .clone()
+            .unwrap();
+        let csv = fs::read_to_string(dump_folder.path().join("utxocounts-1-2.csv")).unwrap();
+        let rows = csv.lines().skip(1).collect::<Vec<&str>>();
+        assert_eq!(rows.len(), 2);
+        assert!(rows.contains(&format!("{};3;6000", address).as_str()));
+    }
+}
@@ -0,0 +1,187 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use clap::{Arg, ArgMatches, Command};
+use serde_json::{json, Value};
+
+use crate::blockchain::proto::block::Block;
+use crate::callbacks::{common, Callback, Control};
+use crate::common::utils;
+use crate::errors::OpResult;
+
+/// Dumps per block the number of created and consumed UTXOs along with the running total.
+/// Like `insert_unspents()` and `remove_unspents()`, every output counts as created
+/// (including unspendable ones) and every non-coinbase input as consumed,
+/// so no UTXO set has to be kept. The running total starts at zero at the start height.
+pub struct UtxoDelta {
+    dump_folder: PathBuf,
+    writer: common::DumpWriter,
+
+    start_height: u64,
+    n_created: u64,
+    n_consumed: u64,
+}
+
+impl UtxoDelta {
+    fn running_total(&self) -> i64 {
+        self.n_created as i64 - self.n_consumed as i64
+    }
+}
+
+impl Callback for UtxoDelta {
+    fn build_subcommand() -> Command
+    where
+        Self: Sized,
+    {
+        Command::new("utxodelta")
+            .about("Dumps the number of created and consumed UTXOs per block to CSV file")
+            .version("0.1")
+            .author("gcarq <egger.m@protonmail.com>")
+            .arg(
+                Arg::new("dump-folder")
+                    .help("Folder to store csv file")
+                    .index(1)
+                    .required(true),
+            )
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let dump_folder = &PathBuf::from(matches.get_one::<String>("dump-folder").unwrap());
+        let cb = UtxoDelta {
+            dump_folder: PathBuf::from(dump_folder),
+            writer: common::create_writer(dump_folder.join("utxodelta.csv.tmp"))?,
+            start_height: 0,
+            n_created: 0,
+            n_consumed: 0,
+        };
+        Ok(cb)
+    }
+
+    fn on_start(&mut self, block_height: u64) -> OpResult<()> {
+        self.start_height = block_height;
+        info!(target: "callback", "Executing utxodelta with dump folder: {} ...", &self.dump_folder.display());
+        self.writer.write_all(
+            format!(
+                "{};{};{};{};{}\n",
+                "height", "created", "consumed", "net_delta", "running_total"
+            )
+            .as_bytes(),
+        )?;
+        Ok(())
+    }
+
+    fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<Control> {
+        let (mut created, mut consumed) = (0, 0);
+        for tx in &block.txs {
+            created += tx.value.out_count.value;
+            if !tx.value.is_coinbase() {
+                consumed += tx.value.in_count.value;
+            }
+        }
+        self.n_created += created;
+        self.n_consumed += consumed;
+        self.writer.write_all(
+            format!(
+                "{};{};{};{};{}\n",
+                block_height,
+                created,
+                consumed,
+                created as i64 - consumed as i64,
+                self.running_total()
+            )
+            .as_bytes(),
+        )?;
+        Ok(Control::Continue)
+    }
+
+    fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+        self.writer.flush()?;
+        utils::rename(
+            self.dump_folder.as_path().join("utxodelta.csv.tmp"),
+            utils::output_path(
+                &self.dump_folder,
+                "utxodelta",
+                self.start_height,
+                block_height,
+                "csv",
+            ),
+        )?;
+
+        info!(target: "callback", "Done.\nCreated {} and consumed {} UTXOs from height {} to {}.",
+             self.n_created, self.n_consumed, self.start_height, block_height);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> OpResult<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    fn dump_folder(&self) -> Option<&Path> {
+        Some(&self.dump_folder)
+    }
+
+    fn supports_incremental(&self) -> bool {
+        true
+    }
+
+    fn summary(&self) -> Value {
+        json!({
+            "created": self.n_created,
+            "consumed": self.n_consumed,
+            "running_total": self.running_total(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::testutils;
+    use bitcoin::hashes::{sha256d, Hash};
+    use std::fs;
+
+    #[test]
+    fn test_utxodelta() {
+        let block1 = testutils::block(
+            sha256d::Hash::all_zeros(),
+            0,
+            vec![testutils::coinbase(
+                1,
+                &[
+                    (3000, testutils::p2pkh_script(1)),
+                    (2000, testutils::p2pkh_script(2)),
+                ],
+            )],
+        );
+        let coinbase = block1.txs[0].hash;
+        // Consolidates both outputs of block 1 into one
+        let block2 = testutils::block(
+            block1.header.hash,
+            600,
+            vec![
+                testutils::coinbase(2, &[(5000, testutils::p2pkh_script(0))]),
+                testutils::tx(
+                    &[(coinbase, 0), (coinbase, 1)],
+                    &[(4900, testutils::p2pkh_script(3))],
+                ),
+            ],
+        );
+
+        let dump_folder = tempfile::tempdir().unwrap();
+        let matches = UtxoDelta::build_subcommand()
+            .get_matches_from(["utxodelta", dump_folder.path().to_str().unwrap()]);
+        let mut cb = UtxoDelta::new(&matches).unwrap();
+        cb.on_start(1).unwrap();
+        cb.on_block(&block1, 1).unwrap();
+        cb.on_block(&block2, 2).unwrap();
+        cb.on_complete(2).unwrap();
+
+        let csv = fs::read_to_string(dump_folder.path().join("utxodelta-1-2.csv")).unwrap();
+        let rows: Vec<&str> = csv.lines().skip(1).collect();
+        assert_eq!(rows, vec!["1;2;0;2;2", "2;2;2;0;2"]);
+    }
+}
@@ -0,0 +1,338 @@
+use bitcoin::hashes::{sha256d, Hash};
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::PathBuf;
+
+use byteorder::{LittleEndian, ReadBytesExt};
+use clap::{Arg, ArgMatches, Command};
+
+use crate::blockchain::parser::snapshot::UtxoSnapshotReader;
+use crate::blockchain::proto::block::Block;
+use crate::blockchain::proto::tx::{OutpointKey, TxOutpoint};
+use crate::callbacks::{common, Callback, Control};
+use crate::common::address::CompactAddress;
+use crate::common::utils;
+use crate::errors::OpResult;
+
+/// Diffs the reconstructed UTXO set against a Bitcoin Core `dumptxoutset` snapshot
+pub struct UtxoDiff {
+    dump_folder: PathBuf,
+    snapshot_path: PathBuf,
+    writer: common::DumpWriter,
+
+    // key: txid + index
+    unspents: HashMap<OutpointKey, common::UnspentValue>,
+    base_block_hash: sha256d::Hash,
+    diff_height: Option<u64>,
+
+    start_height: u64,
+    n_missing_in_core: u64,
+    n_missing_in_parser: u64,
+    n_value_mismatch: u64,
+}
+
+impl UtxoDiff {
+    fn write_diff(
+        &mut self,
+        key: &[u8],
+        status: &str,
+        parser_value: Option<u64>,
+        core_value: Option<u64>,
+    ) -> OpResult<()> {
+        let txid = sha256d::Hash::from_slice(&key[0..32]).unwrap();
+        let mut index = &key[32..];
+        let fmt_value = |v: Option<u64>| v.map(|v| v.to_string()).unwrap_or_default();
+        self.writer.write_all(
+            format!(
+                "{};{};{};{};{}\n",
+                utils::hash_to_string(&txid),
+                index.read_u32::<LittleEndian>()?,
+                status,
+                fmt_value(parser_value),
+                fmt_value(core_value)
+            )
+            .as_bytes(),
+        )?;
+        Ok(())
+    }
+
+    /// Streams all coins from the snapshot and compares them with the tracked unspents.
+    /// Consumes the tracked unspents, everything left over is missing in the snapshot.
+    fn diff(&mut self) -> OpResult<()> {
+        info!(target: "callback", "Comparing UTXO set with {} ...", self.snapshot_path.display());
+        let reader = UtxoSnapshotReader::open(&self.snapshot_path)?;
+        for coin in reader {
+            let coin = coin?;
+            let key = coin.outpoint.key();
+            match self.unspents.remove(&key) {
+                Some(unspent) if unspent.value != coin.value => {
+                    self.n_value_mismatch += 1;
+                    self.write_diff(
+                        &key,
+                        "value_mismatch",
+                        Some(unspent.value),
+                        Some(coin.value),
+                    )?;
+                }
+                Some(_) => {}
+                None => {
+                    self.n_missing_in_parser += 1;
+                    self.write_diff(&key, "missing_in_parser", None, Some(coin.value))?;
+                }
+            }
+        }
+        let leftovers = self
+            .unspents
+            .drain()
+            .map(|(key, unspent)| (key, unspent.value))
+            .collect::<Vec<(OutpointKey, u64)>>();
+        for (key, value) in leftovers {
+            self.n_missing_in_core += 1;
+            self.write_diff(&key, "missing_in_core", Some(value), None)?;
+        }
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+impl Callback for UtxoDiff {
+    fn build_subcommand() -> Command
+    where
+        Self: Sized,
+    {
+        Command::new("utxodiff")
+            .about("Diffs the UTXO set against a Bitcoin Core dumptxoutset snapshot")
+            .version("0.1")
+            .author("gcarq <egger.m@protonmail.com>")
+            .arg(
+                Arg::new("dump-folder")
+                    .help("Folder to store csv file")
+                    .index(1)
+                    .required(true),
+            )
+            .arg(
+                Arg::new("snapshot")
+                    .long("snapshot")
+                    .value_name("FILE")
+                    .help("UTXO snapshot created with `bitcoin-cli dumptxoutset`")
+                    .required(true),
+            )
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let dump_folder = &PathBuf::from(matches.get_one::<String>("dump-folder").unwrap());
+        let snapshot_path = PathBuf::from(matches.get_one::<String>("snapshot").unwrap());
+        // Read metadata upfront to fail early on invalid snapshots
+        let metadata = UtxoSnapshotReader::open(&snapshot_path)?.metadata;
+        let cb = UtxoDiff {
+            dump_folder: PathBuf::from(dump_folder),
+            snapshot_path,
+            writer: common::create_writer(dump_folder.join("utxodiff.csv.tmp"))?,
+            unspents: HashMap::with_capacity(10000000),
+            base_block_hash: metadata.base_block_hash,
+            diff_height: None,
+            start_height: 0,
+            n_missing_in_core: 0,
+            n_missing_in_parser: 0,
+            n_value_mismatch: 0,
+        };
+        Ok(cb)
+    }
+
+    fn on_start(&mut self, block_height: u64) -> OpResult<()> {
+        self.start_height = block_height;
+        info!(target: "callback", "Executing utxodiff with dump folder: {} (snapshot base: {}) ...",
+              &self.dump_folder.display(), &self.base_block_hash);
+        self.writer.write_all(
+            format!(
+                "{};{};{};{};{}\n",
+                "txid", "indexOut", "status", "parserValue", "coreValue"
+            )
+            .as_bytes(),
+        )?;
+        Ok(())
+    }
+
+    fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<Control> {
+        if self.diff_height.is_some() {
+            return Ok(Control::Continue);
+        }
+        for tx in &block.txs {
+            common::remove_unspents(tx, &mut self.unspents);
+            for (i, output) in tx.value.outputs.iter().enumerate() {
+                if !common::is_tracked_by_core(&output.out.script_pubkey, block_height) {
+                    continue;
+                }
+                let unspent = common::UnspentValue {
+                    block_height,
+                    value: output.out.value,
+                    address: CompactAddress::default(),
+                };
+                self.unspents
+                    .insert(TxOutpoint::new(tx.hash, i as u32).key(), unspent);
+            }
+        }
+        if block.hash() == self.base_block_hash {
+            self.diff()?;
+            self.diff_height = Some(block_height);
+        }
+        Ok(Control::Continue)
+    }
+
+    fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+        utils::rename(
+            self.dump_folder.as_path().join("utxodiff.csv.tmp"),
+            utils::output_path(
+                &self.dump_folder,
+                "utxodiff",
+                self.start_height,
+                block_height,
+                "csv",
+            ),
+        )?;
+
+        match self.diff_height {
+            Some(height) => {
+                info!(target: "callback", "Done.\nCompared UTXO set at height {}:\n\
+                                   \t-> missing in snapshot: {:9}\n\
+                                   \t-> missing in parser:   {:9}\n\
+                                   \t-> value mismatches:    {:9}",
+                     height, self.n_missing_in_core, self.n_missing_in_parser, self.n_value_mismatch);
+            }
+            None => {
+                warn!(target: "callback", "Snapshot base block {} was not reached, nothing compared. \
+                                           Make sure --end covers the snapshot height.", &self.base_block_hash);
+            }
+        }
+        Ok(())
+    }
+
+    fn show_progress(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::parser::snapshot::tests::write_snapshot;
+    use crate::common::testutils;
+    use std::fs;
+
+    fn run(snapshot: Vec<u8>, blocks: &[Block]) -> UtxoDiff {
+        let dump_folder = tempfile::tempdir().unwrap();
+        let snapshot_path = dump_folder.path().join("utxo.dat");
+        fs::write(&snapshot_path, snapshot).unwrap();
+        let matches = UtxoDiff::build_subcommand().get_matches_from([
+            "utxodiff",
+            dump_folder.path().to_str().unwrap(),
+            "--snapshot",
+            snapshot_path.to_str().unwrap(),
+        ]);
+        let mut cb = UtxoDiff::new(&matches).unwrap();
+        cb.on_start(0).unwrap();
+        for (height, block) in blocks.iter().enumerate() {
+            cb.on_block(block, height as u64).unwrap();
+        }
+        cb.on_complete(blocks.len() as u64 - 1).unwrap();
+        cb
+    }
+
+    fn chain() -> Vec<Block> {
+        let block0 = testutils::block(
+            sha256d::Hash::all_zeros(),
+            0,
+            vec![testutils::coinbase(
+                0,
+                &[(5000000000, testutils::p2pkh_script(0))],
+            )],
+        );
+        let block1 = testutils::block(
+            block0.header.hash,
+            600,
+            vec![testutils::coinbase(
+                1,
+                &[(5000000000, testutils::p2pkh_script(1))],
+            )],
+        );
+        let spend = testutils::tx(
+            &[(block1.txs[0].hash, 0)],
+            &[
+                (3000000000, testutils::p2pkh_script(2)),
+                (2000000000, testutils::p2pkh_script(3)),
+                (0, vec![0x6a, 0x01, 0x00]),
+            ],
+        );
+        let block2 = testutils::block(
+            block1.header.hash,
+            1200,
+            vec![
+                testutils::coinbase(2, &[(5000000000, testutils::p2pkh_script(4))]),
+                spend,
+            ],
+        );
+        vec![block0, block1, block2]
+    }
+
+    #[test]
+    fn test_utxodiff_matching_snapshot() {
+        let blocks = chain();
+        let snapshot = write_snapshot(
+            &blocks[2].header.hash,
+            &[
+                (
+                    TxOutpoint::new(blocks[2].txs[0].hash, 0),
+                    2,
+                    5000000000,
+                    testutils::p2pkh_script(4),
+                ),
+                (
+                    TxOutpoint::new(blocks[2].txs[1].hash, 0),
+                    2,
+                    3000000000,
+                    testutils::p2pkh_script(2),
+                ),
+                (
+                    TxOutpoint::new(blocks[2].txs[1].hash, 1),
+                    2,
+                    2000000000,
+                    testutils::p2pkh_script(3),
+                ),
+            ],
+        );
+        let cb = run(snapshot, &blocks);
+        assert_eq!(cb.diff_height, Some(2));
+        assert_eq!(cb.n_missing_in_core, 0);
+        assert_eq!(cb.n_missing_in_parser, 0);
+        assert_eq!(cb.n_value_mismatch, 0);
+    }
+
+    #[test]
+    fn test_utxodiff_discrepancies() {
+        let blocks = chain();
+        let snapshot = write_snapshot(
+            &blocks[2].header.hash,
+            &[
+                (
+                    TxOutpoint::new(blocks[2].txs[1].hash, 0),
+                    2,
+                    3000000001,
+                    testutils::p2pkh_script(2),
+                ),
+                (
+                    TxOutpoint::new(sha256d::Hash::hash(&[0xff]), 0),
+                    1,
+                    546,
+                    testutils::p2pkh_script(5),
+                ),
+            ],
+        );
+        let cb = run(snapshot, &blocks);
+        assert_eq!(cb.n_value_mismatch, 1);
+        assert_eq!(cb.n_missing_in_parser, 1);
+        assert_eq!(cb.n_missing_in_core, 2);
+    }
+}
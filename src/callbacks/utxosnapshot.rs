@@ -0,0 +1,235 @@
+use bitcoin::hashes::{sha256d, Hash};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+
+use clap::{Arg, ArgMatches, Command};
+use serde_json::{json, Value};
+
+use crate::blockchain::parser::snapshot::{SnapshotCoin, SnapshotMetadata, UtxoSnapshotWriter};
+use crate::blockchain::proto::block::Block;
+use crate::blockchain::proto::tx::{OutpointKey, TxOutpoint};
+use crate::callbacks::{common, Callback, Control};
+use crate::common::utils;
+use crate::errors::{OpError, OpErrorKind, OpResult};
+
+/// Writes the UTXO set at the last parsed block in the format of Bitcoin Core's `dumptxoutset`
+pub struct UtxoSnapshot {
+    dump_folder: PathBuf,
+    network_magic: u32,
+
+    // key: txid + index
+    coins: HashMap<OutpointKey, SnapshotCoin>,
+    base_block_hash: sha256d::Hash,
+    txoutset_hash: Option<sha256d::Hash>,
+
+    start_height: u64,
+}
+
+impl UtxoSnapshot {
+    /// Writes all coins ordered by outpoint and returns the hash of the serialized UTXO set
+    fn write_snapshot(&self, path: &Path) -> OpResult<sha256d::Hash> {
+        let metadata = SnapshotMetadata {
+            network_magic: self.network_magic,
+            base_block_hash: self.base_block_hash,
+            coins_count: self.coins.len() as u64,
+        };
+        let file = BufWriter::with_capacity(4000000, File::create(path)?);
+        let mut writer = UtxoSnapshotWriter::new(file, &metadata)?;
+        let mut coins: Vec<&SnapshotCoin> = self.coins.values().collect();
+        coins.sort_unstable_by_key(|coin| (coin.outpoint.txid, coin.outpoint.index));
+        for coin in coins {
+            writer.write_coin(coin)?;
+        }
+        writer.finish()
+    }
+}
+
+impl Callback for UtxoSnapshot {
+    fn build_subcommand() -> Command
+    where
+        Self: Sized,
+    {
+        Command::new("utxosnapshot")
+            .about("Writes the UTXO set in the format of Bitcoin Core's dumptxoutset")
+            .version("0.1")
+            .author("gcarq <egger.m@protonmail.com>")
+            .arg(
+                Arg::new("dump-folder")
+                    .help("Folder to store the snapshot")
+                    .index(1)
+                    .required(true),
+            )
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let dump_folder = &PathBuf::from(matches.get_one::<String>("dump-folder").unwrap());
+        let cb = UtxoSnapshot {
+            dump_folder: PathBuf::from(dump_folder),
+            network_magic: common::get_coin(matches).magic,
+            coins: HashMap::with_capacity(10000000),
+            base_block_hash: sha256d::Hash::all_zeros(),
+            txoutset_hash: None,
+            start_height: 0,
+        };
+        Ok(cb)
+    }
+
+    fn on_start(&mut self, block_height: u64) -> OpResult<()> {
+        if block_height > 0 {
+            let msg = format!(
+                "utxosnapshot needs the whole chain to build the UTXO set, but starts at height {}",
+                block_height
+            );
+            return Err(OpError::new(OpErrorKind::InvalidArgsError).join_msg(&msg));
+        }
+        self.start_height = block_height;
+        info!(target: "callback", "Executing utxosnapshot with dump folder: {} ...", &self.dump_folder.display());
+        Ok(())
+    }
+
+    fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<Control> {
+        for tx in &block.txs {
+            let is_coinbase = tx.value.is_coinbase();
+            if !is_coinbase {
+                for input in &tx.value.inputs {
+                    self.coins.remove(&input.outpoint.key());
+                }
+            }
+            for (i, output) in tx.value.outputs.iter().enumerate() {
+                if !common::is_tracked_by_core(&output.out.script_pubkey, block_height) {
+                    continue;
+                }
+                let outpoint = TxOutpoint::new(tx.hash, i as u32);
+                let key = outpoint.key();
+                let coin = SnapshotCoin {
+                    outpoint,
+                    height: block_height,
+                    is_coinbase,
+                    value: output.out.value,
+                    script_pubkey: output.out.script_pubkey.clone(),
+                };
+                // Duplicate coinbases (BIP30) overwrite the unspent outputs of the first one, like in Core
+                self.coins.insert(key, coin);
+            }
+        }
+        self.base_block_hash = block.hash();
+        Ok(Control::Continue)
+    }
+
+    fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+        let tmp_path = self.dump_folder.join("utxosnapshot.dat.tmp");
+        let hash = self.write_snapshot(&tmp_path)?;
+        utils::rename(
+            tmp_path,
+            utils::output_path(
+                &self.dump_folder,
+                "utxosnapshot",
+                self.start_height,
+                block_height,
+                "dat",
+            ),
+        )?;
+        self.txoutset_hash = Some(hash);
+        info!(target: "callback", "Done.\nWrote {} coins at height {} (base block: {}).\n\
+                                   txoutset_hash: {}",
+              self.coins.len(), block_height, utils::hash_to_string(&self.base_block_hash),
+              utils::hash_to_string(&hash));
+        Ok(())
+    }
+
+    fn dump_folder(&self) -> Option<&Path> {
+        Some(&self.dump_folder)
+    }
+
+    fn summary(&self) -> Value {
+        json!({
+            "coins": self.coins.len(),
+            "base_block_hash": utils::hash_to_string(&self.base_block_hash),
+            "txoutset_hash": self.txoutset_hash.map(|hash| utils::hash_to_string(&hash)),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::parser::snapshot::UtxoSnapshotReader;
+    use crate::common::testutils;
+
+    #[test]
+    fn test_utxosnapshot() {
+        let block0 = testutils::block(
+            sha256d::Hash::all_zeros(),
+            0,
+            vec![testutils::coinbase(
+                0,
+                &[(5000000000, testutils::p2pkh_script(0))],
+            )],
+        );
+        let block1 = testutils::block(
+            block0.header.hash,
+            600,
+            vec![testutils::coinbase(
+                1,
+                &[(5000000000, testutils::p2pkh_script(1))],
+            )],
+        );
+        let block2 = testutils::block(
+            block1.header.hash,
+            1200,
+            vec![
+                testutils::coinbase(2, &[(5000000000, testutils::p2pkh_script(2))]),
+                testutils::tx(
+                    &[(block1.txs[0].hash, 0)],
+                    &[
+                        (4000000000, testutils::p2pkh_script(3)),
+                        (0, vec![0x6a, 0x01, 0x00]),
+                        (1000000000, vec![0x51]),
+                        // Unspendable for rust-bitcoin, but kept by Core
+                        (500000000, vec![0x62, 0x51]),
+                    ],
+                ),
+            ],
+        );
+
+        let dump_folder = tempfile::tempdir().unwrap();
+        let matches = UtxoSnapshot::build_subcommand()
+            .get_matches_from(["utxosnapshot", dump_folder.path().to_str().unwrap()]);
+        let mut cb = UtxoSnapshot::new(&matches).unwrap();
+        assert!(cb.on_start(1).is_err());
+        cb.on_start(0).unwrap();
+        for (height, block) in [&block0, &block1, &block2].iter().enumerate() {
+            cb.on_block(block, height as u64).unwrap();
+        }
+        cb.on_complete(2).unwrap();
+
+        let path = dump_folder.path().join("utxosnapshot-0-2.dat");
+        let reader = UtxoSnapshotReader::open(&path).unwrap();
+        assert_eq!(reader.metadata.base_block_hash, block2.header.hash);
+        assert_eq!(reader.metadata.network_magic, 0xd9b4bef9);
+        let mut coins: Vec<(TxOutpoint, u64, bool, u64)> = reader
+            .map(|coin| coin.unwrap())
+            .map(|coin| (coin.outpoint, coin.height, coin.is_coinbase, coin.value))
+            .collect();
+        assert!(coins
+            .windows(2)
+            .all(|w| (w[0].0.txid, w[0].0.index) < (w[1].0.txid, w[1].0.index)));
+        coins.sort_unstable_by_key(|coin| coin.3);
+        assert_eq!(
+            coins,
+            vec![
+                (TxOutpoint::new(block2.txs[1].hash, 3), 2, false, 500000000),
+                (TxOutpoint::new(block2.txs[1].hash, 2), 2, false, 1000000000),
+                (TxOutpoint::new(block2.txs[1].hash, 0), 2, false, 4000000000),
+                (TxOutpoint::new(block2.txs[0].hash, 0), 2, true, 5000000000),
+            ]
+        );
+        assert!(cb.summary()["txoutset_hash"].is_string());
+        assert_eq!(cb.summary()["coins"], 4);
+    }
+}
@@ -0,0 +1,505 @@
+//! Unspent outputs of the callbacks dumping balances or the UTXO set, selected with `--utxo-store`.
+//! They are kept in a HashMap by default, which needs more than 30 GB for the current UTXO set.
+//! The disk store keeps them in a LevelDB database in the dump folder instead and only the
+//! recently changed ones in memory.
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use byteorder::{LittleEndian, ReadBytesExt};
+use clap::{Arg, ArgMatches};
+use rusty_leveldb::{LdbIterator, Options, WriteBatch, DB};
+
+use crate::blockchain::proto::tx::{EvaluatedTx, OutpointKey, TxOutpoint};
+use crate::blockchain::proto::Hashed;
+use crate::callbacks::common::{self, UnspentValue};
+use crate::common::address::CompactAddress;
+use crate::errors::{OpError, OpErrorKind, OpResult};
+
+/// Keys of the database which are not outpoints (txid + index, 36 bytes)
+const COUNT_KEY: &[u8] = b"count";
+/// Set by `save()` and removed with the first write after it, so a database which was
+/// modified by an interrupted run is not used by the next incremental run
+const CLEAN_KEY: &[u8] = b"clean";
+
+/// Unspent outputs keyed by txid + index
+pub trait UtxoStore {
+    fn get(&mut self, key: &OutpointKey) -> OpResult<Option<UnspentValue>>;
+
+    /// Returns true if the output was not known yet
+    fn insert(&mut self, key: OutpointKey, unspent: UnspentValue) -> OpResult<bool>;
+
+    fn remove(&mut self, key: &OutpointKey) -> OpResult<Option<UnspentValue>>;
+
+    fn len(&self) -> u64;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn clear(&mut self) -> OpResult<()>;
+
+    /// Calls `f` for each unspent output, in no particular order
+    fn for_each(
+        &mut self,
+        f: &mut dyn FnMut(&OutpointKey, &UnspentValue) -> OpResult<()>,
+    ) -> OpResult<()>;
+
+    /// Saves the unspents to the dump folder for the next incremental run
+    fn save(&self, folder: &Path) -> OpResult<()>;
+
+    /// Loads the unspents saved by the last run
+    fn load(&mut self, folder: &Path) -> OpResult<()>;
+
+    /// Returns false if the store changes after a checkpoint of `--resume`, so it can't be resumed from it
+    fn supports_checkpoints(&self) -> bool;
+
+    /// Removes the outputs spent by the transaction, see `common::remove_unspents()`.
+    /// Returns the number of inputs and the value of the removed outputs.
+    fn remove_spent(&mut self, tx: &Hashed<EvaluatedTx>) -> OpResult<(u64, u64)> {
+        let mut spent_value = 0;
+        for input in &tx.value.inputs {
+            if let Some(unspent) = self.remove(&input.outpoint.key())? {
+                spent_value += unspent.value;
+            }
+        }
+        Ok((tx.value.in_count.value, spent_value))
+    }
+
+    /// Adds the outputs of the transaction, see `common::insert_unspents()`.
+    /// Returns the number of outputs and the value of the outputs which were not known yet.
+    fn insert_outputs(
+        &mut self,
+        tx: &Hashed<EvaluatedTx>,
+        block_height: u64,
+    ) -> OpResult<(u64, u64)> {
+        let mut new_value = 0;
+        for (i, output) in tx.value.outputs.iter().enumerate() {
+            let unspent = UnspentValue {
+                block_height,
+                address: CompactAddress::new(output.script.address.as_deref().unwrap_or_default()),
+                value: output.out.value,
+            };
+            let value = unspent.value;
+            if self.insert(TxOutpoint::new(tx.hash, i as u32).key(), unspent)? {
+                new_value += value;
+            }
+        }
+        Ok((tx.value.outputs.len() as u64, new_value))
+    }
+}
+
+/// Arguments to select the store, see `open()`
+pub fn utxo_store_args() -> [Arg; 2] {
+    [
+        Arg::new("utxo-store")
+            .long("utxo-store")
+            .value_parser(["memory", "disk"])
+            .default_value("memory")
+            .help("Keeps the unspent outputs in memory or in a database in the dump folder, which bounds the memory usage"),
+        Arg::new("utxo-cache")
+            .long("utxo-cache")
+            .value_name("N")
+            .value_parser(clap::value_parser!(u64).range(1..))
+            .default_value("2000000")
+            .help("Number of changed unspent outputs kept in memory by --utxo-store disk (about 150 bytes each)"),
+    ]
+}
+
+/// Opens the store of `--utxo-store`. `name` is the callback, which names the state
+/// in the dump folder (`<name>.unspents` or `<name>.utxodb`).
+pub fn open(matches: &ArgMatches, dump_folder: &Path, name: &str) -> Box<dyn UtxoStore> {
+    match matches.get_one::<String>("utxo-store").map(String::as_str) {
+        Some("disk") => {
+            let cache_size = *matches.get_one::<u64>("utxo-cache").unwrap() as usize;
+            Box::new(DiskUtxoStore::new(
+                dump_folder.join(format!("{}.utxodb", name)),
+                cache_size,
+            ))
+        }
+        _ => Box::new(MemoryUtxoStore::new(name)),
+    }
+}
+
+/// Keeps all unspents in a HashMap, saved to `<name>.unspents` with `common::save_unspents()`
+pub struct MemoryUtxoStore {
+    name: String,
+    unspents: HashMap<OutpointKey, UnspentValue>,
+}
+
+impl MemoryUtxoStore {
+    pub fn new(name: &str) -> Self {
+        MemoryUtxoStore {
+            name: String::from(name),
+            unspents: HashMap::with_capacity(10000000),
+        }
+    }
+
+    fn path(&self, folder: &Path) -> PathBuf {
+        folder.join(format!("{}.unspents", self.name))
+    }
+}
+
+impl UtxoStore for MemoryUtxoStore {
+    fn get(&mut self, key: &OutpointKey) -> OpResult<Option<UnspentValue>> {
+        Ok(self.unspents.get(key).cloned())
+    }
+
+    fn insert(&mut self, key: OutpointKey, unspent: UnspentValue) -> OpResult<bool> {
+        Ok(self.unspents.insert(key, unspent).is_none())
+    }
+
+    fn remove(&mut self, key: &OutpointKey) -> OpResult<Option<UnspentValue>> {
+        Ok(self.unspents.remove(key))
+    }
+
+    fn len(&self) -> u64 {
+        self.unspents.len() as u64
+    }
+
+    fn clear(&mut self) -> OpResult<()> {
+        self.unspents.clear();
+        Ok(())
+    }
+
+    fn for_each(
+        &mut self,
+        f: &mut dyn FnMut(&OutpointKey, &UnspentValue) -> OpResult<()>,
+    ) -> OpResult<()> {
+        for (key, unspent) in &self.unspents {
+            f(key, unspent)?;
+        }
+        Ok(())
+    }
+
+    fn save(&self, folder: &Path) -> OpResult<()> {
+        common::save_unspents(&self.path(folder), &self.unspents)
+    }
+
+    fn load(&mut self, folder: &Path) -> OpResult<()> {
+        self.unspents = common::load_unspents(&self.path(folder))?;
+        Ok(())
+    }
+
+    fn supports_checkpoints(&self) -> bool {
+        true
+    }
+}
+
+/// Change of an unspent output which is not written to the database yet
+struct CachedEntry {
+    // None if the output was spent
+    unspent: Option<UnspentValue>,
+    // The database holds the output, so a spend deletes it there
+    stored: bool,
+}
+
+struct DiskState {
+    // Opened on first use, which creates a new database unless the last one was loaded
+    db: Option<DB>,
+    cache: HashMap<OutpointKey, CachedEntry>,
+    count: u64,
+    // The database holds CLEAN_KEY
+    clean: bool,
+}
+
+/// Keeps the unspents in a LevelDB database and caches the changes of the last `cache_size`
+/// outputs in memory, most outputs are spent again before they are written.
+pub struct DiskUtxoStore {
+    path: PathBuf,
+    cache_size: usize,
+    // `save()` takes &self, as the state of the callbacks is saved through a shared reference
+    state: RefCell<DiskState>,
+}
+
+impl DiskUtxoStore {
+    pub fn new(path: PathBuf, cache_size: usize) -> Self {
+        DiskUtxoStore {
+            path,
+            cache_size,
+            state: RefCell::new(DiskState {
+                db: None,
+                cache: HashMap::new(),
+                count: 0,
+                clean: false,
+            }),
+        }
+    }
+
+    fn db<'a>(path: &Path, state: &'a mut DiskState) -> OpResult<&'a mut DB> {
+        if state.db.is_none() {
+            if path.exists() {
+                fs::remove_dir_all(path)?;
+            }
+            debug!(target: "utxostore", "Creating {} ...", path.display());
+            state.db = Some(DB::open(path, Options::default())?);
+        }
+        Ok(state.db.as_mut().unwrap())
+    }
+
+    /// Writes the cached changes along with the count
+    fn write_cache(
+        path: &Path,
+        state: &mut DiskState,
+        mut batch: WriteBatch,
+        sync: bool,
+    ) -> OpResult<()> {
+        for (key, entry) in state.cache.drain() {
+            match entry.unspent {
+                Some(unspent) => batch.put(&key, &encode(&unspent)),
+                None if entry.stored => batch.delete(&key),
+                None => {}
+            }
+        }
+        batch.put(COUNT_KEY, &state.count.to_le_bytes());
+        if state.clean {
+            batch.delete(CLEAN_KEY);
+            state.clean = false;
+        }
+        let db = DiskUtxoStore::db(path, state)?;
+        db.write(batch, sync)?;
+        Ok(())
+    }
+
+    fn lookup(
+        path: &Path,
+        state: &mut DiskState,
+        key: &OutpointKey,
+    ) -> OpResult<Option<UnspentValue>> {
+        if let Some(entry) = state.cache.get(key) {
+            return Ok(entry.unspent.clone());
+        }
+        match DiskUtxoStore::db(path, state)?.get(key) {
+            Some(data) => Ok(Some(decode(&data)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+impl UtxoStore for DiskUtxoStore {
+    fn get(&mut self, key: &OutpointKey) -> OpResult<Option<UnspentValue>> {
+        DiskUtxoStore::lookup(&self.path, self.state.get_mut(), key)
+    }
+
+    fn insert(&mut self, key: OutpointKey, unspent: UnspentValue) -> OpResult<bool> {
+        let state = self.state.get_mut();
+        let (known, stored) = match state.cache.get(&key) {
+            Some(entry) => (entry.unspent.is_some(), entry.stored),
+            None => {
+                let stored = DiskUtxoStore::lookup(&self.path, state, &key)?.is_some();
+                (stored, stored)
+            }
+        };
+        state.cache.insert(
+            key,
+            CachedEntry {
+                unspent: Some(unspent),
+                stored,
+            },
+        );
+        if !known {
+            state.count += 1;
+        }
+        if state.cache.len() >= self.cache_size {
+            DiskUtxoStore::write_cache(&self.path, state, WriteBatch::new(), false)?;
+        }
+        Ok(!known)
+    }
+
+    fn remove(&mut self, key: &OutpointKey) -> OpResult<Option<UnspentValue>> {
+        let state = self.state.get_mut();
+        let unspent = match state.cache.get_mut(key) {
+            Some(entry) if !entry.stored => state.cache.remove(key).and_then(|e| e.unspent),
+            Some(entry) => entry.unspent.take(),
+            None => {
+                let unspent = DiskUtxoStore::lookup(&self.path, state, key)?;
+                if unspent.is_some() {
+                    let entry = CachedEntry {
+                        unspent: None,
+                        stored: true,
+                    };
+                    state.cache.insert(*key, entry);
+                }
+                unspent
+            }
+        };
+        if unspent.is_some() {
+            state.count -= 1;
+        }
+        Ok(unspent)
+    }
+
+    fn len(&self) -> u64 {
+        self.state.borrow().count
+    }
+
+    fn clear(&mut self) -> OpResult<()> {
+        let state = self.state.get_mut();
+        state.db = None;
+        state.cache.clear();
+        state.count = 0;
+        state.clean = false;
+        DiskUtxoStore::db(&self.path, state)?;
+        Ok(())
+    }
+
+    fn for_each(
+        &mut self,
+        f: &mut dyn FnMut(&OutpointKey, &UnspentValue) -> OpResult<()>,
+    ) -> OpResult<()> {
+        let state = self.state.get_mut();
+        DiskUtxoStore::write_cache(&self.path, state, WriteBatch::new(), false)?;
+        let mut iter = DiskUtxoStore::db(&self.path, state)?.new_iter()?;
+        let (mut key, mut data) = (Vec::new(), Vec::new());
+        while iter.advance() {
+            iter.current(&mut key, &mut data);
+            if let Ok(key) = OutpointKey::try_from(&key[..]) {
+                f(&key, &decode(&data)?)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn save(&self, _: &Path) -> OpResult<()> {
+        let mut state = self.state.borrow_mut();
+        let mut batch = WriteBatch::new();
+        batch.put(CLEAN_KEY, &[1]);
+        // Deleting the clean flag in the same batch is skipped
+        state.clean = false;
+        DiskUtxoStore::write_cache(&self.path, &mut state, batch, true)?;
+        state.clean = true;
+        Ok(())
+    }
+
+    fn load(&mut self, _: &Path) -> OpResult<()> {
+        let mut db = match self.path.exists() {
+            true => Some(DB::open(&self.path, Options::default())?),
+            false => None,
+        };
+        if !matches!(db.as_mut().map(|db| db.get(CLEAN_KEY)), Some(Some(_))) {
+            let msg = format!(
+                "UTXO store {} is missing or was modified by an interrupted run. Run without --incremental to rebuild it",
+                self.path.display()
+            );
+            return Err(OpError::new(OpErrorKind::RuntimeError).join_msg(&msg));
+        }
+        let mut db = db.unwrap();
+        let count = match db.get(COUNT_KEY) {
+            Some(data) => (&data[..]).read_u64::<LittleEndian>()?,
+            None => 0,
+        };
+        let state = self.state.get_mut();
+        state.db = Some(db);
+        state.cache.clear();
+        state.count = count;
+        state.clean = true;
+        Ok(())
+    }
+
+    fn supports_checkpoints(&self) -> bool {
+        false
+    }
+}
+
+/// Height (u32), value (u64) and the compact address
+fn encode(unspent: &UnspentValue) -> Vec<u8> {
+    let mut data = Vec::with_capacity(12 + unspent.address.as_bytes().len());
+    data.extend((unspent.block_height as u32).to_le_bytes());
+    data.extend(unspent.value.to_le_bytes());
+    data.extend(unspent.address.as_bytes());
+    data
+}
+
+fn decode(mut data: &[u8]) -> OpResult<UnspentValue> {
+    let block_height = data.read_u32::<LittleEndian>()? as u64;
+    let value = data.read_u64::<LittleEndian>()?;
+    Ok(UnspentValue {
+        block_height,
+        value,
+        address: CompactAddress::from_bytes(data.to_vec())?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::hashes::{sha256d, Hash};
+
+    fn unspent(value: u64) -> UnspentValue {
+        UnspentValue {
+            block_height: 7,
+            value,
+            address: CompactAddress::new(&format!("addr{}", value)),
+        }
+    }
+
+    fn key(i: u32) -> OutpointKey {
+        TxOutpoint::new(sha256d::Hash::hash(&i.to_le_bytes()), i).key()
+    }
+
+    fn check_store(store: &mut dyn UtxoStore) {
+        for i in 0..10 {
+            assert!(store.insert(key(i), unspent(i as u64)).unwrap());
+        }
+        assert!(!store.insert(key(3), unspent(30)).unwrap());
+        assert_eq!(store.remove(&key(4)).unwrap().unwrap().value, 4);
+        assert!(store.remove(&key(4)).unwrap().is_none());
+        assert!(store.insert(key(4), unspent(40)).unwrap());
+        assert_eq!(store.remove(&key(5)).unwrap().unwrap().value, 5);
+        assert_eq!(store.get(&key(3)).unwrap().unwrap().value, 30);
+        assert_eq!(store.len(), 9);
+
+        let mut values = Vec::new();
+        store
+            .for_each(&mut |_, unspent| {
+                values.push(unspent.value);
+                Ok(())
+            })
+            .unwrap();
+        values.sort_unstable();
+        assert_eq!(values, [0, 1, 2, 6, 7, 8, 9, 30, 40]);
+    }
+
+    #[test]
+    fn test_memory_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = MemoryUtxoStore::new("test");
+        check_store(&mut store);
+        store.save(dir.path()).unwrap();
+        let mut loaded = MemoryUtxoStore::new("test");
+        loaded.load(dir.path()).unwrap();
+        assert_eq!(loaded.len(), 9);
+        assert_eq!(loaded.get(&key(4)).unwrap().unwrap(), unspent(40));
+    }
+
+    #[test]
+    fn test_disk_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.utxodb");
+        // Writes to the database every 3 changes
+        let mut store = DiskUtxoStore::new(path.clone(), 3);
+        check_store(&mut store);
+        assert!(store.remove(&key(0)).unwrap().is_some());
+        store.save(dir.path()).unwrap();
+        drop(store);
+
+        let mut loaded = DiskUtxoStore::new(path.clone(), 3);
+        loaded.load(dir.path()).unwrap();
+        assert_eq!(loaded.len(), 8);
+        assert!(loaded.get(&key(0)).unwrap().is_none());
+        assert_eq!(loaded.get(&key(4)).unwrap().unwrap(), unspent(40));
+        for i in 10..15 {
+            loaded.insert(key(i), unspent(i as u64)).unwrap();
+        }
+        drop(loaded);
+        // Changed after it was saved without being saved again
+        let mut interrupted = DiskUtxoStore::new(path.clone(), 3);
+        assert!(interrupted.load(dir.path()).is_err());
+
+        // A new run starts over
+        let mut store = DiskUtxoStore::new(path, 3);
+        assert!(store.get(&key(4)).unwrap().is_none());
+        assert!(store.is_empty());
+    }
+}
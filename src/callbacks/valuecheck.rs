@@ -0,0 +1,221 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use clap::{Arg, ArgMatches, Command};
+
+use crate::blockchain::proto::block::Block;
+use crate::blockchain::proto::tx::OutpointKey;
+use crate::callbacks::{common, Callback, Control};
+use crate::common::utils;
+use crate::errors::OpResult;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Violation {
+    /// At least one input spends an output which is not in the UTXO set
+    UnknownPrevout,
+    /// The outputs spend more than the inputs provide
+    OutputsExceedInputs,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let str = match *self {
+            Violation::UnknownPrevout => "unknown_prevout",
+            Violation::OutputsExceedInputs => "outputs_exceed_inputs",
+        };
+        write!(f, "{}", str)
+    }
+}
+
+/// Verifies that the inputs of each transaction cover its outputs
+pub struct ValueCheck {
+    dump_folder: PathBuf,
+    writer: common::DumpWriter,
+
+    // key: txid + index
+    unspents: HashMap<OutpointKey, common::UnspentValue>,
+
+    start_height: u64,
+    n_txs: u64,
+    n_violations: u64,
+}
+
+impl Callback for ValueCheck {
+    fn build_subcommand() -> Command
+    where
+        Self: Sized,
+    {
+        Command::new("valuecheck")
+            .about("Dumps transactions whose inputs don't cover their outputs to CSV file")
+            .version("0.1")
+            .author("gcarq <egger.m@protonmail.com>")
+            .arg(
+                Arg::new("dump-folder")
+                    .help("Folder to store csv file")
+                    .index(1)
+                    .required(true),
+            )
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let dump_folder = &PathBuf::from(matches.get_one::<String>("dump-folder").unwrap());
+        let cb = ValueCheck {
+            dump_folder: PathBuf::from(dump_folder),
+            writer: common::create_writer(dump_folder.join("valuecheck.csv.tmp"))?,
+            unspents: HashMap::with_capacity(10000000),
+            start_height: 0,
+            n_txs: 0,
+            n_violations: 0,
+        };
+        Ok(cb)
+    }
+
+    fn on_start(&mut self, block_height: u64) -> OpResult<()> {
+        self.start_height = block_height;
+        if block_height > 0 {
+            warn!(target: "callback", "Transactions spending outputs below height {} are reported as unknown_prevout!", block_height);
+        }
+        info!(target: "callback", "Executing valuecheck with dump folder: {} ...", &self.dump_folder.display());
+        self.writer.write_all(
+            format!(
+                "{};{};{};{};{}\n",
+                "height", "txid", "inputValue", "outputValue", "violation"
+            )
+            .as_bytes(),
+        )?;
+        Ok(())
+    }
+
+    fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<Control> {
+        for tx in &block.txs {
+            if !tx.value.is_coinbase() {
+                let spent = common::take_unspents(tx, &mut self.unspents);
+                let in_value: u64 = spent.iter().map(|unspent| unspent.value).sum();
+                let out_value: u64 = tx.value.outputs.iter().map(|o| o.out.value).sum();
+                let violation = if spent.len() < tx.value.inputs.len() {
+                    Some(Violation::UnknownPrevout)
+                } else if in_value < out_value {
+                    Some(Violation::OutputsExceedInputs)
+                } else {
+                    None
+                };
+
+                if let Some(violation) = violation {
+                    let txid = utils::hash_to_string(&tx.hash);
+                    warn!(target: "callback", "Transaction {} at height {} failed the value check: {} (in: {}, out: {})",
+                          txid, block_height, violation, in_value, out_value);
+                    self.writer.write_all(
+                        format!(
+                            "{};{};{};{};{}\n",
+                            block_height, txid, in_value, out_value, violation
+                        )
+                        .as_bytes(),
+                    )?;
+                    self.n_violations += 1;
+                }
+                self.n_txs += 1;
+            }
+            common::insert_unspents(tx, block_height, &mut self.unspents);
+        }
+        Ok(Control::Continue)
+    }
+
+    fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+        self.writer.flush()?;
+        utils::rename(
+            self.dump_folder.as_path().join("valuecheck.csv.tmp"),
+            utils::output_path(
+                &self.dump_folder,
+                "valuecheck",
+                self.start_height,
+                block_height,
+                "csv",
+            ),
+        )?;
+
+        info!(target: "callback", "Done.\nChecked {} transactions from height {} to {}, {} violations.",
+             self.n_txs, self.start_height, block_height, self.n_violations);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> OpResult<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    fn dump_folder(&self) -> Option<&Path> {
+        Some(&self.dump_folder)
+    }
+
+    fn supports_incremental(&self) -> bool {
+        true
+    }
+
+    fn save_state(&self, folder: &Path) -> OpResult<()> {
+        common::save_unspents(&folder.join("valuecheck.unspents"), &self.unspents)
+    }
+
+    fn load_state(&mut self, folder: &Path) -> OpResult<()> {
+        self.unspents = common::load_unspents(&folder.join("valuecheck.unspents"))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::testutils;
+    use bitcoin::hashes::{sha256d, Hash};
+    use std::fs;
+
+    #[test]
+    fn test_valuecheck() {
+        let block1 = testutils::block(
+            sha256d::Hash::all_zeros(),
+            0,
+            vec![testutils::coinbase(
+                1,
+                &[(5000, testutils::p2pkh_script(0))],
+            )],
+        );
+        let valid = testutils::tx(
+            &[(block1.txs[0].hash, 0)],
+            &[(4000, testutils::p2pkh_script(1))],
+        );
+        let unknown = testutils::tx(
+            &[(sha256d::Hash::hash(&[0x01]), 0)],
+            &[(1000, testutils::p2pkh_script(2))],
+        );
+        let block2 = testutils::block(
+            block1.header.hash,
+            600,
+            vec![
+                testutils::coinbase(2, &[(5000, testutils::p2pkh_script(0))]),
+                valid,
+                unknown,
+            ],
+        );
+
+        let dump_folder = tempfile::tempdir().unwrap();
+        let matches = ValueCheck::build_subcommand()
+            .get_matches_from(["valuecheck", dump_folder.path().to_str().unwrap()]);
+        let mut cb = ValueCheck::new(&matches).unwrap();
+        cb.on_start(0).unwrap();
+        cb.on_block(&block1, 0).unwrap();
+        cb.on_block(&block2, 1).unwrap();
+        cb.on_complete(1).unwrap();
+
+        let csv = fs::read_to_string(dump_folder.path().join("valuecheck-0-1.csv")).unwrap();
+        let rows: Vec<&str> = csv.lines().skip(1).collect();
+        assert_eq!(
+            rows,
+            vec![format!("1;{};0;1000;unknown_prevout", block2.txs[2].hash)]
+        );
+        assert_eq!(cb.n_txs, 2);
+    }
+}
@@ -0,0 +1,193 @@
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use clap::{Arg, ArgMatches, Command};
+use serde_json::{json, Value};
+
+use crate::blockchain::proto::block::Block;
+use crate::callbacks::{common, Callback, Control};
+use crate::common::utils;
+use crate::errors::OpResult;
+
+/// Dumps the BIP-9 deployment bits signaled by each block.
+/// Blocks with a legacy version number get `-` instead of a bit list, so they can be told apart
+/// from BIP-9 blocks which don't signal anything.
+pub struct VersionBits {
+    dump_folder: PathBuf,
+    writer: common::DumpWriter,
+
+    start_height: u64,
+    n_legacy: u64,
+    // Number of signaling blocks per bit
+    totals: BTreeMap<u8, u64>,
+}
+
+impl Callback for VersionBits {
+    fn build_subcommand() -> Command
+    where
+        Self: Sized,
+    {
+        Command::new("versionbits")
+            .about("Dumps the BIP-9 version bits signaled by each block to CSV file")
+            .version("0.1")
+            .author("gcarq <egger.m@protonmail.com>")
+            .arg(
+                Arg::new("dump-folder")
+                    .help("Folder to store csv file")
+                    .index(1)
+                    .required(true),
+            )
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let dump_folder = &PathBuf::from(matches.get_one::<String>("dump-folder").unwrap());
+        let cb = VersionBits {
+            dump_folder: PathBuf::from(dump_folder),
+            writer: common::create_writer(dump_folder.join("versionbits.csv.tmp"))?,
+            start_height: 0,
+            n_legacy: 0,
+            totals: BTreeMap::new(),
+        };
+        Ok(cb)
+    }
+
+    fn on_start(&mut self, block_height: u64) -> OpResult<()> {
+        self.start_height = block_height;
+        info!(target: "callback", "Executing versionbits with dump folder: {} ...", &self.dump_folder.display());
+        self.writer
+            .write_all(format!("{};{};{}\n", "height", "version", "signaled_bits").as_bytes())?;
+        Ok(())
+    }
+
+    /// The version is written in hex, the bits are separated by commas
+    fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<Control> {
+        let header = &block.header.value;
+        let signaled_bits = if header.uses_version_bits() {
+            let bits = header.signaled_bits();
+            for bit in &bits {
+                *self.totals.entry(*bit).or_insert(0) += 1;
+            }
+            bits.iter()
+                .map(|bit| bit.to_string())
+                .collect::<Vec<String>>()
+                .join(",")
+        } else {
+            self.n_legacy += 1;
+            String::from("-")
+        };
+        self.writer.write_all(
+            format!(
+                "{};{:#010x};{}\n",
+                block_height, header.version, signaled_bits
+            )
+            .as_bytes(),
+        )?;
+        Ok(Control::Continue)
+    }
+
+    fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+        self.writer.flush()?;
+        utils::rename(
+            self.dump_folder.as_path().join("versionbits.csv.tmp"),
+            utils::output_path(
+                &self.dump_folder,
+                "versionbits",
+                self.start_height,
+                block_height,
+                "csv",
+            ),
+        )?;
+
+        let summary = self
+            .totals
+            .iter()
+            .map(|(bit, count)| format!("\t-> bit {}: {} blocks", bit, count))
+            .collect::<Vec<String>>()
+            .join("\n");
+        info!(target: "callback", "Done.\nDumped version bits from height {} to {}, {} blocks with legacy versions:\n{}",
+             self.start_height, block_height, self.n_legacy, summary);
+        Ok(())
+    }
+
+    fn supports_reverse(&self) -> bool {
+        true
+    }
+
+    fn flush(&mut self) -> OpResult<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    fn dump_folder(&self) -> Option<&Path> {
+        Some(&self.dump_folder)
+    }
+
+    fn supports_incremental(&self) -> bool {
+        true
+    }
+
+    fn summary(&self) -> Value {
+        let bits: serde_json::Map<String, Value> = self
+            .totals
+            .iter()
+            .map(|(bit, count)| (bit.to_string(), json!(count)))
+            .collect();
+        json!({ "legacy_blocks": self.n_legacy, "signaling_blocks": bits })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::testutils;
+    use bitcoin::hashes::{sha256d, Hash};
+    use std::fs;
+
+    #[test]
+    fn test_versionbits() {
+        let mut blocks: Vec<Block> = Vec::new();
+        for (height, version) in [4u32, 0x20000000, 0x20000002, 0x20000006]
+            .iter()
+            .enumerate()
+        {
+            let prev_hash = blocks
+                .last()
+                .map_or(sha256d::Hash::all_zeros(), |b| b.header.hash);
+            let coinbase =
+                testutils::coinbase(height as u64, &[(5000, testutils::p2pkh_script(0))]);
+            let mut block = testutils::block(prev_hash, height as u32 * 600, vec![coinbase]);
+            block.header.value.version = *version;
+            blocks.push(block);
+        }
+
+        let dump_folder = tempfile::tempdir().unwrap();
+        let matches = VersionBits::build_subcommand()
+            .get_matches_from(["versionbits", dump_folder.path().to_str().unwrap()]);
+        let mut cb = VersionBits::new(&matches).unwrap();
+        cb.on_start(0).unwrap();
+        for (height, block) in blocks.iter().enumerate() {
+            cb.on_block(block, height as u64).unwrap();
+        }
+        cb.on_complete(3).unwrap();
+
+        let csv = fs::read_to_string(dump_folder.path().join("versionbits-0-3.csv")).unwrap();
+        let rows: Vec<&str> = csv.lines().skip(1).collect();
+        assert_eq!(
+            rows,
+            vec![
+                "0;0x00000004;-",
+                "1;0x20000000;",
+                "2;0x20000002;1",
+                "3;0x20000006;1,2"
+            ]
+        );
+        assert_eq!(
+            cb.summary(),
+            json!({ "legacy_blocks": 1, "signaling_blocks": { "1": 2, "2": 1 } })
+        );
+    }
+}
@@ -0,0 +1,608 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use bitcoin::base58;
+use bitcoin::bip32::{ChildNumber, ExtendedPubKey};
+use bitcoin::secp256k1::{Secp256k1, VerifyOnly};
+use bitcoin::ScriptBuf;
+use clap::{Arg, ArgMatches, Command};
+use serde_json::{json, Value};
+
+use crate::blockchain::proto::block::Block;
+use crate::blockchain::proto::tx::{OutpointKey, TxOutpoint};
+use crate::callbacks::{common, Callback, Control};
+use crate::common::utils;
+use crate::errors::{OpError, OpErrorKind, OpResult};
+
+/// Version bytes of the SLIP-132 extended public keys and the script type they imply
+const KEY_VERSIONS: [([u8; 4], [u8; 4], ScriptKind); 6] = [
+    ([0x04, 0x88, 0xb2, 0x1e], XPUB, ScriptKind::Pkh),
+    ([0x04, 0x9d, 0x7c, 0xb2], XPUB, ScriptKind::ShWpkh),
+    ([0x04, 0xb2, 0x47, 0x46], XPUB, ScriptKind::Wpkh),
+    ([0x04, 0x35, 0x87, 0xcf], TPUB, ScriptKind::Pkh),
+    ([0x04, 0x4a, 0x52, 0x62], TPUB, ScriptKind::ShWpkh),
+    ([0x04, 0x5f, 0x1c, 0xf6], TPUB, ScriptKind::Wpkh),
+];
+const XPUB: [u8; 4] = [0x04, 0x88, 0xb2, 0x1e];
+const TPUB: [u8; 4] = [0x04, 0x35, 0x87, 0xcf];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ScriptKind {
+    Pkh,
+    ShWpkh,
+    Wpkh,
+    Tr,
+}
+
+/// Unhardened derivation chain of a descriptor, e.g. the receive addresses `xpub/0/*`
+struct Chain {
+    // Index of the --descriptor argument
+    descriptor: usize,
+    kind: ScriptKind,
+    // Key derived up to the wildcard
+    key: ExtendedPubKey,
+    // Derivation path relative to the given key, without the wildcard
+    path: String,
+    // Number of derived addresses
+    derived: u32,
+}
+
+/// Wallet output which has not been spent yet
+struct WalletOutput {
+    outpoint: TxOutpoint,
+    height: u64,
+    value: u64,
+    address: String,
+    chain: usize,
+    index: u32,
+}
+
+/// Scans the chain for the addresses of output descriptors or extended public keys,
+/// like a wallet rescan without an indexing node
+pub struct WalletScan {
+    dump_folder: PathBuf,
+    writer: common::DumpWriter,
+    secp: Secp256k1<VerifyOnly>,
+    gap_limit: u32,
+
+    chains: Vec<Chain>,
+    // key: scriptPubKey of a derived address, value: chain and index
+    scripts: HashMap<Vec<u8>, (usize, u32)>,
+    // key: txid + index
+    unspents: HashMap<OutpointKey, WalletOutput>,
+    balance: u64,
+
+    start_height: u64,
+    n_transactions: u64,
+}
+
+impl WalletScan {
+    fn invalid(descriptor: &str, msg: &str) -> OpError {
+        OpError::new(OpErrorKind::InvalidArgsError)
+            .join_msg(&format!("Invalid descriptor {}: {}", descriptor, msg))
+    }
+
+    /// Parses `pkh(KEY)`, `wpkh(KEY)`, `sh(wpkh(KEY))`, `tr(KEY)` or a bare extended public key.
+    /// KEY is an extended public key with an optional `[origin]` and an unhardened derivation path
+    /// ending with `/*`, a `<0;1>` step expands to multiple chains.
+    /// A key without derivation path scans its receive and change chains `/0/*` and `/1/*`,
+    /// a bare key with the script type of its SLIP-132 version.
+    fn parse_descriptor(
+        secp: &Secp256k1<VerifyOnly>,
+        index: usize,
+        descriptor: &str,
+    ) -> OpResult<Vec<Chain>> {
+        // Drop the checksum
+        let desc = descriptor.split('#').next().unwrap_or_default().trim();
+        let wrappers = [
+            ("sh(wpkh(", "))", Some(ScriptKind::ShWpkh)),
+            ("wpkh(", ")", Some(ScriptKind::Wpkh)),
+            ("pkh(", ")", Some(ScriptKind::Pkh)),
+            ("tr(", ")", Some(ScriptKind::Tr)),
+        ];
+        let (kind, key_expr) = wrappers
+            .iter()
+            .find_map(|(prefix, suffix, kind)| {
+                desc.strip_prefix(prefix)
+                    .and_then(|d| d.strip_suffix(suffix))
+                    .map(|key| (*kind, key))
+            })
+            .unwrap_or((None, desc));
+        if key_expr.contains('(') {
+            return Err(WalletScan::invalid(
+                descriptor,
+                "only pkh, wpkh, sh(wpkh) and tr are supported",
+            ));
+        }
+        // Key origin, e.g. [d34db33f/84h/0h/0h]
+        let key_expr = match key_expr.strip_prefix('[') {
+            Some(rest) => match rest.split_once(']') {
+                Some((_, key)) => key,
+                None => return Err(WalletScan::invalid(descriptor, "unterminated key origin")),
+            },
+            None => key_expr,
+        };
+        let mut steps = key_expr.split('/');
+        let (key, key_kind) = WalletScan::decode_key(steps.next().unwrap_or_default())
+            .ok_or_else(|| WalletScan::invalid(descriptor, "invalid extended public key"))?;
+        let kind = kind.unwrap_or(key_kind);
+        let steps: Vec<&str> = steps.collect();
+        let steps = match steps.split_last() {
+            Some((&"*", steps)) => steps.to_vec(),
+            Some(_) => {
+                return Err(WalletScan::invalid(
+                    descriptor,
+                    "the derivation path must end with /*",
+                ))
+            }
+            None => vec!["<0;1>"],
+        };
+
+        // Expand the multipath steps
+        let mut paths: Vec<Vec<u32>> = vec![vec![]];
+        for step in steps {
+            let choices = match step.strip_prefix('<').and_then(|s| s.strip_suffix('>')) {
+                Some(choices) => choices.split(';').collect(),
+                None => vec![step],
+            };
+            let choices = choices
+                .iter()
+                .map(|c| c.parse::<u32>().ok().filter(|c| *c < 1 << 31))
+                .collect::<Option<Vec<u32>>>()
+                .ok_or_else(|| {
+                    WalletScan::invalid(
+                        descriptor,
+                        &format!("{} is not an unhardened derivation step", step),
+                    )
+                })?;
+            paths = paths
+                .iter()
+                .flat_map(|path| {
+                    choices.iter().map(move |c| {
+                        let mut path = path.clone();
+                        path.push(*c);
+                        path
+                    })
+                })
+                .collect();
+        }
+
+        paths
+            .into_iter()
+            .map(|path| {
+                let numbers: Vec<ChildNumber> =
+                    path.iter().map(|i| ChildNumber::from(*i)).collect();
+                let key = key
+                    .derive_pub(secp, &numbers)
+                    .map_err(|e| WalletScan::invalid(descriptor, &e.to_string()))?;
+                let path = path
+                    .iter()
+                    .map(u32::to_string)
+                    .collect::<Vec<String>>()
+                    .join("/");
+                Ok(Chain {
+                    descriptor: index,
+                    kind,
+                    key,
+                    path,
+                    derived: 0,
+                })
+            })
+            .collect()
+    }
+
+    /// Decodes an xpub, ypub, zpub or their testnet variants
+    fn decode_key(key: &str) -> Option<(ExtendedPubKey, ScriptKind)> {
+        let mut data = base58::decode_check(key).ok()?;
+        if data.len() != 78 {
+            return None;
+        }
+        let (_, version, kind) = KEY_VERSIONS
+            .iter()
+            .find(|(version, _, _)| data[0..4] == version[..])?;
+        data[0..4].copy_from_slice(version);
+        ExtendedPubKey::decode(&data).ok().map(|key| (key, *kind))
+    }
+
+    fn script(&self, kind: ScriptKind, key: ExtendedPubKey) -> Vec<u8> {
+        let pubkey = key.to_pub();
+        let script = match kind {
+            ScriptKind::Pkh => ScriptBuf::new_p2pkh(&pubkey.pubkey_hash()),
+            // Derived keys are always compressed
+            ScriptKind::Wpkh => ScriptBuf::new_v0_p2wpkh(&pubkey.wpubkey_hash().unwrap()),
+            ScriptKind::ShWpkh => ScriptBuf::new_p2sh(
+                &ScriptBuf::new_v0_p2wpkh(&pubkey.wpubkey_hash().unwrap()).script_hash(),
+            ),
+            ScriptKind::Tr => ScriptBuf::new_v1_p2tr(&self.secp, key.to_x_only_pub(), None),
+        };
+        script.into_bytes()
+    }
+
+    /// Derives the addresses of the chain up to the given number
+    fn derive(&mut self, chain: usize, count: u32) -> OpResult<()> {
+        while self.chains[chain].derived < count {
+            let index = self.chains[chain].derived;
+            let key = self.chains[chain]
+                .key
+                .derive_pub(&self.secp, &[ChildNumber::from(index)])
+                .map_err(|e| OpError::from(e.to_string()))?;
+            let script = self.script(self.chains[chain].kind, key);
+            self.scripts.insert(script, (chain, index));
+            self.chains[chain].derived += 1;
+        }
+        Ok(())
+    }
+
+    /// Returns the derivation path of an address relative to the key of its descriptor
+    fn path(&self, chain: usize, index: u32) -> String {
+        match self.chains[chain].path.as_str() {
+            "" => index.to_string(),
+            path => format!("{}/{}", path, index),
+        }
+    }
+
+    /// Writes the unspent outputs of the wallet, ordered by height and outpoint
+    fn write_unspents(&self, path: PathBuf) -> OpResult<()> {
+        let mut writer = common::create_writer(path)?;
+        writer.write_all(
+            format!(
+                "{};{};{};{};{};{};{}\n",
+                "txid", "indexOut", "height", "value", "address", "descriptor", "path"
+            )
+            .as_bytes(),
+        )?;
+        let mut unspents: Vec<&WalletOutput> = self.unspents.values().collect();
+        unspents.sort_unstable_by_key(|o| (o.height, o.outpoint.txid, o.outpoint.index));
+        for output in unspents {
+            writer.write_all(
+                format!(
+                    "{};{};{};{};{};{};{}\n",
+                    utils::hash_to_string(&output.outpoint.txid),
+                    output.outpoint.index,
+                    output.height,
+                    output.value,
+                    output.address,
+                    self.chains[output.chain].descriptor,
+                    self.path(output.chain, output.index)
+                )
+                .as_bytes(),
+            )?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+impl Callback for WalletScan {
+    fn build_subcommand() -> Command
+    where
+        Self: Sized,
+    {
+        Command::new("walletscan")
+            .about("Dumps the transactions, unspent outputs and balance of a wallet given by descriptors or xpubs")
+            .version("0.1")
+            .author("gcarq <egger.m@protonmail.com>")
+            .arg(
+                Arg::new("dump-folder")
+                    .help("Folder to store csv files")
+                    .index(1)
+                    .required(true),
+            )
+            .arg(
+                Arg::new("descriptor")
+                    .long("descriptor")
+                    .value_name("DESC")
+                    .required(true)
+                    .action(clap::ArgAction::Append)
+                    .help("Output descriptor like wpkh([d34db33f/84h/0h/0h]xpub.../<0;1>/*) or a bare xpub, ypub or zpub. Can be given multiple times"),
+            )
+            .arg(
+                Arg::new("gap-limit")
+                    .long("gap-limit")
+                    .value_name("N")
+                    .value_parser(clap::value_parser!(u32).range(1..))
+                    .default_value("20")
+                    .help("Number of unused addresses derived after the last used one of each chain"),
+            )
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let dump_folder = &PathBuf::from(matches.get_one::<String>("dump-folder").unwrap());
+        let secp = Secp256k1::verification_only();
+        let mut chains = Vec::new();
+        for (i, descriptor) in matches
+            .get_many::<String>("descriptor")
+            .unwrap()
+            .enumerate()
+        {
+            chains.extend(WalletScan::parse_descriptor(&secp, i, descriptor)?);
+        }
+        let mut cb = WalletScan {
+            dump_folder: PathBuf::from(dump_folder),
+            writer: common::create_writer(dump_folder.join("walletscan.csv.tmp"))?,
+            secp,
+            gap_limit: *matches.get_one::<u32>("gap-limit").unwrap(),
+            chains,
+            scripts: HashMap::new(),
+            unspents: HashMap::new(),
+            balance: 0,
+            start_height: 0,
+            n_transactions: 0,
+        };
+        for chain in 0..cb.chains.len() {
+            cb.derive(chain, cb.gap_limit)?;
+        }
+        Ok(cb)
+    }
+
+    fn on_start(&mut self, block_height: u64) -> OpResult<()> {
+        self.start_height = block_height;
+        if block_height > 0 {
+            warn!(target: "callback", "Transactions of the wallet below height {} are not reported!", block_height);
+        }
+        info!(target: "callback", "Executing walletscan for {} derivation chains with dump folder: {} ...",
+              self.chains.len(), &self.dump_folder.display());
+        self.writer.write_all(
+            format!(
+                "{};{};{};{};{};{}\n",
+                "txid", "height", "timestamp", "received", "sent", "balance"
+            )
+            .as_bytes(),
+        )?;
+        Ok(())
+    }
+
+    /// Reports each transaction spending or receiving wallet outputs with the balance afterwards.
+    /// Receiving on an address extends the derivation of its chain by the gap limit.
+    fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<Control> {
+        for tx in &block.txs {
+            let mut sent = 0;
+            let mut touched = false;
+            if !tx.value.is_coinbase() && !self.unspents.is_empty() {
+                for input in &tx.value.inputs {
+                    if let Some(output) = self.unspents.remove(&input.outpoint.key()) {
+                        sent += output.value;
+                        touched = true;
+                    }
+                }
+            }
+            let mut received = 0;
+            for (vout, output) in tx.value.outputs.iter().enumerate() {
+                let (chain, index) = match self.scripts.get(&output.out.script_pubkey) {
+                    Some(location) => *location,
+                    None => continue,
+                };
+                self.derive(chain, index + 1 + self.gap_limit)?;
+                let outpoint = TxOutpoint::new(tx.hash, vout as u32);
+                self.unspents.insert(
+                    outpoint.key(),
+                    WalletOutput {
+                        outpoint,
+                        height: block_height,
+                        value: output.out.value,
+                        address: output.script.address.clone().unwrap_or_default(),
+                        chain,
+                        index,
+                    },
+                );
+                received += output.out.value;
+                touched = true;
+            }
+            if !touched {
+                continue;
+            }
+            self.balance = self.balance + received - sent;
+            self.writer.write_all(
+                format!(
+                    "{};{};{};{};{};{}\n",
+                    utils::hash_to_string(&tx.hash),
+                    block_height,
+                    block.header.value.timestamp,
+                    received,
+                    sent,
+                    self.balance
+                )
+                .as_bytes(),
+            )?;
+            self.n_transactions += 1;
+        }
+        Ok(Control::Continue)
+    }
+
+    fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+        self.writer.flush()?;
+        utils::rename(
+            self.dump_folder.as_path().join("walletscan.csv.tmp"),
+            utils::output_path(
+                &self.dump_folder,
+                "walletscan",
+                self.start_height,
+                block_height,
+                "csv",
+            ),
+        )?;
+        let tmp_path = self.dump_folder.join("walletscan-utxos.csv.tmp");
+        self.write_unspents(tmp_path.clone())?;
+        utils::rename(
+            tmp_path,
+            utils::output_path(
+                &self.dump_folder,
+                "walletscan-utxos",
+                self.start_height,
+                block_height,
+                "csv",
+            ),
+        )?;
+
+        info!(target: "callback", "Done.\nFound {} transactions of the wallet from height {} to {}.\n\
+                                   Balance: {:.8} in {} unspent outputs",
+              self.n_transactions, self.start_height, block_height,
+              self.balance as f64 * 1E-8, self.unspents.len());
+        Ok(())
+    }
+
+    fn flush(&mut self) -> OpResult<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    fn dump_folder(&self) -> Option<&Path> {
+        Some(&self.dump_folder)
+    }
+
+    fn summary(&self) -> Value {
+        json!({
+            "transactions": self.n_transactions,
+            "unspents": self.unspents.len(),
+            "balance": self.balance,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::testutils;
+    use bitcoin::bip32::ExtendedPrivKey;
+    use bitcoin::hashes::{sha256d, Hash};
+    use bitcoin::Network;
+    use std::fs;
+
+    fn xpub() -> ExtendedPubKey {
+        let secp = Secp256k1::new();
+        let xprv = ExtendedPrivKey::new_master(Network::Bitcoin, &[0x42; 32]).unwrap();
+        ExtendedPubKey::from_priv(&secp, &xprv)
+    }
+
+    fn wpkh_script(path: &[u32]) -> Vec<u8> {
+        let path: Vec<ChildNumber> = path.iter().map(|i| ChildNumber::from(*i)).collect();
+        let key = xpub().derive_pub(&Secp256k1::new(), &path).unwrap();
+        ScriptBuf::new_v0_p2wpkh(&key.to_pub().wpubkey_hash().unwrap()).into_bytes()
+    }
+
+    #[test]
+    fn test_parse_descriptor() {
+        let secp = Secp256k1::verification_only();
+        let xpub = xpub().to_string();
+        let chains = WalletScan::parse_descriptor(
+            &secp,
+            1,
+            &format!("wpkh([d34db33f/84h/0h/0h]{}/<0;1>/*)#8x7wx3k2", xpub),
+        )
+        .unwrap();
+        let paths: Vec<&str> = chains.iter().map(|c| c.path.as_str()).collect();
+        assert_eq!(paths, vec!["0", "1"]);
+        assert!(chains
+            .iter()
+            .all(|c| c.kind == ScriptKind::Wpkh && c.descriptor == 1));
+
+        // zpub implies wpkh, without path the receive and change chains are scanned
+        let mut data = base58::decode_check(&xpub).unwrap();
+        data[0..4].copy_from_slice(&[0x04, 0xb2, 0x47, 0x46]);
+        let zpub = base58::encode_check(&data);
+        let chains = WalletScan::parse_descriptor(&secp, 0, &zpub).unwrap();
+        assert_eq!(chains.len(), 2);
+        assert_eq!(chains[1].kind, ScriptKind::Wpkh);
+        assert_eq!(chains[1].path, "1");
+        let chains = WalletScan::parse_descriptor(&secp, 0, &format!("tr({}/7/*)", xpub)).unwrap();
+        assert_eq!(chains.len(), 1);
+        assert_eq!(chains[0].kind, ScriptKind::Tr);
+
+        for invalid in [
+            format!("wpkh({}/0h/*)", xpub),
+            format!("wpkh({}/0)", xpub),
+            format!("sh(multi(1,{}/0/*))", xpub),
+            String::from("wpkh(xpub123/0/*)"),
+        ] {
+            assert!(WalletScan::parse_descriptor(&secp, 0, &invalid).is_err());
+        }
+    }
+
+    #[test]
+    fn test_walletscan() {
+        let block1 = testutils::block(
+            sha256d::Hash::all_zeros(),
+            0,
+            vec![testutils::coinbase(
+                1,
+                &[
+                    (5000, wpkh_script(&[0, 1])),
+                    (1000, testutils::p2pkh_script(1)),
+                ],
+            )],
+        );
+        let block2 = testutils::block(
+            block1.header.hash,
+            600,
+            vec![
+                testutils::coinbase(2, &[(5000, testutils::p2pkh_script(2))]),
+                // Beyond the gap limit of the initial derivation, but within the one of address 1
+                testutils::tx(
+                    &[(sha256d::Hash::hash(&[1]), 0)],
+                    &[(1000, wpkh_script(&[0, 3]))],
+                ),
+                testutils::tx(
+                    &[(block1.txs[0].hash, 0)],
+                    &[
+                        (2000, testutils::p2pkh_script(3)),
+                        (2900, wpkh_script(&[1, 0])),
+                    ],
+                ),
+                testutils::tx(
+                    &[(block1.txs[0].hash, 1)],
+                    &[(900, testutils::p2pkh_script(4))],
+                ),
+            ],
+        );
+
+        let dump_folder = tempfile::tempdir().unwrap();
+        let matches = WalletScan::build_subcommand().get_matches_from([
+            "walletscan",
+            dump_folder.path().to_str().unwrap(),
+            "--descriptor",
+            &format!("wpkh({}/<0;1>/*)", xpub()),
+            "--gap-limit",
+            "2",
+        ]);
+        let mut cb = WalletScan::new(&matches).unwrap();
+        cb.on_start(1).unwrap();
+        cb.on_block(&block1, 1).unwrap();
+        cb.on_block(&block2, 2).unwrap();
+        cb.on_complete(2).unwrap();
+
+        let txid = |block: &Block, i: usize| utils::hash_to_string(&block.txs[i].hash);
+        let csv = fs::read_to_string(dump_folder.path().join("walletscan-1-2.csv")).unwrap();
+        assert_eq!(
+            csv.lines().collect::<Vec<&str>>(),
+            vec![
+                String::from("txid;height;timestamp;received;sent;balance"),
+                format!("{};1;0;5000;0;5000", txid(&block1, 0)),
+                format!("{};2;600;1000;0;6000", txid(&block2, 1)),
+                format!("{};2;600;2900;5000;3900", txid(&block2, 2)),
+            ]
+        );
+
+        let address = |i: usize, vout: usize| {
+            block2.txs[i].value.outputs[vout]
+                .script
+                .address
+                .clone()
+                .unwrap()
+        };
+        let csv = fs::read_to_string(dump_folder.path().join("walletscan-utxos-1-2.csv")).unwrap();
+        let mut rows: Vec<&str> = csv.lines().skip(1).collect();
+        rows.sort_unstable();
+        let mut expected = vec![
+            format!("{};0;2;1000;{};0;0/3", txid(&block2, 1), address(1, 0)),
+            format!("{};1;2;2900;{};0;1/0", txid(&block2, 2), address(2, 1)),
+        ];
+        expected.sort_unstable();
+        assert_eq!(rows, expected);
+        assert_eq!(cb.summary()["balance"], 3900);
+        assert_eq!(cb.summary()["unspents"], 2);
+    }
+}
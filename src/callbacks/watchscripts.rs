@@ -0,0 +1,301 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use bitcoin::hashes::hex::FromHex;
+use clap::{Arg, ArgMatches, Command};
+
+use crate::blockchain::proto::block::Block;
+use crate::blockchain::proto::tx::{OutpointKey, TxOutpoint};
+use crate::callbacks::{common, Callback, Control};
+use crate::common::address::CompactAddress;
+use crate::common::utils;
+use crate::errors::{OpError, OpErrorKind, OpResult};
+
+/// Output of a watched script which has not been spent yet
+struct WatchedOutput {
+    value: u64,
+    script_pubkey: Vec<u8>,
+}
+
+/// Dumps all transactions creating or spending outputs with one of the watched scriptPubKeys
+pub struct WatchScripts {
+    dump_folder: PathBuf,
+    writer: common::DumpWriter,
+
+    scripts: HashSet<Vec<u8>>,
+    // key: txid + index
+    unspents: HashMap<OutpointKey, WatchedOutput>,
+
+    start_height: u64,
+    n_created: u64,
+    n_spent: u64,
+}
+
+impl WatchScripts {
+    /// Reads one hex encoded scriptPubKey per line, empty lines and lines starting with # are skipped
+    fn read_scripts(path: &Path) -> OpResult<HashSet<Vec<u8>>> {
+        let mut scripts = HashSet::new();
+        for (i, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let script = Vec::<u8>::from_hex(line).map_err(|_| {
+                OpError::new(OpErrorKind::InvalidArgsError).join_msg(&format!(
+                    "Invalid script in {} line {}: {}",
+                    path.display(),
+                    i + 1,
+                    line
+                ))
+            })?;
+            scripts.insert(script);
+        }
+        Ok(scripts)
+    }
+}
+
+impl Callback for WatchScripts {
+    fn build_subcommand() -> Command
+    where
+        Self: Sized,
+    {
+        Command::new("watchscripts")
+            .about("Dumps transactions creating or spending outputs with the watched scripts to CSV file")
+            .version("0.1")
+            .author("gcarq <egger.m@protonmail.com>")
+            .arg(
+                Arg::new("dump-folder")
+                    .help("Folder to store csv file")
+                    .index(1)
+                    .required(true),
+            )
+            .arg(
+                Arg::new("scripts-file")
+                    .long("scripts-file")
+                    .value_name("FILE")
+                    .required(true)
+                    .help("File with one hex encoded scriptPubKey per line"),
+            )
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let dump_folder = &PathBuf::from(matches.get_one::<String>("dump-folder").unwrap());
+        let scripts_file = PathBuf::from(matches.get_one::<String>("scripts-file").unwrap());
+        let cb = WatchScripts {
+            dump_folder: PathBuf::from(dump_folder),
+            writer: common::create_writer(dump_folder.join("watchscripts.csv.tmp"))?,
+            scripts: WatchScripts::read_scripts(&scripts_file)?,
+            unspents: HashMap::new(),
+            start_height: 0,
+            n_created: 0,
+            n_spent: 0,
+        };
+        Ok(cb)
+    }
+
+    fn on_start(&mut self, block_height: u64) -> OpResult<()> {
+        self.start_height = block_height;
+        if block_height > 0 {
+            warn!(target: "callback", "Spends of watched outputs below height {} are not reported!", block_height);
+        }
+        info!(target: "callback", "Executing watchscripts for {} scripts with dump folder: {} ...",
+              self.scripts.len(), &self.dump_folder.display());
+        self.writer.write_all(
+            format!(
+                "{};{};{};{};{};{}\n",
+                "height", "txid", "event", "index", "value", "script"
+            )
+            .as_bytes(),
+        )?;
+        Ok(())
+    }
+
+    /// Reports created outputs with their vout and spent outputs with the vin of the spending input
+    fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<Control> {
+        for tx in &block.txs {
+            let txid = utils::hash_to_string(&tx.hash);
+            if !tx.value.is_coinbase() && !self.unspents.is_empty() {
+                for (vin, input) in tx.value.inputs.iter().enumerate() {
+                    if let Some(output) = self.unspents.remove(&input.outpoint.key()) {
+                        self.writer.write_all(
+                            format!(
+                                "{};{};spent;{};{};{}\n",
+                                block_height,
+                                txid,
+                                vin,
+                                output.value,
+                                utils::arr_to_hex(&output.script_pubkey)
+                            )
+                            .as_bytes(),
+                        )?;
+                        self.n_spent += 1;
+                    }
+                }
+            }
+            for (vout, output) in tx.value.outputs.iter().enumerate() {
+                if !self.scripts.contains(&output.out.script_pubkey) {
+                    continue;
+                }
+                self.writer.write_all(
+                    format!(
+                        "{};{};created;{};{};{}\n",
+                        block_height,
+                        txid,
+                        vout,
+                        output.out.value,
+                        utils::arr_to_hex(&output.out.script_pubkey)
+                    )
+                    .as_bytes(),
+                )?;
+                self.unspents.insert(
+                    TxOutpoint::new(tx.hash, vout as u32).key(),
+                    WatchedOutput {
+                        value: output.out.value,
+                        script_pubkey: output.out.script_pubkey.clone(),
+                    },
+                );
+                self.n_created += 1;
+            }
+        }
+        Ok(Control::Continue)
+    }
+
+    fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+        self.writer.flush()?;
+        utils::rename(
+            self.dump_folder.as_path().join("watchscripts.csv.tmp"),
+            utils::output_path(
+                &self.dump_folder,
+                "watchscripts",
+                self.start_height,
+                block_height,
+                "csv",
+            ),
+        )?;
+
+        info!(target: "callback", "Done.\nFound {} created and {} spent watched outputs from height {} to {}.",
+             self.n_created, self.n_spent, self.start_height, block_height);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> OpResult<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    fn dump_folder(&self) -> Option<&Path> {
+        Some(&self.dump_folder)
+    }
+
+    fn supports_incremental(&self) -> bool {
+        true
+    }
+
+    fn save_state(&self, folder: &Path) -> OpResult<()> {
+        let unspents = self
+            .unspents
+            .iter()
+            .map(|(key, output)| {
+                let unspent = common::UnspentValue {
+                    block_height: 0,
+                    value: output.value,
+                    address: CompactAddress::new(&utils::arr_to_hex(&output.script_pubkey)),
+                };
+                (*key, unspent)
+            })
+            .collect();
+        common::save_unspents(&folder.join("watchscripts.unspents"), &unspents)
+    }
+
+    fn load_state(&mut self, folder: &Path) -> OpResult<()> {
+        let path = folder.join("watchscripts.unspents");
+        if !path.exists() {
+            return Ok(());
+        }
+        for (key, unspent) in common::load_unspents(&path)? {
+            let output = WatchedOutput {
+                value: unspent.value,
+                script_pubkey: utils::hex_to_vec(&unspent.address.to_string()),
+            };
+            self.unspents.insert(key, output);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::testutils;
+    use bitcoin::hashes::{sha256d, Hash};
+    use std::fs;
+
+    #[test]
+    fn test_watchscripts() {
+        // 1-of-1 bare multisig: OP_1 <33 byte pubkey> OP_1 OP_CHECKMULTISIG
+        let mut multisig = vec![0x51, 0x21, 0x02];
+        multisig.extend_from_slice(&[0x11; 32]);
+        multisig.extend_from_slice(&[0x51, 0xae]);
+        let hex = utils::arr_to_hex(&multisig);
+
+        let creating = testutils::tx(
+            &[(sha256d::Hash::hash(&[1]), 0)],
+            &[(1000, testutils::p2pkh_script(1)), (2000, multisig.clone())],
+        );
+        let block1 = testutils::block(
+            sha256d::Hash::all_zeros(),
+            0,
+            vec![
+                testutils::coinbase(1, &[(5000, testutils::p2pkh_script(0))]),
+                creating,
+            ],
+        );
+        let spending = testutils::tx(
+            &[(block1.txs[1].hash, 0), (block1.txs[1].hash, 1)],
+            &[(2900, testutils::p2pkh_script(2))],
+        );
+        let block2 = testutils::block(
+            block1.header.hash,
+            600,
+            vec![
+                testutils::coinbase(2, &[(5000, testutils::p2pkh_script(0))]),
+                spending,
+            ],
+        );
+
+        let dump_folder = tempfile::tempdir().unwrap();
+        let scripts_file = dump_folder.path().join("scripts.txt");
+        fs::write(&scripts_file, format!("# bare multisig\n{}\n\n", hex)).unwrap();
+        let matches = WatchScripts::build_subcommand().get_matches_from([
+            "watchscripts",
+            dump_folder.path().to_str().unwrap(),
+            "--scripts-file",
+            scripts_file.to_str().unwrap(),
+        ]);
+        let mut cb = WatchScripts::new(&matches).unwrap();
+        cb.on_start(1).unwrap();
+        cb.on_block(&block1, 1).unwrap();
+        cb.on_block(&block2, 2).unwrap();
+        cb.on_complete(2).unwrap();
+
+        let csv = fs::read_to_string(dump_folder.path().join("watchscripts-1-2.csv")).unwrap();
+        let rows: Vec<&str> = csv.lines().skip(1).collect();
+        let txid = |b: &Block| utils::hash_to_string(&b.txs[1].hash);
+        assert_eq!(
+            rows,
+            vec![
+                format!("1;{};created;1;2000;{}", txid(&block1), hex),
+                format!("2;{};spent;1;2000;{}", txid(&block2), hex),
+            ]
+        );
+
+        fs::write(&scripts_file, "51ae\nxyz\n").unwrap();
+        assert!(WatchScripts::new(&matches).is_err());
+    }
+}
@@ -0,0 +1,243 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use clap::{Arg, ArgMatches, Command};
+use serde_json::{json, Value};
+
+use crate::blockchain::proto::block::Block;
+use crate::blockchain::proto::script::witness;
+use crate::callbacks::{common, Callback, Control};
+use crate::common::utils;
+use crate::errors::OpResult;
+
+const SPEND_TYPES: [&str; 6] = [
+    "p2wpkh",
+    "p2sh_p2wpkh",
+    "p2wsh",
+    "p2sh_p2wsh",
+    "p2tr_keypath",
+    "p2tr_scriptpath",
+];
+
+/// Dumps the witness stack of each segwit input along with the spend type inferred from it.
+/// Unlike `witnesses` it writes one row per input, the items are comma separated.
+/// The coinbase input is left out, its witness only holds the commitment reserved value.
+pub struct WitnessDump {
+    dump_folder: PathBuf,
+    writer: common::DumpWriter,
+    compression: Option<utils::Compression>,
+
+    /// Spend types to dump, all if None
+    types: Option<Vec<String>>,
+
+    start_height: u64,
+    /// Number of dumped inputs per spend type
+    counts: HashMap<String, u64>,
+}
+
+impl Callback for WitnessDump {
+    fn build_subcommand() -> Command
+    where
+        Self: Sized,
+    {
+        Command::new("witnessdump")
+            .about(
+                "Dumps the witness stack and inferred spend type of each segwit input to CSV file",
+            )
+            .version("0.1")
+            .author("gcarq <egger.m@protonmail.com>")
+            .arg(
+                Arg::new("dump-folder")
+                    .help("Folder to store csv file")
+                    .index(1)
+                    .required(true),
+            )
+            .arg(
+                Arg::new("only-spend-type")
+                    .long("only-spend-type")
+                    .value_name("TYPE")
+                    .value_delimiter(',')
+                    .value_parser(SPEND_TYPES)
+                    .help(
+                        "Only dumps inputs with the given inferred spend types (comma separated)",
+                    ),
+            )
+            .arg(common::gzip_arg())
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let dump_folder = &PathBuf::from(matches.get_one::<String>("dump-folder").unwrap());
+        let compression = common::dump_compression(matches);
+        let cb = WitnessDump {
+            dump_folder: PathBuf::from(dump_folder),
+            writer: common::DumpWriter::create(
+                dump_folder.join(format!(
+                    "witnessdump.{}.tmp",
+                    common::csv_extension(compression)
+                )),
+                compression,
+            )?,
+            compression,
+            types: matches
+                .get_many::<String>("only-spend-type")
+                .map(|types| types.cloned().collect()),
+            start_height: 0,
+            counts: HashMap::new(),
+        };
+        Ok(cb)
+    }
+
+    fn on_start(&mut self, block_height: u64) -> OpResult<()> {
+        self.start_height = block_height;
+        info!(target: "callback", "Executing witnessdump with dump folder: {} ...", &self.dump_folder.display());
+        self.writer.write_all(
+            format!(
+                "{};{};{};{};{};{};{};{}\n",
+                "height",
+                "txid",
+                "vin",
+                "spend_type",
+                "item_count",
+                "witness_size",
+                "item_sizes",
+                "items_hex"
+            )
+            .as_bytes(),
+        )?;
+        Ok(())
+    }
+
+    fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<Control> {
+        for tx in block.txs.iter().skip(1) {
+            let txid = utils::hash_to_string(&tx.hash);
+            for (vin, input) in tx.value.inputs.iter().enumerate() {
+                let spend_type = match witness::spend_type(input) {
+                    Some(spend_type) => spend_type.to_string(),
+                    None => continue,
+                };
+                if let Some(types) = &self.types {
+                    if !types.contains(&spend_type) {
+                        continue;
+                    }
+                }
+                let sizes: Vec<String> =
+                    input.witness.iter().map(|i| i.len().to_string()).collect();
+                let items: Vec<String> =
+                    input.witness.iter().map(|i| utils::arr_to_hex(i)).collect();
+                self.writer.write_all(
+                    format!(
+                        "{};{};{};{};{};{};{};{}\n",
+                        block_height,
+                        txid,
+                        vin,
+                        spend_type,
+                        input.witness.len(),
+                        input.witness_size(),
+                        sizes.join(","),
+                        items.join(",")
+                    )
+                    .as_bytes(),
+                )?;
+                *self.counts.entry(spend_type).or_insert(0) += 1;
+            }
+        }
+        Ok(Control::Continue)
+    }
+
+    fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+        self.writer.finish()?;
+        let ext = common::csv_extension(self.compression);
+        utils::rename(
+            self.dump_folder
+                .as_path()
+                .join(format!("witnessdump.{}.tmp", ext)),
+            utils::output_path(
+                &self.dump_folder,
+                "witnessdump",
+                self.start_height,
+                block_height,
+                ext,
+            ),
+        )?;
+
+        info!(target: "callback", "Done.\nDumped the witnesses of {} inputs from height {} to {}.",
+             self.counts.values().sum::<u64>(), self.start_height, block_height);
+        Ok(())
+    }
+
+    fn supports_reverse(&self) -> bool {
+        true
+    }
+
+    fn flush(&mut self) -> OpResult<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    fn dump_folder(&self) -> Option<&Path> {
+        Some(&self.dump_folder)
+    }
+
+    fn supports_incremental(&self) -> bool {
+        true
+    }
+
+    fn summary(&self) -> Value {
+        json!({ "inputs": self.counts })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::testutils;
+    use bitcoin::hashes::{sha256d, Hash};
+    use std::fs;
+
+    fn run(block: &Block, args: &[&str]) -> Vec<String> {
+        let dump_folder = tempfile::tempdir().unwrap();
+        let mut cli = vec!["witnessdump", dump_folder.path().to_str().unwrap()];
+        cli.extend_from_slice(args);
+        let matches = WitnessDump::build_subcommand().get_matches_from(cli);
+        let mut cb = WitnessDump::new(&matches).unwrap();
+        cb.on_start(1).unwrap();
+        cb.on_block(block, 1).unwrap();
+        cb.on_complete(1).unwrap();
+
+        let csv = fs::read_to_string(dump_folder.path().join("witnessdump-1-1.csv")).unwrap();
+        csv.lines().skip(1).map(String::from).collect()
+    }
+
+    #[test]
+    fn test_witnessdump() {
+        let outpoint = |i: u8| (sha256d::Hash::hash(&[i]), 0);
+        let mut spend = testutils::tx(
+            &[outpoint(1), outpoint(2), outpoint(3)],
+            &[(1000, testutils::p2pkh_script(1))],
+        );
+        spend.inputs[0].witness = vec![vec![0xaa; 64]];
+        // Legacy input
+        spend.inputs[1].witness = Vec::new();
+        spend.inputs[2].witness = vec![Vec::new(), vec![0x51, 0xae]];
+        let mut coinbase = testutils::coinbase(1, &[(5000, testutils::p2pkh_script(0))]);
+        coinbase.inputs[0].witness = vec![vec![0; 32]];
+        let block = testutils::block(sha256d::Hash::all_zeros(), 0, vec![coinbase, spend]);
+        let txid = utils::hash_to_string(&block.txs[1].hash);
+
+        assert_eq!(
+            run(&block, &[]),
+            vec![
+                format!("1;{};0;p2tr_keypath;1;66;64;{}", txid, "aa".repeat(64)),
+                format!("1;{};2;p2wsh;2;5;0,2;,51ae", txid),
+            ]
+        );
+        assert_eq!(
+            run(&block, &["--only-spend-type", "p2wsh,p2wpkh"]),
+            vec![format!("1;{};2;p2wsh;2;5;0,2;,51ae", txid)]
+        );
+    }
+}
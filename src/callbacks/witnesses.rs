@@ -0,0 +1,254 @@
+use std::collections::HashSet;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use clap::{Arg, ArgMatches, Command};
+
+use crate::blockchain::proto::block::Block;
+use crate::blockchain::proto::tx::{OutpointKey, TxOutpoint};
+use crate::callbacks::{common, Callback, Control};
+use crate::common::utils;
+use crate::errors::OpResult;
+
+/// Dumps every item of the input witness stacks
+pub struct Witnesses {
+    dump_folder: PathBuf,
+    writer: common::DumpWriter,
+    compression: Option<utils::Compression>,
+
+    /// Script types of the spent outputs to dump, all inputs if None
+    types: Option<Vec<String>>,
+    // txid + index of the unspent outputs with one of the selected types
+    unspents: HashSet<OutpointKey>,
+
+    start_height: u64,
+    n_items: u64,
+}
+
+impl Witnesses {
+    /// Remembers the outputs matching the type filter, so their spends can be recognized
+    fn track_outputs(&mut self, block: &Block) {
+        let types = match &self.types {
+            Some(types) => types,
+            None => return,
+        };
+        for tx in &block.txs {
+            for (i, output) in tx.value.outputs.iter().enumerate() {
+                let name = output.script.pattern.short_name();
+                if types.iter().any(|t| t == name) {
+                    self.unspents
+                        .insert(TxOutpoint::new(tx.hash, i as u32).key());
+                }
+            }
+        }
+    }
+}
+
+impl Callback for Witnesses {
+    fn build_subcommand() -> Command
+    where
+        Self: Sized,
+    {
+        Command::new("witnesses")
+            .about("Dumps the witness stack items of all inputs to CSV file")
+            .version("0.1")
+            .author("gcarq <egger.m@protonmail.com>")
+            .arg(
+                Arg::new("dump-folder")
+                    .help("Folder to store csv file")
+                    .index(1)
+                    .required(true),
+            )
+            .arg(
+                Arg::new("only-type")
+                    .long("only-type")
+                    .value_name("TYPE")
+                    .value_delimiter(',')
+                    .value_parser(common::SCRIPT_TYPES)
+                    .help("Only dumps inputs spending outputs with the given script types (comma separated)"),
+            )
+            .arg(common::gzip_arg())
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let dump_folder = &PathBuf::from(matches.get_one::<String>("dump-folder").unwrap());
+        let compression = common::dump_compression(matches);
+        let cb = Witnesses {
+            dump_folder: PathBuf::from(dump_folder),
+            writer: common::DumpWriter::create(
+                dump_folder.join(format!(
+                    "witnesses.{}.tmp",
+                    common::csv_extension(compression)
+                )),
+                compression,
+            )?,
+            compression,
+            types: matches
+                .get_many::<String>("only-type")
+                .map(|types| types.cloned().collect()),
+            unspents: HashSet::new(),
+            start_height: 0,
+            n_items: 0,
+        };
+        Ok(cb)
+    }
+
+    fn on_start(&mut self, block_height: u64) -> OpResult<()> {
+        self.start_height = block_height;
+        if self.types.is_some() && block_height > 0 {
+            warn!(target: "callback", "Script types of outputs below height {} are unknown, their spends are skipped!", block_height);
+        }
+        info!(target: "callback", "Executing witnesses with dump folder: {} ...", &self.dump_folder.display());
+        self.writer.write_all(
+            format!(
+                "{};{};{};{};{};{}\n",
+                "height", "txid", "vin", "stack_index", "item_hex", "item_len"
+            )
+            .as_bytes(),
+        )?;
+        Ok(())
+    }
+
+    fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<Control> {
+        // Outputs can be spent within the same block
+        self.track_outputs(block);
+        for tx in &block.txs {
+            let txid = utils::hash_to_string(&tx.hash);
+            for (vin, input) in tx.value.inputs.iter().enumerate() {
+                if self.types.is_some() && !self.unspents.remove(&input.outpoint.key()) {
+                    continue;
+                }
+                for (index, item) in input.witness.iter().enumerate() {
+                    self.writer.write_all(
+                        format!(
+                            "{};{};{};{};{};{}\n",
+                            block_height,
+                            txid,
+                            vin,
+                            index,
+                            utils::arr_to_hex(item),
+                            item.len()
+                        )
+                        .as_bytes(),
+                    )?;
+                    self.n_items += 1;
+                }
+            }
+        }
+        Ok(Control::Continue)
+    }
+
+    fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+        self.writer.finish()?;
+        let ext = common::csv_extension(self.compression);
+        utils::rename(
+            self.dump_folder
+                .as_path()
+                .join(format!("witnesses.{}.tmp", ext)),
+            utils::output_path(
+                &self.dump_folder,
+                "witnesses",
+                self.start_height,
+                block_height,
+                ext,
+            ),
+        )?;
+
+        info!(target: "callback", "Done.\nDumped {} witness items from height {} to {}.",
+             self.n_items, self.start_height, block_height);
+        Ok(())
+    }
+
+    /// --only-type tracks the outputs of previous blocks
+    fn supports_reverse(&self) -> bool {
+        self.types.is_none()
+    }
+
+    fn flush(&mut self) -> OpResult<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    fn dump_folder(&self) -> Option<&Path> {
+        Some(&self.dump_folder)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::testutils;
+    use bitcoin::hashes::{sha256d, Hash};
+    use std::fs;
+
+    fn run(blocks: &[Block], args: &[&str]) -> Vec<String> {
+        let dump_folder = tempfile::tempdir().unwrap();
+        let mut cli = vec!["witnesses", dump_folder.path().to_str().unwrap()];
+        cli.extend_from_slice(args);
+        let matches = Witnesses::build_subcommand().get_matches_from(cli);
+        let mut cb = Witnesses::new(&matches).unwrap();
+        cb.on_start(0).unwrap();
+        for (height, block) in blocks.iter().enumerate() {
+            cb.on_block(block, height as u64).unwrap();
+        }
+        cb.on_complete(blocks.len() as u64 - 1).unwrap();
+
+        let csv = fs::read_to_string(
+            dump_folder
+                .path()
+                .join(format!("witnesses-0-{}.csv", blocks.len() - 1)),
+        )
+        .unwrap();
+        csv.lines().skip(1).map(String::from).collect()
+    }
+
+    #[test]
+    fn test_witnesses() {
+        let mut p2tr = vec![0x51, 0x20];
+        p2tr.extend_from_slice(&[9; 32]);
+        let funding = testutils::block(
+            sha256d::Hash::all_zeros(),
+            0,
+            vec![testutils::coinbase(
+                0,
+                &[(1000, p2tr), (1000, testutils::p2pkh_script(1))],
+            )],
+        );
+        let cb_txid = funding.txs[0].hash;
+        let mut spend = testutils::tx(
+            &[(cb_txid, 0), (cb_txid, 1)],
+            &[(1500, testutils::p2pkh_script(2))],
+        );
+        spend.inputs[0].witness = vec![vec![0xaa, 0xbb], vec![0xcc]];
+        spend.inputs[1].witness = vec![vec![0xdd]];
+        let block = testutils::block(
+            funding.header.hash,
+            600,
+            vec![
+                testutils::coinbase(1, &[(5000, testutils::p2pkh_script(0))]),
+                spend,
+            ],
+        );
+        let txid = utils::hash_to_string(&block.txs[1].hash);
+        let blocks = [funding, block];
+
+        assert_eq!(
+            run(&blocks, &[]),
+            vec![
+                format!("1;{};0;0;aabb;2", txid),
+                format!("1;{};0;1;cc;1", txid),
+                format!("1;{};1;0;dd;1", txid),
+            ]
+        );
+        assert_eq!(
+            run(&blocks, &["--only-type", "p2tr"]),
+            vec![
+                format!("1;{};0;0;aabb;2", txid),
+                format!("1;{};0;1;cc;1", txid),
+            ]
+        );
+    }
+}
@@ -0,0 +1,206 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use bitcoin::Script;
+use clap::{Arg, ArgMatches, Command};
+use serde_json::{json, Value};
+
+use crate::blockchain::proto::block::Block;
+use crate::callbacks::{common, Callback, Control};
+use crate::common::utils;
+use crate::errors::OpResult;
+
+/// Number and value of outputs of one witness version
+#[derive(Clone, Copy, Default)]
+struct Tally {
+    count: u64,
+    value: u64,
+}
+
+/// Dumps per block the number and value of outputs paying to witness version 0 (p2wpkh and p2wsh)
+/// and witness version 1 programs (p2tr, apart from rare non-standard program lengths),
+/// e.g. to follow the taproot adoption weighted by value.
+pub struct WitnessVersions {
+    dump_folder: PathBuf,
+    writer: common::DumpWriter,
+
+    start_height: u64,
+    // Totals over all blocks for v0 and v1
+    totals: [Tally; 2],
+}
+
+impl WitnessVersions {
+    /// Returns the witness version of a witness program (BIP141), None for other scripts
+    fn witness_version(script_pubkey: &[u8]) -> Option<u8> {
+        let script = Script::from_bytes(script_pubkey);
+        if !script.is_witness_program() {
+            return None;
+        }
+        script.witness_version().map(|version| version.to_num())
+    }
+}
+
+impl Callback for WitnessVersions {
+    fn build_subcommand() -> Command
+    where
+        Self: Sized,
+    {
+        Command::new("witnessversions")
+            .about("Dumps the number and value of SegWit v0 and v1 (taproot) outputs per block to CSV file")
+            .version("0.1")
+            .author("gcarq <egger.m@protonmail.com>")
+            .arg(
+                Arg::new("dump-folder")
+                    .help("Folder to store csv file")
+                    .index(1)
+                    .required(true),
+            )
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let dump_folder = &PathBuf::from(matches.get_one::<String>("dump-folder").unwrap());
+        let cb = WitnessVersions {
+            dump_folder: PathBuf::from(dump_folder),
+            writer: common::create_writer(dump_folder.join("witnessversions.csv.tmp"))?,
+            start_height: 0,
+            totals: [Tally::default(); 2],
+        };
+        Ok(cb)
+    }
+
+    fn on_start(&mut self, block_height: u64) -> OpResult<()> {
+        self.start_height = block_height;
+        info!(target: "callback", "Executing witnessversions with dump folder: {} ...", &self.dump_folder.display());
+        self.writer.write_all(
+            format!(
+                "{};{};{};{};{}\n",
+                "height", "v0_count", "v0_value", "v1_count", "v1_value"
+            )
+            .as_bytes(),
+        )?;
+        Ok(())
+    }
+
+    fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<Control> {
+        let mut tallies = [Tally::default(); 2];
+        for tx in &block.txs {
+            for output in &tx.value.outputs {
+                if let Some(version @ (0 | 1)) =
+                    WitnessVersions::witness_version(&output.out.script_pubkey)
+                {
+                    let tally = &mut tallies[version as usize];
+                    tally.count += 1;
+                    tally.value += output.out.value;
+                }
+            }
+        }
+        self.writer.write_all(
+            format!(
+                "{};{};{};{};{}\n",
+                block_height,
+                tallies[0].count,
+                tallies[0].value,
+                tallies[1].count,
+                tallies[1].value
+            )
+            .as_bytes(),
+        )?;
+        for (total, tally) in self.totals.iter_mut().zip(tallies) {
+            total.count += tally.count;
+            total.value += tally.value;
+        }
+        Ok(Control::Continue)
+    }
+
+    fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+        self.writer.flush()?;
+        utils::rename(
+            self.dump_folder.as_path().join("witnessversions.csv.tmp"),
+            utils::output_path(
+                &self.dump_folder,
+                "witnessversions",
+                self.start_height,
+                block_height,
+                "csv",
+            ),
+        )?;
+
+        info!(target: "callback", "Done.\nCounted {} v0 outputs with {:.8} and {} v1 outputs with {:.8} from height {} to {}.",
+             self.totals[0].count, self.totals[0].value as f64 * 1E-8,
+             self.totals[1].count, self.totals[1].value as f64 * 1E-8,
+             self.start_height, block_height);
+        Ok(())
+    }
+
+    fn supports_reverse(&self) -> bool {
+        true
+    }
+
+    fn flush(&mut self) -> OpResult<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    fn dump_folder(&self) -> Option<&Path> {
+        Some(&self.dump_folder)
+    }
+
+    fn supports_incremental(&self) -> bool {
+        true
+    }
+
+    fn summary(&self) -> Value {
+        json!({
+            "v0_count": self.totals[0].count,
+            "v0_value": self.totals[0].value,
+            "v1_count": self.totals[1].count,
+            "v1_value": self.totals[1].value,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::testutils;
+    use bitcoin::hashes::{sha256d, Hash};
+    use std::fs;
+
+    #[test]
+    fn test_witnessversions() {
+        let mut p2wpkh = vec![0x00, 0x14];
+        p2wpkh.extend([0x11; 20]);
+        let mut p2tr = vec![0x51, 0x20];
+        p2tr.extend([0x22; 32]);
+        let block = testutils::block(
+            sha256d::Hash::all_zeros(),
+            0,
+            vec![testutils::coinbase(
+                1,
+                &[
+                    (1000, testutils::p2pkh_script(1)),
+                    (2000, p2wpkh),
+                    (3000, p2tr),
+                ],
+            )],
+        );
+
+        let dump_folder = tempfile::tempdir().unwrap();
+        let matches = WitnessVersions::build_subcommand()
+            .get_matches_from(["witnessversions", dump_folder.path().to_str().unwrap()]);
+        let mut cb = WitnessVersions::new(&matches).unwrap();
+        cb.on_start(1).unwrap();
+        cb.on_block(&block, 1).unwrap();
+        cb.on_complete(1).unwrap();
+
+        let csv = fs::read_to_string(dump_folder.path().join("witnessversions-1-1.csv")).unwrap();
+        assert_eq!(csv.lines().nth(1).unwrap(), "1;1;2000;1;3000");
+        assert_eq!(
+            WitnessVersions::witness_version(&testutils::p2pkh_script(1)),
+            None
+        );
+    }
+}
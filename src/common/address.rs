@@ -0,0 +1,172 @@
+//! Compact in-memory form of the addresses kept along with unspent outputs. Base58Check and
+//! segwit addresses are stored as their decoded payload, which takes about half the bytes of the
+//! string, and encoded again when they are dumped. Other strings, like CashAddr addresses or the
+//! pseudo-addresses of `--bucket-nonstandard`, are kept as they are.
+use std::fmt;
+
+use bitcoin::base58;
+use bitcoin::bech32::{self, FromBase32, ToBase32, Variant};
+
+use crate::errors::{OpError, OpErrorKind, OpResult};
+
+const TAG_RAW: u8 = 0;
+/// Version bytes and hash, without the checksum
+const TAG_BASE58: u8 = 1;
+/// Length of the human readable part (u8), the part itself, witness version and program
+const TAG_BECH32: u8 = 2;
+const TAG_BECH32M: u8 = 3;
+
+/// Address of an unspent output, empty for outputs without an address.
+/// Equal addresses have the same encoding, so it can be used as key to aggregate per address.
+#[derive(Clone, Default, PartialEq, Eq, Hash)]
+pub struct CompactAddress(Box<[u8]>);
+
+impl CompactAddress {
+    pub fn new(address: &str) -> Self {
+        if address.is_empty() {
+            return Self::default();
+        }
+        let compact = Self::from_base58(address)
+            .or_else(|| Self::from_bech32(address))
+            .unwrap_or_else(|| {
+                let mut bytes = Vec::with_capacity(1 + address.len());
+                bytes.push(TAG_RAW);
+                bytes.extend_from_slice(address.as_bytes());
+                bytes
+            });
+        CompactAddress(compact.into_boxed_slice())
+    }
+
+    /// Base58Check strings have a single encoding, so the payload is encoded to the same address again
+    fn from_base58(address: &str) -> Option<Vec<u8>> {
+        let payload = base58::decode_check(address).ok()?;
+        let mut bytes = Vec::with_capacity(1 + payload.len());
+        bytes.push(TAG_BASE58);
+        bytes.extend(payload);
+        Some(bytes)
+    }
+
+    fn from_bech32(address: &str) -> Option<Vec<u8>> {
+        let (hrp, data, variant) = bech32::decode(address).ok()?;
+        let (version, program) = data.split_first()?;
+        let program = Vec::<u8>::from_base32(program).ok()?;
+        let tag = match variant {
+            Variant::Bech32 => TAG_BECH32,
+            Variant::Bech32m => TAG_BECH32M,
+        };
+        let mut bytes = Vec::with_capacity(3 + hrp.len() + program.len());
+        bytes.push(tag);
+        bytes.push(u8::try_from(hrp.len()).ok()?);
+        bytes.extend_from_slice(hrp.as_bytes());
+        bytes.push(version.to_u8());
+        bytes.extend(program);
+        // Mixed case addresses or non-zero padding would not be encoded the same way again
+        let compact = CompactAddress(bytes.into_boxed_slice());
+        match compact.encode() == address {
+            true => Some(compact.0.into_vec()),
+            false => None,
+        }
+    }
+
+    /// Parses bytes returned by `as_bytes()`
+    pub fn from_bytes(bytes: Vec<u8>) -> OpResult<Self> {
+        let compact = CompactAddress(bytes.into_boxed_slice());
+        match compact.0.first() {
+            None | Some(&(TAG_RAW..=TAG_BECH32M)) => Ok(compact),
+            Some(tag) => Err(OpError::new(OpErrorKind::RuntimeError)
+                .join_msg(&format!("Invalid address encoding {}", tag))),
+        }
+    }
+
+    /// Encoded form, e.g. to persist it
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn encode(&self) -> String {
+        let (tag, data) = match self.0.split_first() {
+            Some(parts) => parts,
+            None => return String::new(),
+        };
+        match *tag {
+            TAG_BASE58 => base58::encode_check(data),
+            TAG_BECH32 | TAG_BECH32M => {
+                let hrp_len = data[0] as usize;
+                let hrp = String::from_utf8_lossy(&data[1..1 + hrp_len]);
+                let (version, program) = (data[1 + hrp_len], &data[2 + hrp_len..]);
+                let variant = match *tag {
+                    TAG_BECH32 => Variant::Bech32,
+                    _ => Variant::Bech32m,
+                };
+                let mut values = vec![bech32::u5::try_from_u8(version).unwrap()];
+                values.extend(program.to_base32());
+                bech32::encode(&hrp, values, variant).unwrap_or_default()
+            }
+            _ => String::from_utf8_lossy(data).into_owned(),
+        }
+    }
+}
+
+impl From<&str> for CompactAddress {
+    fn from(address: &str) -> Self {
+        CompactAddress::new(address)
+    }
+}
+
+impl fmt::Display for CompactAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.encode())
+    }
+}
+
+impl fmt::Debug for CompactAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.encode())
+    }
+}
+
+impl PartialEq<&str> for CompactAddress {
+    fn eq(&self, other: &&str) -> bool {
+        self.encode() == *other
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compact_address() {
+        let addresses = [
+            ("", 0),
+            ("1JqDybm2nWTENrHvMyafbSXXtTk5Uv5QAn", 22),
+            ("3J98t1WpEZ73CNmQviecrnyiWrnqRhWNLy", 22),
+            ("bc1qar0srrr7xfkvy5l643lydnw9re59gtzzwf5mdq", 25),
+            (
+                "bc1p5d7rjq7g6rdk2yhzks9smlaqtedr4dekq08ge8ztwac72sfr9rusxg3297",
+                37,
+            ),
+            ("bitcoincash:qpm2qsznhks23z7629mms6s4cwef74vcwvy22gdx6a", 55),
+            ("nonstandard:00ff", 17),
+            // Not Base58Check, the checksum is missing
+            ("1", 2),
+        ];
+        for (address, len) in addresses {
+            let compact = CompactAddress::new(address);
+            assert_eq!(compact.to_string(), address);
+            assert_eq!(compact.as_bytes().len(), len, "{}", address);
+            let loaded = CompactAddress::from_bytes(compact.as_bytes().to_vec()).unwrap();
+            assert_eq!(loaded, compact);
+        }
+        // Only lower case addresses are encoded again the same way
+        let upper = "BC1QAR0SRRR7XFKVY5L643LYDNW9RE59GTZZWF5MDQ";
+        assert_eq!(CompactAddress::new(upper).as_bytes()[0], TAG_RAW);
+        assert_eq!(CompactAddress::new(upper), upper);
+        assert!(CompactAddress::new("").is_empty());
+        assert!(CompactAddress::from_bytes(vec![9]).is_err());
+    }
+}
@@ -0,0 +1,76 @@
+/// Timers for the stages of the parsing pipeline, as reported by `--bench-report`
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    /// Reading the serialized block from the blk file
+    Read,
+    /// Decoding header and transactions
+    Deserialize,
+    /// Evaluating the scripts of the outputs
+    ScriptParse,
+    /// Hashing headers and transactions
+    Hash,
+    /// Running the callback
+    Callback,
+}
+
+pub const STAGES: [Stage; 5] = [
+    Stage::Read,
+    Stage::Deserialize,
+    Stage::ScriptParse,
+    Stage::Hash,
+    Stage::Callback,
+];
+
+impl Stage {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Stage::Read => "read",
+            Stage::Deserialize => "deserialize",
+            Stage::ScriptParse => "script_parse",
+            Stage::Hash => "hash",
+            Stage::Callback => "callback",
+        }
+    }
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static NANOS: [AtomicU64; STAGES.len()] = [
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+];
+
+/// Starts measuring from zero. Timing is disabled by default, as it costs a clock read per transaction.
+pub fn enable() {
+    for nanos in &NANOS {
+        nanos.store(0, Ordering::Relaxed);
+    }
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Runs `f` and adds its duration to the stage if timing is enabled.
+/// Stages running on several threads add up their time.
+#[inline]
+pub fn time<T>(stage: Stage, f: impl FnOnce() -> T) -> T {
+    if !is_enabled() {
+        return f();
+    }
+    let started_at = Instant::now();
+    let result = f();
+    NANOS[stage as usize].fetch_add(started_at.elapsed().as_nanos() as u64, Ordering::Relaxed);
+    result
+}
+
+/// Returns the accumulated time of the stage
+pub fn elapsed(stage: Stage) -> Duration {
+    Duration::from_nanos(NANOS[stage as usize].load(Ordering::Relaxed))
+}
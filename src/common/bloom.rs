@@ -0,0 +1,124 @@
+use std::collections::hash_map::DefaultHasher;
+use std::fs::File;
+use std::hash::{Hash, Hasher};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::Path;
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::common::utils;
+use crate::errors::OpResult;
+
+/// Bloom filter sized for an expected number of items and false positive rate.
+/// Membership tests never miss an inserted item, but may claim to contain items which were never inserted.
+pub struct BloomFilter {
+    bits: Vec<u64>,
+    n_bits: u64,
+    n_hashes: u32,
+}
+
+impl BloomFilter {
+    pub fn new(capacity: u64, fp_rate: f64) -> Self {
+        let ln2 = std::f64::consts::LN_2;
+        let n_bits =
+            ((-(capacity.max(1) as f64) * fp_rate.ln() / (ln2 * ln2)).ceil() as u64).max(64);
+        let n_hashes = ((n_bits as f64 / capacity.max(1) as f64) * ln2)
+            .round()
+            .max(1.0) as u32;
+        Self {
+            bits: vec![0; n_bits.div_ceil(64) as usize],
+            n_bits,
+            n_hashes,
+        }
+    }
+
+    /// Inserts the item and returns true if it wasn't contained before
+    pub fn insert<T: Hash + ?Sized>(&mut self, item: &T) -> bool {
+        let mut inserted = false;
+        for index in self.indexes(item) {
+            let (word, mask) = ((index / 64) as usize, 1 << (index % 64));
+            if self.bits[word] & mask == 0 {
+                self.bits[word] |= mask;
+                inserted = true;
+            }
+        }
+        inserted
+    }
+
+    pub fn contains<T: Hash + ?Sized>(&self, item: &T) -> bool {
+        self.indexes(item)
+            .all(|index| self.bits[(index / 64) as usize] & (1 << (index % 64)) != 0)
+    }
+
+    /// Size of the bit array in bytes
+    pub fn size(&self) -> usize {
+        self.bits.len() * 8
+    }
+
+    /// Derives the bit indexes from two hashes (Kirsch and Mitzenmacher)
+    fn indexes<T: Hash + ?Sized>(&self, item: &T) -> impl Iterator<Item = u64> {
+        let hash = |seed: u8| {
+            let mut hasher = DefaultHasher::new();
+            seed.hash(&mut hasher);
+            item.hash(&mut hasher);
+            hasher.finish()
+        };
+        let (h1, h2) = (hash(0), hash(1));
+        let n_bits = self.n_bits;
+        (0..self.n_hashes as u64).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % n_bits)
+    }
+
+    /// Format: bit count (u64), hash count (u32), bits (u64 words)
+    pub fn save(&self, path: &Path) -> OpResult<()> {
+        let tmp_path = path.with_extension("tmp");
+        let mut writer = BufWriter::with_capacity(4000000, File::create(&tmp_path)?);
+        writer.write_u64::<LittleEndian>(self.n_bits)?;
+        writer.write_u32::<LittleEndian>(self.n_hashes)?;
+        for word in &self.bits {
+            writer.write_u64::<LittleEndian>(*word)?;
+        }
+        writer.flush()?;
+        drop(writer);
+        utils::rename(&tmp_path, path)?;
+        Ok(())
+    }
+
+    /// Loads a filter written by `save()`
+    pub fn load(path: &Path) -> OpResult<Self> {
+        let mut reader = BufReader::with_capacity(4000000, File::open(path)?);
+        let n_bits = reader.read_u64::<LittleEndian>()?;
+        let n_hashes = reader.read_u32::<LittleEndian>()?;
+        let mut bits = vec![0; n_bits.div_ceil(64) as usize];
+        reader.read_u64_into::<LittleEndian>(&mut bits)?;
+        Ok(Self {
+            bits,
+            n_bits,
+            n_hashes,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bloom_filter() {
+        let mut bloom = BloomFilter::new(10000, 0.01);
+        assert!(bloom.insert(&0u32));
+        for i in 1..10000u32 {
+            bloom.insert(&i);
+        }
+        assert!((0..10000u32).all(|i| bloom.contains(&i)));
+        assert!(!bloom.insert(&42u32));
+        let false_positives = (10000..20000u32).filter(|i| bloom.contains(i)).count();
+        assert!(false_positives < 200, "{}", false_positives);
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("bloom");
+        bloom.save(&path).unwrap();
+        let loaded = BloomFilter::load(&path).unwrap();
+        assert_eq!(loaded.bits, bloom.bits);
+        assert!(loaded.contains(&42u32));
+    }
+}
@@ -0,0 +1,72 @@
+//! Appends all warnings and errors as JSON lines to the file given with `--error-log`,
+//! so data-quality issues of a long run can be audited afterwards.
+//! Each line holds the time, level, the height of the block being processed (if any),
+//! the category (the log target, e.g. `chain` or `callback`) and the message as detail.
+
+use std::cell::Cell;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use log::{Level, Record};
+use serde_json::json;
+
+use crate::errors::OpResult;
+
+static SINK: Mutex<Option<BufWriter<File>>> = Mutex::new(None);
+
+thread_local! {
+    // Block which is processed by this thread
+    static HEIGHT: Cell<Option<u64>> = const { Cell::new(None) };
+}
+
+/// Appends to the given file from now on
+pub fn open(path: &Path) -> OpResult<()> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    *SINK.lock().unwrap() = Some(BufWriter::new(file));
+    Ok(())
+}
+
+/// Flushes and closes the file
+pub fn close() -> OpResult<()> {
+    if let Some(mut writer) = SINK.lock().unwrap().take() {
+        writer.flush()?;
+    }
+    Ok(())
+}
+
+/// Sets the height which is attached to the records logged by the current thread
+pub fn set_height(height: Option<u64>) {
+    HEIGHT.with(|h| h.set(height));
+}
+
+/// Writes warnings and errors if the error log is open
+pub fn write(record: &Record) {
+    if record.level() > Level::Warn {
+        return;
+    }
+    let mut sink = SINK.lock().unwrap();
+    let writer = match sink.as_mut() {
+        Some(writer) => writer,
+        None => return,
+    };
+    let datetime: DateTime<Utc> = SystemTime::now().into();
+    let line = json!({
+        "time": datetime.to_rfc3339_opts(SecondsFormat::Secs, true),
+        "level": record.level().as_str(),
+        "height": HEIGHT.with(|h| h.get()),
+        "category": record.target(),
+        "detail": record.args().to_string(),
+    });
+    // Errors are flushed right away, as they often precede the exit of the process
+    let result = writeln!(writer, "{}", line).and_then(|_| match record.level() {
+        Level::Error => writer.flush(),
+        _ => Ok(()),
+    });
+    if let Err(err) = result {
+        eprintln!("Unable to write to error log: {}", err);
+    }
+}
@@ -0,0 +1,74 @@
+//! Typed values of the rows written to tables: SQLite, JSON lines and PostgreSQL keep the type,
+//! csv files get the text of each field.
+use serde_json::Value;
+
+use crate::common::utils;
+
+/// Value of a column. Integers are sent as `BIGINT` to PostgreSQL,
+/// so the columns there must have exactly the types of the fields.
+pub enum Field<'a> {
+    Null,
+    Int(i64),
+    Bool(bool),
+    Text(&'a str),
+    Bytes(&'a [u8]),
+}
+
+impl Field<'_> {
+    /// Appends the field as csv text: NULL is an empty field, bytes are hex encoded
+    pub fn push_csv(&self, line: &mut String) {
+        match self {
+            Field::Null => {}
+            Field::Int(value) => line.push_str(&value.to_string()),
+            Field::Bool(value) => line.push(if *value { '1' } else { '0' }),
+            Field::Text(value) => line.push_str(value),
+            Field::Bytes(value) => line.push_str(&utils::arr_to_hex(value)),
+        }
+    }
+
+    /// Returns the field as JSON value, bytes are hex encoded
+    pub fn to_json(&self) -> Value {
+        match self {
+            Field::Null => Value::Null,
+            Field::Int(value) => Value::from(*value),
+            Field::Bool(value) => Value::from(*value),
+            Field::Text(value) => Value::from(*value),
+            Field::Bytes(value) => Value::from(utils::arr_to_hex(value)),
+        }
+    }
+}
+
+/// Returns the fields as `;` separated csv line
+pub fn csv_line(fields: &[Field]) -> String {
+    let mut line = String::new();
+    for (i, field) in fields.iter().enumerate() {
+        if i > 0 {
+            line.push(';');
+        }
+        field.push_csv(&mut line);
+    }
+    line.push('\n');
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_field() {
+        let fields = [
+            Field::Text("aa"),
+            Field::Int(-1),
+            Field::Null,
+            Field::Bool(true),
+            Field::Bytes(&[0x00, 0xff]),
+        ];
+        assert_eq!(csv_line(&fields), "aa;-1;;1;00ff\n");
+        let json = fields.iter().map(Field::to_json).collect::<Vec<Value>>();
+        assert_eq!(
+            Value::from(json).to_string(),
+            r#"["aa",-1,null,true,"00ff"]"#
+        );
+    }
+}
@@ -0,0 +1,103 @@
+//! Double SHA256 of block headers, transactions and merkle tree nodes.
+//! With the `fast-hash` feature (enabled by default) the assembly implementation of ring is used,
+//! which takes advantage of the SHA extensions of recent CPUs. Without it the portable
+//! implementation of bitcoin_hashes is used.
+
+use bitcoin::hashes::{sha256d, Hash};
+
+#[cfg(feature = "fast-hash")]
+fn sha256(data: &[u8]) -> [u8; 32] {
+    let digest = ring::digest::digest(&ring::digest::SHA256, data);
+    digest.as_ref().try_into().unwrap()
+}
+
+#[cfg(not(feature = "fast-hash"))]
+fn sha256(data: &[u8]) -> [u8; 32] {
+    bitcoin::hashes::sha256::Hash::hash(data).to_byte_array()
+}
+
+/// Returns SHA256(SHA256(data))
+pub fn sha256d(data: &[u8]) -> sha256d::Hash {
+    sha256d::Hash::from_byte_array(sha256(&sha256(data)))
+}
+
+/// Returns the parent of two merkle tree nodes without allocating
+pub fn merkle_node(left: &sha256d::Hash, right: &sha256d::Hash) -> sha256d::Hash {
+    let mut data = [0u8; 64];
+    data[..32].copy_from_slice(left.as_byte_array());
+    data[32..].copy_from_slice(right.as_byte_array());
+    sha256d(&data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::proto::ToRaw;
+    use crate::common::testutils;
+    use std::time::Instant;
+
+    #[test]
+    fn test_sha256d() {
+        for len in [0, 1, 55, 56, 64, 1000] {
+            let data = vec![0x5a; len];
+            assert_eq!(sha256d(&data), sha256d::Hash::hash(&data));
+        }
+        let (left, right) = (sha256d::Hash::hash(&[1]), sha256d::Hash::hash(&[2]));
+        assert_eq!(
+            merkle_node(&left, &right),
+            sha256d::Hash::hash(&[&left[..], &right[..]].concat())
+        );
+    }
+
+    #[test]
+    fn test_cached_hashes() {
+        let block = testutils::block(
+            sha256d::Hash::all_zeros(),
+            0,
+            vec![
+                testutils::coinbase(1, &[(5000, testutils::p2pkh_script(0))]),
+                testutils::tx(
+                    &[(sha256d::Hash::hash(&[1]), 0)],
+                    &[(1000, testutils::p2pkh_script(1))],
+                ),
+            ],
+        );
+        assert_eq!(
+            block.hash(),
+            sha256d::Hash::hash(&block.header.value.to_bytes())
+        );
+        for tx in &block.txs {
+            assert_eq!(tx.hash, sha256d::Hash::hash(&tx.value.to_bytes()));
+        }
+        assert!(block.verify_merkle_root().is_ok());
+    }
+
+    /// Compares the selected backend with the portable implementation, run with
+    /// `cargo test --release bench_sha256d -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn bench_sha256d() {
+        // Roughly the size of an average transaction
+        let data = vec![0x5a; 400];
+        let n = 1000000;
+        let measure = |f: &dyn Fn(&[u8]) -> sha256d::Hash| {
+            let started_at = Instant::now();
+            let mut hash = sha256d::Hash::all_zeros();
+            for _ in 0..n {
+                hash = f(&data);
+            }
+            (Instant::now() - started_at, hash)
+        };
+        let (portable, expected) = measure(&|d| sha256d::Hash::hash(d));
+        let (selected, hash) = measure(&sha256d);
+        assert_eq!(hash, expected);
+        println!(
+            "{} hashes of {} bytes: portable {:.2?}, selected backend {:.2?} ({:.1}x)",
+            n,
+            data.len(),
+            portable,
+            selected,
+            portable.as_secs_f64() / selected.as_secs_f64()
+        );
+    }
+}
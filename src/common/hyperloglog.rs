@@ -0,0 +1,72 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+/// HyperLogLog cardinality estimator with 2^precision registers of one byte each.
+/// The standard error is about 1.04 / sqrt(2^precision), e.g. 0.8% for a precision of 14 (16 KiB).
+pub struct HyperLogLog {
+    precision: u32,
+    registers: Vec<u8>,
+}
+
+impl HyperLogLog {
+    pub fn new(precision: u32) -> Self {
+        assert!((4..=18).contains(&precision), "precision out of range");
+        Self {
+            precision,
+            registers: vec![0; 1 << precision],
+        }
+    }
+
+    pub fn insert<T: Hash + ?Sized>(&mut self, item: &T) {
+        let mut hasher = DefaultHasher::new();
+        item.hash(&mut hasher);
+        let hash = hasher.finish();
+        // The first bits select the register, the rank is taken from the remaining ones
+        let index = (hash >> (64 - self.precision)) as usize;
+        let rest = (hash << self.precision) | (1 << (self.precision - 1));
+        let rank = rest.leading_zeros() as u8 + 1;
+        if rank > self.registers[index] {
+            self.registers[index] = rank;
+        }
+    }
+
+    /// Returns the estimated number of distinct items, using linear counting for small cardinalities
+    pub fn count(&self) -> u64 {
+        let m = self.registers.len() as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+        let sum: f64 = self.registers.iter().map(|r| 2f64.powi(-(*r as i32))).sum();
+        let estimate = alpha * m * m / sum;
+        let zeros = self.registers.iter().filter(|r| **r == 0).count();
+        if estimate <= 2.5 * m && zeros > 0 {
+            (m * (m / zeros as f64).ln()).round() as u64
+        } else {
+            estimate.round() as u64
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.registers.iter_mut().for_each(|r| *r = 0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hyperloglog() {
+        let mut hll = HyperLogLog::new(14);
+        assert_eq!(hll.count(), 0);
+        for _ in 0..3 {
+            for i in 0..100000u32 {
+                hll.insert(&i);
+            }
+        }
+        let count = hll.count() as f64;
+        assert!((count - 100000.0).abs() / 100000.0 < 0.03, "{}", count);
+
+        hll.clear();
+        hll.insert("1BitcoinEaterAddressDontSendf59kuE");
+        assert_eq!(hll.count(), 1);
+    }
+}
@@ -4,15 +4,32 @@ use std::time::SystemTime;
 
 use log::{self, Level, LevelFilter, Metadata, Record, SetLoggerError};
 
+use crate::common::errorlog;
+
 pub struct SimpleLogger {
     level_filter: LevelFilter,
+    // Keeps stdout free for the output of the callback
+    stderr_only: bool,
 }
 
 impl SimpleLogger {
+    /// Warnings and errors are always passed on, so they reach the error log independent of the verbosity
     pub fn init(level_filter: LevelFilter) -> Result<(), SetLoggerError> {
-        let logger = SimpleLogger { level_filter };
+        SimpleLogger::install(level_filter, false)
+    }
+
+    /// Like `init()`, but writes all levels to stderr
+    pub fn init_stderr(level_filter: LevelFilter) -> Result<(), SetLoggerError> {
+        SimpleLogger::install(level_filter, true)
+    }
+
+    fn install(level_filter: LevelFilter, stderr_only: bool) -> Result<(), SetLoggerError> {
+        let logger = SimpleLogger {
+            level_filter,
+            stderr_only,
+        };
         log::set_boxed_logger(Box::new(logger))?;
-        log::set_max_level(level_filter);
+        log::set_max_level(level_filter.max(LevelFilter::Warn));
         Ok(())
     }
 
@@ -34,9 +51,13 @@ impl log::Log for SimpleLogger {
     }
 
     fn log(&self, record: &Record) {
+        errorlog::write(record);
         if self.enabled(record.metadata()) {
             let line = self.format_log(record);
             match record.level() {
+                _ if self.stderr_only => {
+                    stderr().write_all(line.as_bytes()).unwrap();
+                }
                 Level::Error => {
                     stderr().write_all(line.as_bytes()).unwrap();
                 }
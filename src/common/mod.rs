@@ -1,2 +1,21 @@
+pub mod address;
+pub mod bench;
+pub mod bloom;
+pub mod errorlog;
+pub mod field;
+pub mod hashing;
+pub mod hyperloglog;
 pub mod logger;
+pub mod muhash;
+pub mod parquet;
+pub mod postgres;
+pub mod sink;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+#[cfg(test)]
+pub mod testutils;
+pub mod toml;
+pub mod unionfind;
 pub mod utils;
+#[cfg(feature = "zstd")]
+pub mod zstd;
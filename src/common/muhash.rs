@@ -0,0 +1,301 @@
+//! MuHash3072 as used by Bitcoin Core for `gettxoutsetinfo muhash`,
+//! see https://github.com/bitcoin/bitcoin/blob/master/src/crypto/muhash.h
+//! The hash of a set is the product of its hashed elements modulo the prime 2^3072 - 1103717,
+//! so elements can be added and removed in any order.
+
+use bitcoin::hashes::{sha256, Hash};
+
+const LIMBS: usize = 48;
+/// 2^3072 - p
+const MAX_PRIME_DIFF: u64 = 1103717;
+
+/// Number modulo 2^3072 - 1103717 with little endian 64 bit limbs
+#[derive(Clone, PartialEq, Eq)]
+struct Num3072 {
+    limbs: [u64; LIMBS],
+}
+
+impl Num3072 {
+    const BYTE_SIZE: usize = LIMBS * 8;
+
+    fn one() -> Self {
+        let mut limbs = [0; LIMBS];
+        limbs[0] = 1;
+        Self { limbs }
+    }
+
+    fn from_bytes(bytes: &[u8; Num3072::BYTE_SIZE]) -> Self {
+        let mut limbs = [0; LIMBS];
+        for (limb, chunk) in limbs.iter_mut().zip(bytes.chunks_exact(8)) {
+            *limb = u64::from_le_bytes(chunk.try_into().unwrap());
+        }
+        Self { limbs }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        self.limbs
+            .iter()
+            .flat_map(|limb| limb.to_le_bytes())
+            .collect()
+    }
+
+    fn is_one(&self) -> bool {
+        *self == Num3072::one()
+    }
+
+    /// Adds `c * 2^3072 = c * MAX_PRIME_DIFF (mod p)` until no carry is left
+    fn fold(&mut self, mut carry: u64) {
+        while carry > 0 {
+            let mut acc = carry as u128 * MAX_PRIME_DIFF as u128;
+            for limb in self.limbs.iter_mut() {
+                if acc == 0 {
+                    break;
+                }
+                acc += *limb as u128;
+                *limb = acc as u64;
+                acc >>= 64;
+            }
+            carry = acc as u64;
+        }
+    }
+
+    /// Reduces the number below p, it is at most 2^3072 - 1
+    fn normalize(&mut self) {
+        // n >= p if n + MAX_PRIME_DIFF overflows 3072 bits, in which case the result is the truncated sum
+        let mut sum = self.limbs;
+        let mut acc = MAX_PRIME_DIFF as u128;
+        for limb in sum.iter_mut() {
+            acc += *limb as u128;
+            *limb = acc as u64;
+            acc >>= 64;
+        }
+        if acc > 0 {
+            self.limbs = sum;
+        }
+    }
+
+    fn multiply(&mut self, other: &Num3072) {
+        let mut product = [0u64; LIMBS * 2];
+        for (i, a) in self.limbs.iter().enumerate() {
+            let mut carry: u128 = 0;
+            for (j, b) in other.limbs.iter().enumerate() {
+                let cur = product[i + j] as u128 + *a as u128 * *b as u128 + carry;
+                product[i + j] = cur as u64;
+                carry = cur >> 64;
+            }
+            product[i + LIMBS] = carry as u64;
+        }
+        // low + high * 2^3072 = low + high * MAX_PRIME_DIFF (mod p)
+        let mut carry: u128 = 0;
+        for i in 0..LIMBS {
+            let cur =
+                product[i] as u128 + product[i + LIMBS] as u128 * MAX_PRIME_DIFF as u128 + carry;
+            self.limbs[i] = cur as u64;
+            carry = cur >> 64;
+        }
+        self.fold(carry as u64);
+    }
+
+    /// Returns the multiplicative inverse by Fermat's little theorem: n^(p - 2)
+    fn inverse(&self) -> Num3072 {
+        // p - 2 = 2^3072 - MAX_PRIME_DIFF - 2, all bits above the lowest limb are set
+        let mut exponent = [u64::MAX; LIMBS];
+        exponent[0] = 0u64.wrapping_sub(MAX_PRIME_DIFF + 2);
+        let mut result = Num3072::one();
+        for limb in exponent.iter().rev() {
+            for bit in (0..64).rev() {
+                let squared = result.clone();
+                result.multiply(&squared);
+                if limb >> bit & 1 == 1 {
+                    result.multiply(self);
+                }
+            }
+        }
+        result
+    }
+}
+
+/// Rolling hash of a set of byte strings
+#[derive(Clone)]
+pub struct MuHash3072 {
+    numerator: Num3072,
+    denominator: Num3072,
+}
+
+impl Default for MuHash3072 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MuHash3072 {
+    /// Returns the hash of the empty set
+    pub fn new() -> Self {
+        Self {
+            numerator: Num3072::one(),
+            denominator: Num3072::one(),
+        }
+    }
+
+    /// Maps an element to a number: ChaCha20 keystream keyed with the SHA256 of the element
+    fn to_num3072(data: &[u8]) -> Num3072 {
+        Num3072::from_bytes(&MuHash3072::element_from_digest(
+            sha256::Hash::hash(data).as_byte_array(),
+        ))
+    }
+
+    fn element_from_digest(digest: &[u8; 32]) -> [u8; Num3072::BYTE_SIZE] {
+        let mut bytes = [0u8; Num3072::BYTE_SIZE];
+        for (counter, block) in bytes.chunks_exact_mut(64).enumerate() {
+            block.copy_from_slice(&chacha20_block(digest, counter as u32));
+        }
+        bytes
+    }
+
+    pub fn insert(&mut self, data: &[u8]) {
+        self.numerator.multiply(&MuHash3072::to_num3072(data));
+    }
+
+    pub fn remove(&mut self, data: &[u8]) {
+        self.denominator.multiply(&MuHash3072::to_num3072(data));
+    }
+
+    /// Like `insert()`, but takes the SHA256 of the element, which is smaller to keep around
+    pub fn insert_digest(&mut self, digest: &[u8; 32]) {
+        self.numerator
+            .multiply(&Num3072::from_bytes(&MuHash3072::element_from_digest(
+                digest,
+            )));
+    }
+
+    /// Adds all elements of the other set
+    pub fn combine(&mut self, other: &MuHash3072) {
+        self.numerator.multiply(&other.numerator);
+        self.denominator.multiply(&other.denominator);
+    }
+
+    /// Returns the SHA256 of the serialized set hash. Bitcoin Core shows it in reversed byte order.
+    pub fn finalize(&self) -> [u8; 32] {
+        let mut num = self.numerator.clone();
+        if !self.denominator.is_one() {
+            num.multiply(&self.denominator.inverse());
+        }
+        num.normalize();
+        sha256::Hash::hash(&num.to_bytes()).to_byte_array()
+    }
+}
+
+/// Returns the 64 byte ChaCha20 keystream block with the given counter and an all zero nonce
+fn chacha20_block(key: &[u8; 32], counter: u32) -> [u8; 64] {
+    fn quarter_round(s: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+        s[a] = s[a].wrapping_add(s[b]);
+        s[d] = (s[d] ^ s[a]).rotate_left(16);
+        s[c] = s[c].wrapping_add(s[d]);
+        s[b] = (s[b] ^ s[c]).rotate_left(12);
+        s[a] = s[a].wrapping_add(s[b]);
+        s[d] = (s[d] ^ s[a]).rotate_left(8);
+        s[c] = s[c].wrapping_add(s[d]);
+        s[b] = (s[b] ^ s[c]).rotate_left(7);
+    }
+
+    // "expand 32-byte k"
+    let mut state = [
+        0x61707865, 0x3320646e, 0x79622d32, 0x6b206574, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    ];
+    for (word, chunk) in state[4..12].iter_mut().zip(key.chunks_exact(4)) {
+        *word = u32::from_le_bytes(chunk.try_into().unwrap());
+    }
+    state[12] = counter;
+    let mut working = state;
+    for _ in 0..10 {
+        quarter_round(&mut working, 0, 4, 8, 12);
+        quarter_round(&mut working, 1, 5, 9, 13);
+        quarter_round(&mut working, 2, 6, 10, 14);
+        quarter_round(&mut working, 3, 7, 11, 15);
+        quarter_round(&mut working, 0, 5, 10, 15);
+        quarter_round(&mut working, 1, 6, 11, 12);
+        quarter_round(&mut working, 2, 7, 8, 13);
+        quarter_round(&mut working, 3, 4, 9, 14);
+    }
+    let mut block = [0u8; 64];
+    for (i, chunk) in block.chunks_exact_mut(4).enumerate() {
+        chunk.copy_from_slice(&working[i].wrapping_add(state[i]).to_le_bytes());
+    }
+    block
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::utils;
+
+    /// Reversed hex, like Bitcoin Core shows a uint256
+    fn to_hex(hash: [u8; 32]) -> String {
+        let mut hash = hash;
+        hash.reverse();
+        utils::arr_to_hex(&hash)
+    }
+
+    fn from_int(i: u8) -> [u8; 32] {
+        let mut data = [0u8; 32];
+        data[0] = i;
+        data
+    }
+
+    #[test]
+    fn test_chacha20_block() {
+        // RFC 7539 section 2.3.2 with an all zero key and nonce
+        assert_eq!(
+            utils::arr_to_hex(&chacha20_block(&[0; 32], 0)),
+            "76b8e0ada0f13d90405d6ae55386bd28bdd219b8a08ded1aa836efcc8b770dc7\
+             da41597c5157488d7724e03fb8d84a376a43b8f41518a11cc387b669b2ee6586"
+        );
+    }
+
+    #[test]
+    fn test_muhash() {
+        // Test vectors of Bitcoin Core's muhash_tests
+        let mut acc = MuHash3072::new();
+        acc.insert(&from_int(0));
+        acc.insert(&from_int(1));
+        acc.remove(&from_int(2));
+        assert_eq!(
+            to_hex(acc.finalize()),
+            "10d312b100cbd32ada024a6646e40d3482fcff103668d2625f10002a607d5863"
+        );
+
+        // Order independent
+        let mut other = MuHash3072::new();
+        other.remove(&from_int(2));
+        other.insert(&from_int(1));
+        let mut zero = MuHash3072::new();
+        zero.insert(&from_int(0));
+        other.combine(&zero);
+        assert_eq!(other.finalize(), acc.finalize());
+
+        // Removing what was inserted gives the empty set
+        let mut acc = MuHash3072::new();
+        acc.insert(&from_int(3));
+        acc.remove(&from_int(3));
+        assert_eq!(acc.finalize(), MuHash3072::new().finalize());
+
+        let mut digest = MuHash3072::new();
+        digest.insert_digest(sha256::Hash::hash(&from_int(3)).as_byte_array());
+        let mut acc = MuHash3072::new();
+        acc.insert(&from_int(3));
+        assert_eq!(digest.finalize(), acc.finalize());
+    }
+
+    #[test]
+    fn test_num3072_reduction() {
+        // (p - 1) * (p - 1) = 1 (mod p)
+        let mut max = Num3072 {
+            limbs: [u64::MAX; LIMBS],
+        };
+        max.limbs[0] = 0u64.wrapping_sub(MAX_PRIME_DIFF + 1);
+        let other = max.clone();
+        max.multiply(&other);
+        max.normalize();
+        assert!(max.is_one());
+    }
+}
@@ -0,0 +1,818 @@
+//! Minimal writer of Parquet files for `--format parquet`.
+//! The columns are typed after the SQL definitions of the dump tables: INTEGER columns are INT64,
+//! TEXT columns UTF8 strings and BLOB columns byte arrays, columns without NOT NULL are optional.
+//! Each column chunk of a row group is a single PLAIN encoded data page, compressed with the
+//! codec of `--compress`. The metadata is encoded with the Thrift compact protocol.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+
+use flate2::write::GzEncoder;
+
+use crate::common::field::Field;
+use crate::common::utils::Compression;
+#[cfg(feature = "zstd")]
+use crate::common::zstd;
+use crate::errors::{OpError, OpErrorKind, OpResult};
+
+const MAGIC: &[u8; 4] = b"PAR1";
+/// Buffered bytes of all columns after which a row group is written
+const ROW_GROUP_SIZE: usize = 64 << 20;
+
+// Physical types
+const INT64: i32 = 2;
+const BYTE_ARRAY: i32 = 6;
+// Encodings
+const PLAIN: i32 = 0;
+const RLE: i32 = 3;
+// Field repetition types
+const REQUIRED: i32 = 0;
+const OPTIONAL: i32 = 1;
+const CONVERTED_UTF8: i32 = 0;
+const DATA_PAGE: i32 = 0;
+
+// Types of the Thrift compact protocol
+const T_I32: u8 = 5;
+const T_I64: u8 = 6;
+const T_BINARY: u8 = 8;
+const T_LIST: u8 = 9;
+const T_STRUCT: u8 = 12;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ColumnType {
+    Int,
+    Text,
+    Blob,
+}
+
+/// Values of a column in the current row group
+struct Column {
+    name: String,
+    kind: ColumnType,
+    required: bool,
+    // PLAIN encoded values
+    values: Vec<u8>,
+    // Whether each row has a value, only kept for optional columns
+    defined: Vec<bool>,
+}
+
+impl Column {
+    /// Parses a SQL column definition like `txid TEXT NOT NULL`
+    fn new(definition: &str) -> OpResult<Self> {
+        let mut words = definition.split_whitespace();
+        let name = words.next().unwrap_or_default();
+        let kind = match words.next().map(str::to_uppercase).as_deref() {
+            Some("INTEGER") => ColumnType::Int,
+            Some("TEXT") => ColumnType::Text,
+            Some("BLOB") => ColumnType::Blob,
+            _ => {
+                let msg = format!("Unsupported Parquet column `{}`", definition);
+                return Err(OpError::new(OpErrorKind::InvalidArgsError).join_msg(&msg));
+            }
+        };
+        Ok(Column {
+            name: String::from(name),
+            kind,
+            required: definition.to_uppercase().contains("NOT NULL"),
+            values: Vec::new(),
+            defined: Vec::new(),
+        })
+    }
+
+    fn physical_type(&self) -> i32 {
+        match self.kind {
+            ColumnType::Int => INT64,
+            ColumnType::Text | ColumnType::Blob => BYTE_ARRAY,
+        }
+    }
+
+    /// Whether the field can be stored in the column, booleans are stored as 0 or 1
+    fn accepts(&self, field: &Field) -> bool {
+        matches!(
+            (field, self.kind),
+            (Field::Int(_) | Field::Bool(_), ColumnType::Int)
+                | (Field::Text(_), ColumnType::Text | ColumnType::Blob)
+                | (Field::Bytes(_), ColumnType::Blob)
+        ) || matches!(field, Field::Null) && !self.required
+    }
+
+    fn push(&mut self, field: &Field) {
+        let bytes = match field {
+            Field::Null => {
+                self.defined.push(false);
+                return;
+            }
+            Field::Int(value) => return self.push_value(&value.to_le_bytes()),
+            Field::Bool(value) => return self.push_value(&(*value as i64).to_le_bytes()),
+            Field::Text(value) => value.as_bytes(),
+            Field::Bytes(value) => value,
+        };
+        self.values
+            .extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+        self.push_value(bytes);
+    }
+
+    fn push_value(&mut self, bytes: &[u8]) {
+        self.values.extend_from_slice(bytes);
+        if !self.required {
+            self.defined.push(true);
+        }
+    }
+}
+
+/// Position and sizes of a column chunk, for the metadata in the footer
+struct ColumnChunk {
+    offset: u64,
+    uncompressed_size: u64,
+    compressed_size: u64,
+}
+
+struct RowGroup {
+    num_rows: u64,
+    columns: Vec<ColumnChunk>,
+}
+
+/// Writes the rows of a table to a Parquet file. The rows are kept in memory until
+/// `ROW_GROUP_SIZE` is reached or the writer is flushed, `finish()` writes the footer.
+pub struct ParquetWriter {
+    file: BufWriter<File>,
+    compression: Option<Compression>,
+    columns: Vec<Column>,
+    num_rows: u64,
+    offset: u64,
+    row_groups: Vec<RowGroup>,
+    finished: bool,
+}
+
+impl ParquetWriter {
+    pub fn create(
+        path: PathBuf,
+        columns: &[&str],
+        compression: Option<Compression>,
+    ) -> OpResult<Self> {
+        let columns = columns
+            .iter()
+            .map(|column| Column::new(column))
+            .collect::<OpResult<Vec<Column>>>()?;
+        let mut file = BufWriter::with_capacity(4000000, File::create(path)?);
+        file.write_all(MAGIC)?;
+        Ok(ParquetWriter {
+            file,
+            compression,
+            columns,
+            num_rows: 0,
+            offset: MAGIC.len() as u64,
+            row_groups: Vec::new(),
+            finished: false,
+        })
+    }
+
+    /// Appends a row with a field for each column, the fields must match the column types
+    pub fn write_row(&mut self, fields: &[Field]) -> OpResult<()> {
+        if self.finished {
+            return Err(OpError::from(String::from(
+                "Parquet file is finished already",
+            )));
+        }
+        let invalid = fields.len() != self.columns.len()
+            || !self.columns.iter().zip(fields).all(|(c, f)| c.accepts(f));
+        if invalid {
+            let names = self.columns.iter().map(|c| c.name.as_str());
+            let msg = format!(
+                "Row doesn't match the Parquet columns {}",
+                names.collect::<Vec<&str>>().join(", ")
+            );
+            return Err(OpError::new(OpErrorKind::RuntimeError).join_msg(&msg));
+        }
+        for (column, field) in self.columns.iter_mut().zip(fields) {
+            column.push(field);
+        }
+        self.num_rows += 1;
+        let buffered: usize = self
+            .columns
+            .iter()
+            .map(|c| c.values.len() + c.defined.len())
+            .sum();
+        if buffered >= ROW_GROUP_SIZE {
+            self.write_row_group()?;
+        }
+        Ok(())
+    }
+
+    /// Writes the buffered rows as row group
+    fn write_row_group(&mut self) -> OpResult<()> {
+        let written: u64 = self.row_groups.iter().map(|g| g.num_rows).sum();
+        let num_rows = self.num_rows - written;
+        if num_rows == 0 {
+            return Ok(());
+        }
+        let mut chunks = Vec::with_capacity(self.columns.len());
+        for column in self.columns.iter_mut() {
+            let mut page = Vec::new();
+            if !column.required {
+                let levels = rle_levels(&column.defined);
+                page.extend_from_slice(&(levels.len() as u32).to_le_bytes());
+                page.extend_from_slice(&levels);
+                column.defined.clear();
+            }
+            page.extend_from_slice(&column.values);
+            column.values.clear();
+            let uncompressed_len = page.len();
+            let page = compress(page, self.compression)?;
+
+            let mut header = Thrift::default();
+            header.i32(1, DATA_PAGE);
+            header.i32(2, uncompressed_len as i32);
+            header.i32(3, page.len() as i32);
+            header.begin_struct(5);
+            header.i32(1, num_rows as i32);
+            header.i32(2, PLAIN);
+            header.i32(3, RLE);
+            header.i32(4, RLE);
+            header.end_struct();
+            header.end_struct();
+            self.file.write_all(&header.buf)?;
+            self.file.write_all(&page)?;
+
+            let chunk = ColumnChunk {
+                offset: self.offset,
+                uncompressed_size: (header.buf.len() + uncompressed_len) as u64,
+                compressed_size: (header.buf.len() + page.len()) as u64,
+            };
+            self.offset += chunk.compressed_size;
+            chunks.push(chunk);
+        }
+        self.row_groups.push(RowGroup {
+            num_rows,
+            columns: chunks,
+        });
+        Ok(())
+    }
+
+    /// Encodes the FileMetaData of the footer
+    fn metadata(&self) -> Vec<u8> {
+        let codec = match self.compression {
+            None => 0,
+            Some(Compression::Gzip) => 2,
+            #[cfg(feature = "zstd")]
+            Some(Compression::Zstd) => 6,
+        };
+        let mut t = Thrift::default();
+        t.i32(1, 1);
+        // The schema is a root element followed by the columns
+        t.list(2, T_STRUCT, self.columns.len() + 1);
+        t.begin_element();
+        t.binary(4, b"schema");
+        t.i32(5, self.columns.len() as i32);
+        t.end_struct();
+        for column in &self.columns {
+            t.begin_element();
+            t.i32(1, column.physical_type());
+            t.i32(3, if column.required { REQUIRED } else { OPTIONAL });
+            t.binary(4, column.name.as_bytes());
+            if column.kind == ColumnType::Text {
+                t.i32(6, CONVERTED_UTF8);
+            }
+            t.end_struct();
+        }
+        t.i64(3, self.num_rows as i64);
+        t.list(4, T_STRUCT, self.row_groups.len());
+        for group in &self.row_groups {
+            t.begin_element();
+            t.list(1, T_STRUCT, group.columns.len());
+            for (column, chunk) in self.columns.iter().zip(&group.columns) {
+                t.begin_element();
+                t.i64(2, chunk.offset as i64);
+                t.begin_struct(3);
+                t.i32(1, column.physical_type());
+                t.list(2, T_I32, 2);
+                t.element_i32(PLAIN);
+                t.element_i32(RLE);
+                t.list(3, T_BINARY, 1);
+                t.element_binary(column.name.as_bytes());
+                t.i32(4, codec);
+                t.i64(5, group.num_rows as i64);
+                t.i64(6, chunk.uncompressed_size as i64);
+                t.i64(7, chunk.compressed_size as i64);
+                t.i64(9, chunk.offset as i64);
+                t.end_struct();
+                t.end_struct();
+            }
+            let size: u64 = group.columns.iter().map(|c| c.uncompressed_size).sum();
+            t.i64(2, size as i64);
+            t.i64(3, group.num_rows as i64);
+            t.end_struct();
+        }
+        let created_by = format!("rusty-blockparser version {}", env!("CARGO_PKG_VERSION"));
+        t.binary(6, created_by.as_bytes());
+        t.end_struct();
+        t.buf
+    }
+
+    /// Writes the remaining rows and the footer. Nothing must be written afterwards.
+    pub fn finish(&mut self) -> OpResult<()> {
+        if self.finished {
+            return Ok(());
+        }
+        self.write_row_group()?;
+        let metadata = self.metadata();
+        self.file.write_all(&metadata)?;
+        self.file
+            .write_all(&(metadata.len() as u32).to_le_bytes())?;
+        self.file.write_all(MAGIC)?;
+        self.file.flush()?;
+        self.finished = true;
+        Ok(())
+    }
+
+    /// Writes the buffered rows as row group, e.g. for `--flush-interval`.
+    /// The file is only readable once the footer is written by `finish()`.
+    pub fn flush(&mut self) -> OpResult<()> {
+        if !self.finished {
+            self.write_row_group()?;
+        }
+        Ok(self.file.flush()?)
+    }
+}
+
+/// Compresses the data of a page
+fn compress(page: Vec<u8>, compression: Option<Compression>) -> io::Result<Vec<u8>> {
+    match compression {
+        None => Ok(page),
+        Some(Compression::Gzip) => {
+            let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(&page)?;
+            encoder.finish()
+        }
+        #[cfg(feature = "zstd")]
+        Some(Compression::Zstd) => {
+            let mut compressed = Vec::new();
+            let mut encoder = zstd::Encoder::new(&mut compressed);
+            encoder.write_all(&page)?;
+            encoder.try_finish()?;
+            drop(encoder);
+            Ok(compressed)
+        }
+    }
+}
+
+/// Encodes definition levels of bit width 1 as runs of the RLE/bit-packing hybrid encoding
+fn rle_levels(defined: &[bool]) -> Vec<u8> {
+    let mut levels = Vec::new();
+    let mut i = 0;
+    while i < defined.len() {
+        let value = defined[i];
+        let run = defined[i..].iter().take_while(|d| **d == value).count();
+        push_varint(&mut levels, (run as u64) << 1);
+        levels.push(value as u8);
+        i += run;
+    }
+    levels
+}
+
+fn push_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+/// Encoder of the Thrift compact protocol, only for the types of the Parquet metadata.
+/// Field ids are encoded as delta to the previous field of the same struct.
+#[derive(Default)]
+struct Thrift {
+    buf: Vec<u8>,
+    last_id: i16,
+    // Last field ids of the enclosing structs
+    stack: Vec<i16>,
+}
+
+impl Thrift {
+    fn field(&mut self, id: i16, kind: u8) {
+        let delta = id - self.last_id;
+        if (1..=15).contains(&delta) {
+            self.buf.push((delta as u8) << 4 | kind);
+        } else {
+            self.buf.push(kind);
+            push_varint(&mut self.buf, zigzag(id as i64));
+        }
+        self.last_id = id;
+    }
+
+    fn i32(&mut self, id: i16, value: i32) {
+        self.field(id, T_I32);
+        self.element_i32(value);
+    }
+
+    fn i64(&mut self, id: i16, value: i64) {
+        self.field(id, T_I64);
+        push_varint(&mut self.buf, zigzag(value));
+    }
+
+    fn binary(&mut self, id: i16, value: &[u8]) {
+        self.field(id, T_BINARY);
+        self.element_binary(value);
+    }
+
+    /// Starts a list, followed by `size` elements
+    fn list(&mut self, id: i16, kind: u8, size: usize) {
+        self.field(id, T_LIST);
+        if size < 15 {
+            self.buf.push((size as u8) << 4 | kind);
+        } else {
+            self.buf.push(0xf0 | kind);
+            push_varint(&mut self.buf, size as u64);
+        }
+    }
+
+    fn begin_struct(&mut self, id: i16) {
+        self.field(id, T_STRUCT);
+        self.begin_element();
+    }
+
+    /// Starts a struct which is an element of a list
+    fn begin_element(&mut self) {
+        self.stack.push(self.last_id);
+        self.last_id = 0;
+    }
+
+    fn end_struct(&mut self) {
+        self.buf.push(0);
+        self.last_id = self.stack.pop().unwrap_or(0);
+    }
+
+    fn element_i32(&mut self, value: i32) {
+        push_varint(&mut self.buf, zigzag(value as i64));
+    }
+
+    fn element_binary(&mut self, value: &[u8]) {
+        push_varint(&mut self.buf, value.len() as u64);
+        self.buf.extend_from_slice(value);
+    }
+}
+
+fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+/// Reads the files of `ParquetWriter` back, for the tests of the dump callbacks
+#[cfg(test)]
+pub mod reader {
+    use super::*;
+    use std::io::Read;
+    use std::path::Path;
+
+    /// Value decoded with the Thrift compact protocol
+    #[derive(Debug)]
+    pub enum Value {
+        Int(i64),
+        Bin(Vec<u8>),
+        List(Vec<Value>),
+        Struct(Vec<(i16, Value)>),
+    }
+
+    impl Value {
+        pub fn get(&self, id: i16) -> &Value {
+            match self {
+                Value::Struct(fields) => &fields.iter().find(|(i, _)| *i == id).unwrap().1,
+                _ => panic!("{:?} is no struct", self),
+            }
+        }
+
+        pub fn int(&self) -> i64 {
+            match self {
+                Value::Int(value) => *value,
+                _ => panic!("{:?} is no integer", self),
+            }
+        }
+
+        pub fn bin(&self) -> &[u8] {
+            match self {
+                Value::Bin(value) => value,
+                _ => panic!("{:?} is no binary", self),
+            }
+        }
+
+        pub fn list(&self) -> &[Value] {
+            match self {
+                Value::List(values) => values,
+                _ => panic!("{:?} is no list", self),
+            }
+        }
+    }
+
+    pub struct Decoder<'a> {
+        pub data: &'a [u8],
+        pub pos: usize,
+    }
+
+    impl Decoder<'_> {
+        fn byte(&mut self) -> u8 {
+            self.pos += 1;
+            self.data[self.pos - 1]
+        }
+
+        fn varint(&mut self) -> u64 {
+            let mut value = 0;
+            let mut shift = 0;
+            loop {
+                let byte = self.byte();
+                value |= ((byte & 0x7f) as u64) << shift;
+                if byte < 0x80 {
+                    return value;
+                }
+                shift += 7;
+            }
+        }
+
+        fn zigzag(&mut self) -> i64 {
+            let value = self.varint();
+            (value >> 1) as i64 ^ -((value & 1) as i64)
+        }
+
+        pub fn value(&mut self, kind: u8) -> Value {
+            match kind {
+                T_I32 | T_I64 => Value::Int(self.zigzag()),
+                T_BINARY => {
+                    let len = self.varint() as usize;
+                    self.pos += len;
+                    Value::Bin(self.data[self.pos - len..self.pos].to_vec())
+                }
+                T_LIST => {
+                    let header = self.byte();
+                    let size = match header >> 4 {
+                        15 => self.varint() as usize,
+                        size => size as usize,
+                    };
+                    Value::List((0..size).map(|_| self.value(header & 0x0f)).collect())
+                }
+                T_STRUCT => {
+                    let mut fields = Vec::new();
+                    let mut last_id = 0;
+                    loop {
+                        let header = self.byte();
+                        if header == 0 {
+                            return Value::Struct(fields);
+                        }
+                        last_id = match header >> 4 {
+                            0 => self.zigzag() as i16,
+                            delta => last_id + delta as i16,
+                        };
+                        fields.push((last_id, self.value(header & 0x0f)));
+                    }
+                }
+                _ => panic!("Unexpected type {}", kind),
+            }
+        }
+    }
+
+    pub fn read_metadata(data: &[u8]) -> Value {
+        assert_eq!(&data[..4], MAGIC);
+        assert_eq!(&data[data.len() - 4..], MAGIC);
+        let len_pos = data.len() - 8;
+        let len = u32::from_le_bytes(data[len_pos..len_pos + 4].try_into().unwrap()) as usize;
+        let mut decoder = Decoder {
+            data: &data[len_pos - len..len_pos],
+            pos: 0,
+        };
+        let metadata = decoder.value(T_STRUCT);
+        assert_eq!(decoder.pos, len);
+        metadata
+    }
+
+    /// Returns the values of a column chunk, the bytes of each value or None for NULL
+    pub fn read_chunk(data: &[u8], chunk: &Value, required: bool) -> Vec<Option<Vec<u8>>> {
+        let meta = chunk.get(3);
+        let offset = meta.get(9).int() as usize;
+        let mut decoder = Decoder { data, pos: offset };
+        let header = decoder.value(T_STRUCT);
+        assert_eq!(header.get(1).int(), DATA_PAGE as i64);
+        let page = &data[decoder.pos..decoder.pos + header.get(3).int() as usize];
+        assert_eq!(
+            (decoder.pos - offset + page.len()) as i64,
+            meta.get(7).int()
+        );
+        let page = match meta.get(4).int() {
+            0 => page.to_vec(),
+            2 => {
+                let mut page_data = Vec::new();
+                flate2::read::GzDecoder::new(page)
+                    .read_to_end(&mut page_data)
+                    .unwrap();
+                page_data
+            }
+            codec => panic!("Unexpected codec {}", codec),
+        };
+        assert_eq!(page.len() as i64, header.get(2).int());
+
+        let num_values = header.get(5).get(1).int() as usize;
+        let mut decoder = Decoder {
+            data: &page,
+            pos: 0,
+        };
+        let mut defined = Vec::new();
+        if required {
+            defined.resize(num_values, true);
+        } else {
+            let len = u32::from_le_bytes(page[..4].try_into().unwrap()) as usize;
+            decoder.pos = 4;
+            while decoder.pos < 4 + len {
+                let run = (decoder.varint() >> 1) as usize;
+                let value = decoder.byte() == 1;
+                defined.extend(std::iter::repeat_n(value, run));
+            }
+            assert_eq!(defined.len(), num_values);
+        }
+        let is_int = meta.get(1).int() == INT64 as i64;
+        let values = defined
+            .iter()
+            .map(|defined| {
+                defined.then(|| {
+                    let len = match is_int {
+                        true => 8,
+                        false => {
+                            decoder.pos += 4;
+                            u32::from_le_bytes(
+                                page[decoder.pos - 4..decoder.pos].try_into().unwrap(),
+                            ) as usize
+                        }
+                    };
+                    decoder.pos += len;
+                    page[decoder.pos - len..decoder.pos].to_vec()
+                })
+            })
+            .collect();
+        assert_eq!(decoder.pos, page.len());
+        values
+    }
+
+    /// Returns all rows as text like `sqlite::Connection::query()`: NULL values as empty
+    /// strings, BLOB values hex encoded
+    pub fn read_rows(path: &Path) -> Vec<Vec<String>> {
+        let data = std::fs::read(path).unwrap();
+        let metadata = read_metadata(&data);
+        let schema = &metadata.get(2).list()[1..];
+        let mut rows = Vec::new();
+        for group in metadata.get(4).list() {
+            let columns = group.get(1).list().iter().zip(schema).map(|(chunk, element)| {
+                let values = read_chunk(&data, chunk, element.get(3).int() == REQUIRED as i64);
+                values.into_iter().map(|value| match value {
+                    None => String::new(),
+                    Some(v) if element.get(1).int() == INT64 as i64 => {
+                        i64::from_le_bytes(v.try_into().unwrap()).to_string()
+                    }
+                    Some(v) if matches!(element, Value::Struct(f) if f.iter().any(|(id, _)| *id == 6)) => {
+                        String::from_utf8(v).unwrap()
+                    }
+                    Some(v) => crate::common::utils::arr_to_hex(&v),
+                })
+                .collect::<Vec<String>>()
+            });
+            let columns = columns.collect::<Vec<Vec<String>>>();
+            for i in 0..group.get(3).int() as usize {
+                rows.push(columns.iter().map(|column| column[i].clone()).collect());
+            }
+        }
+        rows
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::reader::*;
+    use super::*;
+
+    #[test]
+    fn test_parquet_writer() {
+        let dir = tempfile::tempdir().unwrap();
+        for compression in [None, Some(Compression::Gzip)] {
+            let path = dir.path().join("tx_out.parquet");
+            let columns = [
+                "txid TEXT NOT NULL",
+                "value INTEGER NOT NULL",
+                "script BLOB",
+                "address TEXT",
+            ];
+            let mut writer = ParquetWriter::create(path.clone(), &columns, compression).unwrap();
+            for i in 0..20i64 {
+                let address = format!("addr{}", i);
+                writer
+                    .write_row(&[
+                        Field::Text("aa"),
+                        Field::Int(i - 1),
+                        Field::Bytes(&[0x00, i as u8]),
+                        match i % 3 {
+                            0 => Field::Null,
+                            _ => Field::Text(&address),
+                        },
+                    ])
+                    .unwrap();
+                // The first row group gets 5 rows
+                if i == 4 {
+                    writer.flush().unwrap();
+                }
+            }
+            // Rows which don't match the columns are rejected as a whole
+            assert!(writer.write_row(&[Field::Text("aa")]).is_err());
+            let row = [Field::Null, Field::Int(0), Field::Null, Field::Null];
+            assert!(writer.write_row(&row).is_err());
+            let row = [
+                Field::Text("aa"),
+                Field::Text("0"),
+                Field::Null,
+                Field::Null,
+            ];
+            assert!(writer.write_row(&row).is_err());
+            writer.finish().unwrap();
+            assert!(writer.write_row(&row).is_err());
+
+            let data = std::fs::read(&path).unwrap();
+            let metadata = read_metadata(&data);
+            assert_eq!(metadata.get(3).int(), 20);
+            let schema = metadata.get(2).list();
+            assert_eq!(schema[0].get(5).int(), 4);
+            let names = schema[1..]
+                .iter()
+                .map(|e| e.get(4).bin())
+                .collect::<Vec<_>>();
+            assert_eq!(names, [&b"txid"[..], b"value", b"script", b"address"]);
+            let types = schema[1..]
+                .iter()
+                .map(|e| e.get(1).int())
+                .collect::<Vec<_>>();
+            assert_eq!(types, [6, 2, 6, 6]);
+            let repetition = schema[1..].iter().map(|e| e.get(3).int());
+            assert_eq!(repetition.collect::<Vec<_>>(), [0, 0, 1, 1]);
+            assert_eq!(schema[1].get(6).int(), CONVERTED_UTF8 as i64);
+
+            let row_groups = metadata.get(4).list();
+            assert_eq!(row_groups.len(), 2);
+            assert_eq!(row_groups[0].get(3).int(), 5);
+            assert_eq!(row_groups[1].get(3).int(), 15);
+            let mut values = vec![Vec::new(); 4];
+            for group in row_groups {
+                for (i, chunk) in group.get(1).list().iter().enumerate() {
+                    assert_eq!(
+                        chunk.get(3).get(3).list()[0].bin(),
+                        names[i],
+                        "path_in_schema"
+                    );
+                    values[i].extend(read_chunk(&data, chunk, i < 2));
+                }
+            }
+            assert!(values[0].iter().all(|v| v.as_deref() == Some(b"aa")));
+            let ints = values[1]
+                .iter()
+                .map(|v| i64::from_le_bytes(v.as_deref().unwrap().try_into().unwrap()));
+            assert_eq!(ints.collect::<Vec<_>>(), (-1..19).collect::<Vec<_>>());
+            assert_eq!(values[2][7].as_deref(), Some(&[0x00, 7][..]));
+            assert_eq!(values[3][0], None);
+            assert_eq!(values[3][1].as_deref(), Some(&b"addr1"[..]));
+            assert_eq!(values[3].iter().filter(|v| v.is_none()).count(), 7);
+        }
+    }
+
+    #[test]
+    fn test_thrift() {
+        let mut t = Thrift::default();
+        t.i32(1, -1);
+        t.i64(17, 300);
+        t.begin_struct(18);
+        t.binary(1, b"ab");
+        t.end_struct();
+        t.list(19, T_I32, 15);
+        (0..15).for_each(|i| t.element_i32(i));
+        t.end_struct();
+        assert_eq!(&t.buf[..2], &[0x15, 0x01]);
+        // Field 17 doesn't fit into the delta of the header
+        assert_eq!(&t.buf[2..6], &[0x06, 0x22, 0xd8, 0x04]);
+        assert_eq!(&t.buf[6..11], &[0x1c, 0x18, 0x02, b'a', b'b']);
+
+        let mut decoder = Decoder {
+            data: &t.buf,
+            pos: 0,
+        };
+        let value = decoder.value(T_STRUCT);
+        assert_eq!(decoder.pos, t.buf.len());
+        assert_eq!(value.get(1).int(), -1);
+        assert_eq!(value.get(17).int(), 300);
+        assert_eq!(value.get(18).get(1).bin(), b"ab");
+        assert_eq!(value.get(19).list().len(), 15);
+        assert_eq!(value.get(19).list()[14].int(), 14);
+    }
+
+    #[test]
+    fn test_column_definitions() {
+        assert!(ParquetWriter::create(PathBuf::from("x"), &["a REAL"], None).is_err());
+        let column = Column::new("isCoinbase INTEGER NOT NULL").unwrap();
+        assert!(column.required);
+        assert!(column.accepts(&Field::Bool(true)));
+        assert!(!column.accepts(&Field::Null));
+        let column = Column::new("scriptSig TEXT").unwrap();
+        assert!(!column.required);
+        assert!(column.accepts(&Field::Null));
+        assert!(!column.accepts(&Field::Bytes(&[0x00])));
+    }
+}
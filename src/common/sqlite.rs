@@ -0,0 +1,344 @@
+//! Minimal bindings to the system libsqlite3 for `--format sqlite`.
+//! Only what the dump callbacks need: executing statements and inserting rows.
+
+use std::ffi::{c_char, c_int, c_void, CStr, CString};
+use std::path::Path;
+use std::ptr;
+use std::rc::Rc;
+
+use crate::common::field::Field;
+use crate::errors::{OpError, OpErrorKind, OpResult};
+
+#[allow(non_camel_case_types)]
+type sqlite3 = c_void;
+#[allow(non_camel_case_types)]
+type sqlite3_stmt = c_void;
+
+const SQLITE_OK: c_int = 0;
+#[cfg(test)]
+const SQLITE_ROW: c_int = 100;
+const SQLITE_DONE: c_int = 101;
+const SQLITE_OPEN_READWRITE: c_int = 0x02;
+const SQLITE_OPEN_CREATE: c_int = 0x04;
+// Lets SQLite copy bound values, see SQLITE_TRANSIENT
+const SQLITE_TRANSIENT: isize = -1;
+
+#[link(name = "sqlite3")]
+extern "C" {
+    fn sqlite3_open_v2(
+        filename: *const c_char,
+        db: *mut *mut sqlite3,
+        flags: c_int,
+        vfs: *const c_char,
+    ) -> c_int;
+    fn sqlite3_close_v2(db: *mut sqlite3) -> c_int;
+    fn sqlite3_errmsg(db: *mut sqlite3) -> *const c_char;
+    fn sqlite3_exec(
+        db: *mut sqlite3,
+        sql: *const c_char,
+        callback: *const c_void,
+        arg: *mut c_void,
+        errmsg: *mut *mut c_char,
+    ) -> c_int;
+    fn sqlite3_prepare_v2(
+        db: *mut sqlite3,
+        sql: *const c_char,
+        n_byte: c_int,
+        stmt: *mut *mut sqlite3_stmt,
+        tail: *mut *const c_char,
+    ) -> c_int;
+    fn sqlite3_bind_text(
+        stmt: *mut sqlite3_stmt,
+        index: c_int,
+        value: *const c_char,
+        n: c_int,
+        destructor: isize,
+    ) -> c_int;
+    fn sqlite3_bind_blob(
+        stmt: *mut sqlite3_stmt,
+        index: c_int,
+        value: *const c_void,
+        n: c_int,
+        destructor: isize,
+    ) -> c_int;
+    fn sqlite3_bind_int64(stmt: *mut sqlite3_stmt, index: c_int, value: i64) -> c_int;
+    fn sqlite3_bind_null(stmt: *mut sqlite3_stmt, index: c_int) -> c_int;
+    fn sqlite3_step(stmt: *mut sqlite3_stmt) -> c_int;
+    fn sqlite3_reset(stmt: *mut sqlite3_stmt) -> c_int;
+    fn sqlite3_finalize(stmt: *mut sqlite3_stmt) -> c_int;
+    #[cfg(test)]
+    fn sqlite3_column_count(stmt: *mut sqlite3_stmt) -> c_int;
+    #[cfg(test)]
+    fn sqlite3_column_text(stmt: *mut sqlite3_stmt, col: c_int) -> *const c_char;
+}
+
+/// Connection to a database file. All writes happen in one open transaction,
+/// which is committed by `commit()`. Dropping the connection rolls back uncommitted rows,
+/// so a failed run leaves the database as it was after the last commit.
+pub struct Connection {
+    db: *mut sqlite3,
+}
+
+impl Connection {
+    /// Opens or creates the database and begins a transaction
+    pub fn open(path: &Path) -> OpResult<Self> {
+        let filename = CString::new(path.to_string_lossy().as_bytes())
+            .map_err(|e| OpError::from(e.to_string()))?;
+        let mut db = ptr::null_mut();
+        let flags = SQLITE_OPEN_READWRITE | SQLITE_OPEN_CREATE;
+        let rc = unsafe { sqlite3_open_v2(filename.as_ptr(), &mut db, flags, ptr::null()) };
+        let conn = Self { db };
+        if rc != SQLITE_OK {
+            return Err(conn
+                .error()
+                .join_msg(&format!(" Unable to open {}", path.display())));
+        }
+        conn.execute("PRAGMA journal_mode = WAL; BEGIN")?;
+        Ok(conn)
+    }
+
+    pub fn execute(&self, sql: &str) -> OpResult<()> {
+        let sql = CString::new(sql).map_err(|e| OpError::from(e.to_string()))?;
+        let rc = unsafe {
+            sqlite3_exec(
+                self.db,
+                sql.as_ptr(),
+                ptr::null(),
+                ptr::null_mut(),
+                ptr::null_mut(),
+            )
+        };
+        match rc {
+            SQLITE_OK => Ok(()),
+            _ => Err(self.error()),
+        }
+    }
+
+    /// Makes all rows written so far durable and begins the next transaction
+    pub fn commit(&self) -> OpResult<()> {
+        self.execute("COMMIT; BEGIN")
+    }
+
+    fn prepare(&self, sql: &str) -> OpResult<*mut sqlite3_stmt> {
+        let sql = CString::new(sql).map_err(|e| OpError::from(e.to_string()))?;
+        let mut stmt = ptr::null_mut();
+        let rc =
+            unsafe { sqlite3_prepare_v2(self.db, sql.as_ptr(), -1, &mut stmt, ptr::null_mut()) };
+        match rc {
+            SQLITE_OK => Ok(stmt),
+            _ => Err(self.error()),
+        }
+    }
+
+    /// Returns all rows of the query as text, NULL values as empty strings
+    #[cfg(test)]
+    pub fn query(&self, sql: &str) -> OpResult<Vec<Vec<String>>> {
+        let stmt = self.prepare(sql)?;
+        let mut rows = Vec::new();
+        let result = loop {
+            match unsafe { sqlite3_step(stmt) } {
+                SQLITE_ROW => {
+                    let n = unsafe { sqlite3_column_count(stmt) };
+                    let row = (0..n)
+                        .map(|i| match unsafe { sqlite3_column_text(stmt, i) } {
+                            text if text.is_null() => String::new(),
+                            text => unsafe { CStr::from_ptr(text) }
+                                .to_string_lossy()
+                                .into_owned(),
+                        })
+                        .collect();
+                    rows.push(row);
+                }
+                SQLITE_DONE => break Ok(rows),
+                _ => break Err(self.error()),
+            }
+        };
+        unsafe { sqlite3_finalize(stmt) };
+        result
+    }
+
+    fn error(&self) -> OpError {
+        let msg = match self.db.is_null() {
+            true => String::from("out of memory"),
+            false => unsafe { CStr::from_ptr(sqlite3_errmsg(self.db)) }
+                .to_string_lossy()
+                .into_owned(),
+        };
+        OpError::new(OpErrorKind::SqliteError(msg))
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        unsafe { sqlite3_close_v2(self.db) };
+    }
+}
+
+/// Inserts rows of typed fields into a table, integers and booleans as INTEGER, bytes as BLOB.
+pub struct TableWriter {
+    conn: Rc<Connection>,
+    stmt: *mut sqlite3_stmt,
+    table: String,
+    indexes: Vec<String>,
+    // Rows are replaced on the first write instead of appended
+    replace: bool,
+}
+
+impl TableWriter {
+    /// Creates the table with the given column definitions (e.g. `txid TEXT NOT NULL`) if it doesn't exist.
+    /// The indexes on the given columns are created by `finish()`, which is faster than updating them
+    /// for each row of the first run.
+    pub fn new(
+        conn: Rc<Connection>,
+        table: &str,
+        columns: &[&str],
+        indexes: &[&str],
+        replace: bool,
+    ) -> OpResult<Self> {
+        conn.execute(&format!(
+            "CREATE TABLE IF NOT EXISTS {} ({})",
+            table,
+            columns.join(", ")
+        ))?;
+        let params = vec!["?"; columns.len()].join(", ");
+        let stmt = conn.prepare(&format!("INSERT INTO {} VALUES ({})", table, params))?;
+        Ok(Self {
+            conn,
+            stmt,
+            table: String::from(table),
+            indexes: indexes.iter().map(|c| String::from(*c)).collect(),
+            replace,
+        })
+    }
+
+    fn clear(&mut self) -> OpResult<()> {
+        if self.replace {
+            self.conn.execute(&format!("DELETE FROM {}", self.table))?;
+            self.replace = false;
+        }
+        Ok(())
+    }
+
+    /// Inserts a row with a field for each column
+    pub fn insert(&mut self, fields: &[Field]) -> OpResult<()> {
+        self.clear()?;
+        for (i, field) in fields.iter().enumerate() {
+            let index = i as c_int + 1;
+            unsafe {
+                match field {
+                    Field::Null => sqlite3_bind_null(self.stmt, index),
+                    Field::Int(value) => sqlite3_bind_int64(self.stmt, index, *value),
+                    Field::Bool(value) => sqlite3_bind_int64(self.stmt, index, *value as i64),
+                    Field::Text(value) => sqlite3_bind_text(
+                        self.stmt,
+                        index,
+                        value.as_ptr() as *const c_char,
+                        value.len() as c_int,
+                        SQLITE_TRANSIENT,
+                    ),
+                    Field::Bytes(value) => sqlite3_bind_blob(
+                        self.stmt,
+                        index,
+                        value.as_ptr() as *const c_void,
+                        value.len() as c_int,
+                        SQLITE_TRANSIENT,
+                    ),
+                };
+            }
+        }
+        let rc = unsafe { sqlite3_step(self.stmt) };
+        unsafe { sqlite3_reset(self.stmt) };
+        match rc {
+            SQLITE_DONE => Ok(()),
+            _ => Err(self
+                .conn
+                .error()
+                .join_msg(&format!(" Unable to insert into {}", self.table))),
+        }
+    }
+
+    /// Commits the rows inserted so far, e.g. for `--flush-interval`
+    pub fn commit(&self) -> OpResult<()> {
+        self.conn.commit()
+    }
+
+    /// Inserts the remaining rows, creates the indexes and commits
+    pub fn finish(&mut self) -> OpResult<()> {
+        self.clear()?;
+        for column in &self.indexes {
+            self.conn.execute(&format!(
+                "CREATE INDEX IF NOT EXISTS {}_{} ON {} ({})",
+                self.table, column, self.table, column
+            ))?;
+        }
+        self.conn.commit()
+    }
+}
+
+impl Drop for TableWriter {
+    fn drop(&mut self) {
+        unsafe { sqlite3_finalize(self.stmt) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_table_writer() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.sqlite");
+        let conn = Rc::new(Connection::open(&path).unwrap());
+        let columns = [
+            "name TEXT NOT NULL",
+            "value INTEGER",
+            "note TEXT",
+            "data BLOB",
+        ];
+        let mut writer = TableWriter::new(conn.clone(), "t", &columns, &["name"], true).unwrap();
+        let data = [0xab, 0x00];
+        writer
+            .insert(&[
+                Field::Text("a;1"),
+                Field::Int(1),
+                Field::Text(""),
+                Field::Bytes(&data),
+            ])
+            .unwrap();
+        writer
+            .insert(&[
+                Field::Text("b"),
+                Field::Bool(true),
+                Field::Null,
+                Field::Null,
+            ])
+            .unwrap();
+        writer.finish().unwrap();
+        assert_eq!(
+            conn.query("SELECT name, typeof(value), value + 1, note IS NULL, hex(data) FROM t")
+                .unwrap(),
+            vec![
+                vec!["a;1", "integer", "2", "0", "AB00"],
+                vec!["b", "integer", "2", "1", ""]
+            ]
+        );
+
+        // Uncommitted rows are rolled back
+        writer
+            .insert(&[Field::Text("c"), Field::Int(3), Field::Null, Field::Null])
+            .unwrap();
+        drop(writer);
+        drop(conn);
+        let conn = Rc::new(Connection::open(&path).unwrap());
+        assert_eq!(conn.query("SELECT count(*) FROM t").unwrap()[0][0], "2");
+
+        // Replaces the previous rows
+        let mut writer = TableWriter::new(conn.clone(), "t", &columns, &[], true).unwrap();
+        writer
+            .insert(&[Field::Text("d"), Field::Int(4), Field::Null, Field::Null])
+            .unwrap();
+        writer.finish().unwrap();
+        assert_eq!(conn.query("SELECT name FROM t").unwrap(), vec![vec!["d"]]);
+    }
+}
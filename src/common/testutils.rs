@@ -0,0 +1,394 @@
+//! Helpers to build synthetic transactions and blocks for unit tests
+use bitcoin::hashes::{sha256d, Hash};
+use bitcoin::pow::{CompactTarget, Target};
+use bitcoin::BlockHash;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::path::Path;
+use std::thread;
+
+use rusty_leveldb::{Options, DB};
+
+use crate::blockchain::parser::snapshot::write_core_varint;
+use crate::blockchain::parser::types::{Bitcoin, Coin};
+use crate::blockchain::proto::block::Block;
+use crate::blockchain::proto::header::BlockHeader;
+use crate::blockchain::proto::tx::{EvaluatedTx, RawTx, TxInput, TxOutpoint, TxOutput};
+use crate::blockchain::proto::varuint::VarUint;
+use crate::blockchain::proto::{Hashed, ToRaw};
+use crate::blockchain::undo::{BlockUndo, SpentOutput};
+use crate::common::utils;
+
+/// Returns a P2PKH script paying to a hash160 derived from `seed`
+pub fn p2pkh_script(seed: u8) -> Vec<u8> {
+    let mut script = vec![0x76, 0xa9, 0x14];
+    script.extend_from_slice(&[seed; 20]);
+    script.extend_from_slice(&[0x88, 0xac]);
+    script
+}
+
+/// Builds a transaction spending the given outpoints
+pub fn tx(inputs: &[(sha256d::Hash, u32)], outputs: &[(u64, Vec<u8>)]) -> RawTx {
+    let inputs = inputs
+        .iter()
+        .map(|(txid, index)| TxInput {
+            outpoint: TxOutpoint::new(*txid, *index),
+            script_len: VarUint::from(0u8),
+            script_sig: vec![],
+            seq_no: 0xffffffff,
+            witness: vec![],
+        })
+        .collect::<Vec<TxInput>>();
+    build_tx(inputs, outputs)
+}
+
+/// Builds a coinbase transaction. The height is embedded into the scriptSig (BIP34),
+/// so coinbases with the same outputs still have a unique txid.
+pub fn coinbase(height: u64, outputs: &[(u64, Vec<u8>)]) -> RawTx {
+    let mut script_sig = vec![0x08];
+    script_sig.extend_from_slice(&height.to_le_bytes());
+    let input = TxInput {
+        outpoint: TxOutpoint::new(sha256d::Hash::all_zeros(), 0xffffffff),
+        script_len: VarUint::from(script_sig.len() as u8),
+        script_sig,
+        seq_no: 0xffffffff,
+        witness: vec![],
+    };
+    build_tx(vec![input], outputs)
+}
+
+fn build_tx(inputs: Vec<TxInput>, outputs: &[(u64, Vec<u8>)]) -> RawTx {
+    let outputs = outputs
+        .iter()
+        .map(|(value, script)| TxOutput {
+            value: *value,
+            script_len: VarUint::from(script.len() as u8),
+            script_pubkey: script.clone(),
+        })
+        .collect::<Vec<TxOutput>>();
+    RawTx {
+        version: 1,
+        in_count: VarUint::from(inputs.len() as u8),
+        inputs,
+        out_count: VarUint::from(outputs.len() as u8),
+        outputs,
+        locktime: 0,
+        version_id: 0x00,
+        encoder: Bitcoin.address_encoder(),
+    }
+}
+
+/// Builds a block on top of `prev_hash` with a valid merkle root
+pub fn block(prev_hash: sha256d::Hash, timestamp: u32, txs: Vec<RawTx>) -> Block {
+    let tx_count = VarUint::from(txs.len() as u8);
+    let txs = txs
+        .into_iter()
+        .map(|raw| Hashed::double_sha256(EvaluatedTx::from(raw)))
+        .collect::<Vec<Hashed<EvaluatedTx>>>();
+    let mut header = BlockHeader {
+        version: 1,
+        prev_hash,
+        merkle_root: utils::merkle_root(txs.iter().map(|tx| tx.hash).collect()),
+        timestamp,
+        bits: 0x207fffff,
+        nonce: 0,
+    };
+    // Grinds the nonce until the hash meets the regtest target, so the block passes --verify
+    let target = Target::from_compact(CompactTarget::from_consensus(header.bits));
+    while !target.is_met_by(BlockHash::from_raw_hash(
+        Hashed::double_sha256(header.clone()).hash,
+    )) {
+        header.nonce += 1;
+    }
+    let size = 80
+        + tx_count.to_bytes().len()
+        + txs
+            .iter()
+            .map(|tx| tx.value.to_bytes().len())
+            .sum::<usize>();
+    Block {
+        size: size as u32,
+        header: Hashed::double_sha256(header),
+        aux_pow_extension: None,
+        tx_count,
+        txs,
+        raw: None,
+        undo: None,
+    }
+}
+
+/// Serializes the given block as stored in a blk file, without magic and size
+pub fn block_bytes(block: &Block) -> Vec<u8> {
+    let mut data = block.header.value.to_bytes();
+    data.extend(block.tx_count.to_bytes());
+    for tx in &block.txs {
+        data.extend(tx.value.to_bytes());
+    }
+    data
+}
+
+/// Writes the given main chain as `blk00000.dat` along with a LevelDB block index into `dir`,
+/// so it can be consumed like a Bitcoin Core blocks directory.
+/// Existing files are overwritten, which allows to simulate a growing chain.
+pub fn write_blocks_dir(dir: &Path, blocks: &[Block]) {
+    write_blocks_dir_with_stale(dir, blocks, &[]);
+}
+
+/// Like `write_blocks_dir`, but additionally stores stale blocks at the given heights
+pub fn write_blocks_dir_with_stale(dir: &Path, blocks: &[Block], stale: &[(u64, &Block)]) {
+    let stale: Vec<_> = stale.iter().map(|(h, b)| (*h, *b, STATUS_VALID)).collect();
+    write_blocks(dir, blocks, &stale, 0xd9b4bef9, false);
+}
+
+/// Like `write_blocks_dir_with_stale`, but the extra blocks are marked as failed validation
+/// (BLOCK_FAILED_VALID), or as descendants of a failed block (BLOCK_FAILED_CHILD) if `child` is set
+pub fn write_blocks_dir_with_failed(dir: &Path, blocks: &[Block], failed: &[(u64, &Block, bool)]) {
+    let failed: Vec<_> = failed
+        .iter()
+        .map(|(h, b, child)| (*h, *b, STATUS_VALID | if *child { 64 } else { 32 }))
+        .collect();
+    write_blocks(dir, blocks, &failed, 0xd9b4bef9, false);
+}
+
+/// Like `write_blocks_dir`, but frames the blocks with the given network magic
+pub fn write_blocks_dir_with_magic(dir: &Path, blocks: &[Block], magic: u32) {
+    write_blocks(dir, blocks, &[], magic, false);
+}
+
+/// Like `write_blocks_dir`, but also writes the undo data of the blocks to `rev00000.dat`.
+/// The blocks may only spend outputs of previous blocks.
+pub fn write_blocks_dir_with_undo(dir: &Path, blocks: &[Block]) {
+    write_blocks(dir, blocks, &[], 0xd9b4bef9, true);
+}
+
+/// Returns the undo data of each block, as Bitcoin Core would write it
+pub fn block_undos(blocks: &[Block]) -> Vec<BlockUndo> {
+    let mut coins = HashMap::new();
+    let mut undos = Vec::with_capacity(blocks.len());
+    for (height, block) in blocks.iter().enumerate() {
+        let mut undo = BlockUndo::default();
+        for tx in &block.txs {
+            let is_coinbase = tx.value.is_coinbase();
+            if !is_coinbase {
+                let spent = tx
+                    .value
+                    .inputs
+                    .iter()
+                    .map(|input| coins.remove(&input.outpoint.key()).unwrap())
+                    .collect();
+                undo.txs.push(spent);
+            }
+            for (i, output) in tx.value.outputs.iter().enumerate() {
+                let coin = SpentOutput {
+                    height: height as u64,
+                    is_coinbase,
+                    value: output.out.value,
+                    script_pubkey: output.out.script_pubkey.clone(),
+                };
+                coins.insert(TxOutpoint::new(tx.hash, i as u32).key(), coin);
+            }
+        }
+        undos.push(undo);
+    }
+    undos
+}
+
+// BLOCK_VALID_SCRIPTS | BLOCK_HAVE_DATA
+const STATUS_VALID: u64 = 5 | 8;
+const BLOCK_HAVE_UNDO: u64 = 16;
+
+fn write_blocks(
+    dir: &Path,
+    blocks: &[Block],
+    extra: &[(u64, &Block, u64)],
+    magic: u32,
+    with_undo: bool,
+) {
+    let undos = match with_undo {
+        true => block_undos(blocks),
+        false => Vec::new(),
+    };
+    let mut blk_data = Vec::new();
+    let mut rev_data = Vec::new();
+    let mut db = DB::open(dir.join("index"), Options::default()).unwrap();
+    let all = blocks
+        .iter()
+        .enumerate()
+        .map(|(h, b)| (h as u64, b, STATUS_VALID));
+    for (i, (height, block, status)) in all.chain(extra.iter().copied()).enumerate() {
+        blk_data.extend(magic.to_le_bytes());
+        blk_data.extend(block.size.to_le_bytes());
+        let data_offset = blk_data.len() as u64;
+        blk_data.extend(block_bytes(block));
+
+        // The genesis block has no undo data
+        let undo_offset = match undos.get(i) {
+            Some(undo) if height > 0 => {
+                let data = undo.to_bytes();
+                rev_data.extend(magic.to_le_bytes());
+                rev_data.extend((data.len() as u32).to_le_bytes());
+                let undo_offset = rev_data.len() as u64;
+                let mut preimage = block.header.value.prev_hash.to_byte_array().to_vec();
+                preimage.extend(&data);
+                rev_data.extend(data);
+                rev_data.extend(sha256d::Hash::hash(&preimage).as_byte_array());
+                Some(undo_offset)
+            }
+            _ => None,
+        };
+
+        // version, height, status, tx count, file, offset, undo offset
+        let status = status | undo_offset.map_or(0, |_| BLOCK_HAVE_UNDO);
+        let mut record = Vec::new();
+        for value in [1, height, status, block.tx_count.value, 0, data_offset] {
+            write_core_varint(&mut record, value);
+        }
+        if let Some(undo_offset) = undo_offset {
+            write_core_varint(&mut record, undo_offset);
+        }
+        record.extend(block.header.value.to_bytes());
+        let mut key = vec![b'b'];
+        key.extend(block.header.hash.as_byte_array());
+        db.put(&key, &record).unwrap();
+    }
+    db.close().unwrap();
+    fs::write(dir.join("blk00000.dat"), blk_data).unwrap();
+    if with_undo {
+        fs::write(dir.join("rev00000.dat"), rev_data).unwrap();
+    }
+}
+
+/// Starts a NATS server for a single connection, which answers each PING with a PONG.
+/// Returns its port and a handle to the lines received, along with the payloads.
+pub fn nats_server() -> (u16, thread::JoinHandle<Vec<String>>) {
+    nats_server_with_ping(false)
+}
+
+/// Like `nats_server`, but with `ping` the server also sends a PING after the first PONG
+pub fn nats_server_with_ping(ping: bool) -> (u16, thread::JoinHandle<Vec<String>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let handle = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        stream
+            .write_all(b"INFO {\"server_id\":\"test\",\"max_payload\":1048576}\r\n")
+            .unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut received = Vec::new();
+        let mut line = String::new();
+        while reader.read_line(&mut line).unwrap() > 0 {
+            let command = line.trim_end().to_string();
+            line.clear();
+            if command == "PING" {
+                stream.write_all(b"PONG\r\n").unwrap();
+                if ping && !received.contains(&command) {
+                    stream.write_all(b"PING\r\n").unwrap();
+                }
+            }
+            if let Some(len) = command
+                .strip_prefix("PUB ")
+                .and_then(|c| c.split(' ').nth(1))
+            {
+                let mut payload = vec![0; len.parse::<usize>().unwrap() + 2];
+                reader.read_exact(&mut payload).unwrap();
+                payload.truncate(payload.len() - 2);
+                received.push(command);
+                received.push(String::from_utf8(payload).unwrap());
+                continue;
+            }
+            received.push(command);
+        }
+        received
+    });
+    (port, handle)
+}
+
+/// Statements and data a PostgreSQL server received
+#[derive(Default)]
+pub struct PostgresReceived {
+    pub startup: HashMap<String, String>,
+    pub password: String,
+    pub queries: Vec<String>,
+    /// Data of each COPY statement
+    pub copy_data: Vec<Vec<u8>>,
+}
+
+/// Starts a PostgreSQL server for a single connection, which asks for an MD5 password with the salt
+/// `salt` and accepts all statements but `FAIL`
+pub fn postgres_server() -> (u16, thread::JoinHandle<PostgresReceived>) {
+    fn message(stream: &mut impl Write, tag: u8, body: &[u8]) {
+        stream.write_all(&[tag]).unwrap();
+        stream
+            .write_all(&(body.len() as i32 + 4).to_be_bytes())
+            .unwrap();
+        stream.write_all(body).unwrap();
+    }
+    fn read(reader: &mut impl Read) -> Option<(u8, Vec<u8>)> {
+        let mut header = [0; 5];
+        reader.read_exact(&mut header).ok()?;
+        let len = i32::from_be_bytes(header[1..].try_into().unwrap()) as usize;
+        let mut body = vec![0; len - 4];
+        reader.read_exact(&mut body).unwrap();
+        Some((header[0], body))
+    }
+    fn cstr(body: &[u8]) -> String {
+        String::from_utf8(body[..body.len() - 1].to_vec()).unwrap()
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let handle = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut received = PostgresReceived::default();
+
+        let mut len = [0; 4];
+        reader.read_exact(&mut len).unwrap();
+        let mut startup = vec![0; i32::from_be_bytes(len) as usize - 4];
+        reader.read_exact(&mut startup).unwrap();
+        let params: Vec<&[u8]> = startup[4..].split(|b| *b == 0).collect();
+        for pair in params
+            .chunks(2)
+            .filter(|p| p.len() == 2 && !p[0].is_empty())
+        {
+            let [key, value] = [pair[0], pair[1]].map(|s| String::from_utf8(s.to_vec()).unwrap());
+            received.startup.insert(key, value);
+        }
+        message(&mut stream, b'R', &[0, 0, 0, 5, b's', b'a', b'l', b't']);
+        let (_, password) = read(&mut reader).unwrap();
+        received.password = cstr(&password);
+        message(&mut stream, b'R', &[0; 4]);
+        message(&mut stream, b'S', b"server_version\x0016.0\x00");
+        message(&mut stream, b'Z', b"I");
+
+        while let Some((tag, body)) = read(&mut reader) {
+            if tag != b'Q' {
+                break;
+            }
+            let sql = cstr(&body);
+            if sql == "FAIL" {
+                message(
+                    &mut stream,
+                    b'E',
+                    b"SERROR\x00C42601\x00Msyntax error\x00\x00",
+                );
+            } else if sql.starts_with("COPY") {
+                message(&mut stream, b'G', &[1, 0, 0]);
+                let mut data = Vec::new();
+                while let Some((b'd', chunk)) = read(&mut reader) {
+                    data.extend(chunk);
+                }
+                received.copy_data.push(data);
+                message(&mut stream, b'C', b"COPY\x00");
+            } else {
+                message(&mut stream, b'C', b"OK\x00");
+            }
+            message(&mut stream, b'Z', b"I");
+            received.queries.push(sql);
+        }
+        received
+    });
+    (port, handle)
+}
@@ -0,0 +1,211 @@
+//! Parser for the subset of TOML used by chain spec files: `key = value` pairs of strings,
+//! integers, booleans and arrays thereof at the top level, along with comments.
+//! Tables are not supported, as the coin config is flat.
+use serde_json::{Map, Number, Value};
+
+/// Parses the document into a JSON object, so it is validated like a JSON coin config.
+/// Errors carry the line number.
+pub fn parse(text: &str) -> Result<Value, String> {
+    let mut config = Map::new();
+    let mut lines = text.lines().enumerate();
+    while let Some((i, line)) = lines.next() {
+        let fail = |msg: &str| format!("line {}: {}", i + 1, msg);
+        let rest = strip_comment(line).trim();
+        if rest.is_empty() {
+            continue;
+        }
+        if rest.starts_with('[') {
+            return Err(fail("tables are not supported"));
+        }
+        let (key, value) = match rest.find('=') {
+            Some(pos) => (rest[..pos].trim(), rest[pos + 1..].trim()),
+            None => return Err(fail("expected `key = value`")),
+        };
+        let valid_key = !key.is_empty()
+            && key
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+        if !valid_key {
+            return Err(fail(&format!("invalid key `{}`", key)));
+        }
+        // Arrays may span several lines until the closing bracket
+        let mut value = value.to_string();
+        if value.starts_with('[') {
+            while !is_closed(&value) {
+                match lines.next() {
+                    Some((_, next)) => {
+                        value.push(' ');
+                        value.push_str(strip_comment(next).trim());
+                    }
+                    None => return Err(fail("unclosed array")),
+                }
+            }
+        }
+        let (parsed, remaining) = parse_value(&value).map_err(|msg| fail(&msg))?;
+        if !remaining.trim().is_empty() {
+            return Err(fail(&format!("unexpected `{}`", remaining.trim())));
+        }
+        if config.insert(key.to_string(), parsed).is_some() {
+            return Err(fail(&format!("duplicate key `{}`", key)));
+        }
+    }
+    Ok(Value::Object(config))
+}
+
+/// Removes a trailing comment, a `#` within a string is kept
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match (quote, c) {
+            (Some('"'), '\\') if !escaped => {
+                escaped = true;
+                continue;
+            }
+            (Some(q), c) if c == q && !escaped => quote = None,
+            (None, '"' | '\'') => quote = Some(c),
+            (None, '#') => return &line[..i],
+            _ => {}
+        }
+        escaped = false;
+    }
+    line
+}
+
+/// Returns true if all brackets outside of strings are closed
+fn is_closed(value: &str) -> bool {
+    let mut depth = 0i32;
+    let mut quote = None;
+    for c in value.chars() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (None, '"' | '\'') => quote = Some(c),
+            (None, '[') => depth += 1,
+            (None, ']') => depth -= 1,
+            _ => {}
+        }
+    }
+    depth <= 0
+}
+
+/// Parses a value at the start of the input, returns it along with the remaining input
+fn parse_value(input: &str) -> Result<(Value, &str), String> {
+    let input = input.trim_start();
+    if let Some(rest) = input.strip_prefix('"') {
+        let mut value = String::new();
+        let mut chars = rest.char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => return Ok((Value::String(value), &rest[i + 1..])),
+                '\\' => match chars.next().map(|(_, c)| c) {
+                    Some('"') => value.push('"'),
+                    Some('\\') => value.push('\\'),
+                    Some('n') => value.push('\n'),
+                    Some('t') => value.push('\t'),
+                    _ => return Err(String::from("unsupported escape sequence")),
+                },
+                c => value.push(c),
+            }
+        }
+        return Err(String::from("unclosed string"));
+    }
+    if let Some(rest) = input.strip_prefix('\'') {
+        return match rest.find('\'') {
+            Some(end) => Ok((Value::String(rest[..end].to_string()), &rest[end + 1..])),
+            None => Err(String::from("unclosed string")),
+        };
+    }
+    if let Some(mut rest) = input.strip_prefix('[') {
+        let mut values = Vec::new();
+        loop {
+            rest = rest.trim_start();
+            if let Some(after) = rest.strip_prefix(']') {
+                return Ok((Value::Array(values), after));
+            }
+            let (value, after) = parse_value(rest)?;
+            values.push(value);
+            rest = after.trim_start();
+            match rest.chars().next() {
+                Some(',') => rest = &rest[1..],
+                Some(']') => {}
+                _ => return Err(String::from("expected `,` or `]` in array")),
+            }
+        }
+    }
+    let end = input
+        .find(|c: char| c == ',' || c == ']' || c.is_whitespace())
+        .unwrap_or(input.len());
+    let (token, rest) = input.split_at(end);
+    let value = match token {
+        "true" => Value::Bool(true),
+        "false" => Value::Bool(false),
+        token => Value::Number(parse_integer(token)?),
+    };
+    Ok((value, rest))
+}
+
+/// Parses a decimal or `0x` prefixed hex integer, `_` separators are allowed
+fn parse_integer(token: &str) -> Result<Number, String> {
+    let digits = token.replace('_', "");
+    let parsed = match digits.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).map(Number::from),
+        None if digits.starts_with('-') => digits.parse::<i64>().map(Number::from),
+        None => digits.parse::<u64>().map(Number::from),
+    };
+    parsed.map_err(|_| format!("invalid value `{}`", token))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_parse() {
+        let config = parse(
+            r#"
+            # Bitcoin Cash
+            name = "BitcoinCash"   # display name
+            magic = 'e3e1f3e8'
+            pubkey_version = 0
+            script_version = [5]
+            cashaddr_hrp = "bitcoincash#1"
+            initial_subsidy = 5_000_000_000
+            aux_pow_activation_version = 0x10000
+            flags = [
+                1, 2,  # multi line
+                3,
+            ]
+            enabled = true
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config,
+            json!({
+                "name": "BitcoinCash",
+                "magic": "e3e1f3e8",
+                "pubkey_version": 0,
+                "script_version": [5],
+                "cashaddr_hrp": "bitcoincash#1",
+                "initial_subsidy": 5000000000u64,
+                "aux_pow_activation_version": 65536,
+                "flags": [1, 2, 3],
+                "enabled": true,
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let err = |text: &str| parse(text).unwrap_err();
+        assert_eq!(err("name"), "line 1: expected `key = value`");
+        assert_eq!(err("\n[coin]"), "line 2: tables are not supported");
+        assert_eq!(err("a = 1\na = 2"), "line 2: duplicate key `a`");
+        assert_eq!(err("a = \"x"), "line 1: unclosed string");
+        assert_eq!(err("a = [1, 2"), "line 1: unclosed array");
+        assert_eq!(err("a = 1 2"), "line 1: unexpected `2`");
+        assert_eq!(err("a = yes"), "line 1: invalid value `yes`");
+        assert_eq!(err("a b = 1"), "line 1: invalid key `a b`");
+    }
+}
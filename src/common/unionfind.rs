@@ -0,0 +1,61 @@
+/// Disjoint sets of the items 0..len with union by rank and path halving,
+/// both operations take nearly constant time
+#[derive(Default)]
+pub struct UnionFind {
+    parent: Vec<u32>,
+    rank: Vec<u8>,
+}
+
+impl UnionFind {
+    /// Adds a new set containing only the returned item
+    pub fn add(&mut self) -> u32 {
+        let item = self.parent.len() as u32;
+        self.parent.push(item);
+        self.rank.push(0);
+        item
+    }
+
+    /// Returns the representative of the set containing the item
+    pub fn find(&mut self, mut item: u32) -> u32 {
+        while self.parent[item as usize] != item {
+            let grandparent = self.parent[self.parent[item as usize] as usize];
+            self.parent[item as usize] = grandparent;
+            item = grandparent;
+        }
+        item
+    }
+
+    /// Merges the sets containing both items
+    pub fn union(&mut self, a: u32, b: u32) {
+        let (a, b) = (self.find(a), self.find(b));
+        if a == b {
+            return;
+        }
+        let (a, b) = if self.rank[a as usize] < self.rank[b as usize] {
+            (b, a)
+        } else {
+            (a, b)
+        };
+        self.parent[b as usize] = a;
+        if self.rank[a as usize] == self.rank[b as usize] {
+            self.rank[a as usize] += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_union_find() {
+        let mut sets = UnionFind::default();
+        let items: Vec<u32> = (0..6).map(|_| sets.add()).collect();
+        sets.union(items[0], items[1]);
+        sets.union(items[2], items[3]);
+        sets.union(items[1], items[3]);
+        assert_eq!(sets.find(items[0]), sets.find(items[2]));
+        assert_ne!(sets.find(items[0]), sets.find(items[4]));
+        assert_ne!(sets.find(items[4]), sets.find(items[5]));
+    }
+}
@@ -1,7 +1,288 @@
 use bitcoin::hashes::{sha256d, Hash};
-use std::path::PathBuf;
+use chrono::{DateTime, Utc};
+use std::cell::{Cell, RefCell};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::SystemTime;
 
 use crate::blockchain::parser::types::CoinType;
+use crate::common::hashing;
+use crate::errors::{OpError, OpErrorKind, OpResult};
+
+/// Byte order used to render block and transaction hashes in callback outputs
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum HashEndian {
+    /// Reversed byte order as shown by block explorers and Bitcoin Core RPC
+    Display,
+    /// Byte order as serialized in blk files and on the wire
+    Internal,
+}
+
+impl HashEndian {
+    /// Renders the given hash as hex string in this byte order
+    pub fn format(&self, hash: &sha256d::Hash) -> String {
+        match self {
+            HashEndian::Display => format!("{}", hash),
+            HashEndian::Internal => arr_to_hex(hash.as_byte_array()),
+        }
+    }
+}
+
+impl FromStr for HashEndian {
+    type Err = OpError;
+    fn from_str(s: &str) -> OpResult<Self> {
+        match s {
+            "display" => Ok(HashEndian::Display),
+            "internal" => Ok(HashEndian::Internal),
+            n => Err(OpError::new(OpErrorKind::InvalidArgsError)
+                .join_msg(&format!("Unknown hash endianness `{}`!", n))),
+        }
+    }
+}
+
+thread_local! {
+    // Set per parser thread like the output config below
+    static HASH_ENDIAN: Cell<HashEndian> = const { Cell::new(HashEndian::Display) };
+}
+
+/// Sets the byte order which is used by `hash_to_string()`
+pub fn set_hash_endian(endian: HashEndian) {
+    HASH_ENDIAN.with(|e| e.set(endian));
+}
+
+/// Returns the configured byte order for hashes (default: display)
+pub fn hash_endian() -> HashEndian {
+    HASH_ENDIAN.with(|e| e.get())
+}
+
+thread_local! {
+    static COPY_ON_RENAME: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Lets `rename()` copy and delete files instead of renaming them (`--no-atomic-rename`)
+pub fn set_atomic_rename(atomic: bool) {
+    COPY_ON_RENAME.with(|c| c.set(!atomic));
+}
+
+/// Moves the finished tmp file of a dump to its final path.
+/// Some network or overlay filesystems don't support renames, so with `--no-atomic-rename`
+/// the file is copied and deleted instead, which leaves a partial file if it is interrupted.
+pub fn rename<P: AsRef<Path>, Q: AsRef<Path>>(from: P, to: Q) -> OpResult<()> {
+    move_file(
+        from.as_ref(),
+        to.as_ref(),
+        !COPY_ON_RENAME.with(|c| c.get()),
+    )
+}
+
+fn move_file(from: &Path, to: &Path, atomic: bool) -> OpResult<()> {
+    let result = match atomic {
+        true => fs::rename(from, to),
+        false => fs::copy(from, to).and_then(|_| fs::remove_file(from)),
+    };
+    result.map_err(|err| {
+        let mut msg = format!("Unable to move {} to {}", from.display(), to.display());
+        if atomic {
+            msg.push_str(" (try --no-atomic-rename on network filesystems)");
+        }
+        OpError::from(err).join_msg(&format!("{}.", msg))
+    })
+}
+
+/// Names of the output files of all dump callbacks (`--filename-template`).
+/// Supported placeholders: `{callback}` (e.g. `balances` or `transactions` for csvdump),
+/// `{coin}`, `{network}` (`mainnet`, `testnet`, `signet` or `regtest`), `{start}`, `{end}` and `{date}` (UTC, YYYY-MM-DD).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FilenameTemplate {
+    template: String,
+    coin: String,
+    network: String,
+}
+
+impl FilenameTemplate {
+    const PLACEHOLDERS: [&'static str; 6] = ["callback", "coin", "network", "start", "end", "date"];
+
+    pub fn new(template: &str, coin: &CoinType) -> OpResult<Self> {
+        let invalid = |msg: String| Err(OpError::new(OpErrorKind::InvalidArgsError).join_msg(&msg));
+        let mut rest = template;
+        while let Some(open) = rest.find('{') {
+            let close = match rest[open..].find('}') {
+                Some(close) => open + close,
+                None => return invalid(format!("Unclosed placeholder in `{}`!", template)),
+            };
+            let name = &rest[open + 1..close];
+            if !FilenameTemplate::PLACEHOLDERS.contains(&name) {
+                return invalid(format!(
+                    "Unknown placeholder `{{{}}}` in `{}`!",
+                    name, template
+                ));
+            }
+            rest = &rest[close + 1..];
+        }
+        // Callbacks like csvdump write several files, which would overwrite each other
+        if !template.contains("{callback}") {
+            return invalid(format!("`{}` must contain {{callback}}!", template));
+        }
+        if template.contains(std::path::is_separator) {
+            return invalid(format!("`{}` must not contain a path separator!", template));
+        }
+        let coin = coin.name.to_lowercase();
+        let network = match coin.as_str() {
+            network @ ("signet" | "regtest") => String::from(network),
+            coin if coin.starts_with("testnet") => String::from("testnet"),
+            _ => String::from("mainnet"),
+        };
+        Ok(FilenameTemplate {
+            template: String::from(template),
+            coin,
+            network,
+        })
+    }
+
+    /// Renders the file name without extension
+    pub fn render(&self, callback: &str, start: u64, end: u64) -> String {
+        let date: DateTime<Utc> = SystemTime::now().into();
+        self.template
+            .replace("{callback}", callback)
+            .replace("{coin}", &self.coin)
+            .replace("{network}", &self.network)
+            .replace("{start}", &start.to_string())
+            .replace("{end}", &end.to_string())
+            .replace("{date}", &date.format("%Y-%m-%d").to_string())
+    }
+}
+
+thread_local! {
+    // Set per parser thread, so parsers running side by side (e.g. in tests) don't interfere
+    static FILENAME_TEMPLATE: RefCell<Option<FilenameTemplate>> = const { RefCell::new(None) };
+}
+
+/// Sets the template which is used by `output_name()`, None restores the default naming
+pub fn set_filename_template(template: Option<FilenameTemplate>) {
+    FILENAME_TEMPLATE.with(|t| *t.borrow_mut() = template);
+}
+
+/// Compression of the final output files (`--compress`)
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Compression {
+    Gzip,
+    #[cfg(feature = "zstd")]
+    Zstd,
+}
+
+impl Compression {
+    /// Extension appended to the name of compressed files
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Compression::Gzip => "gz",
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => "zst",
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = OpError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "gzip" => Ok(Compression::Gzip),
+            #[cfg(feature = "zstd")]
+            "zstd" => Ok(Compression::Zstd),
+            #[cfg(not(feature = "zstd"))]
+            "zstd" => Err(OpError::new(OpErrorKind::InvalidArgsError).join_msg(
+                "--compress zstd requires a build with zstd support: cargo build --release --features zstd",
+            )),
+            _ => Err(OpError::new(OpErrorKind::InvalidArgsError)
+                .join_msg(&format!("Unsupported compression `{}`", s))),
+        }
+    }
+}
+
+/// Location and naming of the final output files of all callbacks,
+/// temp files and the state of incremental runs stay in the dump folder
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct OutputConfig {
+    /// Folder for the output files instead of the dump folder (`--output-dir`)
+    pub dir: Option<PathBuf>,
+    /// Prepended to the name of each output file (`--output-prefix`)
+    pub prefix: Option<String>,
+    /// Compresses the output files while writing or once the callback completed (`--compress`)
+    pub compression: Option<Compression>,
+}
+
+impl OutputConfig {
+    pub fn new(
+        dir: Option<PathBuf>,
+        prefix: Option<String>,
+        compression: Option<Compression>,
+    ) -> OpResult<Self> {
+        if matches!(&prefix, Some(prefix) if prefix.contains(std::path::is_separator)) {
+            return Err(
+                OpError::new(OpErrorKind::InvalidArgsError).join_msg(&format!(
+                    "--output-prefix `{}` must not contain a path separator!",
+                    prefix.unwrap()
+                )),
+            );
+        }
+        Ok(OutputConfig {
+            dir,
+            prefix,
+            compression,
+        })
+    }
+}
+
+thread_local! {
+    static OUTPUT_CONFIG: RefCell<OutputConfig> = RefCell::new(OutputConfig::default());
+    // Paths handed out by `output_path()` since the last `take_output_paths()`
+    static OUTPUT_PATHS: RefCell<Vec<PathBuf>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Sets the output configuration which is used by `output_name()` and `output_path()`
+pub fn set_output_config(config: OutputConfig) {
+    OUTPUT_CONFIG.with(|c| *c.borrow_mut() = config);
+    OUTPUT_PATHS.with(|paths| paths.borrow_mut().clear());
+}
+
+/// Returns the compression of the output files, if any
+pub fn output_compression() -> Option<Compression> {
+    OUTPUT_CONFIG.with(|c| c.borrow().compression)
+}
+
+/// Returns the name of the final output file of a callback,
+/// `<callback>-<start>-<end>.<ext>` unless a template is set, with the output prefix in front
+pub fn output_name(callback: &str, start: u64, end: u64, ext: &str) -> String {
+    let name = FILENAME_TEMPLATE.with(|t| match &*t.borrow() {
+        Some(template) => template.render(callback, start, end),
+        None => format!("{}-{}-{}", callback, start, end),
+    });
+    let prefix = OUTPUT_CONFIG.with(|c| c.borrow().prefix.clone().unwrap_or_default());
+    format!("{}{}.{}", prefix, name, ext)
+}
+
+/// Returns the path of the final output file of a callback, in the output dir if one is set.
+/// The path is remembered, so the file can be compressed once the callback completed.
+pub fn output_path(dump_folder: &Path, callback: &str, start: u64, end: u64, ext: &str) -> PathBuf {
+    let dir = OUTPUT_CONFIG.with(|c| c.borrow().dir.clone());
+    let path = dir
+        .as_deref()
+        .unwrap_or(dump_folder)
+        .join(output_name(callback, start, end, ext));
+    OUTPUT_PATHS.with(|paths| paths.borrow_mut().push(path.clone()));
+    path
+}
+
+/// Returns the paths handed out by `output_path()` so far and forgets them
+pub fn take_output_paths() -> Vec<PathBuf> {
+    OUTPUT_PATHS.with(|paths| paths.take())
+}
+
+/// Renders block and transaction hashes uniformly across all callbacks
+pub fn hash_to_string(hash: &sha256d::Hash) -> String {
+    hash_endian().format(hash)
+}
 
 /// Calculates merkle root for the whole block
 /// See: https://en.bitcoin.it/wiki/Protocol_documentation#Merkle_Trees
@@ -13,15 +294,13 @@ pub fn merkle_root(hashes: Vec<sha256d::Hash>) -> sha256d::Hash {
         let mut new_hashes = hashes
             .chunks(2)
             .filter(|c| c.len() == 2)
-            .map(|c| sha256d::Hash::hash(&[c[0], c[1]].concat()))
+            .map(|c| hashing::merkle_node(&c[0], &c[1]))
             .collect::<Vec<sha256d::Hash>>();
 
         // If the length is odd, take the last hash twice
-        if hashes.len() % 2 == 1 {
+        if !hashes.len().is_multiple_of(2) {
             let last_hash = hashes.last().unwrap();
-            new_hashes.push(sha256d::Hash::hash(
-                &[&last_hash[..], &last_hash[..]].concat(),
-            ));
+            new_hashes.push(hashing::merkle_node(last_hash, last_hash));
         }
         hashes = new_hashes;
     }
@@ -35,7 +314,7 @@ pub fn arr_to_hex(data: &[u8]) -> String {
 }
 
 pub fn hex_to_vec(hex_str: &str) -> Vec<u8> {
-    if hex_str.len() % 2 != 0 {
+    if !hex_str.len().is_multiple_of(2) {
         panic!("string length is not even");
     }
 
@@ -76,6 +355,37 @@ mod tests {
         assert_eq!(arr_to_hex(&test), expected);
     }
 
+    #[test]
+    fn test_hash_endian() {
+        let hash = sha256d::Hash::from_str(
+            "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f",
+        )
+        .unwrap();
+        assert_eq!(
+            HashEndian::Display.format(&hash),
+            "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f"
+        );
+        assert_eq!(
+            HashEndian::Internal.format(&hash),
+            "6fe28c0ab6f1b372c1a6a246ae63f74f931e8365e15a089c68d6190000000000"
+        );
+        assert_eq!(
+            "display".parse::<HashEndian>().unwrap(),
+            HashEndian::Display
+        );
+        assert_eq!(
+            "internal".parse::<HashEndian>().unwrap(),
+            HashEndian::Internal
+        );
+        assert!("big".parse::<HashEndian>().is_err());
+
+        // Parsers in other threads keep their own byte order
+        std::thread::spawn(|| set_hash_endian(HashEndian::Internal))
+            .join()
+            .unwrap();
+        assert_eq!(hash_endian(), HashEndian::Display);
+    }
+
     #[test]
     fn test_merkle_root() {
         let hashes = Vec::from([
@@ -120,4 +430,91 @@ mod tests {
         let merkle_hash = merkle_root(hashes);
         assert_eq!(merkle_hash, expected);
     }
+
+    #[test]
+    fn test_move_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let from = dir.path().join("balances.csv.tmp");
+        let to = dir.path().join("balances-0-1.csv");
+
+        // A failed rename is returned as error instead of panicking
+        let err = move_file(&from, &to, true).unwrap_err();
+        assert!(matches!(err.kind, OpErrorKind::IoError(_)));
+        assert!(err.to_string().contains("--no-atomic-rename"));
+
+        fs::write(&from, "address;balance\n").unwrap();
+        move_file(&from, &to, false).unwrap();
+        assert!(!from.exists());
+        assert_eq!(fs::read_to_string(&to).unwrap(), "address;balance\n");
+        assert!(move_file(&from, &to, false).is_err());
+
+        // --no-atomic-rename of a parser in another thread doesn't change rename() here
+        std::thread::spawn(|| set_atomic_rename(false))
+            .join()
+            .unwrap();
+        let err = rename(&from, &to).unwrap_err();
+        assert!(err.to_string().contains("--no-atomic-rename"));
+    }
+
+    #[test]
+    fn test_filename_template() {
+        let testnet = CoinType::from_str("testnet3").unwrap();
+        let template = FilenameTemplate::new("{network}/{callback}", &testnet);
+        assert!(template.is_err());
+        assert!(FilenameTemplate::new("{coin}-{start}", &testnet).is_err());
+        assert!(FilenameTemplate::new("{callback}-{height}", &testnet).is_err());
+        assert!(FilenameTemplate::new("{callback}-{start", &testnet).is_err());
+
+        let template =
+            FilenameTemplate::new("{coin}-{network}-{callback}-{end}", &testnet).unwrap();
+        assert_eq!(template.render("fees", 0, 10), "testnet3-testnet-fees-10");
+        let regtest = CoinType::from_str("regtest").unwrap();
+        let template = FilenameTemplate::new("{coin}-{network}-{callback}", &regtest).unwrap();
+        assert_eq!(template.render("fees", 0, 10), "regtest-regtest-fees");
+        let template = FilenameTemplate::new("{callback}-{date}", &testnet).unwrap();
+        assert_eq!(
+            template.render("fees", 0, 10).len(),
+            "fees-2024-01-01".len()
+        );
+
+        assert_eq!(output_name("fees", 0, 10, "csv"), "fees-0-10.csv");
+        set_filename_template(Some(template));
+        assert!(output_name("fees", 0, 10, "csv").starts_with("fees-20"));
+        set_filename_template(None);
+    }
+
+    #[test]
+    fn test_output_config() {
+        assert!(OutputConfig::new(None, Some(String::from("a/b")), None).is_err());
+        assert!("xz".parse::<Compression>().is_err());
+        #[cfg(not(feature = "zstd"))]
+        assert!("zstd".parse::<Compression>().is_err());
+        #[cfg(feature = "zstd")]
+        assert_eq!("zstd".parse::<Compression>().unwrap().extension(), "zst");
+
+        let config = OutputConfig::new(
+            Some(PathBuf::from("out")),
+            Some(String::from("mainnet_")),
+            "gzip".parse().ok(),
+        )
+        .unwrap();
+        set_output_config(config);
+        assert_eq!(output_name("fees", 0, 10, "csv"), "mainnet_fees-0-10.csv");
+        assert_eq!(
+            output_path(Path::new("dump"), "fees", 0, 10, "csv"),
+            PathBuf::from("out/mainnet_fees-0-10.csv")
+        );
+        assert_eq!(output_compression(), Some(Compression::Gzip));
+        assert_eq!(
+            take_output_paths(),
+            vec![PathBuf::from("out/mainnet_fees-0-10.csv")]
+        );
+        assert!(take_output_paths().is_empty());
+
+        set_output_config(OutputConfig::default());
+        assert_eq!(
+            output_path(Path::new("dump"), "fees", 0, 10, "csv"),
+            PathBuf::from("dump/fees-0-10.csv")
+        );
+    }
 }
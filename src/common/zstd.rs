@@ -0,0 +1,206 @@
+//! Minimal bindings to the system libzstd for `--compress zstd`.
+//! Only a streaming encoder, which is used like the `GzEncoder` of flate2.
+
+use std::ffi::{c_char, c_int, c_uint, c_void, CStr};
+use std::io::{self, Write};
+
+#[allow(non_camel_case_types)]
+type ZSTD_CCtx = c_void;
+
+// ZSTD_EndDirective
+const ZSTD_E_CONTINUE: c_int = 0;
+const ZSTD_E_FLUSH: c_int = 1;
+const ZSTD_E_END: c_int = 2;
+
+#[repr(C)]
+struct InBuffer {
+    src: *const c_void,
+    size: usize,
+    pos: usize,
+}
+
+#[repr(C)]
+struct OutBuffer {
+    dst: *mut c_void,
+    size: usize,
+    pos: usize,
+}
+
+#[link(name = "zstd")]
+extern "C" {
+    fn ZSTD_createCCtx() -> *mut ZSTD_CCtx;
+    fn ZSTD_freeCCtx(cctx: *mut ZSTD_CCtx) -> usize;
+    fn ZSTD_compressStream2(
+        cctx: *mut ZSTD_CCtx,
+        output: *mut OutBuffer,
+        input: *mut InBuffer,
+        end_op: c_int,
+    ) -> usize;
+    fn ZSTD_CStreamOutSize() -> usize;
+    fn ZSTD_isError(code: usize) -> c_uint;
+    fn ZSTD_getErrorName(code: usize) -> *const c_char;
+    #[cfg(test)]
+    fn ZSTD_createDCtx() -> *mut c_void;
+    #[cfg(test)]
+    fn ZSTD_freeDCtx(dctx: *mut c_void) -> usize;
+    #[cfg(test)]
+    fn ZSTD_decompressStream(
+        dctx: *mut c_void,
+        output: *mut OutBuffer,
+        input: *mut InBuffer,
+    ) -> usize;
+}
+
+fn check(code: usize) -> io::Result<usize> {
+    if unsafe { ZSTD_isError(code) } != 0 {
+        let name = unsafe { CStr::from_ptr(ZSTD_getErrorName(code)) };
+        return Err(io::Error::other(format!(
+            "zstd: {}",
+            name.to_string_lossy()
+        )));
+    }
+    Ok(code)
+}
+
+/// Compresses everything written to it into a single zstd frame with the default level.
+/// Like `GzEncoder`, the frame is only complete after `try_finish()`, dropping the encoder
+/// completes it as well but ignores errors.
+pub struct Encoder<W: Write> {
+    cctx: *mut ZSTD_CCtx,
+    writer: W,
+    buf: Vec<u8>,
+    finished: bool,
+}
+
+impl<W: Write> Encoder<W> {
+    pub fn new(writer: W) -> Self {
+        Encoder {
+            cctx: unsafe { ZSTD_createCCtx() },
+            writer,
+            buf: vec![0u8; unsafe { ZSTD_CStreamOutSize() }],
+            finished: false,
+        }
+    }
+
+    /// Passes `data` to the compressor and writes out what it returns, until
+    /// all input is consumed and, for a flush or the end of the frame, nothing is left.
+    fn compress(&mut self, data: &[u8], end_op: c_int) -> io::Result<()> {
+        let mut input = InBuffer {
+            src: data.as_ptr() as *const c_void,
+            size: data.len(),
+            pos: 0,
+        };
+        loop {
+            let mut output = OutBuffer {
+                dst: self.buf.as_mut_ptr() as *mut c_void,
+                size: self.buf.len(),
+                pos: 0,
+            };
+            let remaining =
+                check(unsafe { ZSTD_compressStream2(self.cctx, &mut output, &mut input, end_op) })?;
+            self.writer.write_all(&self.buf[..output.pos])?;
+            let done = match end_op {
+                ZSTD_E_CONTINUE => input.pos == input.size,
+                _ => remaining == 0,
+            };
+            if done {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Completes the frame and flushes the underlying writer. Nothing must be written afterwards.
+    pub fn try_finish(&mut self) -> io::Result<()> {
+        if !self.finished {
+            self.compress(&[], ZSTD_E_END)?;
+            self.finished = true;
+        }
+        self.writer.flush()
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.finished {
+            return Err(io::Error::other("zstd: write after the end of the frame"));
+        }
+        self.compress(buf, ZSTD_E_CONTINUE)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.finished {
+            self.compress(&[], ZSTD_E_FLUSH)?;
+        }
+        self.writer.flush()
+    }
+}
+
+impl<W: Write> Drop for Encoder<W> {
+    fn drop(&mut self) {
+        let _ = self.try_finish();
+        unsafe { ZSTD_freeCCtx(self.cctx) };
+    }
+}
+
+/// Decompresses the frames written by `Encoder`
+#[cfg(test)]
+pub fn decompress(data: &[u8]) -> Vec<u8> {
+    let dctx = unsafe { ZSTD_createDCtx() };
+    let mut input = InBuffer {
+        src: data.as_ptr() as *const c_void,
+        size: data.len(),
+        pos: 0,
+    };
+    let mut out = Vec::new();
+    let mut buf = vec![0u8; 1 << 17];
+    loop {
+        let mut output = OutBuffer {
+            dst: buf.as_mut_ptr() as *mut c_void,
+            size: buf.len(),
+            pos: 0,
+        };
+        let remaining = unsafe { ZSTD_decompressStream(dctx, &mut output, &mut input) };
+        out.extend_from_slice(&buf[..output.pos]);
+        // Done once the input is consumed and the output buffer wasn't filled up
+        if check(remaining).unwrap() == 0 && input.pos == input.size && output.pos < buf.len() {
+            break;
+        }
+        assert!(
+            input.pos < input.size || output.pos > 0,
+            "truncated zstd frame"
+        );
+    }
+    unsafe { ZSTD_freeDCtx(dctx) };
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encoder() {
+        let data = (0..1000000u32)
+            .flat_map(|i| (i % 1000).to_le_bytes())
+            .collect::<Vec<u8>>();
+        let mut encoder = Encoder::new(Vec::new());
+        encoder.write_all(&data[..10]).unwrap();
+        encoder.flush().unwrap();
+        encoder.write_all(&data[10..]).unwrap();
+        encoder.try_finish().unwrap();
+        assert!(encoder.write_all(b"x").is_err());
+        let compressed = std::mem::take(&mut encoder.writer);
+        assert!(compressed.len() < data.len() / 10);
+        assert_eq!(&compressed[..4], &[0x28, 0xb5, 0x2f, 0xfd]);
+        assert_eq!(decompress(&compressed), data);
+
+        // Dropping completes the frame as well
+        let mut compressed = Vec::new();
+        {
+            let mut encoder = Encoder::new(&mut compressed);
+            encoder.write_all(b"abc").unwrap();
+        }
+        assert_eq!(decompress(&compressed), b"abc");
+    }
+}
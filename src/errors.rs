@@ -49,6 +49,46 @@ impl OpError {
             message: self.message,
         }
     }
+
+    /// Wraps an error which occurred while reading the data at the given offset
+    pub fn deserialize(offset: u64, cause: OpError) -> Self {
+        Self::new(OpErrorKind::DeserializeError(DeserializeError {
+            offset,
+            height: None,
+            cause: Box::new(cause),
+        }))
+    }
+
+    /// Sets the height of the block which could not be deserialized, other errors are returned as is
+    pub fn at_height(mut self, block_height: u64) -> Self {
+        if let OpErrorKind::DeserializeError(ref mut err) = self.kind {
+            err.height = Some(block_height);
+        }
+        self
+    }
+}
+
+/// Location of blockchain data which could not be deserialized
+#[derive(Debug)]
+pub struct DeserializeError {
+    /// Byte offset in the blk file where reading failed
+    pub offset: u64,
+    /// Height of the block, if known
+    pub height: Option<u64>,
+    pub cause: Box<OpError>,
+}
+
+impl fmt::Display for DeserializeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.height {
+            Some(height) => write!(
+                f,
+                "block {} at offset {}: {}",
+                height, self.offset, self.cause
+            ),
+            None => write!(f, "offset {}: {}", self.offset, self.cause),
+        }
+    }
 }
 
 impl fmt::Display for OpError {
@@ -77,6 +117,8 @@ pub enum OpErrorKind {
     ByteOrderError(io::Error),
     Utf8Error(string::FromUtf8Error),
     ScriptError(script::ScriptError),
+    DeserializeError(DeserializeError),
+    AddressEncodingError(String),
     InvalidArgsError,
     CallbackError,
     ValidationError,
@@ -84,6 +126,8 @@ pub enum OpErrorKind {
     PoisonError,
     SendError,
     LevelDBError(String),
+    #[cfg(feature = "sqlite")]
+    SqliteError(String),
 }
 
 impl fmt::Display for OpErrorKind {
@@ -93,13 +137,17 @@ impl fmt::Display for OpErrorKind {
             OpErrorKind::ByteOrderError(ref err) => write!(f, "ByteOrder: {}", err),
             OpErrorKind::Utf8Error(ref err) => write!(f, "Utf8 Conversion: {}", err),
             OpErrorKind::ScriptError(ref err) => write!(f, "Script: {}", err),
+            OpErrorKind::DeserializeError(ref err) => write!(f, "Deserialize: {}", err),
+            OpErrorKind::AddressEncodingError(ref err) => write!(f, "AddressEncoding: {}", err),
             OpErrorKind::LevelDBError(ref err) => write!(f, "LevelDB: {}", err),
-            ref err @ OpErrorKind::PoisonError => write!(f, "Threading Error: {}", err),
-            ref err @ OpErrorKind::SendError => write!(f, "Sync: {}", err),
-            ref err @ OpErrorKind::InvalidArgsError => write!(f, "InvalidArgs: {}", err),
-            ref err @ OpErrorKind::CallbackError => write!(f, "Callback: {}", err),
-            ref err @ OpErrorKind::ValidationError => write!(f, "Validation: {}", err),
-            ref err @ OpErrorKind::RuntimeError => write!(f, "RuntimeError: {}", err),
+            #[cfg(feature = "sqlite")]
+            OpErrorKind::SqliteError(ref err) => write!(f, "SQLite: {}", err),
+            OpErrorKind::PoisonError => write!(f, "Threading Error"),
+            OpErrorKind::SendError => write!(f, "Sync"),
+            OpErrorKind::InvalidArgsError => write!(f, "InvalidArgs"),
+            OpErrorKind::CallbackError => write!(f, "Callback"),
+            OpErrorKind::ValidationError => write!(f, "Validation"),
+            OpErrorKind::RuntimeError => write!(f, "RuntimeError"),
             OpErrorKind::None => write!(f, ""),
         }
     }
@@ -112,6 +160,7 @@ impl error::Error for OpErrorKind {
             OpErrorKind::ByteOrderError(ref err) => Some(err),
             OpErrorKind::Utf8Error(ref err) => Some(err),
             OpErrorKind::ScriptError(ref err) => Some(err),
+            OpErrorKind::DeserializeError(ref err) => Some(err.cause.as_ref()),
             ref err @ OpErrorKind::PoisonError => Some(err),
             ref err @ OpErrorKind::SendError => Some(err),
             _ => None,
@@ -155,6 +204,12 @@ impl From<string::FromUtf8Error> for OpError {
     }
 }
 
+impl From<script::ScriptError> for OpError {
+    fn from(err: script::ScriptError) -> Self {
+        Self::new(OpErrorKind::ScriptError(err))
+    }
+}
+
 impl From<rusty_leveldb::Status> for OpError {
     fn from(status: Status) -> Self {
         Self::new(OpErrorKind::LevelDBError(status.err))
@@ -175,4 +230,20 @@ mod tests {
         let err = err.join_msg("Cannot proceed.");
         assert_eq!(format!("{}", err), "Cannot proceed. I/O Error: oh no!");
     }
+
+    #[test]
+    fn test_deserialize_error() {
+        let cause = OpError::from(io::Error::from(io::ErrorKind::UnexpectedEof));
+        let err = OpError::deserialize(120, cause);
+        assert_eq!(
+            format!("{}", err),
+            "Deserialize: offset 120: I/O Error: unexpected end of file"
+        );
+        let err = err.at_height(7);
+        assert_eq!(
+            format!("{}", err),
+            "Deserialize: block 7 at offset 120: I/O Error: unexpected end of file"
+        );
+        assert!(error::Error::source(&err.kind).is_some());
+    }
 }
@@ -0,0 +1,184 @@
+//! Parses the blk files of Bitcoin Core (and coins derived from it) and hands the blocks to a callback.
+//!
+//! ```no_run
+//! use rusty_blockparser::callbacks::simplestats::SimpleStats;
+//! use rusty_blockparser::{BlockchainParser, Callback, ParserOptions};
+//!
+//! let options = ParserOptions::new("/path/to/.bitcoin/blocks".into());
+//! let matches = SimpleStats::build_subcommand().get_matches_from(["simplestats"]);
+//! let callback = SimpleStats::new(&matches).unwrap();
+//! let callback = BlockchainParser::run(options, Box::new(callback)).unwrap();
+//! println!("{}", callback.summary());
+//! ```
+
+use std::fmt;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::blockchain::parser::mempool::MempoolConflicts;
+use crate::blockchain::parser::remote::RemoteSource;
+use crate::blockchain::parser::rpc::RpcClient;
+use crate::callbacks::NullCallback;
+use crate::common::utils::{FilenameTemplate, HashEndian, OutputConfig};
+
+#[macro_use]
+extern crate log;
+extern crate bitcoin;
+extern crate byteorder;
+extern crate chrono;
+extern crate clap;
+extern crate rayon;
+extern crate rusty_leveldb;
+extern crate seek_bufread;
+
+#[macro_use]
+pub mod errors;
+pub mod blockchain;
+pub mod callbacks;
+pub mod cli;
+pub mod common;
+
+pub use crate::blockchain::parser::types::CoinType;
+pub use crate::blockchain::parser::BlockchainParser;
+pub use crate::blockchain::proto::block::Block;
+pub use crate::blockchain::proto::script::classify::{script_type, ScriptType};
+pub use crate::blockchain::proto::script::encoder::AddressEncoder;
+pub use crate::blockchain::proto::script::{
+    disasm, eval_from_bytes, EvaluatedScript, ScriptPattern,
+};
+pub use crate::blockchain::proto::tx::{EvaluatedTx as Tx, TxInput, TxOutput};
+pub use crate::blockchain::proto::Hashed;
+pub use crate::callbacks::registry::CallbackRegistry;
+pub use crate::callbacks::{Callback, Control};
+pub use crate::common::utils::{arr_to_hex, hash_to_string};
+pub use crate::errors::{OpError, OpErrorKind, OpResult};
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct BlockHeightRange {
+    start: u64,
+    end: Option<u64>,
+}
+
+impl BlockHeightRange {
+    pub fn new(start: u64, end: Option<u64>) -> OpResult<Self> {
+        if end.is_some() && start >= end.unwrap() {
+            return Err(OpError::from(String::from(
+                "--start value must be lower than --end value",
+            )));
+        }
+        Ok(Self { start, end })
+    }
+
+    pub fn is_default(&self) -> bool {
+        self.start == 0 && self.end.is_none()
+    }
+}
+
+impl fmt::Display for BlockHeightRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let end = match self.end {
+            Some(e) => e.to_string(),
+            None => String::from("HEAD"),
+        };
+        write!(f, "{}..{}", self.start, end)
+    }
+}
+
+/// Holds all available user arguments.
+/// `ParserOptions::new()` has the defaults of the command line, the fields can be set directly.
+pub struct ParserOptions {
+    /// Name of the callback which gets executed for each block. (See callbacks/mod.rs)
+    pub callback: Box<dyn Callback>,
+    pub callback_name: String,
+    /// Holds the relevant coin parameters we need for parsing
+    pub coin: CoinType,
+    /// Enable this if you want to check the chain index integrity and merkle root for each block.
+    pub verify: bool,
+    /// Path to directory where blk.dat files are stored
+    pub blockchain_dir: PathBuf,
+    /// Verbosity level, 0 = Error, 1 = Info, 2 = Debug, 3+ = Trace
+    pub log_level_filter: log::LevelFilter,
+    /// Range which is considered for parsing
+    pub range: BlockHeightRange,
+    /// Byte order used to render block and transaction hashes in all outputs
+    pub hash_endian: HashEndian,
+    /// Moves finished dump files by renaming them, instead of copying and deleting them
+    pub atomic_rename: bool,
+    /// Names the output files of the callback, None for the default naming
+    pub filename_template: Option<FilenameTemplate>,
+    /// Location, name prefix and compression of the output files of the callback
+    pub output: OutputConfig,
+    /// Continue after the tip of the last run, which is remembered in the dump folder
+    pub incremental: bool,
+    /// Only report the planned work without processing any blocks
+    pub dry_run: bool,
+    /// Memory-map blk files instead of reading them through a buffer
+    pub mmap: bool,
+    /// Number of threads decoding blocks ahead of the callback, 1 decodes on the parser thread
+    pub threads: usize,
+    /// Object store serving the blk files, the block index is still read from blockchain_dir
+    pub source: Option<RemoteSource>,
+    /// Node serving the blocks above the last block in the blk files
+    pub rpc: Option<RpcClient>,
+    /// Fetches all blocks from `rpc` instead of reading the blk files
+    pub rpc_only: bool,
+    /// Reads and verifies all blocks without running a callback
+    pub validate_only: bool,
+    /// Processes the main chain from the tip downward
+    pub reverse: bool,
+    /// Lets the callback write an intermediate snapshot after every n processed blocks
+    pub snapshot_interval: Option<u64>,
+    /// Flushes the buffered writers of the callback after every n processed blocks
+    pub flush_interval: Option<u64>,
+    /// Saves the callback state and the processed tip after every n processed blocks (--resume)
+    pub checkpoint_interval: Option<u64>,
+    /// Writes the throughput and the time spent per pipeline stage to this file
+    pub bench_report: Option<PathBuf>,
+    /// Keeps the current progress of the run as JSON in this file
+    pub progress_json: Option<PathBuf>,
+    /// Polls for new blocks in this interval after reaching the end of the blk files
+    pub watch_interval: Option<Duration>,
+    /// Stops watching if no new block arrived within this time
+    pub watch_timeout: Option<Duration>,
+    /// Appends all warnings and errors as JSON lines to this file
+    pub error_log: Option<PathBuf>,
+    /// Reports mempool transactions of the RPC node which got replaced on-chain
+    pub mempool_conflicts: Option<MempoolConflicts>,
+}
+
+impl ParserOptions {
+    /// Options for parsing the blk files in the given directory, with the defaults of the command line
+    pub fn new(blockchain_dir: PathBuf) -> Self {
+        Self {
+            callback: Box::new(NullCallback),
+            callback_name: String::from("none"),
+            coin: CoinType::from(blockchain::parser::types::Bitcoin),
+            verify: false,
+            blockchain_dir,
+            log_level_filter: log::LevelFilter::Info,
+            range: BlockHeightRange::new(0, None).unwrap(),
+            hash_endian: HashEndian::Display,
+            atomic_rename: true,
+            filename_template: None,
+            output: OutputConfig::default(),
+            incremental: false,
+            dry_run: false,
+            mmap: false,
+            threads: 1,
+            source: None,
+            rpc: None,
+            rpc_only: false,
+            validate_only: false,
+            reverse: false,
+            snapshot_interval: None,
+            flush_interval: None,
+            checkpoint_interval: None,
+            bench_report: None,
+            progress_json: None,
+            watch_interval: None,
+            watch_timeout: None,
+            error_log: None,
+            mempool_conflicts: None,
+        }
+    }
+}